@@ -9,6 +9,10 @@ use libp2p::{
 use libp2p::{Multiaddr, SwarmBuilder};
 use libp2p_identity::Keypair;
 use log::{debug, info, warn, LevelFilter};
+use rust_libp2p_nym::bootstrap;
+use rust_libp2p_nym::client::ManagedMixnetClient;
+use rust_libp2p_nym::config::NymTransportConfig;
+use rust_libp2p_nym::presets;
 use rust_libp2p_nym::transport::NymTransport;
 use std::{
     collections::{hash_map::DefaultHasher, HashSet},
@@ -21,6 +25,7 @@ use tokio::{io, io::AsyncBufReadExt, select, time::sleep};
 #[derive(NetworkBehaviour)]
 struct MyBehaviour {
     gossipsub: gossipsub::Behaviour,
+    bootstrap: bootstrap::Behaviour,
 }
 
 #[tokio::main]
@@ -33,6 +38,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .filter_module("libp2p_swarm", LevelFilter::Debug)
         .init();
 
+    // Every address given on the command line is dialed until it's connected
+    let bootstrap_peers = std::env::args()
+        .skip(1)
+        .map(|addr| addr.parse::<Multiaddr>())
+        .collect::<Result<Vec<_>, _>>()?;
+
     let local_key = Keypair::generate_ed25519();
     let local_peer_id = PeerId::from(local_key.public());
 
@@ -40,16 +51,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
     info!("Running `chat` example using NymTransport");
 
     info!("Connecting to Nym mixnet...");
-    let client = match nym_sdk::mixnet::MixnetClient::connect_new().await {
-        Ok(client) => {
-            info!("Successfully connected to Nym mixnet");
-            client
-        }
-        Err(e) => {
-            warn!("Failed to connect to Nym mixnet: {}", e);
-            return Err(e.into());
-        }
-    };
+    let client = ManagedMixnetClient::new(&NymTransportConfig::default())?
+        .connect()
+        .await?;
+    info!("Successfully connected to Nym mixnet");
 
     let transport = NymTransport::new_with_timeout(
         client,
@@ -70,18 +75,16 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 gossipsub::MessageId::from(s.finish().to_string())
             };
 
-            let gossipsub_config = gossipsub::ConfigBuilder::default()
-                .heartbeat_interval(Duration::from_secs(40))
+            // the preset's heartbeats and caches suit the mixnet's latency; the
+            // mesh is kept small for a chat between a handful of peers
+            let gossipsub_config = presets::gossipsub_config()
                 .validation_mode(gossipsub::ValidationMode::Strict)
                 .message_id_fn(message_id_fn)
-                .max_transmit_size(65536)
-                .duplicate_cache_time(Duration::from_secs(60))
                 .mesh_n(1)
                 .mesh_n_low(1)
                 .mesh_n_high(14)
                 .mesh_outbound_min(0)
                 .gossip_lazy(6)
-                .fanout_ttl(Duration::from_secs(60))
                 .support_floodsub()
                 .flood_publish(true)
                 .build()
@@ -92,12 +95,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 gossipsub_config,
             )?;
 
-            Ok(MyBehaviour { gossipsub })
+            // gossipsub keeps the connections alive, so dropped ones are redialed
+            let bootstrap = bootstrap::Behaviour::new(
+                bootstrap::Config::new(bootstrap_peers.clone()).with_reconnect(true),
+            );
+
+            Ok(MyBehaviour {
+                gossipsub,
+                bootstrap,
+            })
         })?
-        .with_swarm_config(|c| {
-            c.with_idle_connection_timeout(Duration::from_secs(120)) // Timeout increases across the board
-                .with_max_negotiating_inbound_streams(50)
-        })
+        .with_swarm_config(presets::swarm_config)
         .build();
 
     info!("Swarm built successfully");
@@ -164,28 +172,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
     info!("Enter messages via STDIN and they will be sent to connected peers using Gossipsub");
     info!("Note: Wait for 'Ready to chat!' message before sending messages");
 
-    // Handle command line argument for dialing
-    if let Some(addr) = std::env::args().nth(1) {
-        info!("Attempting to dial: {}", addr);
-        let remote: Multiaddr = match addr.parse() {
-            Ok(addr) => addr,
-            Err(e) => {
-                warn!("Failed to parse multiaddr '{}': {}", addr, e);
-                return Err(e.into());
-            }
-        };
-
-        match swarm.dial(remote.clone()) {
-            Ok(_) => info!("Initiated dial to {}", remote),
-            Err(e) => {
-                warn!("Failed to initiate dial to {}: {}", remote, e);
-                return Err(e.into());
-            }
-        }
-    } else {
+    if bootstrap_peers.is_empty() {
         info!("No remote address provided, waiting for incoming connections");
         info!("To connect to this node, run:");
         info!("cargo run --example chat -- /nym/YOUR_ADDRESS_HERE");
+    } else {
+        info!("Dialing bootstrap peers: {:?}", bootstrap_peers);
     }
 
     let mut status_interval = tokio::time::interval(Duration::from_secs(30));
@@ -359,6 +351,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         println!("\n💬 [{}]: {}\n", peer_id.to_string().chars().take(12).collect::<String>(), msg_str);
                     }
 
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Bootstrap(bootstrap::Event::Unreachable { address, error })) => {
+                        warn!("💥 Giving up on bootstrap peer {}: {}", address, error);
+                    }
+
                     SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::GossipsubNotSupported { peer_id })) => {
                         warn!("⚠️  Peer {} does not support gossipsub", peer_id);
                     }
@@ -5,10 +5,13 @@ use libp2p::SwarmBuilder;
 use libp2p::{ping, swarm::SwarmEvent, Multiaddr};
 use libp2p_identity::{Keypair, PeerId};
 use log::LevelFilter;
-use nym_sdk::mixnet::{MixnetClientBuilder, StoragePaths};
+use nym_sphinx::params::PacketSize;
+use rust_libp2p_nym::client::ManagedMixnetClient;
+use rust_libp2p_nym::config::{NymNetwork, NymTransportConfig, PacketSizePolicy};
+use rust_libp2p_nym::presets;
 use rust_libp2p_nym::transport::NymTransport;
+use std::error::Error;
 use std::path::PathBuf;
-use std::{error::Error, time::Duration};
 use tempfile::TempDir;
 
 #[tokio::main]
@@ -25,25 +28,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut swarm = {
         println!("Running `ping` example using NymTransport");
         let config_dir = PathBuf::from(TempDir::new().unwrap().path().to_str().unwrap());
-        let storage_paths = StoragePaths::new_from_dir(&config_dir).unwrap();
 
-        // Create the client with a storage backend, and enable it by giving it some paths. If keys
-        // exists at these paths, they will be loaded, otherwise they will be generated.
-        let client = MixnetClientBuilder::new_with_default_storage(storage_paths)
-            .await
-            .unwrap()
-            .build()
-            .unwrap();
+        // Pings are small, but let larger messages use extended packets.
+        let mut config = NymTransportConfig::default().with_packet_size(PacketSizePolicy::Auto {
+            extended: PacketSize::ExtendedPacket32,
+        });
+        // Point NYM_ENV_FILE at an env file, eg. the sandbox.env of the Nym
+        // repository, to use a network other than mainnet.
+        if let Ok(path) = std::env::var("NYM_ENV_FILE") {
+            config = config.with_network(NymNetwork::EnvFile(path.into()));
+        }
 
-        let client = client.connect_to_mixnet().await.unwrap();
+        // Create the client with a storage backend, and enable it by giving it a directory. If keys
+        // exist in it, they will be loaded, otherwise they will be generated. Failed attempts to
+        // connect are retried. Connecting takes a while, so print the progress.
+        let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(event) = progress_rx.recv().await {
+                println!("Connecting to the mixnet: {event:?}");
+            }
+        });
+        let client = ManagedMixnetClient::new(&config)?
+            .with_storage_dir(config_dir)
+            .with_progress(progress_tx)
+            .connect()
+            .await?;
 
-        let transport = NymTransport::new(client, local_key.clone()).await?;
+        let transport = NymTransport::new_with_config(client, local_key.clone(), config).await?;
 
         SwarmBuilder::with_new_identity()
             .with_tokio()
             .with_other_transport(|_| transport)?
-            .with_behaviour(|_| ping::Behaviour::default())?
-            .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(90))) // TODO this sets the config timeout for the ping example - change for keepalive behaviour if possible
+            .with_behaviour(|_| ping::Behaviour::new(presets::ping_config()))?
+            .with_swarm_config(presets::swarm_config)
             .build()
     };
 
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_libp2p_nym::fuzzing::decode_message;
+
+fuzz_target!(|data: &[u8]| {
+    decode_message(data);
+});
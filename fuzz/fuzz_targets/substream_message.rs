@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_libp2p_nym::fuzzing::decode_substream_message;
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte selects the compact encoding
+    if let Some((compact, data)) = data.split_first() {
+        decode_substream_message(data, compact & 1 == 1);
+    }
+});
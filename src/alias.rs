@@ -0,0 +1,208 @@
+use libp2p::core::Multiaddr;
+use libp2p_identity::Keypair;
+#[cfg(feature = "nym-client")]
+use nym_sdk::mixnet::MixnetClient;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::driver::MixnetDriver;
+use super::error::Error;
+use super::transport::nym_address_to_multiaddress;
+
+/// AliasId identifies an address alias of a transport.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct AliasId(u64);
+
+/// Alias is an address the transport accepts connections at besides its
+/// own, with an identity of its own.
+struct Alias {
+    listen_addr: Multiaddr,
+    /// the identity connections made to the alias are signed with.
+    keypair: Keypair,
+}
+
+#[derive(Default)]
+struct AliasState {
+    next_id: u64,
+    aliases: HashMap<AliasId, Alias>,
+}
+
+/// AliasRegistry holds the transport's address aliases. It's shared by the
+/// transport and the `AddressAliases` handles. The mixnet task tags the
+/// messages each alias receives with its AliasId, and the transport keeps
+/// the alias a connection was made to, so that the replies on it go out
+/// through the alias's client and are signed with its identity. Sender tags
+/// can't tell them apart, as a nym client uses the same one for every
+/// recipient.
+#[derive(Clone, Default)]
+pub(crate) struct AliasRegistry(Arc<Mutex<AliasState>>);
+
+impl AliasRegistry {
+    pub(crate) fn insert(&self, listen_addr: Multiaddr) -> AliasId {
+        let mut state = self.0.lock();
+        let id = AliasId(state.next_id);
+        state.next_id += 1;
+        state.aliases.insert(
+            id,
+            Alias {
+                listen_addr,
+                keypair: Keypair::generate_ed25519(),
+            },
+        );
+        id
+    }
+
+    fn remove(&self, listen_addr: &Multiaddr) -> Option<AliasId> {
+        let mut state = self.0.lock();
+        let id = state
+            .aliases
+            .iter()
+            .find(|(_, alias)| alias.listen_addr == *listen_addr)
+            .map(|(id, _)| *id)?;
+        state.aliases.remove(&id);
+        Some(id)
+    }
+
+    /// returns the listen address and identity of the given alias, if it
+    /// wasn't removed.
+    pub(crate) fn lookup(&self, id: Option<AliasId>) -> Option<(Multiaddr, Keypair)> {
+        let state = self.0.lock();
+        let alias = state.aliases.get(&id?)?;
+        Some((alias.listen_addr.clone(), alias.keypair.clone()))
+    }
+
+    /// forgets an alias whose client is gone.
+    pub(crate) fn forget(&self, id: AliasId) {
+        self.0.lock().aliases.remove(&id);
+    }
+
+    fn listen_addrs(&self) -> Vec<Multiaddr> {
+        let state = self.0.lock();
+        state
+            .aliases
+            .values()
+            .map(|alias| alias.listen_addr.clone())
+            .collect()
+    }
+}
+
+/// AliasCommand tells the mixnet task to start or stop receiving at an alias.
+pub(crate) enum AliasCommand {
+    Add(AliasId, Box<dyn MixnetDriver>),
+    Remove(AliasId),
+}
+
+/// AddressAliases hands out distinct addresses of the transport to
+/// different peers, so that peers which compare notes can't tell they're
+/// talking to the same node. Every alias is a mixnet client of its own, with
+/// its own nym address, and connections made to it are signed with an
+/// identity of its own, so they have a PeerId of their own as well. A handle
+/// can be obtained with `NymTransport::address_aliases()` before the
+/// transport is moved into a swarm.
+///
+/// Aliases only accept connections: dials always go out from the
+/// transport's own address. They're not reported as listen addresses, since
+/// the swarm would announce them to every peer, and their connections are
+/// neither migrated nor closed when the transport's own address changes.
+#[derive(Clone)]
+pub struct AddressAliases {
+    pub(crate) registry: AliasRegistry,
+    pub(crate) commands_tx: UnboundedSender<AliasCommand>,
+}
+
+impl AddressAliases {
+    /// returns a handle which can't add aliases, for transports without a
+    /// mixnet task.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub(crate) fn detached() -> Self {
+        Self {
+            registry: AliasRegistry::default(),
+            commands_tx: tokio::sync::mpsc::unbounded_channel().0,
+        }
+    }
+
+    /// starts accepting connections at the address of the given client, and
+    /// returns it as a `/nym` address to hand out. Fails if the transport
+    /// stopped using the mixnet.
+    #[cfg(feature = "nym-client")]
+    pub fn add(&self, client: MixnetClient) -> Result<Multiaddr, Error> {
+        self.add_driver(client)
+    }
+
+    /// starts accepting connections at the address of the given driver, like
+    /// `add` does a client.
+    pub fn add_driver(&self, driver: impl MixnetDriver) -> Result<Multiaddr, Error> {
+        let listen_addr = nym_address_to_multiaddress(driver.address())?;
+        let id = self.registry.insert(listen_addr.clone());
+        if self
+            .commands_tx
+            .send(AliasCommand::Add(id, Box::new(driver)))
+            .is_err()
+        {
+            self.registry.remove(&listen_addr);
+            return Err(Error::MixnetStopped);
+        }
+        Ok(listen_addr)
+    }
+
+    /// stops accepting connections at the given alias, and disconnects its
+    /// client. Its connections are closed.
+    pub fn remove(&self, alias: &Multiaddr) -> Result<(), Error> {
+        let id = self.registry.remove(alias).ok_or(Error::UnknownAlias)?;
+        self.commands_tx
+            .send(AliasCommand::Remove(id))
+            .map_err(|_| Error::MixnetStopped)
+    }
+
+    /// returns the aliases the transport accepts connections at.
+    pub fn aliases(&self) -> Vec<Multiaddr> {
+        self.registry.listen_addrs()
+    }
+}
+
+impl Debug for AddressAliases {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // the aliases would link the transport's addresses
+        f.debug_struct("AddressAliases")
+            .field("aliases", &self.registry.listen_addrs().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::random_address;
+
+    #[test]
+    fn test_alias_registry() {
+        let registry = AliasRegistry::default();
+        let listen_addr = nym_address_to_multiaddress(random_address()).unwrap();
+        let id = registry.insert(listen_addr.clone());
+        assert_eq!(registry.listen_addrs(), vec![listen_addr.clone()]);
+
+        // every alias has an identity of its own
+        let (addr, keypair) = registry.lookup(Some(id)).unwrap();
+        assert_eq!(addr, listen_addr);
+        assert_eq!(
+            registry.lookup(Some(id)).unwrap().1.public(),
+            keypair.public()
+        );
+        assert!(registry.lookup(None).is_none());
+        let other = registry.insert(nym_address_to_multiaddress(random_address()).unwrap());
+        assert_ne!(
+            registry.lookup(Some(other)).unwrap().1.public(),
+            keypair.public()
+        );
+
+        // a removed alias is no longer looked up
+        assert_eq!(registry.remove(&listen_addr), Some(id));
+        assert!(registry.remove(&listen_addr).is_none());
+        assert!(registry.lookup(Some(id)).is_none());
+        registry.forget(other);
+        assert!(registry.lookup(Some(other)).is_none());
+    }
+}
@@ -0,0 +1,667 @@
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use futures::stream::FuturesUnordered;
+use libp2p::core::{
+    multiaddr::{Multiaddr, Protocol},
+    transport::PortUse,
+    upgrade::ReadyUpgrade,
+    Endpoint, PeerRecord, SignedEnvelope,
+};
+use libp2p::swarm::{
+    behaviour::{ConnectionClosed, ConnectionEstablished, ExpiredListenAddr, NewListenAddr},
+    handler::{ConnectionEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound},
+    ConnectionDenied, ConnectionHandler, ConnectionHandlerEvent, ConnectionId, FromSwarm,
+    NetworkBehaviour, NotifyHandler, StreamProtocol, StreamUpgradeError, SubstreamProtocol,
+    THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+};
+use libp2p_identity::{Keypair, PeerId};
+use log::debug;
+use nym_sphinx::addressing::clients::Recipient;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::{sleep, Instant, Sleep};
+
+use super::redact::redact;
+
+/// the protocol over which peers announce their nym address.
+pub const PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/nym/address-announce/2.0.0");
+
+/// the longest encoded record accepted from a peer.
+const MAX_RECORD_LEN: usize = 4096;
+/// the number of announcements read from a peer at the same time; further
+/// streams are dropped.
+const MAX_INBOUND_STREAMS: usize = 4;
+/// how long an announcement may take to be sent or read.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Config sets how often the address is announced.
+#[derive(Clone, Debug)]
+pub struct Config {
+    interval: Duration,
+    external_address: bool,
+    max_peers: usize,
+}
+
+impl Config {
+    /// returns a config which announces the address every 5 minutes, and
+    /// right away when it changes.
+    pub fn new() -> Self {
+        Config {
+            interval: Duration::from_secs(300),
+            external_address: false,
+            max_peers: 4096,
+        }
+    }
+
+    /// the time between announcements on each connection.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// whether our nym address is reported to the swarm as a confirmed
+    /// external address. It's reachable from anywhere, but the swarm only
+    /// considers listen addresses external once something confirms them;
+    /// Kademlia, for one, stays in client mode until then.
+    pub fn with_external_address(mut self, enabled: bool) -> Self {
+        self.external_address = enabled;
+        self
+    }
+
+    /// the number of peers whose address is remembered. Once it's reached,
+    /// the address announced longest ago is forgotten, preferring peers
+    /// which aren't connected anymore.
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = max_peers;
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Event reports that a peer announced a different nym address than the one
+/// known for it so far, if any.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub peer_id: PeerId,
+    pub address: Multiaddr,
+    pub previous: Option<Multiaddr>,
+}
+
+/// Behaviour shares our `/nym` listen address with connected peers and
+/// records theirs, so that peers can be dialed again after they moved to
+/// another gateway or got a new address. The address is announced when a
+/// connection is established, when it changes, and periodically after that.
+///
+/// Announcements are peer records signed by the announcing peer, and are only
+/// accepted if they were signed by the peer at the other end of the
+/// connection, so a peer can't announce an address on behalf of another one.
+///
+/// Addresses learned from peers are also reported to the swarm as
+/// `ToSwarm::NewExternalAddrOfPeer`, so that other behaviours, eg. Kademlia,
+/// pick them up.
+pub struct Behaviour {
+    config: Config,
+    /// signs the records announcing our address; it has to be the swarm's
+    /// identity, or peers reject them.
+    keypair: Keypair,
+    /// our current nym address, once the transport reported it.
+    local_address: Option<Multiaddr>,
+    /// the signed record announcing `local_address`.
+    local_record: Option<PeerRecord>,
+    /// the last address each peer announced.
+    addresses: HashMap<PeerId, Announced>,
+    connections: HashMap<ConnectionId, PeerId>,
+    events: VecDeque<ToSwarm<Event, PeerRecord>>,
+}
+
+/// an address announced by a peer.
+struct Announced {
+    address: Multiaddr,
+    /// the sequence number of the record it was announced in; older records
+    /// are ignored, so that a replayed one can't revert the address.
+    seq: u64,
+    received_at: Instant,
+}
+
+impl Behaviour {
+    /// returns a behaviour which announces our address in records signed
+    /// with `keypair`, which has to be the swarm's identity.
+    pub fn new(keypair: Keypair, config: Config) -> Self {
+        Behaviour {
+            config,
+            keypair,
+            local_address: None,
+            local_record: None,
+            addresses: HashMap::new(),
+            connections: HashMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// returns the address the peer announced last, if any.
+    pub fn address_of(&self, peer_id: &PeerId) -> Option<&Multiaddr> {
+        self.addresses
+            .get(peer_id)
+            .map(|announced| &announced.address)
+    }
+
+    /// returns the last address announced by each peer, including ones
+    /// which aren't connected anymore, up to `Config::with_max_peers`.
+    pub fn addresses(&self) -> impl Iterator<Item = (&PeerId, &Multiaddr)> {
+        self.addresses
+            .iter()
+            .map(|(peer_id, announced)| (peer_id, &announced.address))
+    }
+
+    fn new_handler(&self) -> Handler {
+        Handler::new(self.config.interval, self.local_record.clone())
+    }
+
+    fn on_record_received(&mut self, peer_id: PeerId, record: PeerRecord) {
+        if record.peer_id() != peer_id {
+            debug!(
+                "peer {} announced a record signed by {}, ignoring it",
+                redact(peer_id),
+                redact(record.peer_id())
+            );
+            return;
+        }
+        let address = match record.addresses() {
+            [address] if is_nym_address(address) => address.clone(),
+            _ => {
+                debug!(
+                    "peer {} announced an invalid nym address, ignoring it",
+                    redact(peer_id)
+                );
+                return;
+            }
+        };
+        match self.addresses.get(&peer_id) {
+            Some(known) if record.seq() < known.seq => {
+                debug!(
+                    "peer {} announced an outdated record, ignoring it",
+                    redact(peer_id)
+                );
+                return;
+            }
+            Some(known) if known.address == address => return,
+            Some(_) => {}
+            None => self.evict(),
+        }
+        let previous = self
+            .addresses
+            .insert(
+                peer_id,
+                Announced {
+                    address: address.clone(),
+                    seq: record.seq(),
+                    received_at: Instant::now(),
+                },
+            )
+            .map(|announced| announced.address);
+        self.events.push_back(ToSwarm::NewExternalAddrOfPeer {
+            peer_id,
+            address: address.clone(),
+        });
+        self.events.push_back(ToSwarm::GenerateEvent(Event {
+            peer_id,
+            address,
+            previous,
+        }));
+    }
+
+    /// makes room for another peer's address once `max_peers` are known,
+    /// forgetting the one announced longest ago, preferring peers which
+    /// aren't connected anymore.
+    fn evict(&mut self) {
+        if self.addresses.len() < self.config.max_peers {
+            return;
+        }
+        let connected: HashSet<&PeerId> = self.connections.values().collect();
+        let oldest = self
+            .addresses
+            .iter()
+            .min_by_key(|(peer_id, announced)| (connected.contains(peer_id), announced.received_at))
+            .map(|(peer_id, _)| *peer_id);
+        if let Some(peer_id) = oldest {
+            self.addresses.remove(&peer_id);
+        }
+    }
+
+    fn set_local_address(&mut self, address: Multiaddr) {
+        let record = match PeerRecord::new(&self.keypair, vec![address.clone()]) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                debug!("failed to sign the record announcing our address: {e}");
+                None
+            }
+        };
+        let previous = self.local_address.replace(address.clone());
+        self.local_record = record.clone();
+        if self.config.external_address {
+            if let Some(previous) = previous {
+                self.events
+                    .push_back(ToSwarm::ExternalAddrExpired(previous));
+            }
+            self.events
+                .push_back(ToSwarm::ExternalAddrConfirmed(address));
+        }
+        let Some(record) = record else {
+            return;
+        };
+        for (connection_id, peer_id) in &self.connections {
+            self.events.push_back(ToSwarm::NotifyHandler {
+                peer_id: *peer_id,
+                handler: NotifyHandler::One(*connection_id),
+                event: record.clone(),
+            });
+        }
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = Handler;
+    type ToSwarm = Event;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(self.new_handler())
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+        _: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(self.new_handler())
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished {
+                peer_id,
+                connection_id,
+                ..
+            }) => {
+                self.connections.insert(connection_id, peer_id);
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed { connection_id, .. }) => {
+                self.connections.remove(&connection_id);
+            }
+            FromSwarm::NewListenAddr(NewListenAddr { addr, .. }) => {
+                if !is_nym_address(addr) || self.local_address.as_ref() == Some(addr) {
+                    return;
+                }
+                self.set_local_address(addr.clone());
+            }
+            FromSwarm::ExpiredListenAddr(ExpiredListenAddr { addr, .. })
+                if self.local_address.as_ref() == Some(addr) =>
+            {
+                self.local_address = None;
+                self.local_record = None;
+                if self.config.external_address {
+                    self.events
+                        .push_back(ToSwarm::ExternalAddrExpired(addr.clone()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        _: ConnectionId,
+        record: THandlerOutEvent<Self>,
+    ) {
+        self.on_record_received(peer_id, record);
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// returns whether the address is a single, valid `/nym` address.
+pub(crate) fn is_nym_address(address: &Multiaddr) -> bool {
+    let mut protocols = address.iter();
+    match (protocols.next(), protocols.next()) {
+        (Some(Protocol::Nym(addr)), None) => Recipient::from_str(&addr).is_ok(),
+        _ => false,
+    }
+}
+
+enum OutboundState {
+    /// a substream was requested to announce the address on.
+    Requested,
+    Sending(BoxFuture<'static, io::Result<()>>),
+}
+
+/// Handler announces our address on a connection, and reads the remote's
+/// announcements. Records are only checked for a valid signature here; the
+/// behaviour checks who signed them.
+pub struct Handler {
+    interval: Duration,
+    record: Option<PeerRecord>,
+    next_announcement: Pin<Box<Sleep>>,
+    outbound: Option<OutboundState>,
+    inbound: FuturesUnordered<BoxFuture<'static, io::Result<PeerRecord>>>,
+    /// false once the remote turned out not to support the protocol.
+    supported: bool,
+}
+
+impl Handler {
+    fn new(interval: Duration, record: Option<PeerRecord>) -> Self {
+        Handler {
+            interval,
+            record,
+            // the address is announced as soon as the connection is up
+            next_announcement: Box::pin(sleep(Duration::ZERO)),
+            outbound: None,
+            inbound: FuturesUnordered::new(),
+            supported: true,
+        }
+    }
+
+    fn schedule_next(&mut self, delay: Duration) {
+        self.next_announcement
+            .as_mut()
+            .reset(Instant::now() + delay);
+    }
+
+    fn on_dial_upgrade_error(
+        &mut self,
+        DialUpgradeError { error, .. }: DialUpgradeError<
+            (),
+            <Self as ConnectionHandler>::OutboundProtocol,
+        >,
+    ) {
+        self.outbound = None;
+        match error {
+            StreamUpgradeError::NegotiationFailed => {
+                debug!("the remote doesn't support address announcements");
+                self.supported = false;
+            }
+            StreamUpgradeError::Apply(e) => match e {},
+            e => {
+                debug!("failed to open a substream for an address announcement: {e}");
+                self.schedule_next(self.interval);
+            }
+        }
+    }
+}
+
+impl ConnectionHandler for Handler {
+    type FromBehaviour = PeerRecord;
+    type ToBehaviour = PeerRecord;
+    type InboundProtocol = ReadyUpgrade<StreamProtocol>;
+    type OutboundProtocol = ReadyUpgrade<StreamProtocol>;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<ReadyUpgrade<StreamProtocol>, ()> {
+        SubstreamProtocol::new(ReadyUpgrade::new(PROTOCOL_NAME), ())
+    }
+
+    /// the behaviour reports a new local address, which is announced right away.
+    fn on_behaviour_event(&mut self, record: PeerRecord) {
+        self.record = Some(record);
+        self.schedule_next(Duration::ZERO);
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<ReadyUpgrade<StreamProtocol>, (), PeerRecord>> {
+        while let Poll::Ready(Some(res)) = self.inbound.poll_next_unpin(cx) {
+            match res {
+                Ok(record) => return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(record)),
+                Err(e) => debug!("failed to read an address announcement: {e}"),
+            }
+        }
+
+        match &mut self.outbound {
+            Some(OutboundState::Sending(send)) => {
+                if let Poll::Ready(res) = send.poll_unpin(cx) {
+                    if let Err(e) = res {
+                        debug!("failed to announce our address: {e}");
+                    }
+                    self.outbound = None;
+                    self.schedule_next(self.interval);
+                }
+            }
+            Some(OutboundState::Requested) => {}
+            // nothing to announce until the transport reported our address
+            None if !self.supported || self.record.is_none() => {}
+            None => {
+                if let Poll::Ready(()) = self.next_announcement.as_mut().poll(cx) {
+                    self.outbound = Some(OutboundState::Requested);
+                    return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                        protocol: SubstreamProtocol::new(ReadyUpgrade::new(PROTOCOL_NAME), ()),
+                    });
+                }
+            }
+        }
+        Poll::Pending
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+        match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol: mut stream,
+                ..
+            }) => {
+                if self.inbound.len() >= MAX_INBOUND_STREAMS {
+                    debug!("too many address announcements from the remote, dropping one");
+                    return;
+                }
+                stream.ignore_for_keep_alive();
+                self.inbound.push(
+                    async move {
+                        tokio::time::timeout(STREAM_TIMEOUT, recv_record(stream))
+                            .await
+                            .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))?
+                    }
+                    .boxed(),
+                );
+            }
+            ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                protocol: mut stream,
+                ..
+            }) => {
+                let Some(record) = self.record.clone() else {
+                    self.outbound = None;
+                    return;
+                };
+                stream.ignore_for_keep_alive();
+                self.outbound = Some(OutboundState::Sending(
+                    async move {
+                        tokio::time::timeout(STREAM_TIMEOUT, send_record(stream, &record))
+                            .await
+                            .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))?
+                    }
+                    .boxed(),
+                ));
+            }
+            ConnectionEvent::DialUpgradeError(e) => self.on_dial_upgrade_error(e),
+            _ => {}
+        }
+    }
+}
+
+/// writes the signed record, prefixed with its length, and closes the stream.
+async fn send_record<S: AsyncWrite + Unpin>(mut stream: S, record: &PeerRecord) -> io::Result<()> {
+    let bytes = record.to_signed_envelope().into_protobuf_encoding();
+    if bytes.len() > MAX_RECORD_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "record is too long",
+        ));
+    }
+    stream
+        .write_all(&(bytes.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(&bytes).await?;
+    stream.close().await
+}
+
+/// reads a record and checks its signature.
+async fn recv_record<S: AsyncRead + Unpin>(mut stream: S) -> io::Result<PeerRecord> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let len = u16::from_be_bytes(len) as usize;
+    if len > MAX_RECORD_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "record is too long",
+        ));
+    }
+    let mut bytes = vec![0; len];
+    stream.read_exact(&mut bytes).await?;
+    let envelope = SignedEnvelope::from_protobuf_encoding(&bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    PeerRecord::from_signed_envelope(envelope)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::nym_address_to_multiaddress;
+    use futures::io::Cursor;
+    use libp2p::core::transport::ListenerId;
+
+    const OTHER_RECIPIENT: &str = "D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN";
+    const TEST_RECIPIENT: &str = "Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR";
+
+    #[tokio::test]
+    async fn test_address_announcement() {
+        let address =
+            nym_address_to_multiaddress(Recipient::from_str(TEST_RECIPIENT).unwrap()).unwrap();
+        assert!(is_nym_address(&address));
+        assert!(!is_nym_address(&"/ip4/127.0.0.1/tcp/1".parse().unwrap()));
+        assert!(!is_nym_address(&Multiaddr::empty()));
+
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let record = PeerRecord::new(&keypair, vec![address.clone()]).unwrap();
+        let mut buf = vec![];
+        send_record(Cursor::new(&mut buf), &record).await.unwrap();
+        assert_eq!(recv_record(Cursor::new(&buf)).await.unwrap(), record);
+        recv_record(Cursor::new(&buf[..buf.len() - 1]))
+            .await
+            .unwrap_err();
+        let too_long = ((MAX_RECORD_LEN + 1) as u16).to_be_bytes();
+        recv_record(Cursor::new(&too_long)).await.unwrap_err();
+        // a record whose signature doesn't match is rejected
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        recv_record(Cursor::new(&buf)).await.unwrap_err();
+
+        // only changed, valid addresses signed by the peer are reported
+        let mut behaviour = Behaviour::new(Keypair::generate_ed25519(), Config::new());
+        let invalid =
+            PeerRecord::new(&keypair, vec!["/ip4/127.0.0.1/tcp/1".parse().unwrap()]).unwrap();
+        behaviour.on_record_received(peer_id, invalid);
+        behaviour.on_record_received(PeerId::random(), record.clone());
+        assert!(behaviour.events.is_empty());
+        behaviour.on_record_received(peer_id, record.clone());
+        behaviour.on_record_received(peer_id, record.clone());
+        assert_eq!(behaviour.address_of(&peer_id), Some(&address));
+        assert_eq!(behaviour.events.len(), 2);
+        assert!(matches!(
+            behaviour.events.pop_front(),
+            Some(ToSwarm::NewExternalAddrOfPeer { peer_id: p, .. }) if p == peer_id
+        ));
+        match behaviour.events.pop_front() {
+            Some(ToSwarm::GenerateEvent(event)) => assert_eq!(
+                event,
+                Event {
+                    peer_id,
+                    address: address.clone(),
+                    previous: None,
+                }
+            ),
+            _ => panic!("expected ToSwarm::GenerateEvent"),
+        }
+
+        // records older than the last one are ignored
+        behaviour.addresses.get_mut(&peer_id).unwrap().seq = u64::MAX;
+        let other_address =
+            nym_address_to_multiaddress(Recipient::from_str(OTHER_RECIPIENT).unwrap()).unwrap();
+        let replayed = PeerRecord::new(&keypair, vec![other_address]).unwrap();
+        behaviour.on_record_received(peer_id, replayed);
+        assert!(behaviour.events.is_empty());
+
+        // the peer announced longest ago is forgotten once max_peers are known
+        let mut behaviour =
+            Behaviour::new(Keypair::generate_ed25519(), Config::new().with_max_peers(2));
+        let peers: Vec<_> = (0..3)
+            .map(|_| {
+                let keypair = Keypair::generate_ed25519();
+                let record = PeerRecord::new(&keypair, vec![address.clone()]).unwrap();
+                behaviour.on_record_received(keypair.public().to_peer_id(), record);
+                keypair.public().to_peer_id()
+            })
+            .collect();
+        assert_eq!(behaviour.addresses().count(), 2);
+        assert_eq!(behaviour.address_of(&peers[0]), None);
+        assert_eq!(behaviour.address_of(&peers[2]), Some(&address));
+
+        // our address is confirmed as external if the config says so, and
+        // the previous one expired when it changes
+        let mut behaviour = Behaviour::new(
+            Keypair::generate_ed25519(),
+            Config::new().with_external_address(true),
+        );
+        let listener_id = ListenerId::next();
+        behaviour.on_swarm_event(FromSwarm::NewListenAddr(NewListenAddr {
+            listener_id,
+            addr: &address,
+        }));
+        assert!(matches!(
+            behaviour.events.pop_front(),
+            Some(ToSwarm::ExternalAddrConfirmed(a)) if a == address
+        ));
+        assert!(behaviour.local_record.is_some());
+        behaviour.on_swarm_event(FromSwarm::ExpiredListenAddr(ExpiredListenAddr {
+            listener_id,
+            addr: &address,
+        }));
+        assert!(matches!(
+            behaviour.events.pop_front(),
+            Some(ToSwarm::ExternalAddrExpired(a)) if a == address
+        ));
+        assert!(behaviour.events.is_empty());
+    }
+}
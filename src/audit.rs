@@ -0,0 +1,250 @@
+use log::warn;
+use serde::Serialize;
+use std::{
+    fmt::{Debug, Formatter},
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use super::error::Error;
+use super::message::{ConnectionId, Message};
+
+/// Stage is a step in the lifecycle of a message sent over a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// the connection created the message and queued it for the mixnet client.
+    Queued,
+    /// the mixnet client accepted the message for sending.
+    Sent,
+    /// the mixnet client refused the message, which was never sent.
+    SendFailed,
+    /// the remote acknowledged every message up to this one. Only recorded
+    /// if acks were negotiated; see `NymTransportConfig::congestion_control`.
+    Acked,
+    /// the message arrived from the mixnet.
+    Received,
+    /// the message was handed to its connection in order, which passes it
+    /// on to its substream.
+    Delivered,
+}
+
+/// AuditEvent records that a message reached a stage of its lifecycle.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AuditEvent {
+    /// when the stage was reached, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    /// the hex-encoded ID of the message's connection, which is the same at
+    /// both of its ends.
+    pub connection: String,
+    /// the message's nonce on its connection.
+    pub nonce: u64,
+    pub stage: Stage,
+}
+
+/// the number of events which may wait to be written to an audit log file;
+/// more are dropped.
+const FILE_QUEUE_LEN: usize = 4096;
+
+enum Sink {
+    Channel(UnboundedSender<AuditEvent>),
+    /// hands the events to the thread writing the file, counting the ones
+    /// dropped because it fell behind.
+    File {
+        tx: SyncSender<AuditEvent>,
+        dropped: Arc<AtomicU64>,
+    },
+}
+
+/// AuditLog records the lifecycle of the messages sent and received over a
+/// transport's connections, keyed by connection and nonce, so that it can
+/// be reconstructed where a stalled connection's messages got stuck. Only
+/// the messages of substreams are recorded, not handshakes or acks.
+/// Enable it with `NymTransportConfig::with_audit_log`; one log may be
+/// shared by several transports.
+#[derive(Clone)]
+pub struct AuditLog {
+    sink: Arc<Sink>,
+}
+
+impl Debug for AuditLog {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let sink = match *self.sink {
+            Sink::Channel(_) => "channel",
+            Sink::File { .. } => "file",
+        };
+        f.debug_struct("AuditLog").field("sink", &sink).finish()
+    }
+}
+
+impl AuditLog {
+    /// returns a log which sends its events to the returned receiver.
+    pub fn channel() -> (Self, UnboundedReceiver<AuditEvent>) {
+        let (tx, rx) = unbounded_channel();
+        let log = AuditLog {
+            sink: Arc::new(Sink::Channel(tx)),
+        };
+        (log, rx)
+    }
+
+    /// returns a log which appends its events to the file at `path`, one
+    /// JSON object per line. The file is created if it doesn't exist. It's
+    /// written by a thread of its own, so that the transport never waits for
+    /// the disk; if the thread falls behind, events are dropped, which it
+    /// logs a warning about.
+    pub fn file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::AuditLogIo)?;
+        let (tx, rx) = sync_channel(FILE_QUEUE_LEN);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer_dropped = dropped.clone();
+        thread::Builder::new()
+            .name("nym-audit-log".to_string())
+            .spawn(move || write_events(BufWriter::new(file), rx, writer_dropped))
+            .map_err(Error::AuditLogIo)?;
+        Ok(AuditLog {
+            sink: Arc::new(Sink::File { tx, dropped }),
+        })
+    }
+
+    pub(crate) fn record(&self, connection: &ConnectionId, nonce: u64, stage: Stage) {
+        let event = AuditEvent {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64),
+            connection: format!("{connection:?}"),
+            nonce,
+            stage,
+        };
+        match &*self.sink {
+            Sink::Channel(tx) => {
+                // the receiver may have been dropped, which is fine.
+                let _ = tx.send(event);
+            }
+            Sink::File { tx, dropped } => {
+                if let Err(TrySendError::Full(_)) = tx.try_send(event) {
+                    dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// records the stage of the message if it belongs to a substream, ie. if
+    /// it has a nonce.
+    pub(crate) fn record_message(&self, msg: &Message, stage: Stage) {
+        match msg {
+            Message::TransportMessage(msg) => self.record(&msg.id, msg.nonce, stage),
+            Message::EncryptedTransportMessage(msg) => self.record(&msg.id, msg.nonce, stage),
+            _ => {}
+        }
+    }
+}
+
+/// writes the events to the file until every AuditLog writing to it was
+/// dropped, flushing it whenever it caught up.
+fn write_events(mut file: BufWriter<File>, rx: Receiver<AuditEvent>, dropped: Arc<AtomicU64>) {
+    while let Ok(event) = rx.recv() {
+        let mut next = Some(event);
+        while let Some(event) = next {
+            let mut line = serde_json::to_vec(&event).unwrap_or_default();
+            line.push(b'\n');
+            if let Err(e) = file.write_all(&line) {
+                warn!("failed to write to the audit log: {}", e);
+            }
+            next = rx.try_recv().ok();
+        }
+        if let Err(e) = file.flush() {
+            warn!("failed to write to the audit log: {}", e);
+        }
+        let dropped = dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("the audit log fell behind and dropped {dropped} events");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{CongestionControl, NymTransportConfig};
+    use crate::test_utils::ConnectedPair;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let (audit_log, mut events) = AuditLog::channel();
+        let config = NymTransportConfig::default()
+            .with_audit_log(audit_log)
+            .with_congestion_control(CongestionControl {
+                ack_every: 1,
+                ..Default::default()
+            });
+        let mut pair = ConnectedPair::with_config(config).await.unwrap();
+        let (mut dialer, mut listener) = pair.open_substream().await.unwrap();
+        dialer.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        listener.read_exact(&mut buf).await.unwrap();
+
+        // both ends of the connection record the first message, up to the
+        // dialer receiving the listener's ack
+        let mut stages = HashSet::new();
+        let seen = async {
+            while let Some(event) = events.recv().await {
+                if event.nonce == 1 {
+                    stages.insert(event.stage);
+                }
+                if stages.contains(&Stage::Acked) {
+                    return;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), seen)
+            .await
+            .unwrap();
+        for stage in [Stage::Queued, Stage::Received, Stage::Delivered] {
+            assert!(stages.contains(&stage), "{stage:?} wasn't recorded");
+        }
+    }
+
+    #[test]
+    fn test_audit_log_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let audit_log = AuditLog::file(&path).unwrap();
+        let id = ConnectionId::generate();
+        audit_log.record(&id, 1, Stage::Queued);
+        audit_log.record(&id, 1, Stage::SendFailed);
+
+        // the events are written in the background
+        let mut contents = String::new();
+        for _ in 0..100 {
+            contents = std::fs::read_to_string(&path).unwrap();
+            if contents.lines().count() == 2 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["connection"], format!("{id:?}"));
+        assert_eq!(lines[0]["nonce"], 1);
+        assert_eq!(lines[1]["stage"], "send_failed");
+    }
+}
@@ -0,0 +1,212 @@
+use libp2p::core::PeerId;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::config::{BandwidthQuota, QuotaAction};
+
+/// BandwidthUsage counts the substream data exchanged with a peer, across
+/// all of its connections. Handshakes, acks and other control messages
+/// aren't counted, nor are retransmissions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BandwidthUsage {
+    /// bytes written to the peer's substreams.
+    pub sent: u64,
+    /// bytes received on the peer's substreams.
+    pub received: u64,
+}
+
+#[derive(Debug)]
+struct PeerState {
+    usage: BandwidthUsage,
+    /// bytes the peer may still exchange before its quota is exceeded; goes
+    /// negative when a frame overshoots it.
+    allowance: f64,
+    refilled_at: Instant,
+}
+
+/// PeerBandwidth is the ledger entry of one peer, shared by the ledger and
+/// the peer's connections and substreams.
+#[derive(Debug)]
+pub(crate) struct PeerBandwidth {
+    quota: Option<BandwidthQuota>,
+    state: Mutex<PeerState>,
+}
+
+impl PeerBandwidth {
+    fn new(quota: Option<BandwidthQuota>) -> Self {
+        PeerBandwidth {
+            state: Mutex::new(PeerState {
+                usage: BandwidthUsage::default(),
+                allowance: quota.map_or(0.0, |quota| quota.bytes_per_hour as f64),
+                refilled_at: Instant::now(),
+            }),
+            quota,
+        }
+    }
+
+    /// returns the state with the allowance refilled for the time passed
+    /// since it was last refilled, up to a full hour's worth.
+    fn refilled(&self) -> parking_lot::MutexGuard<'_, PeerState> {
+        let mut state = self.state.lock();
+        if let Some(quota) = self.quota {
+            let capacity = quota.bytes_per_hour as f64;
+            let elapsed = state.refilled_at.elapsed().as_secs_f64();
+            state.allowance = (state.allowance + elapsed * capacity / 3600.0).min(capacity);
+            state.refilled_at = Instant::now();
+        }
+        state
+    }
+
+    pub(crate) fn action(&self) -> Option<QuotaAction> {
+        self.quota.map(|quota| quota.action)
+    }
+
+    pub(crate) fn record_sent(&self, len: usize) {
+        let mut state = self.refilled();
+        state.usage.sent += len as u64;
+        state.allowance -= len as f64;
+    }
+
+    /// records received data, and returns whether the quota is exceeded.
+    pub(crate) fn record_received(&self, len: usize) -> bool {
+        let mut state = self.refilled();
+        state.usage.received += len as u64;
+        state.allowance -= len as f64;
+        self.quota.is_some() && state.allowance < 0.0
+    }
+
+    pub(crate) fn exceeded(&self) -> bool {
+        self.quota.is_some() && self.refilled().allowance < 0.0
+    }
+
+    /// returns how long it takes until the peer is within its quota again,
+    /// or None if it is.
+    pub(crate) fn throttle_delay(&self) -> Option<Duration> {
+        let quota = self.quota?;
+        let allowance = self.refilled().allowance;
+        if allowance >= 0.0 {
+            return None;
+        }
+        let rate = quota.bytes_per_hour.max(1) as f64 / 3600.0;
+        // at least a millisecond, so that the timer doesn't fire right away
+        Some(Duration::from_secs_f64(-allowance / rate).max(Duration::from_millis(1)))
+    }
+
+    fn is_idle(&self) -> bool {
+        let state = self.refilled();
+        match self.quota {
+            Some(quota) => state.allowance >= quota.bytes_per_hour as f64,
+            None => true,
+        }
+    }
+}
+
+/// BandwidthLedger tracks the substream data a transport exchanged with each
+/// remote peer and, if configured, holds each of them to a quota; see
+/// `NymTransportConfig::bandwidth_quota`. A handle can be obtained with
+/// `NymTransport::bandwidth()` before the transport is moved into a swarm.
+///
+/// A peer is forgotten once it has no open connections and its allowance
+/// has refilled, so that a peer can't escape its quota by reconnecting.
+/// Dialers appear as a new PeerId on every connection unless they enable
+/// `stable_identity`, so quotas only add up across the connections of
+/// those which do.
+#[derive(Clone, Debug, Default)]
+pub struct BandwidthLedger {
+    quota: Option<BandwidthQuota>,
+    peers: Arc<Mutex<HashMap<PeerId, Arc<PeerBandwidth>>>>,
+}
+
+impl BandwidthLedger {
+    pub(crate) fn new(quota: Option<BandwidthQuota>) -> Self {
+        BandwidthLedger {
+            quota,
+            peers: Arc::default(),
+        }
+    }
+
+    /// returns the usage of the given peer, if it's known.
+    pub fn usage(&self, peer_id: &PeerId) -> Option<BandwidthUsage> {
+        self.peers
+            .lock()
+            .get(peer_id)
+            .map(|peer| peer.refilled().usage)
+    }
+
+    /// returns the usage of all known peers.
+    pub fn all(&self) -> Vec<(PeerId, BandwidthUsage)> {
+        self.peers
+            .lock()
+            .iter()
+            .map(|(peer_id, peer)| (*peer_id, peer.refilled().usage))
+            .collect()
+    }
+
+    /// returns whether the given peer exceeded its quota.
+    pub fn exceeded(&self, peer_id: &PeerId) -> bool {
+        self.peers
+            .lock()
+            .get(peer_id)
+            .is_some_and(|peer| peer.exceeded())
+    }
+
+    /// returns the ledger entry of the given peer, which its connections
+    /// hold on to; entries nothing else holds are dropped once they're idle.
+    pub(crate) fn peer(&self, peer_id: PeerId) -> Arc<PeerBandwidth> {
+        let mut peers = self.peers.lock();
+        peers.retain(|_, peer| Arc::strong_count(peer) > 1 || !peer.is_idle());
+        peers
+            .entry(peer_id)
+            .or_insert_with(|| Arc::new(PeerBandwidth::new(self.quota)))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bandwidth_ledger() {
+        let ledger = BandwidthLedger::new(Some(BandwidthQuota {
+            bytes_per_hour: 3600,
+            action: QuotaAction::Throttle,
+        }));
+        let peer_id = PeerId::random();
+        let peer = ledger.peer(peer_id);
+        assert!(Arc::ptr_eq(&peer, &ledger.peer(peer_id)));
+
+        peer.record_sent(1000);
+        assert!(!peer.record_received(2600));
+        assert_eq!(
+            ledger.usage(&peer_id),
+            Some(BandwidthUsage {
+                sent: 1000,
+                received: 2600
+            })
+        );
+        assert_eq!(peer.throttle_delay(), None);
+
+        // the quota refills at a byte per second here, so the overshoot of
+        // 10 bytes takes about 10 seconds to make up for
+        assert!(peer.record_received(10));
+        assert!(ledger.exceeded(&peer_id));
+        let delay = peer.throttle_delay().unwrap();
+        assert!(delay > Duration::from_secs(9) && delay <= Duration::from_secs(10));
+
+        // a peer over its quota is remembered after its connections are gone
+        drop(peer);
+        ledger.peer(PeerId::random());
+        assert!(ledger.exceeded(&peer_id));
+
+        // while one without a quota is forgotten right away
+        let ledger = BandwidthLedger::default();
+        ledger.peer(peer_id).record_sent(10);
+        ledger.peer(PeerId::random());
+        assert_eq!(ledger.usage(&peer_id), None);
+    }
+}
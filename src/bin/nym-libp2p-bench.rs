@@ -0,0 +1,443 @@
+//! nym-libp2p-bench measures the transport's performance: it opens a number
+//! of connections to an echo server, opens substreams on each, writes
+//! messages of the given size at the given rate on every substream, and
+//! reports the throughput, loss and latency percentiles of the echoed
+//! messages.
+//!
+//! ```text
+//! nym-libp2p-bench [--connections N] [--substreams M] [--size BYTES] [--rate MSGS_PER_SEC]
+//!                  [--duration SECS] [--drain SECS] [--config FILE] [--listen | --remote ADDR]
+//! ```
+//!
+//! By default both the echo server and the client run in this process, on
+//! their own mixnet clients. `--listen` runs only the echo server and prints
+//! its address, and `--remote` runs only the client, against that address.
+//! The rate is per substream; the drain time is how long echoes are waited
+//! for after the last message was written, after which the missing ones
+//! count as lost.
+
+use futures::future::{self, poll_fn};
+use futures::prelude::*;
+use libp2p::core::muxing::StreamMuxerExt;
+use libp2p::core::transport::{DialOpts, PortUse, Transport, TransportEvent};
+use libp2p::core::{Endpoint, Multiaddr};
+use log::{info, warn};
+use rust_libp2p_nym::config::NymTransportConfig;
+use rust_libp2p_nym::config_file::ConfigFile;
+use rust_libp2p_nym::connection::Connection;
+use rust_libp2p_nym::error::Error;
+use rust_libp2p_nym::substream::Substream;
+use rust_libp2p_nym::transport::NymTransport;
+use std::pin::Pin;
+use std::process::ExitCode;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+const USAGE: &str = "usage: nym-libp2p-bench [--connections N] [--substreams M] [--size BYTES] \
+    [--rate MSGS_PER_SEC] [--duration SECS] [--drain SECS] [--config FILE] [--listen | --remote ADDR]";
+
+/// every message starts with its sequence number and the time it was
+/// written, in microseconds since the bench started.
+const HEADER_LEN: usize = 16;
+
+#[derive(Debug)]
+enum Mode {
+    Local,
+    Listen,
+    Remote(Multiaddr),
+}
+
+#[derive(Debug)]
+struct Args {
+    connections: usize,
+    substreams: usize,
+    size: usize,
+    rate: f64,
+    duration: Duration,
+    drain: Duration,
+    config: Option<String>,
+    mode: Mode,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        connections: 1,
+        substreams: 1,
+        size: 1024,
+        rate: 1.0,
+        duration: Duration::from_secs(30),
+        drain: Duration::from_secs(30),
+        config: None,
+        mode: Mode::Local,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{name} needs a value"));
+        fn number<T: std::str::FromStr>(name: &str, value: String) -> Result<T, String> {
+            value
+                .parse()
+                .map_err(|_| format!("invalid {name}: {value}"))
+        }
+        match arg.as_str() {
+            "--connections" => parsed.connections = number(&arg, value(&arg)?)?,
+            "--substreams" => parsed.substreams = number(&arg, value(&arg)?)?,
+            "--size" => parsed.size = number(&arg, value(&arg)?)?,
+            "--rate" => parsed.rate = number(&arg, value(&arg)?)?,
+            "--duration" => parsed.duration = Duration::from_secs(number(&arg, value(&arg)?)?),
+            "--drain" => parsed.drain = Duration::from_secs(number(&arg, value(&arg)?)?),
+            "--config" => parsed.config = Some(value(&arg)?),
+            "--listen" => parsed.mode = Mode::Listen,
+            "--remote" => {
+                let addr = value(&arg)?;
+                let addr = addr
+                    .parse()
+                    .map_err(|e| format!("invalid address {addr}: {e}"))?;
+                parsed.mode = Mode::Remote(addr);
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => return Err(format!("unexpected argument {arg}\n{USAGE}")),
+        }
+    }
+
+    if !(parsed.rate > 0.0) {
+        return Err("--rate must be positive".to_string());
+    }
+    parsed.size = parsed.size.max(HEADER_LEN);
+    Ok(parsed)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    pretty_env_logger::init();
+
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(2);
+        }
+    };
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            println!("bench failed: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let file = match &args.config {
+        Some(path) => ConfigFile::load(path)?,
+        None => ConfigFile::default(),
+    };
+    let config = file.transport_config();
+
+    let remote = match &args.mode {
+        Mode::Remote(addr) => addr.clone(),
+        Mode::Listen | Mode::Local => {
+            let mut server = new_transport(&file, config.clone()).await?;
+            let address = listen_address(&mut server).await;
+            println!("echo server listening on {address}");
+            let (_, dial_rx) = mpsc::unbounded_channel();
+            let server = tokio::spawn(drive_transport(server, dial_rx));
+            if matches!(args.mode, Mode::Listen) {
+                server.await?;
+                return Ok(());
+            }
+            address
+        }
+    };
+
+    let mut client = new_transport(&file, config).await?;
+    listen_address(&mut client).await;
+    let (dial_tx, dial_rx) = mpsc::unbounded_channel();
+    tokio::spawn(drive_transport(client, dial_rx));
+
+    let started = Instant::now();
+    let mut connections = vec![];
+    for _ in 0..args.connections {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        dial_tx.send((remote.clone(), reply_tx))?;
+        connections.push(reply_rx);
+    }
+    let mut substreams = vec![];
+    let mut drivers = vec![];
+    for connection in connections {
+        let mut connection = connection.await??;
+        for _ in 0..args.substreams {
+            substreams.push(poll_fn(|cx| poll_outbound(&mut connection, cx)).await?);
+        }
+        drivers.push(tokio::spawn(drive_connection(connection)));
+    }
+    println!(
+        "opened {} connection(s) with {} substream(s) each in {:?}",
+        args.connections,
+        args.substreams,
+        started.elapsed()
+    );
+
+    let bench_start = Instant::now();
+    let workers = substreams
+        .into_iter()
+        .map(|substream| tokio::spawn(run_substream(substream, bench_start, settings(&args))));
+    let mut report = Report::default();
+    for worker in future::join_all(workers).await {
+        report.merge(worker?);
+    }
+    for driver in drivers {
+        driver.abort();
+    }
+
+    report.print(&args);
+    Ok(())
+}
+
+async fn new_transport(
+    file: &ConfigFile,
+    config: NymTransportConfig,
+) -> Result<NymTransport, Error> {
+    let client = file.mixnet_client(&config)?.connect().await?;
+    let keypair = libp2p_identity::Keypair::generate_ed25519();
+    NymTransport::new_with_config(client, keypair, config).await
+}
+
+/// polls the transport until it reports the address it listens on.
+async fn listen_address(transport: &mut NymTransport) -> Multiaddr {
+    loop {
+        if let TransportEvent::NewAddress { listen_addr, .. } =
+            poll_fn(|cx| Pin::new(&mut *transport).poll(cx)).await
+        {
+            return listen_addr;
+        }
+    }
+}
+
+type DialRequest = (Multiaddr, oneshot::Sender<Result<Connection, String>>);
+
+/// drives the transport: incoming connections are echoed, and the addresses
+/// received over `dial_rx` are dialed.
+async fn drive_transport(
+    mut transport: NymTransport,
+    mut dial_rx: mpsc::UnboundedReceiver<DialRequest>,
+) {
+    let mut dialing = true;
+    loop {
+        tokio::select! {
+            event = poll_fn(|cx| Pin::new(&mut transport).poll(cx)) => {
+                if let TransportEvent::Incoming { upgrade, .. } = event {
+                    tokio::spawn(async move {
+                        match upgrade.await {
+                            Ok((peer_id, connection)) => {
+                                info!("accepted a connection from {peer_id}");
+                                serve_connection(connection).await;
+                            }
+                            Err(e) => warn!("failed to accept a connection: {e}"),
+                        }
+                    });
+                }
+            }
+            request = dial_rx.recv(), if dialing => {
+                let Some((addr, reply_tx)) = request else {
+                    dialing = false;
+                    continue;
+                };
+                let dial_opts = DialOpts {
+                    role: Endpoint::Dialer,
+                    port_use: PortUse::Reuse,
+                };
+                match transport.dial(addr, dial_opts) {
+                    Ok(dial) => {
+                        tokio::spawn(async move {
+                            let res = dial.await.map(|(_, connection)| connection);
+                            let _ = reply_tx.send(res.map_err(|e| e.to_string()));
+                        });
+                    }
+                    Err(e) => {
+                        let _ = reply_tx.send(Err(e.to_string()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// handles the connection's events, which needs to happen while substreams
+/// are opened and used.
+fn poll_events(connection: &mut Connection, cx: &mut Context<'_>) -> Result<(), Error> {
+    while let Poll::Ready(event) = connection.poll_unpin(cx) {
+        event?;
+    }
+    Ok(())
+}
+
+fn poll_outbound(
+    connection: &mut Connection,
+    cx: &mut Context<'_>,
+) -> Poll<Result<Substream, Error>> {
+    poll_events(connection, cx)?;
+    connection.poll_outbound_unpin(cx)
+}
+
+fn poll_inbound(
+    connection: &mut Connection,
+    cx: &mut Context<'_>,
+) -> Poll<Result<Substream, Error>> {
+    poll_events(connection, cx)?;
+    connection.poll_inbound_unpin(cx)
+}
+
+/// keeps the connection's events handled until it fails.
+async fn drive_connection(mut connection: Connection) {
+    let res: Result<(), Error> = poll_fn(|cx| match poll_events(&mut connection, cx) {
+        Ok(()) => Poll::Pending,
+        Err(e) => Poll::Ready(Err(e)),
+    })
+    .await;
+    if let Err(e) = res {
+        warn!("connection failed: {e}");
+    }
+}
+
+/// echoes everything written on the connection's substreams.
+async fn serve_connection(mut connection: Connection) {
+    loop {
+        match poll_fn(|cx| poll_inbound(&mut connection, cx)).await {
+            Ok(substream) => {
+                tokio::spawn(echo(substream));
+            }
+            Err(e) => {
+                warn!("connection failed: {e}");
+                return;
+            }
+        }
+    }
+}
+
+async fn echo(mut substream: Substream) {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = match substream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+        if substream.write_all(&buf[..n]).await.is_err() || substream.flush().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Settings {
+    size: usize,
+    interval: Duration,
+    duration: Duration,
+    drain: Duration,
+}
+
+fn settings(args: &Args) -> Settings {
+    Settings {
+        size: args.size,
+        interval: Duration::from_secs_f64(1.0 / args.rate),
+        duration: args.duration,
+        drain: args.drain,
+    }
+}
+
+/// writes messages on the substream for the bench's duration, and reads
+/// their echoes until the drain time is over.
+async fn run_substream(substream: Substream, bench_start: Instant, settings: Settings) -> Report {
+    let (mut reader, mut writer) = substream.split();
+    let mut report = Report::default();
+
+    let write = async {
+        let mut sent: u64 = 0;
+        let mut interval = tokio::time::interval(settings.interval);
+        let mut msg = vec![0; settings.size];
+        while bench_start.elapsed() < settings.duration {
+            interval.tick().await;
+            msg[..8].copy_from_slice(&sent.to_be_bytes());
+            let written_at = bench_start.elapsed().as_micros() as u64;
+            msg[8..HEADER_LEN].copy_from_slice(&written_at.to_be_bytes());
+            if writer.write_all(&msg).await.is_err() || writer.flush().await.is_err() {
+                break;
+            }
+            sent += 1;
+        }
+        sent
+    };
+
+    // the samples are kept outside of the read, so that they survive the
+    // timeout which ends it
+    let mut latencies = vec![];
+    let read = async {
+        let mut msg = vec![0; settings.size];
+        while reader.read_exact(&mut msg).await.is_ok() {
+            let written_at = u64::from_be_bytes(msg[8..HEADER_LEN].try_into().unwrap());
+            latencies.push(bench_start.elapsed() - Duration::from_micros(written_at));
+        }
+    };
+    let deadline = tokio::time::Instant::from_std(bench_start + settings.duration + settings.drain);
+    let read = async {
+        // the read never finishes by itself while the connection lives
+        let _ = tokio::time::timeout_at(deadline, read).await;
+    };
+
+    let (sent, ()) = future::join(write, read).await;
+    report.sent = sent;
+    report.received = latencies.len() as u64;
+    report.received_bytes = latencies.len() as u64 * settings.size as u64;
+    report.latencies = latencies;
+    report
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    sent: u64,
+    received: u64,
+    received_bytes: u64,
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.sent += other.sent;
+        self.received += other.received;
+        self.received_bytes += other.received_bytes;
+        self.latencies.extend(other.latencies);
+    }
+
+    fn print(mut self, args: &Args) {
+        let lost = self.sent.saturating_sub(self.received);
+        println!(
+            "sent {} messages of {} bytes, received {} echoes, lost {} ({:.1}%)",
+            self.sent,
+            args.size,
+            self.received,
+            lost,
+            100.0 * lost as f64 / self.sent.max(1) as f64
+        );
+        println!(
+            "throughput: {:.1} KiB/s",
+            self.received_bytes as f64 / 1024.0 / args.duration.as_secs_f64()
+        );
+
+        self.latencies.sort();
+        if self.latencies.is_empty() {
+            return;
+        }
+        println!(
+            "latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            percentile(&self.latencies, 50),
+            percentile(&self.latencies, 90),
+            percentile(&self.latencies, 99),
+            self.latencies[self.latencies.len() - 1]
+        );
+    }
+}
+
+/// returns the given percentile of the sorted, non-empty latencies.
+fn percentile(sorted: &[Duration], percentile: usize) -> Duration {
+    let rank = (sorted.len() * percentile).div_ceil(100);
+    sorted[rank.saturating_sub(1).min(sorted.len() - 1)]
+}
@@ -0,0 +1,164 @@
+//! nym-libp2p-probe checks whether a peer is reachable through the mixnet:
+//! it dials the given `/nym/...` address, pings the peer over the connection
+//! and prints how long each step took. The peer has to support the libp2p
+//! ping protocol, like the `ping` example does.
+//!
+//! ```text
+//! nym-libp2p-probe <address> [--count N] [--timeout SECS] [--config FILE]
+//! ```
+//!
+//! Exits with 0 if every ping was answered, and 1 otherwise.
+
+use futures::prelude::*;
+use libp2p::swarm::SwarmEvent;
+use libp2p::{ping, Multiaddr, SwarmBuilder};
+use libp2p_identity::Keypair;
+use rust_libp2p_nym::config_file::ConfigFile;
+use rust_libp2p_nym::transport::NymTransport;
+use std::error::Error;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+const USAGE: &str =
+    "usage: nym-libp2p-probe <address> [--count N] [--timeout SECS] [--config FILE]";
+
+struct Args {
+    address: Multiaddr,
+    /// the number of pings to send.
+    count: usize,
+    /// how long the whole probe may take, including connecting to the mixnet.
+    timeout: Duration,
+    config: Option<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut address = None;
+    let mut count = 3;
+    let mut timeout = Duration::from_secs(300);
+    let mut config = None;
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{name} needs a value"));
+        match arg.as_str() {
+            "--count" => {
+                count = value("--count")?
+                    .parse()
+                    .map_err(|e| format!("invalid --count: {e}"))?;
+            }
+            "--timeout" => {
+                let secs = value("--timeout")?
+                    .parse()
+                    .map_err(|e| format!("invalid --timeout: {e}"))?;
+                timeout = Duration::from_secs(secs);
+            }
+            "--config" => config = Some(value("--config")?),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if address.is_none() => {
+                address = Some(
+                    arg.parse()
+                        .map_err(|e| format!("invalid address {arg}: {e}"))?,
+                );
+            }
+            _ => return Err(format!("unexpected argument {arg}")),
+        }
+    }
+
+    Ok(Args {
+        address: address.ok_or(USAGE.to_string())?,
+        count: count.max(1),
+        timeout,
+        config,
+    })
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    pretty_env_logger::init();
+
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match tokio::time::timeout(args.timeout, probe(&args)).await {
+        Ok(Ok(())) => ExitCode::SUCCESS,
+        Ok(Err(e)) => {
+            println!("probe failed: {e}");
+            ExitCode::FAILURE
+        }
+        Err(_) => {
+            println!("probe timed out after {:?}", args.timeout);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn probe(args: &Args) -> Result<(), Box<dyn Error>> {
+    let file = match &args.config {
+        Some(path) => ConfigFile::load(path)?,
+        None => ConfigFile::default(),
+    };
+    let config = file.transport_config();
+    // unless the file says otherwise, the client is ephemeral, as a probe has
+    // no use for a stable address
+    let client = file.mixnet_client(&config)?;
+
+    let local_key = Keypair::generate_ed25519();
+    let started = Instant::now();
+    let client = client.connect().await?;
+    println!("connected to the mixnet in {:?}", started.elapsed());
+
+    let transport = NymTransport::new_with_config(client, local_key.clone(), config).await?;
+    let mut swarm = SwarmBuilder::with_existing_identity(local_key)
+        .with_tokio()
+        .with_other_transport(|_| transport)?
+        .with_behaviour(|_| {
+            ping::Behaviour::new(
+                ping::Config::new()
+                    .with_interval(Duration::from_secs(1))
+                    .with_timeout(args.timeout),
+            )
+        })?
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(args.timeout))
+        .build();
+
+    let dialed = Instant::now();
+    swarm.dial(args.address.clone())?;
+    println!("dialing {}", args.address);
+
+    let mut rtts = vec![];
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                println!("connected to {peer_id} in {:?}", dialed.elapsed());
+            }
+            SwarmEvent::OutgoingConnectionError { error, .. } => {
+                return Err(format!("failed to connect: {error}").into());
+            }
+            SwarmEvent::ConnectionClosed { cause, .. } => {
+                return Err(format!("connection closed: {cause:?}").into());
+            }
+            SwarmEvent::Behaviour(ping::Event { result, .. }) => {
+                let rtt = result.map_err(|e| format!("ping failed: {e}"))?;
+                println!("ping {}: {:?}", rtts.len() + 1, rtt);
+                rtts.push(rtt);
+                if rtts.len() >= args.count {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let total: Duration = rtts.iter().sum();
+    println!(
+        "{} pings: min {:?}, avg {:?}, max {:?}",
+        rtts.len(),
+        rtts.iter().min().unwrap(),
+        total / rtts.len() as u32,
+        rtts.iter().max().unwrap()
+    );
+    Ok(())
+}
@@ -0,0 +1,389 @@
+use futures::prelude::*;
+use libp2p::core::{multiaddr::Multiaddr, transport::PortUse, Endpoint};
+use libp2p::swarm::{
+    behaviour::{ConnectionClosed, ConnectionEstablished, DialFailure},
+    dial_opts::DialOpts,
+    dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent,
+    THandlerOutEvent, ToSwarm,
+};
+use libp2p_identity::PeerId;
+use log::debug;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use tokio::time::{sleep, Sleep};
+
+use super::config::RetryPolicy;
+
+/// Config lists the bootstrap peers and how they're dialed.
+#[derive(Clone, Debug)]
+pub struct Config {
+    peers: Vec<Multiaddr>,
+    retry: RetryPolicy,
+    max_attempts: Option<u32>,
+    reconnect: bool,
+}
+
+impl Config {
+    /// returns a config which dials the given `/nym` addresses until each of
+    /// them is connected, backing off from 5 to 60 seconds between attempts.
+    pub fn new(peers: impl IntoIterator<Item = Multiaddr>) -> Self {
+        Config {
+            peers: peers.into_iter().collect(),
+            retry: RetryPolicy {
+                initial_backoff: Duration::from_secs(5),
+                max_backoff: Duration::from_secs(60),
+                multiplier: 2,
+            },
+            max_attempts: None,
+            reconnect: false,
+        }
+    }
+
+    /// the backoff between failed dials of a peer.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// the number of dials after which a peer is given up on; unlimited by default.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// whether a peer is dialed again when its connection closes. The swarm
+    /// closes connections which are idle, so this should only be enabled if
+    /// another behaviour keeps them alive.
+    pub fn with_reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+}
+
+/// Event reports the progress of dialing a bootstrap peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// the peer at the address is connected. This is the place to fetch
+    /// more peers from it, eg. with `discovery::Client::discover`.
+    Connected { peer_id: PeerId, address: Multiaddr },
+    /// the peer's connection closed; it's dialed again if the config says so.
+    Disconnected { peer_id: PeerId, address: Multiaddr },
+    /// the peer couldn't be dialed in the configured number of attempts, and
+    /// is given up on.
+    Unreachable { address: Multiaddr, error: String },
+}
+
+enum State {
+    /// waiting to be dialed on the next poll.
+    Idle,
+    Dialing(ConnectionId),
+    Connected(PeerId, ConnectionId),
+    Backoff(Pin<Box<Sleep>>),
+    /// given up on, or disconnected without reconnecting.
+    Done,
+}
+
+struct Peer {
+    address: Multiaddr,
+    state: State,
+    backoff: Duration,
+    attempts: u32,
+}
+
+/// Behaviour dials a list of bootstrap `/nym` addresses when the swarm
+/// starts, retrying each of them with backoff until it's connected, so that
+/// a node joins its network without addresses being passed around by hand.
+/// The peers' IDs needn't be known in advance: they're learnt from the
+/// handshake and reported with `Event::Connected`.
+pub struct Behaviour {
+    config: Config,
+    peers: Vec<Peer>,
+    events: VecDeque<ToSwarm<Event, THandlerInEvent<Self>>>,
+    waker: Option<Waker>,
+}
+
+impl Behaviour {
+    pub fn new(config: Config) -> Self {
+        let mut behaviour = Behaviour {
+            peers: vec![],
+            events: VecDeque::new(),
+            waker: None,
+            config,
+        };
+        for address in behaviour.config.peers.clone() {
+            behaviour.add_peer(address);
+        }
+        behaviour
+    }
+
+    /// adds a bootstrap peer, eg. one fetched from a peer already connected;
+    /// it's dialed on the next poll. Addresses already added are ignored.
+    pub fn add_peer(&mut self, address: Multiaddr) {
+        if self.peers.iter().any(|peer| peer.address == address) {
+            return;
+        }
+        self.peers.push(Peer {
+            address,
+            state: State::Idle,
+            backoff: self.config.retry.initial_backoff,
+            attempts: 0,
+        });
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// returns the connected bootstrap peers with their addresses.
+    pub fn connected(&self) -> impl Iterator<Item = (&PeerId, &Multiaddr)> {
+        self.peers.iter().filter_map(|peer| match &peer.state {
+            State::Connected(peer_id, _) => Some((peer_id, &peer.address)),
+            _ => None,
+        })
+    }
+
+    fn peer_mut(&mut self, connection_id: ConnectionId) -> Option<&mut Peer> {
+        self.peers.iter_mut().find(|peer| match peer.state {
+            State::Dialing(id) | State::Connected(_, id) => id == connection_id,
+            _ => false,
+        })
+    }
+
+    /// schedules the next dial of the peer, or gives up on it.
+    fn retry(&mut self, connection_id: ConnectionId, error: String) {
+        let retry = self.config.retry.clone();
+        let max_attempts = self.config.max_attempts;
+        let Some(peer) = self.peer_mut(connection_id) else {
+            return;
+        };
+        if max_attempts.is_some_and(|max| peer.attempts >= max) {
+            peer.state = State::Done;
+            let address = peer.address.clone();
+            self.events
+                .push_back(ToSwarm::GenerateEvent(Event::Unreachable {
+                    address,
+                    error,
+                }));
+            return;
+        }
+        debug!(
+            "failed to dial bootstrap peer {}, retrying in {:?}: {error}",
+            peer.address, peer.backoff
+        );
+        peer.state = State::Backoff(Box::pin(sleep(peer.backoff)));
+        peer.backoff = retry.next_backoff(peer.backoff);
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Event;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+        _: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished {
+                peer_id,
+                connection_id,
+                ..
+            }) => {
+                let initial_backoff = self.config.retry.initial_backoff;
+                let Some(peer) = self.peer_mut(connection_id) else {
+                    return;
+                };
+                peer.state = State::Connected(peer_id, connection_id);
+                peer.backoff = initial_backoff;
+                peer.attempts = 0;
+                let address = peer.address.clone();
+                self.events
+                    .push_back(ToSwarm::GenerateEvent(Event::Connected {
+                        peer_id,
+                        address,
+                    }));
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                connection_id,
+                ..
+            }) => {
+                let reconnect = self.config.reconnect;
+                let Some(peer) = self.peer_mut(connection_id) else {
+                    return;
+                };
+                peer.state = match reconnect {
+                    true => State::Backoff(Box::pin(sleep(peer.backoff))),
+                    false => State::Done,
+                };
+                let address = peer.address.clone();
+                self.events
+                    .push_back(ToSwarm::GenerateEvent(Event::Disconnected {
+                        peer_id,
+                        address,
+                    }));
+            }
+            FromSwarm::DialFailure(DialFailure {
+                connection_id,
+                error,
+                ..
+            }) => self.retry(connection_id, error.to_string()),
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        for peer in &mut self.peers {
+            if let State::Backoff(delay) = &mut peer.state {
+                if delay.as_mut().poll(cx).is_pending() {
+                    continue;
+                }
+            } else if !matches!(peer.state, State::Idle) {
+                continue;
+            }
+            let opts = DialOpts::unknown_peer_id()
+                .address(peer.address.clone())
+                .build();
+            peer.state = State::Dialing(opts.connection_id());
+            peer.attempts += 1;
+            self.events.push_back(ToSwarm::Dial { opts });
+        }
+
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => {
+                self.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use libp2p::core::ConnectedPoint;
+    use libp2p::swarm::DialError;
+
+    fn poll_event(behaviour: &mut Behaviour) -> Option<ToSwarm<Event, THandlerInEvent<Behaviour>>> {
+        match behaviour.poll(&mut Context::from_waker(noop_waker_ref())) {
+            Poll::Ready(event) => Some(event),
+            Poll::Pending => None,
+        }
+    }
+
+    fn poll_dial(behaviour: &mut Behaviour) -> ConnectionId {
+        match poll_event(behaviour) {
+            Some(ToSwarm::Dial { opts }) => opts.connection_id(),
+            _ => panic!("expected a dial"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap() {
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let mut behaviour = Behaviour::new(
+            Config::new([address.clone()])
+                .with_retry(RetryPolicy {
+                    initial_backoff: Duration::from_millis(10),
+                    max_backoff: Duration::from_millis(20),
+                    multiplier: 2,
+                })
+                .with_max_attempts(3),
+        );
+        behaviour.add_peer(address.clone());
+        assert_eq!(behaviour.peers.len(), 1);
+
+        // a failed dial is retried once the backoff has passed
+        let connection_id = poll_dial(&mut behaviour);
+        behaviour.on_swarm_event(FromSwarm::DialFailure(DialFailure {
+            peer_id: None,
+            error: &DialError::Aborted,
+            connection_id,
+        }));
+        assert!(poll_event(&mut behaviour).is_none());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let connection_id = poll_dial(&mut behaviour);
+
+        let peer_id = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer {
+            address: address.clone(),
+            role_override: Endpoint::Dialer,
+            port_use: PortUse::New,
+        };
+        behaviour.on_swarm_event(FromSwarm::ConnectionEstablished(ConnectionEstablished {
+            peer_id,
+            connection_id,
+            endpoint: &endpoint,
+            failed_addresses: &[],
+            other_established: 0,
+        }));
+        match poll_event(&mut behaviour) {
+            Some(ToSwarm::GenerateEvent(event)) => assert_eq!(
+                event,
+                Event::Connected {
+                    peer_id,
+                    address: address.clone()
+                }
+            ),
+            _ => panic!("expected Event::Connected"),
+        }
+        assert_eq!(behaviour.connected().count(), 1);
+
+        // peers are given up on after the configured number of attempts
+        let other: Multiaddr = "/ip4/127.0.0.1/tcp/2".parse().unwrap();
+        behaviour.add_peer(other.clone());
+        for attempt in 0..3 {
+            let connection_id = poll_dial(&mut behaviour);
+            behaviour.on_swarm_event(FromSwarm::DialFailure(DialFailure {
+                peer_id: None,
+                error: &DialError::Aborted,
+                connection_id,
+            }));
+            if attempt < 2 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+        match poll_event(&mut behaviour) {
+            Some(ToSwarm::GenerateEvent(Event::Unreachable { address, .. })) => {
+                assert_eq!(address, other)
+            }
+            _ => panic!("expected Event::Unreachable"),
+        }
+        assert!(poll_event(&mut behaviour).is_none());
+    }
+}
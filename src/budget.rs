@@ -0,0 +1,276 @@
+use parking_lot::Mutex;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use super::metrics::TransportMetrics;
+
+/// BufferKind is what a buffered byte is held for, which decides how much of
+/// the memory budget it may use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BufferKind {
+    /// messages held back until the ones before them arrive. They're the
+    /// lowest priority, and may only fill half of the budget.
+    HeldBack,
+    /// data received on a substream which the application hasn't read yet.
+    Unread,
+    /// data sent on a connection which the remote hasn't acknowledged yet.
+    Unacked,
+}
+
+/// MemoryBudget accounts for the bytes buffered across all connections of a
+/// transport: messages held back for reordering, data received but not read
+/// yet, and data written but not handed to the mixnet client yet.
+///
+/// If it has a limit, buffering inbound data which would exceed its kind's
+/// share of it is refused, and the data is dropped by the caller. Writes
+/// instead wait until there's room again; a substream with nothing unsent
+/// may always write one frame though, so that writes can't get stuck.
+///
+/// Each connection is accounted to its own share of the transport's budget,
+/// see `share`, so that a single peer can't use all of it and starve the
+/// others.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryBudget {
+    used: AtomicUsize,
+    limit: Option<usize>,
+    /// writers waiting for room in the budget.
+    waiters: Mutex<Vec<Waker>>,
+    metrics: Arc<TransportMetrics>,
+    /// the transport's budget, if this is a connection's share of it. Bytes
+    /// are reserved in both, and must fit in both.
+    parent: Option<Arc<MemoryBudget>>,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limit: Option<usize>, metrics: Arc<TransportMetrics>) -> Self {
+        MemoryBudget {
+            limit,
+            metrics,
+            ..Default::default()
+        }
+    }
+
+    /// share returns a budget for a single connection, which may use up to
+    /// `limit` bytes of this one.
+    pub(crate) fn share(self: &Arc<Self>, limit: Option<usize>) -> Arc<MemoryBudget> {
+        Arc::new(MemoryBudget {
+            limit,
+            metrics: self.metrics.clone(),
+            parent: Some(self.clone()),
+            ..Default::default()
+        })
+    }
+
+    /// returns the number of bytes currently buffered.
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// reserves `len` bytes of the given kind, unless that would exceed the
+    /// share of the budget the kind may use.
+    pub(crate) fn try_reserve(&self, kind: BufferKind, len: usize) -> bool {
+        let Some(limit) = self.limit else {
+            self.reserve_own(len);
+            return self.try_reserve_parent(kind, len);
+        };
+        let share = match kind {
+            BufferKind::HeldBack => limit / 2,
+            BufferKind::Unread | BufferKind::Unacked => limit,
+        };
+
+        let reserved = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                (used + len <= share).then_some(used + len)
+            });
+        match reserved {
+            Ok(used) => {
+                self.set_buffered_bytes(used + len);
+                self.try_reserve_parent(kind, len)
+            }
+            Err(_) => {
+                TransportMetrics::inc(&self.metrics.buffer_budget_exceeded);
+                false
+            }
+        }
+    }
+
+    /// reserves `len` bytes in the transport's budget as well, and takes them
+    /// back from this share if they don't fit there.
+    fn try_reserve_parent(&self, kind: BufferKind, len: usize) -> bool {
+        let Some(parent) = &self.parent else {
+            return true;
+        };
+        if parent.try_reserve(kind, len) {
+            return true;
+        }
+        self.release_own(len);
+        false
+    }
+
+    /// reserves `len` bytes regardless of the limit, eg. for a write which
+    /// already waited for room with `poll_ready`.
+    pub(crate) fn reserve(&self, len: usize) {
+        self.reserve_own(len);
+        if let Some(parent) = &self.parent {
+            parent.reserve(len);
+        }
+    }
+
+    fn reserve_own(&self, len: usize) {
+        let used = self.used.fetch_add(len, Ordering::SeqCst) + len;
+        self.set_buffered_bytes(used);
+    }
+
+    /// returns `len` reserved bytes to the budget.
+    pub(crate) fn release(&self, len: usize) {
+        if len == 0 {
+            return;
+        }
+        self.release_own(len);
+        if let Some(parent) = &self.parent {
+            parent.release(len);
+        }
+    }
+
+    fn release_own(&self, len: usize) {
+        let used = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used.saturating_sub(len))
+            })
+            .unwrap()
+            .saturating_sub(len);
+        self.set_buffered_bytes(used);
+        if self.limit.is_none_or(|limit| used < limit) {
+            for waker in self.waiters.lock().drain(..) {
+                waker.wake();
+            }
+        }
+    }
+
+    /// only the transport's budget is reported; its shares add up to it.
+    fn set_buffered_bytes(&self, used: usize) {
+        if self.parent.is_none() {
+            TransportMetrics::set(&self.metrics.buffered_bytes, used as u64);
+        }
+    }
+
+    /// registers the task to be woken once bytes are released, eg. by a
+    /// reader which made room in its substream's buffer.
+    pub(crate) fn register_waiter(&self, cx: &mut Context<'_>) {
+        self.waiters.lock().push(cx.waker().clone());
+        if let Some(parent) = &self.parent {
+            parent.register_waiter(cx);
+        }
+    }
+
+    /// subtracts up to `len` bytes from the given per-substream counter, and
+    /// releases as many from the budget; the counter and the budget may race
+    /// when a substream is dropped while data arrives for it.
+    pub(crate) fn release_from(&self, counter: &AtomicUsize, len: usize) {
+        let before = counter
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                Some(n.saturating_sub(len))
+            })
+            .unwrap();
+        self.release(before.min(len));
+    }
+
+    /// polls until there's room in the budget for more written data.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(limit) = self.limit {
+            if self.used() >= limit {
+                self.waiters.lock().push(cx.waker().clone());
+                // room may have been released before the waker was registered
+                if self.used() >= limit {
+                    return Poll::Pending;
+                }
+            }
+        }
+        match &self.parent {
+            Some(parent) => parent.poll_ready(cx),
+            None => Poll::Ready(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    #[test]
+    fn test_memory_budget() {
+        let metrics = Arc::new(TransportMetrics::default());
+        let budget = MemoryBudget::new(Some(100), metrics.clone());
+
+        // held-back messages may only use half of the budget
+        assert!(budget.try_reserve(BufferKind::HeldBack, 50));
+        assert!(!budget.try_reserve(BufferKind::HeldBack, 1));
+        assert!(budget.try_reserve(BufferKind::Unread, 50));
+        assert!(!budget.try_reserve(BufferKind::Unread, 1));
+        assert_eq!(metrics.snapshot().buffered_bytes, 100);
+        assert_eq!(metrics.snapshot().buffer_budget_exceeded, 2);
+
+        // writes wait for room
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(budget.poll_ready(&mut cx).is_pending());
+        budget.release(10);
+        assert!(budget.poll_ready(&mut cx).is_ready());
+        assert!(budget.waiters.lock().is_empty());
+
+        // a counter never releases more than it holds
+        let counter = AtomicUsize::new(20);
+        budget.release_from(&counter, 30);
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+        assert_eq!(budget.used(), 70);
+        assert_eq!(metrics.snapshot().buffered_bytes, 70);
+
+        // without a limit, everything is accounted but nothing is refused
+        let unlimited = MemoryBudget::default();
+        assert!(unlimited.try_reserve(BufferKind::HeldBack, usize::MAX / 2));
+        assert!(unlimited.poll_ready(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn test_memory_budget_share() {
+        let metrics = Arc::new(TransportMetrics::default());
+        let budget = Arc::new(MemoryBudget::new(Some(100), metrics.clone()));
+        let first = budget.share(Some(60));
+        let second = budget.share(Some(60));
+
+        // a connection can't use more than its share
+        assert!(first.try_reserve(BufferKind::Unread, 60));
+        assert!(!first.try_reserve(BufferKind::Unread, 1));
+        assert_eq!(budget.used(), 60);
+        assert_eq!(metrics.snapshot().buffered_bytes, 60);
+
+        // nor more than what's left of the transport's budget
+        assert!(!second.try_reserve(BufferKind::Unread, 50));
+        assert_eq!(second.used(), 0);
+        assert!(second.try_reserve(BufferKind::Unread, 40));
+        assert_eq!(metrics.snapshot().buffer_budget_exceeded, 2);
+
+        // a writer waits for room in its own share, and in the transport's
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(first.poll_ready(&mut cx).is_pending());
+        second.release(10);
+        assert!(first.poll_ready(&mut cx).is_pending());
+        first.release(10);
+        assert!(first.poll_ready(&mut cx).is_ready());
+        assert_eq!(budget.used(), 80);
+        assert_eq!(metrics.snapshot().buffered_bytes, 80);
+
+        // without a share, a connection is only bound by the transport's budget
+        let unbounded = budget.share(None);
+        assert!(unbounded.try_reserve(BufferKind::Unread, 20));
+        assert!(!unbounded.try_reserve(BufferKind::Unread, 1));
+        assert_eq!(unbounded.used(), 20);
+    }
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use super::config::NymTransportConfig;
+
+#[cfg(feature = "chaos")]
+use rand::Rng;
+
+/// Faults describes the fraction of messages, between 0 and 1, which are
+/// subjected to each fault in one direction. Every message is subjected to
+/// at most one fault, tried in the order of the fields.
+#[cfg(feature = "chaos")]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Faults {
+    /// the message is dropped, as if the mixnet lost it.
+    pub drop: f64,
+    /// the message is delivered twice.
+    pub duplicate: f64,
+    /// the message is held back for up to `max_delay`, so that it's
+    /// reordered with the ones after it.
+    pub delay: f64,
+    pub max_delay: Duration,
+    /// a random byte of the encoded message is flipped.
+    pub corrupt: f64,
+}
+
+/// FaultInjection selects the faults injected into the messages the
+/// transport sends and receives, to soak test its ordering, ack and
+/// reconnection logic without a hostile network. Only available with the
+/// `chaos` feature.
+#[cfg(feature = "chaos")]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FaultInjection {
+    pub outbound: Faults,
+    pub inbound: Faults,
+}
+
+/// Fault is what happens to a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "chaos"), allow(dead_code))]
+pub(crate) enum Fault {
+    Drop,
+    Duplicate,
+    Delay(Duration),
+    Corrupt,
+}
+
+#[cfg(feature = "chaos")]
+impl Faults {
+    fn pick(&self) -> Option<Fault> {
+        let mut rng = rand::thread_rng();
+        if rng.gen_bool(self.drop.clamp(0.0, 1.0)) {
+            return Some(Fault::Drop);
+        }
+        if rng.gen_bool(self.duplicate.clamp(0.0, 1.0)) {
+            return Some(Fault::Duplicate);
+        }
+        if rng.gen_bool(self.delay.clamp(0.0, 1.0)) {
+            return Some(Fault::Delay(self.max_delay.mul_f64(rng.gen())));
+        }
+        if rng.gen_bool(self.corrupt.clamp(0.0, 1.0)) {
+            return Some(Fault::Corrupt);
+        }
+        None
+    }
+}
+
+/// Chaos picks the faults the mixnet task injects, if any.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Chaos {
+    #[cfg(feature = "chaos")]
+    pub(crate) config: Option<FaultInjection>,
+}
+
+impl Chaos {
+    #[cfg_attr(not(feature = "chaos"), allow(unused_variables))]
+    pub(crate) fn new(config: &NymTransportConfig) -> Self {
+        Chaos {
+            #[cfg(feature = "chaos")]
+            config: config.fault_injection.clone(),
+        }
+    }
+
+    /// returns the fault to inject into the next outbound message.
+    pub(crate) fn outbound(&self) -> Option<Fault> {
+        #[cfg(feature = "chaos")]
+        if let Some(config) = &self.config {
+            return config.outbound.pick();
+        }
+        None
+    }
+
+    /// returns the fault to inject into the next inbound message.
+    pub(crate) fn inbound(&self) -> Option<Fault> {
+        #[cfg(feature = "chaos")]
+        if let Some(config) = &self.config {
+            return config.inbound.pick();
+        }
+        None
+    }
+}
+
+/// flips a random byte of the message.
+pub(crate) fn corrupt(bytes: &mut [u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    let i = rand::random::<usize>() % bytes.len();
+    bytes[i] ^= 0xff;
+}
+
+#[cfg(all(test, feature = "chaos"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fault_injection() {
+        let faults = Faults {
+            drop: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(faults.pick(), Some(Fault::Drop));
+
+        let chaos = Chaos {
+            config: Some(FaultInjection {
+                outbound: Faults {
+                    delay: 1.0,
+                    max_delay: Duration::from_millis(100),
+                    corrupt: 1.0,
+                    ..Default::default()
+                },
+                inbound: Faults::default(),
+            }),
+        };
+        for _ in 0..100 {
+            match chaos.outbound() {
+                Some(Fault::Delay(delay)) => assert!(delay <= Duration::from_millis(100)),
+                fault => panic!("expected Fault::Delay, got {fault:?}"),
+            }
+            assert_eq!(chaos.inbound(), None);
+        }
+        assert_eq!(Chaos::default().outbound(), None);
+
+        let mut bytes = vec![0; 4];
+        corrupt(&mut bytes);
+        assert_eq!(bytes.iter().filter(|b| **b == 0xff).count(), 1);
+    }
+}
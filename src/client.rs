@@ -0,0 +1,304 @@
+use libp2p::core::Multiaddr;
+use log::{info, warn};
+use nym_sdk::mixnet::{DebugConfig, MixnetClient, MixnetClientBuilder, StoragePaths};
+use nym_sdk::NymNetworkDetails;
+use rand::Rng;
+use std::{path::PathBuf, time::Duration};
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::config::{NymTransportConfig, RetryPolicy};
+use super::error::Error;
+use super::transport::nym_address_to_multiaddress;
+
+/// StartupEvent reports the progress of `ManagedMixnetClient::connect`, which
+/// can take a while, so that applications can show what's going on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StartupEvent {
+    /// the client's keys were loaded from the storage directory, or generated
+    /// if there weren't any yet or the client is ephemeral.
+    KeysLoaded { attempt: u32 },
+    /// the client is registering with a gateway: the given one, or one of
+    /// its own choice if `None`.
+    Registering {
+        attempt: u32,
+        gateway: Option<String>,
+    },
+    /// the attempt failed; it's retried after the given delay, unless the
+    /// attempts are used up.
+    AttemptFailed {
+        attempt: u32,
+        error: String,
+        retry_in: Option<Duration>,
+    },
+    /// the client registered with its gateway, and can be reached at the
+    /// given address, which the transport listens on.
+    Registered { attempt: u32, address: Multiaddr },
+}
+
+/// KeyStorage selects where a `ManagedMixnetClient` keeps its keys.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum KeyStorage {
+    /// the keys are generated for every connection and only kept in memory,
+    /// so nothing is written to disk and the client gets a new address every
+    /// time; for short-lived anonymous nodes and tests.
+    #[default]
+    Ephemeral,
+    /// the keys are kept in the given directory, so that the client keeps
+    /// its address across restarts.
+    Persistent(PathBuf),
+}
+
+/// ManagedMixnetClient connects the mixnet client a `NymTransport` is created
+/// with, retrying failed and stalled attempts with jittered backoff, so that
+/// applications don't have to. The client is built for the network, packet
+/// sizes and traffic profile of the transport's config.
+#[derive(Clone, Debug)]
+pub struct ManagedMixnetClient {
+    network_details: NymNetworkDetails,
+    debug_config: DebugConfig,
+    storage: KeyStorage,
+    /// the identity key of the gateway to register with, if not the client's choice.
+    pub(crate) gateway: Option<String>,
+    retry: RetryPolicy,
+    connect_timeout: Duration,
+    max_attempts: Option<u32>,
+    /// if set, the progress of `connect` is reported here.
+    progress_tx: Option<UnboundedSender<StartupEvent>>,
+}
+
+impl ManagedMixnetClient {
+    /// returns a client for the given transport config, with ephemeral keys.
+    /// Fails if the config's network can't be resolved.
+    pub fn new(config: &NymTransportConfig) -> Result<Self, Error> {
+        Ok(ManagedMixnetClient {
+            network_details: config.mixnet_network_details()?,
+            debug_config: config.mixnet_debug_config(),
+            storage: KeyStorage::Ephemeral,
+            gateway: None,
+            retry: RetryPolicy {
+                initial_backoff: Duration::from_secs(1),
+                max_backoff: Duration::from_secs(30),
+                multiplier: 2,
+            },
+            connect_timeout: Duration::from_secs(60),
+            max_attempts: Some(5),
+            progress_tx: None,
+        })
+    }
+
+    /// returns a client for the given transport config which never touches
+    /// the disk; the same as `new`, but explicit about it.
+    pub fn ephemeral(config: &NymTransportConfig) -> Result<Self, Error> {
+        Self::new(config)
+    }
+
+    /// returns a client for the given transport config which keeps its keys
+    /// in the given directory.
+    pub fn persistent(config: &NymTransportConfig, dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        Ok(Self::new(config)?.with_key_storage(KeyStorage::Persistent(dir.into())))
+    }
+
+    pub fn with_key_storage(mut self, storage: KeyStorage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// keeps the client's keys in the given directory; see `KeyStorage::Persistent`.
+    pub fn with_storage_dir(self, dir: impl Into<PathBuf>) -> Self {
+        self.with_key_storage(KeyStorage::Persistent(dir.into()))
+    }
+
+    pub fn is_ephemeral(&self) -> bool {
+        self.storage == KeyStorage::Ephemeral
+    }
+
+    pub fn with_gateway(mut self, gateway: impl Into<String>) -> Self {
+        self.gateway = Some(gateway.into());
+        self
+    }
+
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// `None` retries until an attempt succeeds.
+    pub fn with_max_attempts(mut self, attempts: Option<u32>) -> Self {
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// reports the progress of `connect` as `StartupEvent`s over the given channel.
+    pub fn with_progress(mut self, progress_tx: UnboundedSender<StartupEvent>) -> Self {
+        self.progress_tx = Some(progress_tx);
+        self
+    }
+
+    /// connects the client, retrying until an attempt succeeds or the
+    /// attempts are used up, in which case the last attempt's error is returned.
+    pub async fn connect(&self) -> Result<MixnetClient, Error> {
+        let mut backoff = self.retry.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            let e = match self.try_connect_reporting(attempt).await {
+                Ok(client) => {
+                    info!("connected to the mixnet after {} attempt(s)", attempt);
+                    return Ok(client);
+                }
+                Err(e) => e,
+            };
+            if self.max_attempts.is_some_and(|max| attempt >= max) {
+                self.report(StartupEvent::AttemptFailed {
+                    attempt,
+                    error: e.to_string(),
+                    retry_in: None,
+                });
+                return Err(e);
+            }
+
+            let delay = jittered(backoff);
+            self.report(StartupEvent::AttemptFailed {
+                attempt,
+                error: e.to_string(),
+                retry_in: Some(delay),
+            });
+            warn!(
+                "attempt {} to connect to the mixnet failed, retrying in {:?}: {}",
+                attempt, delay, e
+            );
+            tokio::time::sleep(delay).await;
+            backoff = self.retry.next_backoff(backoff);
+            attempt += 1;
+        }
+    }
+
+    /// makes a single attempt to connect the client, bounded by the connect timeout.
+    pub(crate) async fn try_connect(&self) -> Result<MixnetClient, Error> {
+        self.try_connect_reporting(1).await
+    }
+
+    async fn try_connect_reporting(&self, attempt: u32) -> Result<MixnetClient, Error> {
+        let connect = self.build_and_connect(attempt);
+        match tokio::time::timeout(self.connect_timeout, connect).await {
+            Ok(res) => res.map_err(|e| Error::MixnetConnectFailed(e.to_string())),
+            Err(_) => Err(Error::MixnetConnectFailed(format!(
+                "timed out after {:?}",
+                self.connect_timeout
+            ))),
+        }
+    }
+
+    async fn build_and_connect(&self, attempt: u32) -> Result<MixnetClient, nym_sdk::Error> {
+        // the builders for persistent and ephemeral clients are different types
+        let client = match &self.storage {
+            KeyStorage::Persistent(dir) => {
+                let storage_paths = StoragePaths::new_from_dir(dir)?;
+                let mut builder = MixnetClientBuilder::new_with_default_storage(storage_paths)
+                    .await?
+                    .network_details(self.network_details.clone())
+                    .debug_config(self.debug_config.clone());
+                self.report(StartupEvent::KeysLoaded { attempt });
+                if let Some(gateway) = &self.gateway {
+                    builder = builder.request_gateway(gateway.clone());
+                }
+                let client = builder.build()?;
+                self.report_registering(attempt);
+                client.connect_to_mixnet().await?
+            }
+            KeyStorage::Ephemeral => {
+                let mut builder = MixnetClientBuilder::new_ephemeral()
+                    .network_details(self.network_details.clone())
+                    .debug_config(self.debug_config.clone());
+                self.report(StartupEvent::KeysLoaded { attempt });
+                if let Some(gateway) = &self.gateway {
+                    builder = builder.request_gateway(gateway.clone());
+                }
+                let client = builder.build()?;
+                self.report_registering(attempt);
+                client.connect_to_mixnet().await?
+            }
+        };
+
+        if let Ok(address) = nym_address_to_multiaddress(*client.nym_address()) {
+            self.report(StartupEvent::Registered { attempt, address });
+        }
+        Ok(client)
+    }
+
+    fn report_registering(&self, attempt: u32) {
+        self.report(StartupEvent::Registering {
+            attempt,
+            gateway: self.gateway.clone(),
+        });
+    }
+
+    fn report(&self, event: StartupEvent) {
+        if let Some(progress_tx) = &self.progress_tx {
+            // the application may not care about progress anymore, which is fine.
+            let _ = progress_tx.send(event);
+        }
+    }
+}
+
+/// returns a random delay between half the given backoff and all of it, so
+/// that clients which failed together don't retry in lockstep.
+fn jittered(backoff: Duration) -> Duration {
+    backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_managed_mixnet_client_backoff() {
+        let backoff = Duration::from_secs(10);
+        for _ in 0..100 {
+            let delay = jittered(backoff);
+            assert!(delay >= backoff / 2 && delay <= backoff);
+        }
+        assert_eq!(jittered(Duration::ZERO), Duration::ZERO);
+
+        let client = ManagedMixnetClient::new(&NymTransportConfig::default())
+            .unwrap()
+            .with_gateway("gateway")
+            .with_max_attempts(None);
+        assert_eq!(client.gateway.as_deref(), Some("gateway"));
+        assert_eq!(client.max_attempts, None);
+        assert!(client.is_ephemeral());
+        let dir = PathBuf::from("keys");
+        let persistent = client.clone().with_storage_dir(&dir);
+        assert!(!persistent.is_ephemeral());
+        assert_eq!(persistent.storage, KeyStorage::Persistent(dir.clone()));
+        assert_eq!(
+            ManagedMixnetClient::persistent(&NymTransportConfig::default(), &dir)
+                .unwrap()
+                .storage,
+            KeyStorage::Persistent(dir)
+        );
+        assert!(
+            ManagedMixnetClient::ephemeral(&NymTransportConfig::default())
+                .unwrap()
+                .is_ephemeral()
+        );
+
+        // progress is only reported if asked for
+        client.report_registering(1);
+        let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+        let client = client.with_progress(progress_tx);
+        client.report_registering(2);
+        assert_eq!(
+            progress_rx.try_recv().unwrap(),
+            StartupEvent::Registering {
+                attempt: 2,
+                gateway: Some("gateway".to_string()),
+            }
+        );
+        assert!(progress_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::error::Error;
+
+/// length of the random nonce `seal` puts in front of the ciphertext.
+pub(crate) const NONCE_LENGTH: usize = 12;
+
+/// returns the current unix time in seconds.
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// appends a field prefixed with its u32 length.
+pub(crate) fn put_length_prefixed(bytes: &mut Vec<u8>, field: &[u8]) {
+    bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(field);
+}
+
+/// Decoder splits the fields of an encoding off its front, failing with the
+/// encoding's own error if it's too short.
+pub(crate) struct Decoder<'a> {
+    bytes: &'a [u8],
+    invalid: fn() -> Error,
+}
+
+impl<'a> Decoder<'a> {
+    pub(crate) fn new(bytes: &'a [u8], invalid: fn() -> Error) -> Self {
+        Decoder { bytes, invalid }
+    }
+
+    /// splits `len` bytes off the front.
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < len {
+            return Err((self.invalid)());
+        }
+        let (field, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(field)
+    }
+
+    /// splits exactly `N` bytes off the front.
+    pub(crate) fn take_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub(crate) fn take_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn take_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.take_array()?))
+    }
+
+    pub(crate) fn take_u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_be_bytes(self.take_array()?))
+    }
+
+    /// splits a field written with `put_length_prefixed` off the front.
+    pub(crate) fn take_length_prefixed(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.take_u32()? as usize;
+        self.take(len)
+    }
+
+    /// splits a field prefixed with its u16 length, as the wire messages
+    /// encode them, off the front.
+    pub(crate) fn take_short_length_prefixed(&mut self) -> Result<&'a [u8], Error> {
+        let len = u16::from_be_bytes(self.take_array()?) as usize;
+        self.take(len)
+    }
+
+    /// returns the bytes which weren't taken yet.
+    pub(crate) fn rest(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+/// encrypts and authenticates `plaintext` with ChaCha20Poly1305, prefixed
+/// with a fresh random nonce, as the transport's files and tickets are.
+pub(crate) fn seal(key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(key.into())
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| Error::EncryptionFailure)?;
+
+    let mut bytes = nonce.to_vec();
+    bytes.extend_from_slice(&ciphertext);
+    Ok(bytes)
+}
+
+/// decrypts what `seal` returned; `None` if it's too short, or wasn't sealed
+/// with the same key and associated data.
+pub(crate) fn open(key: &[u8; 32], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LENGTH {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .ok()
+}
+
+/// replaces the file at `path` atomically, so a crash doesn't leave half of
+/// it behind.
+pub(crate) fn replace_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// reads the file at `path` and removes it, so that what's in it is never
+/// picked up twice; `None` if there's no file.
+pub(crate) fn take_file(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    fs::remove_file(path)?;
+    Ok(Some(bytes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decoder() {
+        let mut bytes = vec![7];
+        bytes.extend_from_slice(&9u64.to_be_bytes());
+        put_length_prefixed(&mut bytes, b"hello");
+        bytes.extend_from_slice(&2u16.to_be_bytes());
+        bytes.extend_from_slice(b"hi!");
+
+        let mut decoder = Decoder::new(&bytes, || Error::InvalidOutbox);
+        assert_eq!(decoder.take_u8().unwrap(), 7);
+        assert_eq!(decoder.take_u64().unwrap(), 9);
+        assert_eq!(decoder.take_length_prefixed().unwrap(), b"hello");
+        assert_eq!(decoder.take_short_length_prefixed().unwrap(), b"hi");
+        assert_eq!(decoder.rest(), b"!");
+
+        // a field running past the end fails with the encoding's error
+        assert!(matches!(decoder.take_u32(), Err(Error::InvalidOutbox)));
+        assert!(!decoder.is_empty());
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let sealed = seal(&[1u8; 32], b"aad", b"hello").unwrap();
+        assert!(!sealed.windows(5).any(|w| w == b"hello"));
+        assert_eq!(open(&[1u8; 32], b"aad", &sealed).unwrap(), b"hello");
+
+        // it only opens with the same key and associated data
+        assert!(open(&[2u8; 32], b"aad", &sealed).is_none());
+        assert!(open(&[1u8; 32], b"other", &sealed).is_none());
+        assert!(open(&[1u8; 32], b"aad", &sealed[..NONCE_LENGTH - 1]).is_none());
+    }
+}
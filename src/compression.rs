@@ -0,0 +1,183 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use super::config::Compression;
+use super::error::Error;
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+use super::message::MAX_MESSAGE_LEN;
+
+/// the first byte of data sent as it is, since compressing it didn't make it
+/// any smaller.
+const UNCOMPRESSED: u8 = 0;
+
+/// DataCodec compresses the data messages of a connection which negotiated
+/// compression, see `Compression`. The data of every message then starts
+/// with a byte saying how the rest of it is compressed: 0 if it isn't, or
+/// else the ID of the negotiated algorithm.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct DataCodec {
+    compression: Compression,
+}
+
+impl DataCodec {
+    pub(crate) fn new(compression: Compression) -> Self {
+        DataCodec { compression }
+    }
+
+    pub(crate) fn compress(&self, data: &[u8]) -> Bytes {
+        let compressed = compress(self.compression, data).filter(|c| c.len() < data.len());
+        let (method, body) = match &compressed {
+            Some(compressed) => (self.compression.algorithm().id(), &compressed[..]),
+            None => (UNCOMPRESSED, data),
+        };
+        let mut bytes = BytesMut::with_capacity(1 + body.len());
+        bytes.put_u8(method);
+        bytes.put_slice(body);
+        bytes.freeze()
+    }
+
+    pub(crate) fn decompress(&self, data: Bytes) -> Result<Bytes, Error> {
+        match data.first() {
+            Some(&UNCOMPRESSED) => Ok(data.slice(1..)),
+            Some(&method) if method == self.compression.algorithm().id() => {
+                decompress(self.compression, &data[1..]).map(Bytes::from)
+            }
+            _ => Err(Error::DecompressionFailure),
+        }
+    }
+}
+
+fn compress(compression: Compression, data: &[u8]) -> Option<Vec<u8>> {
+    match compression {
+        Compression::Lz4 => compress_lz4(data),
+        Compression::Zstd { level } => compress_zstd(data, level),
+    }
+}
+
+fn decompress(compression: Compression, data: &[u8]) -> Result<Vec<u8>, Error> {
+    match compression {
+        Compression::Lz4 => decompress_lz4(data),
+        Compression::Zstd { .. } => decompress_zstd(data),
+    }
+}
+
+#[cfg(feature = "lz4")]
+fn compress_lz4(data: &[u8]) -> Option<Vec<u8>> {
+    Some(lz4_flex::block::compress_prepend_size(data))
+}
+
+#[cfg(feature = "lz4")]
+fn decompress_lz4(data: &[u8]) -> Result<Vec<u8>, Error> {
+    // the decompressed size is prepended, and allocated up front
+    let size = data
+        .get(..4)
+        .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize)
+        .ok_or(Error::DecompressionFailure)?;
+    if size > MAX_MESSAGE_LEN {
+        return Err(Error::DecompressionFailure);
+    }
+    lz4_flex::block::decompress_size_prepended(data).map_err(|_| Error::DecompressionFailure)
+}
+
+#[cfg(not(feature = "lz4"))]
+fn compress_lz4(_data: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(not(feature = "lz4"))]
+fn decompress_lz4(_data: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Error::DecompressionFailure)
+}
+
+#[cfg(feature = "zstd")]
+fn compress_zstd(data: &[u8], level: i32) -> Option<Vec<u8>> {
+    zstd::bulk::compress(data, level).ok()
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_zstd(data: &[u8]) -> Result<Vec<u8>, Error> {
+    use std::io::Read;
+
+    // stop reading at the limit, rather than trusting the frame's header
+    let mut decompressed = vec![];
+    zstd::stream::read::Decoder::new(data)
+        .and_then(|decoder| {
+            decoder
+                .take(MAX_MESSAGE_LEN as u64 + 1)
+                .read_to_end(&mut decompressed)
+        })
+        .map_err(|_| Error::DecompressionFailure)?;
+    if decompressed.len() > MAX_MESSAGE_LEN {
+        return Err(Error::DecompressionFailure);
+    }
+    Ok(decompressed)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress_zstd(_data: &[u8], _level: i32) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_zstd(_data: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Error::DecompressionFailure)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::message::MAX_MESSAGE_LEN;
+    use super::*;
+
+    fn codecs() -> Vec<DataCodec> {
+        [Compression::Lz4, Compression::Zstd { level: 3 }]
+            .into_iter()
+            .map(DataCodec::new)
+            .collect()
+    }
+
+    #[test]
+    fn test_data_codec_round_trip() {
+        let data = b"hello hello hello hello hello hello hello hello".repeat(100);
+        for codec in codecs() {
+            let compressed = codec.compress(&data);
+            if codec.compression.is_available() {
+                assert!(compressed.len() < data.len() / 10, "{:?}", codec);
+                assert_eq!(compressed[0], codec.compression.algorithm().id());
+            } else {
+                assert_eq!(compressed[0], UNCOMPRESSED);
+            }
+            assert_eq!(codec.decompress(compressed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_data_codec_incompressible() {
+        let data: Vec<u8> = (0..64).map(|_| rand::random()).collect();
+        for codec in codecs() {
+            // data which doesn't shrink is sent as it is
+            let compressed = codec.compress(&data);
+            assert_eq!(compressed[0], UNCOMPRESSED);
+            assert_eq!(&compressed[1..], &data[..]);
+            assert_eq!(codec.decompress(compressed).unwrap(), data);
+            assert!(codec.decompress(Bytes::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_data_codec_rejects_invalid_data() {
+        let lz4 = DataCodec::new(Compression::Lz4);
+        let zstd = DataCodec::new(Compression::Zstd { level: 3 });
+        let data = b"hello hello hello hello hello hello hello hello".repeat(100);
+
+        // only the negotiated algorithm is accepted
+        let compressed = lz4.compress(&data);
+        if compressed[0] != UNCOMPRESSED {
+            assert!(zstd.decompress(compressed).is_err());
+        }
+
+        // nor is data claiming to be larger than a message can be
+        let mut oversized = vec![Compression::Lz4.algorithm().id()];
+        oversized.extend_from_slice(&(MAX_MESSAGE_LEN as u32 + 1).to_le_bytes());
+        oversized.extend_from_slice(&[0; 16]);
+        assert!(lz4.decompress(oversized.into()).is_err());
+    }
+}
@@ -0,0 +1,1994 @@
+#[cfg(feature = "nym-client")]
+use nym_sdk::mixnet::DebugConfig;
+#[cfg(feature = "nym-client")]
+use nym_sdk::{DenomDetailsOwned, NymNetworkDetails, ValidatorDetails};
+use nym_sphinx::params::PacketSize;
+#[cfg(feature = "nym-client")]
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+#[cfg(feature = "nym-client")]
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+use super::audit::AuditLog;
+#[cfg(feature = "chaos")]
+use super::chaos::FaultInjection;
+#[cfg(feature = "nym-client")]
+use super::error::Error;
+use super::gating::{PeerFilter, PreSharedKey};
+use super::message::{CipherSuite, CompressionAlgorithm, KeyUpdateLimits};
+use super::{
+    DEFAULT_HANDSHAKE_TIMEOUT_SECS, DEFAULT_MAX_FRAME_BYTES, DEFAULT_MAX_HANDSHAKE_AGE_SECS,
+    DEFAULT_MAX_OUTBOUND_BACKLOG, DEFAULT_MAX_SUBSTREAM_BUFFER_BYTES, DEFAULT_MAX_UNSENT_BYTES,
+};
+
+/// NymTransportConfig contains the tunable parameters of a `NymTransport`.
+#[derive(Clone, Debug)]
+pub struct NymTransportConfig {
+    /// Timeout for the connection handshake, ie. the dial future and
+    /// the [`crate::transport::Upgrade`] future.
+    pub handshake_timeout: Duration,
+
+    /// Maximum age of a signed `ConnectionRequest` or `ConnectionResponse`;
+    /// older ones are dropped to limit replays. Since the age is computed from
+    /// the sender's clock, this also bounds the clock skew tolerated between peers.
+    pub max_handshake_age: Duration,
+
+    /// If set, the transport talks to peers from before signed handshakes:
+    /// it accepts their unsigned `ConnectionRequest`s and answers them
+    /// unsigned, and dials with unsigned requests, which every listener
+    /// answers in kind. The remote's PeerId isn't proven on such
+    /// connections, and none of the connection options are negotiated.
+    /// Dials still sign their requests if payload encryption or a private
+    /// network needs them. Off by default, so that unsigned handshakes
+    /// are dropped.
+    pub unsigned_handshakes: bool,
+
+    /// If set, dialing a recipient which we already have an in-flight
+    /// outbound connection to fails immediately with
+    /// `Error::DialInProgress`, instead of starting a second handshake to the
+    /// same recipient. Dialing a recipient we're already connected to still
+    /// makes a new connection.
+    pub deduplicate_dials: bool,
+
+    /// If set, the `ConnectionRequest` of a dial is resent according to this
+    /// policy until the `ConnectionResponse` arrives or the handshake times out.
+    /// Since Nym only guarantees delivery on a best-effort basis, this avoids a
+    /// single lost packet failing the whole dial.
+    pub dial_retry: Option<RetryPolicy>,
+
+    /// If set, bounds the number of concurrent dials, so that applications
+    /// firing many dials at once don't flood the mixnet client.
+    pub dial_limits: Option<DialLimits>,
+
+    /// If set, only peers allowed by the filter may connect to us or be
+    /// connected to.
+    pub peer_filter: Option<PeerFilter>,
+
+    /// If set, the transport is in a private network: connection requests
+    /// from peers which don't know its key are dropped, and dials to them
+    /// fail; see `PreSharedKey`. All peers of the network must set it.
+    pub pre_shared_key: Option<PreSharedKey>,
+
+    /// If set, the payloads of all messages sent over a connection are encrypted
+    /// end-to-end with keys from an ephemeral X25519 exchange during the handshake,
+    /// so that gateways can't read them. Keys are rotated periodically and old
+    /// keys are erased. Both peers must enable this; connections to or from
+    /// peers which don't are refused.
+    pub encrypt_payloads: bool,
+
+    /// The cipher suites encrypted connections may use, in order of
+    /// preference. The listener of a connection picks the first of the
+    /// dialer's it supports as well. Offering anything but the default
+    /// X25519 with ChaCha20-Poly1305 takes a handshake extension, which
+    /// listeners from before it reject.
+    pub cipher_suites: Vec<CipherSuite>,
+
+    /// If set, the session keys of encrypted connections we dial are
+    /// replaced periodically, and those of connections dialed to us as
+    /// often as the dialer or we ask for; see `SessionRekeying`.
+    pub session_rekeying: Option<SessionRekeying>,
+
+    /// If set, outbound data which waited longer than this to be handed to the
+    /// mixnet client, eg. during a mixnet outage, is dropped instead of being
+    /// delivered late. Since messages are processed in order, the substream the
+    /// data belongs to is closed instead, and its local reads and writes fail
+    /// with `io::ErrorKind::TimedOut`. Control messages never expire.
+    pub message_ttl: Option<Duration>,
+
+    /// If set, connections are asked to deliver messages as they arrive
+    /// rather than in the order they were sent, which avoids a single delayed
+    /// message holding up all others, eg. for gossipsub or ping. Only used if
+    /// the remote enables it as well. Since ordering isn't guaranteed even
+    /// within a substream, data overtaking the opening of its substream, or
+    /// data overtaken by the closing of its substream, is dropped; this is
+    /// only suitable for protocols which tolerate that.
+    pub unordered_delivery: bool,
+
+    /// If set, connections are offered `ConnectionFlags::COMPACT_IDS`, which
+    /// sends connection and substream IDs in 8 instead of 32 bytes. Since an
+    /// ID is all that identifies a connection's unencrypted messages, the
+    /// shorter IDs are easier to guess; only enable it together with
+    /// `encrypt_payloads` or where the overhead matters more.
+    pub compact_ids: bool,
+
+    /// Maximum number of bytes received on a substream which may wait to be
+    /// read by the application. Data beyond it is held back until the reader
+    /// catches up, along with the connection's later messages, since they're
+    /// handled in order; a slow reader thus slows down the whole connection
+    /// rather than losing data. `None` disables the limit.
+    pub max_substream_buffer: Option<usize>,
+
+    /// If set, `NymTransport::backlog_overload` reports when more than this
+    /// many outbound messages are waiting to be handed to the mixnet client,
+    /// so that applications know to shed load, until the backlog has dropped
+    /// to half the threshold. The current backlog is always available in the
+    /// metrics.
+    pub outbound_backlog_threshold: Option<usize>,
+
+    /// Maximum number of outbound messages which may wait to be handed to
+    /// the mixnet client, across all connections. The client takes messages
+    /// only as fast as it can send them, so once the limit is reached,
+    /// substream writes wait until it caught up. Handshakes, acks and other
+    /// control messages aren't held back. If the mixnet task stops, waiting
+    /// writes fail instead. `None` disables the limit; the per-substream
+    /// `max_unsent_bytes` still applies.
+    pub max_outbound_backlog: Option<usize>,
+
+    /// Maximum number of bytes sent in a single data message. Larger writes
+    /// are accepted partially, so `write_all` streams them as multiple
+    /// messages instead of building one giant one. Must be non-zero.
+    pub max_frame_size: usize,
+
+    /// If set, each connection sizes its data messages by the loss it
+    /// observes, up to `max_frame_size`; see `AdaptiveFrameSize`. Only used on
+    /// connections which negotiated selective repeat, whose retransmissions
+    /// reveal the loss.
+    pub adaptive_frame_size: Option<AdaptiveFrameSize>,
+
+    /// Maximum number of bytes written to a substream which may wait to be
+    /// handed to the mixnet client. Writes beyond it wait until the mixnet
+    /// catches up, and flushing a substream waits until all of its data has
+    /// been handed over. `None` disables the limit.
+    pub max_unsent_bytes: Option<usize>,
+
+    /// Maximum number of bytes buffered across all connections: messages held
+    /// back for reordering, data not read by the application yet, and data not
+    /// handed to the mixnet client yet. Once it's reached, writes wait, and
+    /// inbound data is held back: messages held back for reordering, which may
+    /// only use half of it, close their connection, and data for the
+    /// application waits until a reader makes room.
+    /// `None` disables the limit; the per-substream limits still apply.
+    pub max_buffered_bytes: Option<usize>,
+
+    /// Maximum number of bytes a single connection may buffer out of
+    /// `max_buffered_bytes`, so that one peer can't use all of it and starve
+    /// the others. `None` allows each connection a quarter of
+    /// `max_buffered_bytes`, or no limit if that's `None` as well.
+    pub max_buffered_bytes_per_connection: Option<usize>,
+
+    /// Bounds how far messages may arrive out of order on a connection before
+    /// they're dropped or it's closed; see `ReorderWindow`. `None` lets a connection queue any
+    /// number of early messages, which a misbehaving peer could abuse.
+    pub reorder_window: Option<ReorderWindow>,
+
+    /// Sphinx packet sizes the mixnet client should use; see `PacketSizePolicy`.
+    /// Since the client is built before the transport, this only takes effect
+    /// if it's applied to the client's config with `mixnet_debug_config` or
+    /// `PacketSizePolicy::apply_to`.
+    pub packet_size: PacketSizePolicy,
+
+    /// Number of reply SURBs attached to the messages we send as the dialer of
+    /// a connection; see `ReplySurbs`.
+    pub reply_surbs: ReplySurbs,
+
+    /// If set, the inbound connections open when the transport is dropped are
+    /// saved, and picked up again by the next transport created with the same
+    /// settings; see `SessionPersistence`.
+    pub session_persistence: Option<SessionPersistence>,
+
+    /// If set, listeners issue session tickets for the connections dialed to
+    /// them, with which dialers resume a connection after a restart instead
+    /// of running the handshake again; see `SessionResumption`. Only used if
+    /// the remote enables it as well.
+    pub session_resumption: Option<SessionResumption>,
+
+    /// If set, substream data the mixnet client couldn't take, eg. during an
+    /// outage, is kept and sent again once it's back, and what's still
+    /// waiting when the transport shuts down is saved for the next one; see
+    /// `StoreAndForward`.
+    pub store_and_forward: Option<StoreAndForward>,
+
+    /// If set, the outbound data held back while the mixnet client is
+    /// disconnected from its gateway is bounded, and expires; see
+    /// `OfflineBuffer`. Otherwise it waits for as long as the outage lasts.
+    pub offline_buffer: Option<OfflineBuffer>,
+
+    /// If set, extra copies of critical control messages, eg. handshakes,
+    /// are sent, so that they're less likely to be lost; see `Redundancy`.
+    pub redundancy: Option<Redundancy>,
+
+    /// If set, groups of TransportMessages are followed by parity, from which
+    /// the remote recovers lost messages without waiting for a retransmission;
+    /// see `ForwardErrorCorrection`. Only used if the remote enables it as well.
+    pub forward_error_correction: Option<ForwardErrorCorrection>,
+
+    /// The algorithms data may be compressed with, in order of preference;
+    /// see `Compression`. The listener of a connection picks the first of the
+    /// dialer's it supports as well. Empty by default, which leaves data
+    /// uncompressed.
+    pub compression: Vec<Compression>,
+
+    /// If set, every message we send is padded to one of a few sizes, so that
+    /// the sizes of the application's messages don't show through to the
+    /// gateways and mix nodes; see `MessagePadding`. Peers from before it
+    /// can't decode padded messages.
+    pub message_padding: Option<MessagePadding>,
+
+    /// If set, established connections carry dummy messages at random
+    /// intervals, so that idle connections look like busy ones; see
+    /// `CoverTraffic`. Only used if the remote enables it as well.
+    pub cover_traffic: Option<CoverTraffic>,
+
+    /// If set, ConnectionResponses and the responses to substreams the
+    /// remote opens are held back for a random time, so that they can't be
+    /// matched to the messages they answer by timing; see `ResponseDelay`.
+    /// Off by default, since every handshake and substream takes longer.
+    pub response_delay: Option<ResponseDelay>,
+
+    /// If set, opening a substream waits until the remote acknowledges it,
+    /// sending the request again if it doesn't in time, and fails once it
+    /// never does; see `SubstreamOpenTimeout`. By default substreams can be
+    /// written to right away, and a lost open goes unnoticed.
+    pub substream_open_timeout: Option<SubstreamOpenTimeout>,
+
+    /// If set, both sides of dialed connections refuse substreams the remote
+    /// opens with IDs on their own side of the connection, so that
+    /// substreams opened by both sides at once can never collide. Substream IDs are always
+    /// generated for the side that opens them; this only asks the listener
+    /// to do the same, which it agrees to whenever it knows how. Listeners
+    /// from before handshake extensions reject the connection, so it's off
+    /// by default.
+    pub substream_directions: bool,
+
+    /// If set, connections accept out-of-band data, see
+    /// `Connection::send_out_of_band`, of which up to this many messages
+    /// wait to be read; more are dropped. Only used if the remote enables it
+    /// as well. `None`, the default, drops any out-of-band data the remote
+    /// sends.
+    pub out_of_band_queue: Option<usize>,
+
+    /// If set, both sides of a connection sign their ConnectionCloses with
+    /// the identity they authenticated it with, and drop unsigned ones, so
+    /// that nobody else can close it. Only used if the remote enables it as
+    /// well; otherwise a close is only accepted over the connection's route.
+    /// Listeners from before handshake extensions reject the connection, so
+    /// it's off by default.
+    pub signed_closes: bool,
+
+    /// If set, closing a substream only ends our writes to it: we still read
+    /// what the remote sends until it closes the substream too, and the
+    /// remote can still write after our close. Protocols which close their
+    /// side after sending a request and then read the response, such as
+    /// Kademlia's, depend on it. Only used if the remote enables it as well;
+    /// otherwise either side's close ends the substream for both. Listeners
+    /// from before handshake extensions reject the connection, so it's off
+    /// by default.
+    pub half_close: bool,
+
+    /// If set, the dialer of a connection sends its nym address to the
+    /// listener once the listener has authenticated itself in the handshake,
+    /// so that either side can dial the other again if the connection drops.
+    /// Listeners find the address in the connection's `ConnectionStats`. Only
+    /// used if the remote enables it as well; note that sharing the address
+    /// gives up the anonymity the dialer otherwise has towards the listener.
+    pub address_exchange: bool,
+
+    /// If set, connections we dial are authenticated with the transport's
+    /// keypair, so that the remote sees the same PeerId on every connection,
+    /// instead of one made up for each of them. DHTs such as Kademlia need
+    /// this, as they key their routing tables by PeerId; see
+    /// `with_kademlia_compatibility`. Like `address_exchange`, it links our
+    /// dials to each other and to our listen address.
+    pub stable_identity: bool,
+
+    /// The Nym network the mixnet client connects to; see `NymNetwork`. Like
+    /// `packet_size`, this only takes effect if it's applied to the client
+    /// with `mixnet_network_details`.
+    pub network: NymNetwork,
+
+    /// How the mixnet client trades anonymity for latency and bandwidth; see
+    /// `TrafficProfile`. Applied with `mixnet_debug_config`.
+    pub traffic_profile: TrafficProfile,
+
+    /// If set, connections which carried no substream traffic for this long
+    /// are closed with `CloseCode::Idle`, and the remote is told to close them
+    /// as well, even if the swarm still holds their `Connection`. Cover
+    /// messages don't count as traffic. A dropped `Connection`, eg. on the
+    /// swarm's idle timeout, is closed and its state freed right away either
+    /// way, so this only matters for connections the swarm keeps open unused.
+    pub idle_timeout: Option<Duration>,
+
+    /// If set, the receiver of a connection's messages acknowledges them, and
+    /// the sender limits the messages it has in flight with a congestion
+    /// window driven by the acks; see `CongestionControl`. Only used if the
+    /// remote enables it as well.
+    pub congestion_control: Option<CongestionControl>,
+
+    /// If set, messages which aren't acknowledged by the remote in time are
+    /// retransmitted; see `SelectiveRepeat`. Only used if the remote enables
+    /// it as well.
+    pub selective_repeat: Option<SelectiveRepeat>,
+
+    /// If set, the transport replaces its mixnet client when the client's
+    /// connection to its gateway is lost, registering with one of the fallback
+    /// gateways; see `GatewayFailover`. Otherwise the transport stops sending
+    /// and receiving once the gateway is gone, until the application replaces
+    /// the client with a `MixnetClientHandle`.
+    pub gateway_failover: Option<GatewayFailover>,
+
+    /// If set, the transport moves to a new mixnet client, with a new nym
+    /// address, every once in a while, so that a long-running listener can't
+    /// be followed by its address; see `AddressRotation`.
+    pub address_rotation: Option<AddressRotation>,
+
+    /// If set, connections follow us to the new address of our mixnet client,
+    /// eg. after a gateway failover, instead of being closed: we send the
+    /// remote a signed `MigrateMessage`, and it updates where it sends the
+    /// connection's messages. Only used if the remote enables it as well.
+    /// Messages the remote sent to the old address meanwhile are lost, so
+    /// connections without selective repeat may stall after migrating.
+    pub connection_migration: bool,
+
+    /// If set, every remote peer may exchange at most this much substream
+    /// data per hour with us, after which its connections are throttled or
+    /// closed; see `BandwidthQuota`. Usage is tracked either way, see
+    /// `NymTransport::bandwidth()`.
+    pub bandwidth_quota: Option<BandwidthQuota>,
+
+    /// How the outbound data of interactive and bulk substreams is
+    /// scheduled; see `TrafficClasses`.
+    pub traffic_classes: TrafficClasses,
+
+    /// If set, writes smaller than a frame are held back for up to this long,
+    /// so that consecutive small writes go out in a single frame, trading
+    /// latency for fewer mixnet packets. Flushing a substream sends the held
+    /// back data right away. Otherwise every write is sent as it's made.
+    pub flush_interval: Option<Duration>,
+
+    /// If set, the lifecycle of every substream message is recorded to the
+    /// log, to debug stalled connections; see `AuditLog`.
+    pub audit_log: Option<AuditLog>,
+
+    /// If set, faults are injected into the messages the transport sends and
+    /// receives; see `FaultInjection`. For soak tests only.
+    #[cfg(feature = "chaos")]
+    pub fault_injection: Option<FaultInjection>,
+}
+
+/// DialLimits bounds the number of dials handled at once.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DialLimits {
+    /// maximum number of dials whose handshake is in progress at the same time.
+    /// Further dials wait for a free slot; the handshake timeout only starts
+    /// once they got one.
+    pub max_concurrent: usize,
+    /// maximum number of dials waiting for a free slot; dialing fails
+    /// immediately with `Error::DialQueueFull` once it's reached.
+    pub max_queued: usize,
+    /// how long a dial may wait for a free slot before it fails with
+    /// `Error::DialQueueTimeout`.
+    pub max_queue_wait: Duration,
+}
+
+impl Default for DialLimits {
+    fn default() -> Self {
+        DialLimits {
+            max_concurrent: 16,
+            max_queued: 256,
+            max_queue_wait: Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT_SECS),
+        }
+    }
+}
+
+/// ReorderWindow bounds the messages a connection holds back because they
+/// arrived before one with a lower nonce. On connections using
+/// `SelectiveRepeat`, a message outside the window is dropped, and the
+/// remote retransmits it once it isn't acked. The mixnet itself doesn't
+/// retransmit, so other connections are closed with
+/// `Error::ReorderWindowExceeded` instead. The data held back counts against
+/// `max_buffered_bytes`. Routes with a lot of latency jitter may need a
+/// larger window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReorderWindow {
+    /// maximum distance between a message's nonce and the next expected one.
+    pub max_gap: u64,
+    /// maximum number of messages held back at the same time.
+    pub max_queued: usize,
+}
+
+impl Default for ReorderWindow {
+    fn default() -> Self {
+        ReorderWindow {
+            max_gap: 16384,
+            max_queued: 4096,
+        }
+    }
+}
+
+/// CongestionControl configures the congestion window of a connection, which
+/// bounds its data messages that were sent but not acknowledged yet, so that
+/// senders find a rate the mixnet sustains instead of overflowing the gateway
+/// queues and losing packets. The window grows by one message per ack until
+/// it reaches the slow start threshold, then by one message per window's
+/// worth of acks. If nothing is acknowledged for `loss_timeout`, the messages
+/// in flight are considered lost and the window is halved.
+///
+/// Every ack sent by a listener uses up one of the dialer's reply SURBs, so
+/// acking less often saves SURBs at the cost of a slower reacting window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CongestionControl {
+    /// window size of a new connection, in messages.
+    pub initial_window: u32,
+    /// the window is never halved below this many messages.
+    pub min_window: u32,
+    /// the window never grows beyond this many messages.
+    pub max_window: u32,
+    /// the receiver acks after this many messages...
+    pub ack_every: u32,
+    /// ...and pending acks are sent at least this often.
+    pub ack_delay: Duration,
+    /// time without any ack after which the messages in flight are
+    /// considered lost. Should be well above the round trip time of the mixnet.
+    pub loss_timeout: Duration,
+}
+
+impl Default for CongestionControl {
+    fn default() -> Self {
+        CongestionControl {
+            initial_window: 8,
+            min_window: 2,
+            max_window: 512,
+            ack_every: 4,
+            ack_delay: Duration::from_millis(500),
+            loss_timeout: Duration::from_secs(20),
+        }
+    }
+}
+
+/// SelectiveRepeat configures the retransmission of lost messages. The
+/// receiver acks the highest nonce up to which it got every message, along
+/// with the ranges of nonces it got above that, so the sender only resends
+/// the messages which are actually missing rather than everything after the
+/// first lost one, which matters with the multi-second round trips of the
+/// mixnet. Acks are sent as configured by `CongestionControl`, or by its
+/// defaults if congestion control is disabled.
+///
+/// Sent messages are kept until they're acknowledged, and count against
+/// `NymTransportConfig::max_buffered_bytes` in the meantime.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelectiveRepeat {
+    /// when an unacknowledged message is resent; the initial backoff should
+    /// be well above the round trip time of the mixnet.
+    pub retransmit: RetryPolicy,
+    /// the connection is closed if a message is still unacknowledged after
+    /// being resent this many times.
+    pub max_retransmissions: u32,
+}
+
+impl Default for SelectiveRepeat {
+    fn default() -> Self {
+        SelectiveRepeat {
+            retransmit: RetryPolicy {
+                initial_backoff: Duration::from_secs(10),
+                max_backoff: Duration::from_secs(60),
+                multiplier: 2,
+            },
+            max_retransmissions: 5,
+        }
+    }
+}
+
+/// AdaptiveFrameSize picks the size of a connection's data messages from the
+/// loss it observes, instead of always filling `max_frame_size`. The mixnet
+/// client splits a message into sphinx packets, and the message is lost if
+/// any one of them is, so large messages are lost, and resent, far more
+/// often than their packets are.
+///
+/// The packet loss is estimated from the share of messages which are resent,
+/// see `SelectiveRepeat`, and the frame size is the largest whole number of
+/// packets, of the size selected by `NymTransportConfig::packet_size`, for
+/// which at most `target_loss` of the messages are lost. The current
+/// decision is shown in `ConnectionStats::frame_size`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveFrameSize {
+    /// the share of data messages, between 0 and 1, which may be lost.
+    pub target_loss: f64,
+    /// the fewest packets a data message is allowed to span, however lossy
+    /// the connection.
+    pub min_packets: usize,
+    /// how much the loss estimate follows each message, between 0 and 1.
+    pub smoothing: f64,
+}
+
+impl Default for AdaptiveFrameSize {
+    fn default() -> Self {
+        AdaptiveFrameSize {
+            target_loss: 0.05,
+            min_packets: 1,
+            smoothing: 1.0 / 32.0,
+        }
+    }
+}
+
+/// GatewayFailover describes how the transport recovers from losing its
+/// gateway: it connects a new, ephemeral `ManagedMixnetClient` for the
+/// transport's config, registered with the next of the fallback gateways, and
+/// retries with backoff until one of them accepts it, or `max_rounds` ran out.
+///
+/// The new client has a different nym address, so the transport reports the
+/// old one as expired and the new one as its listen address. Connections
+/// can't follow on their own: the remotes of the transport's connections reply
+/// with SURBs of the old client, or the transport with theirs, so they're
+/// closed with `CloseCode::AddressChanged`, and have to be dialed again, unless
+/// both sides enabled `connection_migration`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GatewayFailover {
+    /// identity keys of the gateways to register with, tried in turn. If
+    /// empty, the mixnet client picks a gateway itself.
+    pub gateways: Vec<String>,
+    /// the delay between rounds of attempts, once every gateway was tried.
+    pub retry: RetryPolicy,
+    /// the number of rounds after which failover gives up, eg. because the
+    /// network can't be reached. The transport then only recovers if the
+    /// application hands over a client with `MixnetClientHandle`, and stops
+    /// otherwise. `None` retries forever.
+    pub max_rounds: Option<u32>,
+}
+
+impl Default for GatewayFailover {
+    fn default() -> Self {
+        GatewayFailover {
+            gateways: vec![],
+            retry: RetryPolicy {
+                initial_backoff: Duration::from_secs(1),
+                max_backoff: Duration::from_secs(60),
+                multiplier: 2,
+            },
+            max_rounds: Some(10),
+        }
+    }
+}
+
+/// AddressRotation moves the transport to a new, ephemeral
+/// `ManagedMixnetClient` for the transport's config every `interval`, with a
+/// new identity and so a new nym address. It needs the `nym-client` feature
+/// and a client owned by the transport.
+///
+/// The new address is reported right away, and new connections are made to
+/// it. The old client keeps receiving, and replying over the SURBs it holds,
+/// for `grace_period`, after which the old address is reported as expired.
+/// Connections are migrated to the new address where both sides enabled
+/// `connection_migration`; the others carry on over the old client until the
+/// grace period is over, and are closed with `CloseCode::AddressChanged` then.
+/// A rotation due before the previous old client was retired waits for it, so
+/// that at most two addresses are in use at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddressRotation {
+    /// how long each client is used before the next one is connected. Must be
+    /// non-zero.
+    pub interval: Duration,
+    /// how long the client rotated away from keeps receiving. Must not be
+    /// longer than `interval`.
+    pub grace_period: Duration,
+}
+
+impl Default for AddressRotation {
+    fn default() -> Self {
+        AddressRotation {
+            interval: Duration::from_secs(24 * 60 * 60),
+            grace_period: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// BandwidthQuota limits the substream data a remote peer exchanges with us,
+/// counting both directions, eg. for a public service which anyone may use
+/// anonymously over the mixnet. It's enforced as a token bucket: a peer may
+/// use up to `bytes_per_hour` in a burst, after which its allowance refills
+/// at that rate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BandwidthQuota {
+    pub bytes_per_hour: u64,
+    /// what happens to a peer's connections once it exceeded its quota.
+    pub action: QuotaAction,
+}
+
+/// QuotaAction is what the transport does with a peer which exceeded its
+/// `BandwidthQuota`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaAction {
+    /// writes to the peer wait until its allowance has refilled. Data it
+    /// sends still arrives, but counts against the allowance, so a peer
+    /// making requests gets its responses at the rate of the quota.
+    Throttle,
+    /// the peer's connections are closed with `CloseCode::ResourceLimit`,
+    /// writes to it fail, and its connection requests are rejected until
+    /// its allowance has refilled.
+    Close,
+}
+
+/// TrafficClasses schedules the outbound data of substreams by their
+/// `TrafficClass`, so that interactive protocols stay responsive while bulk
+/// transfers run in the background. Each class has its own queue to the
+/// mixnet client: interactive data is handed to the client first, and bulk
+/// data only fills half of the outbound backlog, see `max_outbound_backlog`.
+///
+/// Applications which use the `Substream`s of a connection directly mark
+/// them with `Substream::set_traffic_class`. Those behind a swarm can't reach
+/// the substreams, so they're classified by the protocol they negotiate.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrafficClasses {
+    /// data messages per second interactive substreams may hand to the
+    /// mixnet client, in bursts of up to a second's worth; unlimited if None.
+    pub interactive_rate: Option<u32>,
+    /// data messages per second bulk substreams may hand to the mixnet
+    /// client, in bursts of up to a second's worth; unlimited if None.
+    pub bulk_rate: Option<u32>,
+    /// substreams negotiating one of these protocols with multistream-select,
+    /// eg. "/ipfs/bitswap/1.2.0", are bulk.
+    pub bulk_protocols: Vec<String>,
+}
+
+/// ReplySurbs sets how many reply SURBs (single-use reply blocks) are attached
+/// to the messages a dialer sends. The listener doesn't know the dialer's
+/// address, so every message it sends uses up one of them; when it runs out,
+/// its mixnet client has to ask the dialer for more, which delays its
+/// messages by a round trip. Attaching more SURBs makes connections last
+/// longer without that delay, at the cost of larger messages from the dialer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReplySurbs {
+    /// SURBs attached to every `ConnectionRequest`, which the listener uses
+    /// for its response and the first messages of the connection.
+    pub handshake: u32,
+    /// SURBs attached to every later message, topping up the listener's supply.
+    pub top_up: u32,
+}
+
+impl Default for ReplySurbs {
+    fn default() -> Self {
+        // the mixnet client's default
+        ReplySurbs {
+            handshake: 10,
+            top_up: 10,
+        }
+    }
+}
+
+/// SessionPersistence configures where inbound connections are saved across
+/// restarts. Dialers are anonymous, so a listener can only reach them through
+/// the sender tags their messages arrived with; these are lost on a restart,
+/// which breaks all inbound connections, even though the mixnet client keeps
+/// its reply SURBs and the gateway holds messages while we're offline.
+///
+/// When the transport is dropped, the sender tag and nonces of every open
+/// inbound connection are written to an encrypted file. The next transport
+/// reads and deletes the file, and emits each connection as a new
+/// `TransportEvent::Incoming`, which the remote keeps using as before.
+/// Substreams aren't restored, and outbound messages which hadn't been handed
+/// to the mixnet client yet are lost. Encrypted and unordered connections,
+/// and those using acks, aren't saved, as their state can't be restored safely.
+#[derive(Clone)]
+pub struct SessionPersistence {
+    /// the file connections are saved to.
+    pub path: PathBuf,
+    /// connections are only restored if they were saved no longer than this
+    /// ago, since the remote gives up on a connection eventually.
+    pub max_downtime: Duration,
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl SessionPersistence {
+    /// `key` encrypts the saved connections, since sender tags link us to the
+    /// peers we're talking to; it should be kept as safe as the client's keys.
+    pub fn new(path: impl Into<PathBuf>, key: [u8; 32]) -> Self {
+        SessionPersistence {
+            path: path.into(),
+            max_downtime: Duration::from_secs(300),
+            key: Zeroizing::new(key),
+        }
+    }
+
+    pub fn with_max_downtime(mut self, max_downtime: Duration) -> Self {
+        self.max_downtime = max_downtime;
+        self
+    }
+
+    pub(crate) fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+}
+
+impl Debug for SessionPersistence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionPersistence")
+            .field("path", &self.path)
+            .field("max_downtime", &self.max_downtime)
+            .finish_non_exhaustive()
+    }
+}
+
+/// SessionResumption configures session tickets, which let the dialer of a
+/// connection resume it after a restart without another handshake. Where
+/// `SessionPersistence` keeps a listener's connections across its restarts,
+/// tickets cover the dialer's: its new mixnet client replies with new sender
+/// tags, so the listener could no longer reach it over the old connection.
+///
+/// The listener seals the connection's ID, the dialer's PeerId and the
+/// negotiated options into a ticket, and sends it to the dialer, which keeps
+/// it in its `SessionTicketStore`. Dialing the listener's address again with a
+/// ticket resumes the connection right away: the dialer sends the ticket with
+/// fresh reply SURBs, and the listener replaces the connection with one that
+/// has the same ID and PeerIds. Substreams aren't resumed, and nonces start
+/// over. The dialer signs the ticket with the identity it was issued to, so
+/// only dialers with a stable identity ask for tickets; see
+/// `NymTransportConfig::stable_identity`. The listener accepts each ticket
+/// once, but only remembers the used ones until the transport is dropped.
+/// Encrypted connections don't get tickets, as their session keys can't be
+/// restored.
+#[derive(Clone)]
+pub struct SessionResumption {
+    /// tickets are only accepted for this long after they were issued.
+    pub lifetime: Duration,
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl SessionResumption {
+    /// `key` seals the tickets we issue as a listener; a listener has to keep
+    /// it across restarts for its tickets to stay valid.
+    pub fn new(key: [u8; 32]) -> Self {
+        SessionResumption {
+            lifetime: Duration::from_secs(3600),
+            key: Zeroizing::new(key),
+        }
+    }
+
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    pub(crate) fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+}
+
+impl Debug for SessionResumption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionResumption")
+            .field("lifetime", &self.lifetime)
+            .finish_non_exhaustive()
+    }
+}
+
+/// StoreAndForward configures the outbox, which keeps the substream data the
+/// transport couldn't hand to the mixnet client, rather than dropping it.
+/// Messaging applications use it to ride out mixnet outages and restarts.
+///
+/// Messages the client refuses, eg. because it lost its gateway, are kept in
+/// the outbox, and sent again once the client takes a message again or is
+/// replaced; a replaced client sends them after the connections were moved to
+/// it, see `NymTransportConfig::connection_migration`. They go to wherever
+/// their connection was migrated to meanwhile. When the transport shuts down, the outbox and the data still
+/// waiting to be sent are written to an encrypted file, which the next
+/// transport reads and deletes. Only the messages of the connections restored
+/// with `SessionPersistence` are sent then, since every other connection ends
+/// with the restart. Messages the client took aren't kept: whether they
+/// arrive is only known from the remote's acks, see `SelectiveRepeat`.
+#[derive(Clone)]
+pub struct StoreAndForward {
+    /// the file the outbox is saved to.
+    pub path: PathBuf,
+    /// messages are dropped once they've been kept this long, since the
+    /// remote gives up on the connection eventually.
+    pub max_age: Duration,
+    /// the most messages kept; the oldest ones are dropped first.
+    pub max_messages: usize,
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl StoreAndForward {
+    /// `key` encrypts the saved messages, since their sender tags and
+    /// addresses link us to the peers we're talking to.
+    pub fn new(path: impl Into<PathBuf>, key: [u8; 32]) -> Self {
+        StoreAndForward {
+            path: path.into(),
+            max_age: Duration::from_secs(3600),
+            max_messages: 10_000,
+            key: Zeroizing::new(key),
+        }
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn with_max_messages(mut self, max_messages: usize) -> Self {
+        self.max_messages = max_messages;
+        self
+    }
+
+    pub(crate) fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+}
+
+impl Debug for StoreAndForward {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StoreAndForward")
+            .field("path", &self.path)
+            .field("max_age", &self.max_age)
+            .field("max_messages", &self.max_messages)
+            .finish_non_exhaustive()
+    }
+}
+
+/// OfflineBuffer bounds the outbound messages held back while the mixnet
+/// client is disconnected from its gateway, until failover or the application
+/// replaces it; see `MixnetClientHandle`. They're sent in order once the new
+/// client is up.
+///
+/// Once `max_messages` data messages are held back, writing more fails with
+/// `Error::OfflineBufferFull`. The remote can't skip the missing data, so a
+/// substream which ran into the limit is closed. Data which was held back for
+/// longer than `ttl` closes its substream the same way once the client is
+/// back, as with `NymTransportConfig::message_ttl`. Control messages, eg.
+/// acks and closes, are always held back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OfflineBuffer {
+    /// the most data messages held back during an outage.
+    pub max_messages: usize,
+    /// data held back for longer is dropped.
+    pub ttl: Duration,
+}
+
+impl Default for OfflineBuffer {
+    fn default() -> Self {
+        OfflineBuffer {
+            max_messages: 1024,
+            ttl: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Redundancy sends extra copies of the transport's control messages, per
+/// class of message. Every copy is sent as its own sphinx packets, which take
+/// independent random routes through the mixnet, so a message is only lost if
+/// all of its copies are; it trades bandwidth, and reply SURBs for replies,
+/// for fewer failed handshakes. The copies are sent `delay` apart, so that a
+/// burst of loss doesn't take them all. They leave through the same gateway
+/// as the original.
+///
+/// The remote handles duplicates already: a repeated ConnectionRequest is
+/// answered again, and repeated acks and closes are ignored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Redundancy {
+    /// extra copies of connection requests, responses and resumptions.
+    pub handshake: u32,
+    /// extra copies of connection closes.
+    pub close: u32,
+    /// extra copies of acks, see `CongestionControl`.
+    pub ack: u32,
+    /// the time between two copies of a message.
+    pub delay: Duration,
+}
+
+impl Default for Redundancy {
+    fn default() -> Self {
+        Redundancy {
+            handshake: 1,
+            close: 1,
+            ack: 0,
+            delay: Duration::from_millis(200),
+        }
+    }
+}
+
+/// MessagePadding pads the messages the transport hands to the mixnet client
+/// to the smallest of `buckets` they fit in, and larger ones to a multiple of
+/// the largest bucket. The client splits messages into equally sized sphinx
+/// packets, but their number still gives away how large each message was,
+/// and with it much about the protocol running over the connection. Padded,
+/// a handshake, a ping and a small request all look the same.
+///
+/// The padding costs bandwidth, and a little of the sender's share of the
+/// mixnet. A regular sphinx packet carries 2 KiB, of which the client's
+/// framing and the packet's ack take a share, so a message filling the
+/// smallest default bucket takes two regular packets, and the larger buckets
+/// grow from it fourfold each.
+///
+/// Padding is negotiated: only the messages of connections whose remote
+/// pads as well are padded, as peers from before it can't decode padded
+/// messages. The ConnectionRequest offering it is sent as it is, and
+/// resumed connections aren't padded, since the ticket doesn't record it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessagePadding {
+    /// the sizes messages are padded to, in bytes.
+    pub buckets: Vec<usize>,
+}
+
+impl Default for MessagePadding {
+    fn default() -> Self {
+        MessagePadding {
+            buckets: vec![2 * 1024, 8 * 1024, 32 * 1024, 128 * 1024],
+        }
+    }
+}
+
+impl MessagePadding {
+    /// returns the size a message of `len` bytes is padded to.
+    pub(crate) fn padded_len(&self, len: usize) -> usize {
+        let fitting = self.buckets.iter().filter(|&&b| b >= len).min();
+        match (fitting, self.buckets.iter().max()) {
+            (Some(&bucket), _) => bucket,
+            (None, Some(&largest)) if largest > 0 => len.div_ceil(largest) * largest,
+            _ => len,
+        }
+    }
+}
+
+/// CoverTraffic sends dummy messages over every established connection, as a
+/// Poisson process averaging one message per `mean_interval`, whether or not
+/// the connection carries any other traffic. The messages are sent like the
+/// connection's data, encrypted if it is, so that an observer at either
+/// gateway can't tell an idle connection from an active one by when messages
+/// are sent. The remote drops them before they reach a substream, and they
+/// don't count as activity for `idle_timeout`.
+///
+/// Every message costs `size` bytes of padding, plus the sphinx packets
+/// carrying it, on both gateways' bandwidth; combine it with `MessagePadding`
+/// so that they're also the size of the data messages. Asking for it takes a
+/// handshake extension, and either side sends cover messages only if both
+/// enabled it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CoverTraffic {
+    /// the mean time between two cover messages of a connection.
+    pub mean_interval: Duration,
+    /// the number of bytes of padding each cover message carries.
+    pub size: usize,
+}
+
+impl Default for CoverTraffic {
+    fn default() -> Self {
+        CoverTraffic {
+            mean_interval: Duration::from_secs(5),
+            size: 256,
+        }
+    }
+}
+
+impl CoverTraffic {
+    /// returns the time until the next cover message, which is exponentially
+    /// distributed around the mean interval.
+    pub(crate) fn next_interval(&self) -> Duration {
+        // 1 - x lies in (0, 1], keeping the logarithm finite
+        let x: f64 = rand::random();
+        self.mean_interval.mul_f64(-(1.0 - x).ln())
+    }
+}
+
+/// ResponseDelay holds back the replies of the transport for a random time
+/// between `min` and `max`, uniformly distributed. A gateway which sees a
+/// message arrive for us and another one leave right after can link the two,
+/// and with them both ends of a connection; the delay blurs that. It's
+/// applied to ConnectionResponses, by handling ConnectionRequests late, and
+/// to the responses to substreams the remote opens. Acks aren't delayed, as
+/// the remote's congestion control times them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResponseDelay {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Default for ResponseDelay {
+    fn default() -> Self {
+        ResponseDelay {
+            min: Duration::from_millis(50),
+            max: Duration::from_millis(500),
+        }
+    }
+}
+
+impl ResponseDelay {
+    /// returns how long to hold back the next response.
+    pub(crate) fn next_delay(&self) -> Duration {
+        if self.max <= self.min {
+            return self.min;
+        }
+        self.min + (self.max - self.min).mul_f64(rand::random())
+    }
+}
+
+/// SubstreamOpenTimeout bounds how long opening a substream waits for the
+/// remote's OpenResponse. The OpenRequest is sent again if no response
+/// arrives within `timeout`, up to `retries` times, after which the open
+/// fails with `Error::SubstreamOpenTimeout` and the substream is reset. It
+/// applies to substreams opened by the swarm and through a
+/// `ConnectionControl`. The timeout should allow for the response delay of
+/// the remote, if it has one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SubstreamOpenTimeout {
+    pub timeout: Duration,
+    pub retries: usize,
+}
+
+impl Default for SubstreamOpenTimeout {
+    fn default() -> Self {
+        SubstreamOpenTimeout {
+            timeout: Duration::from_secs(10),
+            retries: 2,
+        }
+    }
+}
+
+/// ForwardErrorCorrection adds Reed-Solomon parity to the TransportMessages
+/// of a connection, for bulk transfers over a lossy mixnet. Every group of
+/// `data_shards` consecutive messages is followed by `parity_shards`
+/// ParityMessages, and the remote rebuilds up to that many lost messages of
+/// the group from them. With `SelectiveRepeat`, a lost message then costs a
+/// little bandwidth instead of a retransmission several round trips later.
+///
+/// The parity is only sent once a group is complete, so it helps steady
+/// streams of data rather than the last messages before a pause. Every
+/// ParityMessage is as large as the largest message of its group. It's
+/// negotiated in the handshake, and each side sends parity with its own
+/// settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ForwardErrorCorrection {
+    /// the messages per group.
+    pub data_shards: u8,
+    /// the ParityMessages sent per group, at most 256 together with
+    /// `data_shards`.
+    pub parity_shards: u8,
+}
+
+impl Default for ForwardErrorCorrection {
+    fn default() -> Self {
+        ForwardErrorCorrection {
+            data_shards: 8,
+            parity_shards: 2,
+        }
+    }
+}
+
+/// Compression is an algorithm the data of a connection's substreams may be
+/// compressed with. Each side compresses with its own settings for the
+/// algorithm the handshake picked, and sends data which doesn't shrink as it
+/// is. Note that the number of packets a message takes then depends on the
+/// content of the data, not just on its length.
+///
+/// Compressed connections aren't persisted, and resumed ones are uncompressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// LZ4, which is fast and needs little memory, for constrained devices.
+    /// Requires the `lz4` feature.
+    Lz4,
+    /// Zstandard at the given level, from 1 to 22; higher levels trade CPU
+    /// time for better ratios. Requires the `zstd` feature.
+    Zstd { level: i32 },
+}
+
+impl Compression {
+    pub fn algorithm(&self) -> CompressionAlgorithm {
+        match self {
+            Compression::Lz4 => CompressionAlgorithm::LZ4,
+            Compression::Zstd { .. } => CompressionAlgorithm::ZSTD,
+        }
+    }
+
+    /// returns whether the crate was built with the algorithm's feature.
+    pub fn is_available(&self) -> bool {
+        match self {
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Zstd { .. } => cfg!(feature = "zstd"),
+        }
+    }
+}
+
+/// SessionRekeying replaces the session keys of encrypted connections with
+/// keys from a new X25519 exchange, once they've been used for `max_messages`
+/// messages in both directions together, or for `max_age`. Unlike the keys
+/// derived every 1024 messages, the new ones don't follow from the old ones,
+/// so compromising a connection's keys only exposes its traffic until the
+/// next update.
+///
+/// The dialer of a connection asks for it in the handshake, and both sides
+/// use the lower of their limits. Only the dialer starts updates, which are
+/// checked for every handshake timeout, so they may run a little over the
+/// limits. Asking for it takes a handshake extension, like offering cipher
+/// suites other than the default; listeners which don't support it leave it
+/// out of their response, and the connection keeps its keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionRekeying {
+    pub max_messages: u64,
+    pub max_age: Duration,
+}
+
+impl Default for SessionRekeying {
+    fn default() -> Self {
+        SessionRekeying {
+            max_messages: 1 << 16,
+            max_age: Duration::from_secs(600),
+        }
+    }
+}
+
+impl SessionRekeying {
+    pub(crate) fn limits(&self) -> KeyUpdateLimits {
+        KeyUpdateLimits {
+            max_messages: self.max_messages,
+            max_age_secs: self.max_age.as_secs(),
+        }
+    }
+}
+
+/// PacketSizePolicy selects the sphinx packet sizes used for the transport's
+/// messages. Extended packets carry a lot more payload per packet, which
+/// significantly improves throughput for bulk protocols, but are wasteful for
+/// small messages such as handshakes or pings; all packets of the same size
+/// look alike, so padding hides the actual message size either way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketSizePolicy {
+    /// every message is sent in packets of the given size.
+    Fixed(PacketSize),
+    /// small messages are sent in regular packets, and large ones in packets
+    /// of the given extended size; the mixnet client picks the size which
+    /// needs fewer packets for each message.
+    Auto { extended: PacketSize },
+}
+
+impl Default for PacketSizePolicy {
+    fn default() -> Self {
+        PacketSizePolicy::Fixed(PacketSize::RegularPacket)
+    }
+}
+
+impl PacketSizePolicy {
+    /// sets the packet sizes in the given mixnet client config, which is then
+    /// passed to `MixnetClientBuilder::debug_config`.
+    #[cfg(feature = "nym-client")]
+    pub fn apply_to(&self, debug_config: &mut DebugConfig) {
+        let (primary, secondary) = match *self {
+            PacketSizePolicy::Fixed(size) => (size, None),
+            PacketSizePolicy::Auto { extended } => (PacketSize::RegularPacket, Some(extended)),
+        };
+        debug_config.traffic.primary_packet_size = primary;
+        debug_config.traffic.secondary_packet_size = secondary;
+    }
+}
+
+/// TrafficProfile selects how much of the mixnet's protection against traffic
+/// analysis the mixnet client provides, which is what makes it slow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrafficProfile {
+    /// the mixnet client's defaults: real packets are hidden among a constant
+    /// stream of cover packets, and are delayed randomly at every hop.
+    #[default]
+    Anonymous,
+    /// no cover traffic is sent, so real packets are sent right away; they're
+    /// still delayed at every hop. Saves bandwidth, eg. on test networks, but
+    /// makes it visible when and how much we send.
+    NoCoverTraffic,
+    /// no cover traffic, and no delays at the mix nodes either, for
+    /// benchmarking, or for applications which only want the mixnet's
+    /// addressing and NAT traversal. This gives up most of the anonymity the
+    /// mixnet provides.
+    LowLatency,
+}
+
+impl TrafficProfile {
+    /// sets the traffic options in the given mixnet client config, which is
+    /// then passed to `MixnetClientBuilder::debug_config`.
+    #[cfg(feature = "nym-client")]
+    pub fn apply_to(&self, debug_config: &mut DebugConfig) {
+        if *self == TrafficProfile::Anonymous {
+            return;
+        }
+
+        debug_config.cover_traffic.disable_loop_cover_traffic_stream = true;
+        debug_config
+            .traffic
+            .disable_main_poisson_packet_distribution = true;
+        if *self == TrafficProfile::LowLatency {
+            debug_config.traffic.average_packet_delay = Duration::ZERO;
+            debug_config.traffic.message_sending_average_delay = Duration::ZERO;
+        }
+    }
+}
+
+/// NetworkPreset selects a Nym network together with the transport settings
+/// suited to it, for `NymTransportConfig::for_network`.
+#[derive(Clone, Debug)]
+pub enum NetworkPreset {
+    /// mainnet, with the default settings.
+    Mainnet,
+    /// the sandbox testnet, described by the given env file, eg. the
+    /// `sandbox.env` of the Nym repository. Handshakes get more time, as
+    /// routes are less reliable, and cover traffic is disabled.
+    Sandbox(PathBuf),
+    /// a private mixnet, eg. set up with harbourmaster for integration tests.
+    /// Since it's fast and nobody needs to hide on it, handshakes time out
+    /// sooner, large messages use extended packets and cover traffic is disabled.
+    Custom(NymNetwork),
+}
+
+/// NymNetwork selects the Nym network the transport's mixnet client connects
+/// to, eg. to run integration tests against the sandbox or a private mixnet
+/// set up with harbourmaster instead of mainnet.
+#[derive(Clone, Debug, Default)]
+pub enum NymNetwork {
+    #[default]
+    Mainnet,
+    /// the network described by the environment variables the Nym binaries
+    /// use, eg. `NETWORK_NAME` and `NYM_API`.
+    FromEnv,
+    /// the network described by an env file such as the `sandbox.env` in the
+    /// Nym repository. The file's variables take precedence over the process
+    /// environment, which is only read for those the file doesn't set; the
+    /// environment itself isn't modified.
+    EnvFile(PathBuf),
+    /// the given network.
+    #[cfg(feature = "nym-client")]
+    Custom(NymNetworkDetails),
+}
+
+#[cfg(feature = "nym-client")]
+impl NymNetwork {
+    /// returns the network's details, loading its env file first if it has one.
+    pub fn details(&self) -> Result<NymNetworkDetails, Error> {
+        match self {
+            NymNetwork::Mainnet => Ok(NymNetworkDetails::new_mainnet()),
+            NymNetwork::FromEnv => Ok(NymNetworkDetails::new_from_env()),
+            NymNetwork::EnvFile(path) => {
+                let contents = fs::read_to_string(path).map_err(Error::NetworkEnvFileIo)?;
+                let vars: HashMap<&str, &str> = parse_env_file(&contents)?.into_iter().collect();
+                network_details(|key| {
+                    vars.get(key)
+                        .map(|value| value.to_string())
+                        .or_else(|| std::env::var(key).ok())
+                })
+            }
+            NymNetwork::Custom(details) => Ok(details.clone()),
+        }
+    }
+}
+
+/// returns the details of the network described by the variables `var`
+/// returns, the ones `NymNetworkDetails::new_from_env` reads. Unlike it, a
+/// missing variable fails rather than panics, and the process environment,
+/// which other threads may read at the same time, is left alone.
+#[cfg(feature = "nym-client")]
+fn network_details(var: impl Fn(&str) -> Option<String>) -> Result<NymNetworkDetails, Error> {
+    let required = |key: &'static str| var(key).ok_or(Error::InvalidNetworkVariable(key));
+    let display_exponent = required("DENOMS_EXPONENT")?
+        .parse::<u32>()
+        .map_err(|_| Error::InvalidNetworkVariable("DENOMS_EXPONENT"))?;
+    let denom = |base: &'static str, display: &'static str| -> Result<_, Error> {
+        Ok(DenomDetailsOwned {
+            base: required(base)?,
+            display: required(display)?,
+            display_exponent,
+        })
+    };
+    Ok(NymNetworkDetails::new_empty()
+        .with_network_name(required("NETWORK_NAME")?)
+        .with_bech32_account_prefix(required("BECH32_PREFIX")?)
+        .with_mix_denom(denom("MIX_DENOM", "MIX_DENOM_DISPLAY")?)
+        .with_stake_denom(denom("STAKE_DENOM", "STAKE_DENOM_DISPLAY")?)
+        .with_additional_validator_endpoint(ValidatorDetails::new(
+            required("NYXD")?,
+            Some(required("NYM_API")?),
+            var("NYXD_WEBSOCKET"),
+        ))
+        .with_mixnet_contract(var("MIXNET_CONTRACT_ADDRESS"))
+        .with_vesting_contract(var("VESTING_CONTRACT_ADDRESS"))
+        .with_ecash_contract(var("ECASH_CONTRACT_ADDRESS"))
+        .with_group_contract(var("GROUP_CONTRACT_ADDRESS"))
+        .with_multisig_contract(var("MULTISIG_CONTRACT_ADDRESS"))
+        .with_coconut_dkg_contract(var("COCONUT_DKG_CONTRACT_ADDRESS"))
+        .with_explorer_api(var("EXPLORER_API"))
+        .with_nym_vpn_api_url(var("NYM_VPN_API")))
+}
+
+/// parses the `KEY=VALUE` lines of an env file, skipping blank lines and
+/// comments. Values may be quoted, and keys may be preceded by `export`.
+#[cfg(feature = "nym-client")]
+fn parse_env_file(contents: &str) -> Result<Vec<(&str, &str)>, Error> {
+    let mut vars = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or(Error::InvalidNetworkEnvFile(i + 1))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(Error::InvalidNetworkEnvFile(i + 1));
+        }
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q))
+            .unwrap_or(value);
+        vars.push((key, value));
+    }
+    Ok(vars)
+}
+
+/// RetryPolicy describes an exponential backoff schedule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// delay before the first retry.
+    pub initial_backoff: Duration,
+    /// upper bound for the delay between two retries.
+    pub max_backoff: Duration,
+    /// factor the delay is multiplied by after every retry.
+    pub multiplier: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(20),
+            multiplier: 2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// returns the delay to wait after a retry which was preceded by `current`.
+    pub fn next_backoff(&self, current: Duration) -> Duration {
+        std::cmp::min(current.saturating_mul(self.multiplier), self.max_backoff)
+    }
+}
+
+impl Default for NymTransportConfig {
+    fn default() -> Self {
+        NymTransportConfig {
+            handshake_timeout: Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT_SECS),
+            max_handshake_age: Duration::from_secs(DEFAULT_MAX_HANDSHAKE_AGE_SECS),
+            unsigned_handshakes: false,
+            deduplicate_dials: false,
+            dial_retry: None,
+            dial_limits: None,
+            peer_filter: None,
+            pre_shared_key: None,
+            encrypt_payloads: false,
+            cipher_suites: vec![CipherSuite::default()],
+            session_rekeying: None,
+            message_ttl: None,
+            unordered_delivery: false,
+            compact_ids: false,
+            max_substream_buffer: Some(DEFAULT_MAX_SUBSTREAM_BUFFER_BYTES),
+            outbound_backlog_threshold: None,
+            max_outbound_backlog: Some(DEFAULT_MAX_OUTBOUND_BACKLOG),
+            max_frame_size: DEFAULT_MAX_FRAME_BYTES,
+            adaptive_frame_size: None,
+            max_unsent_bytes: Some(DEFAULT_MAX_UNSENT_BYTES),
+            max_buffered_bytes: None,
+            max_buffered_bytes_per_connection: None,
+            reorder_window: Some(ReorderWindow::default()),
+            packet_size: PacketSizePolicy::default(),
+            reply_surbs: ReplySurbs::default(),
+            session_persistence: None,
+            session_resumption: None,
+            store_and_forward: None,
+            offline_buffer: None,
+            redundancy: None,
+            forward_error_correction: None,
+            compression: vec![],
+            message_padding: None,
+            cover_traffic: None,
+            response_delay: None,
+            substream_open_timeout: None,
+            substream_directions: false,
+            out_of_band_queue: None,
+            signed_closes: false,
+            half_close: false,
+            address_exchange: false,
+            stable_identity: false,
+            network: NymNetwork::default(),
+            traffic_profile: TrafficProfile::default(),
+            idle_timeout: None,
+            congestion_control: None,
+            selective_repeat: None,
+            gateway_failover: None,
+            address_rotation: None,
+            connection_migration: false,
+            bandwidth_quota: None,
+            traffic_classes: TrafficClasses::default(),
+            flush_interval: None,
+            audit_log: None,
+            #[cfg(feature = "chaos")]
+            fault_injection: None,
+        }
+    }
+}
+
+impl NymTransportConfig {
+    /// returns the default config for the given network, with the settings
+    /// which depend on it tuned; see `NetworkPreset`.
+    pub fn for_network(preset: NetworkPreset) -> Self {
+        let config = NymTransportConfig::default();
+        match preset {
+            NetworkPreset::Mainnet => config,
+            NetworkPreset::Sandbox(env_file) => config
+                .with_network(NymNetwork::EnvFile(env_file))
+                // the sandbox has few mix nodes, and is shared by everyone testing on it
+                .with_handshake_timeout(Duration::from_secs(60))
+                .with_traffic_profile(TrafficProfile::NoCoverTraffic),
+            NetworkPreset::Custom(network) => config
+                .with_network(network)
+                .with_handshake_timeout(Duration::from_secs(10))
+                .with_packet_size(PacketSizePolicy::Auto {
+                    extended: PacketSize::ExtendedPacket32,
+                })
+                .with_traffic_profile(TrafficProfile::NoCoverTraffic),
+        }
+    }
+
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn with_max_handshake_age(mut self, max_age: Duration) -> Self {
+        self.max_handshake_age = max_age;
+        self
+    }
+
+    pub fn with_unsigned_handshakes(mut self, enabled: bool) -> Self {
+        self.unsigned_handshakes = enabled;
+        self
+    }
+
+    pub fn with_dial_deduplication(mut self, enabled: bool) -> Self {
+        self.deduplicate_dials = enabled;
+        self
+    }
+
+    pub fn with_dial_retry(mut self, policy: RetryPolicy) -> Self {
+        self.dial_retry = Some(policy);
+        self
+    }
+
+    pub fn with_dial_limits(mut self, limits: DialLimits) -> Self {
+        self.dial_limits = Some(limits);
+        self
+    }
+
+    pub fn with_peer_filter(mut self, filter: PeerFilter) -> Self {
+        self.peer_filter = Some(filter);
+        self
+    }
+
+    pub fn with_pre_shared_key(mut self, psk: PreSharedKey) -> Self {
+        self.pre_shared_key = Some(psk);
+        self
+    }
+
+    pub fn with_payload_encryption(mut self, enabled: bool) -> Self {
+        self.encrypt_payloads = enabled;
+        self
+    }
+
+    pub fn with_cipher_suites(mut self, suites: Vec<CipherSuite>) -> Self {
+        self.cipher_suites = suites;
+        self
+    }
+
+    pub fn with_session_rekeying(mut self, rekeying: SessionRekeying) -> Self {
+        self.session_rekeying = Some(rekeying);
+        self
+    }
+
+    pub fn with_message_ttl(mut self, ttl: Duration) -> Self {
+        self.message_ttl = Some(ttl);
+        self
+    }
+
+    pub fn with_unordered_delivery(mut self, enabled: bool) -> Self {
+        self.unordered_delivery = enabled;
+        self
+    }
+
+    pub fn with_compact_ids(mut self, enabled: bool) -> Self {
+        self.compact_ids = enabled;
+        self
+    }
+
+    pub fn with_max_substream_buffer(mut self, max_bytes: Option<usize>) -> Self {
+        self.max_substream_buffer = max_bytes;
+        self
+    }
+
+    pub fn with_outbound_backlog_threshold(mut self, threshold: usize) -> Self {
+        self.outbound_backlog_threshold = Some(threshold);
+        self
+    }
+
+    pub fn with_max_outbound_backlog(mut self, max_messages: Option<usize>) -> Self {
+        self.max_outbound_backlog = max_messages;
+        self
+    }
+
+    pub fn with_max_frame_size(mut self, max_bytes: usize) -> Self {
+        assert!(max_bytes > 0, "max_frame_size must be non-zero");
+        self.max_frame_size = max_bytes;
+        self
+    }
+
+    pub fn with_adaptive_frame_size(mut self, adaptive: AdaptiveFrameSize) -> Self {
+        self.adaptive_frame_size = Some(adaptive);
+        self
+    }
+
+    pub fn with_max_unsent_bytes(mut self, max_bytes: Option<usize>) -> Self {
+        self.max_unsent_bytes = max_bytes;
+        self
+    }
+
+    pub fn with_max_buffered_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_buffered_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn with_max_buffered_bytes_per_connection(mut self, max_bytes: usize) -> Self {
+        self.max_buffered_bytes_per_connection = Some(max_bytes);
+        self
+    }
+
+    pub fn with_reorder_window(mut self, window: Option<ReorderWindow>) -> Self {
+        self.reorder_window = window;
+        self
+    }
+
+    pub fn with_packet_size(mut self, policy: PacketSizePolicy) -> Self {
+        self.packet_size = policy;
+        self
+    }
+
+    pub fn with_reply_surbs(mut self, surbs: ReplySurbs) -> Self {
+        self.reply_surbs = surbs;
+        self
+    }
+
+    pub fn with_session_persistence(mut self, persistence: SessionPersistence) -> Self {
+        self.session_persistence = Some(persistence);
+        self
+    }
+
+    pub fn with_session_resumption(mut self, resumption: SessionResumption) -> Self {
+        self.session_resumption = Some(resumption);
+        self
+    }
+
+    pub fn with_store_and_forward(mut self, store_and_forward: StoreAndForward) -> Self {
+        self.store_and_forward = Some(store_and_forward);
+        self
+    }
+
+    pub fn with_offline_buffer(mut self, offline_buffer: OfflineBuffer) -> Self {
+        self.offline_buffer = Some(offline_buffer);
+        self
+    }
+
+    pub fn with_message_padding(mut self, padding: MessagePadding) -> Self {
+        self.message_padding = Some(padding);
+        self
+    }
+
+    pub fn with_cover_traffic(mut self, cover_traffic: CoverTraffic) -> Self {
+        self.cover_traffic = Some(cover_traffic);
+        self
+    }
+
+    pub fn with_response_delay(mut self, delay: ResponseDelay) -> Self {
+        self.response_delay = Some(delay);
+        self
+    }
+
+    pub fn with_substream_open_timeout(mut self, timeout: SubstreamOpenTimeout) -> Self {
+        self.substream_open_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_substream_directions(mut self, enabled: bool) -> Self {
+        self.substream_directions = enabled;
+        self
+    }
+
+    pub fn with_out_of_band(mut self, max_queued: usize) -> Self {
+        assert!(max_queued > 0, "out_of_band_queue must be non-zero");
+        self.out_of_band_queue = Some(max_queued);
+        self
+    }
+
+    pub fn with_signed_closes(mut self, enabled: bool) -> Self {
+        self.signed_closes = enabled;
+        self
+    }
+
+    pub fn with_half_close(mut self, enabled: bool) -> Self {
+        self.half_close = enabled;
+        self
+    }
+
+    pub fn with_redundancy(mut self, redundancy: Redundancy) -> Self {
+        self.redundancy = Some(redundancy);
+        self
+    }
+
+    pub fn with_forward_error_correction(mut self, fec: ForwardErrorCorrection) -> Self {
+        self.forward_error_correction = Some(fec);
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Vec<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_address_exchange(mut self, enabled: bool) -> Self {
+        self.address_exchange = enabled;
+        self
+    }
+
+    pub fn with_stable_identity(mut self, enabled: bool) -> Self {
+        self.stable_identity = enabled;
+        self
+    }
+
+    /// enables what `libp2p-kad` needs to maintain routing tables and find
+    /// providers over `/nym` addresses: a stable identity when dialing,
+    /// address exchange, so that listeners learn where to reach their
+    /// dialers, and half-closed substreams, over which requests are answered.
+    /// The swarm has to use the transport's keypair as its identity, and
+    /// `announce::Behaviour` with `Config::with_external_address` should be
+    /// part of its behaviour.
+    pub fn with_kademlia_compatibility(self) -> Self {
+        self.with_stable_identity(true)
+            .with_address_exchange(true)
+            .with_half_close(true)
+    }
+
+    pub fn with_network(mut self, network: NymNetwork) -> Self {
+        self.network = network;
+        self
+    }
+
+    pub fn with_traffic_profile(mut self, profile: TrafficProfile) -> Self {
+        self.traffic_profile = profile;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_congestion_control(mut self, congestion_control: CongestionControl) -> Self {
+        self.congestion_control = Some(congestion_control);
+        self
+    }
+
+    pub fn with_selective_repeat(mut self, selective_repeat: SelectiveRepeat) -> Self {
+        self.selective_repeat = Some(selective_repeat);
+        self
+    }
+
+    pub fn with_gateway_failover(mut self, failover: GatewayFailover) -> Self {
+        self.gateway_failover = Some(failover);
+        self
+    }
+
+    pub fn with_address_rotation(mut self, rotation: AddressRotation) -> Self {
+        assert!(
+            !rotation.interval.is_zero(),
+            "address_rotation interval must be non-zero"
+        );
+        assert!(
+            rotation.grace_period <= rotation.interval,
+            "address_rotation grace_period must not be longer than its interval"
+        );
+        self.address_rotation = Some(rotation);
+        self
+    }
+
+    pub fn with_connection_migration(mut self, enabled: bool) -> Self {
+        self.connection_migration = enabled;
+        self
+    }
+
+    pub fn with_bandwidth_quota(mut self, quota: BandwidthQuota) -> Self {
+        self.bandwidth_quota = Some(quota);
+        self
+    }
+
+    pub fn with_traffic_classes(mut self, classes: TrafficClasses) -> Self {
+        self.traffic_classes = classes;
+        self
+    }
+
+    pub fn with_flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = Some(interval);
+        self
+    }
+
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    #[cfg(feature = "chaos")]
+    pub fn with_fault_injection(mut self, faults: FaultInjection) -> Self {
+        self.fault_injection = Some(faults);
+        self
+    }
+
+    /// returns the settings acks are sent with, if acks are enabled: those
+    /// of `congestion_control`, or its defaults if only `selective_repeat` is set.
+    pub(crate) fn ack_settings(&self) -> Option<CongestionControl> {
+        self.congestion_control.or_else(|| {
+            self.selective_repeat
+                .as_ref()
+                .map(|_| CongestionControl::default())
+        })
+    }
+
+    /// returns a mixnet client config with the packet sizes selected by
+    /// `packet_size` and the traffic selected by `traffic_profile`, to build
+    /// the client the transport is created with.
+    #[cfg(feature = "nym-client")]
+    pub fn mixnet_debug_config(&self) -> DebugConfig {
+        let mut debug_config = DebugConfig::default();
+        self.packet_size.apply_to(&mut debug_config);
+        self.traffic_profile.apply_to(&mut debug_config);
+        debug_config
+    }
+
+    /// returns the details of the network selected by `network`, to pass to
+    /// `MixnetClientBuilder::network_details` when building the client.
+    #[cfg(feature = "nym-client")]
+    pub fn mixnet_network_details(&self) -> Result<NymNetworkDetails, Error> {
+        self.network.details()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2,
+        };
+
+        let mut backoff = policy.initial_backoff;
+        let mut schedule = vec![];
+        for _ in 0..5 {
+            schedule.push(backoff.as_secs());
+            backoff = policy.next_backoff(backoff);
+        }
+        assert_eq!(schedule, vec![1, 2, 4, 5, 5]);
+    }
+
+    #[test]
+    fn test_response_delay() {
+        let delay = ResponseDelay {
+            min: Duration::from_millis(50),
+            max: Duration::from_millis(100),
+        };
+        for _ in 0..1000 {
+            let next = delay.next_delay();
+            assert!(next >= delay.min && next <= delay.max);
+        }
+        let fixed = ResponseDelay {
+            min: Duration::from_millis(50),
+            max: Duration::ZERO,
+        };
+        assert_eq!(fixed.next_delay(), fixed.min);
+    }
+
+    #[test]
+    fn test_cover_traffic_intervals() {
+        let cover = CoverTraffic {
+            mean_interval: Duration::from_millis(100),
+            size: 0,
+        };
+        let intervals: Vec<Duration> = (0..10_000).map(|_| cover.next_interval()).collect();
+        let mean = intervals.iter().sum::<Duration>() / intervals.len() as u32;
+        assert!(mean > Duration::from_millis(90) && mean < Duration::from_millis(110));
+        // exponentially distributed, so about 37% are longer than the mean
+        let longer = intervals
+            .iter()
+            .filter(|&&i| i > cover.mean_interval)
+            .count();
+        assert!((3_000..4_400).contains(&longer), "{}", longer);
+    }
+
+    #[cfg(feature = "nym-client")]
+    #[test]
+    fn test_packet_size_policy() {
+        let config = NymTransportConfig::default();
+        let debug_config = config.mixnet_debug_config();
+        assert_eq!(
+            debug_config.traffic.primary_packet_size,
+            PacketSize::RegularPacket
+        );
+        assert_eq!(debug_config.traffic.secondary_packet_size, None);
+
+        let config = config.with_packet_size(PacketSizePolicy::Auto {
+            extended: PacketSize::ExtendedPacket32,
+        });
+        let debug_config = config.mixnet_debug_config();
+        assert_eq!(
+            debug_config.traffic.primary_packet_size,
+            PacketSize::RegularPacket
+        );
+        assert_eq!(
+            debug_config.traffic.secondary_packet_size,
+            Some(PacketSize::ExtendedPacket32)
+        );
+
+        let mut debug_config = DebugConfig::default();
+        PacketSizePolicy::Fixed(PacketSize::ExtendedPacket16).apply_to(&mut debug_config);
+        assert_eq!(
+            debug_config.traffic.primary_packet_size,
+            PacketSize::ExtendedPacket16
+        );
+        assert_eq!(debug_config.traffic.secondary_packet_size, None);
+    }
+
+    #[cfg(feature = "nym-client")]
+    #[test]
+    fn test_network_presets() {
+        let config = NymTransportConfig::for_network(NetworkPreset::Mainnet);
+        assert!(matches!(config.network, NymNetwork::Mainnet));
+        assert_eq!(config.traffic_profile, TrafficProfile::Anonymous);
+        let debug_config = config.mixnet_debug_config();
+        assert!(!debug_config.cover_traffic.disable_loop_cover_traffic_stream);
+        assert!(
+            !debug_config
+                .traffic
+                .disable_main_poisson_packet_distribution
+        );
+
+        let config = NymTransportConfig::for_network(NetworkPreset::Sandbox("sandbox.env".into()));
+        assert!(matches!(config.network, NymNetwork::EnvFile(_)));
+        assert_eq!(config.handshake_timeout, Duration::from_secs(60));
+        assert_eq!(
+            config.packet_size,
+            PacketSizePolicy::Fixed(PacketSize::RegularPacket)
+        );
+
+        let config = NymTransportConfig::for_network(NetworkPreset::Custom(NymNetwork::FromEnv));
+        assert!(matches!(config.network, NymNetwork::FromEnv));
+        let debug_config = config.mixnet_debug_config();
+        assert!(debug_config.cover_traffic.disable_loop_cover_traffic_stream);
+        assert!(
+            debug_config
+                .traffic
+                .disable_main_poisson_packet_distribution
+        );
+        assert_eq!(
+            debug_config.traffic.secondary_packet_size,
+            Some(PacketSize::ExtendedPacket32)
+        );
+    }
+
+    #[cfg(feature = "nym-client")]
+    #[test]
+    fn test_traffic_profile() {
+        let mut debug_config = DebugConfig::default();
+        debug_config.traffic.average_packet_delay = Duration::from_millis(50);
+        TrafficProfile::NoCoverTraffic.apply_to(&mut debug_config);
+        assert!(debug_config.cover_traffic.disable_loop_cover_traffic_stream);
+        assert!(
+            debug_config
+                .traffic
+                .disable_main_poisson_packet_distribution
+        );
+        assert_eq!(
+            debug_config.traffic.average_packet_delay,
+            Duration::from_millis(50)
+        );
+
+        let config = NymTransportConfig::default().with_traffic_profile(TrafficProfile::LowLatency);
+        let debug_config = config.mixnet_debug_config();
+        assert!(debug_config.cover_traffic.disable_loop_cover_traffic_stream);
+        assert_eq!(debug_config.traffic.average_packet_delay, Duration::ZERO);
+        assert_eq!(
+            debug_config.traffic.message_sending_average_delay,
+            Duration::ZERO
+        );
+    }
+
+    #[cfg(feature = "nym-client")]
+    #[test]
+    fn test_parse_env_file() {
+        let contents = "# sandbox\n\nNETWORK_NAME=sandbox\nexport NYM_API = \"https://sandbox-nym-api1.nymtech.net/api\"\nEMPTY=\nQUOTED='a=b'\n";
+        assert_eq!(
+            parse_env_file(contents).unwrap(),
+            vec![
+                ("NETWORK_NAME", "sandbox"),
+                ("NYM_API", "https://sandbox-nym-api1.nymtech.net/api"),
+                ("EMPTY", ""),
+                ("QUOTED", "a=b"),
+            ]
+        );
+        assert!(matches!(
+            parse_env_file("NETWORK_NAME=sandbox\nNYM_API\n"),
+            Err(Error::InvalidNetworkEnvFile(2))
+        ));
+        assert!(matches!(
+            parse_env_file("=sandbox"),
+            Err(Error::InvalidNetworkEnvFile(1))
+        ));
+    }
+
+    #[cfg(feature = "nym-client")]
+    const TEST_NETWORK_ENV: &str = "NETWORK_NAME=local\nBECH32_PREFIX=n\nMIX_DENOM=unym\nMIX_DENOM_DISPLAY=nym\nSTAKE_DENOM=unyx\nSTAKE_DENOM_DISPLAY=nyx\nDENOMS_EXPONENT=6\nNYXD=http://localhost:26657\nNYM_API=http://localhost:8080\n";
+
+    #[cfg(feature = "nym-client")]
+    #[test]
+    fn test_network_details() {
+        let vars: HashMap<&str, &str> = parse_env_file(TEST_NETWORK_ENV)
+            .unwrap()
+            .into_iter()
+            .collect();
+        let details = network_details(|key| vars.get(key).map(|value| value.to_string())).unwrap();
+        assert_eq!(details.network_name, "local");
+
+        // a missing variable fails instead of panicking
+        let mut incomplete = vars.clone();
+        incomplete.remove("NYM_API");
+        assert!(matches!(
+            network_details(|key| incomplete.get(key).map(|value| value.to_string())),
+            Err(Error::InvalidNetworkVariable("NYM_API"))
+        ));
+    }
+
+    #[cfg(feature = "nym-client")]
+    #[test]
+    fn test_network_env_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.env");
+        fs::write(
+            &path,
+            format!("{TEST_NETWORK_ENV}RUST_LIBP2P_NYM_TEST_NETWORK=local\n"),
+        )
+        .unwrap();
+
+        // the file is read without touching the process environment
+        let details = NymNetwork::EnvFile(path.clone()).details().unwrap();
+        assert_eq!(details.network_name, "local");
+        assert!(std::env::var_os("RUST_LIBP2P_NYM_TEST_NETWORK").is_none());
+
+        assert!(matches!(
+            NymNetwork::EnvFile(dir.path().join("missing.env")).details(),
+            Err(Error::NetworkEnvFileIo(_))
+        ));
+    }
+}
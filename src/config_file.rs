@@ -0,0 +1,521 @@
+use libp2p::core::multiaddr::Multiaddr;
+use nym_sphinx::params::PacketSize;
+use serde::Deserialize;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::bootstrap;
+#[cfg(feature = "nym-client")]
+use super::client::ManagedMixnetClient;
+use super::config::{
+    NymNetwork, NymTransportConfig, PacketSizePolicy, RetryPolicy, TrafficProfile,
+};
+use super::error::Error;
+
+/// ConfigFile holds the settings of a node read from a TOML or JSON file, so
+/// that operators can tune it without recompiling: the transport's timeouts
+/// and limits, and the mixnet client it runs on. Every setting is optional;
+/// those which aren't set keep their defaults.
+///
+/// Durations are given as strings such as `"30s"` or `"5m"`. Limits which
+/// may be disabled are disabled by setting them to 0.
+///
+/// ```toml
+/// deduplicate_dials = true
+///
+/// [timeouts]
+/// handshake = "60s"
+/// idle = "10m"
+///
+/// [limits]
+/// max_substream_buffer = 1048576
+/// max_outbound_backlog = 0
+///
+/// [mixnet]
+/// network = { env-file = "sandbox.env" }
+/// traffic_profile = "no-cover-traffic"
+/// packet_size = { auto = "extended32" }
+/// storage_dir = "/var/lib/node/mixnet"
+/// connect_timeout = "2m"
+///
+/// [bootstrap]
+/// peers = ["/nym/..."]
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub deduplicate_dials: Option<bool>,
+    pub encrypt_payloads: Option<bool>,
+    pub unordered_delivery: Option<bool>,
+    pub address_exchange: Option<bool>,
+    pub connection_migration: Option<bool>,
+    pub stable_identity: Option<bool>,
+    pub timeouts: TimeoutsSection,
+    pub limits: LimitsSection,
+    pub dial: DialSection,
+    pub mixnet: MixnetSection,
+    pub bootstrap: BootstrapSection,
+}
+
+/// TimeoutsSection is the `[timeouts]` section of a `ConfigFile`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsSection {
+    #[serde(with = "humantime_serde")]
+    pub handshake: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub max_handshake_age: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub message_ttl: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub idle: Option<Duration>,
+}
+
+/// LimitsSection is the `[limits]` section of a `ConfigFile`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    /// must be non-zero; see `NymTransportConfig::max_frame_size`.
+    pub max_frame_size: Option<NonZeroUsize>,
+    pub max_substream_buffer: Option<usize>,
+    pub outbound_backlog_threshold: Option<usize>,
+    pub max_outbound_backlog: Option<usize>,
+    pub max_unsent_bytes: Option<usize>,
+    pub max_buffered_bytes: Option<usize>,
+    pub max_buffered_bytes_per_connection: Option<usize>,
+}
+
+/// DialSection is the `[dial]` section of a `ConfigFile`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DialSection {
+    pub retry: Option<RetrySection>,
+    pub max_concurrent: Option<usize>,
+    pub max_queued: Option<usize>,
+}
+
+/// RetrySection describes a `RetryPolicy` in a `ConfigFile`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RetrySection {
+    #[serde(with = "humantime_serde")]
+    pub initial_backoff: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+    pub multiplier: u32,
+}
+
+/// MixnetSection is the `[mixnet]` section of a `ConfigFile`, with the
+/// options of the mixnet client and the network it connects to.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MixnetSection {
+    pub network: Option<NetworkSetting>,
+    pub traffic_profile: Option<TrafficProfileSetting>,
+    pub packet_size: Option<PacketSizeSetting>,
+    /// where the client's keys are kept; see `ManagedMixnetClient::with_storage_dir`.
+    pub storage_dir: Option<PathBuf>,
+    /// the identity key of the gateway to register with.
+    pub gateway: Option<String>,
+    /// gateways to fail over to when the client's gateway goes away.
+    pub failover_gateways: Option<Vec<String>>,
+    #[serde(with = "humantime_serde")]
+    pub connect_timeout: Option<Duration>,
+    /// 0 retries until an attempt succeeds.
+    pub max_attempts: Option<u32>,
+    pub connect_retry: Option<RetrySection>,
+}
+
+/// BootstrapSection is the `[bootstrap]` section of a `ConfigFile`, with the
+/// peers dialed on startup; see `bootstrap::Behaviour`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootstrapSection {
+    pub peers: Vec<Multiaddr>,
+    pub retry: Option<RetrySection>,
+    /// 0 retries until the peer is connected.
+    pub max_attempts: Option<u32>,
+    pub reconnect: Option<bool>,
+}
+
+/// NetworkSetting selects a `NymNetwork` in a `ConfigFile`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NetworkSetting {
+    Mainnet,
+    FromEnv,
+    EnvFile(PathBuf),
+}
+
+/// TrafficProfileSetting selects a `TrafficProfile` in a `ConfigFile`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrafficProfileSetting {
+    Anonymous,
+    NoCoverTraffic,
+    LowLatency,
+}
+
+/// PacketSizeSetting selects a `PacketSizePolicy` in a `ConfigFile`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PacketSizeSetting {
+    Fixed(PacketSizeName),
+    Auto(PacketSizeName),
+}
+
+/// PacketSizeName names a sphinx `PacketSize` in a `ConfigFile`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PacketSizeName {
+    Regular,
+    Extended8,
+    Extended16,
+    Extended32,
+}
+
+impl From<PacketSizeName> for PacketSize {
+    fn from(name: PacketSizeName) -> Self {
+        match name {
+            PacketSizeName::Regular => PacketSize::RegularPacket,
+            PacketSizeName::Extended8 => PacketSize::ExtendedPacket8,
+            PacketSizeName::Extended16 => PacketSize::ExtendedPacket16,
+            PacketSizeName::Extended32 => PacketSize::ExtendedPacket32,
+        }
+    }
+}
+
+impl From<RetrySection> for RetryPolicy {
+    fn from(retry: RetrySection) -> Self {
+        RetryPolicy {
+            initial_backoff: retry.initial_backoff,
+            max_backoff: retry.max_backoff,
+            multiplier: retry.multiplier,
+        }
+    }
+}
+
+/// returns `None` for a limit of 0, which disables it.
+fn limit(value: usize) -> Option<usize> {
+    (value > 0).then_some(value)
+}
+
+impl ConfigFile {
+    /// reads the file at the given path, as JSON if its extension is `.json`
+    /// and as TOML otherwise.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(Error::ConfigFileIo)?;
+        if path.extension().is_some_and(|ext| ext == "json") {
+            Self::from_json(&contents)
+        } else {
+            Self::from_toml(&contents)
+        }
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self, Error> {
+        toml::from_str(contents).map_err(|e| Error::InvalidConfigFile(e.to_string()))
+    }
+
+    pub fn from_json(contents: &str) -> Result<Self, Error> {
+        serde_json::from_str(contents).map_err(|e| Error::InvalidConfigFile(e.to_string()))
+    }
+
+    /// returns the config of the `bootstrap::Behaviour` dialing the file's
+    /// bootstrap peers.
+    pub fn bootstrap_config(&self) -> bootstrap::Config {
+        let section = &self.bootstrap;
+        let mut config = bootstrap::Config::new(section.peers.clone());
+        if let Some(retry) = &section.retry {
+            config = config.with_retry(retry.clone().into());
+        }
+        if let Some(max_attempts) = section.max_attempts.filter(|max| *max > 0) {
+            config = config.with_max_attempts(max_attempts);
+        }
+        if let Some(reconnect) = section.reconnect {
+            config = config.with_reconnect(reconnect);
+        }
+        config
+    }
+
+    /// returns the default transport config with the file's settings applied.
+    pub fn transport_config(&self) -> NymTransportConfig {
+        self.apply_to(NymTransportConfig::default())
+    }
+
+    /// applies the file's settings to the given transport config, eg. one
+    /// returned by `NymTransportConfig::for_network`.
+    pub fn apply_to(&self, mut config: NymTransportConfig) -> NymTransportConfig {
+        if let Some(enabled) = self.deduplicate_dials {
+            config.deduplicate_dials = enabled;
+        }
+        if let Some(enabled) = self.encrypt_payloads {
+            config.encrypt_payloads = enabled;
+        }
+        if let Some(enabled) = self.unordered_delivery {
+            config.unordered_delivery = enabled;
+        }
+        if let Some(enabled) = self.address_exchange {
+            config.address_exchange = enabled;
+        }
+        if let Some(enabled) = self.connection_migration {
+            config.connection_migration = enabled;
+        }
+        if let Some(enabled) = self.stable_identity {
+            config.stable_identity = enabled;
+        }
+
+        let timeouts = &self.timeouts;
+        if let Some(timeout) = timeouts.handshake {
+            config.handshake_timeout = timeout;
+        }
+        if let Some(max_age) = timeouts.max_handshake_age {
+            config.max_handshake_age = max_age;
+        }
+        if let Some(ttl) = timeouts.message_ttl {
+            config.message_ttl = Some(ttl);
+        }
+        if let Some(timeout) = timeouts.idle {
+            config.idle_timeout = Some(timeout);
+        }
+
+        let limits = &self.limits;
+        if let Some(max_bytes) = limits.max_frame_size {
+            config.max_frame_size = max_bytes.get();
+        }
+        if let Some(max_bytes) = limits.max_substream_buffer {
+            config.max_substream_buffer = limit(max_bytes);
+        }
+        if let Some(threshold) = limits.outbound_backlog_threshold {
+            config.outbound_backlog_threshold = limit(threshold);
+        }
+        if let Some(max_messages) = limits.max_outbound_backlog {
+            config.max_outbound_backlog = limit(max_messages);
+        }
+        if let Some(max_bytes) = limits.max_unsent_bytes {
+            config.max_unsent_bytes = limit(max_bytes);
+        }
+        if let Some(max_bytes) = limits.max_buffered_bytes {
+            config.max_buffered_bytes = limit(max_bytes);
+        }
+        if let Some(max_bytes) = limits.max_buffered_bytes_per_connection {
+            config.max_buffered_bytes_per_connection = limit(max_bytes);
+        }
+
+        let dial = &self.dial;
+        if let Some(retry) = &dial.retry {
+            config.dial_retry = Some(retry.clone().into());
+        }
+        if dial.max_concurrent.is_some() || dial.max_queued.is_some() {
+            let mut limits = config.dial_limits.unwrap_or_default();
+            limits.max_concurrent = dial.max_concurrent.unwrap_or(limits.max_concurrent);
+            limits.max_queued = dial.max_queued.unwrap_or(limits.max_queued);
+            config.dial_limits = Some(limits);
+        }
+
+        let mixnet = &self.mixnet;
+        if let Some(network) = &mixnet.network {
+            config.network = match network {
+                NetworkSetting::Mainnet => NymNetwork::Mainnet,
+                NetworkSetting::FromEnv => NymNetwork::FromEnv,
+                NetworkSetting::EnvFile(path) => NymNetwork::EnvFile(path.clone()),
+            };
+        }
+        if let Some(profile) = mixnet.traffic_profile {
+            config.traffic_profile = match profile {
+                TrafficProfileSetting::Anonymous => TrafficProfile::Anonymous,
+                TrafficProfileSetting::NoCoverTraffic => TrafficProfile::NoCoverTraffic,
+                TrafficProfileSetting::LowLatency => TrafficProfile::LowLatency,
+            };
+        }
+        if let Some(packet_size) = mixnet.packet_size {
+            config.packet_size = match packet_size {
+                PacketSizeSetting::Fixed(size) => PacketSizePolicy::Fixed(size.into()),
+                PacketSizeSetting::Auto(extended) => PacketSizePolicy::Auto {
+                    extended: extended.into(),
+                },
+            };
+        }
+        if let Some(gateways) = &mixnet.failover_gateways {
+            let mut failover = config.gateway_failover.unwrap_or_default();
+            failover.gateways = gateways.clone();
+            config.gateway_failover = Some(failover);
+        }
+        config
+    }
+
+    /// returns a `ManagedMixnetClient` for the given transport config, eg.
+    /// the one returned by `transport_config`, with the file's client options.
+    #[cfg(feature = "nym-client")]
+    pub fn mixnet_client(&self, config: &NymTransportConfig) -> Result<ManagedMixnetClient, Error> {
+        let mixnet = &self.mixnet;
+        let mut client = ManagedMixnetClient::new(config)?;
+        if let Some(dir) = &mixnet.storage_dir {
+            client = client.with_storage_dir(dir);
+        }
+        if let Some(gateway) = &mixnet.gateway {
+            client = client.with_gateway(gateway);
+        }
+        if let Some(timeout) = mixnet.connect_timeout {
+            client = client.with_connect_timeout(timeout);
+        }
+        if let Some(attempts) = mixnet.max_attempts {
+            client = client.with_max_attempts((attempts > 0).then_some(attempts));
+        }
+        if let Some(retry) = &mixnet.connect_retry {
+            client = client.with_retry(retry.clone().into());
+        }
+        Ok(client)
+    }
+}
+
+impl NymTransportConfig {
+    /// reads the transport config from a TOML or JSON file; see `ConfigFile`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(ConfigFile::load(path)?.transport_config())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::{DialLimits, GatewayFailover};
+    use super::*;
+
+    #[test]
+    fn test_config_file() {
+        let toml = r#"
+            deduplicate_dials = false
+
+            [timeouts]
+            handshake = "1m"
+            idle = "90s"
+
+            [limits]
+            max_frame_size = 32768
+            max_outbound_backlog = 0
+            max_buffered_bytes_per_connection = 4096
+
+            [dial]
+            max_queued = 8
+            retry = { initial_backoff = "1s", max_backoff = "10s", multiplier = 3 }
+
+            [mixnet]
+            network = { env-file = "sandbox.env" }
+            traffic_profile = "low-latency"
+            packet_size = { auto = "extended16" }
+            failover_gateways = ["a", "b"]
+            gateway = "a"
+            max_attempts = 0
+
+            [bootstrap]
+            peers = ["/ip4/127.0.0.1/tcp/1"]
+            max_attempts = 5
+        "#;
+        let file = ConfigFile::from_toml(toml).unwrap();
+        let config = file.transport_config();
+        assert!(!config.deduplicate_dials);
+        assert_eq!(config.handshake_timeout, Duration::from_secs(60));
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(90)));
+        assert_eq!(config.max_frame_size, 32768);
+        assert_eq!(config.max_buffered_bytes_per_connection, Some(4096));
+        assert_eq!(config.max_outbound_backlog, None);
+        // what isn't set keeps its default
+        let defaults = NymTransportConfig::default();
+        assert_eq!(config.max_handshake_age, defaults.max_handshake_age);
+        assert_eq!(config.max_substream_buffer, defaults.max_substream_buffer);
+
+        let dial_limits = config.dial_limits.unwrap();
+        assert_eq!(dial_limits.max_queued, 8);
+        assert_eq!(
+            dial_limits.max_concurrent,
+            DialLimits::default().max_concurrent
+        );
+        assert_eq!(config.dial_retry.as_ref().unwrap().multiplier, 3);
+        assert!(matches!(config.network, NymNetwork::EnvFile(_)));
+        assert_eq!(config.traffic_profile, TrafficProfile::LowLatency);
+        assert_eq!(
+            config.packet_size,
+            PacketSizePolicy::Auto {
+                extended: PacketSize::ExtendedPacket16
+            }
+        );
+        assert_eq!(
+            config.gateway_failover.as_ref().unwrap().gateways,
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            config.gateway_failover.unwrap().retry,
+            GatewayFailover::default().retry
+        );
+        assert_eq!(
+            file.bootstrap.peers,
+            vec!["/ip4/127.0.0.1/tcp/1".parse::<Multiaddr>().unwrap()]
+        );
+        assert_eq!(file.bootstrap.max_attempts, Some(5));
+
+        // the same settings may be given as JSON
+        let json = r#"{
+            "deduplicate_dials": false,
+            "timeouts": { "handshake": "1m", "idle": "90s" },
+            "limits": {
+                "max_frame_size": 32768,
+                "max_outbound_backlog": 0,
+                "max_buffered_bytes_per_connection": 4096
+            },
+            "dial": {
+                "max_queued": 8,
+                "retry": { "initial_backoff": "1s", "max_backoff": "10s", "multiplier": 3 }
+            },
+            "mixnet": {
+                "network": { "env-file": "sandbox.env" },
+                "traffic_profile": "low-latency",
+                "packet_size": { "auto": "extended16" },
+                "failover_gateways": ["a", "b"],
+                "gateway": "a",
+                "max_attempts": 0
+            },
+            "bootstrap": { "peers": ["/ip4/127.0.0.1/tcp/1"], "max_attempts": 5 }
+        }"#;
+        assert_eq!(ConfigFile::from_json(json).unwrap(), file);
+
+        assert!(matches!(
+            ConfigFile::from_toml("[timeouts]\nhandshake = 30"),
+            Err(Error::InvalidConfigFile(_))
+        ));
+        assert!(matches!(
+            ConfigFile::from_toml("unknown = true"),
+            Err(Error::InvalidConfigFile(_))
+        ));
+        // a frame must be able to carry some data
+        assert!(matches!(
+            ConfigFile::from_toml("[limits]\nmax_frame_size = 0"),
+            Err(Error::InvalidConfigFile(_))
+        ));
+    }
+
+    #[test]
+    fn test_config_file_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node.json");
+        fs::write(&path, r#"{ "timeouts": { "handshake": "45s" } }"#).unwrap();
+        let config = NymTransportConfig::from_file(&path).unwrap();
+        assert_eq!(config.handshake_timeout, Duration::from_secs(45));
+
+        let path = dir.path().join("node.toml");
+        fs::write(&path, "[mixnet]\ngateway = \"gw\"\nmax_attempts = 0\n").unwrap();
+        let file = ConfigFile::load(&path).unwrap();
+        assert_eq!(file.mixnet.gateway.as_deref(), Some("gw"));
+        #[cfg(feature = "nym-client")]
+        {
+            let client = file.mixnet_client(&file.transport_config()).unwrap();
+            assert_eq!(client.gateway.as_deref(), Some("gw"));
+        }
+
+        assert!(matches!(
+            ConfigFile::load(dir.path().join("missing.toml")),
+            Err(Error::ConfigFileIo(_))
+        ));
+    }
+}
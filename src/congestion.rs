@@ -0,0 +1,229 @@
+use parking_lot::Mutex;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+    time::Instant,
+};
+
+use super::config::CongestionControl;
+
+/// CongestionWindow bounds the TransportMessages a connection has in flight,
+/// ie. sent but not acknowledged by the remote yet, with the window managed by
+/// additive increase, multiplicative decrease; see `CongestionControl`.
+///
+/// It's shared by the substreams of the connection, which wait for room in
+/// the window before writing, and the transport, which feeds it the acks.
+#[derive(Debug)]
+pub(crate) struct CongestionWindow {
+    config: CongestionControl,
+    /// the connection's outbound nonce counter; every nonce below it was sent.
+    message_nonce: Arc<AtomicU64>,
+    state: Mutex<WindowState>,
+    /// writers waiting for room in the window.
+    waiters: Mutex<Vec<Waker>>,
+}
+
+#[derive(Debug)]
+struct WindowState {
+    /// window size in messages; fractional, since congestion avoidance grows
+    /// it by less than one message per ack.
+    window: f64,
+    /// the window grows exponentially below this, and linearly above it.
+    slow_start_threshold: f64,
+    /// highest nonce acknowledged, or given up on as lost.
+    acked: u64,
+    /// when the last ack arrived, or the window was last idle.
+    last_progress: Instant,
+}
+
+impl CongestionWindow {
+    pub(crate) fn new(config: CongestionControl, message_nonce: Arc<AtomicU64>) -> Self {
+        let min_window = config.min_window.max(1);
+        let max_window = config.max_window.max(min_window);
+        // nonces start at 1, so none below the current one were acknowledged
+        let acked = message_nonce.load(Ordering::SeqCst).saturating_sub(1);
+        CongestionWindow {
+            state: Mutex::new(WindowState {
+                window: config.initial_window.clamp(min_window, max_window) as f64,
+                slow_start_threshold: max_window as f64,
+                acked,
+                last_progress: Instant::now(),
+            }),
+            config: CongestionControl {
+                min_window,
+                max_window,
+                ..config
+            },
+            message_nonce,
+            waiters: Mutex::new(vec![]),
+        }
+    }
+
+    /// returns the current window size, in messages.
+    pub(crate) fn window(&self) -> u32 {
+        self.state.lock().window as u32
+    }
+
+    /// returns the number of messages sent but not acknowledged yet.
+    #[cfg(test)]
+    pub(crate) fn in_flight(&self) -> u64 {
+        self.in_flight_locked(&self.state.lock())
+    }
+
+    fn in_flight_locked(&self, state: &WindowState) -> u64 {
+        let sent = self.message_nonce.load(Ordering::SeqCst).saturating_sub(1);
+        sent.saturating_sub(state.acked)
+    }
+
+    /// polls until the window has room for another message.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.has_room() {
+            return Poll::Ready(());
+        }
+        self.waiters.lock().push(cx.waker().clone());
+        // an ack may have arrived before the waker was registered
+        if self.has_room() {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+
+    fn has_room(&self) -> bool {
+        let mut state = self.state.lock();
+        let in_flight = self.in_flight_locked(&state);
+        if in_flight == 0 {
+            // the loss timeout only runs while something is in flight
+            state.last_progress = Instant::now();
+        }
+        (in_flight as f64) < state.window.floor()
+    }
+
+    /// handles an ack of every message up to and including `nonce`, and
+    /// returns the number of messages it newly acknowledged.
+    pub(crate) fn on_ack(&self, nonce: u64) -> u64 {
+        let sent = self.message_nonce.load(Ordering::SeqCst).saturating_sub(1);
+        let mut state = self.state.lock();
+        // acks may be reordered, and a remote may ack what we never sent
+        let nonce = nonce.min(sent);
+        if nonce <= state.acked {
+            return 0;
+        }
+
+        let newly_acked = nonce - state.acked;
+        state.acked = nonce;
+        state.last_progress = Instant::now();
+        if state.window < state.slow_start_threshold {
+            state.window += newly_acked as f64;
+        } else {
+            state.window += newly_acked as f64 / state.window;
+        }
+        state.window = state.window.min(self.config.max_window as f64);
+        drop(state);
+        self.wake_waiters();
+        newly_acked
+    }
+
+    /// halves the window if nothing was acknowledged for the loss timeout
+    /// while messages were in flight, and gives up on those messages, so that
+    /// lost acks can't stall the connection. Returns whether it did.
+    pub(crate) fn check_loss(&self) -> bool {
+        let sent = self.message_nonce.load(Ordering::SeqCst).saturating_sub(1);
+        let mut state = self.state.lock();
+        if sent <= state.acked || state.last_progress.elapsed() < self.config.loss_timeout {
+            return false;
+        }
+
+        state.slow_start_threshold = (state.window / 2.0).max(self.config.min_window as f64);
+        state.window = state.slow_start_threshold;
+        state.acked = sent;
+        state.last_progress = Instant::now();
+        drop(state);
+        self.wake_waiters();
+        true
+    }
+
+    fn wake_waiters(&self) {
+        for waker in self.waiters.lock().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use std::time::Duration;
+
+    /// sends a message, as a substream would once the window has room.
+    fn send(nonce: &AtomicU64) {
+        nonce.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_congestion_window() {
+        let nonce = Arc::new(AtomicU64::new(1));
+        let window = CongestionWindow::new(
+            CongestionControl {
+                initial_window: 2,
+                min_window: 1,
+                max_window: 8,
+                loss_timeout: Duration::ZERO,
+                ..Default::default()
+            },
+            nonce.clone(),
+        );
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        // the window bounds the messages in flight
+        assert!(window.poll_ready(&mut cx).is_ready());
+        send(&nonce);
+        send(&nonce);
+        assert_eq!(window.in_flight(), 2);
+        assert!(window.poll_ready(&mut cx).is_pending());
+
+        // slow start grows the window by one message per ack
+        assert_eq!(window.on_ack(2), 2);
+        assert_eq!(window.window(), 4);
+        assert_eq!(window.in_flight(), 0);
+        assert!(window.waiters.lock().is_empty());
+
+        // stale acks and acks of unsent messages are ignored
+        assert_eq!(window.on_ack(1), 0);
+        assert_eq!(window.on_ack(100), 0);
+        assert_eq!(window.window(), 4);
+        for _ in 0..6 {
+            send(&nonce);
+        }
+        window.on_ack(10);
+        assert_eq!(window.window(), 8);
+
+        // a loss halves the window and gives up on the messages in flight
+        send(&nonce);
+        assert!(window.check_loss());
+        assert_eq!(window.window(), 4);
+        assert_eq!(window.in_flight(), 0);
+        assert!(!window.check_loss());
+
+        // above the threshold, the window grows by one message per window
+        for _ in 0..6 {
+            send(&nonce);
+        }
+        window.on_ack(12);
+        assert_eq!(window.window(), 4);
+        window.on_ack(13);
+        assert_eq!(window.window(), 4);
+        window.on_ack(15);
+        assert_eq!(window.window(), 5);
+
+        // the window never shrinks below the minimum
+        for _ in 0..5 {
+            send(&nonce);
+            assert!(window.check_loss());
+        }
+        assert_eq!(window.window(), 1);
+    }
+}
@@ -1,32 +1,221 @@
-use libp2p::core::{muxing::StreamMuxerEvent, PeerId, StreamMuxer};
-use log::debug;
-use nym_sdk::mixnet::AnonymousSenderTag;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use libp2p::core::{muxing::StreamMuxerEvent, Endpoint, PeerId, StreamMuxer};
+use libp2p_identity::Keypair;
+use log::{debug, warn};
 use nym_sphinx::addressing::clients::Recipient;
+use nym_sphinx::anonymous_replies::requests::AnonymousSenderTag;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
+    fmt::{Debug, Formatter},
+    future::Future,
     pin::Pin,
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
     },
     task::{Context, Poll, Waker},
+    time::{Duration, Instant},
 };
 use tokio::sync::{
-    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    mpsc::{unbounded_channel, Receiver, UnboundedReceiver, UnboundedSender},
     oneshot,
 };
-use tracing::field::debug;
+use tokio::time::{sleep, Sleep};
 
+use super::bandwidth::PeerBandwidth;
+use super::budget::{BufferKind, MemoryBudget};
+use super::compression::DataCodec;
+use super::config::{CoverTraffic, ResponseDelay, SubstreamOpenTimeout};
+use super::congestion::CongestionWindow;
 use super::error::Error;
+use super::framing::FrameSizer;
 use super::message::{
-    ConnectionId, Message, OutboundMessage, SubstreamId, SubstreamMessage, SubstreamMessageType,
-    TransportMessage,
+    ConnectionId, Message, OutOfBandMessage, OutboundMessage, SubstreamId, SubstreamMessage,
+    SubstreamMessageType, TransportMessage,
 };
-use super::substream::Substream;
+use super::metrics::TransportMetrics;
+use super::mixnet::OutboundSender;
+use super::redact::{redact, redact_always};
+use super::session::{HandshakeSecret, Session};
+use super::stats::ConnectionStatsRegistry;
+use super::substream::{Closed, Substream, SubstreamState};
+use super::ticket::SessionTicket;
+use super::{DEFAULT_MAX_FRAME_BYTES, DEFAULT_MAX_UNSENT_BYTES, POLL_BUDGET};
+
+/// CloseCode is the reason a connection was closed deliberately, which is
+/// sent to the remote along with the close. It's serialized as its code on
+/// the wire. New codes may be added, so matches need a wildcard arm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u16", into = "u16")]
+#[non_exhaustive]
+pub enum CloseCode {
+    /// the connection or the transport was dropped.
+    Shutdown,
+    /// the connection carried no traffic for the idle timeout.
+    Idle,
+    /// the peer isn't allowed by the peer filter.
+    PeerNotAllowed,
+    /// the peer exceeded a resource limit, eg. the reorder window.
+    ResourceLimit,
+    /// the peer sent something it shouldn't have.
+    ProtocolError,
+    /// the peer stopped acknowledging the messages sent to it.
+    Unresponsive,
+    /// our nym address changed, eg. after failing over to another gateway,
+    /// so the peer can't reach us over the connection anymore.
+    AddressChanged,
+    /// the session ticket the connection was resumed with wasn't accepted, eg.
+    /// because it expired; the dialer has to dial again.
+    TicketRejected,
+    /// the listener is too busy to take the connection right now; the dialer
+    /// may dial again later.
+    Busy,
+    /// the dialer's handshake asked for options the listener doesn't
+    /// support or doesn't accept, eg. no common cipher suite.
+    VersionMismatch,
+    /// a code this version doesn't know about.
+    Other(u16),
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => CloseCode::Shutdown,
+            2 => CloseCode::Idle,
+            3 => CloseCode::PeerNotAllowed,
+            4 => CloseCode::ResourceLimit,
+            5 => CloseCode::ProtocolError,
+            6 => CloseCode::Unresponsive,
+            7 => CloseCode::AddressChanged,
+            8 => CloseCode::TicketRejected,
+            9 => CloseCode::Busy,
+            10 => CloseCode::VersionMismatch,
+            code => CloseCode::Other(code),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> Self {
+        match code {
+            CloseCode::Shutdown => 1,
+            CloseCode::Idle => 2,
+            CloseCode::PeerNotAllowed => 3,
+            CloseCode::ResourceLimit => 4,
+            CloseCode::ProtocolError => 5,
+            CloseCode::Unresponsive => 6,
+            CloseCode::AddressChanged => 7,
+            CloseCode::TicketRejected => 8,
+            CloseCode::Busy => 9,
+            CloseCode::VersionMismatch => 10,
+            CloseCode::Other(code) => code,
+        }
+    }
+}
+
+/// CloseReason is why a connection was closed: a code, and optionally a
+/// human-readable description, eg. the error which caused the close.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct CloseReason {
+    pub code: CloseCode,
+    pub message: Option<String>,
+}
+
+impl CloseReason {
+    pub fn new(code: CloseCode) -> Self {
+        CloseReason {
+            code,
+            message: None,
+        }
+    }
+
+    pub fn with_message(mut self, message: impl Into<String>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} ({})", self.code, u16::from(self.code))?;
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+/// SenderTag identifies the anonymous sender of an inbound connection, ie. the
+/// dialer's nym client, which is replied to with the SURBs it attached. It's
+/// opaque: tags can be compared and hashed, eg. to recognize connections from
+/// the same client, but the tag itself isn't exposed.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SenderTag(pub(crate) AnonymousSenderTag);
+
+impl Debug for SenderTag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SenderTag({})", redact_always(self.0))
+    }
+}
+
+/// a request to open a substream, which is answered with the substream.
+type OpenRequest = oneshot::Sender<Result<Substream, Error>>;
+
+/// OutboundOpen is a substream we opened which waits for the remote's
+/// OpenResponse; see `SubstreamOpenTimeout`.
+struct OutboundOpen {
+    substream: Substream,
+    /// the number of times the OpenRequest was sent again.
+    retries: usize,
+    /// when the OpenRequest is sent again, or the open given up on.
+    deadline: Pin<Box<Sleep>>,
+}
+
+/// ConnectionControl opens substreams on a `Connection` from outside the
+/// swarm, like `libp2p-stream` does, eg. for tools which want raw streams
+/// over the mixnet without writing a `NetworkBehaviour`. Get one with
+/// `Connection::control()` before the connection is handed to the swarm,
+/// which must keep polling the connection for substreams to open.
+#[derive(Clone)]
+pub struct ConnectionControl {
+    peer_id: PeerId,
+    id: ConnectionId,
+    open_tx: UnboundedSender<OpenRequest>,
+}
+
+impl Debug for ConnectionControl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionControl")
+            .field("peer_id", &redact(self.peer_id))
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ConnectionControl {
+    /// returns the remote peer of the connection.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// opens a substream on the connection. Fails with
+    /// `Error::ConnectionDropped` once the connection was dropped.
+    pub async fn open_substream(&self) -> Result<Substream, Error> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.open_tx
+            .send(reply_tx)
+            .map_err(|_| Error::ConnectionDropped)?;
+        reply_rx.await.map_err(|_| Error::ConnectionDropped)?
+    }
+}
 
 /// Connection represents the result of a connection setup process.
 /// It implements `StreamMuxer` and thus has stream multiplexing built in.
-#[derive(Debug)]
 pub struct Connection {
     pub(crate) peer_id: PeerId,
     /// This will be Some(Receipient) for dialing connections since the outbound conn knows the nym/ multiaddr of the recipient, whereas receivers of connection requests will reply with SURBs
@@ -36,20 +225,63 @@ pub struct Connection {
     /// receive inbound messages from the `InnerConnection`
     pub(crate) inbound_rx: UnboundedReceiver<SubstreamMessage>,
 
+    /// receives the data of inbound OutOfBandMessages; see `poll_out_of_band`.
+    out_of_band_rx: Option<Receiver<Bytes>>,
+
+    /// receives why the transport closed the connection, if it was closed
+    /// deliberately; returned from `poll` once `inbound_rx` is closed.
+    closed_rx: Option<oneshot::Receiver<Error>>,
+
+    /// our side of the connection, which the IDs of the substreams we open
+    /// are generated for; only the dialer knows the remote's address.
+    endpoint: Endpoint,
+
+    /// if set, the remote generates its substream IDs for its side of the
+    /// connection as well, so OpenRequests for IDs on our side are refused.
+    substream_directions: bool,
+    /// if set, both sides support half-close: a substream's Close only ends
+    /// its sender's writes, and the other side can still write to it.
+    half_close: bool,
+
     /// substream ID -> outbound pending substream exists
     /// the key is deleted when the response is received, or the request times out
     pending_substreams: HashSet<SubstreamId>,
 
     /// substream ID -> substream's inbound_tx channel
-    substream_inbound_txs: HashMap<SubstreamId, UnboundedSender<Vec<u8>>>,
+    substream_inbound_txs: HashMap<SubstreamId, UnboundedSender<Bytes>>,
 
     /// substream ID -> substream's close_tx channel
-    substream_close_txs: HashMap<SubstreamId, oneshot::Sender<()>>,
+    substream_close_txs: HashMap<SubstreamId, oneshot::Sender<Closed>>,
+
+    /// substream ID -> number of bytes received but not yet read on the substream
+    substream_buffered: HashMap<SubstreamId, Arc<AtomicUsize>>,
+
+    /// substream ID -> whether we closed the substream
+    substream_closed_locally: HashMap<SubstreamId, Arc<AtomicBool>>,
+
+    /// substream ID -> number of bytes not yet read on a substream the remote
+    /// closed; the entry is removed once they've all been read.
+    half_closed_substreams: HashMap<SubstreamId, Arc<AtomicUsize>>,
+
+    /// substreams whose unread data would exceed this many bytes get no more
+    /// data until their reader catches up.
+    max_substream_buffer: Option<usize>,
+
+    /// data held back until its substream's reader makes room for it, or
+    /// the memory budget does; the messages after it wait behind it, since
+    /// they're handled in order.
+    stalled: Option<(SubstreamId, Bytes)>,
+
+    /// passed on to new substreams; see `Substream::with_write_limits`.
+    max_frame_size: usize,
+    max_unsent_bytes: Option<usize>,
+    /// if set, picks the frame size of new substreams below `max_frame_size`.
+    frame_sizer: Option<Arc<FrameSizer>>,
 
     /// send messages to the mixnet
     /// used for sending `SubstreamMessageType::OpenRequest` messages
     /// also passed to each substream so they can write to the mixnet
-    pub(crate) mixnet_outbound_tx: UnboundedSender<OutboundMessage>,
+    pub(crate) mixnet_outbound_tx: OutboundSender,
 
     /// sender_tag for SURB replies to incoming messages
     pub(crate) sender_tag: Option<AnonymousSenderTag>,
@@ -62,11 +294,92 @@ pub struct Connection {
     close_tx: UnboundedSender<SubstreamId>,
     close_rx: UnboundedReceiver<SubstreamId>,
 
+    /// substreams requested through a `ConnectionControl`; opened in poll.
+    open_tx: UnboundedSender<OpenRequest>,
+    open_rx: UnboundedReceiver<OpenRequest>,
+
     /// message nonce contains the next nonce that should be used when
     /// sending a message over the connection
     pub(crate) message_nonce: Arc<AtomicU64>,
 
-    waker: Option<Waker>,
+    /// if set, outbound TransportMessages are encrypted with the session keys
+    pub(crate) session: Option<Arc<Session>>,
+
+    /// if set, outbound TransportMessages use the compact ID encoding
+    compact_ids: bool,
+
+    /// if set, data is compressed with the negotiated algorithm
+    pub(crate) codec: Option<DataCodec>,
+
+    /// the registry holding this connection's stats; the entry is removed on drop.
+    stats_registry: Option<ConnectionStatsRegistry>,
+
+    /// the transport's metrics, which polls of the connection are recorded in.
+    metrics: Option<Arc<TransportMetrics>>,
+
+    /// tells the transport the connection was dropped, so it frees its state.
+    dropped_tx: Option<UnboundedSender<ConnectionId>>,
+
+    /// the transport's budget, which substream buffers count against.
+    budget: Arc<MemoryBudget>,
+
+    /// if set, substreams wait for room in the window before writing.
+    congestion: Option<Arc<CongestionWindow>>,
+
+    /// the remote peer's entry in the transport's bandwidth ledger.
+    bandwidth: Option<Arc<PeerBandwidth>>,
+
+    /// protocols which make a substream bulk if it negotiates them.
+    bulk_protocols: Arc<[String]>,
+
+    /// if set, substreams hold small writes back for up to this long.
+    flush_interval: Option<Duration>,
+
+    /// if set, dummy messages are sent at random intervals; see `CoverTraffic`.
+    cover: Option<(CoverTraffic, Pin<Box<Sleep>>)>,
+
+    /// the number of cover messages sent, which the transport doesn't count
+    /// as activity.
+    pub(crate) cover_messages: Arc<AtomicU64>,
+
+    /// if set, OpenResponses are held back for a random time; see `ResponseDelay`.
+    response_delay: Option<ResponseDelay>,
+
+    /// the substreams whose OpenResponse is held back, which are answered as
+    /// their delays run out.
+    delayed_open_responses: FuturesUnordered<BoxFuture<'static, SubstreamId>>,
+
+    /// if set, substreams we open are only handed out once the remote
+    /// acknowledged them; see `SubstreamOpenTimeout`.
+    open_timeout: Option<SubstreamOpenTimeout>,
+
+    /// the substream poll_outbound opened, while it waits for its OpenResponse.
+    outbound_open: Option<OutboundOpen>,
+    /// woken once an OpenResponse arrives, so poll_outbound can hand out
+    /// its substream.
+    outbound_waker: Option<Waker>,
+
+    /// the substreams requested through a `ConnectionControl`, while they
+    /// wait for their OpenResponse.
+    control_opens: Vec<(OutboundOpen, OpenRequest)>,
+}
+
+impl Debug for Connection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connection")
+            .field("peer_id", &redact(self.peer_id))
+            .field(
+                "remote_recipient",
+                &self.remote_recipient.map(redact_always),
+            )
+            .field("id", &self.id)
+            .field("sender_tag", &self.sender_tag.map(redact_always))
+            .field("substreams", &self.substream_inbound_txs.len())
+            .field("pending_substreams", &self.pending_substreams.len())
+            .field("next_nonce", &self.message_nonce.load(Ordering::Relaxed))
+            .field("encrypted", &self.session.is_some())
+            .finish_non_exhaustive()
+    }
 }
 
 impl Connection {
@@ -75,38 +388,346 @@ impl Connection {
         remote_recipient: Option<Recipient>,
         id: ConnectionId,
         inbound_rx: UnboundedReceiver<SubstreamMessage>,
-        mixnet_outbound_tx: UnboundedSender<OutboundMessage>,
+        mixnet_outbound_tx: OutboundSender,
         sender_tag: Option<AnonymousSenderTag>,
     ) -> Self {
         let (inbound_open_tx, inbound_open_rx) = unbounded_channel();
         let (close_tx, close_rx) = unbounded_channel();
+        let (open_tx, open_rx) = unbounded_channel();
 
         Connection {
             peer_id,
+            endpoint: if remote_recipient.is_some() {
+                Endpoint::Dialer
+            } else {
+                Endpoint::Listener
+            },
             remote_recipient,
             id,
             inbound_rx,
+            out_of_band_rx: None,
+            closed_rx: None,
+            substream_directions: false,
+            half_close: false,
             pending_substreams: HashSet::new(),
             substream_inbound_txs: HashMap::new(),
             substream_close_txs: HashMap::new(),
+            substream_buffered: HashMap::new(),
+            substream_closed_locally: HashMap::new(),
+            half_closed_substreams: HashMap::new(),
+            max_substream_buffer: None,
+            stalled: None,
+            max_frame_size: DEFAULT_MAX_FRAME_BYTES,
+            max_unsent_bytes: Some(DEFAULT_MAX_UNSENT_BYTES),
+            frame_sizer: None,
             mixnet_outbound_tx,
             sender_tag,
             inbound_open_tx,
             inbound_open_rx,
             close_tx,
             close_rx,
+            open_tx,
+            open_rx,
             message_nonce: Arc::new(AtomicU64::new(1)),
-            waker: None,
+            session: None,
+            compact_ids: false,
+            codec: None,
+            stats_registry: None,
+            metrics: None,
+            dropped_tx: None,
+            budget: Arc::default(),
+            congestion: None,
+            bandwidth: None,
+            bulk_protocols: Arc::new([]),
+            flush_interval: None,
+            cover: None,
+            cover_messages: Arc::default(),
+            response_delay: None,
+            delayed_open_responses: FuturesUnordered::new(),
+            open_timeout: None,
+            outbound_open: None,
+            outbound_waker: None,
+            control_opens: vec![],
+        }
+    }
+
+    pub(crate) fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub(crate) fn with_congestion_window(mut self, window: Option<Arc<CongestionWindow>>) -> Self {
+        self.congestion = window;
+        self
+    }
+
+    pub(crate) fn with_dropped_tx(mut self, tx: UnboundedSender<ConnectionId>) -> Self {
+        self.dropped_tx = Some(tx);
+        self
+    }
+
+    pub(crate) fn with_bandwidth(mut self, bandwidth: Arc<PeerBandwidth>) -> Self {
+        self.bandwidth = Some(bandwidth);
+        self
+    }
+
+    pub(crate) fn with_bulk_protocols(mut self, protocols: Arc<[String]>) -> Self {
+        self.bulk_protocols = protocols;
+        self
+    }
+
+    pub(crate) fn with_flush_interval(mut self, interval: Option<Duration>) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    pub(crate) fn with_cover_traffic(mut self, cover_traffic: Option<CoverTraffic>) -> Self {
+        self.cover = cover_traffic.map(|cover| (cover, Box::pin(sleep(cover.next_interval()))));
+        self
+    }
+
+    pub(crate) fn with_response_delay(mut self, delay: Option<ResponseDelay>) -> Self {
+        self.response_delay = delay;
+        self
+    }
+
+    pub(crate) fn with_substream_directions(mut self, enabled: bool) -> Self {
+        self.substream_directions = enabled;
+        self
+    }
+
+    pub(crate) fn with_half_close(mut self, enabled: bool) -> Self {
+        self.half_close = enabled;
+        self
+    }
+
+    pub(crate) fn with_open_timeout(mut self, timeout: Option<SubstreamOpenTimeout>) -> Self {
+        self.open_timeout = timeout;
+        self
+    }
+
+    pub(crate) fn with_stats_registry(mut self, registry: ConnectionStatsRegistry) -> Self {
+        self.stats_registry = Some(registry);
+        self
+    }
+
+    pub(crate) fn with_metrics(mut self, metrics: Arc<TransportMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub(crate) fn with_out_of_band_rx(mut self, rx: Receiver<Bytes>) -> Self {
+        self.out_of_band_rx = Some(rx);
+        self
+    }
+
+    pub(crate) fn with_closed_rx(mut self, rx: oneshot::Receiver<Error>) -> Self {
+        self.closed_rx = Some(rx);
+        self
+    }
+
+    pub(crate) fn with_session(mut self, session: Option<Arc<Session>>) -> Self {
+        self.session = session;
+        self
+    }
+
+    pub(crate) fn with_compact_ids(mut self, enabled: bool) -> Self {
+        self.compact_ids = enabled;
+        self
+    }
+
+    pub(crate) fn with_compression(mut self, codec: Option<DataCodec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub(crate) fn with_max_substream_buffer(mut self, max_bytes: Option<usize>) -> Self {
+        self.max_substream_buffer = max_bytes;
+        self
+    }
+
+    pub(crate) fn with_write_limits(
+        mut self,
+        max_frame_size: usize,
+        max_unsent_bytes: Option<usize>,
+    ) -> Self {
+        self.max_frame_size = max_frame_size;
+        self.max_unsent_bytes = max_unsent_bytes;
+        self
+    }
+
+    pub(crate) fn with_frame_sizer(mut self, frame_sizer: Option<Arc<FrameSizer>>) -> Self {
+        self.frame_sizer = frame_sizer;
+        self
+    }
+
+    /// returns the tag of the anonymous sender which dialed the connection.
+    /// Only known for inbound connections, since the dialer is replied to
+    /// via the tag rather than its address.
+    pub fn sender_tag(&self) -> Option<SenderTag> {
+        self.sender_tag.map(SenderTag)
+    }
+
+    /// returns a handle which opens substreams on the connection; see
+    /// `ConnectionControl`.
+    pub fn control(&self) -> ConnectionControl {
+        ConnectionControl {
+            peer_id: self.peer_id,
+            id: self.id.clone(),
+            open_tx: self.open_tx.clone(),
+        }
+    }
+
+    /// returns the IDs of the substreams which haven't been dropped yet, and
+    /// how far each of them got.
+    pub fn substreams(&self) -> Vec<(SubstreamId, SubstreamState)> {
+        let open = self
+            .substream_inbound_txs
+            .iter()
+            // the substream was dropped
+            .filter(|(_, inbound_tx)| !inbound_tx.is_closed())
+            .map(|(id, _)| {
+                let state = if self.substream_closed_locally[id].load(Ordering::SeqCst) {
+                    SubstreamState::Closing
+                } else if self.pending_substreams.contains(id) {
+                    SubstreamState::Opening
+                } else {
+                    SubstreamState::Open
+                };
+                (id.clone(), state)
+            });
+        let half_closed = self
+            .half_closed_substreams
+            .iter()
+            // dropping the substream releases its unread data as well
+            .filter(|(_, buffered)| buffered.load(Ordering::SeqCst) > 0)
+            .map(|(id, _)| (id.clone(), SubstreamState::HalfClosed));
+        open.chain(half_closed).collect()
+    }
+
+    /// sends `data` to the remote outside of any substream, eg. for custom
+    /// reply flows. On inbound connections it's sent via the sender tag,
+    /// using up one of the dialer's reply SURBs per packet. Out-of-band data
+    /// isn't ordered with the connection's other messages, and, like
+    /// everything sent over the mixnet, may be lost; so is data the remote's
+    /// queue has no room for. On encrypted connections it's sealed with the
+    /// session keys. The remote receives it with `poll_out_of_band`. Fails
+    /// with `Error::OutOfBandNotNegotiated` unless both sides enabled
+    /// `NymTransportConfig::out_of_band_queue`.
+    pub fn send_out_of_band(&self, data: impl Into<Bytes>) -> Result<(), Error> {
+        if self.out_of_band_rx.is_none() {
+            return Err(Error::OutOfBandNotNegotiated);
+        }
+        self.mixnet_outbound_tx.send(OutboundMessage {
+            message: Message::OutOfBandMessage(OutOfBandMessage {
+                id: self.id.clone(),
+                data: data.into(),
+            }),
+            recipient: self.remote_recipient,
+            sender_tag: self.sender_tag,
+            queued_at: Instant::now(),
+            session: self.session.clone(),
+            write_credit: None,
+            compact_ids: false,
+            alias: None,
+        })
+    }
+
+    /// polls for data the remote sent with `send_out_of_band`. Returns
+    /// `Poll::Ready(None)` once the connection was closed by the transport.
+    pub fn poll_out_of_band(&mut self, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        match &mut self.out_of_band_rx {
+            Some(rx) => rx.poll_recv(cx),
+            None => Poll::Ready(None),
         }
     }
 
     fn new_outbound_substream(&mut self) -> Result<Substream, Error> {
         debug!("new_outbound_substream called");
-        let substream_id = SubstreamId::generate();
+        let substream_id = SubstreamId::generate_for(self.endpoint, self.compact_ids);
         debug!("Generated substream_id: {:?}", substream_id);
+        self.send_open_request(substream_id.clone())?;
+
+        debug!("Creating substream");
+        // track pending outbound substreams
+        // TODO we should probably lock this? storing map values should be atomic
+        let res = self.new_substream(substream_id.clone());
+        if res.is_ok() {
+            debug!("Adding to pending_substreams");
+            self.pending_substreams.insert(substream_id);
+        } else {
+            debug!("Failed to create substream: {:?}", res);
+        }
+        res
+    }
+
+    /// opens a substream which is only handed out once the remote
+    /// acknowledged it.
+    fn new_outbound_open(&mut self, timeout: SubstreamOpenTimeout) -> Result<OutboundOpen, Error> {
+        Ok(OutboundOpen {
+            substream: self.new_outbound_substream()?,
+            retries: 0,
+            deadline: Box::pin(sleep(timeout.timeout)),
+        })
+    }
+
+    /// polls a substream we opened until the remote acknowledged it, sending
+    /// the OpenRequest again whenever the timeout runs out. Once the retries
+    /// are used up, the substream is reset and the open fails.
+    fn poll_open(
+        &mut self,
+        open: &mut OutboundOpen,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Error>> {
+        let Some(timeout) = self.open_timeout else {
+            return Poll::Ready(Ok(()));
+        };
+        let substream_id = open.substream.substream_id.clone();
+        // the substream may have been closed by the remote in the meantime,
+        // which the substream itself reports
+        if !self.pending_substreams.contains(&substream_id) {
+            return Poll::Ready(Ok(()));
+        }
+        while open.deadline.as_mut().poll(cx).is_ready() {
+            if open.retries >= timeout.retries {
+                debug!(
+                    "substream {:?} wasn't acknowledged, giving up",
+                    substream_id
+                );
+                self.reset_substream(substream_id.clone())?;
+                return Poll::Ready(Err(Error::SubstreamOpenTimeout(substream_id)));
+            }
+            debug!(
+                "substream {:?} wasn't acknowledged yet, asking again",
+                substream_id
+            );
+            open.retries += 1;
+            self.send_open_request(substream_id.clone())?;
+            open.deadline
+                .as_mut()
+                .reset(tokio::time::Instant::now() + timeout.timeout);
+        }
+        Poll::Pending
+    }
+
+    /// hands out the substreams requested through a `ConnectionControl`
+    /// once the remote acknowledged them.
+    fn poll_control_opens(&mut self, cx: &mut Context<'_>) {
+        for (mut open, reply_tx) in std::mem::take(&mut self.control_opens) {
+            match self.poll_open(&mut open, cx) {
+                // the requester may have given up waiting
+                Poll::Ready(res) => {
+                    let _ = reply_tx.send(res.map(|()| open.substream));
+                }
+                Poll::Pending => self.control_opens.push((open, reply_tx)),
+            }
+        }
+    }
+
+    fn send_open_request(&self, substream_id: SubstreamId) -> Result<(), Error> {
         let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
         debug!("Using nonce {}", nonce);
-        debug!("Connection sender_tag: {:?}", self.sender_tag);
+        debug!("Connection sender_tag: {:?}", self.sender_tag.map(redact));
         debug!(
             "About to send with sender_tag: {:?}",
             self.sender_tag.is_some()
@@ -122,27 +743,20 @@ impl Connection {
                     message_type: SubstreamMessageType::OpenRequest,
                 },
             }),
-            sender_tag: self.sender_tag.clone(), // None for dialer, Some(sender_tag) for receiver
+            sender_tag: self.sender_tag, // None for dialer, Some(sender_tag) for receiver
+            queued_at: Instant::now(),
+            session: self.session.clone(),
+            write_credit: None,
+            compact_ids: self.compact_ids,
+            alias: None,
         };
 
         debug!("Sending OpenRequest for substream: {:?}", substream_id);
         // Send the outbound message
         self.mixnet_outbound_tx.send(outbound_msg).map_err(|e| {
             debug!("Failed to send outbound message: {}", e);
-            Error::OutboundSendFailure(e.to_string())
-        })?;
-
-        debug!("Creating substream");
-        // track pending outbound substreams
-        // TODO we should probably lock this? storing map values should be atomic
-        let res = self.new_substream(substream_id.clone());
-        if res.is_ok() {
-            debug!("Adding to pending_substreams");
-            self.pending_substreams.insert(substream_id);
-        } else {
-            debug!("Failed to create substream: {:?}", res);
-        }
-        res
+            e
+        })
     }
 
     // creates a new substream instance with the given ID.
@@ -152,35 +766,215 @@ impl Connection {
             return Err(Error::SubstreamIdExists(id));
         }
 
-        let (inbound_tx, inbound_rx) = unbounded_channel::<Vec<u8>>();
-        let (close_tx, close_rx) = oneshot::channel::<()>();
+        let (inbound_tx, inbound_rx) = unbounded_channel::<Bytes>();
+        let (close_tx, close_rx) = oneshot::channel::<Closed>();
         self.substream_inbound_txs.insert(id.clone(), inbound_tx);
         self.substream_close_txs.insert(id.clone(), close_tx);
 
-        if let Some(waker) = self.waker.take() {
-            waker.wake();
-        }
-
-        Ok(Substream::new_with_sender_tag(
+        let substream = Substream::new_with_sender_tag(
             self.remote_recipient,
             self.id.clone(),
-            id,
+            id.clone(),
             inbound_rx,
             self.mixnet_outbound_tx.clone(),
             close_rx,
             self.message_nonce.clone(),
-            self.sender_tag.clone(), // Pass the connection's SURB directly
-        ))
+            self.sender_tag, // Pass the connection's SURB directly
+        )
+        .with_session(self.session.clone())
+        .with_compact_ids(self.compact_ids)
+        .with_half_close(self.half_close)
+        .with_compression(self.codec)
+        .with_memory_budget(self.budget.clone())
+        .with_congestion_window(self.congestion.clone())
+        .with_bandwidth(self.bandwidth.clone())
+        .with_bulk_protocols(self.bulk_protocols.clone())
+        .with_flush_interval(self.flush_interval)
+        .with_write_limits(self.max_frame_size, self.max_unsent_bytes)
+        .with_frame_sizer(self.frame_sizer.clone());
+        self.substream_buffered
+            .insert(id.clone(), substream.buffered.clone());
+        self.substream_closed_locally
+            .insert(id, substream.closed_locally.clone());
+        Ok(substream)
     }
 
-    fn handle_close(&mut self, substream_id: SubstreamId) -> Result<(), Error> {
+    /// buffer_data hands data to its substream's reader. If the substream's
+    /// buffer or the memory budget has no room for it, the data is held back
+    /// instead, and false is returned; the task is woken once a reader makes
+    /// room, so that a slow reader holds back the remote rather than losing data.
+    fn buffer_data(
+        &mut self,
+        cx: &mut Context<'_>,
+        substream_id: SubstreamId,
+        data: Bytes,
+    ) -> bool {
+        let (Some(inbound_tx), Some(buffered)) = (
+            self.substream_inbound_txs.get(&substream_id),
+            self.substream_buffered.get(&substream_id),
+        ) else {
+            debug!("dropping data for closed substream {:?}", substream_id);
+            return true;
+        };
+
+        let len = data.len();
+        let fits = |budget: &MemoryBudget| {
+            let unread = buffered.load(Ordering::SeqCst);
+            // a frame always fits into an empty buffer, so that frames
+            // larger than the limit can't stall the connection for good
+            let within_limit = self
+                .max_substream_buffer
+                .is_none_or(|max| unread == 0 || unread + len <= max);
+            within_limit && budget.try_reserve(BufferKind::Unread, len)
+        };
+        if !fits(&self.budget) {
+            // room may have been made before the waker was registered
+            self.budget.register_waiter(cx);
+            if !fits(&self.budget) {
+                debug!(
+                    "substream {:?} has no room for more data, holding it back",
+                    substream_id
+                );
+                self.stalled = Some((substream_id, data));
+                return false;
+            }
+        }
+
+        // NOTE: this ignores channel closed errors, which is fine because the substream
+        // might have been closed/dropped
+        buffered.fetch_add(len, Ordering::SeqCst);
+        if inbound_tx.send(data).is_err() {
+            self.budget.release_from(buffered, len);
+        }
+        true
+    }
+
+    /// accepts a substream the remote opened.
+    fn send_open_response(&mut self, substream_id: SubstreamId) -> Result<(), Error> {
+        let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
+
+        debug!("About to send OpenResponse with nonce: {}", nonce);
+        debug!("Using sender_tag: {:?}", self.sender_tag.map(redact));
+
+        // send the response to the remote peer
+        let response_msg = OutboundMessage {
+            recipient: self.remote_recipient,
+            message: Message::TransportMessage(TransportMessage {
+                nonce,
+                id: self.id.clone(),
+                message: SubstreamMessage {
+                    substream_id,
+                    message_type: SubstreamMessageType::OpenResponse,
+                },
+            }),
+            sender_tag: self.sender_tag,
+            queued_at: Instant::now(),
+            session: self.session.clone(),
+            write_credit: None,
+            compact_ids: self.compact_ids,
+            alias: None,
+        };
+
+        debug!("Created OutboundMessage: {:?}", response_msg);
+
+        self.mixnet_outbound_tx.send(response_msg).map_err(|e| {
+            debug!("FAILED to send OpenResponse: {}", e);
+            e
+        })?;
+        debug!("Queued OpenResponse for mixnet");
+        Ok(())
+    }
+
+    /// sends a cover message whenever the timer for the next one fires.
+    fn poll_cover(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        let Some((cover, next)) = &mut self.cover else {
+            return Ok(());
+        };
+        while next.as_mut().poll(cx).is_ready() {
+            let interval = cover.next_interval();
+            next.as_mut().reset(tokio::time::Instant::now() + interval);
+            let payload = Bytes::from(vec![0; cover.size]);
+            // counted first, so the transport never sees the nonce advance
+            // without it
+            self.cover_messages.fetch_add(1, Ordering::SeqCst);
+            let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
+            self.mixnet_outbound_tx.send(OutboundMessage {
+                recipient: self.remote_recipient,
+                message: Message::TransportMessage(TransportMessage {
+                    nonce,
+                    id: self.id.clone(),
+                    message: SubstreamMessage {
+                        substream_id: if self.compact_ids {
+                            SubstreamId::generate_compact()
+                        } else {
+                            SubstreamId::generate()
+                        },
+                        message_type: SubstreamMessageType::Cover(payload),
+                    },
+                }),
+                sender_tag: self.sender_tag,
+                queued_at: Instant::now(),
+                session: self.session.clone(),
+                write_credit: None,
+                compact_ids: self.compact_ids,
+                alias: None,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// reset_substream closes a substream on both ends, eg. because its reader
+    /// fell too far behind.
+    fn reset_substream(&mut self, substream_id: SubstreamId) -> Result<(), Error> {
+        self.send_close(substream_id.clone())?;
+        self.handle_close(substream_id, Closed::Reset)
+    }
+
+    /// send_close tells the remote its substream is closed, without closing
+    /// ours.
+    fn send_close(&self, substream_id: SubstreamId) -> Result<(), Error> {
+        let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
+        self.mixnet_outbound_tx.send(OutboundMessage {
+            recipient: self.remote_recipient,
+            message: Message::TransportMessage(TransportMessage {
+                nonce,
+                id: self.id.clone(),
+                message: SubstreamMessage::new_close(substream_id),
+            }),
+            sender_tag: self.sender_tag,
+            queued_at: Instant::now(),
+            session: self.session.clone(),
+            write_credit: None,
+            compact_ids: self.compact_ids,
+            alias: None,
+        })?;
+        Ok(())
+    }
+
+    fn handle_close(&mut self, substream_id: SubstreamId, how: Closed) -> Result<(), Error> {
         if self.substream_inbound_txs.remove(&substream_id).is_none() {
             return Err(Error::SubstreamIdDoesNotExist(substream_id));
         }
+        self.pending_substreams.remove(&substream_id);
+        let closed_locally = self
+            .substream_closed_locally
+            .remove(&substream_id)
+            .is_some_and(|closed| closed.load(Ordering::SeqCst));
+        let buffered = self.substream_buffered.remove(&substream_id);
+        self.half_closed_substreams
+            .retain(|_, buffered| buffered.load(Ordering::SeqCst) > 0);
+        if let (Closed::Remote, Some(buffered)) = (how, buffered) {
+            // we can still read what the remote sent before closing
+            if !closed_locally && buffered.load(Ordering::SeqCst) > 0 {
+                self.half_closed_substreams
+                    .insert(substream_id.clone(), buffered);
+            }
+        }
 
-        // notify substream that it's closed
-        let close_tx = self.substream_close_txs.remove(&substream_id);
-        close_tx.unwrap().send(()).unwrap();
+        // notify substream that it's closed; it may have been dropped already.
+        if let Some(close_tx) = self.substream_close_txs.remove(&substream_id) {
+            let _ = close_tx.send(how);
+        }
 
         // notify poll_close that the substream is closed
         self.close_tx
@@ -206,12 +1000,27 @@ impl StreamMuxer for Connection {
 
     fn poll_outbound(
         mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<Result<Self::Substream, Self::Error>> {
         debug!("poll_outbound called");
-        let result = self.new_outbound_substream();
-        debug!("poll_outbound result: {:?}", result.is_ok());
-        Poll::Ready(result)
+        let Some(timeout) = self.open_timeout else {
+            let result = self.new_outbound_substream();
+            debug!("poll_outbound result: {:?}", result.is_ok());
+            return Poll::Ready(result);
+        };
+
+        let mut open = match self.outbound_open.take() {
+            Some(open) => open,
+            None => self.new_outbound_open(timeout)?,
+        };
+        match self.poll_open(&mut open, cx) {
+            Poll::Ready(res) => Poll::Ready(res.map(|()| open.substream)),
+            Poll::Pending => {
+                self.outbound_waker = Some(cx.waker().clone());
+                self.outbound_open = Some(open);
+                Poll::Pending
+            }
+        }
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -226,7 +1035,71 @@ impl StreamMuxer for Connection {
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
-        while let Poll::Ready(Some(msg)) = self.inbound_rx.poll_recv(cx) {
+        let started = Instant::now();
+        let mut handled = 0;
+        let res = self.poll_messages(cx, &mut handled);
+        if let Some(metrics) = &self.metrics {
+            TransportMetrics::record_poll(
+                &metrics.connection_poll_micros,
+                &metrics.connection_poll_messages,
+                started,
+                handled,
+            );
+        }
+        res
+    }
+}
+
+impl Connection {
+    /// does the work of `StreamMuxer::poll`, counting the substream messages
+    /// it handles in `handled`.
+    fn poll_messages(
+        &mut self,
+        cx: &mut Context<'_>,
+        handled: &mut u64,
+    ) -> Poll<Result<StreamMuxerEvent, Error>> {
+        while let Poll::Ready(Some(reply_tx)) = self.open_rx.poll_recv(cx) {
+            match self.open_timeout {
+                Some(timeout) => match self.new_outbound_open(timeout) {
+                    Ok(open) => self.control_opens.push((open, reply_tx)),
+                    Err(e) => {
+                        let _ = reply_tx.send(Err(e));
+                    }
+                },
+                // the requester may have given up waiting
+                None => {
+                    let _ = reply_tx.send(self.new_outbound_substream());
+                }
+            }
+        }
+        self.poll_control_opens(cx);
+        self.poll_cover(cx)?;
+        while let Poll::Ready(Some(substream_id)) = self.delayed_open_responses.poll_next_unpin(cx)
+        {
+            // the substream may have been closed in the meantime
+            if self.substream_inbound_txs.contains_key(&substream_id) {
+                self.send_open_response(substream_id)?;
+            }
+        }
+
+        if let Some((substream_id, data)) = self.stalled.take() {
+            if !self.buffer_data(cx, substream_id, data) {
+                return Poll::Pending;
+            }
+        }
+
+        for _ in 0..POLL_BUDGET {
+            let msg = match self.inbound_rx.poll_recv(cx) {
+                Poll::Ready(Some(msg)) => msg,
+                // the transport dropped the connection, eg. because the remote
+                // broke the protocol, or the transport itself was dropped.
+                Poll::Ready(None) => {
+                    let reason = self.closed_rx.as_mut().and_then(|rx| rx.try_recv().ok());
+                    return Poll::Ready(Err(reason.unwrap_or(Error::RecvFailure)));
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            *handled += 1;
             debug!(
                 "Connection poll received message type: {:?} for substream: {:?}",
                 msg.message_type, msg.substream_id
@@ -244,34 +1117,34 @@ impl StreamMuxer for Connection {
                         debug!("Dialer received OpenRequest - something is not right here");
                     }
 
-                    // create a new substream with the given ID
-                    let substream = self.new_substream(msg.substream_id.clone())?;
-                    let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
-
-                    debug!("About to send OpenResponse with nonce: {}", nonce);
-                    debug!("Using sender_tag: {:?}", self.sender_tag);
-
-                    // send the response to the remote peer
-                    let response_msg = OutboundMessage {
-                        recipient: self.remote_recipient,
-                        message: Message::TransportMessage(TransportMessage {
-                            nonce,
-                            id: self.id.clone(),
-                            message: SubstreamMessage {
-                                substream_id: msg.substream_id.clone(),
-                                message_type: SubstreamMessageType::OpenResponse,
-                            },
-                        }),
-                        sender_tag: self.sender_tag.clone(),
-                    };
+                    if self.substream_directions && msg.substream_id.opener() == self.endpoint {
+                        // the ID is on our side, where it may collide with a
+                        // substream we open
+                        debug!(
+                            "refusing substream {:?} opened on our side",
+                            msg.substream_id
+                        );
+                        self.send_close(msg.substream_id)?;
+                        continue;
+                    }
 
-                    debug!("Created OutboundMessage: {:?}", response_msg);
+                    if self.substream_inbound_txs.contains_key(&msg.substream_id) {
+                        // the opener didn't get our response in time and asked again
+                        self.send_open_response(msg.substream_id)?;
+                        continue;
+                    }
 
-                    self.mixnet_outbound_tx.send(response_msg).map_err(|e| {
-                        debug!("FAILED to send OpenResponse: {}", e);
-                        Error::OutboundSendFailure(e.to_string())
-                    })?;
-                    debug!("Queued OpenResponse for mixnet");
+                    // create a new substream with the given ID
+                    let substream = self.new_substream(msg.substream_id.clone())?;
+                    match self.response_delay {
+                        Some(delay) => {
+                            let substream_id = msg.substream_id.clone();
+                            let delay = delay.next_delay();
+                            self.delayed_open_responses
+                                .push(sleep(delay).map(move |_| substream_id).boxed());
+                        }
+                        None => self.send_open_response(msg.substream_id.clone())?,
+                    }
 
                     // send the substream to our own channel to be returned in poll_inbound
                     self.inbound_open_tx
@@ -291,57 +1164,149 @@ impl StreamMuxer for Connection {
                             &msg.substream_id
                         );
                     }
+                    // hand out the substream if an open waits for it
+                    if let Some(waker) = self.outbound_waker.take() {
+                        waker.wake();
+                    }
+                    if !self.control_opens.is_empty() {
+                        cx.waker().wake_by_ref();
+                    }
                 }
                 SubstreamMessageType::Close => {
                     debug!("Processing Close for substream: {:?}", msg.substream_id);
-                    self.handle_close(msg.substream_id)?;
+                    // the substream may have been closed already, eg. if the remote
+                    // closed it because some of its outbound data expired.
+                    if let Err(e) = self.handle_close(msg.substream_id, Closed::Remote) {
+                        debug!("ignoring Close: {}", e);
+                    }
+                }
+                SubstreamMessageType::Cover(_) => {
+                    debug!("dropping cover message");
                 }
                 SubstreamMessageType::Data(data) => {
                     debug!("Processing Data: {:?}", &data);
-                    let inbound_tx = self
-                        .substream_inbound_txs
-                        .get_mut(&msg.substream_id)
-                        .expect("must have a substream channel for substream");
-
-                    // NOTE: this ignores channel closed errors, which is fine because the substream
-                    // might have been closed/dropped
-                    inbound_tx.send(data).ok();
+                    if !self.substream_inbound_txs.contains_key(&msg.substream_id) {
+                        debug!("dropping data for closed substream {:?}", msg.substream_id);
+                        continue;
+                    }
+                    let data = match self.codec {
+                        Some(codec) => codec.decompress(data),
+                        None => Ok(data),
+                    };
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(e) => {
+                            warn!(
+                                "resetting substream {:?} after invalid data: {}",
+                                msg.substream_id, e
+                            );
+                            self.reset_substream(msg.substream_id)?;
+                            continue;
+                        }
+                    };
+
+                    if !self.buffer_data(cx, msg.substream_id, data) {
+                        return Poll::Pending;
+                    }
                 }
             }
         }
 
-        self.waker = Some(cx.waker().clone());
+        // there may be more inbound messages; yield rather than monopolizing
+        // the executor.
+        cx.waker().wake_by_ref();
         Poll::Pending
     }
 }
 
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(registry) = &self.stats_registry {
+            registry.remove(&self.id);
+        }
+        if let Some(dropped_tx) = &self.dropped_tx {
+            // the transport may have been dropped already
+            let _ = dropped_tx.send(self.id.clone());
+        }
+    }
+}
+
 /// PendingConnection represents a connection that's been initiated, but not completed.
 pub(crate) struct PendingConnection {
     pub(crate) remote_recipient: Recipient,
-    pub(crate) connection_tx: oneshot::Sender<Connection>,
+    pub(crate) connection_tx: oneshot::Sender<Result<Connection, Error>>,
+    /// the connection's identity, which signed the ConnectionRequest.
+    pub(crate) local_key: Keypair,
+    /// when the dial was initiated; used to purge dials that never got a response.
+    pub(crate) created_at: Instant,
+    /// our half of the key exchange, if payload encryption is enabled.
+    pub(crate) handshake_secret: Option<HandshakeSecret>,
+    /// set by the dial future when the first ConnectionRequest is sent;
+    /// used to measure the handshake round-trip time.
+    pub(crate) request_sent_at: Arc<OnceLock<Instant>>,
+    /// the session ticket the connection is resumed with, instead of a handshake.
+    pub(crate) ticket: Option<SessionTicket>,
+    /// the PeerId the dialed address names, if any; a ConnectionClose
+    /// failing the dial must be signed by it.
+    pub(crate) remote_peer_id: Option<PeerId>,
 }
 
 impl PendingConnection {
     pub(crate) fn new(
         remote_recipient: Recipient,
-        connection_tx: oneshot::Sender<Connection>,
+        connection_tx: oneshot::Sender<Result<Connection, Error>>,
+        local_key: Keypair,
+        handshake_secret: Option<HandshakeSecret>,
     ) -> Self {
         PendingConnection {
             remote_recipient,
             connection_tx,
+            local_key,
+            created_at: Instant::now(),
+            handshake_secret,
+            request_sent_at: Arc::new(OnceLock::new()),
+            ticket: None,
+            remote_peer_id: None,
         }
     }
+
+    pub(crate) fn with_ticket(mut self, ticket: SessionTicket) -> Self {
+        self.ticket = Some(ticket);
+        self
+    }
+
+    pub(crate) fn with_remote_peer_id(mut self, peer_id: Option<PeerId>) -> Self {
+        self.remote_peer_id = peer_id;
+        self
+    }
+}
+
+impl Debug for PendingConnection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PendingConnection")
+            .field("remote_recipient", &redact_always(self.remote_recipient))
+            .field("peer_id", &redact(self.local_key.public().to_peer_id()))
+            .field("age", &self.created_at.elapsed())
+            .field("encrypted", &self.handshake_secret.is_some())
+            .field("resumed", &self.ticket.is_some())
+            .finish_non_exhaustive()
+    }
 }
 
 #[cfg(test)]
 mod test {
+    #[cfg(feature = "nym-client")]
     use super::super::message::InboundMessage;
+    #[cfg(feature = "nym-client")]
     use super::super::mixnet::initialize_mixnet;
     use super::*;
     use futures::future::poll_fn;
+    use futures::task::{waker_ref, ArcWake};
     use futures::{AsyncReadExt, AsyncWriteExt, FutureExt};
+    #[cfg(feature = "nym-client")]
     use nym_sdk::mixnet::MixnetClient;
 
+    #[cfg(feature = "nym-client")]
     async fn inbound_receive_and_send(
         connection_id: ConnectionId,
         mixnet_inbound_rx: &mut UnboundedReceiver<InboundMessage>,
@@ -363,16 +1328,47 @@ mod test {
         }
     }
 
+    #[cfg(feature = "nym-client")]
     #[tokio::test]
     async fn test_connection_stream_muxer() {
         let client = MixnetClient::connect_new().await.unwrap();
-        let (sender_address, mut sender_mixnet_inbound_rx, sender_outbound_tx) =
-            initialize_mixnet(client, None).await.unwrap();
+        let (sender_address, mut sender_mixnet_inbound_rx, sender_outbound_tx, _sender_task) =
+            initialize_mixnet(
+                client,
+                None,
+                None,
+                Default::default(),
+                &Default::default(),
+                Default::default(),
+                None,
+                Default::default(),
+                Default::default(),
+                None,
+            )
+            .await
+            .unwrap();
 
         let client2 = MixnetClient::connect_new().await.unwrap();
 
-        let (recipient_address, mut recipient_mixnet_inbound_rx, recipient_outbound_tx) =
-            initialize_mixnet(client2, None).await.unwrap();
+        let (
+            recipient_address,
+            mut recipient_mixnet_inbound_rx,
+            recipient_outbound_tx,
+            _recipient_task,
+        ) = initialize_mixnet(
+            client2,
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
 
         let connection_id = ConnectionId::generate();
 
@@ -475,4 +1471,388 @@ mod test {
         )
         .await;
     }
+
+    #[tokio::test]
+    async fn test_connection_substream_buffer_limit() {
+        let (inbound_tx, inbound_rx) = unbounded_channel();
+        let (outbound_tx, mut outbound_rx) = unbounded_channel();
+        let outbound_tx = OutboundSender::new(outbound_tx, Default::default());
+        let mut conn = Connection::new_with_sender_tag(
+            PeerId::random(),
+            None,
+            ConnectionId::generate(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        )
+        .with_max_substream_buffer(Some(8));
+
+        let substream_id = SubstreamId::generate();
+        inbound_tx
+            .send(SubstreamMessage {
+                substream_id: substream_id.clone(),
+                message_type: SubstreamMessageType::OpenRequest,
+            })
+            .unwrap();
+        inbound_tx
+            .send(SubstreamMessage::new_with_data(
+                substream_id.clone(),
+                vec![1; 5],
+            ))
+            .unwrap();
+        assert!(poll_fn(|cx| Pin::new(&mut conn).as_mut().poll(cx))
+            .now_or_never()
+            .is_none());
+        let mut substream = poll_fn(|cx| Pin::new(&mut conn).as_mut().poll_inbound(cx))
+            .await
+            .unwrap();
+        outbound_rx.try_recv().unwrap(); // OpenResponse
+
+        // reading frees up room for more data
+        let mut buf = [0u8; 3];
+        substream.read_exact(&mut buf).await.unwrap();
+        inbound_tx
+            .send(SubstreamMessage::new_with_data(
+                substream_id.clone(),
+                vec![2; 6],
+            ))
+            .unwrap();
+        assert!(poll_fn(|cx| Pin::new(&mut conn).as_mut().poll(cx))
+            .now_or_never()
+            .is_none());
+        assert!(outbound_rx.try_recv().is_err());
+        assert_eq!(substream.buffered.load(Ordering::SeqCst), 8);
+
+        // data beyond the limit is held back, along with what follows it
+        inbound_tx
+            .send(SubstreamMessage::new_with_data(
+                substream_id.clone(),
+                vec![3],
+            ))
+            .unwrap();
+        inbound_tx
+            .send(SubstreamMessage::new_close(substream_id.clone()))
+            .unwrap();
+        assert!(poll_fn(|cx| Pin::new(&mut conn).as_mut().poll(cx))
+            .now_or_never()
+            .is_none());
+        assert!(outbound_rx.try_recv().is_err());
+        assert_eq!(substream.buffered.load(Ordering::SeqCst), 8);
+
+        // until the reader catches up, which wakes the connection
+        struct WakeFlag(AtomicBool);
+        impl ArcWake for WakeFlag {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.store(true, Ordering::SeqCst);
+            }
+        }
+        let woken = Arc::new(WakeFlag(AtomicBool::new(false)));
+        assert!(Pin::new(&mut conn)
+            .poll(&mut Context::from_waker(&waker_ref(&woken)))
+            .is_pending());
+        let mut buf = [0u8; 8];
+        substream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 1, 2, 2, 2, 2, 2, 2]);
+        assert!(woken.0.load(Ordering::SeqCst));
+        assert!(poll_fn(|cx| Pin::new(&mut conn).as_mut().poll(cx))
+            .now_or_never()
+            .is_none());
+        let mut rest = [0u8; 1];
+        substream.read_exact(&mut rest).await.unwrap();
+        assert_eq!(rest, [3]);
+    }
+
+    #[tokio::test]
+    async fn test_connection_substreams() {
+        let (inbound_tx, inbound_rx) = unbounded_channel();
+        let (outbound_tx, _outbound_rx) = unbounded_channel();
+        let outbound_tx = OutboundSender::new(outbound_tx, Default::default());
+        let mut conn = Connection::new_with_sender_tag(
+            PeerId::random(),
+            None,
+            ConnectionId::generate(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        );
+        let state = |conn: &Connection, id: &SubstreamId| {
+            conn.substreams()
+                .into_iter()
+                .find(|(substream_id, _)| substream_id == id)
+                .map(|(_, state)| state)
+        };
+
+        // an outbound substream is open once the remote responds
+        let mut outbound = poll_fn(|cx| Pin::new(&mut conn).as_mut().poll_outbound(cx))
+            .await
+            .unwrap();
+        let outbound_id = outbound.substream_id.clone();
+        assert_eq!(state(&conn, &outbound_id), Some(SubstreamState::Opening));
+        inbound_tx
+            .send(SubstreamMessage {
+                substream_id: outbound_id.clone(),
+                message_type: SubstreamMessageType::OpenResponse,
+            })
+            .unwrap();
+        let _ = poll_fn(|cx| Pin::new(&mut conn).as_mut().poll(cx)).now_or_never();
+        assert_eq!(state(&conn, &outbound_id), Some(SubstreamState::Open));
+
+        // closing it leaves it closing until it's dropped
+        outbound.close().await.unwrap();
+        assert_eq!(state(&conn, &outbound_id), Some(SubstreamState::Closing));
+        drop(outbound);
+        assert_eq!(state(&conn, &outbound_id), None);
+
+        // an inbound substream the remote closed is half-closed until its
+        // data has been read
+        let inbound_id = SubstreamId::generate();
+        for message_type in [
+            SubstreamMessageType::OpenRequest,
+            SubstreamMessageType::Data(Bytes::from_static(b"hello")),
+            SubstreamMessageType::Close,
+        ] {
+            inbound_tx
+                .send(SubstreamMessage {
+                    substream_id: inbound_id.clone(),
+                    message_type,
+                })
+                .unwrap();
+        }
+        let _ = poll_fn(|cx| Pin::new(&mut conn).as_mut().poll(cx)).now_or_never();
+        let mut inbound = poll_fn(|cx| Pin::new(&mut conn).as_mut().poll_inbound(cx))
+            .await
+            .unwrap();
+        assert_eq!(state(&conn, &inbound_id), Some(SubstreamState::HalfClosed));
+        let mut buf = [0u8; 5];
+        inbound.read_exact(&mut buf).await.unwrap();
+        assert_eq!(state(&conn, &inbound_id), None);
+        assert!(conn.substreams().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connection_substream_open_timeout() {
+        let (inbound_tx, inbound_rx) = unbounded_channel();
+        let (outbound_tx, mut outbound_rx) = unbounded_channel();
+        let outbound_tx = OutboundSender::new(outbound_tx, Default::default());
+        let mut conn = Connection::new_with_sender_tag(
+            PeerId::random(),
+            None,
+            ConnectionId::generate(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        )
+        .with_open_timeout(Some(SubstreamOpenTimeout {
+            timeout: Duration::from_millis(50),
+            retries: 1,
+        }));
+        let mut sent = || match outbound_rx.try_recv().unwrap().message {
+            Message::TransportMessage(msg) => msg.message,
+            _ => panic!("expected a TransportMessage"),
+        };
+
+        // an open the remote never acknowledges is asked again, then given
+        // up on and reset
+        match poll_fn(|cx| Pin::new(&mut conn).as_mut().poll_outbound(cx)).await {
+            Err(Error::SubstreamOpenTimeout(_)) => {}
+            res => panic!(
+                "expected Error::SubstreamOpenTimeout, got {:?}",
+                res.is_ok()
+            ),
+        }
+        let (request, resent, close) = (sent(), sent(), sent());
+        assert!(matches!(
+            request.message_type,
+            SubstreamMessageType::OpenRequest
+        ));
+        assert!(matches!(
+            resent.message_type,
+            SubstreamMessageType::OpenRequest
+        ));
+        assert!(matches!(close.message_type, SubstreamMessageType::Close));
+        assert_eq!(resent.substream_id, request.substream_id);
+        assert_eq!(close.substream_id, request.substream_id);
+        assert!(conn.substreams().is_empty());
+
+        // one it acknowledges is handed out once the response arrives
+        assert!(poll_fn(|cx| Pin::new(&mut conn).as_mut().poll_outbound(cx))
+            .now_or_never()
+            .is_none());
+        let request = sent();
+        inbound_tx
+            .send(SubstreamMessage {
+                substream_id: request.substream_id.clone(),
+                message_type: SubstreamMessageType::OpenResponse,
+            })
+            .unwrap();
+        let _ = poll_fn(|cx| Pin::new(&mut conn).as_mut().poll(cx)).now_or_never();
+        let substream = poll_fn(|cx| Pin::new(&mut conn).as_mut().poll_outbound(cx))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(substream.substream_id, request.substream_id);
+
+        // a request the remote sends again is answered again, rather than
+        // opening the substream twice
+        let inbound_id = SubstreamId::generate();
+        for _ in 0..2 {
+            inbound_tx
+                .send(SubstreamMessage {
+                    substream_id: inbound_id.clone(),
+                    message_type: SubstreamMessageType::OpenRequest,
+                })
+                .unwrap();
+        }
+        let _ = poll_fn(|cx| Pin::new(&mut conn).as_mut().poll(cx)).now_or_never();
+        for _ in 0..2 {
+            assert!(matches!(
+                sent().message_type,
+                SubstreamMessageType::OpenResponse
+            ));
+        }
+        assert!(poll_fn(|cx| Pin::new(&mut conn).as_mut().poll_inbound(cx))
+            .now_or_never()
+            .is_some());
+        assert!(poll_fn(|cx| Pin::new(&mut conn).as_mut().poll_inbound(cx))
+            .now_or_never()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connection_substream_directions() {
+        let (inbound_tx, inbound_rx) = unbounded_channel();
+        let (outbound_tx, mut outbound_rx) = unbounded_channel();
+        let outbound_tx = OutboundSender::new(outbound_tx, Default::default());
+        // without the remote's address, the connection is the listener's
+        let mut conn = Connection::new_with_sender_tag(
+            PeerId::random(),
+            None,
+            ConnectionId::generate(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        )
+        .with_substream_directions(true);
+        let mut sent = || match outbound_rx.try_recv().unwrap().message {
+            Message::TransportMessage(msg) => msg.message,
+            _ => panic!("expected a TransportMessage"),
+        };
+
+        let substream = poll_fn(|cx| Pin::new(&mut conn).as_mut().poll_outbound(cx))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(substream.substream_id.opener(), Endpoint::Listener);
+        assert!(matches!(
+            sent().message_type,
+            SubstreamMessageType::OpenRequest
+        ));
+
+        // the remote can open substreams on its side, but not on ours
+        for (opener, accepted) in [(Endpoint::Dialer, true), (Endpoint::Listener, false)] {
+            let substream_id = SubstreamId::generate_for(opener, false);
+            inbound_tx
+                .send(SubstreamMessage {
+                    substream_id: substream_id.clone(),
+                    message_type: SubstreamMessageType::OpenRequest,
+                })
+                .unwrap();
+            let _ = poll_fn(|cx| Pin::new(&mut conn).as_mut().poll(cx)).now_or_never();
+            let reply = sent();
+            assert_eq!(reply.substream_id, substream_id);
+            if accepted {
+                assert!(matches!(
+                    reply.message_type,
+                    SubstreamMessageType::OpenResponse
+                ));
+            } else {
+                assert!(matches!(reply.message_type, SubstreamMessageType::Close));
+            }
+            assert_eq!(
+                poll_fn(|cx| Pin::new(&mut conn).as_mut().poll_inbound(cx))
+                    .now_or_never()
+                    .is_some(),
+                accepted
+            );
+        }
+        // refusing the request left our own substreams alone
+        assert!(conn
+            .substreams()
+            .iter()
+            .any(|(id, _)| *id == substream.substream_id));
+    }
+
+    #[tokio::test]
+    async fn test_connection_memory_budget() {
+        let (inbound_tx, inbound_rx) = unbounded_channel();
+        let (outbound_tx, mut outbound_rx) = unbounded_channel();
+        let outbound_tx = OutboundSender::new(outbound_tx, Default::default());
+        let budget = Arc::new(MemoryBudget::new(Some(16), Default::default()));
+        let mut conn = Connection::new_with_sender_tag(
+            PeerId::random(),
+            None,
+            ConnectionId::generate(),
+            inbound_rx,
+            outbound_tx,
+            None,
+        )
+        .with_memory_budget(budget.clone());
+
+        let substream_id = SubstreamId::generate();
+        inbound_tx
+            .send(SubstreamMessage {
+                substream_id: substream_id.clone(),
+                message_type: SubstreamMessageType::OpenRequest,
+            })
+            .unwrap();
+        inbound_tx
+            .send(SubstreamMessage::new_with_data(
+                substream_id.clone(),
+                vec![1; 10],
+            ))
+            .unwrap();
+        assert!(poll_fn(|cx| Pin::new(&mut conn).as_mut().poll(cx))
+            .now_or_never()
+            .is_none());
+        let mut substream = poll_fn(|cx| Pin::new(&mut conn).as_mut().poll_inbound(cx))
+            .await
+            .unwrap();
+        outbound_rx.try_recv().unwrap(); // OpenResponse
+        assert_eq!(budget.used(), 10);
+
+        // unsent data counts as well, and writes wait once the budget is full
+        assert_eq!(
+            substream.write(&[2; 10]).now_or_never().unwrap().unwrap(),
+            10
+        );
+        assert_eq!(budget.used(), 20);
+        assert!(substream.write(&[2]).now_or_never().is_none());
+        drop(outbound_rx.try_recv().unwrap());
+        assert_eq!(budget.used(), 10);
+        assert_eq!(substream.write(&[2]).now_or_never().unwrap().unwrap(), 1);
+        drop(outbound_rx.try_recv().unwrap());
+
+        // unread data beyond the budget is held back until there's room
+        inbound_tx
+            .send(SubstreamMessage::new_with_data(
+                substream_id.clone(),
+                vec![3; 7],
+            ))
+            .unwrap();
+        assert!(poll_fn(|cx| Pin::new(&mut conn).as_mut().poll(cx))
+            .now_or_never()
+            .is_none());
+        assert!(outbound_rx.try_recv().is_err());
+        assert_eq!(budget.used(), 10);
+        let mut buf = [0u8; 5];
+        substream.read_exact(&mut buf).await.unwrap();
+        assert!(poll_fn(|cx| Pin::new(&mut conn).as_mut().poll(cx))
+            .now_or_never()
+            .is_none());
+        assert_eq!(budget.used(), 12);
+
+        // and dropping the substream frees what it didn't read
+        drop(substream);
+        assert_eq!(budget.used(), 0);
+    }
 }
@@ -0,0 +1,105 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::config::DialLimits;
+use super::error::Error;
+
+/// DialLimiter caps the number of dials whose handshake is in progress at
+/// the same time. Dials beyond the cap wait for a free slot in FIFO order;
+/// the number of waiting dials is bounded as well.
+#[derive(Clone, Debug)]
+pub(crate) struct DialLimiter {
+    semaphore: Arc<Semaphore>,
+    /// number of dials which are either in progress or waiting for a slot.
+    outstanding: Arc<AtomicUsize>,
+    max_outstanding: usize,
+    max_queue_wait: Duration,
+}
+
+impl DialLimiter {
+    pub(crate) fn new(limits: &DialLimits) -> Self {
+        DialLimiter {
+            semaphore: Arc::new(Semaphore::new(limits.max_concurrent)),
+            outstanding: Arc::new(AtomicUsize::new(0)),
+            max_outstanding: limits.max_concurrent.saturating_add(limits.max_queued),
+            max_queue_wait: limits.max_queue_wait,
+        }
+    }
+
+    /// reserves a place for a new dial, failing if the waiting queue is full.
+    /// The place is released when the returned ticket is dropped.
+    pub(crate) fn reserve(&self) -> Result<DialTicket, Error> {
+        self.outstanding
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.max_outstanding).then_some(n + 1)
+            })
+            .map_err(|_| Error::DialQueueFull)?;
+
+        Ok(DialTicket {
+            limiter: self.clone(),
+        })
+    }
+}
+
+/// DialTicket is a reserved place in the dial queue.
+pub(crate) struct DialTicket {
+    limiter: DialLimiter,
+}
+
+impl DialTicket {
+    /// waits until the dial is allowed to start, failing if it waited longer
+    /// than `max_queue_wait`; the returned permit must be held for as long as
+    /// the handshake is in progress.
+    pub(crate) async fn acquire(&self) -> Result<OwnedSemaphorePermit, Error> {
+        let permit = self.limiter.semaphore.clone().acquire_owned();
+        match tokio::time::timeout(self.limiter.max_queue_wait, permit).await {
+            Ok(permit) => Ok(permit.expect("dial semaphore is never closed")),
+            Err(_) => Err(Error::DialQueueTimeout),
+        }
+    }
+}
+
+impl Drop for DialTicket {
+    fn drop(&mut self) {
+        self.limiter.outstanding.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn test_dial_limiter() {
+        let limiter = DialLimiter::new(&DialLimits {
+            max_concurrent: 1,
+            max_queued: 1,
+            max_queue_wait: Duration::from_millis(50),
+        });
+
+        let first = limiter.reserve().unwrap();
+        let second = limiter.reserve().unwrap();
+        assert!(matches!(limiter.reserve(), Err(Error::DialQueueFull)));
+
+        // only one dial may run at a time
+        let permit = first.acquire().await.unwrap();
+        assert!(second.acquire().now_or_never().is_none());
+
+        // and the others wait only so long for it
+        assert!(matches!(
+            second.acquire().await,
+            Err(Error::DialQueueTimeout)
+        ));
+
+        // finishing the first dial lets the second one start and frees a place
+        drop(permit);
+        drop(first);
+        let _permit = second.acquire().await.unwrap();
+        let _third = limiter.reserve().unwrap();
+    }
+}
@@ -0,0 +1,1355 @@
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use futures::stream::FuturesUnordered;
+use libp2p::core::{
+    multiaddr::Multiaddr, transport::PortUse, upgrade::ReadyUpgrade, Endpoint, PeerRecord,
+    SignedEnvelope,
+};
+use libp2p::swarm::{
+    behaviour::{ConnectionClosed, ConnectionEstablished, DialFailure, NewListenAddr},
+    dial_opts::{DialOpts, PeerCondition},
+    handler::{ConnectionEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound},
+    ConnectionDenied, ConnectionHandler, ConnectionHandlerEvent, ConnectionId, FromSwarm,
+    NetworkBehaviour, NotifyHandler, Stream, StreamProtocol, StreamUpgradeError, SubstreamProtocol,
+    THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+};
+use libp2p_identity::{Keypair, PeerId};
+use log::debug;
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::{interval, Instant, Interval, MissedTickBehavior};
+
+use super::announce::is_nym_address;
+use super::error::Error;
+
+/// the protocol over which nodes register with and query a rendezvous peer.
+pub const PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/nym/rendezvous/1.0.0");
+
+/// the longest namespace, in bytes.
+pub const MAX_NAMESPACE_LEN: usize = 255;
+/// the longest request or response accepted.
+const MAX_FRAME_LEN: usize = 256 * 1024;
+/// the number of inbound requests a connection serves at the same time;
+/// further streams are dropped.
+const MAX_INBOUND_STREAMS: usize = 8;
+/// how long a request may take to be sent and answered.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+/// how often the client checks whether registrations are due to be refreshed.
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+const REGISTER_REQUEST: u8 = 0;
+const DISCOVER_REQUEST: u8 = 1;
+const REGISTERED_RESPONSE: u8 = 0;
+const DISCOVERED_RESPONSE: u8 = 1;
+const ERROR_RESPONSE: u8 = 2;
+
+/// ErrorCode is the reason a rendezvous peer refused a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// the namespace is empty or too long.
+    InvalidNamespace,
+    /// the record isn't validly signed, has no `/nym` address, or is older
+    /// than the one registered.
+    InvalidRecord,
+    /// the TTL is outside the range the rendezvous peer accepts.
+    InvalidTtl,
+    /// the namespace is full.
+    Unavailable,
+    /// a code this version doesn't know about.
+    Other(u8),
+}
+
+impl From<u8> for ErrorCode {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => ErrorCode::InvalidNamespace,
+            2 => ErrorCode::InvalidRecord,
+            3 => ErrorCode::InvalidTtl,
+            4 => ErrorCode::Unavailable,
+            code => ErrorCode::Other(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::InvalidNamespace => 1,
+            ErrorCode::InvalidRecord => 2,
+            ErrorCode::InvalidTtl => 3,
+            ErrorCode::Unavailable => 4,
+            ErrorCode::Other(code) => code,
+        }
+    }
+}
+
+/// Request is what a node asks a rendezvous peer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    /// registers the record's addresses under the namespace. A TTL of zero
+    /// asks for the rendezvous peer's default.
+    Register {
+        namespace: String,
+        ttl: Duration,
+        record: Box<PeerRecord>,
+    },
+    /// asks for up to `limit` records registered under the namespace.
+    Discover { namespace: String, limit: u16 },
+}
+
+/// Response is how a rendezvous peer answers a request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Registered { ttl: Duration },
+    Discovered(Vec<PeerRecord>),
+    Error(ErrorCode),
+}
+
+impl Request {
+    fn namespace(&self) -> &str {
+        match self {
+            Request::Register { namespace, .. } | Request::Discover { namespace, .. } => namespace,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        match self {
+            Request::Register {
+                namespace,
+                ttl,
+                record,
+            } => {
+                bytes.push(REGISTER_REQUEST);
+                encode_namespace(&mut bytes, namespace);
+                bytes.extend_from_slice(&ttl.as_secs().to_be_bytes());
+                bytes.extend_from_slice(&record.to_signed_envelope().into_protobuf_encoding());
+            }
+            Request::Discover { namespace, limit } => {
+                bytes.push(DISCOVER_REQUEST);
+                encode_namespace(&mut bytes, namespace);
+                bytes.extend_from_slice(&limit.to_be_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// decodes a request, failing with the code to answer it with if it's invalid.
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, ErrorCode> {
+        let mut reader = Reader(bytes);
+        let kind = reader.u8().ok_or(ErrorCode::Other(0))?;
+        let namespace = reader.namespace().ok_or(ErrorCode::InvalidNamespace)?;
+        match kind {
+            REGISTER_REQUEST => {
+                let ttl = Duration::from_secs(reader.u64().ok_or(ErrorCode::InvalidTtl)?);
+                let record = SignedEnvelope::from_protobuf_encoding(reader.rest())
+                    .ok()
+                    .and_then(|envelope| PeerRecord::from_signed_envelope(envelope).ok())
+                    .ok_or(ErrorCode::InvalidRecord)?;
+                Ok(Request::Register {
+                    namespace,
+                    ttl,
+                    record: Box::new(record),
+                })
+            }
+            DISCOVER_REQUEST => {
+                let limit = reader.u16().ok_or(ErrorCode::Other(0))?;
+                Ok(Request::Discover { namespace, limit })
+            }
+            _ => Err(ErrorCode::Other(0)),
+        }
+    }
+}
+
+impl Response {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        match self {
+            Response::Registered { ttl } => {
+                bytes.push(REGISTERED_RESPONSE);
+                bytes.extend_from_slice(&ttl.as_secs().to_be_bytes());
+            }
+            Response::Discovered(records) => {
+                bytes.push(DISCOVERED_RESPONSE);
+                bytes.extend_from_slice(&(records.len() as u16).to_be_bytes());
+                for record in records {
+                    let envelope = record.to_signed_envelope().into_protobuf_encoding();
+                    bytes.extend_from_slice(&(envelope.len() as u32).to_be_bytes());
+                    bytes.extend_from_slice(&envelope);
+                }
+            }
+            Response::Error(code) => {
+                bytes.push(ERROR_RESPONSE);
+                bytes.push((*code).into());
+            }
+        }
+        bytes
+    }
+
+    /// decodes a response. Records which aren't validly signed are skipped,
+    /// as the rendezvous peer isn't trusted to vouch for them.
+    fn try_from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader(bytes);
+        let response = match reader.u8() {
+            Some(REGISTERED_RESPONSE) => reader.u64().map(|ttl| Response::Registered {
+                ttl: Duration::from_secs(ttl),
+            }),
+            Some(DISCOVERED_RESPONSE) => (|| {
+                let count = reader.u16()?;
+                let mut records = vec![];
+                for _ in 0..count {
+                    let len = reader.u32()? as usize;
+                    let envelope = reader.bytes(len)?;
+                    match SignedEnvelope::from_protobuf_encoding(envelope)
+                        .ok()
+                        .and_then(|envelope| PeerRecord::from_signed_envelope(envelope).ok())
+                    {
+                        Some(record) => records.push(record),
+                        None => {
+                            debug!("the rendezvous peer returned an invalid record, skipping it")
+                        }
+                    }
+                }
+                Some(Response::Discovered(records))
+            })(),
+            Some(ERROR_RESPONSE) => reader.u8().map(|code| Response::Error(code.into())),
+            _ => None,
+        };
+        response.ok_or_else(|| invalid_data("invalid rendezvous response"))
+    }
+}
+
+fn encode_namespace(bytes: &mut Vec<u8>, namespace: &str) {
+    bytes.push(namespace.len() as u8);
+    bytes.extend_from_slice(namespace.as_bytes());
+}
+
+fn is_valid_namespace(namespace: &str) -> bool {
+    !namespace.is_empty() && namespace.len() <= MAX_NAMESPACE_LEN
+}
+
+/// Reader decodes the fields of a request or response in order.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2)
+            .map(|b| u16::from_be_bytes(b.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8)
+            .map(|b| u64::from_be_bytes(b.try_into().unwrap()))
+    }
+
+    fn namespace(&mut self) -> Option<String> {
+        let len = self.u8()? as usize;
+        let namespace = String::from_utf8(self.bytes(len)?.to_vec()).ok()?;
+        is_valid_namespace(&namespace).then_some(namespace)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.0)
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "rendezvous message is too long",
+        ));
+    }
+    stream
+        .write_all(&(bytes.len() as u32).to_be_bytes())
+        .await?;
+    stream.write_all(bytes).await?;
+    stream.flush().await
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid_data("rendezvous message is too long"));
+    }
+    let mut bytes = vec![0; len];
+    stream.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/// HandlerIn is what the client or server behaviour asks a connection's handler to do.
+#[derive(Debug)]
+pub enum HandlerIn {
+    /// send a request to the remote; the response is reported with the same ID.
+    Request(u64, Request),
+    /// answer the inbound request with the given ID.
+    Response(u64, Response),
+}
+
+/// HandlerOut is what a connection's handler reports to its behaviour.
+#[derive(Debug)]
+pub enum HandlerOut {
+    /// the remote sent a request, which has to be answered with `HandlerIn::Response`.
+    Request(u64, Request),
+    /// the remote answered our request, or the request failed.
+    Response(u64, Result<Response, String>),
+}
+
+type HandlerEvent =
+    ConnectionHandlerEvent<ReadyUpgrade<StreamProtocol>, (u64, Request), HandlerOut>;
+
+/// InboundRequest is a request read from an inbound stream, still undecoded.
+type InboundRequest = (Vec<u8>, Stream);
+
+/// Handler sends a connection's rendezvous requests, one substream each,
+/// and, for a rendezvous peer, reads the requests it receives.
+pub struct Handler {
+    /// whether inbound requests are accepted, ie. we're a rendezvous peer.
+    serve: bool,
+    events: VecDeque<HandlerEvent>,
+    outbound: FuturesUnordered<BoxFuture<'static, (u64, Result<Response, String>)>>,
+    /// inbound streams whose request is being read.
+    inbound: FuturesUnordered<BoxFuture<'static, io::Result<InboundRequest>>>,
+    /// inbound streams waiting for the behaviour's response.
+    awaiting: HashMap<u64, Stream>,
+    next_inbound_id: u64,
+    replies: FuturesUnordered<BoxFuture<'static, io::Result<()>>>,
+}
+
+impl Handler {
+    fn new(serve: bool) -> Self {
+        Handler {
+            serve,
+            events: VecDeque::new(),
+            outbound: FuturesUnordered::new(),
+            inbound: FuturesUnordered::new(),
+            awaiting: HashMap::new(),
+            next_inbound_id: 0,
+            replies: FuturesUnordered::new(),
+        }
+    }
+
+    fn reply(&mut self, id: u64, response: Response) {
+        let Some(mut stream) = self.awaiting.remove(&id) else {
+            return;
+        };
+        self.replies.push(
+            async move {
+                let reply = async {
+                    write_frame(&mut stream, &response.to_bytes()).await?;
+                    stream.close().await
+                };
+                tokio::time::timeout(STREAM_TIMEOUT, reply)
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))?
+            }
+            .boxed(),
+        );
+    }
+}
+
+impl ConnectionHandler for Handler {
+    type FromBehaviour = HandlerIn;
+    type ToBehaviour = HandlerOut;
+    type InboundProtocol = ReadyUpgrade<StreamProtocol>;
+    type OutboundProtocol = ReadyUpgrade<StreamProtocol>;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = (u64, Request);
+
+    fn listen_protocol(&self) -> SubstreamProtocol<ReadyUpgrade<StreamProtocol>, ()> {
+        SubstreamProtocol::new(ReadyUpgrade::new(PROTOCOL_NAME), ())
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        // a request waits here until its substream is requested
+        !self.events.is_empty()
+    }
+
+    fn on_behaviour_event(&mut self, event: HandlerIn) {
+        match event {
+            HandlerIn::Request(id, request) => {
+                let protocol =
+                    SubstreamProtocol::new(ReadyUpgrade::new(PROTOCOL_NAME), (id, request))
+                        .with_timeout(STREAM_TIMEOUT);
+                self.events
+                    .push_back(ConnectionHandlerEvent::OutboundSubstreamRequest { protocol });
+            }
+            HandlerIn::Response(id, response) => self.reply(id, response),
+        }
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<HandlerEvent> {
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(event);
+        }
+
+        if let Poll::Ready(Some((id, res))) = self.outbound.poll_next_unpin(cx) {
+            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                HandlerOut::Response(id, res),
+            ));
+        }
+
+        while let Poll::Ready(Some(res)) = self.inbound.poll_next_unpin(cx) {
+            let (bytes, mut stream) = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    debug!("failed to read a rendezvous request: {e}");
+                    continue;
+                }
+            };
+            let id = self.next_inbound_id;
+            self.next_inbound_id += 1;
+            match Request::try_from_bytes(&bytes) {
+                Ok(request) => {
+                    self.awaiting.insert(id, stream);
+                    return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                        HandlerOut::Request(id, request),
+                    ));
+                }
+                Err(code) => {
+                    stream.ignore_for_keep_alive();
+                    self.awaiting.insert(id, stream);
+                    self.reply(id, Response::Error(code));
+                }
+            }
+        }
+
+        while let Poll::Ready(Some(res)) = self.replies.poll_next_unpin(cx) {
+            if let Err(e) = res {
+                debug!("failed to answer a rendezvous request: {e}");
+            }
+        }
+        Poll::Pending
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+        match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol: mut stream,
+                ..
+            }) => {
+                if !self.serve {
+                    debug!("the remote sent a rendezvous request, but we aren't a rendezvous peer");
+                    return;
+                }
+                if self.inbound.len() + self.awaiting.len() >= MAX_INBOUND_STREAMS {
+                    debug!("too many rendezvous requests from the remote, dropping one");
+                    return;
+                }
+                stream.ignore_for_keep_alive();
+                self.inbound.push(
+                    async move {
+                        let bytes = tokio::time::timeout(STREAM_TIMEOUT, read_frame(&mut stream))
+                            .await
+                            .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))??;
+                        Ok((bytes, stream))
+                    }
+                    .boxed(),
+                );
+            }
+            ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                protocol: mut stream,
+                info: (id, request),
+            }) => {
+                self.outbound.push(
+                    async move {
+                        let exchange = async {
+                            write_frame(&mut stream, &request.to_bytes()).await?;
+                            let bytes = read_frame(&mut stream).await?;
+                            Response::try_from_bytes(&bytes)
+                        };
+                        let res = match tokio::time::timeout(STREAM_TIMEOUT, exchange).await {
+                            Ok(res) => res.map_err(|e| e.to_string()),
+                            Err(_) => Err("the rendezvous peer didn't answer in time".to_string()),
+                        };
+                        (id, res)
+                    }
+                    .boxed(),
+                );
+            }
+            ConnectionEvent::DialUpgradeError(DialUpgradeError {
+                info: (id, _),
+                error,
+            }) => {
+                let error = match error {
+                    StreamUpgradeError::NegotiationFailed => {
+                        "the peer isn't a rendezvous peer".to_string()
+                    }
+                    StreamUpgradeError::Apply(e) => match e {},
+                    e => e.to_string(),
+                };
+                self.events
+                    .push_back(ConnectionHandlerEvent::NotifyBehaviour(
+                        HandlerOut::Response(id, Err(error)),
+                    ));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// ServerConfig sets the limits of a rendezvous peer.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    default_ttl: Duration,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    max_registrations: usize,
+    max_namespaces: usize,
+    max_namespaces_per_peer: usize,
+    max_discover: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            default_ttl: Duration::from_secs(2 * 60 * 60),
+            min_ttl: Duration::from_secs(2 * 60),
+            max_ttl: Duration::from_secs(72 * 60 * 60),
+            max_registrations: 1000,
+            max_namespaces: 1000,
+            max_namespaces_per_peer: 10,
+            max_discover: 100,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// the TTL of registrations which don't ask for one.
+    pub fn with_default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// the range of TTLs registrations may ask for.
+    pub fn with_ttl_range(mut self, min: Duration, max: Duration) -> Self {
+        self.min_ttl = min;
+        self.max_ttl = max;
+        self
+    }
+
+    /// the number of registrations kept per namespace.
+    pub fn with_max_registrations(mut self, max: usize) -> Self {
+        self.max_registrations = max;
+        self
+    }
+
+    /// the number of namespaces with registrations. Registrations under
+    /// another namespace are refused once it's reached.
+    pub fn with_max_namespaces(mut self, max: usize) -> Self {
+        self.max_namespaces = max;
+        self
+    }
+
+    /// the number of namespaces a single peer may be registered under.
+    pub fn with_max_namespaces_per_peer(mut self, max: usize) -> Self {
+        self.max_namespaces_per_peer = max;
+        self
+    }
+
+    /// the number of records returned per discovery.
+    pub fn with_max_discover(mut self, max: u16) -> Self {
+        self.max_discover = max;
+        self
+    }
+}
+
+struct Registration {
+    record: PeerRecord,
+    expires: Instant,
+}
+
+/// ServerEvent reports the registrations a rendezvous peer accepted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerEvent {
+    Registered {
+        peer_id: PeerId,
+        namespace: String,
+        ttl: Duration,
+    },
+    Discovered {
+        namespace: String,
+        count: usize,
+    },
+}
+
+/// Server is the behaviour of a rendezvous peer: it keeps the signed
+/// records nodes register under a namespace until their TTL runs out, and
+/// hands them out to nodes discovering the namespace. Nodes are identified
+/// by the key that signed their record, not by their connection, as dialers
+/// use a fresh identity for every connection.
+pub struct Server {
+    config: ServerConfig,
+    registrations: HashMap<String, HashMap<PeerId, Registration>>,
+    events: VecDeque<ToSwarm<ServerEvent, HandlerIn>>,
+}
+
+impl Server {
+    pub fn new(config: ServerConfig) -> Self {
+        Server {
+            config,
+            registrations: HashMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    fn handle_request(&mut self, request: Request) -> Response {
+        let now = Instant::now();
+        for registrations in self.registrations.values_mut() {
+            registrations.retain(|_, registration| registration.expires > now);
+        }
+        self.registrations
+            .retain(|_, registrations| !registrations.is_empty());
+
+        match request {
+            Request::Register {
+                namespace,
+                ttl,
+                record,
+            } => {
+                let ttl = match ttl {
+                    Duration::ZERO => self.config.default_ttl,
+                    ttl => ttl,
+                };
+                if ttl < self.config.min_ttl || ttl > self.config.max_ttl {
+                    return Response::Error(ErrorCode::InvalidTtl);
+                }
+                if record.addresses().is_empty() || !record.addresses().iter().all(is_nym_address) {
+                    return Response::Error(ErrorCode::InvalidRecord);
+                }
+                let peer_id = record.peer_id();
+                if !self.registrations.contains_key(&namespace)
+                    && self.registrations.len() >= self.config.max_namespaces
+                {
+                    return Response::Error(ErrorCode::Unavailable);
+                }
+                let peer_namespaces = self
+                    .registrations
+                    .iter()
+                    .filter(|(ns, registrations)| {
+                        **ns != namespace && registrations.contains_key(&peer_id)
+                    })
+                    .count();
+                if peer_namespaces >= self.config.max_namespaces_per_peer {
+                    return Response::Error(ErrorCode::Unavailable);
+                }
+                let registrations = self.registrations.entry(namespace.clone()).or_default();
+                match registrations.get(&peer_id) {
+                    Some(existing) if existing.record.seq() > record.seq() => {
+                        return Response::Error(ErrorCode::InvalidRecord);
+                    }
+                    None if registrations.len() >= self.config.max_registrations => {
+                        return Response::Error(ErrorCode::Unavailable);
+                    }
+                    _ => {}
+                }
+                registrations.insert(
+                    peer_id,
+                    Registration {
+                        record: *record,
+                        expires: now + ttl,
+                    },
+                );
+                self.events
+                    .push_back(ToSwarm::GenerateEvent(ServerEvent::Registered {
+                        peer_id,
+                        namespace,
+                        ttl,
+                    }));
+                Response::Registered { ttl }
+            }
+            Request::Discover { namespace, limit } => {
+                let limit = match limit {
+                    0 => self.config.max_discover,
+                    limit => limit.min(self.config.max_discover),
+                };
+                let records: Vec<_> = self
+                    .registrations
+                    .get(&namespace)
+                    .into_iter()
+                    .flat_map(|registrations| registrations.values())
+                    .take(limit as usize)
+                    .map(|registration| registration.record.clone())
+                    .collect();
+                self.events
+                    .push_back(ToSwarm::GenerateEvent(ServerEvent::Discovered {
+                        namespace,
+                        count: records.len(),
+                    }));
+                Response::Discovered(records)
+            }
+        }
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new(ServerConfig::default())
+    }
+}
+
+impl NetworkBehaviour for Server {
+    type ConnectionHandler = Handler;
+    type ToSwarm = ServerEvent;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(Handler::new(true))
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+        _: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(Handler::new(true))
+    }
+
+    fn on_swarm_event(&mut self, _: FromSwarm) {}
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        let HandlerOut::Request(id, request) = event else {
+            return;
+        };
+        let response = self.handle_request(request);
+        self.events.push_back(ToSwarm::NotifyHandler {
+            peer_id,
+            handler: NotifyHandler::One(connection_id),
+            event: HandlerIn::Response(id, response),
+        });
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// ClientEvent reports the outcome of the requests a `Client` made.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// the rendezvous peer registered our address for the given TTL. The
+    /// registration is refreshed before it runs out, and when our address changes.
+    Registered {
+        rendezvous: PeerId,
+        namespace: String,
+        ttl: Duration,
+    },
+    /// the nodes registered under the namespace, other than us, with their
+    /// addresses; they can be dialed with these.
+    Discovered {
+        rendezvous: PeerId,
+        namespace: String,
+        peers: Vec<(PeerId, Vec<Multiaddr>)>,
+    },
+    /// the request failed, or the rendezvous peer refused it; a failed
+    /// registration isn't retried.
+    Failed {
+        rendezvous: PeerId,
+        namespace: String,
+        error: String,
+    },
+}
+
+struct ClientRegistration {
+    /// the TTL asked for, or zero for the rendezvous peer's default.
+    ttl: Duration,
+    /// when the registration is refreshed, once it's been accepted.
+    refresh_at: Option<Instant>,
+}
+
+/// Client registers our `/nym` address with rendezvous peers under a
+/// namespace, and discovers the other nodes registered there, so that a
+/// mesh can be bootstrapped from a single well-known address.
+///
+/// The address is signed with the given keypair, which has to be the one
+/// the transport identifies us with to listeners. Requests to a rendezvous
+/// peer we aren't connected to are sent once we are; it's dialed if its
+/// address was added with `add_rendezvous_point`.
+pub struct Client {
+    keypair: Keypair,
+    local_address: Option<Multiaddr>,
+    rendezvous_points: HashMap<PeerId, Multiaddr>,
+    connected: HashMap<PeerId, usize>,
+    /// requests waiting for a connection to their rendezvous peer.
+    queued: HashMap<PeerId, Vec<(u64, Request)>>,
+    in_flight: HashMap<u64, (PeerId, Request)>,
+    registrations: HashMap<(PeerId, String), ClientRegistration>,
+    next_id: u64,
+    refresh: Interval,
+    events: VecDeque<ToSwarm<ClientEvent, HandlerIn>>,
+}
+
+impl Client {
+    pub fn new(keypair: Keypair) -> Self {
+        let mut refresh = interval(REFRESH_CHECK_INTERVAL);
+        refresh.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Client {
+            keypair,
+            local_address: None,
+            rendezvous_points: HashMap::new(),
+            connected: HashMap::new(),
+            queued: HashMap::new(),
+            in_flight: HashMap::new(),
+            registrations: HashMap::new(),
+            next_id: 0,
+            refresh,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// the address the rendezvous peer is dialed at when there's a request for it.
+    pub fn add_rendezvous_point(&mut self, peer_id: PeerId, address: Multiaddr) {
+        self.rendezvous_points.insert(peer_id, address);
+    }
+
+    /// registers our address with the rendezvous peer under the namespace,
+    /// for the given TTL or the rendezvous peer's default. Fails if the
+    /// namespace is invalid or the transport didn't report our address yet.
+    pub fn register(
+        &mut self,
+        namespace: impl Into<String>,
+        rendezvous: PeerId,
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        let namespace = namespace.into();
+        if !is_valid_namespace(&namespace) {
+            return Err(Error::InvalidNamespace);
+        }
+        let ttl = ttl.unwrap_or_default();
+        let request = self.register_request(namespace.clone(), ttl)?;
+        self.registrations.insert(
+            (rendezvous, namespace),
+            ClientRegistration {
+                ttl,
+                refresh_at: None,
+            },
+        );
+        self.send(rendezvous, request);
+        Ok(())
+    }
+
+    /// stops refreshing the registration; the rendezvous peer drops it once
+    /// its TTL runs out.
+    pub fn forget(&mut self, namespace: &str, rendezvous: PeerId) {
+        self.registrations
+            .remove(&(rendezvous, namespace.to_string()));
+    }
+
+    /// asks the rendezvous peer for up to `limit` nodes registered under the
+    /// namespace, or as many as it returns if `None`.
+    pub fn discover(
+        &mut self,
+        namespace: impl Into<String>,
+        rendezvous: PeerId,
+        limit: Option<u16>,
+    ) -> Result<(), Error> {
+        let namespace = namespace.into();
+        if !is_valid_namespace(&namespace) {
+            return Err(Error::InvalidNamespace);
+        }
+        let request = Request::Discover {
+            namespace,
+            limit: limit.unwrap_or(0),
+        };
+        self.send(rendezvous, request);
+        Ok(())
+    }
+
+    fn register_request(&self, namespace: String, ttl: Duration) -> Result<Request, Error> {
+        let address = self.local_address.clone().ok_or(Error::NoNymAddress)?;
+        let record = PeerRecord::new(&self.keypair, vec![address])?;
+        Ok(Request::Register {
+            namespace,
+            ttl,
+            record: Box::new(record),
+        })
+    }
+
+    fn send(&mut self, rendezvous: PeerId, request: Request) {
+        let id = self.next_id;
+        self.next_id += 1;
+        if self.connected.contains_key(&rendezvous) {
+            self.notify(rendezvous, id, request);
+            return;
+        }
+
+        let queued = self.queued.entry(rendezvous).or_default();
+        queued.push((id, request));
+        if queued.len() == 1 {
+            let opts = DialOpts::peer_id(rendezvous)
+                .addresses(
+                    self.rendezvous_points
+                        .get(&rendezvous)
+                        .into_iter()
+                        .cloned()
+                        .collect(),
+                )
+                .condition(PeerCondition::DisconnectedAndNotDialing)
+                .build();
+            self.events.push_back(ToSwarm::Dial { opts });
+        }
+    }
+
+    fn notify(&mut self, rendezvous: PeerId, id: u64, request: Request) {
+        self.in_flight.insert(id, (rendezvous, request.clone()));
+        self.events.push_back(ToSwarm::NotifyHandler {
+            peer_id: rendezvous,
+            handler: NotifyHandler::Any,
+            event: HandlerIn::Request(id, request),
+        });
+    }
+
+    fn fail(&mut self, rendezvous: PeerId, request: &Request, error: String) {
+        let namespace = request.namespace().to_string();
+        if let Request::Register { .. } = request {
+            self.registrations.remove(&(rendezvous, namespace.clone()));
+        }
+        self.events
+            .push_back(ToSwarm::GenerateEvent(ClientEvent::Failed {
+                rendezvous,
+                namespace,
+                error,
+            }));
+    }
+
+    fn on_response(&mut self, id: u64, res: Result<Response, String>) {
+        let Some((rendezvous, request)) = self.in_flight.remove(&id) else {
+            return;
+        };
+        let namespace = request.namespace().to_string();
+        let event = match res {
+            Ok(Response::Registered { ttl }) => {
+                if let Some(registration) =
+                    self.registrations.get_mut(&(rendezvous, namespace.clone()))
+                {
+                    registration.refresh_at = Some(Instant::now() + ttl / 2);
+                }
+                ClientEvent::Registered {
+                    rendezvous,
+                    namespace,
+                    ttl,
+                }
+            }
+            Ok(Response::Discovered(records)) => {
+                let local_peer_id = self.keypair.public().to_peer_id();
+                let peers = records
+                    .into_iter()
+                    .filter(|record| record.peer_id() != local_peer_id)
+                    .map(|record| (record.peer_id(), record.addresses().to_vec()))
+                    .collect();
+                ClientEvent::Discovered {
+                    rendezvous,
+                    namespace,
+                    peers,
+                }
+            }
+            Ok(Response::Error(code)) => {
+                return self.fail(rendezvous, &request, format!("refused: {code:?}"));
+            }
+            Err(error) => return self.fail(rendezvous, &request, error),
+        };
+        self.events.push_back(ToSwarm::GenerateEvent(event));
+    }
+
+    /// registers again with every rendezvous peer whose registration is due,
+    /// or all of them if `all`.
+    fn refresh_registrations(&mut self, all: bool) {
+        let now = Instant::now();
+        let due: Vec<_> = self
+            .registrations
+            .iter_mut()
+            .filter(|(_, registration)| {
+                all || registration
+                    .refresh_at
+                    .is_some_and(|refresh_at| refresh_at <= now)
+            })
+            .map(|(key, registration)| {
+                // not refreshed again until the rendezvous peer answers
+                registration.refresh_at = None;
+                (key.clone(), registration.ttl)
+            })
+            .collect();
+        for ((rendezvous, namespace), ttl) in due {
+            match self.register_request(namespace, ttl) {
+                Ok(request) => self.send(rendezvous, request),
+                Err(e) => debug!("failed to refresh a rendezvous registration: {e}"),
+            }
+        }
+    }
+}
+
+impl NetworkBehaviour for Client {
+    type ConnectionHandler = Handler;
+    type ToSwarm = ClientEvent;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(Handler::new(false))
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+        _: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(Handler::new(false))
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer_id: Option<PeerId>,
+        _: &[Multiaddr],
+        _: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        Ok(peer_id
+            .and_then(|peer_id| self.rendezvous_points.get(&peer_id))
+            .into_iter()
+            .cloned()
+            .collect())
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished { peer_id, .. }) => {
+                *self.connected.entry(peer_id).or_default() += 1;
+                for (id, request) in self.queued.remove(&peer_id).unwrap_or_default() {
+                    self.notify(peer_id, id, request);
+                }
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                remaining_established,
+                ..
+            }) => {
+                if remaining_established > 0 {
+                    return;
+                }
+                self.connected.remove(&peer_id);
+                let lost: Vec<_> = self
+                    .in_flight
+                    .iter()
+                    .filter(|(_, (rendezvous, _))| *rendezvous == peer_id)
+                    .map(|(id, _)| *id)
+                    .collect();
+                for id in lost {
+                    self.on_response(id, Err("the connection was closed".to_string()));
+                }
+            }
+            FromSwarm::DialFailure(DialFailure {
+                peer_id: Some(peer_id),
+                error,
+                ..
+            }) => {
+                for (_, request) in self.queued.remove(&peer_id).unwrap_or_default() {
+                    self.fail(peer_id, &request, format!("failed to dial: {error}"));
+                }
+            }
+            FromSwarm::NewListenAddr(NewListenAddr { addr, .. }) => {
+                if !is_nym_address(addr) || self.local_address.as_ref() == Some(addr) {
+                    return;
+                }
+                let changed = self.local_address.is_some();
+                self.local_address = Some(addr.clone());
+                if changed {
+                    self.refresh_registrations(true);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {
+            HandlerOut::Response(id, res) => self.on_response(id, res),
+            // the handler doesn't accept inbound requests
+            HandlerOut::Request(..) => {}
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        if self.refresh.poll_tick(cx).is_ready() {
+            self.refresh_registrations(false);
+        }
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::nym_address_to_multiaddress;
+    use nym_sphinx::addressing::clients::Recipient;
+    use std::str::FromStr;
+
+    const TEST_RECIPIENT: &str = "Hmer6Ndt3PV13YW53HM8ri4NvqqtfDQUQBhzvKqb1dag.2g478dyxtrQXGWc1Mk2VEqdPcWXpz7EhAcjhdAJtVZdA@AnnYnEtBjB2a5sHmeRCnBq43qxyHDf95Bqd7cwQyKNLR";
+
+    fn register(keypair: &Keypair, namespace: &str, ttl: Duration) -> Request {
+        let address =
+            nym_address_to_multiaddress(Recipient::from_str(TEST_RECIPIENT).unwrap()).unwrap();
+        Request::Register {
+            namespace: namespace.to_string(),
+            ttl,
+            record: Box::new(PeerRecord::new(keypair, vec![address]).unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rendezvous() {
+        let keypair = Keypair::generate_ed25519();
+        let request = register(&keypair, "mesh", Duration::ZERO);
+        assert_eq!(
+            Request::try_from_bytes(&request.to_bytes()),
+            Ok(request.clone())
+        );
+        let discover = Request::Discover {
+            namespace: "mesh".to_string(),
+            limit: 10,
+        };
+        assert_eq!(Request::try_from_bytes(&discover.to_bytes()), Ok(discover));
+        let mut bytes = request.to_bytes();
+        *bytes.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            Request::try_from_bytes(&bytes),
+            Err(ErrorCode::InvalidRecord)
+        );
+        assert_eq!(
+            Request::try_from_bytes(&[REGISTER_REQUEST, 0]),
+            Err(ErrorCode::InvalidNamespace)
+        );
+
+        let mut frame = vec![];
+        write_frame(
+            &mut futures::io::Cursor::new(&mut frame),
+            &request.to_bytes(),
+        )
+        .await
+        .unwrap();
+        let bytes = read_frame(&mut futures::io::Cursor::new(&frame))
+            .await
+            .unwrap();
+        assert_eq!(bytes, request.to_bytes());
+
+        // the server keeps registrations per signing key, and hands them out
+        let mut server = Server::new(ServerConfig::default().with_max_registrations(1));
+        let ttl = Duration::from_secs(600);
+        assert_eq!(
+            server.handle_request(register(&keypair, "mesh", ttl)),
+            Response::Registered { ttl }
+        );
+        assert_eq!(
+            server.handle_request(register(&keypair, "mesh", Duration::from_secs(1))),
+            Response::Error(ErrorCode::InvalidTtl)
+        );
+        assert_eq!(
+            server.handle_request(register(&Keypair::generate_ed25519(), "mesh", ttl)),
+            Response::Error(ErrorCode::Unavailable)
+        );
+        let unsigned =
+            PeerRecord::new(&keypair, vec!["/ip4/127.0.0.1/tcp/1".parse().unwrap()]).unwrap();
+        assert_eq!(
+            server.handle_request(Request::Register {
+                namespace: "other".to_string(),
+                ttl,
+                record: Box::new(unsigned),
+            }),
+            Response::Error(ErrorCode::InvalidRecord)
+        );
+        let discovered = server.handle_request(Request::Discover {
+            namespace: "mesh".to_string(),
+            limit: 0,
+        });
+        let Response::Discovered(records) = &discovered else {
+            panic!("expected Response::Discovered, got {discovered:?}");
+        };
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].peer_id(), keypair.public().to_peer_id());
+        assert_eq!(
+            Response::try_from_bytes(&discovered.to_bytes()).unwrap(),
+            discovered
+        );
+        assert_eq!(
+            server.handle_request(Request::Discover {
+                namespace: "empty".to_string(),
+                limit: 0,
+            }),
+            Response::Discovered(vec![])
+        );
+
+        // the number of namespaces, and of namespaces per peer, is bounded
+        let mut server = Server::new(
+            ServerConfig::default()
+                .with_max_namespaces(2)
+                .with_max_namespaces_per_peer(1),
+        );
+        assert_eq!(
+            server.handle_request(register(&keypair, "mesh", ttl)),
+            Response::Registered { ttl }
+        );
+        assert_eq!(
+            server.handle_request(register(&keypair, "mesh", ttl)),
+            Response::Registered { ttl }
+        );
+        assert_eq!(
+            server.handle_request(register(&keypair, "other", ttl)),
+            Response::Error(ErrorCode::Unavailable)
+        );
+        assert_eq!(
+            server.handle_request(register(&Keypair::generate_ed25519(), "other", ttl)),
+            Response::Registered { ttl }
+        );
+        assert_eq!(
+            server.handle_request(register(&Keypair::generate_ed25519(), "third", ttl)),
+            Response::Error(ErrorCode::Unavailable)
+        );
+        assert_eq!(server.registrations.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rendezvous_client() {
+        let keypair = Keypair::generate_ed25519();
+        let mut client = Client::new(keypair.clone());
+        let rendezvous = PeerId::random();
+        assert!(matches!(
+            client.register("mesh", rendezvous, None),
+            Err(Error::NoNymAddress)
+        ));
+        assert!(matches!(
+            client.discover("", rendezvous, None),
+            Err(Error::InvalidNamespace)
+        ));
+
+        client.local_address = Some(
+            nym_address_to_multiaddress(Recipient::from_str(TEST_RECIPIENT).unwrap()).unwrap(),
+        );
+        client.register("mesh", rendezvous, None).unwrap();
+        // not connected, so the rendezvous peer is dialed and the request queued
+        assert!(matches!(
+            client.events.pop_front(),
+            Some(ToSwarm::Dial { .. })
+        ));
+        assert_eq!(client.queued[&rendezvous].len(), 1);
+
+        // a registration is refreshed after half its TTL
+        let (id, request) = client.queued.remove(&rendezvous).unwrap().remove(0);
+        client.in_flight.insert(id, (rendezvous, request));
+        let ttl = Duration::from_secs(600);
+        client.on_response(id, Ok(Response::Registered { ttl }));
+        let registration = &client.registrations[&(rendezvous, "mesh".to_string())];
+        assert!(registration.refresh_at.unwrap() > Instant::now() + ttl / 3);
+        let Some(ToSwarm::GenerateEvent(event)) = client.events.pop_front() else {
+            panic!("expected an event");
+        };
+        assert_eq!(
+            event,
+            ClientEvent::Registered {
+                rendezvous,
+                namespace: "mesh".to_string(),
+                ttl,
+            }
+        );
+
+        // we aren't among the peers we discover
+        let Request::Register { record: ours, .. } = register(&keypair, "mesh", ttl) else {
+            unreachable!();
+        };
+        let Request::Register { record: theirs, .. } =
+            register(&Keypair::generate_ed25519(), "mesh", ttl)
+        else {
+            unreachable!();
+        };
+        client.in_flight.insert(
+            7,
+            (
+                rendezvous,
+                Request::Discover {
+                    namespace: "mesh".to_string(),
+                    limit: 0,
+                },
+            ),
+        );
+        client.on_response(7, Ok(Response::Discovered(vec![*ours, *theirs.clone()])));
+        let Some(ToSwarm::GenerateEvent(event)) = client.events.pop_front() else {
+            panic!("expected an event");
+        };
+        assert_eq!(
+            event,
+            ClientEvent::Discovered {
+                rendezvous,
+                namespace: "mesh".to_string(),
+                peers: vec![(theirs.peer_id(), theirs.addresses().to_vec())],
+            }
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use futures::future::BoxFuture;
+#[cfg(feature = "nym-client")]
+use futures::{stream::BoxStream, FutureExt, StreamExt};
+#[cfg(feature = "nym-client")]
+use nym_sdk::mixnet::{IncludedSurbs, MixnetClient, MixnetClientSender, MixnetMessageSender};
+use nym_sphinx::addressing::clients::Recipient;
+use nym_sphinx::anonymous_replies::requests::AnonymousSenderTag;
+use nym_sphinx::receiver::ReconstructedMessage;
+use std::sync::Arc;
+
+use super::error::Error;
+
+/// MixnetDriver is a mixnet client the transport sends and receives its
+/// messages with; see `NymTransport::new_with_driver`. nym-sdk's
+/// `MixnetClient` is one, with the `nym-client` feature. Implement it to run
+/// the transport on another client, or on a mock of the mixnet.
+pub trait MixnetDriver: Send + 'static {
+    /// returns the address messages to us are sent to.
+    fn address(&self) -> Recipient;
+
+    /// returns the sender the transport sends with, while it waits for the
+    /// next inbound message.
+    fn sender(&self) -> Arc<dyn MixnetDriverSender>;
+
+    /// returns the next inbound message, with the sender tag of its reply
+    /// SURBs if it came with any. None once the client lost its connection
+    /// to the mixnet.
+    fn next(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>>;
+
+    /// disconnects from the mixnet, once the transport stops using the client.
+    fn disconnect(self: Box<Self>) -> BoxFuture<'static, ()>;
+}
+
+/// MixnetDriverSender is the sending half of a `MixnetDriver`.
+pub trait MixnetDriverSender: Send + Sync + 'static {
+    /// sends a message to the recipient, with the given number of reply SURBs.
+    fn send<'a>(
+        &'a self,
+        recipient: Recipient,
+        message: &'a [u8],
+        reply_surbs: u32,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// replies to the sender of an inbound message, with one of its SURBs.
+    /// Fails with `Error::SurbExhausted` if there are none left for the
+    /// sender tag, or it expired; the reply is then sent again once the
+    /// sender had the chance to top them up.
+    fn send_reply<'a>(
+        &'a self,
+        sender_tag: AnonymousSenderTag,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+#[cfg(feature = "nym-client")]
+impl MixnetDriver for MixnetClient {
+    fn address(&self) -> Recipient {
+        *self.nym_address()
+    }
+
+    fn sender(&self) -> Arc<dyn MixnetDriverSender> {
+        Arc::new(self.split_sender())
+    }
+
+    fn next(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>> {
+        StreamExt::next(self).boxed()
+    }
+
+    fn disconnect(self: Box<Self>) -> BoxFuture<'static, ()> {
+        MixnetClient::disconnect(*self).boxed()
+    }
+}
+
+#[cfg(feature = "nym-client")]
+impl MixnetDriverSender for MixnetClientSender {
+    fn send<'a>(
+        &'a self,
+        recipient: Recipient,
+        message: &'a [u8],
+        reply_surbs: u32,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            self.send_message(recipient, message, IncludedSurbs::new(reply_surbs))
+                .await
+                .map_err(|e| Error::OutboundSendFailure(e.to_string()))
+        }
+        .boxed()
+    }
+
+    fn send_reply<'a>(
+        &'a self,
+        sender_tag: AnonymousSenderTag,
+        message: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            MixnetMessageSender::send_reply(self, sender_tag, message)
+                .await
+                .map_err(|e| reply_error(e.to_string()))
+        }
+        .boxed()
+    }
+}
+
+/// what the SDK reports when a reply has no SURBs left for its sender tag,
+/// or the tag is unknown, lowercased.
+#[cfg(any(feature = "nym-client", test))]
+const SURB_EXHAUSTED_ERRORS: &[&str] = &[
+    "not enough reply surbs",
+    "no reply surbs available",
+    "unknown sender tag",
+];
+
+/// maps the error of a reply the nym client refused to `Error::SurbExhausted`
+/// if it had no SURBs left for the sender tag, or didn't know the tag. The
+/// SDK only reports those as text, so they're told apart by it; other errors
+/// about SURBs, eg. malformed ones, are send failures.
+#[cfg(any(feature = "nym-client", test))]
+fn reply_error(message: String) -> Error {
+    let lowercase = message.to_lowercase();
+    if SURB_EXHAUSTED_ERRORS
+        .iter()
+        .any(|exhausted| lowercase.contains(exhausted))
+    {
+        return Error::SurbExhausted;
+    }
+    Error::OutboundSendFailure(message)
+}
+
+/// SharedClient is a mixnet client the application shares with the
+/// transport; see `NymTransport::new_with_shared_client`. The application
+/// keeps ownership of it, so the transport never disconnects it.
+#[cfg(feature = "nym-client")]
+pub(crate) struct SharedClient {
+    pub(crate) address: Recipient,
+    pub(crate) sender: MixnetClientSender,
+    /// the inbound messages the application forwards to the transport.
+    pub(crate) messages: BoxStream<'static, ReconstructedMessage>,
+}
+
+#[cfg(feature = "nym-client")]
+impl MixnetDriver for SharedClient {
+    fn address(&self) -> Recipient {
+        self.address
+    }
+
+    fn sender(&self) -> Arc<dyn MixnetDriverSender> {
+        Arc::new(self.sender.clone())
+    }
+
+    fn next(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>> {
+        self.messages.next().boxed()
+    }
+
+    fn disconnect(self: Box<Self>) -> BoxFuture<'static, ()> {
+        futures::future::ready(()).boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reply_error() {
+        assert!(matches!(
+            reply_error("no reply SURBs available for the sender tag".to_string()),
+            Error::SurbExhausted
+        ));
+        assert!(matches!(
+            reply_error("unknown sender tag".to_string()),
+            Error::SurbExhausted
+        ));
+        assert!(matches!(
+            reply_error("not enough reply SURBs: 0 available, 1 required".to_string()),
+            Error::SurbExhausted
+        ));
+        assert!(matches!(
+            reply_error("the client is shutting down".to_string()),
+            Error::OutboundSendFailure(_)
+        ));
+
+        // other errors mentioning SURBs or the tag aren't exhaustion
+        assert!(matches!(
+            reply_error("malformed reply SURB".to_string()),
+            Error::OutboundSendFailure(_)
+        ));
+        assert!(matches!(
+            reply_error("failed to encode the reply for the sender tag".to_string()),
+            Error::OutboundSendFailure(_)
+        ));
+    }
+}
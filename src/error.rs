@@ -1,7 +1,9 @@
-use libp2p::core::multiaddr;
+use libp2p::core::{multiaddr, PeerId};
 use nym_sphinx::addressing::clients::RecipientFormattingError;
 
-use super::message::SubstreamId;
+use super::connection::CloseReason;
+use super::message::{ConnectionId, SubstreamId};
+use super::redact::redact;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -13,6 +15,8 @@ pub enum Error {
     InvalidProtocolForMultiaddr,
     #[error("failed to decode message")]
     InvalidMessageBytes,
+    #[error("message of {0} bytes is too large")]
+    MessageTooLarge(usize),
     #[error("no connection found for ConnectionResponse")]
     NoConnectionForResponse,
     #[error("received ConnectionResponse but connection was already established")]
@@ -27,6 +31,78 @@ pub enum Error {
     ConnectionMessageBytesNoPeerId,
     #[error("invalid peer ID bytes")]
     InvalidPeerIdBytes,
+    #[error("invalid public key bytes")]
+    InvalidPublicKeyBytes,
+    #[error("failed to sign ConnectionMessage")]
+    HandshakeSigningFailure(#[from] libp2p_identity::SigningError),
+    #[error("ConnectionMessage public key does not match peer ID {}", redact(.0))]
+    PeerIdMismatch(PeerId),
+    #[error("invalid ConnectionMessage signature from peer {}", redact(.0))]
+    InvalidHandshakeSignature(PeerId),
+    #[error("ConnectionMessage from peer {} is too old", redact(.0))]
+    StaleHandshake(PeerId),
+    #[error("unsigned ConnectionMessage from peer {}", redact(.0))]
+    UnsignedHandshake(PeerId),
+    #[error("invalid ephemeral key in ConnectionMessage")]
+    InvalidEphemeralKey,
+    #[error("invalid key share in ConnectionMessage")]
+    InvalidKeyShare,
+    #[error("payload encryption was not negotiated with the remote peer")]
+    EncryptionNotNegotiated,
+    #[error("no cipher suite in common with the remote")]
+    UnsupportedCipherSuite,
+    #[error("key exchange produced a non-contributory shared secret")]
+    NonContributoryKeyExchange,
+    #[error("failed to encrypt TransportMessage")]
+    EncryptionFailure,
+    #[error("failed to decrypt TransportMessage")]
+    DecryptionFailure,
+    #[error("session key for TransportMessage with nonce {0} is no longer available")]
+    SessionKeyUnavailable(u64),
+    #[error("invalid KeyUpdateMessage")]
+    InvalidKeyUpdate,
+    #[error("no connection found for KeyUpdateMessage")]
+    NoConnectionForKeyUpdate,
+    #[error("received unencrypted TransportMessage on an encrypted transport")]
+    UnencryptedTransportMessage,
+    #[error("received a SealedMessage which was received before")]
+    ReplayedMessage,
+    #[error("no connection found for SealedMessage")]
+    NoConnectionForSealed,
+    #[error("received an unsealed {0} on an encrypted connection")]
+    UnsealedControlMessage(&'static str),
+    #[error("failed to decode AddressMessage; too short")]
+    AddressMessageBytesTooShort,
+    #[error("invalid AddressMessage from peer {}", redact(.0))]
+    InvalidAddressMessage(PeerId),
+    #[error("no connection found for OutOfBandMessage")]
+    NoConnectionForOutOfBand,
+    #[error("out-of-band messages were not negotiated with the remote peer")]
+    OutOfBandNotNegotiated,
+    #[error("dropped OutOfBandMessage; the connection's queue is full")]
+    OutOfBandQueueFull,
+    #[error("no connection found for AckMessage")]
+    NoConnectionForAck,
+    #[error("no connection found for AddressMessage")]
+    NoConnectionForAddress,
+    #[error("address exchange was not negotiated with the remote peer")]
+    AddressExchangeNotNegotiated,
+    #[error("invalid MigrateMessage from peer {}", redact(.0))]
+    InvalidMigrateMessage(PeerId),
+    #[error("invalid ConnectionClose from peer {}", redact(.0))]
+    InvalidConnectionClose(PeerId),
+    #[error("ConnectionClose didn't arrive over the connection's route")]
+    UnexpectedCloseSender,
+    #[error("Ack didn't arrive over the connection's route")]
+    UnexpectedAckSender,
+    #[error("Ack for nonce {0}, which wasn't sent yet")]
+    InvalidAck(u64),
+    #[error("ConnectionClose for a dial which offered signed closes isn't signed")]
+    UnsignedConnectionClose,
+    #[error("no connection found for MigrateMessage")]
+    NoConnectionForMigrate,
+    #[error("the connection can't migrate to a new address")]
+    MigrationUnavailable,
     #[error("invalid recipient bytes")]
     InvalidRecipientBytes(#[from] RecipientFormattingError),
     #[error("failed to decode TransportMessage; too short")]
@@ -37,22 +113,100 @@ pub enum Error {
     InvalidSubstreamMessageBytes,
     #[error("invalid substream message type byte")]
     InvalidSubstreamMessageType,
+    #[error("invalid connection or substream ID: {0}")]
+    InvalidId(String),
     #[error("substrean with given ID already exists")]
     SubstreamIdExists(SubstreamId),
     #[error("no substream found for given ID")]
     SubstreamIdDoesNotExist(SubstreamId),
+    #[error("the remote didn't acknowledge opening substream {0:?}")]
+    SubstreamOpenTimeout(SubstreamId),
+    #[error("the remote didn't open the raw stream in time")]
+    RawStreamTimeout,
     #[error("recv error: channel closed")]
     OneshotRecvFailure(#[from] tokio::sync::oneshot::error::RecvError),
     #[error("recv error: channel closed")]
     RecvFailure,
+    #[error("connection closed: {0}")]
+    ConnectionClosed(CloseReason),
+    #[error("connection closed by the remote peer: {0}")]
+    ClosedByRemote(CloseReason),
+    #[error("connection rejected by the remote peer: {0}")]
+    ConnectionRejected(CloseReason),
+    #[error("the connection was dropped")]
+    ConnectionDropped,
     #[error("outbound send error")]
     OutboundSendFailure(String),
+    #[error("the mixnet client is offline, and {0} messages are held back already")]
+    OfflineBufferFull(usize),
+    #[error("the mixnet client lost the connection to its gateway")]
+    GatewayDisconnected,
+    #[error("the transport stopped using the mixnet")]
+    MixnetStopped,
+    #[error("failed to connect to the mixnet: {0}")]
+    MixnetConnectFailed(String),
     #[error("inbound send error")]
     InboundSendFailure(String),
+    #[error("connection {0:?} was dropped while messages were still arriving")]
+    ConnectionDroppedWhileReceiving(ConnectionId),
+    #[error("no reply SURBs left for the remote, or its sender tag expired")]
+    SurbExhausted,
     #[error("failed to send new connection; receiver dropped")]
     ConnectionSendFailure,
     #[error("failed to send initial TransportEvent::NewAddress")]
     SendErrorTransportEvent,
     #[error("dial timed out")]
     DialTimeout(#[from] tokio::time::error::Elapsed),
+    #[error("can't dial our own nym address; use ReachabilityProbe to check that it's reachable")]
+    SelfDial,
+    #[error("a dial to this recipient is already in progress")]
+    DialInProgress,
+    #[error("too many dials waiting for a free slot")]
+    DialQueueFull,
+    #[error("the dial waited too long for a free slot")]
+    DialQueueTimeout,
+    #[error("the mixnet client was replaced before the dial completed")]
+    ClientReplacedWhileDialing,
+    #[error("peer {} is not allowed by the peer filter", redact(.0))]
+    PeerNotAllowed(PeerId),
+    #[error("the remote is not in our private network")]
+    NotInPrivateNetwork,
+    #[error("TransportMessage with nonce {0} is outside the reorder window")]
+    ReorderWindowExceeded(u64),
+    #[error("TransportMessage with nonce {0} can't be held back; the buffer budget is exhausted")]
+    BufferBudgetExceeded(u64),
+    #[error("failed to access the session store")]
+    SessionStoreIo(#[from] std::io::Error),
+    #[error("session store is corrupted or was encrypted with a different key")]
+    InvalidSessionStore,
+    #[error("session ticket is malformed, expired, or was issued by another listener")]
+    InvalidSessionTicket,
+    #[error("saved outbox is corrupted or was encrypted with a different key")]
+    InvalidOutbox,
+    #[error("failed to read network env file: {0}")]
+    NetworkEnvFileIo(std::io::Error),
+    #[error("invalid network env file; line {0} is not of the form KEY=VALUE")]
+    InvalidNetworkEnvFile(usize),
+    #[error("network variable {0} is missing or invalid")]
+    InvalidNetworkVariable(&'static str),
+    #[error("failed to open the audit log: {0}")]
+    AuditLogIo(std::io::Error),
+    #[error("failed to read config file: {0}")]
+    ConfigFileIo(std::io::Error),
+    #[error("invalid config file: {0}")]
+    InvalidConfigFile(String),
+    #[error("the reachability probe didn't arrive in time")]
+    ProbeTimeout,
+    #[error("received a reachability probe we didn't send")]
+    UnknownProbe,
+    #[error("received parity for a connection without forward error correction")]
+    UnexpectedParity,
+    #[error("failed to decompress data")]
+    DecompressionFailure,
+    #[error("the transport hasn't reported our nym address yet")]
+    NoNymAddress,
+    #[error("rendezvous namespaces must be between 1 and 255 bytes")]
+    InvalidNamespace,
+    #[error("not an address alias of this transport")]
+    UnknownAlias,
 }
@@ -0,0 +1,416 @@
+use bytes::Bytes;
+use log::{debug, warn};
+use parking_lot::{Mutex, RwLock};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use super::config::ForwardErrorCorrection;
+use super::message::{ConnectionId, Message, ParityMessage};
+use super::metrics::TransportMetrics;
+
+/// the most received messages kept per connection to recover others from.
+const MAX_KEPT_MESSAGES: usize = 1024;
+/// the most groups per connection for which parity is kept, waiting for more
+/// of the group's messages to arrive.
+const MAX_PENDING_GROUPS: usize = 64;
+/// every shard starts with the length of the message it holds.
+const LENGTH_PREFIX_LEN: usize = 4;
+
+/// FecRegistry holds the parity encoders and decoders of the connections
+/// which negotiated forward error correction. The transport adds them as the
+/// connections are set up, and the mixnet task runs the messages it sends and
+/// receives through them.
+#[derive(Clone, Default)]
+pub(crate) struct FecRegistry {
+    inner: Arc<RwLock<HashMap<ConnectionId, Arc<Mutex<ConnectionFec>>>>>,
+    metrics: Arc<TransportMetrics>,
+}
+
+impl FecRegistry {
+    pub(crate) fn new(metrics: Arc<TransportMetrics>) -> Self {
+        FecRegistry {
+            inner: Default::default(),
+            metrics,
+        }
+    }
+
+    /// adds a connection, which sends parity with the given settings, if any.
+    /// The remote may send parity either way.
+    pub(crate) fn insert(&self, id: ConnectionId, config: Option<ForwardErrorCorrection>) {
+        let encoder = config.and_then(|config| match FecEncoder::new(config) {
+            Ok(encoder) => Some(encoder),
+            Err(e) => {
+                warn!(
+                    "invalid forward error correction settings {:?}: {}",
+                    config, e
+                );
+                None
+            }
+        });
+        let fec = ConnectionFec {
+            encoder,
+            decoder: FecDecoder::default(),
+        };
+        self.inner.write().insert(id, Arc::new(Mutex::new(fec)));
+    }
+
+    pub(crate) fn remove(&self, id: &ConnectionId) {
+        self.inner.write().remove(id);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn contains(&self, id: &ConnectionId) -> bool {
+        self.inner.read().contains_key(id)
+    }
+
+    fn get(&self, id: &ConnectionId) -> Option<Arc<Mutex<ConnectionFec>>> {
+        self.inner.read().get(id).cloned()
+    }
+
+    /// records a TransportMessage as it's sent, encoded as `bytes`. Returns
+    /// the parity to send after it, once it completes a group.
+    pub(crate) fn on_sent(&self, msg: &Message, bytes: &[u8]) -> Vec<ParityMessage> {
+        let Some(nonce) = transport_nonce(msg) else {
+            return vec![];
+        };
+        let Some(fec) = self.get(msg.connection_id()) else {
+            return vec![];
+        };
+        let mut fec = fec.lock();
+        let Some(encoder) = &mut fec.encoder else {
+            return vec![];
+        };
+        let parity = encoder.on_sent(msg.connection_id(), nonce, bytes);
+        TransportMetrics::add(&self.metrics.fec_parity_sent, parity.len() as u64);
+        parity
+    }
+
+    /// records a received TransportMessage or ParityMessage, with the bytes it
+    /// was decoded from. Returns the encoded messages it recovers.
+    pub(crate) fn on_received(&self, msg: &Message, bytes: &Bytes) -> Vec<Bytes> {
+        let Some(fec) = self.get(msg.connection_id()) else {
+            return vec![];
+        };
+        let mut fec = fec.lock();
+        let recovered = match msg {
+            Message::Parity(parity) => fec.decoder.on_parity(parity),
+            _ => match transport_nonce(msg) {
+                Some(nonce) => fec.decoder.on_data(nonce, bytes.clone()),
+                None => return vec![],
+            },
+        };
+        TransportMetrics::add(&self.metrics.fec_recovered, recovered.len() as u64);
+        recovered
+    }
+}
+
+struct ConnectionFec {
+    encoder: Option<FecEncoder>,
+    decoder: FecDecoder,
+}
+
+/// returns the nonce of a TransportMessage, encrypted or not.
+fn transport_nonce(msg: &Message) -> Option<u64> {
+    match msg {
+        Message::TransportMessage(msg) => Some(msg.nonce),
+        Message::EncryptedTransportMessage(msg) => Some(msg.nonce),
+        _ => None,
+    }
+}
+
+/// prefixes each message with its length, and pads them to the same length.
+fn to_shards<'a>(messages: impl Iterator<Item = &'a [u8]>, shard_len: usize) -> Vec<Vec<u8>> {
+    messages
+        .map(|msg| {
+            let mut shard = Vec::with_capacity(shard_len);
+            shard.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+            shard.extend_from_slice(msg);
+            shard.resize(shard_len, 0);
+            shard
+        })
+        .collect()
+}
+
+/// FecEncoder collects the messages of a group as they're sent, and computes
+/// the group's parity once it's complete. Groups start at multiples of
+/// `data_shards`; messages sent out of order, eg. retransmissions, break
+/// the group they fall into.
+struct FecEncoder {
+    config: ForwardErrorCorrection,
+    codec: ReedSolomon,
+    /// the nonce of the current group's first message, and its messages so far.
+    group: Option<(u64, Vec<Vec<u8>>)>,
+}
+
+impl FecEncoder {
+    fn new(config: ForwardErrorCorrection) -> Result<Self, reed_solomon_erasure::Error> {
+        Ok(FecEncoder {
+            config,
+            codec: ReedSolomon::new(config.data_shards as usize, config.parity_shards as usize)?,
+            group: None,
+        })
+    }
+
+    fn on_sent(&mut self, id: &ConnectionId, nonce: u64, bytes: &[u8]) -> Vec<ParityMessage> {
+        let data_shards = self.config.data_shards as u64;
+        if nonce.is_multiple_of(data_shards) {
+            self.group = Some((nonce, vec![]));
+        }
+        let Some((first_nonce, messages)) = &mut self.group else {
+            return vec![];
+        };
+        let next_nonce = *first_nonce + messages.len() as u64;
+        if nonce < next_nonce {
+            // a retransmission of a message the group already has
+            return vec![];
+        }
+        if nonce > next_nonce {
+            self.group = None;
+            return vec![];
+        }
+        messages.push(bytes.to_vec());
+        if messages.len() < data_shards as usize {
+            return vec![];
+        }
+
+        let (first_nonce, messages) = self.group.take().expect("the group is complete");
+        let shard_len = LENGTH_PREFIX_LEN + messages.iter().map(Vec::len).max().unwrap_or(0);
+        let mut shards = to_shards(messages.iter().map(Vec::as_slice), shard_len);
+        shards.resize(
+            shards.len() + self.config.parity_shards as usize,
+            vec![0; shard_len],
+        );
+        if let Err(e) = self.codec.encode(&mut shards) {
+            debug!("failed to compute the parity of a group: {}", e);
+            return vec![];
+        }
+        shards
+            .drain(data_shards as usize..)
+            .enumerate()
+            .map(|(index, shard)| ParityMessage {
+                id: id.clone(),
+                first_nonce,
+                data_shards: self.config.data_shards,
+                parity_shards: self.config.parity_shards,
+                index: index as u8,
+                shard: shard.into(),
+            })
+            .collect()
+    }
+}
+
+/// FecDecoder keeps the last messages received on a connection, and the
+/// parity of the groups which miss some of theirs, until enough of a group
+/// arrived to rebuild the rest.
+#[derive(Default)]
+struct FecDecoder {
+    /// the encoded messages received or recovered, by nonce.
+    messages: BTreeMap<u64, Bytes>,
+    /// the parity received for incomplete groups, by their first nonce.
+    groups: BTreeMap<u64, ParityGroup>,
+}
+
+struct ParityGroup {
+    data_shards: u8,
+    parity: Vec<Option<Bytes>>,
+}
+
+impl FecDecoder {
+    fn on_data(&mut self, nonce: u64, bytes: Bytes) -> Vec<Bytes> {
+        if self.messages.insert(nonce, bytes).is_some() {
+            return vec![];
+        }
+        if self.messages.len() > MAX_KEPT_MESSAGES {
+            self.messages.pop_first();
+        }
+        let Some((&first_nonce, group)) = self.groups.range(..=nonce).next_back() else {
+            return vec![];
+        };
+        if nonce >= first_nonce + group.data_shards as u64 {
+            return vec![];
+        }
+        self.try_recover(first_nonce)
+    }
+
+    fn on_parity(&mut self, msg: &ParityMessage) -> Vec<Bytes> {
+        let group = self
+            .groups
+            .entry(msg.first_nonce)
+            .or_insert_with(|| ParityGroup {
+                data_shards: msg.data_shards,
+                parity: vec![None; msg.parity_shards as usize],
+            });
+        if group.data_shards != msg.data_shards || group.parity.len() != msg.parity_shards as usize
+        {
+            debug!("ignoring parity which doesn't match its group");
+            return vec![];
+        }
+        group.parity[msg.index as usize] = Some(msg.shard.clone());
+        if self.groups.len() > MAX_PENDING_GROUPS {
+            self.groups.pop_first();
+        }
+        self.try_recover(msg.first_nonce)
+    }
+
+    /// rebuilds the missing messages of a group, once as many of its messages
+    /// and parity shards arrived as it has messages. The group is forgotten
+    /// once it's complete.
+    fn try_recover(&mut self, first_nonce: u64) -> Vec<Bytes> {
+        let Some(group) = self.groups.get(&first_nonce) else {
+            return vec![];
+        };
+        let data_shards = group.data_shards as usize;
+        let nonces = first_nonce..first_nonce + data_shards as u64;
+        let received: Vec<Option<Bytes>> = nonces
+            .clone()
+            .map(|n| self.messages.get(&n).cloned())
+            .collect();
+        let missing = received.iter().filter(|msg| msg.is_none()).count();
+        if missing == 0 {
+            self.groups.remove(&first_nonce);
+            return vec![];
+        }
+        if missing > group.parity.iter().flatten().count() {
+            return vec![];
+        }
+
+        let group = self.groups.remove(&first_nonce).expect("the group exists");
+        let shard_len = group.parity.iter().flatten().next().map_or(0, Bytes::len);
+        let too_long = received
+            .iter()
+            .flatten()
+            .any(|msg| LENGTH_PREFIX_LEN + msg.len() > shard_len);
+        if too_long || group.parity.iter().flatten().any(|p| p.len() != shard_len) {
+            debug!("ignoring parity which doesn't match its group");
+            return vec![];
+        }
+        let mut shards: Vec<Option<Vec<u8>>> = received
+            .iter()
+            .map(|msg| {
+                msg.as_ref()
+                    .map(|msg| to_shards(std::iter::once(msg.as_ref()), shard_len).remove(0))
+            })
+            .collect();
+        shards.extend(group.parity.iter().map(|p| p.as_ref().map(|p| p.to_vec())));
+        let codec = match ReedSolomon::new(data_shards, group.parity.len()) {
+            Ok(codec) => codec,
+            Err(e) => {
+                debug!("invalid parity group: {}", e);
+                return vec![];
+            }
+        };
+        if let Err(e) = codec.reconstruct_data(&mut shards) {
+            debug!("failed to recover the messages of a group: {}", e);
+            return vec![];
+        }
+
+        let mut recovered = vec![];
+        for (nonce, (was_received, shard)) in nonces.zip(received.iter().zip(shards)) {
+            if was_received.is_some() {
+                continue;
+            }
+            let Some(shard) = shard else { continue };
+            let len = u32::from_be_bytes(shard[..LENGTH_PREFIX_LEN].try_into().unwrap()) as usize;
+            if LENGTH_PREFIX_LEN + len > shard.len() {
+                debug!("recovered a message with an invalid length");
+                continue;
+            }
+            let bytes = Bytes::copy_from_slice(&shard[LENGTH_PREFIX_LEN..LENGTH_PREFIX_LEN + len]);
+            self.messages.insert(nonce, bytes.clone());
+            recovered.push(bytes);
+        }
+        recovered
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::{SubstreamId, SubstreamMessage, TransportMessage};
+
+    fn transport_message(id: &ConnectionId, nonce: u64) -> Message {
+        Message::TransportMessage(TransportMessage {
+            nonce,
+            id: id.clone(),
+            message: SubstreamMessage::new_with_data(
+                SubstreamId::generate(),
+                vec![nonce as u8; nonce as usize * 10],
+            ),
+        })
+    }
+
+    #[test]
+    fn test_fec_recovers_lost_messages() {
+        let id = ConnectionId::generate();
+        let config = ForwardErrorCorrection {
+            data_shards: 4,
+            parity_shards: 2,
+        };
+        let sender = FecRegistry::default();
+        sender.insert(id.clone(), Some(config));
+        let receiver = FecRegistry::default();
+        receiver.insert(id.clone(), None);
+
+        // the parity follows the last message of the group
+        let mut sent = vec![];
+        let mut parity = vec![];
+        for nonce in 1..=7 {
+            let msg = transport_message(&id, nonce);
+            let bytes = msg.to_bytes();
+            parity.extend(sender.on_sent(&msg, &bytes));
+            sent.push((msg, Bytes::from(bytes)));
+        }
+        assert_eq!(parity.len(), 2);
+        assert!(parity.iter().all(|p| p.first_nonce == 4));
+
+        // two lost messages are recovered, whichever arrives last
+        for (msg, bytes) in sent.iter().filter(|(msg, _)| {
+            let nonce = transport_nonce(msg).unwrap();
+            nonce != 5 && nonce != 6
+        }) {
+            assert!(receiver.on_received(msg, bytes).is_empty());
+        }
+        assert!(receiver
+            .on_received(&Message::Parity(parity[1].clone()), &Bytes::new())
+            .is_empty());
+        let mut recovered =
+            receiver.on_received(&Message::Parity(parity[0].clone()), &Bytes::new());
+        recovered.sort_by_key(|bytes| bytes.len());
+        assert_eq!(recovered, vec![sent[4].1.clone(), sent[5].1.clone()]);
+        assert_eq!(receiver.metrics.snapshot().fec_recovered, 2);
+
+        // and arriving late, they're ignored
+        assert!(receiver.on_received(&sent[4].0, &sent[4].1).is_empty());
+    }
+
+    #[test]
+    fn test_fec_out_of_order() {
+        let id = ConnectionId::generate();
+        let sender = FecRegistry::default();
+        sender.insert(
+            id.clone(),
+            Some(ForwardErrorCorrection {
+                data_shards: 2,
+                parity_shards: 1,
+            }),
+        );
+        let send = |nonce| {
+            let msg = transport_message(&id, nonce);
+            sender.on_sent(&msg, &msg.to_bytes()).len()
+        };
+
+        // a retransmission doesn't break the group, but a gap does
+        assert_eq!(send(2), 0);
+        assert_eq!(send(2), 0);
+        assert_eq!(send(3), 1);
+        assert_eq!(send(4), 0);
+        assert_eq!(send(6), 0);
+        assert_eq!(send(7), 1);
+
+        // nor is parity sent on connections which didn't negotiate it
+        let receiver = FecRegistry::default();
+        let msg = transport_message(&id, 1);
+        assert!(receiver.on_sent(&msg, &msg.to_bytes()).is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::config::{AdaptiveFrameSize, PacketSizePolicy};
+use super::stats::FrameSizeStats;
+
+/// room left in a frame's last packet for the header of its TransportMessage,
+/// and the tag of its encryption.
+const MESSAGE_OVERHEAD: usize = 128;
+
+/// FrameSizer picks the size of a connection's data messages, see
+/// `AdaptiveFrameSize`. It's shared by the substreams of the connection,
+/// which read the size as they write, and the transport, which feeds it the
+/// acks and retransmissions.
+#[derive(Debug)]
+pub(crate) struct FrameSizer {
+    config: AdaptiveFrameSize,
+    /// the bytes of a message one sphinx packet carries.
+    packet_payload: usize,
+    max_frame_size: usize,
+    state: Mutex<LossEstimate>,
+    /// the current frame size, read on every write.
+    frame_size: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct LossEstimate {
+    /// the estimated share of packets lost.
+    packet_loss: f64,
+    /// the packets a frame spans at the current frame size.
+    packets: usize,
+}
+
+impl FrameSizer {
+    pub(crate) fn new(
+        config: AdaptiveFrameSize,
+        packet_size: PacketSizePolicy,
+        max_frame_size: usize,
+    ) -> Self {
+        // large messages are sent in extended packets, if there are any
+        let packet_size = match packet_size {
+            PacketSizePolicy::Fixed(size) => size,
+            PacketSizePolicy::Auto { extended } => extended,
+        };
+        let packet_payload = packet_size.plaintext_size().max(1);
+        let sizer = FrameSizer {
+            config,
+            packet_payload,
+            max_frame_size,
+            state: Mutex::new(LossEstimate {
+                packet_loss: 0.0,
+                packets: 0,
+            }),
+            frame_size: AtomicUsize::new(max_frame_size),
+        };
+        sizer.update(&mut sizer.state.lock());
+        sizer
+    }
+
+    /// returns the most bytes to send in a data message.
+    pub(crate) fn frame_size(&self) -> usize {
+        self.frame_size.load(Ordering::Relaxed)
+    }
+
+    /// records messages the remote acknowledged.
+    pub(crate) fn on_acked(&self, messages: usize) {
+        self.record(messages, false);
+    }
+
+    /// records messages which weren't acknowledged in time, and are resent.
+    pub(crate) fn on_resent(&self, messages: usize) {
+        self.record(messages, true);
+    }
+
+    pub(crate) fn stats(&self) -> FrameSizeStats {
+        let state = self.state.lock();
+        FrameSizeStats {
+            frame_size: self.frame_size(),
+            packets: state.packets,
+            packet_loss_ppm: (state.packet_loss * 1_000_000.0).round() as u32,
+        }
+    }
+
+    fn record(&self, messages: usize, lost: bool) {
+        if messages == 0 {
+            return;
+        }
+        let mut state = self.state.lock();
+        // the samples are of messages of the current size, whose loss is
+        // moved towards theirs
+        let delivered = (1.0 - state.packet_loss).powi(state.packets as i32);
+        let weight = (1.0 - self.config.smoothing.clamp(0.0, 1.0)).powi(messages as i32);
+        let delivered = match lost {
+            true => delivered * weight,
+            false => 1.0 - (1.0 - delivered) * weight,
+        };
+        state.packet_loss = 1.0 - delivered.powf(1.0 / state.packets as f64);
+        self.update(&mut state);
+    }
+
+    /// picks the most packets per frame at which the frames' loss stays
+    /// within the target.
+    fn update(&self, state: &mut LossEstimate) {
+        let max_packets = (self.max_frame_size / self.packet_payload).max(1);
+        let min_packets = self.config.min_packets.clamp(1, max_packets);
+        let target = self.config.target_loss.clamp(0.0, 1.0);
+        let packets = if state.packet_loss <= 0.0 {
+            max_packets
+        } else if state.packet_loss >= 1.0 || target >= 1.0 {
+            min_packets
+        } else {
+            let packets = (1.0 - target).ln() / (1.0 - state.packet_loss).ln();
+            (packets.floor() as usize).clamp(min_packets, max_packets)
+        };
+        state.packets = packets;
+        let frame_size = (packets * self.packet_payload)
+            .saturating_sub(MESSAGE_OVERHEAD)
+            .clamp(1, self.max_frame_size);
+        self.frame_size.store(frame_size, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nym_sphinx::params::PacketSize;
+
+    #[test]
+    fn test_frame_sizer_follows_loss() {
+        let payload = PacketSize::RegularPacket.plaintext_size();
+        let max_frame_size = 64 * payload;
+        let sizer = FrameSizer::new(
+            AdaptiveFrameSize::default(),
+            PacketSizePolicy::default(),
+            max_frame_size,
+        );
+
+        // without loss, frames fill max_frame_size
+        assert_eq!(sizer.frame_size(), max_frame_size - MESSAGE_OVERHEAD);
+        sizer.on_acked(100);
+        assert_eq!(sizer.stats().packets, 64);
+
+        // a lossy connection sends smaller frames, of whole packets
+        for _ in 0..20 {
+            sizer.on_acked(10);
+            sizer.on_resent(3);
+        }
+        let stats = sizer.stats();
+        assert!(stats.packets < 8, "{:?}", stats);
+        assert!(stats.packets >= 1);
+        assert_eq!(stats.frame_size, stats.packets * payload - MESSAGE_OVERHEAD);
+        assert!(stats.packet_loss_ppm > 0);
+
+        // and they grow again once the loss subsides
+        sizer.on_acked(1000);
+        assert!(sizer.stats().packets > stats.packets);
+    }
+
+    #[test]
+    fn test_frame_sizer_bounds() {
+        let payload = PacketSize::RegularPacket.plaintext_size();
+        let config = AdaptiveFrameSize {
+            min_packets: 4,
+            ..Default::default()
+        };
+        let sizer = FrameSizer::new(config, PacketSizePolicy::default(), 16 * payload);
+        sizer.on_resent(1000);
+        assert_eq!(sizer.stats().packets, 4);
+
+        // frames never exceed max_frame_size, even below a packet
+        let sizer = FrameSizer::new(config, PacketSizePolicy::default(), 100);
+        assert_eq!(sizer.frame_size(), 100);
+        sizer.on_resent(1000);
+        assert_eq!(sizer.frame_size(), 100);
+    }
+}
@@ -0,0 +1,167 @@
+use hkdf::Hkdf;
+use libp2p::core::PeerId;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+use zeroize::Zeroizing;
+
+use super::message::PSK_PROOF_LENGTH;
+
+const PSK_PROOF_INFO: &[u8] = b"nym-libp2p-pnet-proof";
+
+/// PeerFilter is a static allow-list and deny-list of PeerIds.
+/// It's enforced for inbound connection requests, which are dropped before any
+/// connection state is allocated, and for outbound dials, which fail with
+/// `Error::PeerNotAllowed` once the remote's PeerId is known.
+///
+/// Note that dialers using this transport generate a fresh PeerId for every
+/// connection, so inbound filtering is only meaningful for dialers which
+/// use a stable identity.
+#[derive(Clone, Debug, Default)]
+pub struct PeerFilter {
+    /// if set, only these peers are allowed.
+    allow: Option<HashSet<PeerId>>,
+    /// these peers are never allowed, even if they're on the allow-list.
+    deny: HashSet<PeerId>,
+}
+
+impl PeerFilter {
+    /// returns a filter that only allows the given peers.
+    pub fn allow_only(peers: impl IntoIterator<Item = PeerId>) -> Self {
+        PeerFilter {
+            allow: Some(peers.into_iter().collect()),
+            deny: HashSet::new(),
+        }
+    }
+
+    /// returns a filter that allows all peers except the given ones.
+    pub fn deny(peers: impl IntoIterator<Item = PeerId>) -> Self {
+        PeerFilter {
+            allow: None,
+            deny: peers.into_iter().collect(),
+        }
+    }
+
+    /// adds a peer to the allow-list, creating it if the filter didn't have one.
+    pub fn with_allowed(mut self, peer_id: PeerId) -> Self {
+        self.allow.get_or_insert_with(HashSet::new).insert(peer_id);
+        self
+    }
+
+    /// adds a peer to the deny-list.
+    pub fn with_denied(mut self, peer_id: PeerId) -> Self {
+        self.deny.insert(peer_id);
+        self
+    }
+
+    pub fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        if self.deny.contains(peer_id) {
+            return false;
+        }
+
+        match &self.allow {
+            Some(allow) => allow.contains(peer_id),
+            None => true,
+        }
+    }
+}
+
+/// PreSharedKey puts the transport in a private network, like libp2p's pnet
+/// does for TCP: only peers which know the key may connect to each other.
+///
+/// Every ConnectionRequest carries a proof that its dialer knows the key,
+/// derived from the key and the request. Requests without a valid proof are
+/// dropped before the signature is checked or any state is allocated, and
+/// without a reply, so outsiders can't tell whether anyone listens at the
+/// address. Resumptions, out-of-band messages and probes which don't belong
+/// to a connection or probe that passed the check are dropped silently too.
+/// The proof is derived from the request alone, which isn't encrypted, so a
+/// captured request can be replayed until it's older than
+/// `max_handshake_age`; the response then tells the outsider that someone
+/// listens. Listeners prove knowledge of the key in their ConnectionResponse
+/// in turn, and dials to listeners which don't fail with
+/// `Error::NotInPrivateNetwork`.
+///
+/// Unlike pnet, the traffic itself isn't encrypted with the key; enable
+/// payload encryption for that.
+#[derive(Clone)]
+pub struct PreSharedKey(Zeroizing<[u8; 32]>);
+
+impl PreSharedKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        PreSharedKey(Zeroizing::new(key))
+    }
+
+    /// returns a fingerprint of the key, with which peers can check they're
+    /// in the same network without revealing it.
+    pub fn fingerprint(&self) -> String {
+        hex::encode(&Sha256::digest(self.0.as_ref())[..16])
+    }
+
+    /// returns the proof that the sender of a ConnectionMessage knows the key.
+    pub(crate) fn proof(&self, payload: &[u8]) -> [u8; PSK_PROOF_LENGTH] {
+        let mut proof = [0u8; PSK_PROOF_LENGTH];
+        Hkdf::<Sha256>::new(Some(self.0.as_ref()), payload)
+            .expand(PSK_PROOF_INFO, &mut proof)
+            .expect("proof length is valid for HKDF-SHA256");
+        proof
+    }
+
+    /// checks a proof in constant time.
+    pub(crate) fn verify(&self, payload: &[u8], proof: &[u8; PSK_PROOF_LENGTH]) -> bool {
+        let expected = self.proof(payload);
+        expected
+            .iter()
+            .zip(proof)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+impl Debug for PreSharedKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PreSharedKey")
+            .field(&self.fingerprint())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_peer_filter() {
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        let filter = PeerFilter::default();
+        assert!(filter.is_allowed(&a));
+
+        let filter = PeerFilter::deny([a]);
+        assert!(!filter.is_allowed(&a));
+        assert!(filter.is_allowed(&b));
+
+        let filter = PeerFilter::allow_only([a, b]).with_denied(b);
+        assert!(filter.is_allowed(&a));
+        assert!(!filter.is_allowed(&b));
+        assert!(!filter.is_allowed(&c));
+
+        let filter = PeerFilter::deny([b]).with_allowed(a);
+        assert!(filter.is_allowed(&a));
+        assert!(!filter.is_allowed(&c));
+    }
+
+    #[test]
+    fn test_pre_shared_key() {
+        let psk = PreSharedKey::new([1; 32]);
+        let proof = psk.proof(b"request");
+        assert!(psk.verify(b"request", &proof));
+        assert!(!psk.verify(b"other request", &proof));
+
+        // a proof made with another key doesn't check out
+        let other = PreSharedKey::new([2; 32]);
+        assert!(!other.verify(b"request", &proof));
+        assert_ne!(psk.fingerprint(), other.fingerprint());
+        assert_eq!(psk.fingerprint(), PreSharedKey::new([1; 32]).fingerprint());
+    }
+}
@@ -1,10 +1,55 @@
-pub(crate) mod connection;
+pub mod alias;
+pub mod announce;
+pub mod audit;
+pub mod bandwidth;
+pub mod bootstrap;
+pub(crate) mod budget;
+pub mod chaos;
+#[cfg(feature = "nym-client")]
+pub mod client;
+pub(crate) mod codec;
+pub(crate) mod compression;
+pub mod config;
+pub mod config_file;
+pub(crate) mod congestion;
+pub mod connection;
+pub(crate) mod dial;
+pub mod discovery;
+pub mod driver;
 pub mod error;
+pub(crate) mod fec;
+pub(crate) mod framing;
+pub mod gating;
 pub(crate) mod message;
+pub mod metrics;
 pub(crate) mod mixnet;
+pub(crate) mod outbox;
+pub(crate) mod padding;
+pub(crate) mod persist;
+pub mod presets;
 pub(crate) mod queue;
+pub mod raw;
+pub mod redact;
+pub(crate) mod retransmit;
+pub(crate) mod session;
+pub mod stats;
 pub mod substream;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+pub mod ticket;
 pub mod transport;
+pub mod wire;
+
+#[cfg(feature = "fuzzing")]
+pub use message::fuzzing;
 
 /// The deafult timeout secs for [`transport::Upgrade`] future.
 const DEFAULT_HANDSHAKE_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_HANDSHAKE_AGE_SECS: u64 = 300;
+const DEFAULT_MAX_SUBSTREAM_BUFFER_BYTES: usize = 4 * 1024 * 1024;
+const DEFAULT_MAX_FRAME_BYTES: usize = 64 * 1024;
+const DEFAULT_MAX_UNSENT_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_OUTBOUND_BACKLOG: usize = 1024;
+/// The maximum number of inbound messages the transport or a connection
+/// handles in a single poll before yielding to the executor.
+const POLL_BUDGET: usize = 64;
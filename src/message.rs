@@ -1,160 +1,1958 @@
-use libp2p::core::PeerId;
-use nym_sdk::mixnet::AnonymousSenderTag;
+use bytes::Bytes;
+use libp2p::core::{Endpoint, PeerId};
+use libp2p_identity::{Keypair, PublicKey};
+use multihash::Multihash;
 use nym_sphinx::addressing::clients::Recipient;
+use nym_sphinx::anonymous_replies::requests::AnonymousSenderTag;
 use rand::rngs::OsRng;
 use rand::RngCore;
-use std::fmt::{Debug, Formatter};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use super::alias::AliasId;
+use super::codec::{unix_time, Decoder};
+use super::connection::CloseReason;
 use super::error::Error;
+use super::gating::PreSharedKey;
+use super::padding::strip_padding;
+use super::redact::{redact, redact_always};
+use super::session::Session;
+use super::substream::WriteCredit;
 
 const CONNECTION_ID_LENGTH: usize = 32;
 const SUBSTREAM_ID_LENGTH: usize = 32;
+/// length of the compact encoding of connection and substream IDs, see `ConnectionFlags::COMPACT_IDS`.
+const COMPACT_ID_LENGTH: usize = 8;
 
 const NONCE_BYTES_LEN: usize = 8; // length of u64
 const MIN_CONNECTION_MESSAGE_LEN: usize = CONNECTION_ID_LENGTH + NONCE_BYTES_LEN;
+const MIN_COMPACT_CONNECTION_MESSAGE_LEN: usize = COMPACT_ID_LENGTH + NONCE_BYTES_LEN;
+
+const TIMESTAMP_BYTES_LEN: usize = 8; // length of u64
+const FLAGS_BYTES_LEN: usize = 1;
+pub(crate) const EPHEMERAL_KEY_LENGTH: usize = 32;
+const CLOSE_CODE_BYTES_LEN: usize = 2; // length of u16
+/// longer close messages are truncated, so a close always fits in one packet.
+const MAX_CLOSE_MESSAGE_LEN: usize = 256;
+/// further ranges of received nonces are left out of an ack, so that it
+/// always fits in one packet; the sender learns about them with later acks.
+pub const MAX_SACK_BLOCKS: usize = 16;
+const SACK_BLOCK_BYTES_LEN: usize = 2 * NONCE_BYTES_LEN;
+/// larger payloads are rejected before being decoded; well above anything a
+/// peer sends, as data frames are bounded by `NymTransportConfig::max_frame_size`.
+pub const MAX_MESSAGE_LEN: usize = 8 * 1024 * 1024;
+/// bounds for the handshake's length-prefixed fields, which fit keys and
+/// signatures of every key type libp2p supports.
+const MAX_PUBLIC_KEY_LEN: usize = 2048;
+const MAX_SIGNATURE_LEN: usize = 2048;
+
+/// the first byte of every message, identifying its type.
+/// Unsigned ConnectionMessages keep the types and encoding of releases from
+/// before signed handshakes, so that those peers can still be talked to.
+const CONNECTION_REQUEST_TYPE: u8 = 0;
+const CONNECTION_RESPONSE_TYPE: u8 = 1;
+const TRANSPORT_MESSAGE_TYPE: u8 = 2;
+const ENCRYPTED_TRANSPORT_MESSAGE_TYPE: u8 = 3;
+const COMPACT_TRANSPORT_MESSAGE_TYPE: u8 = 4;
+const COMPACT_ENCRYPTED_TRANSPORT_MESSAGE_TYPE: u8 = 5;
+const ADDRESS_MESSAGE_TYPE: u8 = 6;
+const OUT_OF_BAND_MESSAGE_TYPE: u8 = 7;
+const CONNECTION_CLOSE_TYPE: u8 = 8;
+const ACK_MESSAGE_TYPE: u8 = 9;
+const PROBE_MESSAGE_TYPE: u8 = 10;
+const SESSION_TICKET_TYPE: u8 = 11;
+const RESUME_MESSAGE_TYPE: u8 = 12;
+const MIGRATE_MESSAGE_TYPE: u8 = 13;
+const PARITY_MESSAGE_TYPE: u8 = 14;
+const KEY_UPDATE_TYPE: u8 = 15;
+/// a message padded to a fixed size, see `MessagePadding`.
+pub(crate) const PADDED_MESSAGE_TYPE: u8 = 16;
+const SIGNED_CONNECTION_REQUEST_TYPE: u8 = 17;
+const SIGNED_CONNECTION_RESPONSE_TYPE: u8 = 18;
+/// a control message sealed with the session of its connection, see `SealedMessage`.
+pub(crate) const SEALED_MESSAGE_TYPE: u8 = 19;
+const SIGNED_CONNECTION_CLOSE_TYPE: u8 = 20;
+
+/// extensions trail the PeerId of a ConnectionMessage, as a type, a u16
+/// length and a value each. Unknown ones are skipped.
+const COMPRESSION_EXTENSION: u8 = 1;
+const CIPHER_SUITES_EXTENSION: u8 = 2;
+const KEY_SHARE_EXTENSION: u8 = 3;
+const KEY_UPDATES_EXTENSION: u8 = 4;
+const COVER_TRAFFIC_EXTENSION: u8 = 5;
+const PSK_PROOF_EXTENSION: u8 = 6;
+const SUBSTREAM_DIRECTIONS_EXTENSION: u8 = 7;
+const OUT_OF_BAND_EXTENSION: u8 = 8;
+const SIGNED_CLOSES_EXTENSION: u8 = 9;
+const HALF_CLOSE_EXTENSION: u8 = 10;
+const PADDING_EXTENSION: u8 = 11;
+
+/// the length of the proof that the sender of a ConnectionMessage knows the
+/// private network's key.
+pub(crate) const PSK_PROOF_LENGTH: usize = 32;
+
+/// the length of the tag authenticating a KeyUpdateMessage.
+pub(crate) const KEY_UPDATE_TAG_LENGTH: usize = 16;
+
+/// domain separators for ConnectionMessage signatures, so that a signed
+/// request can't be replayed as a response or vice versa.
+const CONNECTION_REQUEST_DOMAIN: &[u8] = b"nym-libp2p-connection-request";
+const CONNECTION_RESPONSE_DOMAIN: &[u8] = b"nym-libp2p-connection-response";
+const ADDRESS_MESSAGE_DOMAIN: &[u8] = b"nym-libp2p-address";
+const MIGRATE_MESSAGE_DOMAIN: &[u8] = b"nym-libp2p-migrate";
+const CONNECTION_CLOSE_DOMAIN: &[u8] = b"nym-libp2p-connection-close";
+const RESUME_MESSAGE_DOMAIN: &[u8] = b"nym-libp2p-resume";
 
 /// ConnectionId is a unique, randomly-generated per-connection ID that's used to
 /// identify which connection a message belongs to.
+/// Compact IDs only use the first `COMPACT_ID_LENGTH` bytes, so that they can
+/// be sent in compact form; they're only generated for connections which
+/// offer `ConnectionFlags::COMPACT_IDS`, as they're easier to guess.
 #[derive(Clone, Default, Eq, Hash, PartialEq)]
-pub(crate) struct ConnectionId([u8; 32]);
+pub struct ConnectionId(pub(crate) [u8; 32]);
 
 impl ConnectionId {
-    pub(crate) fn generate() -> Self {
+    /// generates a random ID of all 32 bytes.
+    pub fn generate() -> Self {
         let mut bytes = [0u8; 32];
         OsRng.fill_bytes(&mut bytes);
         ConnectionId(bytes)
     }
 
-    fn from_bytes(bytes: &[u8]) -> Self {
-        let mut id = [0u8; 32];
-        id[..].copy_from_slice(&bytes[0..CONNECTION_ID_LENGTH]);
-        ConnectionId(id)
+    /// generates a random ID, which can be sent in compact form.
+    pub fn generate_compact() -> Self {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes[..COMPACT_ID_LENGTH]);
+        ConnectionId(bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(ConnectionId(decode_id(bytes, CONNECTION_ID_LENGTH)?))
+    }
+
+    fn from_compact_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(ConnectionId(decode_id(bytes, COMPACT_ID_LENGTH)?))
+    }
+
+    /// returns true if the ID can be sent in compact form.
+    pub fn is_compact(&self) -> bool {
+        is_compact(&self.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Debug for ConnectionId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl Display for ConnectionId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl FromStr for ConnectionId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ConnectionId(parse_id(s)?))
+    }
+}
+
+impl Serialize for ConnectionId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ConnectionId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// SubstreamId is a unique, randomly-generated per-substream ID that's used to
+/// identify which substream a message belongs to.
+/// Like `ConnectionId`, compact IDs only use the first `COMPACT_ID_LENGTH`
+/// bytes; they're only generated on connections using compact IDs.
+/// The top bit of the first byte tells which side of the connection opened
+/// the substream, so that IDs opened concurrently by both sides never
+/// collide; older peers generate it at random.
+#[derive(Clone, Default, Eq, Hash, PartialEq)]
+pub struct SubstreamId(pub(crate) [u8; 32]);
+
+/// the bit of the first byte of a SubstreamId which is set if the dialer
+/// opened the substream.
+const DIALER_SUBSTREAM_BIT: u8 = 0x80;
+
+impl SubstreamId {
+    /// generates a random ID of all 32 bytes.
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        SubstreamId(bytes)
+    }
+
+    /// generates a random ID, which can be sent in compact form.
+    pub fn generate_compact() -> Self {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes[..COMPACT_ID_LENGTH]);
+        SubstreamId(bytes)
+    }
+
+    /// generates a random ID for a substream opened by the given side of the
+    /// connection, in compact form if `compact` is set.
+    pub fn generate_for(opener: Endpoint, compact: bool) -> Self {
+        let mut id = if compact {
+            Self::generate_compact()
+        } else {
+            Self::generate()
+        };
+        match opener {
+            Endpoint::Dialer => id.0[0] |= DIALER_SUBSTREAM_BIT,
+            Endpoint::Listener => id.0[0] &= !DIALER_SUBSTREAM_BIT,
+        }
+        id
+    }
+
+    /// returns the side of the connection which opened the substream. It's
+    /// only meaningful if the remote namespaces its IDs as well; see
+    /// `HandshakeExtensions::substream_directions`.
+    pub fn opener(&self) -> Endpoint {
+        if self.0[0] & DIALER_SUBSTREAM_BIT != 0 {
+            Endpoint::Dialer
+        } else {
+            Endpoint::Listener
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(SubstreamId(decode_id(bytes, SUBSTREAM_ID_LENGTH)?))
+    }
+
+    fn from_compact_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(SubstreamId(decode_id(bytes, COMPACT_ID_LENGTH)?))
+    }
+
+    /// returns true if the ID can be sent in compact form.
+    pub fn is_compact(&self) -> bool {
+        is_compact(&self.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Debug for SubstreamId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl Display for SubstreamId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl FromStr for SubstreamId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(SubstreamId(parse_id(s)?))
+    }
+}
+
+impl Serialize for SubstreamId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SubstreamId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// decodes an ID from the first `len` bytes, zero-padding compact ones.
+fn decode_id(bytes: &[u8], len: usize) -> Result<[u8; 32], Error> {
+    let encoded = bytes.get(..len).ok_or(Error::InvalidMessageBytes)?;
+    let mut id = [0u8; 32];
+    id[..len].copy_from_slice(encoded);
+    Ok(id)
+}
+
+/// parses a hex-encoded ID, as displayed. The compact form, of
+/// `COMPACT_ID_LENGTH` bytes, is zero-padded like on the wire.
+fn parse_id(s: &str) -> Result<[u8; 32], Error> {
+    let bytes = hex::decode(s).map_err(|_| Error::InvalidId(s.to_string()))?;
+    if bytes.len() != CONNECTION_ID_LENGTH && bytes.len() != COMPACT_ID_LENGTH {
+        return Err(Error::InvalidId(s.to_string()));
+    }
+    decode_id(&bytes, bytes.len())
+}
+
+/// an ID is compact if all bytes after the first `COMPACT_ID_LENGTH` are zero,
+/// ie. if it survives being truncated and zero-padded again.
+fn is_compact(id: &[u8; 32]) -> bool {
+    id[COMPACT_ID_LENGTH..].iter().all(|b| *b == 0)
+}
+
+/// Message is a message exchanged between two transports through the mixnet.
+/// Further kinds may be added in minor versions; peers which don't know a
+/// kind fail to decode it, and drop it.
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
+#[non_exhaustive]
+pub enum Message {
+    ConnectionRequest(ConnectionMessage),
+    ConnectionResponse(ConnectionMessage),
+    TransportMessage(TransportMessage),
+    EncryptedTransportMessage(EncryptedTransportMessage),
+    AddressMessage(AddressMessage),
+    OutOfBandMessage(OutOfBandMessage),
+    /// tells the remote that the connection was closed, and why.
+    ConnectionClose(ConnectionCloseMessage),
+    /// acknowledges the TransportMessages received on a connection.
+    Ack(AckMessage),
+    /// sent to our own nym address to check that we're reachable.
+    Probe(ProbeMessage),
+    /// a session ticket the listener issues to the dialer of a connection.
+    SessionTicket(SessionTicketMessage),
+    /// resumes a connection with a session ticket, instead of a handshake.
+    Resume(ResumeMessage),
+    /// moves a connection to the new address of the remote's mixnet client.
+    Migrate(MigrateMessage),
+    /// a parity shard for a group of TransportMessages.
+    Parity(ParityMessage),
+    /// a step of replacing the session keys of an encrypted connection.
+    KeyUpdate(KeyUpdateMessage),
+    /// a control message of an encrypted connection, sealed with its session.
+    Sealed(SealedMessage),
+}
+
+/// ConnectionFlags are the connection options a dialer asks for in its
+/// ConnectionRequest. The ConnectionResponse carries the subset the listener
+/// agreed to, which is what both sides use. Unknown flags are ignored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionFlags(u8);
+
+impl ConnectionFlags {
+    /// messages are handed to the connection as they arrive, instead of in
+    /// nonce order.
+    pub const UNORDERED: ConnectionFlags = ConnectionFlags(1);
+    /// connection and substream IDs are sent in compact form, saving 48 bytes
+    /// of every TransportMessage. Peers which don't know the flag drop it from
+    /// the response, so both keep using the full 32-byte encoding. Only
+    /// offered with `NymTransportConfig::compact_ids`.
+    pub const COMPACT_IDS: ConnectionFlags = ConnectionFlags(2);
+    /// the dialer sends its nym address in an AddressMessage once the
+    /// handshake is complete, so the listener can dial it as well.
+    pub const ADDRESS_EXCHANGE: ConnectionFlags = ConnectionFlags(4);
+    /// the receiver of TransportMessages acknowledges them with AckMessages,
+    /// which the sender's congestion window is driven by.
+    pub const ACKS: ConnectionFlags = ConnectionFlags(8);
+    /// messages which aren't acknowledged in time are retransmitted; acks
+    /// carry the ranges of nonces received beyond the first missing one.
+    pub const SELECTIVE_REPEAT: ConnectionFlags = ConnectionFlags(16);
+    /// the listener sends the dialer session tickets, with which it can
+    /// resume the connection after a restart.
+    pub const SESSION_TICKETS: ConnectionFlags = ConnectionFlags(32);
+    /// either side sends a MigrateMessage when its nym address changes,
+    /// instead of closing the connection.
+    pub const MIGRATION: ConnectionFlags = ConnectionFlags(64);
+    /// either side may follow groups of its TransportMessages with
+    /// ParityMessages, from which the remote recovers lost messages.
+    pub const FEC: ConnectionFlags = ConnectionFlags(128);
+
+    pub fn contains(self, other: ConnectionFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(self, other: ConnectionFlags) -> ConnectionFlags {
+        ConnectionFlags(self.0 | other.0)
+    }
+
+    pub fn intersection(self, other: ConnectionFlags) -> ConnectionFlags {
+        ConnectionFlags(self.0 & other.0)
+    }
+
+    pub fn difference(self, other: ConnectionFlags) -> ConnectionFlags {
+        ConnectionFlags(self.0 & !other.0)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn from_bits(bits: u8) -> ConnectionFlags {
+        ConnectionFlags(bits)
+    }
+}
+
+/// CompressionAlgorithm identifies an algorithm data messages may be
+/// compressed with. Algorithms this version doesn't know keep their ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CompressionAlgorithm(u8);
+
+impl CompressionAlgorithm {
+    pub const LZ4: CompressionAlgorithm = CompressionAlgorithm(1);
+    pub const ZSTD: CompressionAlgorithm = CompressionAlgorithm(2);
+
+    pub fn id(self) -> u8 {
+        self.0
+    }
+
+    pub fn from_id(id: u8) -> CompressionAlgorithm {
+        CompressionAlgorithm(id)
+    }
+}
+
+/// CipherSuite identifies the key exchange and AEAD an encrypted connection
+/// uses. Suites this version doesn't know keep their ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CipherSuite(u8);
+
+impl CipherSuite {
+    /// the suite of connections whose handshake doesn't name one.
+    pub const X25519_CHACHA20_POLY1305: CipherSuite = CipherSuite(1);
+    pub const X25519_AES_256_GCM: CipherSuite = CipherSuite(2);
+    /// a hybrid of X25519 and the post-quantum ML-KEM-768 (Kyber), so that
+    /// recorded traffic stays secret unless both are broken.
+    pub const X25519_MLKEM768_CHACHA20_POLY1305: CipherSuite = CipherSuite(3);
+
+    pub fn id(self) -> u8 {
+        self.0
+    }
+
+    pub fn from_id(id: u8) -> CipherSuite {
+        CipherSuite(id)
+    }
+
+    /// returns whether this version implements the suite. The hybrid suite
+    /// requires the `post-quantum` feature.
+    pub fn is_supported(self) -> bool {
+        match self {
+            CipherSuite::X25519_CHACHA20_POLY1305 | CipherSuite::X25519_AES_256_GCM => true,
+            CipherSuite::X25519_MLKEM768_CHACHA20_POLY1305 => cfg!(feature = "post-quantum"),
+            _ => false,
+        }
+    }
+
+    /// returns whether the suite's key exchange takes a KEM key share
+    /// besides the X25519 key.
+    pub fn is_hybrid(self) -> bool {
+        self == CipherSuite::X25519_MLKEM768_CHACHA20_POLY1305
+    }
+}
+
+impl Default for CipherSuite {
+    fn default() -> Self {
+        CipherSuite::X25519_CHACHA20_POLY1305
+    }
+}
+
+/// HandshakeExtensions are the optional capabilities a ConnectionMessage
+/// carries after its PeerId. Listeners older than an extension reject
+/// requests which carry it, so it's only sent when it's needed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HandshakeExtensions {
+    /// the compression algorithms the dialer supports, in order of
+    /// preference, or the one the listener picked of them.
+    pub compression: Vec<CompressionAlgorithm>,
+    /// the cipher suites the dialer supports, in order of preference, or the
+    /// one the listener picked of them. Connections whose handshake doesn't
+    /// name a suite use the default one.
+    pub cipher_suites: Vec<CipherSuite>,
+    /// the post-quantum half of a hybrid key exchange: the dialer's
+    /// encapsulation key if it offers a hybrid suite, or the listener's
+    /// ciphertext for it if it picked one.
+    pub key_share: Option<Vec<u8>>,
+    /// the limits after which the dialer wants the session keys replaced
+    /// with KeyUpdateMessages, or the ones the listener agreed to.
+    pub key_updates: Option<KeyUpdateLimits>,
+    /// whether the sender accepts cover messages, see
+    /// `SubstreamMessageType::Cover`. The listener only sets it if the
+    /// dialer did.
+    pub cover_traffic: bool,
+    /// proves that the sender knows the key of the private network it's in;
+    /// see `PreSharedKey`.
+    pub psk_proof: Option<[u8; PSK_PROOF_LENGTH]>,
+    /// whether the sender generates the IDs of the substreams it opens in
+    /// the namespace of its side of the connection, see
+    /// `SubstreamId::generate_for`, and refuses substreams the remote opens
+    /// in its own. The listener sets it if the dialer did.
+    pub substream_directions: bool,
+    /// whether the sender accepts OutOfBandMessages. The listener only sets
+    /// it if the dialer did.
+    pub out_of_band: bool,
+    /// whether the sender signs its ConnectionCloses, and only accepts
+    /// signed ones. The listener only sets it if the dialer did.
+    pub signed_closes: bool,
+    /// whether the sender keeps reading a substream after closing it, and
+    /// keeps writing to one the remote closed. The listener only sets it if
+    /// the dialer did.
+    pub half_close: bool,
+    /// whether the sender pads its messages, and accepts padded ones; see
+    /// `MessagePadding`. The listener only sets it if the dialer did.
+    pub padding: bool,
+}
+
+impl HandshakeExtensions {
+    fn to_bytes(&self) -> Vec<u8> {
+        let compression: Vec<u8> = self
+            .compression
+            .iter()
+            .map(|algorithm| algorithm.0)
+            .collect();
+        let cipher_suites: Vec<u8> = self.cipher_suites.iter().map(|suite| suite.0).collect();
+        let key_share = self.key_share.clone().unwrap_or_default();
+        let key_updates = self
+            .key_updates
+            .map(KeyUpdateLimits::to_bytes)
+            .unwrap_or_default();
+        let cover_traffic = if self.cover_traffic { vec![1] } else { vec![] };
+        let psk_proof = self.psk_proof.map(Vec::from).unwrap_or_default();
+        let substream_directions = if self.substream_directions {
+            vec![1]
+        } else {
+            vec![]
+        };
+        let out_of_band = if self.out_of_band { vec![1] } else { vec![] };
+        let signed_closes = if self.signed_closes { vec![1] } else { vec![] };
+        let half_close = if self.half_close { vec![1] } else { vec![] };
+        let padding = if self.padding { vec![1] } else { vec![] };
+        let mut bytes = vec![];
+        for (extension, value) in [
+            (COMPRESSION_EXTENSION, compression),
+            (CIPHER_SUITES_EXTENSION, cipher_suites),
+            (KEY_SHARE_EXTENSION, key_share),
+            (KEY_UPDATES_EXTENSION, key_updates),
+            (COVER_TRAFFIC_EXTENSION, cover_traffic),
+            (PSK_PROOF_EXTENSION, psk_proof),
+            (SUBSTREAM_DIRECTIONS_EXTENSION, substream_directions),
+            (OUT_OF_BAND_EXTENSION, out_of_band),
+            (SIGNED_CLOSES_EXTENSION, signed_closes),
+            (HALF_CLOSE_EXTENSION, half_close),
+            (PADDING_EXTENSION, padding),
+        ] {
+            if !value.is_empty() {
+                bytes.push(extension);
+                bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                bytes.extend_from_slice(&value);
+            }
+        }
+        bytes
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut extensions = HandshakeExtensions::default();
+        let mut bytes = fields(bytes);
+        while !bytes.is_empty() {
+            let extension = bytes.take_u8()?;
+            let value = bytes.take_short_length_prefixed()?;
+            match extension {
+                COMPRESSION_EXTENSION => {
+                    extensions.compression =
+                        value.iter().copied().map(CompressionAlgorithm).collect()
+                }
+                CIPHER_SUITES_EXTENSION => {
+                    extensions.cipher_suites = value.iter().copied().map(CipherSuite).collect()
+                }
+                KEY_SHARE_EXTENSION => extensions.key_share = Some(value.to_vec()),
+                KEY_UPDATES_EXTENSION => {
+                    extensions.key_updates = Some(KeyUpdateLimits::try_from_bytes(value)?)
+                }
+                COVER_TRAFFIC_EXTENSION => extensions.cover_traffic = true,
+                PSK_PROOF_EXTENSION => {
+                    extensions.psk_proof =
+                        Some(value.try_into().map_err(|_| Error::InvalidMessageBytes)?)
+                }
+                SUBSTREAM_DIRECTIONS_EXTENSION => extensions.substream_directions = true,
+                OUT_OF_BAND_EXTENSION => extensions.out_of_band = true,
+                SIGNED_CLOSES_EXTENSION => extensions.signed_closes = true,
+                HALF_CLOSE_EXTENSION => extensions.half_close = true,
+                PADDING_EXTENSION => extensions.padding = true,
+                _ => {}
+            }
+        }
+        Ok(extensions)
+    }
+}
+
+/// KeyUpdateLimits bound how long the session keys of an encrypted
+/// connection are used: they're replaced once either many messages were sent
+/// and received with them, or they're as old as `max_age_secs`. Both sides
+/// use the lower of their limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct KeyUpdateLimits {
+    pub max_messages: u64,
+    pub max_age_secs: u64,
+}
+
+impl KeyUpdateLimits {
+    pub fn new(max_messages: u64, max_age_secs: u64) -> Self {
+        KeyUpdateLimits {
+            max_messages,
+            max_age_secs,
+        }
+    }
+
+    /// returns the lower of both limits.
+    pub fn min(self, other: KeyUpdateLimits) -> KeyUpdateLimits {
+        KeyUpdateLimits {
+            max_messages: self.max_messages.min(other.max_messages),
+            max_age_secs: self.max_age_secs.min(other.max_age_secs),
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 * NONCE_BYTES_LEN);
+        bytes.extend_from_slice(&self.max_messages.to_be_bytes());
+        bytes.extend_from_slice(&self.max_age_secs.to_be_bytes());
+        bytes
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != 2 * NONCE_BYTES_LEN {
+            return Err(Error::InvalidMessageBytes);
+        }
+        Ok(KeyUpdateLimits {
+            max_messages: decode_u64(&bytes[..NONCE_BYTES_LEN])?,
+            max_age_secs: decode_u64(&bytes[NONCE_BYTES_LEN..])?,
+        })
+    }
+}
+
+/// ConnectionMessageKind is the handshake step a ConnectionMessage is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionMessageKind {
+    Request,
+    Response,
+}
+
+impl ConnectionMessageKind {
+    fn domain(&self) -> &'static [u8] {
+        match self {
+            ConnectionMessageKind::Request => CONNECTION_REQUEST_DOMAIN,
+            ConnectionMessageKind::Response => CONNECTION_RESPONSE_DOMAIN,
+        }
+    }
+}
+
+/// ConnectionMessage is exchanged to open a new connection.
+/// It's signed by the sender's libp2p key, so that the receiver can verify
+/// the sender actually controls the key corresponding to the claimed PeerId.
+/// It's built with `ConnectionMessage::new_signed` or
+/// `ConnectionMessage::builder`.
+///
+/// Peers from before signed handshakes send and expect unsigned messages,
+/// which carry only the connection ID and PeerId; see
+/// `ConnectionMessage::unsigned` and `NymTransportConfig::unsigned_handshakes`.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct ConnectionMessage {
+    pub peer_id: PeerId,
+    pub id: ConnectionId,
+    /// the key the message is signed with; None if it's unsigned.
+    pub public_key: Option<PublicKey>,
+    /// seconds since the unix epoch at which the message was signed.
+    pub timestamp: u64,
+    /// connection options requested by the dialer, or accepted by the listener.
+    pub flags: ConnectionFlags,
+    /// the sender's X25519 public key for payload encryption, if it wants
+    /// the connection to be encrypted.
+    pub ephemeral_key: Option<[u8; EPHEMERAL_KEY_LENGTH]>,
+    pub extensions: HandshakeExtensions,
+    pub signature: Vec<u8>,
+    // only required if this is a ConnectionRequest.
+    // this is the nym address of the initiator of a connection request, so the recipient could use it to reply. Lets keep that as a None for the moment.
+    // pub(crate) recipient: Option<Recipient>,
+}
+
+impl Debug for ConnectionMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // the public key identifies the peer just as well as its PeerId
+        f.debug_struct("ConnectionMessage")
+            .field("peer_id", &redact(self.peer_id))
+            .field("id", &self.id)
+            .field("timestamp", &self.timestamp)
+            .field("flags", &self.flags)
+            .field("encrypted", &self.ephemeral_key.is_some())
+            .field("extensions", &self.extensions)
+            .finish_non_exhaustive()
+    }
+}
+
+/// AddressMessage is sent by the dialer of a connection after receiving the
+/// listener's signed ConnectionResponse, if address exchange was negotiated.
+/// It's signed like a ConnectionMessage, so that the listener can check it
+/// comes from the peer the connection is with.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct AddressMessage {
+    pub id: ConnectionId,
+    /// the dialer's nym address.
+    pub recipient: Recipient,
+    pub public_key: PublicKey,
+    pub signature: Vec<u8>,
+}
+
+impl Debug for AddressMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AddressMessage")
+            .field("id", &self.id)
+            .field("recipient", &redact_always(self.recipient))
+            .finish_non_exhaustive()
+    }
+}
+
+/// MigrateMessage moves a connection to the new mixnet client of its sender,
+/// eg. after a gateway failover. The listener of a connection sends it over
+/// the dialer's SURBs, with its new address; the dialer sends it from its new
+/// client without one, and the listener replies to the sender tag it arrives
+/// with from then on. It's signed like a ConnectionMessage, so that only the
+/// peer the connection is with can move it.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct MigrateMessage {
+    pub id: ConnectionId,
+    /// the listener's new nym address; None if the dialer migrated.
+    pub recipient: Option<Recipient>,
+    pub public_key: PublicKey,
+    pub signature: Vec<u8>,
+}
+
+impl Debug for MigrateMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MigrateMessage")
+            .field("id", &self.id)
+            .field("recipient", &self.recipient.map(redact_always))
+            .finish_non_exhaustive()
+    }
+}
+
+/// ParityMessage carries one of the parity shards of a group of consecutive
+/// TransportMessages, if forward error correction was negotiated; see
+/// `ForwardErrorCorrection`. The shards are computed over the messages as
+/// they're sent, ie. after encryption, each prefixed with its length. The
+/// receiver rebuilds up to `parity_shards` lost messages of a group from the
+/// rest of it and the parity, instead of waiting for them to be retransmitted.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ParityMessage {
+    pub id: ConnectionId,
+    /// the nonce of the group's first message.
+    pub first_nonce: u64,
+    /// the number of messages in the group.
+    pub data_shards: u8,
+    /// the number of parity shards sent for the group.
+    pub parity_shards: u8,
+    /// which of the parity shards this is.
+    pub index: u8,
+    pub shard: Bytes,
+}
+
+impl ParityMessage {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.id.0);
+        buf.extend_from_slice(&self.first_nonce.to_be_bytes());
+        buf.push(self.data_shards);
+        buf.push(self.parity_shards);
+        buf.push(self.index);
+        buf.extend_from_slice(&self.shard);
+    }
+
+    fn try_from_bytes(bytes: Bytes) -> Result<Self, Error> {
+        let header_len = CONNECTION_ID_LENGTH + NONCE_BYTES_LEN + 3;
+        if bytes.len() <= header_len {
+            return Err(Error::InvalidMessageBytes);
+        }
+        let (id, rest) = bytes.split_at(CONNECTION_ID_LENGTH);
+        let (first_nonce, rest) = rest.split_at(NONCE_BYTES_LEN);
+        let (data_shards, parity_shards, index) = (rest[0], rest[1], rest[2]);
+        // the field the shards are computed in has 256 elements
+        if data_shards == 0
+            || parity_shards == 0
+            || index >= parity_shards
+            || data_shards as usize + parity_shards as usize > 256
+        {
+            return Err(Error::InvalidMessageBytes);
+        }
+        Ok(ParityMessage {
+            id: ConnectionId::from_bytes(id)?,
+            first_nonce: decode_u64(first_nonce)?,
+            data_shards,
+            parity_shards,
+            index,
+            shard: bytes.slice(header_len..),
+        })
+    }
+}
+
+/// KeyUpdateMessage is a step of replacing the session keys of an encrypted
+/// connection with keys from a new X25519 exchange, if the handshake
+/// negotiated `KeyUpdateLimits`. The dialer sends a Request with its public
+/// key, and the listener a Response with its own. The dialer then encrypts
+/// with the new keys and says from which nonce on with a Switched message,
+/// and the listener follows with its own Switched message. Each exchange
+/// replaces the keys of a new generation, the handshake's being the first.
+///
+/// The tag authenticates the message with a key derived from the previous
+/// generation's keys, so only the peer the connection is with can update them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct KeyUpdateMessage {
+    pub id: ConnectionId,
+    pub generation: u64,
+    pub kind: KeyUpdateKind,
+    pub tag: [u8; KEY_UPDATE_TAG_LENGTH],
+}
+
+/// KeyUpdateKind is the step of a key update a KeyUpdateMessage is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyUpdateKind {
+    /// the dialer's public key for the exchange.
+    Request {
+        public_key: [u8; EPHEMERAL_KEY_LENGTH],
+    },
+    /// the listener's public key for the exchange.
+    Response {
+        public_key: [u8; EPHEMERAL_KEY_LENGTH],
+    },
+    /// the sender encrypts its messages with the new keys from this nonce on.
+    Switched { from_nonce: u64 },
+}
+
+impl KeyUpdateKind {
+    fn id(&self) -> u8 {
+        match self {
+            KeyUpdateKind::Request { .. } => 0,
+            KeyUpdateKind::Response { .. } => 1,
+            KeyUpdateKind::Switched { .. } => 2,
+        }
+    }
+}
+
+impl KeyUpdateMessage {
+    /// appends everything but the tag to `buf`, ie. what the tag authenticates.
+    pub(crate) fn encode_authenticated_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.id.0);
+        buf.extend_from_slice(&self.generation.to_be_bytes());
+        buf.push(self.kind.id());
+        match &self.kind {
+            KeyUpdateKind::Request { public_key } | KeyUpdateKind::Response { public_key } => {
+                buf.extend_from_slice(public_key)
+            }
+            KeyUpdateKind::Switched { from_nonce } => {
+                buf.extend_from_slice(&from_nonce.to_be_bytes())
+            }
+        }
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        self.encode_authenticated_into(buf);
+        buf.extend_from_slice(&self.tag);
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let header_len = CONNECTION_ID_LENGTH + NONCE_BYTES_LEN + 1;
+        if bytes.len() < header_len + KEY_UPDATE_TAG_LENGTH {
+            return Err(Error::InvalidMessageBytes);
+        }
+        let (body, tag) = bytes.split_at(bytes.len() - KEY_UPDATE_TAG_LENGTH);
+        let (id, rest) = body.split_at(CONNECTION_ID_LENGTH);
+        let (generation, rest) = rest.split_at(NONCE_BYTES_LEN);
+        let (kind, value) = (rest[0], &rest[1..]);
+        let kind = match kind {
+            0 | 1 => {
+                let public_key = value.try_into().map_err(|_| Error::InvalidMessageBytes)?;
+                if kind == 0 {
+                    KeyUpdateKind::Request { public_key }
+                } else {
+                    KeyUpdateKind::Response { public_key }
+                }
+            }
+            2 if value.len() == NONCE_BYTES_LEN => KeyUpdateKind::Switched {
+                from_nonce: decode_u64(value)?,
+            },
+            _ => return Err(Error::InvalidMessageBytes),
+        };
+        Ok(KeyUpdateMessage {
+            id: ConnectionId::from_bytes(id)?,
+            generation: decode_u64(generation)?,
+            kind,
+            tag: tag.try_into().map_err(|_| Error::InvalidMessageBytes)?,
+        })
+    }
+}
+
+/// OutOfBandMessage carries application data sent with
+/// `Connection::send_out_of_band`, outside of any substream. It has no nonce,
+/// so it's delivered as soon as it arrives.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct OutOfBandMessage {
+    pub id: ConnectionId,
+    pub data: Bytes,
+}
+
+/// SealedMessage carries a control message of an encrypted connection, like
+/// an OutOfBandMessage, encrypted and authenticated with keys derived from
+/// its handshake. Each side numbers the messages it seals, and the remote
+/// drops those whose counter it saw before, so they can't be replayed.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SealedMessage {
+    pub id: ConnectionId,
+    pub counter: u64,
+    /// the encoding of the control message, encrypted, followed by the tag.
+    pub ciphertext: Bytes,
+}
+
+impl SealedMessage {
+    pub(crate) fn encode_header_into(id: &ConnectionId, counter: u64, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&id.0);
+        buf.extend_from_slice(&counter.to_be_bytes());
+    }
+
+    fn try_from_bytes(bytes: Bytes) -> Result<Self, Error> {
+        let header_len = CONNECTION_ID_LENGTH + NONCE_BYTES_LEN;
+        if bytes.len() <= header_len {
+            return Err(Error::InvalidMessageBytes);
+        }
+        Ok(SealedMessage {
+            id: ConnectionId::from_bytes(&bytes[..CONNECTION_ID_LENGTH])?,
+            counter: decode_u64(&bytes[CONNECTION_ID_LENGTH..header_len])?,
+            ciphertext: bytes.slice(header_len..),
+        })
+    }
+}
+
+/// ProbeMessage is sent by a transport to its own nym address, to check that
+/// the mixnet delivers messages to it; see `ReachabilityProbe`. Its ID is
+/// random, and doesn't belong to a connection.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ProbeMessage {
+    pub id: ConnectionId,
+}
+
+/// SessionTicketMessage carries a session ticket from the listener of a
+/// connection to its dialer, along with the options negotiated for the
+/// connection, which the dialer resumes it with. The ticket itself is opaque
+/// to the dialer.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SessionTicketMessage {
+    pub id: ConnectionId,
+    pub flags: ConnectionFlags,
+    pub ticket: Bytes,
+}
+
+/// ResumeMessage is sent by a dialer in place of a ConnectionRequest, to
+/// resume the connection a session ticket was issued for. It's sent with
+/// fresh reply SURBs, which the listener replies over from then on. It's
+/// signed with the identity the ticket was issued to, so that a stolen
+/// ticket can't be used by anyone else.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ResumeMessage {
+    pub id: ConnectionId,
+    pub ticket: Bytes,
+    pub public_key: PublicKey,
+    pub signature: Vec<u8>,
+}
+
+/// ConnectionCloseMessage tells the remote that a connection, or a dial that
+/// hasn't completed yet, was closed deliberately. On connections which
+/// negotiated `HandshakeExtensions::signed_closes` it's signed like a
+/// MigrateMessage, so that only the peer the connection is with can close it.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ConnectionCloseMessage {
+    pub id: ConnectionId,
+    pub reason: CloseReason,
+    /// the key the message is signed with; None if it's unsigned.
+    pub public_key: Option<PublicKey>,
+    pub signature: Vec<u8>,
+}
+
+impl ConnectionCloseMessage {
+    /// creates an unsigned ConnectionCloseMessage.
+    pub fn new(id: ConnectionId, reason: CloseReason) -> Self {
+        ConnectionCloseMessage {
+            id,
+            reason,
+            public_key: None,
+            signature: vec![],
+        }
+    }
+
+    /// creates a ConnectionCloseMessage signed with `keypair`, the identity
+    /// we authenticated the connection with.
+    pub fn new_signed(
+        keypair: &Keypair,
+        id: ConnectionId,
+        mut reason: CloseReason,
+    ) -> Result<Self, Error> {
+        // the signature covers the message as it's sent
+        if let Some(message) = &mut reason.message {
+            message.truncate(close_message_len(message));
+        }
+        let mut msg = ConnectionCloseMessage {
+            id,
+            reason,
+            public_key: Some(keypair.public()),
+            signature: vec![],
+        };
+        msg.signature = keypair.sign(&msg.signing_payload())?;
+        Ok(msg)
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.public_key.is_some()
+    }
+
+    /// checks that the message is signed by the key corresponding to `peer_id`,
+    /// ie. the remote of the connection it's for.
+    pub fn verify(&self, peer_id: &PeerId) -> Result<(), Error> {
+        match &self.public_key {
+            Some(public_key)
+                if PeerId::from_public_key(public_key) == *peer_id
+                    && public_key.verify(&self.signing_payload(), &self.signature) =>
+            {
+                Ok(())
+            }
+            _ => Err(Error::InvalidConnectionClose(*peer_id)),
+        }
+    }
+
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut payload = CONNECTION_CLOSE_DOMAIN.to_vec();
+        self.encode_reason_into(&mut payload);
+        payload
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        if let Some(public_key) = &self.public_key {
+            let public_key = public_key.encode_protobuf();
+            buf.extend_from_slice(&(public_key.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&public_key);
+            buf.extend_from_slice(&(self.signature.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&self.signature);
+        }
+        self.encode_reason_into(buf);
+    }
+
+    fn encode_reason_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.id.0);
+        buf.extend_from_slice(&u16::from(self.reason.code).to_be_bytes());
+        if let Some(message) = &self.reason.message {
+            buf.extend_from_slice(&message.as_bytes()[..close_message_len(message)]);
+        }
+    }
+
+    fn try_from_signed_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut bytes = fields(bytes);
+        let public_key = decode_public_key(bytes.take_short_length_prefixed()?)?;
+        let signature = decode_signature(bytes.take_short_length_prefixed()?)?;
+        let mut msg = ConnectionCloseMessage::try_from_bytes(bytes.rest())?;
+        msg.public_key = Some(public_key);
+        msg.signature = signature;
+        Ok(msg)
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + CLOSE_CODE_BYTES_LEN {
+            return Err(Error::InvalidMessageBytes);
+        }
+        let (id, rest) = bytes.split_at(CONNECTION_ID_LENGTH);
+        let (code, message) = rest.split_at(CLOSE_CODE_BYTES_LEN);
+        if message.len() > MAX_CLOSE_MESSAGE_LEN {
+            return Err(Error::InvalidMessageBytes);
+        }
+        let mut reason = CloseReason::new(u16::from_be_bytes([code[0], code[1]]).into());
+        if !message.is_empty() {
+            reason = reason.with_message(String::from_utf8_lossy(message));
+        }
+        Ok(ConnectionCloseMessage::new(
+            ConnectionId::from_bytes(id)?,
+            reason,
+        ))
+    }
+}
+
+/// returns the length a close message is truncated to on the wire: at most
+/// `MAX_CLOSE_MESSAGE_LEN` bytes, at a char boundary.
+fn close_message_len(message: &str) -> usize {
+    let mut len = message.len().min(MAX_CLOSE_MESSAGE_LEN);
+    while !message.is_char_boundary(len) {
+        len -= 1;
+    }
+    len
+}
+
+/// AckMessage acknowledges the TransportMessages received on a connection, if
+/// acks were negotiated. Acks are cumulative: every message up to `nonce` was
+/// received, and a lost ack is made up for by the next one.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct AckMessage {
+    pub id: ConnectionId,
+    pub nonce: u64,
+    /// inclusive ranges of nonces above `nonce` which were received as well,
+    /// in ascending order; at most `MAX_SACK_BLOCKS` are sent. Peers which
+    /// don't know about them ignore them.
+    pub sacks: Vec<(u64, u64)>,
+}
+
+impl AckMessage {
+    /// returns the highest nonce the ack covers.
+    pub fn highest_nonce(&self) -> u64 {
+        self.sacks.last().map_or(self.nonce, |(_, end)| *end)
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.id.0);
+        buf.extend_from_slice(&self.nonce.to_be_bytes());
+        if self.sacks.is_empty() {
+            return;
+        }
+        let sacks = &self.sacks[..self.sacks.len().min(MAX_SACK_BLOCKS)];
+        buf.push(sacks.len() as u8);
+        for (start, end) in sacks {
+            buf.extend_from_slice(&start.to_be_bytes());
+            buf.extend_from_slice(&end.to_be_bytes());
+        }
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < MIN_CONNECTION_MESSAGE_LEN {
+            return Err(Error::InvalidMessageBytes);
+        }
+        let (id, rest) = bytes.split_at(CONNECTION_ID_LENGTH);
+        let (nonce, rest) = rest.split_at(NONCE_BYTES_LEN);
+
+        let mut sacks = vec![];
+        if let Some((count, blocks)) = rest.split_first() {
+            let count = *count as usize;
+            if count > MAX_SACK_BLOCKS || blocks.len() < count * SACK_BLOCK_BYTES_LEN {
+                return Err(Error::InvalidMessageBytes);
+            }
+            for block in blocks.chunks_exact(SACK_BLOCK_BYTES_LEN).take(count) {
+                let (start, end) = block.split_at(NONCE_BYTES_LEN);
+                let (start, end) = (decode_u64(start)?, decode_u64(end)?);
+                if start > end {
+                    return Err(Error::InvalidMessageBytes);
+                }
+                sacks.push((start, end));
+            }
+        }
+
+        Ok(AckMessage {
+            id: ConnectionId::from_bytes(id)?,
+            nonce: decode_u64(nonce)?,
+            sacks,
+        })
+    }
+}
+
+/// TransportMessage is sent over a connection after establishment.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct TransportMessage {
+    /// increments by 1 for every TransportMessage sent over a connection.
+    /// required for ordering, since Nym does not guarantee ordering.
+    /// ConnectionMessages do not need nonces, as we know that they will
+    /// be the first messages sent over a connection.
+    /// the first TransportMessage sent over a connection will have nonce 1.
+    pub nonce: u64,
+    pub message: SubstreamMessage,
+    pub id: ConnectionId,
+}
+
+/// EncryptedTransportMessage is a TransportMessage whose SubstreamMessage is
+/// encrypted with the connection's session keys.
+/// The nonce and connection ID are sent in the clear, as they're needed to
+/// find the session and order the messages.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct EncryptedTransportMessage {
+    pub nonce: u64,
+    pub id: ConnectionId,
+    pub ciphertext: Bytes,
+    /// whether the IDs, including the encrypted substream ID, are in compact form.
+    pub compact: bool,
+}
+
+impl Message {
+    /// returns whether the message controls a connection rather than carrying
+    /// its data; control messages are sent ahead of queued data, so that eg.
+    /// handshakes don't time out while a bulk transfer saturates the sender.
+    /// Closes only skip the data queued after them; see `OutboundReceiver`.
+    pub(crate) fn is_control(&self) -> bool {
+        matches!(
+            self,
+            Message::ConnectionRequest(_)
+                | Message::ConnectionResponse(_)
+                | Message::ConnectionClose(_)
+                | Message::AddressMessage(_)
+                | Message::Ack(_)
+                | Message::Probe(_)
+                | Message::SessionTicket(_)
+                | Message::Resume(_)
+                | Message::Migrate(_)
+                | Message::KeyUpdate(_)
+                | Message::Sealed(_)
+        )
+    }
+
+    /// returns true for the control messages which are sealed on encrypted
+    /// connections; see `SealedMessage`.
+    pub(crate) fn is_sealable(&self) -> bool {
+        matches!(self, Message::OutOfBandMessage(_))
+    }
+
+    /// returns the ID of the connection the message belongs to.
+    pub fn connection_id(&self) -> &ConnectionId {
+        match self {
+            Message::ConnectionRequest(msg) | Message::ConnectionResponse(msg) => &msg.id,
+            Message::TransportMessage(msg) => &msg.id,
+            Message::EncryptedTransportMessage(msg) => &msg.id,
+            Message::AddressMessage(msg) => &msg.id,
+            Message::OutOfBandMessage(msg) => &msg.id,
+            Message::ConnectionClose(msg) => &msg.id,
+            Message::Ack(msg) => &msg.id,
+            Message::Probe(msg) => &msg.id,
+            Message::SessionTicket(msg) => &msg.id,
+            Message::Resume(msg) => &msg.id,
+            Message::Migrate(msg) => &msg.id,
+            Message::Parity(msg) => &msg.id,
+            Message::KeyUpdate(msg) => &msg.id,
+            Message::Sealed(msg) => &msg.id,
+        }
+    }
+
+    /// decodes a message as received from the mixnet. Payloads of data
+    /// messages point into `bytes` rather than being copied.
+    pub fn try_from_bytes(bytes: Bytes) -> Result<Self, Error> {
+        if bytes.len() < 2 {
+            return Err(Error::InvalidMessageBytes);
+        }
+        if bytes.len() > MAX_MESSAGE_LEN {
+            return Err(Error::MessageTooLarge(bytes.len()));
+        }
+
+        Ok(match bytes[0] {
+            CONNECTION_REQUEST_TYPE => {
+                Message::ConnectionRequest(ConnectionMessage::try_from_unsigned_bytes(&bytes[1..])?)
+            }
+            CONNECTION_RESPONSE_TYPE => Message::ConnectionResponse(
+                ConnectionMessage::try_from_unsigned_bytes(&bytes[1..])?,
+            ),
+            SIGNED_CONNECTION_REQUEST_TYPE => {
+                Message::ConnectionRequest(ConnectionMessage::try_from_bytes(&bytes[1..])?)
+            }
+            SIGNED_CONNECTION_RESPONSE_TYPE => {
+                Message::ConnectionResponse(ConnectionMessage::try_from_bytes(&bytes[1..])?)
+            }
+            TRANSPORT_MESSAGE_TYPE => Message::TransportMessage(TransportMessage::try_from_bytes(
+                bytes.slice(1..),
+                false,
+            )?),
+            ENCRYPTED_TRANSPORT_MESSAGE_TYPE => Message::EncryptedTransportMessage(
+                EncryptedTransportMessage::try_from_bytes(bytes.slice(1..), false)?,
+            ),
+            // compact messages are accepted regardless of what was negotiated,
+            // the flag only tells the remote it may send them.
+            COMPACT_TRANSPORT_MESSAGE_TYPE => {
+                Message::TransportMessage(TransportMessage::try_from_bytes(bytes.slice(1..), true)?)
+            }
+            COMPACT_ENCRYPTED_TRANSPORT_MESSAGE_TYPE => Message::EncryptedTransportMessage(
+                EncryptedTransportMessage::try_from_bytes(bytes.slice(1..), true)?,
+            ),
+            ADDRESS_MESSAGE_TYPE => {
+                Message::AddressMessage(AddressMessage::try_from_bytes(&bytes[1..])?)
+            }
+            CONNECTION_CLOSE_TYPE => {
+                Message::ConnectionClose(ConnectionCloseMessage::try_from_bytes(&bytes[1..])?)
+            }
+            SIGNED_CONNECTION_CLOSE_TYPE => Message::ConnectionClose(
+                ConnectionCloseMessage::try_from_signed_bytes(&bytes[1..])?,
+            ),
+            ACK_MESSAGE_TYPE => Message::Ack(AckMessage::try_from_bytes(&bytes[1..])?),
+            PROBE_MESSAGE_TYPE => {
+                if bytes.len() != 1 + CONNECTION_ID_LENGTH {
+                    return Err(Error::InvalidMessageBytes);
+                }
+                Message::Probe(ProbeMessage {
+                    id: ConnectionId::from_bytes(&bytes[1..])?,
+                })
+            }
+            SESSION_TICKET_TYPE => {
+                if bytes.len() <= 1 + CONNECTION_ID_LENGTH + FLAGS_BYTES_LEN {
+                    return Err(Error::InvalidMessageBytes);
+                }
+                Message::SessionTicket(SessionTicketMessage {
+                    id: ConnectionId::from_bytes(&bytes[1..1 + CONNECTION_ID_LENGTH])?,
+                    flags: ConnectionFlags::from_bits(bytes[1 + CONNECTION_ID_LENGTH]),
+                    ticket: bytes.slice(1 + CONNECTION_ID_LENGTH + FLAGS_BYTES_LEN..),
+                })
+            }
+            RESUME_MESSAGE_TYPE => Message::Resume(ResumeMessage::try_from_bytes(&bytes[1..])?),
+            MIGRATE_MESSAGE_TYPE => Message::Migrate(MigrateMessage::try_from_bytes(&bytes[1..])?),
+            PARITY_MESSAGE_TYPE => {
+                Message::Parity(ParityMessage::try_from_bytes(bytes.slice(1..))?)
+            }
+            KEY_UPDATE_TYPE => Message::KeyUpdate(KeyUpdateMessage::try_from_bytes(&bytes[1..])?),
+            SEALED_MESSAGE_TYPE => {
+                Message::Sealed(SealedMessage::try_from_bytes(bytes.slice(1..))?)
+            }
+            PADDED_MESSAGE_TYPE => Message::try_from_bytes(strip_padding(bytes)?)?,
+            OUT_OF_BAND_MESSAGE_TYPE => {
+                if bytes.len() < 1 + CONNECTION_ID_LENGTH {
+                    return Err(Error::InvalidMessageBytes);
+                }
+                Message::OutOfBandMessage(OutOfBandMessage {
+                    id: ConnectionId::from_bytes(&bytes[1..])?,
+                    data: bytes.slice(1 + CONNECTION_ID_LENGTH..),
+                })
+            }
+            _ => return Err(Error::InvalidMessageBytes),
+        })
+    }
+}
+
+/// ConnectionMessageBuilder builds a ConnectionMessage, see
+/// `ConnectionMessage::builder`. Its PeerId, public key and timestamp are
+/// set when it's signed.
+#[derive(Clone)]
+pub struct ConnectionMessageBuilder {
+    id: ConnectionId,
+    kind: ConnectionMessageKind,
+    flags: ConnectionFlags,
+    ephemeral_key: Option<[u8; EPHEMERAL_KEY_LENGTH]>,
+    extensions: HandshakeExtensions,
+    psk: Option<PreSharedKey>,
+}
+
+impl ConnectionMessageBuilder {
+    pub fn with_flags(mut self, flags: ConnectionFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn with_ephemeral_key(mut self, ephemeral_key: Option<[u8; EPHEMERAL_KEY_LENGTH]>) -> Self {
+        self.ephemeral_key = ephemeral_key;
+        self
+    }
+
+    pub fn with_extensions(mut self, extensions: HandshakeExtensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    /// proves that we know the key of the given private network, if any.
+    /// The proof replaces the one in the extensions.
+    pub fn with_pre_shared_key(mut self, psk: Option<&PreSharedKey>) -> Self {
+        self.psk = psk.cloned();
+        self
+    }
+
+    /// signs the message with `keypair`.
+    pub fn sign(self, keypair: &Keypair) -> Result<ConnectionMessage, Error> {
+        let public_key = keypair.public();
+        let timestamp = unix_time();
+        let mut msg = ConnectionMessage {
+            peer_id: PeerId::from_public_key(&public_key),
+            id: self.id,
+            public_key: Some(public_key),
+            timestamp,
+            flags: self.flags,
+            ephemeral_key: self.ephemeral_key,
+            extensions: self.extensions,
+            signature: vec![],
+        };
+        // the proof is signed along with the other extensions
+        if let Some(psk) = &self.psk {
+            msg.extensions.psk_proof = Some(psk.proof(&msg.proof_payload(self.kind)));
+        }
+        msg.signature = keypair.sign(&msg.signing_payload(self.kind))?;
+        Ok(msg)
+    }
+}
+
+impl Debug for ConnectionMessageBuilder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionMessageBuilder")
+            .field("id", &self.id)
+            .field("kind", &self.kind)
+            .field("flags", &self.flags)
+            .field("encrypted", &self.ephemeral_key.is_some())
+            .field("extensions", &self.extensions)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ConnectionMessage {
+    /// creates a ConnectionMessage for the given connection, signed with `keypair`.
+    pub fn new_signed(
+        keypair: &Keypair,
+        id: ConnectionId,
+        kind: ConnectionMessageKind,
+        flags: ConnectionFlags,
+        ephemeral_key: Option<[u8; EPHEMERAL_KEY_LENGTH]>,
+    ) -> Result<Self, Error> {
+        Self::builder(id, kind)
+            .with_flags(flags)
+            .with_ephemeral_key(ephemeral_key)
+            .sign(keypair)
+    }
+
+    /// returns a builder for a ConnectionMessage for the given connection,
+    /// for handshakes which carry more than `new_signed` takes.
+    pub fn builder(id: ConnectionId, kind: ConnectionMessageKind) -> ConnectionMessageBuilder {
+        ConnectionMessageBuilder {
+            id,
+            kind,
+            flags: ConnectionFlags::default(),
+            ephemeral_key: None,
+            extensions: HandshakeExtensions::default(),
+            psk: None,
+        }
+    }
+
+    /// creates an unsigned ConnectionMessage for the given connection, as
+    /// sent by peers from before signed handshakes. Only the connection ID
+    /// and PeerId are sent; the PeerId isn't proven.
+    pub fn unsigned(id: ConnectionId, peer_id: PeerId) -> Self {
+        ConnectionMessage {
+            peer_id,
+            id,
+            public_key: None,
+            timestamp: 0,
+            flags: ConnectionFlags::default(),
+            ephemeral_key: None,
+            extensions: HandshakeExtensions::default(),
+            signature: vec![],
+        }
+    }
+
+    /// returns true if the message is signed, ie. it's not an unsigned one
+    /// from a peer that predates signed handshakes.
+    pub fn is_signed(&self) -> bool {
+        self.public_key.is_some()
+    }
+
+    /// checks that the sender knows the key of the given private network.
+    /// It's cheaper than checking the signature, so it's done first.
+    pub fn verify_network(&self, kind: ConnectionMessageKind, psk: &PreSharedKey) -> bool {
+        self.extensions
+            .psk_proof
+            .is_some_and(|proof| psk.verify(&self.proof_payload(kind), &proof))
+    }
+
+    /// checks that the message is signed by the key corresponding to its PeerId.
+    pub fn verify(&self, kind: ConnectionMessageKind) -> Result<(), Error> {
+        let Some(public_key) = &self.public_key else {
+            return Err(Error::UnsignedHandshake(self.peer_id));
+        };
+        if PeerId::from_public_key(public_key) != self.peer_id {
+            return Err(Error::PeerIdMismatch(self.peer_id));
+        }
+
+        if !public_key.verify(&self.signing_payload(kind), &self.signature) {
+            return Err(Error::InvalidHandshakeSignature(self.peer_id));
+        }
+
+        Ok(())
+    }
+
+    /// returns true if the message was signed no longer than `max_age` ago,
+    /// allowing for the same amount of clock skew into the future.
+    pub fn is_fresh(&self, max_age: Duration) -> bool {
+        unix_time().abs_diff(self.timestamp) <= max_age.as_secs()
+    }
+
+    /// returns what the proof of knowing the private network's key covers:
+    /// the signed fields but the extensions, which carry the proof.
+    fn proof_payload(&self, kind: ConnectionMessageKind) -> Vec<u8> {
+        let mut payload = kind.domain().to_vec();
+        payload.extend_from_slice(&self.id.0);
+        payload.extend_from_slice(&self.peer_id.to_bytes());
+        payload.extend_from_slice(&self.timestamp.to_be_bytes());
+        payload.push(self.flags.0);
+        if let Some(ephemeral_key) = &self.ephemeral_key {
+            payload.extend_from_slice(ephemeral_key);
+        }
+        payload
+    }
+
+    fn signing_payload(&self, kind: ConnectionMessageKind) -> Vec<u8> {
+        let mut payload = kind.domain().to_vec();
+        payload.extend_from_slice(&self.id.0);
+        payload.extend_from_slice(&self.peer_id.to_bytes());
+        payload.extend_from_slice(&self.timestamp.to_be_bytes());
+        payload.push(self.flags.0);
+        if let Some(ephemeral_key) = &self.ephemeral_key {
+            payload.extend_from_slice(ephemeral_key);
+        }
+        // messages without extensions are signed as they were before them
+        payload.extend_from_slice(&self.extensions.to_bytes());
+        payload
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.id.0.to_vec();
+        let Some(public_key) = &self.public_key else {
+            bytes.append(&mut self.peer_id.to_bytes());
+            return bytes;
+        };
+        let public_key = public_key.encode_protobuf();
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.push(self.flags.0);
+        bytes.extend_from_slice(&(public_key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&public_key);
+        bytes.extend_from_slice(&(self.signature.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.signature);
+        let ephemeral_key = self.ephemeral_key.as_ref().map_or(&[][..], |key| &key[..]);
+        bytes.extend_from_slice(&(ephemeral_key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(ephemeral_key);
+        bytes.append(&mut self.peer_id.to_bytes());
+        bytes.append(&mut self.extensions.to_bytes());
+        bytes
+    }
+
+    /// decodes an unsigned message, ie. a connection ID and a PeerId.
+    fn try_from_unsigned_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + 1 {
+            return Err(Error::ConnectionMessageBytesTooShort);
+        }
+
+        let (id, peer_id) = bytes.split_at(CONNECTION_ID_LENGTH);
+        let id = ConnectionId::from_bytes(id)?;
+        let peer_id = PeerId::from_bytes(peer_id).map_err(|_| Error::InvalidPeerIdBytes)?;
+        Ok(ConnectionMessage::unsigned(id, peer_id))
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + TIMESTAMP_BYTES_LEN + FLAGS_BYTES_LEN + 1 {
+            return Err(Error::ConnectionMessageBytesTooShort);
+        }
+
+        let (id, rest) = bytes.split_at(CONNECTION_ID_LENGTH);
+        let id = ConnectionId::from_bytes(id)?;
+        let (timestamp, rest) = rest.split_at(TIMESTAMP_BYTES_LEN);
+        let timestamp = decode_u64(timestamp)?;
+        let (flags, rest) = rest.split_at(FLAGS_BYTES_LEN);
+        let flags = ConnectionFlags(flags[0]);
+
+        let mut rest = fields(rest);
+        let public_key = decode_public_key(rest.take_short_length_prefixed()?)?;
+        let signature = decode_signature(rest.take_short_length_prefixed()?)?;
+        let ephemeral_key = match rest.take_short_length_prefixed()? {
+            [] => None,
+            key => Some(key.try_into().map_err(|_| Error::InvalidEphemeralKey)?),
+        };
+
+        let mut rest = rest.rest();
+        if rest.is_empty() {
+            return Err(Error::ConnectionMessageBytesNoPeerId);
+        }
+        // the PeerId is a multihash, which knows its own length
+        let peer_id = Multihash::<64>::read(&mut rest)
+            .ok()
+            .and_then(|multihash| PeerId::from_multihash(multihash).ok())
+            .ok_or(Error::InvalidPeerIdBytes)?;
+
+        let extensions = HandshakeExtensions::try_from_bytes(rest)?;
+
+        Ok(ConnectionMessage {
+            peer_id,
+            // recipient,
+            id,
+            public_key: Some(public_key),
+            timestamp,
+            flags,
+            ephemeral_key,
+            extensions,
+            signature,
+        })
+    }
+}
+
+impl AddressMessage {
+    /// creates an AddressMessage announcing `recipient` for the given
+    /// connection, signed with `keypair`.
+    pub fn new_signed(
+        keypair: &Keypair,
+        id: ConnectionId,
+        recipient: Recipient,
+    ) -> Result<Self, Error> {
+        let mut msg = AddressMessage {
+            id,
+            recipient,
+            public_key: keypair.public(),
+            signature: vec![],
+        };
+        msg.signature = keypair.sign(&msg.signing_payload())?;
+        Ok(msg)
+    }
+
+    /// checks that the message is signed by the key corresponding to `peer_id`,
+    /// ie. the remote of the connection it's for.
+    pub fn verify(&self, peer_id: &PeerId) -> Result<(), Error> {
+        if PeerId::from_public_key(&self.public_key) != *peer_id
+            || !self
+                .public_key
+                .verify(&self.signing_payload(), &self.signature)
+        {
+            return Err(Error::InvalidAddressMessage(*peer_id));
+        }
+
+        Ok(())
+    }
+
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut payload = ADDRESS_MESSAGE_DOMAIN.to_vec();
+        payload.extend_from_slice(&self.id.0);
+        payload.extend_from_slice(&self.recipient.to_bytes());
+        payload
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let public_key = self.public_key.encode_protobuf();
+        let mut bytes = self.id.0.to_vec();
+        bytes.extend_from_slice(&self.recipient.to_bytes());
+        bytes.extend_from_slice(&(public_key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&public_key);
+        bytes.extend_from_slice(&(self.signature.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + Recipient::LEN {
+            return Err(Error::AddressMessageBytesTooShort);
+        }
+
+        let (id, rest) = bytes.split_at(CONNECTION_ID_LENGTH);
+        let id = ConnectionId::from_bytes(id)?;
+        let (recipient, rest) = rest.split_at(Recipient::LEN);
+        let recipient = Recipient::try_from_bytes(
+            recipient
+                .try_into()
+                .map_err(|_| Error::AddressMessageBytesTooShort)?,
+        )?;
+        let mut rest = fields(rest);
+        let public_key = decode_public_key(rest.take_short_length_prefixed()?)?;
+        let signature = decode_signature(rest.take_short_length_prefixed()?)?;
+        if !rest.is_empty() {
+            return Err(Error::InvalidMessageBytes);
+        }
+        Ok(AddressMessage {
+            id,
+            recipient,
+            public_key,
+            signature,
+        })
+    }
+}
+
+impl MigrateMessage {
+    /// creates a MigrateMessage for the given connection, signed with
+    /// `keypair`, the identity we authenticated the connection with.
+    pub fn new_signed(
+        keypair: &Keypair,
+        id: ConnectionId,
+        recipient: Option<Recipient>,
+    ) -> Result<Self, Error> {
+        let mut msg = MigrateMessage {
+            id,
+            recipient,
+            public_key: keypair.public(),
+            signature: vec![],
+        };
+        msg.signature = keypair.sign(&msg.signing_payload())?;
+        Ok(msg)
+    }
+
+    /// checks that the message is signed by the key corresponding to `peer_id`,
+    /// ie. the remote of the connection it's for.
+    pub fn verify(&self, peer_id: &PeerId) -> Result<(), Error> {
+        if PeerId::from_public_key(&self.public_key) != *peer_id
+            || !self
+                .public_key
+                .verify(&self.signing_payload(), &self.signature)
+        {
+            return Err(Error::InvalidMigrateMessage(*peer_id));
+        }
+
+        Ok(())
+    }
+
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut payload = MIGRATE_MESSAGE_DOMAIN.to_vec();
+        payload.extend_from_slice(&self.id.0);
+        if let Some(recipient) = self.recipient {
+            payload.extend_from_slice(&recipient.to_bytes());
+        }
+        payload
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let public_key = self.public_key.encode_protobuf();
+        let mut bytes = self.id.0.to_vec();
+        match self.recipient {
+            Some(recipient) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&recipient.to_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes.extend_from_slice(&(public_key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&public_key);
+        bytes.extend_from_slice(&(self.signature.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + 1 {
+            return Err(Error::InvalidMessageBytes);
+        }
+
+        let (id, rest) = bytes.split_at(CONNECTION_ID_LENGTH);
+        let id = ConnectionId::from_bytes(id)?;
+        let (recipient, rest) = match rest[0] {
+            0 => (None, &rest[1..]),
+            1 if rest.len() > Recipient::LEN => {
+                let (recipient, rest) = rest[1..].split_at(Recipient::LEN);
+                let recipient = Recipient::try_from_bytes(
+                    recipient
+                        .try_into()
+                        .map_err(|_| Error::InvalidMessageBytes)?,
+                )?;
+                (Some(recipient), rest)
+            }
+            _ => return Err(Error::InvalidMessageBytes),
+        };
+        let mut rest = fields(rest);
+        let public_key = decode_public_key(rest.take_short_length_prefixed()?)?;
+        let signature = decode_signature(rest.take_short_length_prefixed()?)?;
+        if !rest.is_empty() {
+            return Err(Error::InvalidMessageBytes);
+        }
+        Ok(MigrateMessage {
+            id,
+            recipient,
+            public_key,
+            signature,
+        })
     }
 }
 
-impl Debug for ConnectionId {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", hex::encode(self.0))
+impl ResumeMessage {
+    /// creates a ResumeMessage for the given ticket, signed with `keypair`,
+    /// the identity the ticket was issued to.
+    pub fn new_signed(keypair: &Keypair, id: ConnectionId, ticket: Bytes) -> Result<Self, Error> {
+        let mut msg = ResumeMessage {
+            id,
+            ticket,
+            public_key: keypair.public(),
+            signature: vec![],
+        };
+        msg.signature = keypair.sign(&msg.signing_payload())?;
+        Ok(msg)
     }
-}
 
-/// SubstreamId is a unique, randomly-generated per-substream ID that's used to
-/// identify which substream a message belongs to.
-#[derive(Clone, Default, Eq, Hash, PartialEq)]
-pub struct SubstreamId(pub(crate) [u8; 32]);
+    /// checks that the message is signed by the key corresponding to `peer_id`,
+    /// ie. the dialer the ticket was issued to.
+    pub fn verify(&self, peer_id: &PeerId) -> Result<(), Error> {
+        if PeerId::from_public_key(&self.public_key) != *peer_id
+            || !self
+                .public_key
+                .verify(&self.signing_payload(), &self.signature)
+        {
+            return Err(Error::InvalidSessionTicket);
+        }
 
-impl SubstreamId {
-    pub(crate) fn generate() -> Self {
-        let mut bytes = [0u8; 32];
-        OsRng.fill_bytes(&mut bytes);
-        SubstreamId(bytes)
+        Ok(())
+    }
+
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut payload = RESUME_MESSAGE_DOMAIN.to_vec();
+        payload.extend_from_slice(&self.id.0);
+        payload.extend_from_slice(&self.ticket);
+        payload
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let public_key = self.public_key.encode_protobuf();
+        let mut bytes = self.id.0.to_vec();
+        bytes.extend_from_slice(&(self.ticket.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.ticket);
+        bytes.extend_from_slice(&(public_key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&public_key);
+        bytes.extend_from_slice(&(self.signature.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.signature);
+        bytes
     }
 
-    fn from_bytes(bytes: &[u8]) -> Self {
-        let mut id = [0u8; 32];
-        id[..].copy_from_slice(&bytes[0..SUBSTREAM_ID_LENGTH]);
-        SubstreamId(id)
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH {
+            return Err(Error::InvalidMessageBytes);
+        }
+
+        let (id, rest) = bytes.split_at(CONNECTION_ID_LENGTH);
+        let id = ConnectionId::from_bytes(id)?;
+        let mut rest = fields(rest);
+        let ticket = rest.take_short_length_prefixed()?;
+        if ticket.is_empty() {
+            return Err(Error::InvalidMessageBytes);
+        }
+        let ticket = Bytes::copy_from_slice(ticket);
+        let public_key = decode_public_key(rest.take_short_length_prefixed()?)?;
+        let signature = decode_signature(rest.take_short_length_prefixed()?)?;
+        if !rest.is_empty() {
+            return Err(Error::InvalidMessageBytes);
+        }
+        Ok(ResumeMessage {
+            id,
+            ticket,
+            public_key,
+            signature,
+        })
     }
 }
 
-impl Debug for SubstreamId {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", hex::encode(self.0))
+fn decode_public_key(bytes: &[u8]) -> Result<PublicKey, Error> {
+    if bytes.len() > MAX_PUBLIC_KEY_LEN {
+        return Err(Error::InvalidPublicKeyBytes);
     }
+    PublicKey::try_decode_protobuf(bytes).map_err(|_| Error::InvalidPublicKeyBytes)
 }
 
-#[derive(Debug)]
-#[allow(clippy::enum_variant_names)]
-pub(crate) enum Message {
-    ConnectionRequest(ConnectionMessage),
-    ConnectionResponse(ConnectionMessage),
-    TransportMessage(TransportMessage),
+fn decode_signature(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    if bytes.len() > MAX_SIGNATURE_LEN {
+        return Err(Error::InvalidMessageBytes);
+    }
+    Ok(bytes.to_vec())
 }
 
-/// ConnectionMessage is exchanged to open a new connection.
-#[derive(Debug)]
-pub(crate) struct ConnectionMessage {
-    pub(crate) peer_id: PeerId,
-    pub(crate) id: ConnectionId,
-    // only required if this is a ConnectionRequest.
-    // this is the nym address of the initiator of a connection request, so the recipient could use it to reply. Lets keep that as a None for the moment.
-    // pub(crate) recipient: Option<Recipient>,
+/// decodes a big-endian u64 from exactly eight bytes.
+fn decode_u64(bytes: &[u8]) -> Result<u64, Error> {
+    Ok(u64::from_be_bytes(
+        bytes.try_into().map_err(|_| Error::InvalidMessageBytes)?,
+    ))
 }
 
-/// TransportMessage is sent over a connection after establishment.
-#[derive(Debug, Clone)]
-pub(crate) struct TransportMessage {
-    /// increments by 1 for every TransportMessage sent over a connection.
-    /// required for ordering, since Nym does not guarantee ordering.
-    /// ConnectionMessages do not need nonces, as we know that they will
-    /// be the first messages sent over a connection.
-    /// the first TransportMessage sent over a connection will have nonce 1.
-    pub(crate) nonce: u64,
-    pub(crate) message: SubstreamMessage,
-    pub(crate) id: ConnectionId,
+/// returns a decoder for the u16 length-prefixed fields of a message.
+fn fields(bytes: &[u8]) -> Decoder<'_> {
+    Decoder::new(bytes, || Error::ConnectionMessageBytesTooShort)
 }
 
-impl Message {
-    fn try_from_bytes(bytes: Vec<u8>) -> Result<Self, Error> {
-        if bytes.len() < 2 {
-            return Err(Error::InvalidMessageBytes);
+impl TransportMessage {
+    pub fn new(id: ConnectionId, nonce: u64, message: SubstreamMessage) -> Self {
+        TransportMessage { nonce, message, id }
+    }
+
+    /// returns true if the connection and substream IDs can both be sent in
+    /// compact form.
+    pub(crate) fn is_compact(&self) -> bool {
+        self.id.is_compact() && self.message.substream_id.is_compact()
+    }
+
+    /// appends the nonce and connection ID, which are shared with the
+    /// encrypted encoding, to `buf`.
+    pub(crate) fn encode_header_into(&self, buf: &mut Vec<u8>, compact: bool) {
+        buf.extend_from_slice(&self.nonce.to_be_bytes());
+        if compact {
+            buf.extend_from_slice(&self.id.0[..COMPACT_ID_LENGTH]);
+        } else {
+            buf.extend_from_slice(&self.id.0);
         }
+    }
 
-        Ok(match bytes[0] {
-            0 => Message::ConnectionRequest(ConnectionMessage::try_from_bytes(&bytes[1..])?),
-            1 => Message::ConnectionResponse(ConnectionMessage::try_from_bytes(&bytes[1..])?),
-            2 => Message::TransportMessage(TransportMessage::try_from_bytes(&bytes[1..])?),
-            _ => return Err(Error::InvalidMessageBytes),
-        })
+    pub(crate) fn encode_into(&self, buf: &mut Vec<u8>, compact: bool) {
+        self.encode_header_into(buf, compact);
+        self.message.encode_into(buf, compact);
     }
-}
 
-impl ConnectionMessage {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = self.id.0.to_vec();
-        bytes.append(&mut self.peer_id.to_bytes());
-        bytes
+    pub(crate) fn try_from_bytes(bytes: Bytes, compact: bool) -> Result<Self, Error> {
+        let header_len = header_len(compact);
+        if bytes.len() < header_len + 1 {
+            return Err(Error::TransportMessageBytesTooShort);
+        }
+
+        let nonce = decode_u64(&bytes[0..NONCE_BYTES_LEN]).map_err(|_| Error::InvalidNonce)?;
+        let id = decode_connection_id(&bytes[NONCE_BYTES_LEN..header_len], compact)?;
+        let message = SubstreamMessage::try_from_bytes(bytes.slice(header_len..), compact)?;
+        Ok(TransportMessage { nonce, message, id })
     }
+}
 
-    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        if bytes.len() < CONNECTION_ID_LENGTH + 1 {
-            return Err(Error::ConnectionMessageBytesTooShort);
+impl EncryptedTransportMessage {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.nonce.to_be_bytes());
+        if self.compact {
+            buf.extend_from_slice(&self.id.0[..COMPACT_ID_LENGTH]);
+        } else {
+            buf.extend_from_slice(&self.id.0);
         }
+        buf.extend_from_slice(&self.ciphertext);
+    }
 
-        let id = ConnectionId::from_bytes(&bytes[0..CONNECTION_ID_LENGTH]);
+    pub(crate) fn try_from_bytes(bytes: Bytes, compact: bool) -> Result<Self, Error> {
+        let header_len = header_len(compact);
+        if bytes.len() < header_len + 1 {
+            return Err(Error::TransportMessageBytesTooShort);
+        }
 
-        let peer_id = PeerId::from_bytes(&bytes[CONNECTION_ID_LENGTH..])
-            .map_err(|_| Error::InvalidPeerIdBytes)?;
-        Ok(ConnectionMessage {
-            peer_id,
-            // recipient,
+        let nonce = decode_u64(&bytes[0..NONCE_BYTES_LEN]).map_err(|_| Error::InvalidNonce)?;
+        let id = decode_connection_id(&bytes[NONCE_BYTES_LEN..header_len], compact)?;
+        let ciphertext = bytes.slice(header_len..);
+        Ok(EncryptedTransportMessage {
+            nonce,
             id,
+            ciphertext,
+            compact,
         })
     }
 }
 
-impl TransportMessage {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = self.nonce.to_be_bytes().to_vec();
-        bytes.extend_from_slice(self.id.0.as_ref());
-        bytes.extend_from_slice(&self.message.to_bytes());
-        bytes
+/// returns the length of the nonce and connection ID preceding the payload
+/// of a (possibly encrypted) TransportMessage.
+fn header_len(compact: bool) -> usize {
+    if compact {
+        MIN_COMPACT_CONNECTION_MESSAGE_LEN
+    } else {
+        MIN_CONNECTION_MESSAGE_LEN
     }
+}
 
-    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        if bytes.len() < MIN_CONNECTION_MESSAGE_LEN + 1 {
-            return Err(Error::TransportMessageBytesTooShort);
-        }
-
-        let nonce = u64::from_be_bytes(
-            bytes[0..NONCE_BYTES_LEN]
-                .to_vec()
-                .try_into()
-                .map_err(|_| Error::InvalidNonce)?,
-        );
-        let id = ConnectionId::from_bytes(&bytes[NONCE_BYTES_LEN..MIN_CONNECTION_MESSAGE_LEN]);
-        let message = SubstreamMessage::try_from_bytes(&bytes[MIN_CONNECTION_MESSAGE_LEN..])?;
-        Ok(TransportMessage { nonce, message, id })
+fn decode_connection_id(bytes: &[u8], compact: bool) -> Result<ConnectionId, Error> {
+    if compact {
+        ConnectionId::from_compact_bytes(bytes)
+    } else {
+        ConnectionId::from_bytes(bytes)
     }
 }
 
@@ -178,12 +1976,20 @@ impl std::cmp::PartialEq for TransportMessage {
     }
 }
 
+/// SubstreamMessageType is what a SubstreamMessage does to its substream.
 #[derive(Debug, Clone)]
-pub(crate) enum SubstreamMessageType {
+#[non_exhaustive]
+pub enum SubstreamMessageType {
     OpenRequest,
     OpenResponse,
     Close,
-    Data(Vec<u8>),
+    /// the payload is reference-counted, so handing it from the mixnet
+    /// through the transport and connection to the substream doesn't copy it.
+    Data(Bytes),
+    /// a dummy message, sent so that idle connections look like busy ones.
+    /// The payload is padding; the connection drops it without handing it to
+    /// a substream.
+    Cover(Bytes),
 }
 
 impl SubstreamMessageType {
@@ -193,57 +1999,81 @@ impl SubstreamMessageType {
             SubstreamMessageType::OpenResponse => 1,
             SubstreamMessageType::Close => 2,
             SubstreamMessageType::Data(_) => 3,
+            SubstreamMessageType::Cover(_) => 4,
         }
     }
 }
 
 /// SubstreamMessage is a message sent over a substream.
 #[derive(Debug, Clone)]
-pub(crate) struct SubstreamMessage {
-    pub(crate) substream_id: SubstreamId,
-    pub(crate) message_type: SubstreamMessageType,
+#[non_exhaustive]
+pub struct SubstreamMessage {
+    pub substream_id: SubstreamId,
+    pub message_type: SubstreamMessageType,
 }
 
 impl SubstreamMessage {
-    pub(crate) fn new_with_data(substream_id: SubstreamId, message: Vec<u8>) -> Self {
+    pub fn new(substream_id: SubstreamId, message_type: SubstreamMessageType) -> Self {
+        SubstreamMessage {
+            substream_id,
+            message_type,
+        }
+    }
+
+    pub fn new_with_data(substream_id: SubstreamId, message: impl Into<Bytes>) -> Self {
         SubstreamMessage {
             substream_id,
-            message_type: SubstreamMessageType::Data(message),
+            message_type: SubstreamMessageType::Data(message.into()),
         }
     }
 
-    pub(crate) fn new_close(substream_id: SubstreamId) -> Self {
+    pub fn new_close(substream_id: SubstreamId) -> Self {
         SubstreamMessage {
             substream_id,
             message_type: SubstreamMessageType::Close,
         }
     }
 
-    pub(crate) fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = self.substream_id.0.clone().to_vec();
-        bytes.push(self.message_type.to_u8());
-        if let SubstreamMessageType::Data(message) = &self.message_type {
-            bytes.extend_from_slice(message);
+    pub(crate) fn encode_into(&self, buf: &mut Vec<u8>, compact: bool) {
+        if compact {
+            buf.extend_from_slice(&self.substream_id.0[..COMPACT_ID_LENGTH]);
+        } else {
+            buf.extend_from_slice(&self.substream_id.0);
+        }
+        buf.push(self.message_type.to_u8());
+        if let SubstreamMessageType::Data(message) | SubstreamMessageType::Cover(message) =
+            &self.message_type
+        {
+            buf.extend_from_slice(message);
         }
-        bytes
     }
 
-    pub(crate) fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        if bytes.len() < SUBSTREAM_ID_LENGTH + 1 {
+    pub(crate) fn try_from_bytes(bytes: Bytes, compact: bool) -> Result<Self, Error> {
+        let id_len = if compact {
+            COMPACT_ID_LENGTH
+        } else {
+            SUBSTREAM_ID_LENGTH
+        };
+        if bytes.len() < id_len + 1 {
             return Err(Error::InvalidSubstreamMessageBytes);
         }
 
-        let substream_id = SubstreamId::from_bytes(&bytes[0..SUBSTREAM_ID_LENGTH]);
-        let message_type = match bytes[SUBSTREAM_ID_LENGTH] {
+        let substream_id = if compact {
+            SubstreamId::from_compact_bytes(&bytes[0..id_len])?
+        } else {
+            SubstreamId::from_bytes(&bytes[0..id_len])?
+        };
+        let message_type = match bytes[id_len] {
             0 => SubstreamMessageType::OpenRequest,
             1 => SubstreamMessageType::OpenResponse,
             2 => SubstreamMessageType::Close,
             3 => {
-                if bytes.len() < SUBSTREAM_ID_LENGTH + 2 {
+                if bytes.len() < id_len + 2 {
                     return Err(Error::InvalidSubstreamMessageBytes);
                 }
-                SubstreamMessageType::Data(bytes[SUBSTREAM_ID_LENGTH + 1..].to_vec())
+                SubstreamMessageType::Data(bytes.slice(id_len + 1..))
             }
+            4 => SubstreamMessageType::Cover(bytes.slice(id_len + 1..)),
             _ => return Err(Error::InvalidSubstreamMessageType),
         };
 
@@ -255,45 +2085,865 @@ impl SubstreamMessage {
 }
 
 impl Message {
-    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+    /// returns the message's wire encoding, as sent through the mixnet.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.encode_into(&mut bytes);
+        bytes
+    }
+
+    /// appends the encoded message to `buf`, so that callers sending many
+    /// messages can reuse the same buffer.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
         match self {
             Message::ConnectionRequest(msg) => {
-                let mut bytes = 0_u8.to_be_bytes().to_vec();
-                bytes.append(&mut msg.to_bytes());
-                bytes
+                buf.push(match msg.is_signed() {
+                    true => SIGNED_CONNECTION_REQUEST_TYPE,
+                    false => CONNECTION_REQUEST_TYPE,
+                });
+                buf.extend_from_slice(&msg.to_bytes());
             }
             Message::ConnectionResponse(msg) => {
-                let mut bytes = 1_u8.to_be_bytes().to_vec();
-                bytes.append(&mut msg.to_bytes());
-                bytes
+                buf.push(match msg.is_signed() {
+                    true => SIGNED_CONNECTION_RESPONSE_TYPE,
+                    false => CONNECTION_RESPONSE_TYPE,
+                });
+                buf.extend_from_slice(&msg.to_bytes());
             }
             Message::TransportMessage(msg) => {
-                let mut bytes = 2_u8.to_be_bytes().to_vec();
-                bytes.append(&mut msg.to_bytes());
-                bytes
+                buf.push(TRANSPORT_MESSAGE_TYPE);
+                msg.encode_into(buf, false);
+            }
+            Message::EncryptedTransportMessage(msg) => {
+                buf.push(if msg.compact {
+                    COMPACT_ENCRYPTED_TRANSPORT_MESSAGE_TYPE
+                } else {
+                    ENCRYPTED_TRANSPORT_MESSAGE_TYPE
+                });
+                msg.encode_into(buf);
+            }
+            Message::AddressMessage(msg) => {
+                buf.push(ADDRESS_MESSAGE_TYPE);
+                buf.extend_from_slice(&msg.to_bytes());
+            }
+            Message::OutOfBandMessage(msg) => {
+                buf.push(OUT_OF_BAND_MESSAGE_TYPE);
+                buf.extend_from_slice(&msg.id.0);
+                buf.extend_from_slice(&msg.data);
+            }
+            Message::ConnectionClose(msg) => {
+                buf.push(match msg.is_signed() {
+                    true => SIGNED_CONNECTION_CLOSE_TYPE,
+                    false => CONNECTION_CLOSE_TYPE,
+                });
+                msg.encode_into(buf);
+            }
+            Message::Ack(msg) => {
+                buf.push(ACK_MESSAGE_TYPE);
+                msg.encode_into(buf);
+            }
+            Message::Probe(msg) => {
+                buf.push(PROBE_MESSAGE_TYPE);
+                buf.extend_from_slice(&msg.id.0);
+            }
+            Message::SessionTicket(msg) => {
+                buf.push(SESSION_TICKET_TYPE);
+                buf.extend_from_slice(&msg.id.0);
+                buf.push(msg.flags.bits());
+                buf.extend_from_slice(&msg.ticket);
+            }
+            Message::Resume(msg) => {
+                buf.push(RESUME_MESSAGE_TYPE);
+                buf.extend_from_slice(&msg.to_bytes());
+            }
+            Message::Migrate(msg) => {
+                buf.push(MIGRATE_MESSAGE_TYPE);
+                buf.extend_from_slice(&msg.to_bytes());
+            }
+            Message::Parity(msg) => {
+                buf.push(PARITY_MESSAGE_TYPE);
+                msg.encode_into(buf);
+            }
+            Message::KeyUpdate(msg) => {
+                buf.push(KEY_UPDATE_TYPE);
+                msg.encode_into(buf);
+            }
+            Message::Sealed(msg) => {
+                buf.push(SEALED_MESSAGE_TYPE);
+                SealedMessage::encode_header_into(&msg.id, msg.counter, buf);
+                buf.extend_from_slice(&msg.ciphertext);
             }
         }
     }
 }
 
-/// InboundMessage represents an inbound mixnet message.
-pub(crate) struct InboundMessage(pub(crate) Message, pub(crate) Option<AnonymousSenderTag>);
+/// messages are serialized as their hex-encoded wire encoding, so that
+/// they can be dumped and decoded again with the same checks as when they
+/// arrive from the mixnet.
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut bytes = vec![];
+        self.encode_into(&mut bytes);
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+}
+
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes =
+            hex::decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)?;
+        Message::try_from_bytes(bytes.into()).map_err(serde::de::Error::custom)
+    }
+}
+
+/// InboundMessage represents an inbound mixnet message, along with the
+/// address alias it arrived at, if it wasn't sent to our own address.
+pub(crate) struct InboundMessage(
+    pub(crate) Message,
+    pub(crate) Option<AnonymousSenderTag>,
+    pub(crate) Option<AliasId>,
+);
 
 /// OutboundMessage represents an outbound mixnet message.
-#[derive(Debug)]
 pub(crate) struct OutboundMessage {
     pub(crate) message: Message,
     pub(crate) recipient: Option<Recipient>,
     pub(crate) sender_tag: Option<AnonymousSenderTag>,
+    /// when the message was queued for sending; used to expire messages
+    /// which waited too long to be sent.
+    pub(crate) queued_at: Instant,
+    /// if set, the message is encrypted with the session right before it's sent.
+    pub(crate) session: Option<Arc<Session>>,
+    /// for data written to a substream, the space it takes up in the substream's
+    /// write window; released when the message is dropped, ie. once it's been
+    /// handed to the mixnet client.
+    pub(crate) write_credit: Option<WriteCredit>,
+    /// if set, the remote agreed to receive IDs in compact form.
+    pub(crate) compact_ids: bool,
+    /// for replies on a connection made to an address alias, the alias: only
+    /// its client holds the SURBs for `sender_tag`.
+    pub(crate) alias: Option<AliasId>,
+}
+
+impl Debug for OutboundMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutboundMessage")
+            .field("message", &self.message)
+            .field("recipient", &self.recipient.map(redact_always))
+            .field("sender_tag", &self.sender_tag.map(redact_always))
+            .field("queued_at", &self.queued_at)
+            .field("encrypted", &self.session.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl OutboundMessage {
+    #[cfg(test)]
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![];
+        self.encode_into(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// appends the encoded message to `buf`, encrypting it first if it belongs
+    /// to an encrypted connection, or sealing it if it's a control message
+    /// of one.
+    pub(crate) fn encode_into(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        match (&self.session, &self.message) {
+            (Some(session), Message::TransportMessage(msg)) => {
+                let compact = self.compact_ids && msg.is_compact();
+                buf.push(if compact {
+                    COMPACT_ENCRYPTED_TRANSPORT_MESSAGE_TYPE
+                } else {
+                    ENCRYPTED_TRANSPORT_MESSAGE_TYPE
+                });
+                session.seal_into(msg, buf, compact)
+            }
+            (Some(session), msg) if msg.is_sealable() => {
+                buf.push(SEALED_MESSAGE_TYPE);
+                session.seal_control_into(msg, buf)
+            }
+            (None, Message::TransportMessage(msg)) if self.compact_ids && msg.is_compact() => {
+                buf.push(COMPACT_TRANSPORT_MESSAGE_TYPE);
+                msg.encode_into(buf, true);
+                Ok(())
+            }
+            _ => {
+                self.message.encode_into(buf);
+                Ok(())
+            }
+        }
+    }
 }
 
 pub(crate) fn parse_message_data(
-    data: &[u8],
+    data: Bytes,
     sender_tag: Option<AnonymousSenderTag>,
 ) -> Result<InboundMessage, Error> {
     if data.len() < 2 {
         return Err(Error::InvalidMessageBytes);
     }
-    let msg = Message::try_from_bytes(data.to_vec())?;
-    Ok(InboundMessage(msg, sender_tag))
+    let msg = Message::try_from_bytes(data)?;
+    Ok(InboundMessage(msg, sender_tag, None))
+}
+
+/// Entry points for the fuzz targets in `fuzz/`, which feed the wire decoder
+/// arbitrary mixnet payloads. Only available with the `fuzzing` feature.
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing {
+    use super::*;
+
+    /// decodes a message as received from the mixnet. Valid messages must
+    /// survive being encoded and decoded again unchanged.
+    pub fn decode_message(data: &[u8]) {
+        let Ok(msg) = Message::try_from_bytes(Bytes::copy_from_slice(data)) else {
+            return;
+        };
+        let mut encoded = vec![];
+        msg.encode_into(&mut encoded);
+        let decoded = Message::try_from_bytes(encoded.clone().into())
+            .expect("an encoded message should decode");
+        let mut reencoded = vec![];
+        decoded.encode_into(&mut reencoded);
+        assert_eq!(encoded, reencoded);
+    }
+
+    /// decodes the body of a (compact) TransportMessage.
+    pub fn decode_transport_message(data: &[u8], compact: bool) {
+        let Ok(msg) = TransportMessage::try_from_bytes(Bytes::copy_from_slice(data), compact)
+        else {
+            return;
+        };
+        let mut encoded = vec![];
+        msg.encode_into(&mut encoded, compact && msg.is_compact());
+        TransportMessage::try_from_bytes(encoded.into(), compact && msg.is_compact())
+            .expect("an encoded TransportMessage should decode");
+    }
+
+    /// decodes a (compact) SubstreamMessage, eg. a decrypted one.
+    pub fn decode_substream_message(data: &[u8], compact: bool) {
+        let Ok(msg) = SubstreamMessage::try_from_bytes(Bytes::copy_from_slice(data), compact)
+        else {
+            return;
+        };
+        let mut encoded = vec![];
+        msg.encode_into(&mut encoded, compact);
+        SubstreamMessage::try_from_bytes(encoded.into(), compact)
+            .expect("an encoded SubstreamMessage should decode");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::connection::CloseCode;
+    use super::super::test_utils::random_address;
+    use super::*;
+
+    #[test]
+    fn test_connection_message_signature() {
+        let keypair = Keypair::generate_ed25519();
+        let msg = ConnectionMessage::new_signed(
+            &keypair,
+            ConnectionId::generate(),
+            ConnectionMessageKind::Request,
+            ConnectionFlags::UNORDERED,
+            Some([7u8; EPHEMERAL_KEY_LENGTH]),
+        )
+        .unwrap();
+
+        // the signature survives a round trip through the wire format
+        let bytes = Message::ConnectionRequest(msg).to_bytes();
+        let msg = match Message::try_from_bytes(bytes.into()).unwrap() {
+            Message::ConnectionRequest(msg) => msg,
+            _ => panic!("expected Message::ConnectionRequest"),
+        };
+        assert_eq!(msg.peer_id, PeerId::from_public_key(&keypair.public()));
+        assert_eq!(msg.ephemeral_key, Some([7u8; EPHEMERAL_KEY_LENGTH]));
+        assert_eq!(msg.flags, ConnectionFlags::UNORDERED);
+        msg.verify(ConnectionMessageKind::Request).unwrap();
+        assert!(msg.is_fresh(Duration::from_secs(60)));
+
+        // a request can't be passed off as a response
+        assert!(matches!(
+            msg.verify(ConnectionMessageKind::Response),
+            Err(Error::InvalidHandshakeSignature(_))
+        ));
+
+        // claiming someone else's PeerId is rejected
+        let mut spoofed = msg.clone();
+        spoofed.peer_id = PeerId::random();
+        assert!(matches!(
+            spoofed.verify(ConnectionMessageKind::Request),
+            Err(Error::PeerIdMismatch(_))
+        ));
+
+        // as is a message whose contents were changed after signing
+        let mut tampered = msg.clone();
+        tampered.timestamp -= 1;
+        assert!(matches!(
+            tampered.verify(ConnectionMessageKind::Request),
+            Err(Error::InvalidHandshakeSignature(_))
+        ));
+
+        // including the encryption key, so it can't be swapped by a relay
+        let mut tampered = msg.clone();
+        tampered.ephemeral_key = Some([8u8; EPHEMERAL_KEY_LENGTH]);
+        assert!(matches!(
+            tampered.verify(ConnectionMessageKind::Request),
+            Err(Error::InvalidHandshakeSignature(_))
+        ));
+
+        // and the connection flags
+        let mut tampered = msg;
+        tampered.flags = ConnectionFlags::default();
+        assert!(matches!(
+            tampered.verify(ConnectionMessageKind::Request),
+            Err(Error::InvalidHandshakeSignature(_))
+        ));
+    }
+
+    #[test]
+    fn test_unsigned_connection_message() {
+        let id = ConnectionId::generate();
+        let peer_id = PeerId::random();
+
+        // unsigned messages are encoded like before signed handshakes
+        let bytes =
+            Message::ConnectionRequest(ConnectionMessage::unsigned(id.clone(), peer_id)).to_bytes();
+        let mut expected = vec![CONNECTION_REQUEST_TYPE];
+        expected.extend_from_slice(&id.0);
+        expected.extend_from_slice(&peer_id.to_bytes());
+        assert_eq!(bytes, expected);
+
+        let msg = match Message::try_from_bytes(bytes.into()).unwrap() {
+            Message::ConnectionRequest(msg) => msg,
+            _ => panic!("expected Message::ConnectionRequest"),
+        };
+        assert!(!msg.is_signed());
+        assert_eq!(msg.id, id);
+        assert_eq!(msg.peer_id, peer_id);
+        assert!(matches!(
+            msg.verify(ConnectionMessageKind::Request),
+            Err(Error::UnsignedHandshake(_))
+        ));
+
+        // signed ones have types of their own
+        let msg = ConnectionMessage::new_signed(
+            &Keypair::generate_ed25519(),
+            id,
+            ConnectionMessageKind::Response,
+            ConnectionFlags::default(),
+            None,
+        )
+        .unwrap();
+        let bytes = Message::ConnectionResponse(msg).to_bytes();
+        assert_eq!(bytes[0], SIGNED_CONNECTION_RESPONSE_TYPE);
+        match Message::try_from_bytes(bytes.into()).unwrap() {
+            Message::ConnectionResponse(msg) => assert!(msg.is_signed()),
+            _ => panic!("expected Message::ConnectionResponse"),
+        }
+    }
+
+    #[test]
+    fn test_connection_message_extensions() {
+        let keypair = Keypair::generate_ed25519();
+        let compression = vec![
+            CompressionAlgorithm::ZSTD,
+            CompressionAlgorithm::from_id(200),
+            CompressionAlgorithm::LZ4,
+        ];
+        let extensions = HandshakeExtensions {
+            compression,
+            cipher_suites: vec![CipherSuite::X25519_MLKEM768_CHACHA20_POLY1305],
+            key_share: Some(vec![7; 1184]),
+            key_updates: Some(KeyUpdateLimits {
+                max_messages: 1000,
+                max_age_secs: 600,
+            }),
+            cover_traffic: true,
+            psk_proof: Some([3; PSK_PROOF_LENGTH]),
+            substream_directions: true,
+            out_of_band: true,
+            signed_closes: true,
+            half_close: true,
+            padding: true,
+        };
+        let msg =
+            ConnectionMessage::builder(ConnectionId::generate(), ConnectionMessageKind::Request)
+                .with_extensions(extensions.clone())
+                .sign(&keypair)
+                .unwrap();
+
+        // unknown algorithms and extensions are kept and skipped respectively,
+        // and the signature still checks out
+        let mut bytes = Message::ConnectionRequest(msg).to_bytes();
+        bytes.extend_from_slice(&[99, 0, 2, 1, 2]);
+        let msg = match Message::try_from_bytes(bytes.into()).unwrap() {
+            Message::ConnectionRequest(msg) => msg,
+            _ => panic!("expected Message::ConnectionRequest"),
+        };
+        assert_eq!(msg.peer_id, PeerId::from_public_key(&keypair.public()));
+        assert_eq!(msg.extensions, extensions);
+        msg.verify(ConnectionMessageKind::Request).unwrap();
+
+        // the offers can't be changed by a relay
+        let mut tampered = msg.clone();
+        tampered.extensions.compression = vec![CompressionAlgorithm::LZ4];
+        assert!(matches!(
+            tampered.verify(ConnectionMessageKind::Request),
+            Err(Error::InvalidHandshakeSignature(_))
+        ));
+        let mut tampered = msg.clone();
+        tampered.extensions.cipher_suites = vec![CipherSuite::X25519_CHACHA20_POLY1305];
+        assert!(matches!(
+            tampered.verify(ConnectionMessageKind::Request),
+            Err(Error::InvalidHandshakeSignature(_))
+        ));
+        let mut tampered = msg;
+        tampered.extensions.key_share = Some(vec![8; 1184]);
+        assert!(matches!(
+            tampered.verify(ConnectionMessageKind::Request),
+            Err(Error::InvalidHandshakeSignature(_))
+        ));
+
+        // and a truncated extension is rejected
+        let mut bytes = Message::ConnectionRequest(tampered).to_bytes();
+        bytes.pop();
+        assert!(Message::try_from_bytes(bytes.into()).is_err());
+    }
+
+    #[test]
+    fn test_address_message_signature() {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from_public_key(&keypair.public());
+        let recipient = Recipient::try_from_base58_string("D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN").unwrap();
+        let msg =
+            AddressMessage::new_signed(&keypair, ConnectionId::generate(), recipient).unwrap();
+
+        let bytes = Message::AddressMessage(msg).to_bytes();
+        let msg = match Message::try_from_bytes(bytes.into()).unwrap() {
+            Message::AddressMessage(msg) => msg,
+            _ => panic!("expected Message::AddressMessage"),
+        };
+        assert_eq!(msg.recipient, recipient);
+        msg.verify(&peer_id).unwrap();
+
+        // the address must come from the connection's remote
+        assert!(matches!(
+            msg.verify(&PeerId::random()),
+            Err(Error::InvalidAddressMessage(_))
+        ));
+
+        // and is bound to the connection it was sent for
+        let mut tampered = msg;
+        tampered.id = ConnectionId::generate();
+        assert!(matches!(
+            tampered.verify(&peer_id),
+            Err(Error::InvalidAddressMessage(_))
+        ));
+    }
+
+    #[test]
+    fn test_migrate_message_signature() {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from_public_key(&keypair.public());
+        let msg =
+            MigrateMessage::new_signed(&keypair, ConnectionId::generate(), Some(random_address()))
+                .unwrap();
+        msg.verify(&peer_id).unwrap();
+        assert!(matches!(
+            msg.verify(&PeerId::random()),
+            Err(Error::InvalidMigrateMessage(_))
+        ));
+
+        // the new address can't be swapped for another one
+        let mut tampered = msg.clone();
+        tampered.recipient = Some(random_address());
+        assert!(tampered.verify(&peer_id).is_err());
+        tampered.recipient = None;
+        assert!(tampered.verify(&peer_id).is_err());
+    }
+
+    #[test]
+    fn test_resume_message_signature() {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from_public_key(&keypair.public());
+        let msg = ResumeMessage::new_signed(
+            &keypair,
+            ConnectionId::generate(),
+            Bytes::from_static(b"ticket"),
+        )
+        .unwrap();
+        msg.verify(&peer_id).unwrap();
+        assert!(matches!(
+            msg.verify(&PeerId::random()),
+            Err(Error::InvalidSessionTicket)
+        ));
+
+        // the signature doesn't cover another ticket
+        let mut tampered = msg.clone();
+        tampered.ticket = Bytes::from_static(b"other ticket");
+        assert!(tampered.verify(&peer_id).is_err());
+    }
+
+    #[test]
+    fn test_strict_decoding() {
+        let keypair = Keypair::generate_ed25519();
+        let recipient = Recipient::try_from_base58_string("D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN").unwrap();
+        let messages = vec![
+            Message::ConnectionRequest(
+                ConnectionMessage::new_signed(
+                    &keypair,
+                    ConnectionId::generate(),
+                    ConnectionMessageKind::Request,
+                    ConnectionFlags::default(),
+                    Some([7u8; EPHEMERAL_KEY_LENGTH]),
+                )
+                .unwrap(),
+            ),
+            Message::AddressMessage(
+                AddressMessage::new_signed(&keypair, ConnectionId::generate(), recipient).unwrap(),
+            ),
+            Message::Ack(AckMessage {
+                id: ConnectionId::generate(),
+                nonce: 3,
+                sacks: vec![(5, 6)],
+            }),
+            Message::TransportMessage(TransportMessage {
+                nonce: 1,
+                id: ConnectionId::generate(),
+                message: SubstreamMessage::new_with_data(SubstreamId::generate(), b"hi".to_vec()),
+            }),
+            Message::Probe(ProbeMessage {
+                id: ConnectionId::generate(),
+            }),
+            Message::SessionTicket(SessionTicketMessage {
+                id: ConnectionId::generate(),
+                flags: ConnectionFlags::SESSION_TICKETS,
+                ticket: Bytes::from_static(b"ticket"),
+            }),
+            Message::Resume(
+                ResumeMessage::new_signed(
+                    &keypair,
+                    ConnectionId::generate(),
+                    Bytes::from_static(b"ticket"),
+                )
+                .unwrap(),
+            ),
+            Message::Migrate(
+                MigrateMessage::new_signed(&keypair, ConnectionId::generate(), Some(recipient))
+                    .unwrap(),
+            ),
+            Message::Migrate(
+                MigrateMessage::new_signed(&keypair, ConnectionId::generate(), None).unwrap(),
+            ),
+            Message::Parity(ParityMessage {
+                id: ConnectionId::generate(),
+                first_nonce: 8,
+                data_shards: 8,
+                parity_shards: 2,
+                index: 1,
+                shard: Bytes::from_static(b"parity"),
+            }),
+            Message::KeyUpdate(KeyUpdateMessage {
+                id: ConnectionId::generate(),
+                generation: 2,
+                kind: KeyUpdateKind::Request {
+                    public_key: [7; EPHEMERAL_KEY_LENGTH],
+                },
+                tag: [1; KEY_UPDATE_TAG_LENGTH],
+            }),
+            Message::KeyUpdate(KeyUpdateMessage {
+                id: ConnectionId::generate(),
+                generation: 2,
+                kind: KeyUpdateKind::Switched { from_nonce: 9 },
+                tag: [1; KEY_UPDATE_TAG_LENGTH],
+            }),
+            Message::TransportMessage(TransportMessage {
+                nonce: 2,
+                id: ConnectionId::generate(),
+                message: SubstreamMessage {
+                    substream_id: SubstreamId::generate(),
+                    message_type: SubstreamMessageType::Cover(Bytes::from_static(&[0; 16])),
+                },
+            }),
+        ];
+
+        // truncated messages are rejected rather than panicking
+        for msg in &messages {
+            let bytes = msg.to_bytes();
+            Message::try_from_bytes(bytes.clone().into()).unwrap();
+            for len in 0..bytes.len() {
+                let _ = Message::try_from_bytes(Bytes::copy_from_slice(&bytes[..len]));
+            }
+        }
+
+        // as are trailing bytes where the format has no room for them
+        let mut bytes = messages[1].to_bytes();
+        bytes.push(0);
+        assert!(Message::try_from_bytes(bytes.into()).is_err());
+
+        // and inverted ranges of acknowledged nonces
+        let mut bytes = messages[2].to_bytes();
+        let len = bytes.len();
+        bytes[len - 1] = 4;
+        assert!(Message::try_from_bytes(bytes.into()).is_err());
+
+        let mut bytes = messages[4].to_bytes();
+        bytes.push(0);
+        assert!(Message::try_from_bytes(bytes.into()).is_err());
+
+        let mut bytes = messages[3].to_bytes();
+        bytes.resize(MAX_MESSAGE_LEN + 1, 0);
+        assert!(matches!(
+            Message::try_from_bytes(bytes.into()),
+            Err(Error::MessageTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_connection_close_message() {
+        let id = ConnectionId::generate();
+        let reason = CloseReason::new(CloseCode::ProtocolError).with_message("bad nonce");
+        let bytes =
+            Message::ConnectionClose(ConnectionCloseMessage::new(id.clone(), reason.clone()))
+                .to_bytes();
+        let msg = match Message::try_from_bytes(bytes.into()).unwrap() {
+            Message::ConnectionClose(msg) => msg,
+            _ => panic!("expected Message::ConnectionClose"),
+        };
+        assert_eq!(msg.id, id);
+        assert_eq!(msg.reason, reason);
+
+        // codes from newer versions are kept, and long messages are truncated
+        let mut bytes = vec![];
+        ConnectionCloseMessage::new(
+            id.clone(),
+            CloseReason::new(CloseCode::Other(100)).with_message("é".repeat(200)),
+        )
+        .encode_into(&mut bytes);
+        let msg = ConnectionCloseMessage::try_from_bytes(&bytes).unwrap();
+        assert_eq!(msg.reason.code, CloseCode::Other(100));
+        assert_eq!(msg.reason.message.unwrap(), "é".repeat(128));
+
+        // the code is required
+        assert!(ConnectionCloseMessage::try_from_bytes(&id.0).is_err());
+
+        // a signed close verifies against its signer only, truncated or not
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let reason = CloseReason::new(CloseCode::Shutdown).with_message("é".repeat(200));
+        let signed = ConnectionCloseMessage::new_signed(&keypair, id.clone(), reason).unwrap();
+        let msg = match Message::try_from_bytes(Message::ConnectionClose(signed).to_bytes().into())
+            .unwrap()
+        {
+            Message::ConnectionClose(msg) => msg,
+            _ => panic!("expected Message::ConnectionClose"),
+        };
+        assert!(msg.is_signed());
+        msg.verify(&peer_id).unwrap();
+        assert!(msg.verify(&PeerId::random()).is_err());
+        let mut forged = msg.clone();
+        forged.reason.code = CloseCode::Idle;
+        assert!(forged.verify(&peer_id).is_err());
+        assert!(
+            ConnectionCloseMessage::new(id.clone(), CloseReason::new(CloseCode::Idle))
+                .verify(&peer_id)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_ack_message() {
+        let id = ConnectionId::generate();
+        let bytes = Message::Ack(AckMessage {
+            id: id.clone(),
+            nonce: 42,
+            sacks: vec![],
+        })
+        .to_bytes();
+        let msg = match Message::try_from_bytes(bytes.into()).unwrap() {
+            Message::Ack(msg) => msg,
+            _ => panic!("expected Message::Ack"),
+        };
+        assert_eq!(msg.id, id);
+        assert_eq!(msg.nonce, 42);
+        assert!(msg.sacks.is_empty());
+        assert!(AckMessage::try_from_bytes(&id.0).is_err());
+
+        // ranges received beyond the first missing message, up to a limit
+        let sacks: Vec<(u64, u64)> = (0..20).map(|i| (50 + 10 * i, 55 + 10 * i)).collect();
+        let mut bytes = vec![];
+        AckMessage {
+            id: id.clone(),
+            nonce: 42,
+            sacks: sacks.clone(),
+        }
+        .encode_into(&mut bytes);
+        let msg = AckMessage::try_from_bytes(&bytes).unwrap();
+        assert_eq!(msg.sacks, sacks[..MAX_SACK_BLOCKS]);
+        assert_eq!(msg.highest_nonce(), 205);
+
+        // truncated ranges are rejected
+        assert!(AckMessage::try_from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_serde() {
+        let id = ConnectionId::generate();
+        assert_eq!(id.to_string().parse::<ConnectionId>().unwrap(), id);
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{}\"", id));
+        assert_eq!(serde_json::from_str::<ConnectionId>(&json).unwrap(), id);
+
+        // the compact form parses to the same ID
+        let substream_id = SubstreamId::generate_compact();
+        let compact = hex::encode(&substream_id.0[..COMPACT_ID_LENGTH]);
+        assert_eq!(compact.parse::<SubstreamId>().unwrap(), substream_id);
+        assert!(matches!(
+            "abcd".parse::<SubstreamId>(),
+            Err(Error::InvalidId(_))
+        ));
+        assert!(serde_json::from_str::<SubstreamId>("\"not hex\"").is_err());
+
+        let msg = Message::ConnectionClose(ConnectionCloseMessage::new(
+            id.clone(),
+            CloseReason::new(CloseCode::Idle).with_message("idle"),
+        ));
+        let json = serde_json::to_string(&msg).unwrap();
+        match serde_json::from_str::<Message>(&json).unwrap() {
+            Message::ConnectionClose(close) => {
+                assert_eq!(close.id, id);
+                assert_eq!(close.reason.code, CloseCode::Idle);
+                assert_eq!(close.reason.message.as_deref(), Some("idle"));
+            }
+            _ => panic!("expected Message::ConnectionClose"),
+        }
+        // messages are decoded with the same checks as on the wire
+        assert!(serde_json::from_str::<Message>("\"09\"").is_err());
+
+        let reason = CloseReason::new(CloseCode::Other(100));
+        let json = serde_json::to_string(&reason).unwrap();
+        assert_eq!(json, r#"{"code":100,"message":null}"#);
+        assert_eq!(serde_json::from_str::<CloseReason>(&json).unwrap(), reason);
+    }
+
+    #[test]
+    fn test_transport_message_data_is_not_copied() {
+        let msg = Message::TransportMessage(TransportMessage {
+            nonce: 1,
+            id: ConnectionId::generate(),
+            message: SubstreamMessage::new_with_data(SubstreamId::generate(), b"hello".to_vec()),
+        });
+        let bytes = Bytes::from(msg.to_bytes());
+
+        // the parsed payload points into the received buffer
+        let data = match Message::try_from_bytes(bytes.clone()).unwrap() {
+            Message::TransportMessage(TransportMessage {
+                message:
+                    SubstreamMessage {
+                        message_type: SubstreamMessageType::Data(data),
+                        ..
+                    },
+                ..
+            }) => data,
+            _ => panic!("expected Message::TransportMessage with data"),
+        };
+        assert_eq!(data, &b"hello"[..]);
+        assert_eq!(data.as_ptr(), bytes[bytes.len() - data.len()..].as_ptr());
+    }
+
+    #[test]
+    fn test_encode_into_reuses_buffer() {
+        let msg = Message::TransportMessage(TransportMessage {
+            nonce: 1,
+            id: ConnectionId::generate(),
+            message: SubstreamMessage::new_with_data(SubstreamId::generate(), b"hello".to_vec()),
+        });
+        let expected = msg.to_bytes();
+        assert_eq!(
+            expected.len(),
+            1 + MIN_CONNECTION_MESSAGE_LEN + SUBSTREAM_ID_LENGTH + 1 + 5
+        );
+
+        let mut buf = Vec::with_capacity(expected.len());
+        msg.encode_into(&mut buf);
+        assert_eq!(buf, expected);
+
+        // a cleared buffer is reused without reallocating
+        let ptr = buf.as_ptr();
+        buf.clear();
+        msg.encode_into(&mut buf);
+        assert_eq!(buf, expected);
+        assert_eq!(buf.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn test_substream_id_directions() {
+        for _ in 0..64 {
+            for opener in [Endpoint::Dialer, Endpoint::Listener] {
+                for compact in [false, true] {
+                    let id = SubstreamId::generate_for(opener, compact);
+                    assert_eq!(id.opener(), opener);
+                    assert_eq!(id.is_compact(), compact);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_compact_ids() {
+        let msg = TransportMessage {
+            nonce: 1,
+            id: ConnectionId::generate_compact(),
+            message: SubstreamMessage::new_with_data(
+                SubstreamId::generate_compact(),
+                b"hello".to_vec(),
+            ),
+        };
+        assert!(msg.is_compact());
+        let outbound = |message: TransportMessage, compact_ids: bool| OutboundMessage {
+            message: Message::TransportMessage(message),
+            recipient: None,
+            sender_tag: None,
+            queued_at: Instant::now(),
+            session: None,
+            write_credit: None,
+            compact_ids,
+            alias: None,
+        };
+
+        // the full encoding is used unless the remote agreed to compact IDs
+        let full = outbound(msg.clone(), false).to_bytes().unwrap();
+        assert_eq!(full[0], TRANSPORT_MESSAGE_TYPE);
+        let compact = outbound(msg.clone(), true).to_bytes().unwrap();
+        assert_eq!(compact[0], COMPACT_TRANSPORT_MESSAGE_TYPE);
+        assert_eq!(
+            full.len() - compact.len(),
+            2 * (CONNECTION_ID_LENGTH - COMPACT_ID_LENGTH)
+        );
+
+        // both decode to the same message
+        for bytes in [full, compact] {
+            match Message::try_from_bytes(bytes.into()).unwrap() {
+                Message::TransportMessage(decoded) => {
+                    assert_eq!(decoded.nonce, msg.nonce);
+                    assert_eq!(decoded.id, msg.id);
+                    assert_eq!(decoded.message.substream_id, msg.message.substream_id);
+                }
+                _ => panic!("expected Message::TransportMessage"),
+            }
+        }
+
+        // full IDs use all 32 bytes and can't be truncated
+        let mut legacy = msg.clone();
+        legacy.id = ConnectionId::generate();
+        assert!(!legacy.is_compact());
+        let bytes = outbound(legacy.clone(), true).to_bytes().unwrap();
+        assert_eq!(bytes[0], TRANSPORT_MESSAGE_TYPE);
+        match Message::try_from_bytes(bytes.into()).unwrap() {
+            Message::TransportMessage(decoded) => assert_eq!(decoded.id, legacy.id),
+            _ => panic!("expected Message::TransportMessage"),
+        }
+    }
 }
@@ -0,0 +1,393 @@
+use log::warn;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// polls which take longer than this are logged, since they hold up every
+/// other task on the executor thread.
+const SLOW_POLL: Duration = Duration::from_millis(100);
+
+/// TransportMetrics contains counters describing the activity of a `NymTransport`.
+/// A handle can be obtained with `NymTransport::metrics()` before the transport
+/// is moved into a swarm; counters are updated as the transport runs.
+#[derive(Debug, Default)]
+pub struct TransportMetrics {
+    /// inbound messages which could not be handled and were dropped.
+    pub(crate) inbound_errors: AtomicU64,
+    /// connections whose transport state was removed because of an error,
+    /// eg. the `Connection` was dropped while messages were still arriving.
+    pub(crate) connections_closed_on_error: AtomicU64,
+    /// connections closed because a message arrived for them after their
+    /// `Connection` was dropped, before the transport was told.
+    pub(crate) connections_dropped: AtomicU64,
+    /// `ConnectionRequest`s resent because no response arrived in time.
+    pub(crate) dial_retransmissions: AtomicU64,
+    /// dials rejected because the dial queue was full.
+    pub(crate) dials_rejected: AtomicU64,
+    /// pending dials purged because no response arrived before the handshake timeout.
+    pub(crate) dials_expired: AtomicU64,
+    /// dials whose future was dropped before the handshake completed.
+    pub(crate) dials_canceled: AtomicU64,
+    /// inbound connection requests and dials rejected by the peer filter.
+    pub(crate) peers_rejected: AtomicU64,
+    /// inbound connection requests and dials rejected for lacking proof of
+    /// the private network's key.
+    pub(crate) private_network_rejected: AtomicU64,
+    /// outbound handshakes completed, ie. ConnectionResponses received.
+    pub(crate) handshakes_completed: AtomicU64,
+    /// sum of the round-trip times of all completed outbound handshakes.
+    pub(crate) handshake_rtt_millis_total: AtomicU64,
+    /// connections resumed with a session ticket, as the dialer or the listener.
+    pub(crate) sessions_resumed: AtomicU64,
+    /// connections moved to a new address, ours or the remote's.
+    pub(crate) connections_migrated: AtomicU64,
+    /// outbound data messages dropped because they exceeded the message TTL.
+    pub(crate) messages_expired: AtomicU64,
+    /// messages currently waiting to be handed to the mixnet client.
+    pub(crate) outbound_backlog: AtomicU64,
+    /// messages received from the mixnet which the transport hasn't handled
+    /// yet, as of the last time it was polled.
+    pub(crate) inbound_backlog: AtomicU64,
+    /// messages handed to the mixnet client, including those it failed to
+    /// accept; see `send_failures`.
+    pub(crate) outbound_messages: AtomicU64,
+    /// outbound messages the mixnet client failed to accept, which were never
+    /// sent, unless they were kept in the outbox.
+    pub(crate) send_failures: AtomicU64,
+    /// messages kept in the outbox, see `StoreAndForward`.
+    pub(crate) outbox_stored: AtomicU64,
+    /// messages from the outbox the mixnet client took on another attempt.
+    pub(crate) outbox_flushed: AtomicU64,
+    /// messages dropped from the outbox, because they got too old, the outbox
+    /// was full, or their connection didn't survive a restart.
+    pub(crate) outbox_dropped: AtomicU64,
+    /// outbound data messages held back while the mixnet client was
+    /// disconnected, see `OfflineBuffer`.
+    pub(crate) offline_buffered: AtomicU64,
+    /// outbound data messages refused because the offline buffer was full.
+    pub(crate) offline_buffer_overflows: AtomicU64,
+    /// extra copies of control messages sent, see `Redundancy`.
+    pub(crate) redundant_copies: AtomicU64,
+    /// parity messages sent, see `ForwardErrorCorrection`.
+    pub(crate) fec_parity_sent: AtomicU64,
+    /// inbound messages rebuilt from parity, instead of waiting for them to
+    /// be retransmitted.
+    pub(crate) fec_recovered: AtomicU64,
+    /// sum of the time outbound messages waited before being handed to the
+    /// mixnet client.
+    pub(crate) outbound_queue_millis_total: AtomicU64,
+    /// times the outbound backlog exceeded the configured threshold.
+    pub(crate) backlog_overloads: AtomicU64,
+    /// inbound messages which arrived before one with a lower nonce.
+    pub(crate) messages_out_of_order: AtomicU64,
+    /// the largest distance between the nonce of an arriving message and the
+    /// next expected one, across all connections.
+    pub(crate) max_reorder_gap: AtomicU64,
+    /// inbound messages dropped because they were duplicates, or arrived too
+    /// far out of order.
+    pub(crate) messages_dropped_on_reorder: AtomicU64,
+    /// connections closed because they carried no traffic for the idle timeout.
+    pub(crate) connections_closed_idle: AtomicU64,
+    /// bytes currently buffered across all connections; see
+    /// `NymTransportConfig::max_buffered_bytes`.
+    pub(crate) buffered_bytes: AtomicU64,
+    /// inbound messages or data dropped because the buffer budget was exhausted.
+    pub(crate) buffer_budget_exceeded: AtomicU64,
+    /// times a congestion window was halved because nothing it had in flight
+    /// was acknowledged within the loss timeout.
+    pub(crate) congestion_losses: AtomicU64,
+    /// TransportMessages resent because they weren't acknowledged in time.
+    pub(crate) messages_retransmitted: AtomicU64,
+    /// times the mixnet client was replaced after losing its gateway.
+    pub(crate) gateway_failovers: AtomicU64,
+    /// times the transport rotated to a mixnet client with a new address;
+    /// see `AddressRotation`.
+    pub(crate) address_rotations: AtomicU64,
+    /// connections closed and connection requests rejected because their
+    /// peer exceeded its bandwidth quota.
+    pub(crate) bandwidth_quota_exceeded: AtomicU64,
+    /// reply SURBs attached to the messages we sent as a dialer, for the
+    /// listeners to answer with.
+    pub(crate) reply_surbs_attached: AtomicU64,
+    /// the part of `reply_surbs_attached` sent after the handshake, topping
+    /// up the listeners' supply.
+    pub(crate) reply_surbs_topped_up: AtomicU64,
+    /// our reply SURBs the listeners used up, ie. the messages received on
+    /// connections we dialed.
+    pub(crate) reply_surbs_used: AtomicU64,
+    /// the dialers' reply SURBs we used up, ie. the replies we sent on
+    /// connections we accepted.
+    pub(crate) remote_reply_surbs_used: AtomicU64,
+    /// replies which found no SURB left for their sender tag.
+    pub(crate) reply_surbs_exhausted: AtomicU64,
+    /// how long each poll of the transport took, in microseconds.
+    pub(crate) transport_poll_micros: Histogram,
+    /// how many inbound messages each poll of the transport handled.
+    pub(crate) transport_poll_messages: Histogram,
+    /// how long each poll of a connection took, in microseconds, across all
+    /// connections.
+    pub(crate) connection_poll_micros: Histogram,
+    /// how many substream messages each poll of a connection handled.
+    pub(crate) connection_poll_messages: Histogram,
+}
+
+/// the number of buckets of a `Histogram`; the last one holds everything
+/// from 2^(HISTOGRAM_BUCKETS - 2) up.
+const HISTOGRAM_BUCKETS: usize = 28;
+
+/// Histogram counts observations in buckets whose bounds grow in powers of
+/// two, so that it can be updated from any thread without locking.
+#[derive(Debug, Default)]
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: u64) {
+        // zero goes into the first bucket, and 2^(i-1)..2^i into the i-th
+        let bucket = (u64::BITS - value.leading_zeros()) as usize;
+        self.buckets[bucket.min(HISTOGRAM_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// HistogramSnapshot is a point-in-time copy of a histogram. `buckets[0]`
+/// counts the observations of zero, and `buckets[i]` those from `2^(i-1)`
+/// up to but excluding `2^i`; the last bucket has no upper bound.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    pub buckets: Vec<u64>,
+    pub sum: u64,
+}
+
+impl HistogramSnapshot {
+    /// returns the number of observations.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// returns the mean of the observations.
+    pub fn mean(&self) -> Option<u64> {
+        let count = self.count();
+        (count > 0).then(|| self.sum / count)
+    }
+
+    /// returns an upper bound of the given quantile of the observations, eg.
+    /// 0.99 for the 99th percentile: the exclusive upper bound of the bucket
+    /// it falls into, or `u64::MAX` for the last bucket.
+    pub fn quantile(&self, quantile: f64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((count as f64 * quantile.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+        let bucket = self.buckets.iter().position(|n| {
+            seen += n;
+            seen >= rank
+        })?;
+        Some(if bucket + 1 < self.buckets.len() {
+            1 << bucket
+        } else {
+            u64::MAX
+        })
+    }
+}
+
+/// MetricsSnapshot is a point-in-time copy of the transport's counters.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub inbound_errors: u64,
+    pub connections_closed_on_error: u64,
+    pub connections_dropped: u64,
+    pub dial_retransmissions: u64,
+    pub dials_rejected: u64,
+    pub dials_expired: u64,
+    pub dials_canceled: u64,
+    pub peers_rejected: u64,
+    pub private_network_rejected: u64,
+    pub handshakes_completed: u64,
+    pub handshake_rtt_millis_total: u64,
+    pub sessions_resumed: u64,
+    pub connections_migrated: u64,
+    pub messages_expired: u64,
+    pub outbound_backlog: u64,
+    pub inbound_backlog: u64,
+    pub outbound_messages: u64,
+    pub send_failures: u64,
+    pub outbox_stored: u64,
+    pub outbox_flushed: u64,
+    pub outbox_dropped: u64,
+    pub offline_buffered: u64,
+    pub offline_buffer_overflows: u64,
+    pub redundant_copies: u64,
+    pub fec_parity_sent: u64,
+    pub fec_recovered: u64,
+    pub outbound_queue_millis_total: u64,
+    pub backlog_overloads: u64,
+    pub messages_out_of_order: u64,
+    pub max_reorder_gap: u64,
+    pub messages_dropped_on_reorder: u64,
+    pub connections_closed_idle: u64,
+    pub buffered_bytes: u64,
+    pub buffer_budget_exceeded: u64,
+    pub congestion_losses: u64,
+    pub messages_retransmitted: u64,
+    pub gateway_failovers: u64,
+    pub address_rotations: u64,
+    pub bandwidth_quota_exceeded: u64,
+    pub reply_surbs_attached: u64,
+    pub reply_surbs_topped_up: u64,
+    pub reply_surbs_used: u64,
+    pub remote_reply_surbs_used: u64,
+    pub reply_surbs_exhausted: u64,
+    pub transport_poll_micros: HistogramSnapshot,
+    pub transport_poll_messages: HistogramSnapshot,
+    pub connection_poll_micros: HistogramSnapshot,
+    pub connection_poll_messages: HistogramSnapshot,
+}
+
+impl MetricsSnapshot {
+    /// returns the mean round-trip time of the completed outbound handshakes.
+    pub fn mean_handshake_rtt(&self) -> Option<Duration> {
+        (self.handshakes_completed > 0).then(|| {
+            Duration::from_millis(self.handshake_rtt_millis_total / self.handshakes_completed)
+        })
+    }
+
+    /// returns the mean time outbound messages waited before being handed to
+    /// the mixnet client.
+    pub fn mean_outbound_queue_time(&self) -> Option<Duration> {
+        (self.outbound_messages > 0).then(|| {
+            Duration::from_millis(self.outbound_queue_millis_total / self.outbound_messages)
+        })
+    }
+}
+
+impl TransportMetrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            inbound_errors: self.inbound_errors.load(Ordering::Relaxed),
+            connections_closed_on_error: self.connections_closed_on_error.load(Ordering::Relaxed),
+            connections_dropped: self.connections_dropped.load(Ordering::Relaxed),
+            dial_retransmissions: self.dial_retransmissions.load(Ordering::Relaxed),
+            dials_rejected: self.dials_rejected.load(Ordering::Relaxed),
+            dials_expired: self.dials_expired.load(Ordering::Relaxed),
+            dials_canceled: self.dials_canceled.load(Ordering::Relaxed),
+            peers_rejected: self.peers_rejected.load(Ordering::Relaxed),
+            private_network_rejected: self.private_network_rejected.load(Ordering::Relaxed),
+            handshakes_completed: self.handshakes_completed.load(Ordering::Relaxed),
+            handshake_rtt_millis_total: self.handshake_rtt_millis_total.load(Ordering::Relaxed),
+            sessions_resumed: self.sessions_resumed.load(Ordering::Relaxed),
+            connections_migrated: self.connections_migrated.load(Ordering::Relaxed),
+            messages_expired: self.messages_expired.load(Ordering::Relaxed),
+            outbound_backlog: self.outbound_backlog.load(Ordering::Relaxed),
+            inbound_backlog: self.inbound_backlog.load(Ordering::Relaxed),
+            outbound_messages: self.outbound_messages.load(Ordering::Relaxed),
+            send_failures: self.send_failures.load(Ordering::Relaxed),
+            outbox_stored: self.outbox_stored.load(Ordering::Relaxed),
+            outbox_flushed: self.outbox_flushed.load(Ordering::Relaxed),
+            outbox_dropped: self.outbox_dropped.load(Ordering::Relaxed),
+            offline_buffered: self.offline_buffered.load(Ordering::Relaxed),
+            offline_buffer_overflows: self.offline_buffer_overflows.load(Ordering::Relaxed),
+            redundant_copies: self.redundant_copies.load(Ordering::Relaxed),
+            fec_parity_sent: self.fec_parity_sent.load(Ordering::Relaxed),
+            fec_recovered: self.fec_recovered.load(Ordering::Relaxed),
+            outbound_queue_millis_total: self.outbound_queue_millis_total.load(Ordering::Relaxed),
+            backlog_overloads: self.backlog_overloads.load(Ordering::Relaxed),
+            messages_out_of_order: self.messages_out_of_order.load(Ordering::Relaxed),
+            max_reorder_gap: self.max_reorder_gap.load(Ordering::Relaxed),
+            messages_dropped_on_reorder: self.messages_dropped_on_reorder.load(Ordering::Relaxed),
+            connections_closed_idle: self.connections_closed_idle.load(Ordering::Relaxed),
+            buffered_bytes: self.buffered_bytes.load(Ordering::Relaxed),
+            buffer_budget_exceeded: self.buffer_budget_exceeded.load(Ordering::Relaxed),
+            congestion_losses: self.congestion_losses.load(Ordering::Relaxed),
+            messages_retransmitted: self.messages_retransmitted.load(Ordering::Relaxed),
+            gateway_failovers: self.gateway_failovers.load(Ordering::Relaxed),
+            address_rotations: self.address_rotations.load(Ordering::Relaxed),
+            bandwidth_quota_exceeded: self.bandwidth_quota_exceeded.load(Ordering::Relaxed),
+            reply_surbs_attached: self.reply_surbs_attached.load(Ordering::Relaxed),
+            reply_surbs_topped_up: self.reply_surbs_topped_up.load(Ordering::Relaxed),
+            reply_surbs_used: self.reply_surbs_used.load(Ordering::Relaxed),
+            remote_reply_surbs_used: self.remote_reply_surbs_used.load(Ordering::Relaxed),
+            reply_surbs_exhausted: self.reply_surbs_exhausted.load(Ordering::Relaxed),
+            transport_poll_micros: self.transport_poll_micros.snapshot(),
+            transport_poll_messages: self.transport_poll_messages.snapshot(),
+            connection_poll_micros: self.connection_poll_micros.snapshot(),
+            connection_poll_messages: self.connection_poll_messages.snapshot(),
+        }
+    }
+
+    pub(crate) fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set(gauge: &AtomicU64, n: u64) {
+        gauge.store(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn max(gauge: &AtomicU64, n: u64) {
+        gauge.fetch_max(n, Ordering::Relaxed);
+    }
+
+    /// records how long a poll which started at `started` took, and how many
+    /// messages it handled.
+    pub(crate) fn record_poll(
+        micros: &Histogram,
+        messages: &Histogram,
+        started: Instant,
+        handled: u64,
+    ) {
+        let elapsed = started.elapsed();
+        if elapsed >= SLOW_POLL {
+            warn!(
+                "poll took {:?} and handled {} messages, stalling the executor",
+                elapsed, handled
+            );
+        }
+        micros.observe(elapsed.as_micros().try_into().unwrap_or(u64::MAX));
+        messages.observe(handled);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.snapshot().quantile(0.5), None);
+        for value in [0, 1, 3, 3, 100, u64::MAX / 2] {
+            histogram.observe(value);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count(), 6);
+        assert_eq!(snapshot.buckets[0], 1);
+        assert_eq!(snapshot.buckets[1], 1);
+        assert_eq!(snapshot.buckets[2], 2);
+        assert_eq!(snapshot.buckets[7], 1);
+        assert_eq!(snapshot.buckets[HISTOGRAM_BUCKETS - 1], 1);
+
+        // quantiles are bounded by the bucket they fall into
+        assert_eq!(snapshot.quantile(0.0), Some(1));
+        assert_eq!(snapshot.quantile(0.5), Some(4));
+        assert_eq!(snapshot.quantile(0.8), Some(128));
+        assert_eq!(snapshot.quantile(1.0), Some(u64::MAX));
+    }
+}
@@ -1,203 +1,4304 @@
-use futures::{pin_mut, select};
-use futures::{FutureExt, StreamExt};
-use log::debug;
-use nym_sdk::mixnet::{
-    AnonymousSenderTag, IncludedSurbs, MixnetClient, MixnetClientSender, MixnetMessageSender,
-};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use futures::{future, pin_mut, select, select_biased};
+use log::{debug, warn};
+#[cfg(feature = "nym-client")]
+use nym_sdk::mixnet::MixnetClient;
 use nym_sphinx::addressing::clients::Recipient;
+use nym_sphinx::anonymous_replies::requests::AnonymousSenderTag;
 use nym_sphinx::receiver::ReconstructedMessage;
+use parking_lot::{Mutex, RwLock};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::{Debug, Formatter},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Sleep};
 use tracing::info;
 
-use super::error::Error;
-use super::message::*;
+use super::alias::{AliasCommand, AliasId};
+use super::audit::{AuditLog, Stage};
+use super::budget::{BufferKind, MemoryBudget};
+use super::chaos::{corrupt, Chaos, Fault};
+#[cfg(feature = "nym-client")]
+use super::client::ManagedMixnetClient;
+#[cfg(feature = "nym-client")]
+use super::config::{AddressRotation, GatewayFailover};
+use super::config::{OfflineBuffer, Redundancy, ReplySurbs, TrafficClasses};
+use super::driver::{MixnetDriver, MixnetDriverSender};
+use super::error::Error;
+use super::fec::FecRegistry;
+use super::message::*;
+use super::metrics::TransportMetrics;
+use super::outbox::{Outbox, StoredMessage};
+use super::padding::{strip_padding, PaddingRegistry};
+use super::redact::{redact, redact_always};
+use super::retransmit::SendBuffer;
+use super::stats::{ConnectionStatsRegistry, RttSampler};
+use super::substream::TrafficClass;
+
+/// the outbound encoding buffer is reused for every message, but dropped
+/// after a message larger than this so that a single big write doesn't keep
+/// its memory around.
+const MAX_RETAINED_ENCODE_BUFFER: usize = 64 * 1024;
+
+/// how long a reply which found no SURBs for its sender tag waits before it's
+/// sent again, so that the remote's next message can top them up.
+pub(crate) const SURB_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// the most replies waiting to be sent again at once; see `ReplyRetries`.
+const MAX_REPLY_RETRIES: usize = 256;
+
+/// OutboundBacklog tracks the number of messages waiting in the outbound channel
+/// to be handed to the mixnet client. Once it exceeds the threshold, the
+/// application is told through `BacklogOverload` so it can shed load, until
+/// the backlog has dropped to half the threshold.
+///
+/// Handing a message to the mixnet client waits while the client's own queue
+/// is full, so the backlog grows when the client can't keep up. If it has a
+/// limit, writers wait with `poll_ready` until the backlog is below it again,
+/// so that substream writes see the client's backpressure instead of queueing
+/// up in the channel; control messages aren't held back. Bulk writers wait
+/// until it's below half the limit, leaving the rest to interactive ones.
+/// Once the mixnet task stopped, writers no longer wait, and their writes
+/// fail instead.
+#[derive(Debug, Default)]
+pub(crate) struct OutboundBacklog {
+    depth: AtomicUsize,
+    threshold: Option<usize>,
+    limit: Option<usize>,
+    overloaded: AtomicBool,
+    /// set once the outbound channel's receiver is gone.
+    closed: AtomicBool,
+    /// the backlog which exceeded the threshold, while it hasn't dropped to
+    /// half of it.
+    overload_tx: Option<watch::Sender<Option<usize>>>,
+    /// writers waiting for the backlog to drop below the limit.
+    waiters: Mutex<Vec<Waker>>,
+    metrics: Arc<TransportMetrics>,
+}
+
+impl OutboundBacklog {
+    pub(crate) fn new(
+        threshold: Option<usize>,
+        overload_tx: watch::Sender<Option<usize>>,
+        metrics: Arc<TransportMetrics>,
+    ) -> Self {
+        OutboundBacklog {
+            threshold,
+            overload_tx: Some(overload_tx),
+            metrics,
+            ..Default::default()
+        }
+    }
+
+    /// returns the backlog with the given limit; see `poll_ready`.
+    pub(crate) fn with_limit(mut self, limit: Option<usize>) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// polls until the backlog is below the limit of the given class.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>, class: TrafficClass) -> Poll<()> {
+        let Some(limit) = self.limit else {
+            return Poll::Ready(());
+        };
+        if self.closed.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        let limit = match class {
+            TrafficClass::Interactive => limit,
+            TrafficClass::Bulk => (limit / 2).max(1),
+        };
+        if self.depth.load(Ordering::SeqCst) < limit {
+            return Poll::Ready(());
+        }
+        {
+            // a writer polled again replaces its waker, so the waiters don't
+            // grow while it waits
+            let mut waiters = self.waiters.lock();
+            match waiters.iter_mut().find(|waker| waker.will_wake(cx.waker())) {
+                Some(waker) => waker.clone_from(cx.waker()),
+                None => waiters.push(cx.waker().clone()),
+            }
+        }
+        // a message may have been dequeued, or the channel closed, before
+        // the waker was registered
+        if self.depth.load(Ordering::SeqCst) < limit || self.closed.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+
+    /// wakes the waiting writers for good, as nothing will be dequeued
+    /// anymore; their sends then fail.
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        for waker in self.waiters.lock().drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn queued(&self) {
+        let depth = self.depth.fetch_add(1, Ordering::SeqCst) + 1;
+        TransportMetrics::set(&self.metrics.outbound_backlog, depth as u64);
+
+        let Some(threshold) = self.threshold else {
+            return;
+        };
+        if depth > threshold && !self.overloaded.swap(true, Ordering::SeqCst) {
+            warn!("outbound backlog of {} messages exceeds threshold", depth);
+            TransportMetrics::inc(&self.metrics.backlog_overloads);
+            if let Some(overload_tx) = &self.overload_tx {
+                overload_tx.send_replace(Some(depth));
+            }
+        }
+    }
+
+    pub(crate) fn dequeued(&self, queued_at: Instant) {
+        let depth = self.depth.fetch_sub(1, Ordering::SeqCst) - 1;
+        TransportMetrics::set(&self.metrics.outbound_backlog, depth as u64);
+        TransportMetrics::inc(&self.metrics.outbound_messages);
+        TransportMetrics::add(
+            &self.metrics.outbound_queue_millis_total,
+            queued_at.elapsed().as_millis() as u64,
+        );
+
+        if self
+            .threshold
+            .is_some_and(|threshold| depth <= threshold / 2)
+            && self.overloaded.swap(false, Ordering::SeqCst)
+        {
+            if let Some(overload_tx) = &self.overload_tx {
+                overload_tx.send_replace(None);
+            }
+        }
+        if self.limit.is_some_and(|limit| depth < limit) {
+            for waker in self.waiters.lock().drain(..) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// OutboundSender is the sending half of the outbound mixnet channel, which
+/// keeps track of the channel's backlog.
+#[derive(Clone, Debug)]
+pub(crate) struct OutboundSender {
+    tx: UnboundedSender<OutboundMessage>,
+    /// if set, control messages are sent here instead, see `OutboundReceiver`.
+    /// Each is sent along with the number of data messages queued before it.
+    control_tx: Option<UnboundedSender<(DataCount, OutboundMessage)>>,
+    /// the number of data messages queued by all senders of the channel, of
+    /// the interactive and the bulk class.
+    data_queued: Arc<[AtomicU64; 2]>,
+    /// if set, data messages of bulk senders are sent here instead.
+    bulk_tx: Option<UnboundedSender<OutboundMessage>>,
+    /// the class of the data sent with this sender.
+    class: TrafficClass,
+    backlog: Arc<OutboundBacklog>,
+    /// if set, sent TransportMessages are kept here until they're acknowledged.
+    send_buffer: Option<Arc<SendBuffer>>,
+    /// if set, sent TransportMessages are timed until they're acknowledged.
+    rtt_sampler: Option<Arc<RttSampler>>,
+    /// if set, messages are sent where the route currently points, rather
+    /// than where the connection sending them thinks.
+    route: Option<Arc<ConnectionRoute>>,
+    /// if set, messages are replies on a connection made to the alias, and
+    /// are sent by its client.
+    alias: Option<AliasId>,
+    /// if set, data sent while the mixnet client is disconnected is bounded.
+    outage: Option<Arc<Outage>>,
+    audit_log: Option<AuditLog>,
+}
+
+impl OutboundSender {
+    /// returns a sender which sends all messages over the given channel, in
+    /// order; tests use it to see everything the transport sends.
+    #[cfg(test)]
+    pub(crate) fn new(tx: UnboundedSender<OutboundMessage>, backlog: Arc<OutboundBacklog>) -> Self {
+        OutboundSender {
+            tx,
+            control_tx: None,
+            data_queued: Arc::default(),
+            bulk_tx: None,
+            class: TrafficClass::Interactive,
+            backlog,
+            send_buffer: None,
+            rtt_sampler: None,
+            route: None,
+            alias: None,
+            outage: None,
+            audit_log: None,
+        }
+    }
+
+    /// returns a sender which records the TransportMessages it sends in the
+    /// given buffer, for a connection using selective repeat.
+    pub(crate) fn with_send_buffer(mut self, send_buffer: Option<Arc<SendBuffer>>) -> Self {
+        self.send_buffer = send_buffer;
+        self
+    }
+
+    /// returns a sender which times the TransportMessages it sends with the
+    /// given sampler, for a connection with acks.
+    pub(crate) fn with_rtt_sampler(mut self, rtt_sampler: Option<Arc<RttSampler>>) -> Self {
+        self.rtt_sampler = rtt_sampler;
+        self
+    }
+
+    /// returns a sender which sends along the given route, for a connection
+    /// which may migrate.
+    pub(crate) fn with_route(mut self, route: Option<Arc<ConnectionRoute>>) -> Self {
+        self.route = route;
+        self
+    }
+
+    /// returns a sender which replies through the client of the given alias,
+    /// for a connection made to it.
+    pub(crate) fn with_alias(mut self, alias: Option<AliasId>) -> Self {
+        self.alias = alias;
+        self
+    }
+
+    /// returns a sender which bounds the data held back while the mixnet
+    /// client is disconnected.
+    pub(crate) fn with_outage(mut self, outage: Option<Arc<Outage>>) -> Self {
+        self.outage = outage;
+        self
+    }
+
+    /// returns a sender which records the messages it sends in the given log.
+    pub(crate) fn with_audit_log(mut self, audit_log: Option<AuditLog>) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    /// returns a sender which queues its data messages as the given class.
+    pub(crate) fn with_traffic_class(mut self, class: TrafficClass) -> Self {
+        self.class = class;
+        self
+    }
+
+    pub(crate) fn traffic_class(&self) -> TrafficClass {
+        self.class
+    }
+
+    /// returns true once the mixnet task stopped; nothing queued since is sent.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// polls until the backlog has room for more data; see `OutboundBacklog`.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(send_buffer) = &self.send_buffer {
+            if send_buffer.poll_ready(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        self.backlog.poll_ready(cx, self.class)
+    }
+
+    /// queues the message for the mixnet task. Fails with
+    /// `Error::OfflineBufferFull` for data which doesn't fit into the offline
+    /// buffer; a substream's frame is replaced by a Close then, which is
+    /// still queued.
+    pub(crate) fn send(&self, mut msg: OutboundMessage) -> Result<(), Error> {
+        let refused = self
+            .outage
+            .as_ref()
+            .and_then(|outage| outage.hold(&msg).err());
+        if let Some(e) = refused {
+            if close_substream(&mut msg) {
+                // the writer learns from the error, not from its write window
+                msg.write_credit = None;
+                self.queue(msg)?;
+            }
+            return Err(e);
+        }
+        self.queue(msg)
+    }
+
+    fn queue(&self, mut msg: OutboundMessage) -> Result<(), Error> {
+        if let Some(route) = &self.route {
+            route.apply(&mut msg);
+        }
+        if self.alias.is_some() {
+            msg.alias = self.alias;
+        }
+        let control_tx = self
+            .control_tx
+            .as_ref()
+            .filter(|_| msg.message.is_control());
+        let (tx, class) = match &self.bulk_tx {
+            Some(bulk_tx) if self.class == TrafficClass::Bulk => (bulk_tx, 1),
+            _ => (&self.tx, 0),
+        };
+        if let Some(send_buffer) = &self.send_buffer {
+            send_buffer.record(&msg);
+        }
+        if let (Some(rtt_sampler), Message::TransportMessage(tm)) =
+            (&self.rtt_sampler, &msg.message)
+        {
+            rtt_sampler.on_send(tm.nonce);
+        }
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record_message(&msg.message, Stage::Queued);
+        }
+        // count the message first, so the receiver never sees it uncounted
+        self.backlog.queued();
+        let sent = match control_tx {
+            Some(control_tx) => {
+                let data_queued = self
+                    .data_queued
+                    .each_ref()
+                    .map(|queued| queued.load(Ordering::SeqCst));
+                control_tx.send((data_queued, msg)).is_ok()
+            }
+            None => {
+                self.data_queued[class].fetch_add(1, Ordering::SeqCst);
+                tx.send(msg).is_ok()
+            }
+        };
+        if !sent {
+            self.backlog.depth.fetch_sub(1, Ordering::SeqCst);
+            return Err(Error::OutboundSendFailure(
+                "the mixnet task stopped".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Outage tracks whether the mixnet client is disconnected from its gateway,
+/// and bounds the data held back in the meantime; see `OfflineBuffer`.
+#[derive(Debug)]
+pub(crate) struct Outage {
+    config: OfflineBuffer,
+    /// when the last outage began and, once the client was replaced, ended.
+    window: RwLock<Option<(Instant, Option<Instant>)>>,
+    /// data messages held back during the current outage.
+    held: AtomicUsize,
+    metrics: Arc<TransportMetrics>,
+}
+
+impl Outage {
+    pub(crate) fn new(config: OfflineBuffer, metrics: Arc<TransportMetrics>) -> Self {
+        Outage {
+            config,
+            window: RwLock::new(None),
+            held: AtomicUsize::new(0),
+            metrics,
+        }
+    }
+
+    fn begin(&self) {
+        *self.window.write() = Some((Instant::now(), None));
+        self.held.store(0, Ordering::SeqCst);
+    }
+
+    fn end(&self) {
+        if let Some((_, ended)) = self.window.write().as_mut() {
+            ended.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// counts a data message sent during an outage, unless the buffer is full.
+    fn hold(&self, msg: &OutboundMessage) -> Result<(), Error> {
+        let offline = matches!(*self.window.read(), Some((_, None)));
+        if !offline || !carries_data(&msg.message) {
+            return Ok(());
+        }
+        if self.held.fetch_add(1, Ordering::SeqCst) < self.config.max_messages {
+            TransportMetrics::inc(&self.metrics.offline_buffered);
+            return Ok(());
+        }
+        self.held.fetch_sub(1, Ordering::SeqCst);
+        TransportMetrics::inc(&self.metrics.offline_buffer_overflows);
+        Err(Error::OfflineBufferFull(self.config.max_messages))
+    }
+
+    /// returns whether the message was held back by the last outage for
+    /// longer than the TTL.
+    fn expired(&self, msg: &OutboundMessage) -> bool {
+        let Some((began, ended)) = *self.window.read() else {
+            return false;
+        };
+        msg.queued_at >= began
+            && ended.is_none_or(|ended| msg.queued_at <= ended)
+            && msg.queued_at.elapsed() > self.config.ttl
+    }
+}
+
+/// returns whether the message carries data the offline buffer accounts for.
+fn carries_data(msg: &Message) -> bool {
+    match msg {
+        Message::TransportMessage(tm) => {
+            matches!(tm.message.message_type, SubstreamMessageType::Data(_))
+        }
+        Message::OutOfBandMessage(_) => true,
+        _ => false,
+    }
+}
+
+/// replaces a substream's data frame by a Close of the substream. Returns
+/// whether the message was one.
+fn close_substream(msg: &mut OutboundMessage) -> bool {
+    let Message::TransportMessage(tm) = &mut msg.message else {
+        return false;
+    };
+    if !matches!(tm.message.message_type, SubstreamMessageType::Data(_)) {
+        return false;
+    }
+    tm.message.message_type = SubstreamMessageType::Close;
+    true
+}
+
+/// ConnectionRoute is where the messages of a connection which negotiated
+/// migration are sent: the listener's address if we dialed it, or the
+/// dialer's sender tag otherwise. It changes when the remote migrates to a new
+/// mixnet client; see `MigrateMessage`.
+pub(crate) struct ConnectionRoute {
+    inner: RwLock<(Option<Recipient>, Option<AnonymousSenderTag>)>,
+}
+
+impl ConnectionRoute {
+    pub(crate) fn new(
+        recipient: Option<Recipient>,
+        sender_tag: Option<AnonymousSenderTag>,
+    ) -> Self {
+        ConnectionRoute {
+            inner: RwLock::new((recipient, sender_tag)),
+        }
+    }
+
+    pub(crate) fn set_recipient(&self, recipient: Recipient) {
+        self.inner.write().0 = Some(recipient);
+    }
+
+    pub(crate) fn set_sender_tag(&self, sender_tag: AnonymousSenderTag) {
+        self.inner.write().1 = Some(sender_tag);
+    }
+
+    fn apply(&self, msg: &mut OutboundMessage) {
+        (msg.recipient, msg.sender_tag) = *self.inner.read();
+    }
+}
+
+/// RouteRegistry holds the routes of the connections which negotiated
+/// migration. The transport adds them as the connections are set up, and
+/// the mixnet task sends the messages kept in the outbox where their
+/// connection's route points when they're flushed, rather than where it
+/// pointed when the client refused them.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RouteRegistry {
+    inner: Arc<RwLock<HashMap<ConnectionId, Arc<ConnectionRoute>>>>,
+}
+
+impl RouteRegistry {
+    pub(crate) fn insert(&self, id: ConnectionId, route: Arc<ConnectionRoute>) {
+        self.inner.write().insert(id, route);
+    }
+
+    pub(crate) fn remove(&self, id: &ConnectionId) {
+        self.inner.write().remove(id);
+    }
+
+    /// returns where the messages of the connection go now, if it may migrate.
+    fn get(&self, id: &ConnectionId) -> Option<(Option<Recipient>, Option<AnonymousSenderTag>)> {
+        self.inner.read().get(id).map(|route| *route.inner.read())
+    }
+}
+
+impl Debug for ConnectionRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (recipient, sender_tag) = *self.inner.read();
+        f.debug_struct("ConnectionRoute")
+            .field("recipient", &recipient.map(redact_always))
+            .field("sender_tag", &sender_tag.map(redact_always))
+            .finish()
+    }
+}
+
+/// RateLimiter paces the messages received from a queue to a number per
+/// second, in bursts of up to a second's worth.
+#[derive(Debug)]
+struct RateLimiter {
+    per_second: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    fn new(per_second: u32) -> Self {
+        let per_second = per_second.max(1) as f64;
+        RateLimiter {
+            per_second,
+            tokens: per_second,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let elapsed = self.refilled_at.elapsed().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.per_second);
+        self.refilled_at = Instant::now();
+    }
+
+    /// returns how long it takes until another message may be received.
+    fn ready_in(&mut self) -> Duration {
+        self.refill();
+        if self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - self.tokens) / self.per_second)
+    }
+
+    fn take(&mut self) {
+        self.refill();
+        self.tokens -= 1.0;
+    }
+}
+
+/// DataCount is a number of data messages of the interactive and the bulk
+/// class.
+type DataCount = [u64; 2];
+
+/// ClassQueue is the outbound queue of the data of one traffic class.
+struct ClassQueue {
+    rx: UnboundedReceiver<OutboundMessage>,
+    rate: Option<RateLimiter>,
+    /// the number of messages received so far.
+    received: u64,
+}
+
+impl ClassQueue {
+    fn new(rx: UnboundedReceiver<OutboundMessage>, rate: Option<u32>) -> Self {
+        ClassQueue {
+            rx,
+            rate: rate.map(RateLimiter::new),
+            received: 0,
+        }
+    }
+
+    /// receives the next message once the rate allows it. Dropping the
+    /// future never loses a message: nothing is awaited after receiving one.
+    async fn recv(&mut self) -> Option<OutboundMessage> {
+        if let Some(rate) = &mut self.rate {
+            let delay = rate.ready_in();
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+        let msg = self.rx.recv().await;
+        if msg.is_some() {
+            self.received += 1;
+        }
+        if let (Some(rate), Some(_)) = (&mut self.rate, &msg) {
+            rate.take();
+        }
+        msg
+    }
+
+    fn try_recv(&mut self) -> Option<OutboundMessage> {
+        let msg = self.rx.try_recv().ok()?;
+        self.received += 1;
+        Some(msg)
+    }
+}
+
+/// receives the next data message, preferring interactive data over bulk.
+async fn recv_data(interactive: &mut ClassQueue, bulk: &mut ClassQueue) -> Option<OutboundMessage> {
+    let interactive = interactive.recv().fuse();
+    let bulk = bulk.recv().fuse();
+    pin_mut!(interactive, bulk);
+    select_biased! {
+        msg = interactive => match msg {
+            Some(msg) => Some(msg),
+            None => bulk.await,
+        },
+        msg = bulk => match msg {
+            Some(msg) => Some(msg),
+            None => interactive.await,
+        },
+    }
+}
+
+/// OutboundReceiver is the receiving half of the outbound mixnet channel. It
+/// has three tiers: control messages, eg. handshakes, are received before
+/// any queued data, so they aren't held up by data transfers; and
+/// interactive data is received before bulk data. Each class of data may be
+/// held to a rate; see `TrafficClasses`.
+///
+/// Closes are the exception: they're held back until the data queued before
+/// them was received, as the remote drops whatever arrives after a close.
+pub(crate) struct OutboundReceiver {
+    control_rx: UnboundedReceiver<(DataCount, OutboundMessage)>,
+    interactive: ClassQueue,
+    bulk: ClassQueue,
+    /// closes waiting for the data queued before them, with its count.
+    held_closes: VecDeque<(DataCount, OutboundMessage)>,
+    backlog: Arc<OutboundBacklog>,
+}
+
+impl Drop for OutboundReceiver {
+    fn drop(&mut self) {
+        // the mixnet task stopped, so writers mustn't wait for it
+        self.backlog.close();
+    }
+}
+
+impl OutboundReceiver {
+    /// returns a control message if one is queued, without waiting; closes
+    /// are returned even if data was queued before them.
+    fn try_recv_control(&mut self) -> Option<OutboundMessage> {
+        if let Some((_, msg)) = self.held_closes.pop_front() {
+            return Some(msg);
+        }
+        self.control_rx.try_recv().ok().map(|(_, msg)| msg)
+    }
+
+    /// returns a data message if one is queued, without waiting or regard
+    /// for the rates.
+    fn try_recv_data(&mut self) -> Option<OutboundMessage> {
+        self.interactive.try_recv().or_else(|| self.bulk.try_recv())
+    }
+
+    /// returns whether control messages are waiting.
+    fn control_pending(&self) -> bool {
+        !self.control_rx.is_empty()
+    }
+
+    /// returns whether data queued before the given count is still waiting.
+    fn data_pending(&self, data_queued: &DataCount) -> bool {
+        data_queued[0] > self.interactive.received || data_queued[1] > self.bulk.received
+    }
+
+    /// returns the first held close, once the data before it was received.
+    fn take_held_close(&mut self) -> Option<OutboundMessage> {
+        let (data_queued, _) = self.held_closes.front()?;
+        if self.data_pending(data_queued) {
+            return None;
+        }
+        self.held_closes.pop_front().map(|(_, msg)| msg)
+    }
+
+    pub(crate) async fn recv(&mut self) -> Option<OutboundMessage> {
+        loop {
+            if let Some(msg) = self.take_held_close() {
+                return Some(msg);
+            }
+            let msg = select_biased! {
+                msg = self.control_rx.recv().fuse() => match msg {
+                    Some((data_queued, msg)) => {
+                        if matches!(msg.message, Message::ConnectionClose(_))
+                            && self.data_pending(&data_queued)
+                        {
+                            self.held_closes.push_back((data_queued, msg));
+                            continue;
+                        }
+                        return Some(msg);
+                    }
+                    // all senders were dropped, but there may be data left
+                    None => recv_data(&mut self.interactive, &mut self.bulk).await,
+                },
+                msg = recv_data(&mut self.interactive, &mut self.bulk).fuse() => msg,
+            };
+            // the data senders failed to queue is never received
+            return msg.or_else(|| self.held_closes.pop_front().map(|(_, msg)| msg));
+        }
+    }
+}
+
+/// returns a tiered outbound channel; see `OutboundReceiver`.
+pub(crate) fn outbound_channel(
+    backlog: Arc<OutboundBacklog>,
+    classes: &TrafficClasses,
+) -> (OutboundSender, OutboundReceiver) {
+    let (tx, rx) = unbounded_channel();
+    let (control_tx, control_rx) = unbounded_channel();
+    let (bulk_tx, bulk_rx) = unbounded_channel();
+    let sender = OutboundSender {
+        tx,
+        control_tx: Some(control_tx),
+        data_queued: Arc::default(),
+        bulk_tx: Some(bulk_tx),
+        class: TrafficClass::Interactive,
+        backlog,
+        send_buffer: None,
+        rtt_sampler: None,
+        route: None,
+        alias: None,
+        outage: None,
+        audit_log: None,
+    };
+    let receiver = OutboundReceiver {
+        backlog: sender.backlog.clone(),
+        control_rx,
+        held_closes: VecDeque::new(),
+        interactive: ClassQueue::new(rx, classes.interactive_rate),
+        bulk: ClassQueue::new(bulk_rx, classes.bulk_rate),
+    };
+    (sender, receiver)
+}
+
+/// OutboundExpiry drops outbound data which waited longer than the TTL to be
+/// sent, or than the offline buffer's TTL during an outage. The remote
+/// processes a connection's messages strictly in nonce order, so an expired
+/// message can't just be skipped; instead, it's replaced by a Close for its
+/// substream, as the substream's data is incomplete anyway. The substream is
+/// reset locally too, so that its reads and writes fail.
+pub(crate) struct OutboundExpiry {
+    pub(crate) ttl: Option<Duration>,
+    pub(crate) outage: Option<Arc<Outage>>,
+    pub(crate) connection_stats: ConnectionStatsRegistry,
+    pub(crate) metrics: Arc<TransportMetrics>,
+}
+
+impl OutboundExpiry {
+    fn apply(&self, mut msg: OutboundMessage) -> OutboundMessage {
+        let expired = self.ttl.is_some_and(|ttl| msg.queued_at.elapsed() > ttl)
+            || self
+                .outage
+                .as_ref()
+                .is_some_and(|outage| outage.expired(&msg));
+        if !expired || !close_substream(&mut msg) {
+            return msg;
+        }
+        if let Some(credit) = &msg.write_credit {
+            credit.expired();
+        }
+
+        if let Message::TransportMessage(tm) = &msg.message {
+            debug!(
+                "outbound data with nonce {} expired, closing substream {:?}",
+                tm.nonce, tm.message.substream_id
+            );
+            self.connection_stats.record_expired(&tm.id);
+            TransportMetrics::inc(&self.metrics.messages_expired);
+        }
+        msg
+    }
+}
+
+/// ReplySurbAllocation decides how many reply SURBs are attached to a message
+/// sent to a recipient, ie. by the dialer of a connection, and accounts them
+/// to the connection's SURB budget and the transport's metrics.
+#[derive(Default)]
+pub(crate) struct ReplySurbAllocation {
+    pub(crate) surbs: ReplySurbs,
+    pub(crate) connection_stats: ConnectionStatsRegistry,
+    pub(crate) metrics: Arc<TransportMetrics>,
+}
+
+impl ReplySurbAllocation {
+    fn surbs_for(&self, msg: &Message) -> u32 {
+        // the connection's stats only exist once the handshake completed, so
+        // the SURBs of the ConnectionRequest, or of the ResumeMessage of a
+        // resumed connection, are accounted for by the transport. A migrated
+        // connection is replied to with the SURBs of its MigrateMessage.
+        let surbs = match msg {
+            Message::ConnectionRequest(_) | Message::Resume(_) | Message::Migrate(_) => {
+                self.surbs.handshake
+            }
+            // probes are sent to ourselves, and never replied to
+            Message::Probe(_) => 0,
+            _ => self.surbs.top_up,
+        };
+        self.record_attached(msg, surbs);
+        surbs
+    }
+
+    fn record_attached(&self, msg: &Message, surbs: u32) {
+        let top_up = !matches!(
+            msg,
+            Message::ConnectionRequest(_) | Message::Resume(_) | Message::Migrate(_)
+        );
+        self.connection_stats
+            .record_reply_surbs_attached(msg.connection_id(), surbs, top_up);
+        TransportMetrics::add(&self.metrics.reply_surbs_attached, surbs as u64);
+        if top_up {
+            TransportMetrics::add(&self.metrics.reply_surbs_topped_up, surbs as u64);
+        }
+    }
+}
+
+/// accounts a reply sent over an inbound connection to its SURB budget and
+/// the transport's metrics: it used up one of the dialer's SURBs, unless
+/// there were none left.
+fn record_reply(
+    connection_stats: &ConnectionStatsRegistry,
+    metrics: &TransportMetrics,
+    id: &ConnectionId,
+    res: &Result<(), Error>,
+) {
+    let exhausted = matches!(res, Err(Error::SurbExhausted));
+    if res.is_err() && !exhausted {
+        return;
+    }
+    connection_stats.record_reply(id, exhausted);
+    match exhausted {
+        true => TransportMetrics::inc(&metrics.reply_surbs_exhausted),
+        false => TransportMetrics::inc(&metrics.remote_reply_surbs_used),
+    }
+}
+
+/// DeliveryReport accounts whether the mixnet client accepted each outbound
+/// message to the connection's stats and the transport's metrics, and fails
+/// the next flush of the substream a refused frame was written to.
+///
+/// The SDK's sender only reports whether the client took a message; whether
+/// its gateway received the packets is handled within the client, and isn't
+/// exposed. Delivery to the remote is only confirmed by its acks, see
+/// `NymTransportConfig::congestion_control`.
+#[derive(Default)]
+pub(crate) struct DeliveryReport {
+    pub(crate) connection_stats: ConnectionStatsRegistry,
+    pub(crate) metrics: Arc<TransportMetrics>,
+    pub(crate) audit_log: Option<AuditLog>,
+    /// keeps the data the client refused; see `StoreAndForward`.
+    pub(crate) outbox: Option<Arc<Outbox>>,
+    /// where the messages in the outbox go now.
+    pub(crate) routes: RouteRegistry,
+    /// computes and applies the parity of connections which negotiated
+    /// forward error correction.
+    pub(crate) fec: FecRegistry,
+    /// pads the messages of connections which negotiated padding.
+    pub(crate) padding: PaddingRegistry,
+    /// how long a reply which ran out of SURBs waits to be sent again.
+    pub(crate) surb_retry_delay: Duration,
+    /// the replies which wait to be sent again.
+    pub(crate) retries: ReplyRetries,
+    /// tells the transport about connections whose replies ran out of SURBs
+    /// for good, so it can fail them.
+    pub(crate) surbs_exhausted_tx: Option<UnboundedSender<ConnectionId>>,
+}
+
+impl DeliveryReport {
+    /// keeps a refused message in the outbox, if there is one and the message
+    /// carries data. Returns whether it was kept.
+    fn keep(&self, msg: &OutboundMessage, reply_surbs: u32, bytes: &[u8]) -> bool {
+        let Some(outbox) = &self.outbox else {
+            return false;
+        };
+        if msg.message.is_control() {
+            return false;
+        }
+        outbox.store(StoredMessage::new(
+            msg.message.connection_id().clone(),
+            msg.recipient,
+            msg.sender_tag,
+            reply_surbs,
+            bytes,
+        ));
+        true
+    }
+
+    fn record(&self, msg: &OutboundMessage, res: &Result<(), Error>) {
+        let sent = res.is_ok();
+        if let Some(audit_log) = &self.audit_log {
+            let stage = if sent { Stage::Sent } else { Stage::SendFailed };
+            audit_log.record_message(&msg.message, stage);
+        }
+        self.connection_stats
+            .record_delivery(msg.message.connection_id(), sent);
+        if sent {
+            return;
+        }
+        TransportMetrics::inc(&self.metrics.send_failures);
+        if let Some(credit) = &msg.write_credit {
+            credit.send_failed();
+        }
+    }
+
+    /// holds on to a reply which found no SURBs for its sender tag, to send
+    /// it again after the retry delay. Returns the message if there's no room
+    /// for it.
+    fn retry_reply(&self, msg: OutboundMessage, bytes: &[u8]) -> Option<OutboundMessage> {
+        debug!(
+            "no reply SURBs left for connection {:?}, retrying in {:?}",
+            msg.message.connection_id(),
+            self.surb_retry_delay
+        );
+        self.retries
+            .push(Instant::now() + self.surb_retry_delay, msg, bytes)
+    }
+
+    /// tells the transport that the connection's replies ran out of SURBs
+    /// for good.
+    fn surbs_exhausted(&self, id: &ConnectionId) {
+        if let Some(surbs_exhausted_tx) = &self.surbs_exhausted_tx {
+            // the transport may have been dropped, which is fine.
+            let _ = surbs_exhausted_tx.send(id.clone());
+        }
+    }
+}
+
+/// ReplyRetries holds the replies which found no SURBs for their sender tag,
+/// until they're sent again after the retry delay, once the remote had the
+/// chance to top them up. They're sent by the mixnet task, through the
+/// client which is current by then, and dropped along with it on shutdown.
+/// There are at most `MAX_REPLY_RETRIES` of them, and their bytes are
+/// accounted to the transport's memory budget. A retried reply keeps its
+/// write credit, so its write is only reported once the retry ran.
+#[derive(Default)]
+pub(crate) struct ReplyRetries {
+    queue: Mutex<VecDeque<ReplyRetry>>,
+    budget: Arc<MemoryBudget>,
+}
+
+struct ReplyRetry {
+    due: Instant,
+    message: OutboundMessage,
+    bytes: Vec<u8>,
+}
+
+impl ReplyRetries {
+    pub(crate) fn new(budget: Arc<MemoryBudget>) -> Self {
+        ReplyRetries {
+            queue: Default::default(),
+            budget,
+        }
+    }
+
+    fn push(
+        &self,
+        due: Instant,
+        message: OutboundMessage,
+        bytes: &[u8],
+    ) -> Option<OutboundMessage> {
+        let mut queue = self.queue.lock();
+        if queue.len() >= MAX_REPLY_RETRIES
+            || !self.budget.try_reserve(BufferKind::Unacked, bytes.len())
+        {
+            return Some(message);
+        }
+        queue.push_back(ReplyRetry {
+            due,
+            message,
+            bytes: bytes.to_vec(),
+        });
+        None
+    }
+
+    /// waits until the first retry is due. They all wait the same delay, so
+    /// it's the oldest one.
+    async fn first_due(&self) {
+        let due = self.queue.lock().front().map(|retry| retry.due);
+        match due {
+            Some(due) => tokio::time::sleep_until(due.into()).await,
+            None => future::pending().await,
+        }
+    }
+
+    /// takes the retries which are due, oldest first.
+    fn take_due(&self) -> Vec<ReplyRetry> {
+        let now = Instant::now();
+        let mut queue = self.queue.lock();
+        let mut due = vec![];
+        while queue.front().is_some_and(|retry| retry.due <= now) {
+            let retry = queue.pop_front().unwrap();
+            self.budget.release(retry.bytes.len());
+            due.push(retry);
+        }
+        due
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.queue.lock().len()
+    }
+}
+
+impl Drop for ReplyRetries {
+    /// releases the bytes of the retries which are cancelled.
+    fn drop(&mut self) {
+        for retry in self.queue.get_mut().drain(..) {
+            self.budget.release(retry.bytes.len());
+        }
+    }
+}
+
+/// sends the replies whose retry is due, and reports their writes. A reply
+/// which finds no SURBs again fails its connection.
+async fn retry_replies(
+    mixnet_sender: &Arc<dyn MixnetDriverSender>,
+    replies: Option<&ReplyRoute>,
+    aliases: &AliasSinks,
+    delivery: &DeliveryReport,
+) {
+    for retry in delivery.retries.take_due() {
+        let message = retry.message;
+        let id = message.message.connection_id();
+        let Some(sender) = sender_for(mixnet_sender, replies, aliases, &message) else {
+            debug!("dropping a retried reply to a removed address alias");
+            continue;
+        };
+        let res = route_bytes(
+            sender,
+            message.recipient,
+            message.sender_tag,
+            0,
+            &retry.bytes,
+        )
+        .await;
+        record_reply(&delivery.connection_stats, &delivery.metrics, id, &res);
+        match &res {
+            Ok(()) => {}
+            Err(Error::SurbExhausted) => {
+                warn!("no reply SURBs left for connection {:?}", id);
+                delivery.surbs_exhausted(id);
+            }
+            Err(e) => warn!("failed to retry a reply on connection {:?}: {}", id, e),
+        }
+        delivery.record(&message, &res);
+    }
+}
+
+/// Failover replaces the mixnet client once it lost its gateway; see
+/// `GatewayFailover`.
+#[cfg(feature = "nym-client")]
+pub(crate) struct Failover {
+    pub(crate) config: GatewayFailover,
+    /// the ephemeral client which is connected to each fallback gateway.
+    pub(crate) client: ManagedMixnetClient,
+    /// the index of the fallback gateway to try next.
+    pub(crate) next_gateway: usize,
+    pub(crate) metrics: Arc<TransportMetrics>,
+}
+
+#[cfg(feature = "nym-client")]
+impl Failover {
+    /// returns a new client, connected to the first gateway which accepts
+    /// it. Every gateway is tried once per round, with backoff between rounds.
+    /// None once `max_rounds` rounds failed.
+    async fn reconnect(&mut self) -> Option<MixnetClient> {
+        let mut backoff = self.config.retry.initial_backoff;
+        let mut rounds = 0;
+        loop {
+            for _ in 0..self.config.gateways.len().max(1) {
+                let gateway = self.take_gateway();
+                match self.connect(gateway.clone()).await {
+                    Ok(client) => {
+                        TransportMetrics::inc(&self.metrics.gateway_failovers);
+                        return Some(client);
+                    }
+                    Err(e) => warn!(
+                        "failed to connect to gateway {}: {}",
+                        gateway.as_deref().unwrap_or("of the client's choice"),
+                        e
+                    ),
+                }
+            }
+            rounds += 1;
+            if self.config.max_rounds.is_some_and(|max| rounds >= max) {
+                warn!("giving up on failover after {} rounds", rounds);
+                return None;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = self.config.retry.next_backoff(backoff);
+        }
+    }
+
+    /// returns the fallback gateway to try next, if any are configured.
+    fn take_gateway(&mut self) -> Option<String> {
+        if self.config.gateways.is_empty() {
+            return None;
+        }
+        let gateway = self.config.gateways[self.next_gateway % self.config.gateways.len()].clone();
+        self.next_gateway = self.next_gateway.wrapping_add(1);
+        Some(gateway)
+    }
+
+    async fn connect(&self, gateway: Option<String>) -> Result<MixnetClient, Error> {
+        let mut client = self.client.clone();
+        client.gateway = gateway;
+        client.try_connect().await
+    }
+}
+
+/// ClientSwitch is how the mixnet task's client is replaced while it runs:
+/// by the application, see `MixnetClientHandle`, or by failover, if enabled.
+pub(crate) struct ClientSwitch {
+    pub(crate) replace_rx: UnboundedReceiver<Box<dyn MixnetDriver>>,
+    #[cfg(feature = "nym-client")]
+    pub(crate) failover: Option<Failover>,
+    /// the clients to rotate to, if address rotation is enabled.
+    pub(crate) rotation: Option<Rotation>,
+    /// the clients of the address aliases added and removed by the
+    /// application; see `AddressAliases`.
+    pub(crate) alias_rx: UnboundedReceiver<AliasCommand>,
+    /// the transport is told the address of every new client here.
+    pub(crate) address_tx: UnboundedSender<AddressChange>,
+}
+
+impl ClientSwitch {
+    /// returns the next client handed over by the application, or to rotate
+    /// to, or the next alias added or removed.
+    async fn replacement(switch: &mut Option<ClientSwitch>) -> PumpEvent {
+        let Some(ClientSwitch {
+            replace_rx,
+            rotation,
+            alias_rx,
+            ..
+        }) = switch.as_mut()
+        else {
+            return future::pending().await;
+        };
+        let replaced = async {
+            match replace_rx.recv().await {
+                Some(client) => PumpEvent::Replaced(client),
+                None => future::pending().await,
+            }
+        }
+        .fuse();
+        let rotated = async {
+            match rotation {
+                Some(rotation) => match rotation.clients_rx.recv().await {
+                    Some(client) => PumpEvent::Rotated(client),
+                    None => future::pending().await,
+                },
+                None => future::pending().await,
+            }
+        }
+        .fuse();
+        let aliased = async {
+            match alias_rx.recv().await {
+                Some(AliasCommand::Add(id, client)) => PumpEvent::AliasAdded(id, client),
+                Some(AliasCommand::Remove(id)) => PumpEvent::AliasRemoved(id),
+                None => future::pending().await,
+            }
+        }
+        .fuse();
+        pin_mut!(replaced, rotated, aliased);
+
+        select! {
+            event = replaced => event,
+            event = rotated => event,
+            event = aliased => event,
+        }
+    }
+
+    /// returns a client to replace one which lost its gateway: the first one
+    /// handed over by the application, or connected by failover. None if
+    /// neither can happen anymore, eg. because failover gave up and the
+    /// application dropped its `MixnetClientHandle`.
+    async fn reconnect(switch: &mut Option<ClientSwitch>) -> Option<Box<dyn MixnetDriver>> {
+        let ClientSwitch {
+            replace_rx,
+            #[cfg(feature = "nym-client")]
+            failover,
+            ..
+        } = switch.as_mut()?;
+        let failover = async {
+            #[cfg(feature = "nym-client")]
+            if let Some(failover) = failover {
+                return failover
+                    .reconnect()
+                    .await
+                    .map(|client| Box::new(client) as Box<dyn MixnetDriver>);
+            }
+            future::pending().await
+        }
+        .fuse();
+        let replaced = replace_rx.recv().fuse();
+        pin_mut!(failover, replaced);
+
+        select! {
+            client = failover => match client {
+                Some(client) => Some(client),
+                // failover gave up, but the application may still hand one over
+                None => replaced.await,
+            },
+            client = replaced => client,
+        }
+    }
+}
+
+/// AddressChange is what the mixnet task tells the transport about the
+/// addresses it can be reached at.
+#[derive(Clone, Copy)]
+pub(crate) enum AddressChange {
+    /// the client was replaced by one with the given address, and the old
+    /// one is gone.
+    Replaced(Recipient),
+    /// the client was rotated to one with the given address; the old one
+    /// keeps receiving until it's retired.
+    Rotated(Recipient),
+    /// the client rotated away from, with the given address, was disconnected.
+    Retired(Recipient),
+    /// the client of the given alias was removed, or lost its gateway.
+    AliasRemoved(AliasId),
+}
+
+/// Rotation hands the mixnet task the clients it rotates to; see
+/// `AddressRotation`.
+pub(crate) struct Rotation {
+    pub(crate) clients_rx: UnboundedReceiver<Box<dyn MixnetDriver>>,
+    /// how long the old client keeps receiving after a rotation.
+    pub(crate) grace_period: Duration,
+}
+
+impl Rotation {
+    /// spawns a task which connects a new, ephemeral client every interval
+    /// and hands it to the mixnet task, until the mixnet task stopped. A
+    /// rotation which failed to connect is retried at the next interval.
+    #[cfg(feature = "nym-client")]
+    pub(crate) fn spawn(client: ManagedMixnetClient, config: AddressRotation) -> Self {
+        let (clients_tx, clients_rx) = unbounded_channel::<Box<dyn MixnetDriver>>();
+        tokio::spawn(async move {
+            loop {
+                {
+                    let interval = sleep(config.interval).fuse();
+                    let stopped = clients_tx.closed().fuse();
+                    pin_mut!(interval, stopped);
+                    select! {
+                        _ = interval => {}
+                        _ = stopped => return,
+                    }
+                }
+                let client = match client.connect().await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        warn!("failed to connect a mixnet client to rotate to: {}", e);
+                        continue;
+                    }
+                };
+                if let Err(rejected) = clients_tx.send(Box::new(client)) {
+                    rejected.0.disconnect().await;
+                    return;
+                }
+            }
+        });
+        Rotation {
+            clients_rx,
+            grace_period: config.grace_period,
+        }
+    }
+}
+
+/// Retiring is the client rotated away from, which keeps receiving the
+/// messages sent to its address until its grace period is over.
+struct Retiring {
+    driver: Box<dyn MixnetDriver>,
+    deadline: Pin<Box<Sleep>>,
+    replies: Arc<ReplyRoute>,
+}
+
+impl Retiring {
+    /// hands the messages sent to the old address to the transport, until
+    /// the grace period is over or the client lost its gateway.
+    async fn receive(
+        &mut self,
+        inbound_tx: &UnboundedSender<InboundMessage>,
+        notify_inbound_tx: &Option<UnboundedSender<()>>,
+        fec: &FecRegistry,
+        chaos: &Chaos,
+    ) -> PumpEvent {
+        let inbound = check_inbound(
+            self.driver.as_mut(),
+            inbound_tx,
+            notify_inbound_tx,
+            fec,
+            chaos,
+            None,
+        )
+        .fuse();
+        let deadline = self.deadline.as_mut().fuse();
+        pin_mut!(inbound, deadline);
+
+        select! {
+            res = inbound => match res {
+                Err(Error::GatewayDisconnected) => PumpEvent::Retired,
+                _ => PumpEvent::Handled,
+            },
+            _ = deadline => PumpEvent::Retired,
+        }
+    }
+
+    /// disconnects the client, and tells the transport its address expired.
+    async fn retire(self, switch: &Option<ClientSwitch>) {
+        info!("retiring the mixnet client rotated away from");
+        let address = self.driver.address();
+        self.driver.disconnect().await;
+        if let Some(switch) = switch {
+            // the transport may have been dropped, which is fine.
+            let _ = switch.address_tx.send(AddressChange::Retired(address));
+        }
+    }
+}
+
+/// moves the mixnet task to the client rotated to, and returns the one
+/// rotated away from, which keeps receiving for the grace period.
+fn rotate(
+    client: Box<dyn MixnetDriver>,
+    driver: &mut Box<dyn MixnetDriver>,
+    sink: &mut Arc<dyn MixnetDriverSender>,
+    switch: &Option<ClientSwitch>,
+    metrics: &TransportMetrics,
+) -> Retiring {
+    info!("rotating to a new mixnet client");
+    let grace_period = switch
+        .as_ref()
+        .and_then(|switch| switch.rotation.as_ref())
+        .map_or(Duration::ZERO, |rotation| rotation.grace_period);
+    let address = client.address();
+    let old_sink = std::mem::replace(sink, client.sender());
+    let retiring = Retiring {
+        driver: std::mem::replace(driver, client),
+        deadline: Box::pin(sleep(grace_period)),
+        replies: Arc::new(ReplyRoute {
+            sink: old_sink,
+            current_tags: Mutex::default(),
+        }),
+    };
+    TransportMetrics::inc(&metrics.address_rotations);
+    if let Some(switch) = switch {
+        // the transport may have been dropped, which is fine.
+        let _ = switch.address_tx.send(AddressChange::Rotated(address));
+    }
+    retiring
+}
+
+/// ReplyRoute sends the replies over SURBs the retiring client received with
+/// it, since the current client doesn't hold them.
+struct ReplyRoute {
+    sink: Arc<dyn MixnetDriverSender>,
+    /// the sender tags the current client received messages with since the
+    /// rotation; replies to any other tag are sent by the retiring client.
+    current_tags: Mutex<HashSet<AnonymousSenderTag>>,
+}
+
+/// AliasSinks sends the replies on connections made to an address alias with
+/// the alias's client.
+#[derive(Default)]
+struct AliasSinks {
+    sinks: HashMap<AliasId, Arc<dyn MixnetDriverSender>>,
+}
+
+/// receives the next message at any of the aliases, and tags it with the
+/// alias, so that the replies to it go out through the same one.
+async fn check_aliases(
+    drivers: &mut [(AliasId, Box<dyn MixnetDriver>)],
+    inbound_tx: &UnboundedSender<InboundMessage>,
+    notify_inbound_tx: &Option<UnboundedSender<()>>,
+    fec: &FecRegistry,
+) -> PumpEvent {
+    if drivers.is_empty() {
+        return future::pending().await;
+    }
+    let receives = drivers.iter_mut().map(|(id, driver)| {
+        let id = *id;
+        async move { (id, driver.next().await) }.boxed()
+    });
+    let ((id, msg), _, _) = future::select_all(receives).await;
+    let Some(msg) = msg else {
+        return PumpEvent::AliasRemoved(id);
+    };
+    if let Some(notify_tx) = notify_inbound_tx {
+        let _ = notify_tx.send(());
+    }
+    if let Err(e) = handle_inbound(msg, Some(id), inbound_tx, fec).await {
+        debug!("failed to handle a message sent to an alias: {}", e);
+    }
+    PumpEvent::Handled
+}
+
+/// returns the client to send the given message with: the alias's for
+/// replies on connections made to an alias, the retiring one for replies to
+/// the sender tags it received, the current one otherwise. None if the message is a reply
+/// to an alias which was removed, since no other client holds its SURBs.
+fn sender_for<'a>(
+    sink: &'a Arc<dyn MixnetDriverSender>,
+    replies: Option<&'a ReplyRoute>,
+    aliases: &'a AliasSinks,
+    message: &OutboundMessage,
+) -> Option<&'a Arc<dyn MixnetDriverSender>> {
+    if let Some(id) = message.alias {
+        return aliases.sinks.get(&id);
+    }
+    match (replies, message.sender_tag) {
+        (Some(replies), Some(sender_tag)) if !replies.current_tags.lock().contains(&sender_tag) => {
+            Some(&replies.sink)
+        }
+        _ => Some(sink),
+    }
+}
+
+/// PumpEvent is what interrupted the mixnet task's inbound and outbound pumps.
+enum PumpEvent {
+    /// a message was handled, or failed to be.
+    Handled,
+    /// the transport queued a message to send.
+    Outbound(Box<OutboundMessage>),
+    /// the message being sent was handed to the client, or failed to be;
+    /// with the encoding buffer, and whether the client took it.
+    Sent(Vec<u8>, bool),
+    /// the client lost its gateway.
+    Disconnected,
+    /// the application handed over a new client.
+    Replaced(Box<dyn MixnetDriver>),
+    /// a client with a new address to rotate to was connected.
+    Rotated(Box<dyn MixnetDriver>),
+    /// the grace period of the client rotated away from is over.
+    Retired,
+    /// the application added an address alias with the given client.
+    AliasAdded(AliasId, Box<dyn MixnetDriver>),
+    /// the application removed an address alias, or its client lost its gateway.
+    AliasRemoved(AliasId),
+    /// the transport moved its connections to the new client.
+    Migrated,
+    /// replies which ran out of SURBs are due to be sent again.
+    RetryReplies,
+    /// the transport was dropped.
+    Shutdown,
+}
+
+/// MixnetTask is the handle of the task initialize_mixnet spawns. Dropping
+/// it stops the task: the close messages already queued are sent, and the
+/// client is disconnected if the transport owns it.
+pub(crate) struct MixnetTask {
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    handle: JoinHandle<()>,
+}
+
+impl MixnetTask {
+    /// stops the task, and waits until the client is disconnected.
+    pub(crate) async fn shutdown(mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+        if let Err(e) = (&mut self.handle).await {
+            warn!("the mixnet task failed: {}", e);
+        }
+    }
+}
+
+impl Drop for MixnetTask {
+    fn drop(&mut self) {
+        // the task may have stopped already
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+    }
+}
+
+/// MixnetSource is the mixnet client the transport runs on: either a
+/// nym-sdk client it owns, or any other driver, eg. a client the application
+/// shares with it; see `SharedClient`.
+pub(crate) enum MixnetSource {
+    #[cfg(feature = "nym-client")]
+    Client(MixnetClient),
+    Driver(Box<dyn MixnetDriver>),
+}
+
+#[cfg(feature = "nym-client")]
+impl From<MixnetClient> for MixnetSource {
+    fn from(client: MixnetClient) -> Self {
+        MixnetSource::Client(client)
+    }
+}
+
+impl MixnetSource {
+    fn into_driver(self) -> Box<dyn MixnetDriver> {
+        match self {
+            #[cfg(feature = "nym-client")]
+            MixnetSource::Client(client) => Box::new(client),
+            MixnetSource::Driver(driver) => driver,
+        }
+    }
+}
+
+/// initialize_mixnet initializes a read/write connection to a Nym Client.
+/// It starts a task that listens for inbound messages from the endpoint and writes outbound messages to the endpoint,
+/// which runs until the returned MixnetTask is dropped.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn initialize_mixnet(
+    source: impl Into<MixnetSource>,
+    notify_inbound_tx: Option<UnboundedSender<()>>,
+    expiry: Option<OutboundExpiry>,
+    backlog: Arc<OutboundBacklog>,
+    classes: &TrafficClasses,
+    reply_surbs: ReplySurbAllocation,
+    redundancy: Option<Redundancy>,
+    delivery: DeliveryReport,
+    chaos: Chaos,
+    mut switch: Option<ClientSwitch>,
+) -> Result<
+    (
+        Recipient,
+        UnboundedReceiver<InboundMessage>,
+        OutboundSender,
+        MixnetTask,
+    ),
+    Error,
+> {
+    let mut driver = source.into().into_driver();
+    let recipient = driver.address();
+    let mut sink = driver.sender();
+
+    // a channel of inbound messages from the mixnet..
+    // the transport reads from (listens) to the inbound_rx.
+    // TODO: this is probably a DOS vector; we should limit the size of the channel.
+    let (inbound_tx, inbound_rx) = unbounded_channel::<InboundMessage>();
+
+    // a channel of outbound messages to be written to the mixnet.
+    // the transport writes to outbound_tx.
+    let (outbound_tx, mut outbound_rx) = outbound_channel(backlog.clone(), classes);
+    let outage = expiry.as_ref().and_then(|expiry| expiry.outage.clone());
+    let outbound_tx = outbound_tx.with_outage(outage.clone());
+
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+    let handle = tokio::task::spawn(async move {
+        let mut encode_buf = vec![];
+        let mut retiring: Option<Retiring> = None;
+        // a rotation which arrived while the previous one was still retiring
+        let mut deferred_rotation: Option<Box<dyn MixnetDriver>> = None;
+        let mut alias_drivers: Vec<(AliasId, Box<dyn MixnetDriver>)> = vec![];
+        let mut aliases = AliasSinks::default();
+        // set once the transport migrated its connections to a new client
+        let mut migrated = false;
+        // the message being handed to the client, which may wait for the
+        // client's backpressure. It's kept across the iterations, since
+        // dropping it would lose the message.
+        let mut sending: Option<BoxFuture<'_, (Vec<u8>, bool)>> = None;
+        loop {
+            // the outbox follows the MigrateMessages, which are control messages
+            if migrated && sending.is_none() && !outbound_rx.control_pending() {
+                migrated = false;
+                if let Some(outbox) = &delivery.outbox {
+                    outbox.release();
+                }
+                flush_outbox(&sink, &delivery).await;
+            }
+            let event = {
+                let replies = retiring.as_ref().map(|retiring| retiring.replies.clone());
+                let t1 = check_inbound(
+                    driver.as_mut(),
+                    &inbound_tx,
+                    &notify_inbound_tx,
+                    &delivery.fec,
+                    &chaos,
+                    replies.as_deref().map(|replies| &replies.current_tags),
+                )
+                .fuse();
+                let t2 = async {
+                    match sending.as_mut() {
+                        Some(send) => {
+                            let (buf, sent) = send.await;
+                            PumpEvent::Sent(buf, sent)
+                        }
+                        None => match outbound_rx.recv().await {
+                            Some(message) => PumpEvent::Outbound(Box::new(message)),
+                            None => PumpEvent::Handled,
+                        },
+                    }
+                }
+                .fuse();
+
+                let t3 = ClientSwitch::replacement(&mut switch).fuse();
+                let t4 = (&mut shutdown_rx).fuse();
+                let t5 = async {
+                    match &mut retiring {
+                        Some(retiring) => {
+                            retiring
+                                .receive(&inbound_tx, &notify_inbound_tx, &delivery.fec, &chaos)
+                                .await
+                        }
+                        None => future::pending().await,
+                    }
+                }
+                .fuse();
+                let t6 = check_aliases(
+                    &mut alias_drivers,
+                    &inbound_tx,
+                    &notify_inbound_tx,
+                    &delivery.fec,
+                )
+                .fuse();
+                let t7 = async {
+                    match &delivery.outbox {
+                        Some(outbox) => outbox.wait_migrated().await,
+                        None => future::pending().await,
+                    }
+                }
+                .fuse();
+
+                let t8 = delivery.retries.first_due().fuse();
+
+                pin_mut!(t1, t2, t3, t4, t5, t6, t7, t8);
+
+                select! {
+                    res = t1 => match res {
+                        Err(Error::GatewayDisconnected) => PumpEvent::Disconnected,
+                        _ => PumpEvent::Handled,
+                    },
+                    event = t2 => event,
+                    event = t3 => event,
+                    _ = t4 => PumpEvent::Shutdown,
+                    event = t5 => event,
+                    event = t6 => event,
+                    _ = t7 => PumpEvent::Migrated,
+                    _ = t8 => PumpEvent::RetryReplies,
+                }
+            };
+
+            // outbound messages wait in the channel while the client is
+            // replaced, bounded by the offline buffer if there is one
+            let client = match event {
+                PumpEvent::Handled => continue,
+                PumpEvent::Outbound(message) => {
+                    let replies = retiring.as_ref().map(|retiring| &*retiring.replies);
+                    let sender = sender_for(&sink, replies, &aliases, &message).cloned();
+                    sending = Some(
+                        send_received(
+                            sender,
+                            *message,
+                            &backlog,
+                            &expiry,
+                            &reply_surbs,
+                            &redundancy,
+                            &delivery,
+                            &chaos,
+                            std::mem::take(&mut encode_buf),
+                        )
+                        .boxed(),
+                    );
+                    continue;
+                }
+                PumpEvent::Sent(buf, sent) => {
+                    encode_buf = buf;
+                    sending = None;
+                    if sent {
+                        // the client takes messages again
+                        flush_outbox(&sink, &delivery).await;
+                    }
+                    continue;
+                }
+                PumpEvent::Replaced(client) => {
+                    info!("replacing the mixnet client");
+                    client
+                }
+                PumpEvent::Rotated(client) => {
+                    if retiring.is_some() {
+                        // the previous client's grace period isn't over yet
+                        debug!("deferring the rotation until the previous client retired");
+                        if let Some(stale) = deferred_rotation.replace(client) {
+                            stale.disconnect().await;
+                        }
+                        continue;
+                    }
+                    retiring = Some(rotate(
+                        client,
+                        &mut driver,
+                        &mut sink,
+                        &switch,
+                        &delivery.metrics,
+                    ));
+                    continue;
+                }
+                PumpEvent::Retired => {
+                    if let Some(retiring) = retiring.take() {
+                        retiring.retire(&switch).await;
+                    }
+                    if let Some(client) = deferred_rotation.take() {
+                        retiring = Some(rotate(
+                            client,
+                            &mut driver,
+                            &mut sink,
+                            &switch,
+                            &delivery.metrics,
+                        ));
+                    }
+                    continue;
+                }
+                PumpEvent::AliasAdded(id, client) => {
+                    info!("receiving at a new address alias");
+                    aliases.sinks.insert(id, client.sender());
+                    alias_drivers.push((id, client));
+                    continue;
+                }
+                PumpEvent::Migrated => {
+                    migrated = true;
+                    continue;
+                }
+                PumpEvent::RetryReplies => {
+                    let replies = retiring.as_ref().map(|retiring| &*retiring.replies);
+                    retry_replies(&sink, replies, &aliases, &delivery).await;
+                    continue;
+                }
+                PumpEvent::AliasRemoved(id) => {
+                    info!("no longer receiving at an address alias");
+                    aliases.sinks.remove(&id);
+                    let index = alias_drivers.iter().position(|(alias, _)| *alias == id);
+                    if let Some(index) = index {
+                        alias_drivers.swap_remove(index).1.disconnect().await;
+                    }
+                    if let Some(switch) = &switch {
+                        // the transport may have been dropped, which is fine.
+                        let _ = switch.address_tx.send(AddressChange::AliasRemoved(id));
+                    }
+                    continue;
+                }
+                PumpEvent::Disconnected => {
+                    warn!("the mixnet client lost its gateway");
+                    if let Some(outage) = &outage {
+                        outage.begin();
+                    }
+                    // the transport may be stopped while we wait for a client
+                    let reconnect = ClientSwitch::reconnect(&mut switch).fuse();
+                    let shutdown = (&mut shutdown_rx).fuse();
+                    pin_mut!(reconnect, shutdown);
+                    let client = select! {
+                        client = reconnect => {
+                            if client.is_none() {
+                                warn!("no client to replace the disconnected one with, stopping");
+                            }
+                            client
+                        }
+                        _ = shutdown => {
+                            debug!("stopping the mixnet task while reconnecting");
+                            None
+                        }
+                    };
+                    let Some(client) = client else {
+                        if let Some(send) = sending.take() {
+                            send.await;
+                        }
+                        save_outbox(&mut outbound_rx, &backlog, &reply_surbs, &delivery);
+                        driver.disconnect().await;
+                        if let Some(retiring) = retiring.take() {
+                            retiring.driver.disconnect().await;
+                        }
+                        if let Some(client) = deferred_rotation.take() {
+                            client.disconnect().await;
+                        }
+                        for (_, alias) in alias_drivers {
+                            alias.disconnect().await;
+                        }
+                        return;
+                    };
+                    info!("replaced the disconnected mixnet client");
+                    client
+                }
+                PumpEvent::Shutdown => {
+                    if let Some(send) = sending.take() {
+                        encode_buf = send.await.0;
+                    }
+                    // the transport queued closes for its connections
+                    while let Some(message) = outbound_rx.try_recv_control() {
+                        let replies = retiring.as_ref().map(|retiring| &*retiring.replies);
+                        let Some(sender) = sender_for(&sink, replies, &aliases, &message) else {
+                            backlog.dequeued(message.queued_at);
+                            continue;
+                        };
+                        let res = send_outbound(
+                            sender,
+                            message,
+                            &backlog,
+                            &expiry,
+                            &reply_surbs,
+                            &redundancy,
+                            &delivery,
+                            &chaos,
+                            &mut encode_buf,
+                        )
+                        .await;
+                        if let Err(e) = res {
+                            debug!("failed to send message on shutdown: {}", e);
+                        }
+                    }
+                    save_outbox(&mut outbound_rx, &backlog, &reply_surbs, &delivery);
+                    debug!("stopping the mixnet task");
+                    driver.disconnect().await;
+                    if let Some(retiring) = retiring.take() {
+                        retiring.driver.disconnect().await;
+                    }
+                    if let Some(client) = deferred_rotation.take() {
+                        client.disconnect().await;
+                    }
+                    for (_, alias) in alias_drivers {
+                        alias.disconnect().await;
+                    }
+                    return;
+                }
+            };
+            sink = client.sender();
+            if let Some(outage) = &outage {
+                outage.end();
+            }
+            // the outbox is sent once the transport migrated its connections
+            if let Some(outbox) = &delivery.outbox {
+                outbox.hold();
+            }
+            if let Some(retiring) = &retiring {
+                // the new client received nothing yet
+                retiring.replies.current_tags.lock().clear();
+            }
+            if let Some(switch) = &switch {
+                // the transport may have been dropped, which is fine.
+                let _ = switch
+                    .address_tx
+                    .send(AddressChange::Replaced(client.address()));
+            }
+            // a shared client is left to the application
+            std::mem::replace(&mut driver, client).disconnect().await;
+        }
+    });
+
+    let task = MixnetTask {
+        shutdown_tx: Some(shutdown_tx),
+        handle,
+    };
+    Ok((recipient, inbound_rx, outbound_tx, task))
+}
+
+async fn check_inbound(
+    inbound: &mut dyn MixnetDriver,
+    inbound_tx: &UnboundedSender<InboundMessage>,
+    notify_inbound_tx: &Option<UnboundedSender<()>>,
+    fec: &FecRegistry,
+    chaos: &Chaos,
+    current_tags: Option<&Mutex<HashSet<AnonymousSenderTag>>>,
+) -> Result<(), Error> {
+    // the client's stream ends once it's disconnected from its gateway, and
+    // a shared client's once the application stops forwarding messages
+    let Some(mut msg) = inbound.next().await else {
+        return Err(Error::GatewayDisconnected);
+    };
+    if let (Some(current_tags), Some(sender_tag)) = (current_tags, msg.sender_tag) {
+        current_tags.lock().insert(sender_tag);
+    }
+    let fault = chaos.inbound();
+    if fault == Some(Fault::Drop) {
+        return Ok(());
+    }
+    if let Some(notify_tx) = notify_inbound_tx {
+        notify_tx
+            .send(())
+            .map_err(|e| Error::InboundSendFailure(e.to_string()))?;
+    }
+
+    match fault {
+        Some(Fault::Duplicate) => {
+            let copy = ReconstructedMessage {
+                message: msg.message.clone(),
+                sender_tag: msg.sender_tag,
+            };
+            handle_inbound(copy, None, inbound_tx, fec).await?;
+        }
+        Some(Fault::Delay(delay)) => {
+            let inbound_tx = inbound_tx.clone();
+            let fec = fec.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                if let Err(e) = handle_inbound(msg, None, &inbound_tx, &fec).await {
+                    debug!("failed to handle delayed inbound message: {e}");
+                }
+            });
+            return Ok(());
+        }
+        Some(Fault::Corrupt) => corrupt(&mut msg.message),
+        Some(Fault::Drop) | None => {}
+    }
+    handle_inbound(msg, None, inbound_tx, fec).await?;
+
+    Err(Error::Unimplemented)
+}
+
+/// hands an inbound message to the transport, tagged with the alias it
+/// arrived at, if any.
+async fn handle_inbound(
+    msg: ReconstructedMessage,
+    alias: Option<AliasId>,
+    inbound_tx: &UnboundedSender<InboundMessage>,
+    fec: &FecRegistry,
+) -> Result<(), Error> {
+    let sender_tag = msg.sender_tag;
+
+    // parity covers messages as they were before being padded
+    let bytes = strip_padding(Bytes::from(msg.message))?;
+    let mut data = parse_message_data(bytes.clone(), sender_tag)?;
+    data.2 = alias;
+    // the messages rebuilt from parity follow the one which completed them
+    let recovered = fec.on_received(&data.0, &bytes);
+    if !matches!(data.0, Message::Parity(_)) {
+        inbound_tx
+            .send(data)
+            .map_err(|e| Error::InboundSendFailure(e.to_string()))?;
+    }
+    for bytes in recovered {
+        match parse_message_data(bytes, sender_tag) {
+            Ok(InboundMessage(msg, sender_tag, _)) => inbound_tx
+                .send(InboundMessage(msg, sender_tag, alias))
+                .map_err(|e| Error::InboundSendFailure(e.to_string()))?,
+            Err(e) => debug!("failed to decode a recovered message: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// hands a message received from the transport to the client it goes out
+/// with, and returns the encoding buffer and whether the client took it.
+#[allow(clippy::too_many_arguments)]
+async fn send_received(
+    sender: Option<Arc<dyn MixnetDriverSender>>,
+    message: OutboundMessage,
+    backlog: &OutboundBacklog,
+    expiry: &Option<OutboundExpiry>,
+    reply_surbs: &ReplySurbAllocation,
+    redundancy: &Option<Redundancy>,
+    delivery: &DeliveryReport,
+    chaos: &Chaos,
+    mut encode_buf: Vec<u8>,
+) -> (Vec<u8>, bool) {
+    let Some(sender) = sender else {
+        debug!("dropping a reply to a removed address alias");
+        backlog.dequeued(message.queued_at);
+        return (encode_buf, false);
+    };
+    let res = send_outbound(
+        &sender,
+        message,
+        backlog,
+        expiry,
+        reply_surbs,
+        redundancy,
+        delivery,
+        chaos,
+        &mut encode_buf,
+    )
+    .await;
+    if let Err(e) = &res {
+        debug!("failed to send an outbound message: {}", e);
+    }
+    (encode_buf, res.is_ok())
+}
+
+/// encodes a message and hands it to the mixnet client.
+#[allow(clippy::too_many_arguments)]
+async fn send_outbound(
+    mixnet_sender: &Arc<dyn MixnetDriverSender>,
+    message: OutboundMessage,
+    backlog: &OutboundBacklog,
+    expiry: &Option<OutboundExpiry>,
+    reply_surbs: &ReplySurbAllocation,
+    redundancy: &Option<Redundancy>,
+    delivery: &DeliveryReport,
+    chaos: &Chaos,
+    encode_buf: &mut Vec<u8>,
+) -> Result<(), Error> {
+    backlog.dequeued(message.queued_at);
+    let message = match expiry {
+        Some(expiry) => expiry.apply(message),
+        None => message,
+    };
+    if encode_buf.capacity() > MAX_RETAINED_ENCODE_BUFFER {
+        *encode_buf = vec![];
+    }
+    encode_buf.clear();
+    message.encode_into(encode_buf)?;
+    let padded = delivery.padding.pad(&message.message, encode_buf);
+    let bytes = &*padded;
+    match &message.message {
+        Message::TransportMessage(tm) => {
+            match &tm.message.message_type {
+                SubstreamMessageType::OpenResponse => {
+                    debug!("Outbound OpenResponse: nonce={}, substream={:?}, has_surb={}, has_recipient={}",
+                                       tm.nonce, tm.message.substream_id,
+                                       message.sender_tag.is_some(), message.recipient.is_some());
+                }
+                SubstreamMessageType::OpenRequest => {
+                    debug!("Outbound OpenRequest: nonce={}, substream={:?}, has_surb={}, has_recipient={}",
+                                       tm.nonce, tm.message.substream_id,
+                                       message.sender_tag.is_some(), message.recipient.is_some());
+                }
+                SubstreamMessageType::Data(_) => {
+                    debug!(
+                        "Outbound Data nonce={}, substream={:?}",
+                        tm.nonce, tm.message.substream_id
+                    );
+                }
+                SubstreamMessageType::Close => {
+                    debug!(
+                        "Outbound Close nonce={}, substream={:?}",
+                        tm.nonce, tm.message.substream_id
+                    );
+                }
+                SubstreamMessageType::Cover(_) => {
+                    debug!("Outbound Cover nonce={}", tm.nonce);
+                }
+            }
+        }
+        Message::EncryptedTransportMessage(tm) => {
+            debug!("Outbound encrypted nonce={}", tm.nonce);
+        }
+        Message::ConnectionRequest(_) => debug!("OUTBOUND ConnectionRequest"),
+        Message::ConnectionResponse(_) => debug!("OUTBOUND ConnectionResponse"),
+        Message::AddressMessage(_) => debug!("OUTBOUND AddressMessage"),
+        Message::OutOfBandMessage(_) => debug!("OUTBOUND OutOfBandMessage"),
+        Message::ConnectionClose(_) => debug!("OUTBOUND ConnectionClose"),
+        Message::Ack(msg) => debug!("OUTBOUND Ack: nonce {}", msg.nonce),
+        Message::Probe(_) => debug!("OUTBOUND Probe"),
+        Message::SessionTicket(_) => debug!("OUTBOUND SessionTicket"),
+        Message::Resume(_) => debug!("OUTBOUND Resume"),
+        Message::Migrate(_) => debug!("OUTBOUND Migrate"),
+        Message::Parity(msg) => debug!("OUTBOUND Parity: group {}", msg.first_nonce),
+        Message::KeyUpdate(msg) => debug!("OUTBOUND KeyUpdate: generation {}", msg.generation),
+        Message::Sealed(_) => debug!("OUTBOUND Sealed"),
+    }
+    let surbs = match (&message.recipient, &message.sender_tag) {
+        (Some(_), None) => reply_surbs.surbs_for(&message.message),
+        _ => 0,
+    };
+    let (recipient, sender_tag) = (message.recipient, message.sender_tag);
+    let res = match chaos.outbound() {
+        None => route_bytes(mixnet_sender, recipient, sender_tag, surbs, bytes).await,
+        Some(Fault::Drop) => Ok(()),
+        Some(Fault::Duplicate) => {
+            route_bytes(mixnet_sender, recipient, sender_tag, surbs, bytes).await?;
+            route_bytes(mixnet_sender, recipient, sender_tag, surbs, bytes).await
+        }
+        Some(Fault::Delay(delay)) => {
+            let mixnet_sender = mixnet_sender.clone();
+            let bytes = bytes.to_vec();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let res = route_bytes(&mixnet_sender, recipient, sender_tag, surbs, &bytes).await;
+                if let Err(e) = res {
+                    debug!("failed to send delayed message: {e}");
+                }
+            });
+            Ok(())
+        }
+        Some(Fault::Corrupt) => {
+            let mut bytes = bytes.to_vec();
+            corrupt(&mut bytes);
+            route_bytes(mixnet_sender, recipient, sender_tag, surbs, &bytes).await
+        }
+    };
+    if sender_tag.is_some() {
+        record_reply(
+            &delivery.connection_stats,
+            &delivery.metrics,
+            message.message.connection_id(),
+            &res,
+        );
+    }
+
+    // the parity covers what was handed to the client, lost or not, so that
+    // the remote can rebuild it
+    for parity in delivery.fec.on_sent(&message.message, encode_buf) {
+        send_parity(mixnet_sender, &message, parity, &delivery.padding).await;
+    }
+
+    let mut message = message;
+    if let Err(Error::SurbExhausted) = res {
+        // the remote's next message may bring fresh SURBs for its tag
+        match delivery.retry_reply(message, bytes) {
+            None => return res,
+            Some(refused) => {
+                debug!("no room to retry a reply, failing its connection");
+                delivery.surbs_exhausted(refused.message.connection_id());
+                message = refused;
+            }
+        }
+    }
+
+    if let (Ok(()), Some(redundancy)) = (&res, redundancy) {
+        send_copies(
+            mixnet_sender,
+            redundancy,
+            &message,
+            surbs,
+            bytes,
+            reply_surbs,
+            delivery,
+        );
+    }
+
+    if res.is_err() && delivery.keep(&message, surbs, bytes) {
+        // the outbox sends it again, so the substream isn't failed
+        message.write_credit = None;
+    }
+    delivery.record(&message, &res);
+    // the data is with the mixnet client now, so the substream may write more
+    drop(message.write_credit);
+    res
+}
+
+/// sends a parity message to where the message it follows went.
+async fn send_parity(
+    mixnet_sender: &Arc<dyn MixnetDriverSender>,
+    message: &OutboundMessage,
+    parity: ParityMessage,
+    padding: &PaddingRegistry,
+) {
+    // parity is never replied to, so it carries no SURBs
+    let parity = Message::Parity(parity);
+    let bytes = parity.to_bytes();
+    let bytes = padding.pad(&parity, &bytes);
+    let res = route_bytes(
+        mixnet_sender,
+        message.recipient,
+        message.sender_tag,
+        0,
+        &bytes,
+    )
+    .await;
+    if let Err(e) = res {
+        debug!("failed to send parity: {e}");
+    }
+}
+
+/// sends the extra copies of a control message, see `Redundancy`, in the
+/// background, so that they don't hold up the messages after it.
+fn send_copies(
+    mixnet_sender: &Arc<dyn MixnetDriverSender>,
+    redundancy: &Redundancy,
+    message: &OutboundMessage,
+    surbs: u32,
+    bytes: &[u8],
+    reply_surbs: &ReplySurbAllocation,
+    delivery: &DeliveryReport,
+) {
+    let copies = match &message.message {
+        Message::ConnectionRequest(_) | Message::ConnectionResponse(_) | Message::Resume(_) => {
+            redundancy.handshake
+        }
+        Message::ConnectionClose(_) => redundancy.close,
+        Message::Ack(_) => redundancy.ack,
+        _ => 0,
+    };
+    if copies == 0 {
+        return;
+    }
+    // every copy carries the SURBs the original does, in case it's the only
+    // one which arrives
+    reply_surbs.record_attached(&message.message, surbs * copies);
+
+    let mixnet_sender = mixnet_sender.clone();
+    let (recipient, sender_tag) = (message.recipient, message.sender_tag);
+    let bytes = bytes.to_vec();
+    let delay = redundancy.delay;
+    let metrics = delivery.metrics.clone();
+    tokio::spawn(async move {
+        for _ in 0..copies {
+            tokio::time::sleep(delay).await;
+            match route_bytes(&mixnet_sender, recipient, sender_tag, surbs, &bytes).await {
+                Ok(()) => TransportMetrics::inc(&metrics.redundant_copies),
+                Err(e) => debug!("failed to send a copy of a control message: {e}"),
+            }
+        }
+    });
+}
+
+/// sends the messages in the outbox again, along their connections' current
+/// routes, keeping those the client refuses once more.
+async fn flush_outbox(mixnet_sender: &Arc<dyn MixnetDriverSender>, delivery: &DeliveryReport) {
+    let Some(outbox) = &delivery.outbox else {
+        return;
+    };
+    if outbox.is_empty() || outbox.is_held() {
+        return;
+    }
+    // a message leaves the outbox once the client took it; the rest goes
+    // back even if the flush is cancelled
+    let mut unsent = Unflushed {
+        outbox,
+        messages: outbox.take().into(),
+    };
+    while let Some(msg) = unsent.messages.front() {
+        let (recipient, sender_tag) = delivery
+            .routes
+            .get(&msg.id)
+            .unwrap_or((msg.recipient, msg.sender_tag));
+        let res = route_bytes(
+            mixnet_sender,
+            recipient,
+            sender_tag,
+            msg.reply_surbs,
+            &msg.bytes,
+        )
+        .await;
+        if let Err(e) = res {
+            // the client is likely down again; don't try the rest
+            debug!("failed to send a message from the outbox: {}", e);
+            return;
+        }
+        unsent.messages.pop_front();
+        TransportMetrics::inc(&delivery.metrics.outbox_flushed);
+    }
+}
+
+/// Unflushed puts the messages taken from the outbox which weren't sent back
+/// when it's dropped.
+struct Unflushed<'a> {
+    outbox: &'a Outbox,
+    messages: VecDeque<StoredMessage>,
+}
+
+impl Drop for Unflushed<'_> {
+    fn drop(&mut self) {
+        if !self.messages.is_empty() {
+            self.outbox.put_back(self.messages.drain(..).collect());
+        }
+    }
+}
+
+/// saves the outbox for the next transport, along with the data which is
+/// still waiting to be sent.
+fn save_outbox(
+    outbound_rx: &mut OutboundReceiver,
+    backlog: &OutboundBacklog,
+    reply_surbs: &ReplySurbAllocation,
+    delivery: &DeliveryReport,
+) {
+    let Some(outbox) = &delivery.outbox else {
+        return;
+    };
+    let mut buf = vec![];
+    while let Some(message) = outbound_rx.try_recv_data() {
+        backlog.dequeued(message.queued_at);
+        buf.clear();
+        if let Err(e) = message.encode_into(&mut buf) {
+            debug!("failed to encode a message for the outbox: {}", e);
+            continue;
+        }
+        let surbs = match (&message.recipient, &message.sender_tag) {
+            (Some(_), None) => reply_surbs.surbs_for(&message.message),
+            _ => 0,
+        };
+        outbox.store(StoredMessage::new(
+            message.message.connection_id().clone(),
+            message.recipient,
+            message.sender_tag,
+            surbs,
+            &buf,
+        ));
+    }
+    if let Err(e) = outbox.save() {
+        warn!("failed to save the outbox: {}", e);
+    }
+}
+
+/// sends the encoded message: as a reply if it has a sender tag, or to its
+/// recipient, with the given number of reply SURBs.
+async fn route_bytes(
+    mixnet_sender: &Arc<dyn MixnetDriverSender>,
+    recipient: Option<Recipient>,
+    sender_tag: Option<AnonymousSenderTag>,
+    surbs: u32,
+    bytes: &[u8],
+) -> Result<(), Error> {
+    match (recipient, sender_tag) {
+        (_, Some(sender_tag)) => {
+            // sender_tag for anonymous replies
+            debug!("writing reply to sender_tag {}", redact(sender_tag));
+            write_reply_bytes(mixnet_sender, sender_tag, bytes).await
+        }
+        (Some(recipient), None) => {
+            // recipient for initial messages
+            debug!("sending message to recipient {}", redact(recipient));
+            write_bytes(mixnet_sender, recipient, bytes, surbs).await
+        }
+        (None, None) => {
+            debug!("No recipient or sender_tag provided, cannot route messag");
+            Err(Error::OutboundSendFailure(
+                "No recipient or sender_tag provided, cannot route message".to_string(),
+            ))
+        }
+    }
+}
+
+async fn write_bytes(
+    mixnet_sender: &Arc<dyn MixnetDriverSender>,
+    recipient: Recipient,
+    message: &[u8],
+    reply_surbs: u32,
+) -> Result<(), Error> {
+    mixnet_sender.send(recipient, message, reply_surbs).await?;
+    debug!("wrote message to recipient: {}", redact(recipient));
+    Ok(())
+}
+
+async fn write_reply_bytes(
+    mixnet_sender: &Arc<dyn MixnetDriverSender>,
+    sender_tag: AnonymousSenderTag,
+    message: &[u8],
+) -> Result<(), Error> {
+    mixnet_sender.send_reply(sender_tag, message).await?;
+    debug!("wrote reply to sender_tag: {}", redact(sender_tag));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::alias::{AddressAliases, AliasRegistry};
+    use super::super::audit::{AuditLog, Stage};
+    #[cfg(feature = "nym-client")]
+    use super::super::client::ManagedMixnetClient;
+    #[cfg(feature = "nym-client")]
+    use super::super::config::GatewayFailover;
+    use super::super::config::{
+        ForwardErrorCorrection, MessagePadding, OfflineBuffer, Redundancy, ReplySurbs,
+        StoreAndForward, TrafficClasses,
+    };
+    use super::super::connection::{CloseCode, CloseReason};
+    use super::super::driver::{MixnetDriver, MixnetDriverSender};
+    use super::super::error::Error;
+    use super::super::fec::FecRegistry;
+    use super::super::message::{
+        self, AckMessage, ConnectionCloseMessage, ConnectionFlags, ConnectionId, ConnectionMessage,
+        ConnectionMessageKind, Message, MigrateMessage, ProbeMessage, SubstreamId,
+        SubstreamMessage, SubstreamMessageType, TransportMessage,
+    };
+    use super::super::metrics::TransportMetrics;
+    #[cfg(feature = "nym-client")]
+    use super::super::mixnet::Failover;
+    use super::super::mixnet::{
+        check_inbound, flush_outbox, initialize_mixnet, outbound_channel, retry_replies,
+        send_outbound, AddressChange, AliasSinks, ClientSwitch, ConnectionRoute, DeliveryReport,
+        MixnetSource, Outage, OutboundBacklog, OutboundExpiry, OutboundSender, ReplySurbAllocation,
+        Rotation, MAX_REPLY_RETRIES,
+    };
+    use super::super::outbox::{Outbox, StoredMessage};
+    use super::super::padding::PaddingRegistry;
+    use super::super::stats::{
+        ConnectionStats, ConnectionStatsRegistry, DeliveryStats, ReplySurbBudget,
+    };
+    use super::super::substream::{Substream, TrafficClass};
+    use crate::test_utils::random_address;
+    use futures::future::BoxFuture;
+    use futures::stream::BoxStream;
+    use futures::{AsyncWriteExt, FutureExt, StreamExt};
+    use libp2p::core::{Endpoint, PeerId};
+    #[cfg(feature = "nym-client")]
+    use nym_sdk::mixnet::MixnetClient;
+    use nym_sphinx::addressing::clients::Recipient;
+    use nym_sphinx::anonymous_replies::requests::AnonymousSenderTag;
+    use nym_sphinx::receiver::ReconstructedMessage;
+    use std::sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    };
+    use std::time::{Duration, Instant};
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+    use tokio::sync::{watch, Semaphore};
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    /// TestDriver receives the given messages, and records what's sent with it.
+    /// While it's down, it refuses to send. With a gate, each send waits for
+    /// one of its permits.
+    struct TestDriver {
+        address: Recipient,
+        messages: BoxStream<'static, ReconstructedMessage>,
+        sent_tx: UnboundedSender<ReconstructedMessage>,
+        disconnected: Arc<AtomicBool>,
+        down: Arc<AtomicBool>,
+        gate: Option<Arc<Semaphore>>,
+    }
+
+    impl TestDriver {
+        fn new(
+            messages: BoxStream<'static, ReconstructedMessage>,
+            sent_tx: UnboundedSender<ReconstructedMessage>,
+        ) -> Self {
+            TestDriver {
+                address: random_address(),
+                messages,
+                sent_tx,
+                disconnected: Arc::default(),
+                down: Arc::default(),
+                gate: None,
+            }
+        }
+
+        /// returns a driver which receives the messages sent with it.
+        fn loopback() -> Self {
+            let (sent_tx, sent_rx) = unbounded_channel();
+            Self::new(UnboundedReceiverStream::new(sent_rx).boxed(), sent_tx)
+        }
+    }
+
+    impl MixnetDriver for TestDriver {
+        fn address(&self) -> Recipient {
+            self.address
+        }
+
+        fn sender(&self) -> Arc<dyn MixnetDriverSender> {
+            if let Some(gate) = &self.gate {
+                return Arc::new(GatedSender {
+                    sent_tx: self.sent_tx.clone(),
+                    gate: gate.clone(),
+                });
+            }
+            Arc::new(FlakySender {
+                sent_tx: self.sent_tx.clone(),
+                down: self.down.clone(),
+            })
+        }
+
+        fn next(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>> {
+            self.messages.next().boxed()
+        }
+
+        fn disconnect(self: Box<Self>) -> BoxFuture<'static, ()> {
+            self.disconnected.store(true, Ordering::SeqCst);
+            futures::future::ready(()).boxed()
+        }
+    }
+
+    impl MixnetDriverSender for UnboundedSender<ReconstructedMessage> {
+        fn send<'a>(
+            &'a self,
+            _recipient: Recipient,
+            message: &'a [u8],
+            _reply_surbs: u32,
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            let res = UnboundedSender::send(
+                self,
+                ReconstructedMessage {
+                    message: message.to_vec(),
+                    sender_tag: None,
+                },
+            )
+            .map_err(|e| Error::OutboundSendFailure(e.to_string()));
+            futures::future::ready(res).boxed()
+        }
+
+        fn send_reply<'a>(
+            &'a self,
+            _sender_tag: AnonymousSenderTag,
+            message: &'a [u8],
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            MixnetDriverSender::send(self, random_address(), message, 0)
+        }
+    }
+
+    struct FlakySender {
+        sent_tx: UnboundedSender<ReconstructedMessage>,
+        down: Arc<AtomicBool>,
+    }
+
+    impl MixnetDriverSender for FlakySender {
+        fn send<'a>(
+            &'a self,
+            recipient: Recipient,
+            message: &'a [u8],
+            reply_surbs: u32,
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            if self.down.load(Ordering::SeqCst) {
+                let res = Err(Error::OutboundSendFailure("down".to_string()));
+                return futures::future::ready(res).boxed();
+            }
+            MixnetDriverSender::send(&self.sent_tx, recipient, message, reply_surbs)
+        }
+
+        fn send_reply<'a>(
+            &'a self,
+            _sender_tag: AnonymousSenderTag,
+            message: &'a [u8],
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            self.send(random_address(), message, 0)
+        }
+    }
+
+    /// GatedSender blocks each send until the gate gives it a permit.
+    struct GatedSender {
+        sent_tx: UnboundedSender<ReconstructedMessage>,
+        gate: Arc<Semaphore>,
+    }
+
+    impl MixnetDriverSender for GatedSender {
+        fn send<'a>(
+            &'a self,
+            recipient: Recipient,
+            message: &'a [u8],
+            reply_surbs: u32,
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            async move {
+                self.gate.acquire().await.unwrap().forget();
+                MixnetDriverSender::send(&self.sent_tx, recipient, message, reply_surbs).await
+            }
+            .boxed()
+        }
+
+        fn send_reply<'a>(
+            &'a self,
+            _sender_tag: AnonymousSenderTag,
+            message: &'a [u8],
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            self.send(random_address(), message, 0)
+        }
+    }
+
+    /// RouteSender records where the messages it sends go.
+    #[derive(Default)]
+    struct RouteSender {
+        routes: parking_lot::Mutex<Vec<(Option<Recipient>, Option<AnonymousSenderTag>)>>,
+    }
+
+    impl MixnetDriverSender for RouteSender {
+        fn send<'a>(
+            &'a self,
+            recipient: Recipient,
+            _message: &'a [u8],
+            _reply_surbs: u32,
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            self.routes.lock().push((Some(recipient), None));
+            futures::future::ready(Ok(())).boxed()
+        }
+
+        fn send_reply<'a>(
+            &'a self,
+            sender_tag: AnonymousSenderTag,
+            _message: &'a [u8],
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            self.routes.lock().push((None, Some(sender_tag)));
+            futures::future::ready(Ok(())).boxed()
+        }
+    }
+
+    /// ExhaustedSender has no reply SURBs for the first given number of
+    /// replies, and counts the attempts.
+    struct ExhaustedSender {
+        sent_tx: UnboundedSender<ReconstructedMessage>,
+        exhausted: u64,
+        replies: AtomicU64,
+    }
+
+    impl MixnetDriverSender for ExhaustedSender {
+        fn send<'a>(
+            &'a self,
+            recipient: Recipient,
+            message: &'a [u8],
+            reply_surbs: u32,
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            MixnetDriverSender::send(&self.sent_tx, recipient, message, reply_surbs)
+        }
+
+        fn send_reply<'a>(
+            &'a self,
+            _sender_tag: AnonymousSenderTag,
+            message: &'a [u8],
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            if self.replies.fetch_add(1, Ordering::SeqCst) < self.exhausted {
+                return futures::future::ready(Err(Error::SurbExhausted)).boxed();
+            }
+            self.send(random_address(), message, 0)
+        }
+    }
+
+    #[test]
+    fn test_outbound_backlog() {
+        let (overload_tx, mut overload_rx) = watch::channel(None);
+        let metrics = Arc::new(TransportMetrics::default());
+        let backlog = OutboundBacklog::new(Some(4), overload_tx, metrics.clone());
+
+        // exceeding the threshold is signalled once
+        for _ in 0..6 {
+            backlog.queued();
+        }
+        assert!(overload_rx.has_changed().unwrap());
+        assert_eq!(*overload_rx.borrow_and_update(), Some(5));
+        assert_eq!(metrics.snapshot().outbound_backlog, 6);
+        assert_eq!(metrics.snapshot().backlog_overloads, 1);
+
+        // draining to just above half the threshold doesn't end the overload
+        for _ in 0..3 {
+            backlog.dequeued(Instant::now());
+        }
+        backlog.queued();
+        backlog.queued();
+        assert!(!overload_rx.has_changed().unwrap());
+
+        // draining to half the threshold does
+        for _ in 0..3 {
+            backlog.dequeued(Instant::now());
+        }
+        assert_eq!(*overload_rx.borrow_and_update(), None);
+        backlog.queued();
+        backlog.queued();
+        backlog.queued();
+        assert_eq!(*overload_rx.borrow_and_update(), Some(5));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.outbound_backlog, 5);
+        assert_eq!(snapshot.outbound_messages, 6);
+        assert_eq!(snapshot.backlog_overloads, 2);
+    }
+
+    #[cfg(feature = "nym-client")]
+    #[test]
+    fn test_failover_gateway_rotation() {
+        let mut failover = Failover {
+            config: GatewayFailover {
+                gateways: vec!["a".to_string(), "b".to_string()],
+                ..Default::default()
+            },
+            client: ManagedMixnetClient::new(&Default::default()).unwrap(),
+            next_gateway: 0,
+            metrics: Default::default(),
+        };
+
+        // the gateways are tried in turn, starting over after the last one
+        let gateways: Vec<_> = (0..3).map(|_| failover.take_gateway()).collect();
+        let expected = ["a", "b", "a"].map(|gateway| Some(gateway.to_string()));
+        assert_eq!(gateways, expected);
+
+        // without fallback gateways, the mixnet client picks one
+        failover.config.gateways.clear();
+        assert_eq!(failover.take_gateway(), None);
+    }
+
+    #[tokio::test]
+    async fn test_outbound_backlog_limit() {
+        let backlog = Arc::new(OutboundBacklog::default().with_limit(Some(2)));
+        let (outbound_tx, mut outbound_rx) = unbounded_channel();
+        let (_inbound_tx, inbound_rx) = unbounded_channel();
+        let (_close_tx, close_rx) = tokio::sync::oneshot::channel();
+        let mut substream = Substream::new(
+            None,
+            ConnectionId::generate(),
+            SubstreamId::generate(),
+            inbound_rx,
+            OutboundSender::new(outbound_tx, backlog.clone()),
+            close_rx,
+            Arc::new(AtomicU64::new(1)),
+        );
+
+        // writes wait once the backlog is full..
+        substream.write_all(b"hello").await.unwrap();
+        substream.write_all(b"world").await.unwrap();
+        let mut write = substream.write(b"!");
+        assert!((&mut write).now_or_never().is_none());
+
+        // ..until the mixnet client took a message
+        outbound_rx.try_recv().unwrap();
+        backlog.dequeued(Instant::now());
+        assert_eq!(write.await.unwrap(), 1);
+        assert_eq!(backlog.depth.load(Ordering::SeqCst), 2);
+        assert!(backlog.waiters.lock().is_empty());
+
+        // bulk writes only fill half of it
+        let bulk = OutboundSender::new(unbounded_channel().0, backlog.clone())
+            .with_traffic_class(TrafficClass::Bulk);
+        let waker = futures::task::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        backlog.dequeued(Instant::now());
+        assert!(bulk.poll_ready(&mut cx).is_pending());
+
+        // a writer polling again is registered once
+        assert!(bulk.poll_ready(&mut cx).is_pending());
+        assert_eq!(backlog.waiters.lock().len(), 1);
+        backlog.dequeued(Instant::now());
+        assert!(bulk.poll_ready(&mut cx).is_ready());
+
+        // writers waiting when the mixnet task stops fail instead
+        substream.write_all(b"hello").await.unwrap();
+        substream.write_all(b"world").await.unwrap();
+        let mut write = substream.write(b"!");
+        assert!((&mut write).now_or_never().is_none());
+        drop(outbound_rx);
+        backlog.close();
+        assert!(write.await.is_err());
+        assert!(bulk.poll_ready(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn test_outbound_backlog_closed() {
+        let backlog = Arc::new(OutboundBacklog::default().with_limit(Some(1)));
+        let (outbound_tx, outbound_rx) = outbound_channel(backlog.clone(), &Default::default());
+        let mut cx = std::task::Context::from_waker(futures::task::noop_waker_ref());
+        backlog.queued();
+        assert!(outbound_tx.poll_ready(&mut cx).is_pending());
+
+        // once the mixnet task dropped its receiver, writers stop waiting
+        drop(outbound_rx);
+        assert!(backlog.waiters.lock().is_empty());
+        assert!(outbound_tx.poll_ready(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn test_reply_surb_allocation() {
+        let allocation = ReplySurbAllocation {
+            surbs: ReplySurbs {
+                handshake: 20,
+                top_up: 3,
+            },
+            connection_stats: ConnectionStatsRegistry::default(),
+            metrics: Default::default(),
+        };
+        let id = ConnectionId::generate();
+        let request = Message::ConnectionRequest(
+            ConnectionMessage::new_signed(
+                &libp2p_identity::Keypair::generate_ed25519(),
+                id.clone(),
+                ConnectionMessageKind::Request,
+                ConnectionFlags::default(),
+                None,
+            )
+            .unwrap(),
+        );
+        assert_eq!(allocation.surbs_for(&request), 20);
+
+        // the transport accounts for the request once the handshake completed
+        allocation.connection_stats.insert(
+            id.clone(),
+            ConnectionStats {
+                peer_id: PeerId::random(),
+                endpoint: Endpoint::Dialer,
+                remote_address: None,
+                handshake_rtt: None,
+                setup_duration: None,
+                expired_messages: 0,
+                delivery: DeliveryStats::default(),
+                reorder: Default::default(),
+                reply_surbs: Some(ReplySurbBudget {
+                    attached: 20,
+                    used: 1,
+                    ..Default::default()
+                }),
+                rtt: None,
+                frame_size: None,
+            },
+        );
+
+        let data = Message::TransportMessage(TransportMessage {
+            nonce: 1,
+            id: id.clone(),
+            message: SubstreamMessage::new_with_data(SubstreamId::generate(), b"hello".to_vec()),
+        });
+        assert_eq!(allocation.surbs_for(&data), 3);
+        assert_eq!(allocation.surbs_for(&data), 3);
+        assert!(allocation.connection_stats.record_reply_surb_used(&id));
+
+        // every SURB attached after the handshake is accounted to the connection
+        let budget = allocation.connection_stats.all()[0]
+            .reply_surbs
+            .clone()
+            .unwrap();
+        assert_eq!(budget.attached, 20 + 6);
+        assert_eq!(budget.topped_up, 6);
+        assert_eq!(budget.used, 2);
+        assert_eq!(budget.remaining(), 24);
+
+        // while the metrics count the request's SURBs right away
+        let metrics = allocation.metrics.snapshot();
+        assert_eq!(metrics.reply_surbs_attached, 20 + 6);
+        assert_eq!(metrics.reply_surbs_topped_up, 6);
+    }
+
+    #[test]
+    fn test_outbound_expiry() {
+        let expiry = OutboundExpiry {
+            ttl: Some(Duration::from_secs(10)),
+            outage: None,
+            connection_stats: ConnectionStatsRegistry::default(),
+            metrics: Default::default(),
+        };
+        let id = ConnectionId::generate();
+        let peer_id = PeerId::random();
+        expiry.connection_stats.insert(
+            id.clone(),
+            ConnectionStats {
+                peer_id,
+                endpoint: Endpoint::Dialer,
+                remote_address: None,
+                handshake_rtt: None,
+                setup_duration: None,
+                expired_messages: 0,
+                delivery: DeliveryStats::default(),
+                reorder: Default::default(),
+                reply_surbs: None,
+                rtt: None,
+                frame_size: None,
+            },
+        );
+
+        let substream_id = SubstreamId::generate();
+        let outbound = |message_type, queued_at| message::OutboundMessage {
+            message: Message::TransportMessage(TransportMessage {
+                nonce: 1,
+                id: id.clone(),
+                message: SubstreamMessage {
+                    substream_id: substream_id.clone(),
+                    message_type,
+                },
+            }),
+            recipient: None,
+            sender_tag: None,
+            queued_at,
+            session: None,
+            write_credit: None,
+            compact_ids: false,
+            alias: None,
+        };
+        let message_type = |msg: message::OutboundMessage| match msg.message {
+            Message::TransportMessage(tm) => tm.message.message_type,
+            _ => panic!("expected Message::TransportMessage"),
+        };
+        let expired = Instant::now() - Duration::from_secs(11);
+
+        // data within the TTL is sent as-is
+        let msg = expiry.apply(outbound(
+            SubstreamMessageType::Data(b"hello".to_vec().into()),
+            Instant::now(),
+        ));
+        assert!(matches!(message_type(msg), SubstreamMessageType::Data(_)));
 
-/// initialize_mixnet initializes a read/write connection to a Nym Client.
-/// It starts a task that listens for inbound messages from the endpoint and writes outbound messages to the endpoint.
-pub(crate) async fn initialize_mixnet(
-    client: MixnetClient,
-    notify_inbound_tx: Option<UnboundedSender<()>>,
-) -> Result<
-    (
-        Recipient,
-        UnboundedReceiver<InboundMessage>,
-        UnboundedSender<OutboundMessage>,
-    ),
-    Error,
-> {
-    let recipient = *client.nym_address();
+        // expired data is replaced by a Close of its substream
+        let msg = expiry.apply(outbound(
+            SubstreamMessageType::Data(b"hello".to_vec().into()),
+            expired,
+        ));
+        assert!(matches!(message_type(msg), SubstreamMessageType::Close));
+        assert_eq!(expiry.connection_stats.get(&peer_id)[0].expired_messages, 1);
+        assert_eq!(expiry.metrics.snapshot().messages_expired, 1);
 
-    // a channel of inbound messages from the mixnet..
-    // the transport reads from (listens) to the inbound_rx.
-    // TODO: this is probably a DOS vector; we should limit the size of the channel.
-    let (inbound_tx, inbound_rx) = unbounded_channel::<InboundMessage>();
+        // control messages never expire
+        let msg = expiry.apply(outbound(SubstreamMessageType::OpenRequest, expired));
+        assert!(matches!(
+            message_type(msg),
+            SubstreamMessageType::OpenRequest
+        ));
+        assert_eq!(expiry.metrics.snapshot().messages_expired, 1);
+    }
 
-    // a channel of outbound messages to be written to the mixnet.
-    // the transport writes to outbound_tx.
-    let (outbound_tx, mut outbound_rx) = unbounded_channel::<OutboundMessage>();
+    #[tokio::test]
+    async fn test_delivery_report() {
+        let (audit_log, mut audit_events) = AuditLog::channel();
+        let delivery = DeliveryReport {
+            audit_log: Some(audit_log),
+            ..Default::default()
+        };
+        let id = ConnectionId::generate();
+        delivery.connection_stats.insert(
+            id.clone(),
+            ConnectionStats {
+                peer_id: PeerId::random(),
+                endpoint: Endpoint::Dialer,
+                remote_address: None,
+                handshake_rtt: None,
+                setup_duration: None,
+                expired_messages: 0,
+                delivery: DeliveryStats::default(),
+                reorder: Default::default(),
+                reply_surbs: None,
+                rtt: None,
+                frame_size: None,
+            },
+        );
 
-    let sink = client.split_sender();
-    let mut stream = client;
+        let (outbound_tx, mut outbound_rx) = unbounded_channel();
+        let (_inbound_tx, inbound_rx) = unbounded_channel();
+        let (_close_tx, close_rx) = tokio::sync::oneshot::channel();
+        let mut substream = Substream::new(
+            None,
+            id.clone(),
+            SubstreamId::generate(),
+            inbound_rx,
+            OutboundSender::new(outbound_tx, Default::default()),
+            close_rx,
+            Arc::new(AtomicU64::new(1)),
+        );
 
-    tokio::task::spawn(async move {
-        loop {
-            let t1 = check_inbound(&mut stream, &inbound_tx, &notify_inbound_tx).fuse();
-            let t2 = check_outbound(&sink, &mut outbound_rx).fuse();
+        // a frame the mixnet client accepted flushes
+        substream.write_all(b"hello").await.unwrap();
+        let msg = outbound_rx.try_recv().unwrap();
+        delivery.record(&msg, &Ok(()));
+        drop(msg);
+        substream.flush().await.unwrap();
 
-            pin_mut!(t1, t2);
+        // one it refused fails the next flush, but not the ones after it
+        substream.write_all(b"world").await.unwrap();
+        let msg = outbound_rx.try_recv().unwrap();
+        delivery.record(&msg, &Err(Error::OutboundSendFailure("gone".to_string())));
+        drop(msg);
+        assert!(substream.flush().now_or_never().unwrap().is_err());
+        substream.flush().await.unwrap();
 
-            select! {
-                _ = t1 => {},
-                _ = t2 => {},
-            };
+        let stats = delivery.connection_stats.all()[0].delivery.clone();
+        assert_eq!(stats.sent, 1);
+        assert_eq!(stats.failed, 1);
+        assert_eq!(delivery.metrics.snapshot().send_failures, 1);
+
+        // and both are in the audit log
+        let stages: Vec<_> = std::iter::from_fn(|| audit_events.try_recv().ok())
+            .map(|event| (event.nonce, event.stage))
+            .collect();
+        assert_eq!(stages, [(1, Stage::Sent), (2, Stage::SendFailed)]);
+    }
+
+    #[tokio::test]
+    async fn test_outbound_priority() {
+        let backlog = Arc::new(OutboundBacklog::default());
+        let (outbound_tx, mut outbound_rx) = outbound_channel(backlog.clone(), &Default::default());
+        let id = ConnectionId::generate();
+        let outbound = |message| message::OutboundMessage {
+            message,
+            recipient: None,
+            sender_tag: None,
+            queued_at: Instant::now(),
+            session: None,
+            write_credit: None,
+            compact_ids: false,
+            alias: None,
+        };
+        let data = |nonce| {
+            Message::TransportMessage(TransportMessage {
+                nonce,
+                id: id.clone(),
+                message: SubstreamMessage::new_with_data(SubstreamId::generate(), vec![0; 8]),
+            })
+        };
+
+        for nonce in 1..=3 {
+            outbound_tx.send(outbound(data(nonce))).unwrap();
         }
-    });
+        let response = ConnectionMessage::new_signed(
+            &libp2p_identity::Keypair::generate_ed25519(),
+            ConnectionId::generate(),
+            ConnectionMessageKind::Response,
+            ConnectionFlags::default(),
+            None,
+        )
+        .unwrap();
+        outbound_tx
+            .send(outbound(Message::ConnectionResponse(response)))
+            .unwrap();
+        assert_eq!(backlog.depth.load(Ordering::SeqCst), 4);
 
-    Ok((recipient, inbound_rx, outbound_tx))
-}
+        // the response jumps ahead of the queued data, which stays in order
+        let msg = outbound_rx.recv().await.unwrap();
+        assert!(matches!(msg.message, Message::ConnectionResponse(_)));
+        for expected in 1..=3 {
+            match outbound_rx.recv().await.unwrap().message {
+                Message::TransportMessage(tm) => assert_eq!(tm.nonce, expected),
+                _ => panic!("expected Message::TransportMessage"),
+            }
+        }
 
-async fn check_inbound(
-    client: &mut MixnetClient,
-    inbound_tx: &UnboundedSender<InboundMessage>,
-    notify_inbound_tx: &Option<UnboundedSender<()>>,
-) -> Result<(), Error> {
-    if let Some(msg) = client.next().await {
-        if let Some(notify_tx) = notify_inbound_tx {
-            notify_tx
-                .send(())
-                .map_err(|e| Error::InboundSendFailure(e.to_string()))?;
+        // a close waits for the data queued before it, but not for later data
+        let bulk_tx = outbound_tx.clone().with_traffic_class(TrafficClass::Bulk);
+        bulk_tx.send(outbound(data(4))).unwrap();
+        let close = ConnectionCloseMessage::new(id.clone(), CloseReason::new(CloseCode::Shutdown));
+        outbound_tx
+            .send(outbound(Message::ConnectionClose(close)))
+            .unwrap();
+        outbound_tx.send(outbound(data(5))).unwrap();
+        let order: Vec<_> = std::iter::from_fn(|| outbound_rx.recv().now_or_never().flatten())
+            .map(|msg| match msg.message {
+                Message::TransportMessage(tm) => Some(tm.nonce),
+                Message::ConnectionClose(_) => None,
+                _ => panic!("unexpected message"),
+            })
+            .collect();
+        assert_eq!(order, [Some(5), Some(4), None]);
+
+        // interactive data jumps ahead of bulk data
+        bulk_tx.send(outbound(data(4))).unwrap();
+        outbound_tx.send(outbound(data(5))).unwrap();
+        for expected in [5, 4] {
+            match outbound_rx.recv().await.unwrap().message {
+                Message::TransportMessage(tm) => assert_eq!(tm.nonce, expected),
+                _ => panic!("expected Message::TransportMessage"),
+            }
         }
 
-        handle_inbound(msg, inbound_tx).await?;
+        // data queued before the senders were dropped is still received
+        outbound_tx.send(outbound(data(6))).unwrap();
+        bulk_tx.send(outbound(data(7))).unwrap();
+        drop((outbound_tx, bulk_tx));
+        assert!(outbound_rx.recv().await.is_some());
+        assert!(outbound_rx.recv().await.is_some());
+        assert!(outbound_rx.recv().await.is_none());
     }
 
-    Err(Error::Unimplemented)
-}
+    #[tokio::test]
+    async fn test_outbound_class_rates() {
+        let classes = TrafficClasses {
+            bulk_rate: Some(10),
+            ..Default::default()
+        };
+        let (outbound_tx, mut outbound_rx) = outbound_channel(Arc::default(), &classes);
+        let bulk_tx = outbound_tx.clone().with_traffic_class(TrafficClass::Bulk);
+        let outbound = || message::OutboundMessage {
+            message: Message::TransportMessage(TransportMessage {
+                nonce: 1,
+                id: ConnectionId::generate(),
+                message: SubstreamMessage::new_with_data(SubstreamId::generate(), vec![0; 8]),
+            }),
+            recipient: None,
+            sender_tag: None,
+            queued_at: Instant::now(),
+            session: None,
+            write_credit: None,
+            compact_ids: false,
+            alias: None,
+        };
 
-async fn handle_inbound(
-    msg: ReconstructedMessage,
-    inbound_tx: &UnboundedSender<InboundMessage>,
-) -> Result<(), Error> {
-    let sender_tag = msg.sender_tag.clone();
+        // a second's worth of bulk data goes out in a burst, the rest is paced
+        for _ in 0..11 {
+            bulk_tx.send(outbound()).unwrap();
+        }
+        for _ in 0..10 {
+            assert!(outbound_rx.recv().now_or_never().flatten().is_some());
+        }
+        assert!(outbound_rx.recv().now_or_never().is_none());
 
-    let data = parse_message_data(&msg.message, sender_tag)?;
-    inbound_tx
-        .send(data)
-        .map_err(|e| Error::InboundSendFailure(e.to_string()))?;
-    Ok(())
-}
+        // while interactive data isn't held back
+        outbound_tx.send(outbound()).unwrap();
+        assert!(outbound_rx.recv().now_or_never().flatten().is_some());
+        tokio::time::timeout(Duration::from_secs(1), outbound_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+    }
 
-async fn check_outbound(
-    mixnet_sender: &MixnetClientSender,
-    outbound_rx: &mut UnboundedReceiver<OutboundMessage>,
-) -> Result<(), Error> {
-    match outbound_rx.recv().await {
-        Some(message) => {
-            match &message.message {
-                Message::TransportMessage(tm) => match &tm.message.message_type {
-                    SubstreamMessageType::OpenResponse => {
-                        debug!("Outbound OpenResponse: nonce={}, substream={:?}, has_surb={}, has_recipient={}",
-                                               tm.nonce, tm.message.substream_id,
-                                               message.sender_tag.is_some(), message.recipient.is_some());
-                    }
-                    SubstreamMessageType::OpenRequest => {
-                        debug!("Outbound OpenRequest: nonce={}, substream={:?}, has_surb={}, has_recipient={}",
-                                               tm.nonce, tm.message.substream_id,
-                                               message.sender_tag.is_some(), message.recipient.is_some());
-                    }
-                    SubstreamMessageType::Data(_) => {
-                        debug!(
-                            "Outbound Data nonce={}, substream={:?}",
-                            tm.nonce, tm.message.substream_id
-                        );
-                    }
-                    SubstreamMessageType::Close => {
-                        debug!(
-                            "Outbound Close nonce={}, substream={:?}",
-                            tm.nonce, tm.message.substream_id
-                        );
-                    }
-                },
-                Message::ConnectionRequest(_) => debug!("OUTBOUND ConnectionRequest"),
-                Message::ConnectionResponse(_) => debug!("OUTBOUND ConnectionResponse"),
+    #[tokio::test]
+    async fn test_driver_inbound() {
+        let msg = Message::TransportMessage(TransportMessage {
+            nonce: 1,
+            id: ConnectionId::generate(),
+            message: SubstreamMessage::new_with_data(SubstreamId::generate(), b"hello".to_vec()),
+        });
+        let forwarded = ReconstructedMessage {
+            message: msg.to_bytes(),
+            sender_tag: None,
+        };
+        let mut inbound = TestDriver::new(
+            futures::stream::iter(vec![forwarded]).boxed(),
+            unbounded_channel().0,
+        );
+        let (inbound_tx, mut inbound_rx) = unbounded_channel();
+        let (notify_tx, mut notify_rx) = unbounded_channel();
+        let notify_tx = Some(notify_tx);
+
+        // messages forwarded by the application are handled like the client's own
+        check_inbound(
+            &mut inbound,
+            &inbound_tx,
+            &notify_tx,
+            &Default::default(),
+            &Default::default(),
+            None,
+        )
+        .await
+        .unwrap_err();
+        notify_rx.try_recv().unwrap();
+        match inbound_rx.try_recv().unwrap().0 {
+            Message::TransportMessage(recv_msg) => assert_eq!(recv_msg.nonce, 1),
+            _ => panic!("expected Message::TransportMessage"),
+        }
+
+        // the client is disconnected once the application stops forwarding
+        assert!(matches!(
+            check_inbound(
+                &mut inbound,
+                &inbound_tx,
+                &notify_tx,
+                &Default::default(),
+                &Default::default(),
+                None,
+            )
+            .await,
+            Err(Error::GatewayDisconnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_driver() {
+        let driver = TestDriver::loopback();
+        let disconnected = driver.disconnected.clone();
+        let (self_address, mut inbound_rx, outbound_tx, task) = initialize_mixnet(
+            MixnetSource::Driver(Box::new(driver)),
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        // the task sends and receives with the driver
+        let id = ConnectionId::generate();
+        outbound_tx
+            .send(message::OutboundMessage {
+                message: Message::TransportMessage(TransportMessage {
+                    nonce: 1,
+                    id: id.clone(),
+                    message: SubstreamMessage::new_with_data(
+                        SubstreamId::generate(),
+                        b"hello".to_vec(),
+                    ),
+                }),
+                recipient: Some(self_address),
+                sender_tag: None,
+                queued_at: Instant::now(),
+                session: None,
+                write_credit: None,
+                compact_ids: false,
+                alias: None,
+            })
+            .unwrap();
+        match inbound_rx.recv().await.unwrap().0 {
+            Message::TransportMessage(msg) => assert_eq!(msg.id, id),
+            _ => panic!("expected Message::TransportMessage"),
+        }
+
+        // and disconnects it once stopped
+        task.shutdown().await;
+        assert!(disconnected.load(Ordering::SeqCst));
+        assert!(inbound_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_blocked_send() {
+        let (messages_tx, messages_rx) = unbounded_channel();
+        let (sent_tx, mut sent_rx) = unbounded_channel();
+        let mut driver =
+            TestDriver::new(UnboundedReceiverStream::new(messages_rx).boxed(), sent_tx);
+        let gate = Arc::new(Semaphore::new(0));
+        driver.gate = Some(gate.clone());
+        let (self_address, mut inbound_rx, outbound_tx, task) = initialize_mixnet(
+            MixnetSource::Driver(Box::new(driver)),
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        let message = |nonce| {
+            Message::TransportMessage(TransportMessage {
+                nonce,
+                id: ConnectionId::generate(),
+                message: SubstreamMessage::new_with_data(
+                    SubstreamId::generate(),
+                    b"hello".to_vec(),
+                ),
+            })
+        };
+
+        // the client blocks the sends
+        for nonce in 1..=3 {
+            outbound_tx
+                .send(message::OutboundMessage {
+                    message: message(nonce),
+                    recipient: Some(self_address),
+                    sender_tag: None,
+                    queued_at: Instant::now(),
+                    session: None,
+                    write_credit: None,
+                    compact_ids: false,
+                    alias: None,
+                })
+                .unwrap();
+        }
+
+        // while the task keeps receiving
+        for nonce in 1..=3 {
+            messages_tx
+                .send(ReconstructedMessage {
+                    message: message(nonce).to_bytes(),
+                    sender_tag: None,
+                })
+                .unwrap();
+            inbound_rx.recv().await.unwrap();
+        }
+        assert!(sent_rx.try_recv().is_err());
+
+        // and no message is lost once the client unblocks
+        gate.add_permits(3);
+        for nonce in 1..=3 {
+            let sent = tokio::time::timeout(Duration::from_secs(1), sent_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match Message::try_from_bytes(sent.message.into()).unwrap() {
+                Message::TransportMessage(msg) => assert_eq!(msg.nonce, nonce),
+                _ => panic!("expected Message::TransportMessage"),
             }
-            match (&message.recipient, &message.sender_tag) {
-                (_, Some(sender_tag)) => {
-                    // sender_tag for anonymous replies
-                    debug!(
-                        "writing reply to sender_tag {:?}",
-                        sender_tag.to_base58_string()
-                    );
-                    write_reply_bytes(
-                        mixnet_sender,
-                        sender_tag.clone(),
-                        &message.message.to_bytes(),
-                    )
-                    .await
-                }
-                (Some(recipient), None) => {
-                    // recipient for initial messages
-                    debug!("sending message to recipient {:}", recipient);
-                    write_bytes(
-                        mixnet_sender,
-                        recipient.clone(),
-                        &message.message.to_bytes(),
-                    )
-                    .await
-                }
-                (None, None) => {
-                    debug!("No recipient or sender_tag provided, cannot route messag");
-                    return Err(Error::OutboundSendFailure(
-                        "No recipient or sender_tag provided, cannot route message".to_string(),
-                    ));
+        }
+        task.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_shutdown_while_reconnecting() {
+        // the client loses its gateway, and nothing replaces it
+        let (messages_tx, messages_rx) = unbounded_channel();
+        let (sent_tx, _sent_rx) = unbounded_channel();
+        let driver = TestDriver::new(UnboundedReceiverStream::new(messages_rx).boxed(), sent_tx);
+        let disconnected = driver.disconnected.clone();
+        let (_replace_tx, replace_rx) = unbounded_channel();
+        let switch = ClientSwitch {
+            replace_rx,
+            #[cfg(feature = "nym-client")]
+            failover: None,
+            rotation: None,
+            alias_rx: unbounded_channel().1,
+            address_tx: unbounded_channel().0,
+        };
+        let (_, _inbound_rx, _outbound_tx, task) = initialize_mixnet(
+            MixnetSource::Driver(Box::new(driver)),
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+            Some(switch),
+        )
+        .await
+        .unwrap();
+        drop(messages_tx);
+        tokio::task::yield_now().await;
+
+        // the task still stops when asked to
+        tokio::time::timeout(Duration::from_secs(1), task.shutdown())
+            .await
+            .unwrap();
+        assert!(disconnected.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_message_padding() {
+        let (messages_tx, messages_rx) = unbounded_channel();
+        let (sent_tx, mut sent_rx) = unbounded_channel();
+        let driver = TestDriver::new(UnboundedReceiverStream::new(messages_rx).boxed(), sent_tx);
+        let padding = MessagePadding::default();
+        let registry = PaddingRegistry::default();
+        let id = ConnectionId::generate();
+        registry.insert(id.clone(), padding.clone());
+        let (self_address, mut inbound_rx, outbound_tx, _task) = initialize_mixnet(
+            MixnetSource::Driver(Box::new(driver)),
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            DeliveryReport {
+                padding: registry,
+                ..Default::default()
+            },
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        // the messages of connections which negotiated padding are handed to
+        // the client padded to the smallest bucket
+        outbound_tx
+            .send(message::OutboundMessage {
+                message: Message::TransportMessage(TransportMessage {
+                    nonce: 1,
+                    id: id.clone(),
+                    message: SubstreamMessage::new_with_data(
+                        SubstreamId::generate(),
+                        b"hello".to_vec(),
+                    ),
+                }),
+                recipient: Some(self_address),
+                sender_tag: None,
+                queued_at: Instant::now(),
+                session: None,
+                write_credit: None,
+                compact_ids: false,
+                alias: None,
+            })
+            .unwrap();
+        let sent = sent_rx.recv().await.unwrap();
+        assert_eq!(sent.message.len(), padding.buckets[0]);
+
+        // and the padding is stripped from the ones received
+        messages_tx.send(sent).unwrap();
+        match inbound_rx.recv().await.unwrap().0 {
+            Message::TransportMessage(msg) => assert_eq!(msg.id, id),
+            _ => panic!("expected Message::TransportMessage"),
+        }
+
+        // the messages of other connections are sent as they are
+        let probe = Message::Probe(ProbeMessage {
+            id: ConnectionId::generate(),
+        });
+        outbound_tx
+            .send(message::OutboundMessage {
+                message: probe.clone(),
+                recipient: Some(self_address),
+                sender_tag: None,
+                queued_at: Instant::now(),
+                session: None,
+                write_credit: None,
+                compact_ids: false,
+                alias: None,
+            })
+            .unwrap();
+        let sent = sent_rx.recv().await.unwrap();
+        assert_eq!(sent.message, probe.to_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_store_and_forward() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StoreAndForward::new(dir.path().join("outbox"), [1u8; 32]);
+        let metrics = Arc::new(TransportMetrics::default());
+        let outbox = Arc::new(Outbox::new(config.clone(), metrics.clone()));
+        let delivery = DeliveryReport {
+            metrics: metrics.clone(),
+            outbox: Some(outbox.clone()),
+            ..Default::default()
+        };
+        let driver = TestDriver::loopback();
+        let down = driver.down.clone();
+        let (self_address, mut inbound_rx, outbound_tx, task) = initialize_mixnet(
+            MixnetSource::Driver(Box::new(driver)),
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            delivery,
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        let id = ConnectionId::generate();
+        let send = |nonce| {
+            outbound_tx
+                .send(message::OutboundMessage {
+                    message: Message::TransportMessage(TransportMessage {
+                        nonce,
+                        id: id.clone(),
+                        message: SubstreamMessage::new_with_data(
+                            SubstreamId::generate(),
+                            b"hello".to_vec(),
+                        ),
+                    }),
+                    recipient: Some(self_address),
+                    sender_tag: None,
+                    queued_at: Instant::now(),
+                    session: None,
+                    write_credit: None,
+                    compact_ids: false,
+                    alias: None,
+                })
+                .unwrap()
+        };
+        let recv_nonce = |msg: message::InboundMessage| match msg.0 {
+            Message::TransportMessage(msg) => msg.nonce,
+            _ => panic!("expected Message::TransportMessage"),
+        };
+
+        // data the client refuses is kept...
+        down.store(true, Ordering::SeqCst);
+        send(1);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while outbox.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // ...and sent once the client takes messages again
+        down.store(false, Ordering::SeqCst);
+        send(2);
+        assert_eq!(recv_nonce(inbound_rx.recv().await.unwrap()), 2);
+        assert_eq!(recv_nonce(inbound_rx.recv().await.unwrap()), 1);
+        assert!(outbox.is_empty());
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.outbox_stored, 1);
+        assert_eq!(snapshot.outbox_flushed, 1);
+
+        // what's left on shutdown is saved for the next transport
+        down.store(true, Ordering::SeqCst);
+        send(3);
+        task.shutdown().await;
+        let saved = Outbox::new(config, metrics).take_saved().unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].id, id);
+        assert_eq!(saved[0].recipient, Some(self_address));
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_outbox_routes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StoreAndForward::new(dir.path().join("outbox"), [1u8; 32]);
+        let outbox = Arc::new(Outbox::new(config, Default::default()));
+        let delivery = DeliveryReport {
+            outbox: Some(outbox.clone()),
+            ..Default::default()
+        };
+        let id = ConnectionId::generate();
+        let old_tag = AnonymousSenderTag::new_random(&mut rand::rngs::OsRng);
+        outbox.store(StoredMessage::new(
+            id.clone(),
+            None,
+            Some(old_tag),
+            0,
+            b"hello",
+        ));
+
+        // the remote migrated since the client refused the message
+        let new_tag = AnonymousSenderTag::new_random(&mut rand::rngs::OsRng);
+        let route = Arc::new(ConnectionRoute::new(None, Some(old_tag)));
+        route.set_sender_tag(new_tag);
+        delivery.routes.insert(id, route);
+
+        // nothing is sent while the outbox is held back...
+        let sender = Arc::new(RouteSender::default());
+        let mixnet_sender: Arc<dyn MixnetDriverSender> = sender.clone();
+        outbox.hold();
+        flush_outbox(&mixnet_sender, &delivery).await;
+        assert!(sender.routes.lock().is_empty());
+
+        // ...and then it goes along the current route
+        outbox.release();
+        flush_outbox(&mixnet_sender, &delivery).await;
+        assert_eq!(*sender.routes.lock(), vec![(None, Some(new_tag))]);
+        assert!(outbox.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_outbox_flush_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StoreAndForward::new(dir.path().join("outbox"), [1u8; 32]);
+        let outbox = Arc::new(Outbox::new(config, Default::default()));
+        let delivery = DeliveryReport {
+            outbox: Some(outbox.clone()),
+            ..Default::default()
+        };
+        for bytes in [b"one", b"two", b"six"] {
+            let msg = StoredMessage::new(
+                ConnectionId::generate(),
+                Some(random_address()),
+                None,
+                0,
+                bytes,
+            );
+            outbox.store(msg);
+        }
+
+        // the client takes the first message, and then blocks the flush...
+        let (sent_tx, mut sent_rx) = unbounded_channel();
+        let mixnet_sender: Arc<dyn MixnetDriverSender> = Arc::new(GatedSender {
+            sent_tx,
+            gate: Arc::new(Semaphore::new(1)),
+        });
+        let flush = flush_outbox(&mixnet_sender, &delivery);
+        assert!(tokio::time::timeout(Duration::from_millis(50), flush)
+            .await
+            .is_err());
+        assert_eq!(sent_rx.try_recv().unwrap().message, b"one");
+
+        // ...which is cancelled without losing the rest
+        let kept = outbox.take();
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0].bytes, &b"two"[..]);
+        assert_eq!(kept[1].bytes, &b"six"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_outbox_after_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StoreAndForward::new(dir.path().join("outbox"), [1u8; 32]);
+        let outbox = Arc::new(Outbox::new(config, Default::default()));
+        let delivery = DeliveryReport {
+            outbox: Some(outbox.clone()),
+            ..Default::default()
+        };
+        let driver = TestDriver::loopback();
+        let down = driver.down.clone();
+        let (replace_tx, replace_rx) = unbounded_channel();
+        let (address_tx, mut address_rx) = unbounded_channel();
+        let switch = ClientSwitch {
+            replace_rx,
+            #[cfg(feature = "nym-client")]
+            failover: None,
+            rotation: None,
+            alias_rx: unbounded_channel().1,
+            address_tx,
+        };
+        let (self_address, mut inbound_rx, outbound_tx, _task) = initialize_mixnet(
+            MixnetSource::Driver(Box::new(driver)),
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            delivery,
+            Default::default(),
+            Some(switch),
+        )
+        .await
+        .unwrap();
+        let id = ConnectionId::generate();
+        let send = |message| {
+            outbound_tx
+                .send(message::OutboundMessage {
+                    message,
+                    recipient: Some(self_address),
+                    sender_tag: None,
+                    queued_at: Instant::now(),
+                    session: None,
+                    write_credit: None,
+                    compact_ids: false,
+                    alias: None,
+                })
+                .unwrap()
+        };
+
+        // data the client refuses is kept
+        down.store(true, Ordering::SeqCst);
+        send(Message::TransportMessage(TransportMessage::new(
+            id.clone(),
+            1,
+            SubstreamMessage::new_with_data(SubstreamId::generate(), b"hello".to_vec()),
+        )));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while outbox.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // a new client doesn't send it before the transport migrated
+        replace_tx.send(Box::new(TestDriver::loopback())).unwrap();
+        assert!(matches!(
+            address_rx.recv().await,
+            Some(AddressChange::Replaced(_))
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!outbox.is_empty());
+
+        // and then only after the MigrateMessages
+        let keypair = libp2p_identity::Keypair::generate_ed25519();
+        send(Message::Migrate(
+            MigrateMessage::new_signed(&keypair, id.clone(), None).unwrap(),
+        ));
+        outbox.migrated();
+        assert!(matches!(
+            inbound_rx.recv().await.unwrap().0,
+            Message::Migrate(_)
+        ));
+        match inbound_rx.recv().await.unwrap().0 {
+            Message::TransportMessage(msg) => assert_eq!(msg.nonce, 1),
+            _ => panic!("expected Message::TransportMessage"),
+        }
+        assert!(outbox.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_offline_buffer() {
+        let metrics = Arc::new(TransportMetrics::default());
+        let config = OfflineBuffer {
+            max_messages: 2,
+            ttl: Duration::from_millis(500),
+        };
+        let outage = Arc::new(Outage::new(config, metrics.clone()));
+        let expiry = OutboundExpiry {
+            ttl: None,
+            outage: Some(outage.clone()),
+            connection_stats: Default::default(),
+            metrics: metrics.clone(),
+        };
+        // the first client disconnects once its inbound messages end
+        let (messages_tx, messages_rx) = unbounded_channel();
+        let (sent_tx, _sent_rx) = unbounded_channel();
+        let driver = TestDriver::new(UnboundedReceiverStream::new(messages_rx).boxed(), sent_tx);
+        let (replace_tx, replace_rx) = unbounded_channel();
+        let (address_tx, _address_rx) = unbounded_channel();
+        let switch = ClientSwitch {
+            replace_rx,
+            #[cfg(feature = "nym-client")]
+            failover: None,
+            rotation: None,
+            alias_rx: unbounded_channel().1,
+            address_tx,
+        };
+        let (self_address, mut inbound_rx, outbound_tx, _task) = initialize_mixnet(
+            MixnetSource::Driver(Box::new(driver)),
+            None,
+            Some(expiry),
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+            Some(switch),
+        )
+        .await
+        .unwrap();
+        let id = ConnectionId::generate();
+        let send = |nonce| {
+            outbound_tx.send(message::OutboundMessage {
+                message: Message::TransportMessage(TransportMessage {
+                    nonce,
+                    id: id.clone(),
+                    message: SubstreamMessage::new_with_data(
+                        SubstreamId::generate(),
+                        b"hello".to_vec(),
+                    ),
+                }),
+                recipient: Some(self_address),
+                sender_tag: None,
+                queued_at: Instant::now(),
+                session: None,
+                write_credit: None,
+                compact_ids: false,
+                alias: None,
+            })
+        };
+
+        drop(messages_tx);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while outage.window.read().is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // data is held back up to the limit; beyond it, the substream is closed
+        send(1).unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        send(2).unwrap();
+        assert!(matches!(send(3), Err(Error::OfflineBufferFull(2))));
+
+        // once the client is replaced, the held back data is sent, unless
+        // it expired meanwhile
+        replace_tx.send(Box::new(TestDriver::loopback())).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while matches!(*outage.window.read(), Some((_, None))) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        send(4).unwrap();
+        for (nonce, data) in [(1, false), (2, true), (3, false), (4, true)] {
+            match inbound_rx.recv().await.unwrap().0 {
+                Message::TransportMessage(tm) => {
+                    assert_eq!(tm.nonce, nonce);
+                    let is_data = matches!(tm.message.message_type, SubstreamMessageType::Data(_));
+                    assert_eq!(is_data, data);
                 }
+                _ => panic!("expected Message::TransportMessage"),
             }
         }
-        None => Err(Error::RecvFailure),
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.offline_buffered, 2);
+        assert_eq!(snapshot.offline_buffer_overflows, 1);
+        assert_eq!(snapshot.messages_expired, 1);
     }
-}
 
-async fn write_bytes(
-    mixnet_sender: &MixnetClientSender,
-    recipient: Recipient,
-    message: &[u8],
-) -> Result<(), Error> {
-    if let Err(_err) = mixnet_sender
-        .send_message(recipient, message, IncludedSurbs::default()) // was IncludedSurbs::ExposeSelfAddress
+    #[tokio::test]
+    async fn test_mixnet_address_rotation() {
+        let (old_messages_tx, old_messages_rx) = unbounded_channel();
+        let (old_sent_tx, mut old_sent_rx) = unbounded_channel();
+        let old_driver = TestDriver::new(
+            UnboundedReceiverStream::new(old_messages_rx).boxed(),
+            old_sent_tx,
+        );
+        let old_address = old_driver.address;
+        let old_disconnected = old_driver.disconnected.clone();
+        let (new_messages_tx, new_messages_rx) = unbounded_channel();
+        let (new_sent_tx, mut new_sent_rx) = unbounded_channel();
+        let new_driver = TestDriver::new(
+            UnboundedReceiverStream::new(new_messages_rx).boxed(),
+            new_sent_tx,
+        );
+        let new_address = new_driver.address;
+        let new_disconnected = new_driver.disconnected.clone();
+
+        let metrics = Arc::new(TransportMetrics::default());
+        let (clients_tx, clients_rx) = unbounded_channel();
+        let (address_tx, mut address_rx) = unbounded_channel();
+        let switch = ClientSwitch {
+            replace_rx: unbounded_channel().1,
+            #[cfg(feature = "nym-client")]
+            failover: None,
+            rotation: Some(Rotation {
+                clients_rx,
+                grace_period: Duration::from_millis(200),
+            }),
+            alias_rx: unbounded_channel().1,
+            address_tx,
+        };
+        let delivery = DeliveryReport {
+            metrics: metrics.clone(),
+            ..Default::default()
+        };
+        let (_, mut inbound_rx, outbound_tx, _task) = initialize_mixnet(
+            MixnetSource::Driver(Box::new(old_driver)),
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            delivery,
+            Default::default(),
+            Some(switch),
+        )
         .await
-    {
-        return Err(Error::Unimplemented);
+        .unwrap();
+        let message = |nonce, sender_tag| ReconstructedMessage {
+            message: Message::TransportMessage(TransportMessage {
+                nonce,
+                id: ConnectionId::generate(),
+                message: SubstreamMessage::new_with_data(
+                    SubstreamId::generate(),
+                    b"hello".to_vec(),
+                ),
+            })
+            .to_bytes(),
+            sender_tag,
+        };
+        let reply = |recipient, sender_tag| message::OutboundMessage {
+            message: Message::TransportMessage(TransportMessage {
+                nonce: 1,
+                id: ConnectionId::generate(),
+                message: SubstreamMessage::new_with_data(SubstreamId::generate(), vec![]),
+            }),
+            recipient,
+            sender_tag,
+            queued_at: Instant::now(),
+            session: None,
+            write_credit: None,
+            compact_ids: false,
+            alias: None,
+        };
+        let old_tag = AnonymousSenderTag::new_random(&mut rand::rngs::OsRng);
+        old_messages_tx.send(message(1, Some(old_tag))).unwrap();
+        inbound_rx.recv().await.unwrap();
+
+        // the new client's address is announced right away
+        clients_tx.send(Box::new(new_driver)).unwrap();
+        match address_rx.recv().await.unwrap() {
+            AddressChange::Rotated(address) => assert_eq!(address, new_address),
+            _ => panic!("expected AddressChange::Rotated"),
+        }
+        assert_eq!(metrics.snapshot().address_rotations, 1);
+
+        // while both clients receive during the grace period
+        old_messages_tx.send(message(2, Some(old_tag))).unwrap();
+        assert_eq!(inbound_rx.recv().await.unwrap().1, Some(old_tag));
+        let new_tag = AnonymousSenderTag::new_random(&mut rand::rngs::OsRng);
+        new_messages_tx.send(message(3, Some(new_tag))).unwrap();
+        assert_eq!(inbound_rx.recv().await.unwrap().1, Some(new_tag));
+
+        // and replies go out over the client which holds their SURBs
+        outbound_tx.send(reply(None, Some(old_tag))).unwrap();
+        old_sent_rx.recv().await.unwrap();
+        outbound_tx.send(reply(None, Some(new_tag))).unwrap();
+        new_sent_rx.recv().await.unwrap();
+        outbound_tx.send(reply(Some(old_address), None)).unwrap();
+        new_sent_rx.recv().await.unwrap();
+        assert!(old_sent_rx.try_recv().is_err());
+
+        // once the grace period is over, the old client is disconnected
+        match address_rx.recv().await.unwrap() {
+            AddressChange::Retired(address) => assert_eq!(address, old_address),
+            _ => panic!("expected AddressChange::Retired"),
+        }
+        assert!(old_disconnected.load(Ordering::SeqCst));
+        assert!(!new_disconnected.load(Ordering::SeqCst));
     }
-    debug!("wrote message to recipient: {:?}", recipient.to_string());
-    Ok(())
-}
 
-async fn write_reply_bytes(
-    mixnet_sender: &MixnetClientSender,
-    sender_tag: AnonymousSenderTag,
-    message: &[u8],
-) -> Result<(), Error> {
-    if let Err(_err) = mixnet_sender.send_reply(sender_tag, message).await {
-        return Err(Error::Unimplemented);
+    #[tokio::test]
+    async fn test_mixnet_address_rotation_during_grace_period() {
+        let grace_period = Duration::from_millis(200);
+        let first = TestDriver::loopback();
+        let (first_address, first_disconnected) = (first.address, first.disconnected.clone());
+        let second = TestDriver::loopback();
+        let second_address = second.address;
+        let third = TestDriver::loopback();
+        let third_address = third.address;
+
+        let (clients_tx, clients_rx) = unbounded_channel();
+        let (address_tx, mut address_rx) = unbounded_channel();
+        let switch = ClientSwitch {
+            replace_rx: unbounded_channel().1,
+            #[cfg(feature = "nym-client")]
+            failover: None,
+            rotation: Some(Rotation {
+                clients_rx,
+                grace_period,
+            }),
+            alias_rx: unbounded_channel().1,
+            address_tx,
+        };
+        let (_, _inbound_rx, _outbound_tx, _task) = initialize_mixnet(
+            MixnetSource::Driver(Box::new(first)),
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+            Some(switch),
+        )
+        .await
+        .unwrap();
+
+        let rotated_at = Instant::now();
+        clients_tx.send(Box::new(second)).unwrap();
+        match address_rx.recv().await.unwrap() {
+            AddressChange::Rotated(address) => assert_eq!(address, second_address),
+            _ => panic!("expected AddressChange::Rotated"),
+        }
+
+        // a rotation within the grace period waits for the old client to retire
+        clients_tx.send(Box::new(third)).unwrap();
+        match address_rx.recv().await.unwrap() {
+            AddressChange::Retired(address) => assert_eq!(address, first_address),
+            _ => panic!("expected AddressChange::Retired"),
+        }
+        assert!(rotated_at.elapsed() >= grace_period);
+        assert!(first_disconnected.load(Ordering::SeqCst));
+        match address_rx.recv().await.unwrap() {
+            AddressChange::Rotated(address) => assert_eq!(address, third_address),
+            _ => panic!("expected AddressChange::Rotated"),
+        }
     }
-    debug!("wrote reply to sender_tag: {:?}", sender_tag.to_string());
-    Ok(())
-}
 
-#[cfg(test)]
-mod test {
-    use super::super::message::{
-        self, ConnectionId, Message, SubstreamId, SubstreamMessage, SubstreamMessageType,
-        TransportMessage,
-    };
-    use super::super::mixnet::initialize_mixnet;
-    use nym_sdk::mixnet::MixnetClient;
+    #[tokio::test]
+    async fn test_mixnet_address_aliases() {
+        let (_messages_tx, messages_rx) = unbounded_channel();
+        let (sent_tx, mut sent_rx) = unbounded_channel();
+        let driver = TestDriver::new(UnboundedReceiverStream::new(messages_rx).boxed(), sent_tx);
+        let (alias_messages_tx, alias_messages_rx) = unbounded_channel();
+        let (alias_sent_tx, mut alias_sent_rx) = unbounded_channel();
+        let alias_driver = TestDriver::new(
+            UnboundedReceiverStream::new(alias_messages_rx).boxed(),
+            alias_sent_tx,
+        );
+        let alias_disconnected = alias_driver.disconnected.clone();
+
+        let (alias_tx, alias_rx) = unbounded_channel();
+        let (address_tx, mut address_rx) = unbounded_channel();
+        let registry = AliasRegistry::default();
+        let switch = ClientSwitch {
+            replace_rx: unbounded_channel().1,
+            #[cfg(feature = "nym-client")]
+            failover: None,
+            rotation: None,
+            alias_rx,
+            address_tx,
+        };
+        let (_, mut inbound_rx, outbound_tx, _task) = initialize_mixnet(
+            MixnetSource::Driver(Box::new(driver)),
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+            Some(switch),
+        )
+        .await
+        .unwrap();
+        let aliases = AddressAliases {
+            registry: registry.clone(),
+            commands_tx: alias_tx,
+        };
+        let alias_addr = aliases.add_driver(alias_driver).unwrap();
+        assert_eq!(aliases.aliases(), vec![alias_addr.clone()]);
+
+        // messages received at the alias are forwarded, tagged with the alias
+        let sender_tag = AnonymousSenderTag::new_random(&mut rand::rngs::OsRng);
+        alias_messages_tx
+            .send(ReconstructedMessage {
+                message: Message::TransportMessage(TransportMessage {
+                    nonce: 1,
+                    id: ConnectionId::generate(),
+                    message: SubstreamMessage::new_with_data(
+                        SubstreamId::generate(),
+                        b"hello".to_vec(),
+                    ),
+                })
+                .to_bytes(),
+                sender_tag: Some(sender_tag),
+            })
+            .unwrap();
+        let inbound = inbound_rx.recv().await.unwrap();
+        assert_eq!(inbound.1, Some(sender_tag));
+        let alias = inbound.2.unwrap();
+
+        // so that the replies on its connections go out through the alias's
+        // client, while those on connections to our own address don't, even
+        // if the remote's client uses the same sender tag for both
+        let reply = |alias| message::OutboundMessage {
+            message: Message::TransportMessage(TransportMessage {
+                nonce: 1,
+                id: ConnectionId::generate(),
+                message: SubstreamMessage::new_with_data(SubstreamId::generate(), vec![]),
+            }),
+            recipient: None,
+            sender_tag: Some(sender_tag),
+            queued_at: Instant::now(),
+            session: None,
+            write_credit: None,
+            compact_ids: false,
+            alias,
+        };
+        outbound_tx.send(reply(Some(alias))).unwrap();
+        alias_sent_rx.recv().await.unwrap();
+        outbound_tx.send(reply(None)).unwrap();
+        sent_rx.recv().await.unwrap();
+        assert!(alias_sent_rx.try_recv().is_err());
+
+        // a removed alias's client is disconnected, and the transport told
+        aliases.remove(&alias_addr).unwrap();
+        assert!(matches!(
+            aliases.remove(&alias_addr),
+            Err(Error::UnknownAlias)
+        ));
+        match address_rx.recv().await.unwrap() {
+            AddressChange::AliasRemoved(id) => assert_eq!(id, alias),
+            _ => panic!("expected AddressChange::AliasRemoved"),
+        }
+        assert!(alias_disconnected.load(Ordering::SeqCst));
+        assert!(aliases.aliases().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_surb_exhausted() {
+        let (sent_tx, mut sent_rx) = unbounded_channel();
+        let (surbs_exhausted_tx, mut surbs_exhausted_rx) = unbounded_channel();
+        let delivery = DeliveryReport {
+            surb_retry_delay: Duration::from_millis(10),
+            surbs_exhausted_tx: Some(surbs_exhausted_tx),
+            ..Default::default()
+        };
+        let backlog = OutboundBacklog::new(None, watch::channel(None).0, Default::default());
+        let send = |sender: Arc<dyn MixnetDriverSender>, id: ConnectionId| {
+            let message = message::OutboundMessage {
+                message: Message::Ack(AckMessage {
+                    id,
+                    nonce: 1,
+                    sacks: vec![],
+                }),
+                recipient: None,
+                sender_tag: Some(AnonymousSenderTag::new_random(&mut rand::rngs::OsRng)),
+                queued_at: Instant::now(),
+                session: None,
+                write_credit: None,
+                compact_ids: false,
+                alias: None,
+            };
+            backlog.queued();
+            let delivery = &delivery;
+            let backlog = &backlog;
+            async move {
+                send_outbound(
+                    &sender,
+                    message,
+                    backlog,
+                    &None,
+                    &Default::default(),
+                    &None,
+                    delivery,
+                    &Default::default(),
+                    &mut vec![],
+                )
+                .await
+            }
+        };
+
+        // a reply which ran out of SURBs is held on to, and sent again once
+        // its retry is due
+        let sender = Arc::new(ExhaustedSender {
+            sent_tx: sent_tx.clone(),
+            exhausted: 1,
+            replies: AtomicU64::new(0),
+        });
+        let sink: Arc<dyn MixnetDriverSender> = sender.clone();
+        let aliases = AliasSinks::default();
+        assert!(matches!(
+            send(sink.clone(), ConnectionId::generate()).await,
+            Err(Error::SurbExhausted)
+        ));
+        assert_eq!(delivery.retries.len(), 1);
+        assert!(delivery.retries.budget.used() > 0);
+        retry_replies(&sink, None, &aliases, &delivery).await;
+        assert_eq!(delivery.retries.len(), 1);
+        delivery.retries.first_due().await;
+        retry_replies(&sink, None, &aliases, &delivery).await;
+        sent_rx.recv().await.unwrap();
+        assert_eq!(sender.replies.load(Ordering::SeqCst), 2);
+        assert_eq!(delivery.retries.len(), 0);
+        assert_eq!(delivery.retries.budget.used(), 0);
+        assert!(surbs_exhausted_rx.try_recv().is_err());
+        assert_eq!(delivery.metrics.snapshot().send_failures, 0);
+
+        // the connection is reported once the retry runs out of them as well
+        let sender = Arc::new(ExhaustedSender {
+            sent_tx: sent_tx.clone(),
+            exhausted: 2,
+            replies: AtomicU64::new(0),
+        });
+        let sink: Arc<dyn MixnetDriverSender> = sender.clone();
+        let id = ConnectionId::generate();
+        delivery.connection_stats.insert(
+            id.clone(),
+            ConnectionStats {
+                peer_id: PeerId::random(),
+                endpoint: Endpoint::Listener,
+                remote_address: None,
+                handshake_rtt: None,
+                setup_duration: None,
+                expired_messages: 0,
+                delivery: DeliveryStats::default(),
+                reorder: Default::default(),
+                reply_surbs: Some(ReplySurbBudget::default()),
+                rtt: None,
+                frame_size: None,
+            },
+        );
+        assert!(send(sink.clone(), id.clone()).await.is_err());
+        delivery.retries.first_due().await;
+        retry_replies(&sink, None, &aliases, &delivery).await;
+        assert_eq!(surbs_exhausted_rx.recv().await.unwrap(), id);
+        assert_eq!(sender.replies.load(Ordering::SeqCst), 2);
+        assert!(sent_rx.try_recv().is_err());
+
+        // both attempts are accounted to the connection and the metrics, and
+        // the failed retry is reported as a failed send
+        let budget = delivery.connection_stats.all()[0]
+            .reply_surbs
+            .clone()
+            .unwrap();
+        assert_eq!((budget.used, budget.exhausted), (0, 2));
+        let metrics = delivery.metrics.snapshot();
+        assert_eq!(metrics.send_failures, 1);
+        assert_eq!(metrics.reply_surbs_used, 0);
+        assert_eq!(metrics.remote_reply_surbs_used, 1);
+        assert_eq!(metrics.reply_surbs_exhausted, 3);
+
+        // replies beyond the bound aren't held on to, and fail right away
+        let sender = Arc::new(ExhaustedSender {
+            sent_tx,
+            exhausted: u64::MAX,
+            replies: AtomicU64::new(0),
+        });
+        let sink: Arc<dyn MixnetDriverSender> = sender.clone();
+        for _ in 0..MAX_REPLY_RETRIES {
+            assert!(send(sink.clone(), ConnectionId::generate()).await.is_err());
+        }
+        assert!(surbs_exhausted_rx.try_recv().is_err());
+        let id = ConnectionId::generate();
+        assert!(send(sink.clone(), id.clone()).await.is_err());
+        assert_eq!(surbs_exhausted_rx.try_recv().unwrap(), id);
+        assert_eq!(delivery.retries.len(), MAX_REPLY_RETRIES);
+
+        // and the ones held on to are cancelled along with the mixnet task
+        let budget = delivery.retries.budget.clone();
+        assert!(budget.used() > 0);
+        drop(delivery);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_redundancy() {
+        let metrics = Arc::new(TransportMetrics::default());
+        let redundancy = Redundancy {
+            handshake: 1,
+            close: 0,
+            ack: 2,
+            delay: Duration::from_millis(10),
+        };
+        let (self_address, mut inbound_rx, outbound_tx, _task) = initialize_mixnet(
+            MixnetSource::Driver(Box::new(TestDriver::loopback())),
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            Some(redundancy),
+            DeliveryReport {
+                metrics: metrics.clone(),
+                ..Default::default()
+            },
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        let id = ConnectionId::generate();
+        let send = |message| {
+            outbound_tx
+                .send(message::OutboundMessage {
+                    message,
+                    recipient: Some(self_address),
+                    sender_tag: None,
+                    queued_at: Instant::now(),
+                    session: None,
+                    write_credit: None,
+                    compact_ids: false,
+                    alias: None,
+                })
+                .unwrap()
+        };
 
+        // acks are sent three times, closes once
+        send(Message::Ack(AckMessage {
+            id: id.clone(),
+            nonce: 1,
+            sacks: vec![],
+        }));
+        send(Message::ConnectionClose(ConnectionCloseMessage::new(
+            id.clone(),
+            CloseReason::new(CloseCode::Shutdown),
+        )));
+        let (mut acks, mut closes) = (0, 0);
+        for _ in 0..4 {
+            match inbound_rx.recv().await.unwrap().0 {
+                Message::Ack(_) => acks += 1,
+                Message::ConnectionClose(_) => closes += 1,
+                _ => panic!("expected an Ack or a ConnectionClose"),
+            }
+        }
+        assert_eq!((acks, closes), (3, 1));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), inbound_rx.recv())
+                .await
+                .is_err()
+        );
+        assert_eq!(metrics.snapshot().redundant_copies, 2);
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_forward_error_correction() {
+        let metrics = Arc::new(TransportMetrics::default());
+        let fec = FecRegistry::new(metrics.clone());
+        let driver = TestDriver::loopback();
+        let down = driver.down.clone();
+        let (self_address, mut inbound_rx, outbound_tx, _task) = initialize_mixnet(
+            MixnetSource::Driver(Box::new(driver)),
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            DeliveryReport {
+                metrics: metrics.clone(),
+                fec: fec.clone(),
+                ..Default::default()
+            },
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        // the loopback connection sends its parity to itself
+        let id = ConnectionId::generate();
+        fec.insert(
+            id.clone(),
+            Some(ForwardErrorCorrection {
+                data_shards: 2,
+                parity_shards: 1,
+            }),
+        );
+        let send = |nonce| {
+            outbound_tx
+                .send(message::OutboundMessage {
+                    message: Message::TransportMessage(TransportMessage {
+                        nonce,
+                        id: id.clone(),
+                        message: SubstreamMessage::new_with_data(
+                            SubstreamId::generate(),
+                            vec![nonce as u8; 100],
+                        ),
+                    }),
+                    recipient: Some(self_address),
+                    sender_tag: None,
+                    queued_at: Instant::now(),
+                    session: None,
+                    write_credit: None,
+                    compact_ids: false,
+                    alias: None,
+                })
+                .unwrap()
+        };
+
+        // the first message of the group is lost..
+        down.store(true, Ordering::SeqCst);
+        send(2);
+        while metrics.snapshot().send_failures == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        down.store(false, Ordering::SeqCst);
+
+        // ..and rebuilt once the second one and the parity arrive
+        send(3);
+        let mut nonces = vec![];
+        for _ in 0..2 {
+            match inbound_rx.recv().await.unwrap().0 {
+                Message::TransportMessage(msg) => nonces.push(msg.nonce),
+                _ => panic!("expected a TransportMessage"),
+            }
+        }
+        assert_eq!(nonces, vec![3, 2]);
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot.fec_parity_sent, snapshot.fec_recovered), (1, 1));
+    }
+
+    #[cfg(feature = "nym-client")]
     #[tokio::test]
     async fn test_mixnet_poll_inbound_and_outbound() {
         let client = MixnetClient::connect_new().await.unwrap();
-        let (self_address, mut inbound_rx, outbound_tx) =
-            initialize_mixnet(client, None).await.unwrap();
+        let (self_address, mut inbound_rx, outbound_tx, task) = initialize_mixnet(
+            client,
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
         let msg_inner = "hello".as_bytes();
         let substream_id = SubstreamId::generate();
         let msg = Message::TransportMessage(TransportMessage {
@@ -211,6 +4312,11 @@ mod test {
             message: msg,
             recipient: Some(self_address),
             sender_tag: None,
+            queued_at: std::time::Instant::now(),
+            session: None,
+            write_credit: None,
+            compact_ids: false,
+            alias: None,
         };
 
         outbound_tx.send(out_msg).unwrap();
@@ -220,12 +4326,16 @@ mod test {
         if let Message::TransportMessage(recv_msg) = received_msg.0 {
             assert_eq!(substream_id, recv_msg.message.substream_id);
             if let SubstreamMessageType::Data(data) = recv_msg.message.message_type {
-                assert_eq!(msg_inner, data.as_slice());
+                assert_eq!(msg_inner, data.as_ref());
             } else {
                 panic!("expected SubstreamMessage::Data")
             }
         } else {
             panic!("expected Message::TransportMessage")
         }
+
+        // stopping the task disconnects the client and closes the channels
+        task.shutdown().await;
+        assert!(inbound_rx.recv().await.is_none());
     }
 }
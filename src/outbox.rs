@@ -0,0 +1,325 @@
+use bytes::Bytes;
+use nym_sphinx::addressing::clients::Recipient;
+use nym_sphinx::anonymous_replies::requests::AnonymousSenderTag;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+use super::codec::{self, put_length_prefixed, replace_file, take_file, unix_time, Decoder};
+use super::config::StoreAndForward;
+use super::error::Error;
+use super::message::ConnectionId;
+use super::metrics::TransportMetrics;
+
+/// version of the saved outbox's plaintext encoding.
+const OUTBOX_VERSION: u8 = 1;
+const OUTBOX_AAD: &[u8] = b"nym-libp2p-outbox";
+const SENDER_TAG_LENGTH: usize = 16;
+const HAS_RECIPIENT: u8 = 1;
+const HAS_SENDER_TAG: u8 = 2;
+
+/// StoredMessage is an encoded message the mixnet client didn't take, along
+/// with where it goes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct StoredMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) recipient: Option<Recipient>,
+    pub(crate) sender_tag: Option<AnonymousSenderTag>,
+    pub(crate) reply_surbs: u32,
+    pub(crate) bytes: Bytes,
+    /// unix time the message was stored at, so that its age survives restarts.
+    pub(crate) stored_at: u64,
+}
+
+impl StoredMessage {
+    pub(crate) fn new(
+        id: ConnectionId,
+        recipient: Option<Recipient>,
+        sender_tag: Option<AnonymousSenderTag>,
+        reply_surbs: u32,
+        bytes: &[u8],
+    ) -> Self {
+        StoredMessage {
+            id,
+            recipient,
+            sender_tag,
+            reply_surbs,
+            bytes: Bytes::copy_from_slice(bytes),
+            stored_at: unix_time(),
+        }
+    }
+}
+
+/// Outbox keeps the messages the mixnet client refused, until they're sent
+/// again; see `StoreAndForward`. It's shared by the mixnet task, which fills
+/// and flushes it, and the transport, which picks up the messages saved by
+/// the previous one.
+pub(crate) struct Outbox {
+    config: StoreAndForward,
+    messages: Mutex<VecDeque<StoredMessage>>,
+    metrics: Arc<TransportMetrics>,
+    /// whether the messages are held back; see `hold`.
+    held: AtomicBool,
+    /// notified once the transport moved its connections to a new client.
+    migrated: Notify,
+}
+
+impl Outbox {
+    pub(crate) fn new(config: StoreAndForward, metrics: Arc<TransportMetrics>) -> Self {
+        Outbox {
+            config,
+            messages: Mutex::new(VecDeque::new()),
+            metrics,
+            held: AtomicBool::new(false),
+            migrated: Notify::new(),
+        }
+    }
+
+    /// holds the messages back once the mixnet client was replaced, until
+    /// the transport moved its connections to the new one: they're only
+    /// sent after the connections' MigrateMessages, see `release`.
+    pub(crate) fn hold(&self) {
+        self.held.store(true, Ordering::Relaxed);
+    }
+
+    /// lets the held back messages be sent.
+    pub(crate) fn release(&self) {
+        self.held.store(false, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_held(&self) -> bool {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    /// tells the mixnet task that the transport queued the MigrateMessages
+    /// for a new client.
+    pub(crate) fn migrated(&self) {
+        self.migrated.notify_one();
+    }
+
+    /// waits until the transport calls `migrated`.
+    pub(crate) async fn wait_migrated(&self) {
+        self.migrated.notified().await
+    }
+
+    /// keeps a message, dropping the oldest one if the outbox is full.
+    pub(crate) fn store(&self, msg: StoredMessage) {
+        let mut messages = self.messages.lock();
+        while messages.len() >= self.config.max_messages.max(1) {
+            messages.pop_front();
+            TransportMetrics::inc(&self.metrics.outbox_dropped);
+        }
+        messages.push_back(msg);
+        TransportMetrics::inc(&self.metrics.outbox_stored);
+    }
+
+    /// puts back messages which were taken, but couldn't be sent, ahead of
+    /// the ones stored since.
+    pub(crate) fn put_back(&self, taken: Vec<StoredMessage>) {
+        let mut messages = self.messages.lock();
+        for msg in taken.into_iter().rev() {
+            messages.push_front(msg);
+        }
+        while messages.len() > self.config.max_messages.max(1) {
+            messages.pop_front();
+            TransportMetrics::inc(&self.metrics.outbox_dropped);
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.messages.lock().is_empty()
+    }
+
+    /// takes the messages to send again, oldest first, dropping the expired ones.
+    pub(crate) fn take(&self) -> Vec<StoredMessage> {
+        let messages = std::mem::take(&mut *self.messages.lock());
+        self.unexpired(messages.into())
+    }
+
+    /// writes the outbox to its file, replacing what's there.
+    pub(crate) fn save(&self) -> Result<(), Error> {
+        let messages = self.take();
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let plaintext = encode(&messages);
+        let bytes = codec::seal(self.config.key(), OUTBOX_AAD, &plaintext)?;
+        replace_file(&self.config.path, &bytes)?;
+        Ok(())
+    }
+
+    /// reads the messages the previous transport saved, and removes them from
+    /// the file, so that they're never sent twice.
+    pub(crate) fn take_saved(&self) -> Result<Vec<StoredMessage>, Error> {
+        let Some(bytes) = take_file(&self.config.path)? else {
+            return Ok(vec![]);
+        };
+        let plaintext =
+            codec::open(self.config.key(), OUTBOX_AAD, &bytes).ok_or(Error::InvalidOutbox)?;
+        Ok(self.unexpired(decode(&plaintext)?))
+    }
+
+    fn unexpired(&self, messages: Vec<StoredMessage>) -> Vec<StoredMessage> {
+        let now = unix_time();
+        let count = messages.len();
+        let messages: Vec<StoredMessage> = messages
+            .into_iter()
+            .filter(|msg| now.saturating_sub(msg.stored_at) <= self.config.max_age.as_secs())
+            .collect();
+        TransportMetrics::add(
+            &self.metrics.outbox_dropped,
+            (count - messages.len()) as u64,
+        );
+        messages
+    }
+}
+
+fn encode(messages: &[StoredMessage]) -> Vec<u8> {
+    let mut bytes = vec![OUTBOX_VERSION];
+    bytes.extend_from_slice(&(messages.len() as u32).to_be_bytes());
+    for msg in messages {
+        bytes.extend_from_slice(&msg.id.0);
+        let mut fields = 0;
+        if msg.recipient.is_some() {
+            fields |= HAS_RECIPIENT;
+        }
+        if msg.sender_tag.is_some() {
+            fields |= HAS_SENDER_TAG;
+        }
+        bytes.push(fields);
+        if let Some(recipient) = &msg.recipient {
+            bytes.extend_from_slice(&recipient.to_bytes());
+        }
+        if let Some(sender_tag) = &msg.sender_tag {
+            bytes.extend_from_slice(&sender_tag.to_bytes());
+        }
+        bytes.extend_from_slice(&msg.reply_surbs.to_be_bytes());
+        bytes.extend_from_slice(&msg.stored_at.to_be_bytes());
+        put_length_prefixed(&mut bytes, &msg.bytes);
+    }
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Result<Vec<StoredMessage>, Error> {
+    let mut bytes = Decoder::new(bytes, || Error::InvalidOutbox);
+    if bytes.take_u8()? != OUTBOX_VERSION {
+        return Err(Error::InvalidOutbox);
+    }
+    let count = bytes.take_u32()?;
+
+    let mut messages = vec![];
+    for _ in 0..count {
+        let id = ConnectionId(bytes.take_array()?);
+        let fields = bytes.take_u8()?;
+        let recipient = match fields & HAS_RECIPIENT {
+            0 => None,
+            _ => Some(
+                Recipient::try_from_bytes(bytes.take_array::<{ Recipient::LEN }>()?)
+                    .map_err(|_| Error::InvalidOutbox)?,
+            ),
+        };
+        let sender_tag = match fields & HAS_SENDER_TAG {
+            0 => None,
+            _ => Some(AnonymousSenderTag::from_bytes(
+                bytes.take_array::<SENDER_TAG_LENGTH>()?,
+            )),
+        };
+        let reply_surbs = bytes.take_u32()?;
+        let stored_at = bytes.take_u64()?;
+        messages.push(StoredMessage {
+            id,
+            recipient,
+            sender_tag,
+            reply_surbs,
+            bytes: Bytes::copy_from_slice(bytes.take_length_prefixed()?),
+            stored_at,
+        });
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test_utils::random_address;
+    use super::*;
+    use rand::rngs::OsRng;
+    use std::time::Duration;
+
+    fn outbox(path: &std::path::Path, key: [u8; 32]) -> Outbox {
+        Outbox::new(StoreAndForward::new(path, key), Default::default())
+    }
+
+    #[test]
+    fn test_outbox_save_and_take() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox");
+        let outbox = outbox(&path, [1u8; 32]);
+        let messages = vec![
+            StoredMessage::new(
+                ConnectionId::generate(),
+                Some(random_address()),
+                None,
+                4,
+                b"one",
+            ),
+            StoredMessage::new(
+                ConnectionId::generate(),
+                None,
+                Some(AnonymousSenderTag::new_random(&mut OsRng)),
+                0,
+                b"two",
+            ),
+        ];
+        for msg in &messages {
+            outbox.store(msg.clone());
+        }
+        outbox.save().unwrap();
+        assert!(outbox.is_empty());
+
+        // the next transport reads the messages once
+        let next = self::outbox(&path, [1u8; 32]);
+        assert_eq!(next.take_saved().unwrap(), messages);
+        assert!(next.take_saved().unwrap().is_empty());
+
+        // and only with the same key
+        outbox.store(messages[0].clone());
+        outbox.save().unwrap();
+        let other = self::outbox(&path, [2u8; 32]);
+        assert!(matches!(other.take_saved(), Err(Error::InvalidOutbox)));
+    }
+
+    #[test]
+    fn test_outbox_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = Arc::new(TransportMetrics::default());
+        let config = StoreAndForward::new(dir.path().join("outbox"), [1u8; 32])
+            .with_max_messages(2)
+            .with_max_age(Duration::from_secs(60));
+        let outbox = Outbox::new(config, metrics.clone());
+
+        // the oldest messages make room for new ones
+        for data in [b"one", b"two", b"six"] {
+            outbox.store(StoredMessage::new(
+                ConnectionId::generate(),
+                Some(random_address()),
+                None,
+                0,
+                data,
+            ));
+        }
+        let taken = outbox.take();
+        assert_eq!(taken.len(), 2);
+        assert_eq!(&taken[0].bytes[..], b"two");
+
+        // and old ones are dropped
+        let mut expired = taken[0].clone();
+        expired.stored_at -= 120;
+        outbox.store(expired);
+        assert!(outbox.take().is_empty());
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.outbox_stored, 4);
+        assert_eq!(snapshot.outbox_dropped, 2);
+    }
+}
@@ -0,0 +1,165 @@
+use bytes::{Buf, BufMut, Bytes};
+use parking_lot::RwLock;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::config::MessagePadding;
+use super::error::Error;
+use super::message::{ConnectionId, Message, MAX_MESSAGE_LEN, PADDED_MESSAGE_TYPE};
+
+/// a padded message starts with its type and the length of the message it
+/// holds, which is followed by the padding.
+const HEADER_LEN: usize = 5;
+
+/// pads an encoded message to the size `padding` gives for it. Messages which
+/// would grow past the largest a message may be are sent as they are.
+pub(crate) fn pad(message: &[u8], padding: &MessagePadding) -> Vec<u8> {
+    let len = padding.padded_len(HEADER_LEN + message.len());
+    if len > MAX_MESSAGE_LEN {
+        return message.to_vec();
+    }
+    let mut bytes = Vec::with_capacity(len);
+    bytes.put_u8(PADDED_MESSAGE_TYPE);
+    bytes.put_u32(message.len() as u32);
+    bytes.put_slice(message);
+    bytes.resize(len, 0);
+    bytes
+}
+
+/// returns the message a padded one holds, pointing into `bytes`. Anything
+/// else is returned as it is.
+pub(crate) fn strip_padding(bytes: Bytes) -> Result<Bytes, Error> {
+    if bytes.first() != Some(&PADDED_MESSAGE_TYPE) {
+        return Ok(bytes);
+    }
+    let mut header = bytes.get(1..HEADER_LEN).ok_or(Error::InvalidMessageBytes)?;
+    let len = header.get_u32() as usize;
+    let end = HEADER_LEN
+        .checked_add(len)
+        .ok_or(Error::InvalidMessageBytes)?;
+    let message = bytes
+        .get(HEADER_LEN..end)
+        .ok_or(Error::InvalidMessageBytes)?;
+    // padding is only ever one layer deep
+    if message.first() == Some(&PADDED_MESSAGE_TYPE) {
+        return Err(Error::InvalidMessageBytes);
+    }
+    Ok(bytes.slice(HEADER_LEN..end))
+}
+
+/// PaddingRegistry holds the padding of the connections which negotiated
+/// it. The transport adds them as the connections are set up, and the mixnet
+/// task pads the messages it sends on them. Messages of other connections,
+/// and the ConnectionRequests which offer padding, are sent as they are,
+/// since the remote may not understand padded ones.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PaddingRegistry {
+    inner: Arc<RwLock<HashMap<ConnectionId, MessagePadding>>>,
+}
+
+impl PaddingRegistry {
+    pub(crate) fn insert(&self, id: ConnectionId, padding: MessagePadding) {
+        self.inner.write().insert(id, padding);
+    }
+
+    pub(crate) fn remove(&self, id: &ConnectionId) {
+        self.inner.write().remove(id);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn contains(&self, id: &ConnectionId) -> bool {
+        self.inner.read().contains_key(id)
+    }
+
+    /// returns the encoded `message`, padded if its connection pads.
+    pub(crate) fn pad<'a>(&self, message: &Message, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        match self.inner.read().get(message.connection_id()) {
+            Some(padding) => Cow::Owned(pad(bytes, padding)),
+            None => Cow::Borrowed(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::message::{ConnectionId, Message, ProbeMessage};
+    use super::*;
+
+    #[test]
+    fn test_message_padding_buckets() {
+        let padding = MessagePadding {
+            buckets: vec![4096, 1024],
+        };
+        assert_eq!(pad(&[1; 10], &padding).len(), 1024);
+        assert_eq!(pad(&[1; 1019], &padding).len(), 1024);
+        assert_eq!(pad(&[1; 1020], &padding).len(), 4096);
+        // larger messages are padded to a multiple of the largest bucket
+        assert_eq!(pad(&[1; 5000], &padding).len(), 8192);
+        assert_eq!(pad(&[1; 8192], &padding).len(), 12288);
+        // unless that's more than a message may be
+        let large = vec![1; MAX_MESSAGE_LEN - 2];
+        assert_eq!(pad(&large, &padding), large);
+    }
+
+    #[test]
+    fn test_message_padding_round_trip() {
+        let padding = MessagePadding::default();
+        let message = b"\x02hello".to_vec();
+        let padded = Bytes::from(pad(&message, &padding));
+        assert_eq!(padded.len(), padding.buckets[0]);
+        assert_eq!(padded[0], PADDED_MESSAGE_TYPE);
+        assert_eq!(strip_padding(padded).unwrap(), message);
+
+        // messages which aren't padded are returned as they are
+        assert_eq!(strip_padding(message.clone().into()).unwrap(), message);
+
+        // and padded messages decode like the ones they hold
+        let id = ConnectionId::generate();
+        let probe = Message::Probe(ProbeMessage { id: id.clone() });
+        let padded = pad(&probe.to_bytes(), &padding);
+        match Message::try_from_bytes(padded.into()).unwrap() {
+            Message::Probe(msg) => assert_eq!(msg.id, id),
+            _ => panic!("expected Message::Probe"),
+        }
+    }
+
+    #[test]
+    fn test_message_padding_rejects_invalid_messages() {
+        let padding = MessagePadding::default();
+        // a length past the end of the message
+        let mut padded = pad(b"\x02hello", &padding);
+        let len = padded.len() as u32;
+        padded[1..HEADER_LEN].copy_from_slice(&len.to_be_bytes());
+        assert!(strip_padding(padded.into()).is_err());
+        // a truncated header
+        assert!(strip_padding(vec![PADDED_MESSAGE_TYPE, 0].into()).is_err());
+        // padding within padding
+        let nested = pad(&pad(b"\x02hello", &padding), &padding);
+        assert!(strip_padding(nested.into()).is_err());
+        // a length which overflows the end of the message
+        let mut padded = pad(b"\x02hello", &padding);
+        padded[1..HEADER_LEN].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(strip_padding(padded.into()).is_err());
+    }
+
+    #[test]
+    fn test_padding_registry() {
+        let registry = PaddingRegistry::default();
+        let padding = MessagePadding::default();
+        let padded = ConnectionId::generate();
+        let plain = ConnectionId::generate();
+        registry.insert(padded.clone(), padding.clone());
+
+        // only the connections which negotiated padding are padded
+        let probe = Message::Probe(ProbeMessage { id: padded.clone() });
+        let bytes = probe.to_bytes();
+        assert_eq!(registry.pad(&probe, &bytes).len(), padding.buckets[0]);
+        let probe = Message::Probe(ProbeMessage { id: plain });
+        let bytes = probe.to_bytes();
+        assert_eq!(registry.pad(&probe, &bytes), bytes);
+
+        registry.remove(&padded);
+        assert!(!registry.contains(&padded));
+    }
+}
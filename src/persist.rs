@@ -0,0 +1,198 @@
+use bytes::Bytes;
+use libp2p::core::PeerId;
+use nym_sphinx::anonymous_replies::requests::AnonymousSenderTag;
+
+use super::codec::{self, put_length_prefixed, replace_file, take_file, unix_time, Decoder};
+use super::config::SessionPersistence;
+use super::error::Error;
+use super::message::{ConnectionFlags, ConnectionId, TransportMessage};
+
+/// version of the session store's plaintext encoding.
+const STORE_VERSION: u8 = 1;
+const STORE_AAD: &[u8] = b"nym-libp2p-session-store";
+const SENDER_TAG_LENGTH: usize = 16;
+
+/// PersistedConnection is what's stored about an inbound connection, so that
+/// it can be picked up again after a restart. The sender tag is the only way
+/// to reach the anonymous dialer, and the nonces must continue exactly where
+/// they left off, since the remote processes messages in nonce order.
+#[derive(Clone, Debug)]
+pub(crate) struct PersistedConnection {
+    pub(crate) id: ConnectionId,
+    pub(crate) peer_id: PeerId,
+    pub(crate) sender_tag: AnonymousSenderTag,
+    pub(crate) flags: ConnectionFlags,
+    /// nonce of the next message we send over the connection.
+    pub(crate) next_outbound_nonce: u64,
+    /// nonce of the next message we expect to receive over the connection.
+    pub(crate) next_inbound_nonce: u64,
+    /// messages which arrived early and were held back for reordering.
+    pub(crate) held_back: Vec<TransportMessage>,
+}
+
+/// SessionStore reads and writes the persisted connections of a transport.
+/// The file is encrypted and authenticated with the configured key, since
+/// sender tags link the transport to the peers it's talking to.
+pub(crate) struct SessionStore {
+    config: SessionPersistence,
+}
+
+impl SessionStore {
+    pub(crate) fn new(config: SessionPersistence) -> Self {
+        SessionStore { config }
+    }
+
+    /// writes the given connections to the store, replacing what's there.
+    pub(crate) fn save(&self, connections: &[PersistedConnection]) -> Result<(), Error> {
+        self.write(connections, unix_time())
+    }
+
+    fn write(&self, connections: &[PersistedConnection], saved_at: u64) -> Result<(), Error> {
+        let plaintext = encode(connections, saved_at);
+        let bytes = codec::seal(self.config.key(), STORE_AAD, &plaintext)?;
+        replace_file(&self.config.path, &bytes)?;
+        Ok(())
+    }
+
+    /// reads the stored connections and removes them from the store, so that
+    /// they're never restored twice. Returns nothing if the connections were
+    /// saved longer than the maximum downtime ago.
+    pub(crate) fn take(&self) -> Result<Vec<PersistedConnection>, Error> {
+        let Some(bytes) = take_file(&self.config.path)? else {
+            return Ok(vec![]);
+        };
+        let plaintext =
+            codec::open(self.config.key(), STORE_AAD, &bytes).ok_or(Error::InvalidSessionStore)?;
+
+        let (saved_at, connections) = decode(&plaintext)?;
+        if unix_time().saturating_sub(saved_at) > self.config.max_downtime.as_secs() {
+            return Ok(vec![]);
+        }
+        Ok(connections)
+    }
+}
+
+fn encode(connections: &[PersistedConnection], saved_at: u64) -> Vec<u8> {
+    let mut bytes = vec![STORE_VERSION];
+    bytes.extend_from_slice(&saved_at.to_be_bytes());
+    bytes.extend_from_slice(&(connections.len() as u32).to_be_bytes());
+    for conn in connections {
+        bytes.extend_from_slice(&conn.id.0);
+        bytes.extend_from_slice(&conn.sender_tag.to_bytes());
+        bytes.push(conn.flags.bits());
+        bytes.extend_from_slice(&conn.next_outbound_nonce.to_be_bytes());
+        bytes.extend_from_slice(&conn.next_inbound_nonce.to_be_bytes());
+        put_length_prefixed(&mut bytes, &conn.peer_id.to_bytes());
+        bytes.extend_from_slice(&(conn.held_back.len() as u32).to_be_bytes());
+        for msg in &conn.held_back {
+            let mut encoded = vec![];
+            msg.encode_into(&mut encoded, false);
+            put_length_prefixed(&mut bytes, &encoded);
+        }
+    }
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Result<(u64, Vec<PersistedConnection>), Error> {
+    let mut bytes = Decoder::new(bytes, || Error::InvalidSessionStore);
+    if bytes.take_u8()? != STORE_VERSION {
+        return Err(Error::InvalidSessionStore);
+    }
+    let saved_at = bytes.take_u64()?;
+    let count = bytes.take_u32()?;
+
+    let mut connections = vec![];
+    for _ in 0..count {
+        let id = ConnectionId(bytes.take_array()?);
+        let sender_tag = AnonymousSenderTag::from_bytes(bytes.take_array::<SENDER_TAG_LENGTH>()?);
+        let flags = ConnectionFlags::from_bits(bytes.take_u8()?);
+        let next_outbound_nonce = bytes.take_u64()?;
+        let next_inbound_nonce = bytes.take_u64()?;
+        let peer_id = PeerId::from_bytes(bytes.take_length_prefixed()?)
+            .map_err(|_| Error::InvalidSessionStore)?;
+        let mut held_back = vec![];
+        for _ in 0..bytes.take_u32()? {
+            let encoded = Bytes::copy_from_slice(bytes.take_length_prefixed()?);
+            held_back.push(TransportMessage::try_from_bytes(encoded, false)?);
+        }
+        connections.push(PersistedConnection {
+            id,
+            peer_id,
+            sender_tag,
+            flags,
+            next_outbound_nonce,
+            next_inbound_nonce,
+            held_back,
+        });
+    }
+    Ok((saved_at, connections))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::message::{SubstreamId, SubstreamMessage};
+    use super::*;
+    use rand::rngs::OsRng;
+    use std::fs;
+    use std::time::Duration;
+
+    fn persisted_connection() -> PersistedConnection {
+        let id = ConnectionId::generate();
+        PersistedConnection {
+            id: id.clone(),
+            peer_id: PeerId::random(),
+            sender_tag: AnonymousSenderTag::new_random(&mut OsRng),
+            flags: ConnectionFlags::COMPACT_IDS,
+            next_outbound_nonce: 7,
+            next_inbound_nonce: 3,
+            held_back: vec![TransportMessage {
+                nonce: 5,
+                id,
+                message: SubstreamMessage::new_with_data(
+                    SubstreamId::generate(),
+                    b"hello".to_vec(),
+                ),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_session_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SessionPersistence::new(dir.path().join("sessions"), [7u8; 32]);
+        let store = SessionStore::new(config.clone());
+        assert!(store.take().unwrap().is_empty());
+
+        let conn = persisted_connection();
+        store.save(std::slice::from_ref(&conn)).unwrap();
+        // sender tags aren't stored in the clear
+        let bytes = fs::read(&config.path).unwrap();
+        assert!(!bytes
+            .windows(SENDER_TAG_LENGTH)
+            .any(|w| w == conn.sender_tag.to_bytes()));
+
+        let restored = store.take().unwrap();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].id, conn.id);
+        assert_eq!(restored[0].peer_id, conn.peer_id);
+        assert_eq!(restored[0].sender_tag, conn.sender_tag);
+        assert_eq!(restored[0].flags, conn.flags);
+        assert_eq!(restored[0].next_outbound_nonce, 7);
+        assert_eq!(restored[0].next_inbound_nonce, 3);
+        assert_eq!(restored[0].held_back.len(), 1);
+        assert_eq!(restored[0].held_back[0].nonce, 5);
+
+        // connections are only restored once
+        assert!(store.take().unwrap().is_empty());
+
+        // a different key can't read the store
+        store.save(std::slice::from_ref(&conn)).unwrap();
+        let other = SessionStore::new(SessionPersistence::new(&config.path, [8u8; 32]));
+        assert!(matches!(other.take(), Err(Error::InvalidSessionStore)));
+
+        // connections saved too long ago are dropped
+        let stale = SessionStore::new(config.with_max_downtime(Duration::from_secs(5)));
+        stale.write(&[conn], unix_time() - 10).unwrap();
+        assert!(stale.take().unwrap().is_empty());
+    }
+}
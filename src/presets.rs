@@ -0,0 +1,83 @@
+use libp2p::{gossipsub, identify, ping, swarm};
+use libp2p_identity::PublicKey;
+use std::time::Duration;
+
+/// the round trip time the presets are tuned for: a pessimistic one over the
+/// mixnet, with its per-hop delays and cover traffic. The timeouts are
+/// multiples of it, so that a slow round trip doesn't fail a protocol.
+pub const MIXNET_RTT: Duration = Duration::from_secs(3);
+
+/// returns `config` with the idle connection timeout raised to 40 round
+/// trips, so that connections aren't closed between two exchanges of a
+/// protocol, and room for the inbound streams a slow link piles up.
+/// Pass it to `SwarmBuilder::with_swarm_config`.
+pub fn swarm_config(config: swarm::Config) -> swarm::Config {
+    config
+        .with_idle_connection_timeout(MIXNET_RTT * 40)
+        .with_max_negotiating_inbound_streams(64)
+}
+
+/// returns a gossipsub config builder with heartbeats and caches stretched
+/// to the mixnet's round trip time. Messages are limited to the transport's
+/// frame size, so that each of them is sent in one frame. The builder is
+/// returned so that the mesh size, validation mode and message IDs can be
+/// set to suit the application before it's built.
+pub fn gossipsub_config() -> gossipsub::ConfigBuilder {
+    let mut builder = gossipsub::ConfigBuilder::default();
+    builder
+        .heartbeat_initial_delay(MIXNET_RTT)
+        .heartbeat_interval(MIXNET_RTT * 3)
+        .iwant_followup_time(MIXNET_RTT * 3)
+        .fanout_ttl(MIXNET_RTT * 20)
+        // messages may arrive long after they were first seen
+        .duplicate_cache_time(MIXNET_RTT * 40)
+        .published_message_ids_cache_time(MIXNET_RTT * 20)
+        .max_transmit_size(crate::DEFAULT_MAX_FRAME_BYTES);
+    builder
+}
+
+/// returns a ping config which pings every 10 round trips, and only
+/// considers a ping failed after 20.
+pub fn ping_config() -> ping::Config {
+    ping::Config::new()
+        .with_interval(MIXNET_RTT * 10)
+        .with_timeout(MIXNET_RTT * 20)
+}
+
+/// returns an identify config which pushes our listen addresses as soon as
+/// they change, as a nym address does when the client moves to another
+/// gateway, and otherwise identifies peers every 15 minutes.
+pub fn identify_config(protocol_version: String, local_public_key: PublicKey) -> identify::Config {
+    identify::Config::new(protocol_version, local_public_key)
+        .with_interval(Duration::from_secs(15 * 60))
+        .with_push_listen_addr_updates(true)
+}
+
+/// returns the timeout for request-response protocols, to be passed to
+/// `request_response::Config::with_request_timeout`: 20 round trips, as the
+/// default of 10 seconds is barely more than a few over the mixnet.
+pub fn request_timeout() -> Duration {
+    MIXNET_RTT * 20
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libp2p_identity::Keypair;
+
+    #[test]
+    fn test_presets() {
+        let config = gossipsub_config().build().unwrap();
+        assert_eq!(config.heartbeat_interval(), MIXNET_RTT * 3);
+        assert!(config.duplicate_cache_time() > config.heartbeat_interval());
+        assert_eq!(config.max_transmit_size(), crate::DEFAULT_MAX_FRAME_BYTES);
+
+        // every timeout leaves room for several slow round trips
+        assert!(request_timeout() >= MIXNET_RTT * 10);
+        let identify = identify_config(
+            "/test/1.0.0".to_string(),
+            Keypair::generate_ed25519().public(),
+        );
+        assert!(identify.push_listen_addr_updates);
+    }
+}
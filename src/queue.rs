@@ -1,15 +1,20 @@
 use log::{debug, warn};
 use std::collections::BTreeSet;
+use std::sync::Arc;
 
-use super::message::TransportMessage;
+use super::budget::{BufferKind, MemoryBudget};
+use super::config::ReorderWindow;
+use super::error::Error;
+use super::message::{SubstreamMessageType, TransportMessage};
+use super::stats::ReorderStats;
 
 /// MessageQueue is a queue of messages, ordered by nonce, that we've
 /// received but are not yet able to process because we're waiting for
 /// a message with the next expected nonce first.
 /// This is required because Nym does not guarantee any sort of message
 /// ordering, only delivery.
-/// TODO: is there a DOS vector here where a malicious peer sends us
-/// messages only with nonce higher than the next expected nonce?
+/// The reorder window keeps a peer which only sends messages with nonces
+/// far above the next expected one from growing the queue indefinitely.
 pub(crate) struct MessageQueue {
     /// nonce of the next message we expect to receive on the
     /// connection.
@@ -23,14 +28,110 @@ pub(crate) struct MessageQueue {
     /// the head of the queue's nonce is always greater
     /// than the next expected nonce.
     queue: BTreeSet<TransportMessage>,
+
+    /// if set, messages are returned as soon as they arrive, and only
+    /// their nonces are kept to detect duplicates.
+    unordered: bool,
+
+    /// nonces greater than the next expected nonce which were already
+    /// returned; only used for unordered delivery.
+    received: BTreeSet<u64>,
+
+    /// if set, bounds the messages held back in `queue` or `received`.
+    window: Option<ReorderWindow>,
+
+    /// how messages arrived so far.
+    stats: ReorderStats,
+
+    /// accounts for the data of the messages in `queue`.
+    budget: Arc<MemoryBudget>,
 }
 
 impl MessageQueue {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(window: Option<ReorderWindow>, budget: Arc<MemoryBudget>) -> Self {
         MessageQueue {
             next_expected_nonce: 0,
             queue: BTreeSet::new(),
+            unordered: false,
+            received: BTreeSet::new(),
+            window,
+            stats: ReorderStats::default(),
+            budget,
+        }
+    }
+
+    /// creates the queue of a connection restored after a restart, which
+    /// continues at the given nonce with the messages held back before.
+    pub(crate) fn restored(
+        window: Option<ReorderWindow>,
+        budget: Arc<MemoryBudget>,
+        next_expected_nonce: u64,
+        held_back: Vec<TransportMessage>,
+    ) -> Self {
+        let mut queue = MessageQueue::new(window, budget);
+        queue.next_expected_nonce = next_expected_nonce;
+        // they were held back before, so they're kept regardless of the budget
+        queue
+            .budget
+            .reserve(held_back.iter().map(buffered_len).sum());
+        queue.queue.extend(held_back);
+        queue
+    }
+
+    pub(crate) fn stats(&self) -> &ReorderStats {
+        &self.stats
+    }
+
+    pub(crate) fn next_expected_nonce(&self) -> u64 {
+        self.next_expected_nonce
+    }
+
+    /// returns the messages held back until the ones before them arrive.
+    pub(crate) fn held_back(&self) -> impl Iterator<Item = &TransportMessage> {
+        self.queue.iter()
+    }
+
+    /// returns the ranges of nonces above the next expected one which were
+    /// received, in ascending order; at most `max` of them.
+    pub(crate) fn received_ranges(&self, max: usize) -> Vec<(u64, u64)> {
+        let nonces: Box<dyn Iterator<Item = u64> + '_> = if self.unordered {
+            Box::new(self.received.iter().copied())
+        } else {
+            Box::new(self.queue.iter().map(|msg| msg.nonce))
+        };
+
+        let mut ranges: Vec<(u64, u64)> = vec![];
+        for nonce in nonces {
+            if let Some((_, end)) = ranges.last_mut() {
+                if *end + 1 == nonce {
+                    *end = nonce;
+                    continue;
+                }
+            }
+            if ranges.len() == max {
+                break;
+            }
+            ranges.push((nonce, nonce));
+        }
+        ranges
+    }
+
+    /// records a message with the given nonce, which is greater than the
+    /// next expected one, and checks that it may be held back.
+    fn push_out_of_order(&mut self, nonce: u64, held_back: usize) -> Result<(), Error> {
+        let gap = nonce - self.next_expected_nonce;
+        self.stats.out_of_order += 1;
+        self.stats.max_gap = self.stats.max_gap.max(gap);
+
+        let Some(window) = &self.window else {
+            return Ok(());
+        };
+
+        if gap > window.max_gap || held_back >= window.max_queued {
+            self.stats.dropped += 1;
+            return Err(Error::ReorderWindowExceeded(nonce));
         }
+        Ok(())
     }
 
     pub(crate) fn print_nonces(&self) {
@@ -48,30 +149,86 @@ impl MessageQueue {
         self.next_expected_nonce = self.next_expected_nonce.wrapping_add(1);
     }
 
+    /// switches the queue to unordered delivery, where `try_push` returns every
+    /// message that isn't a duplicate right away. Returns the messages which
+    /// were queued so far, since they no longer need to wait.
+    pub(crate) fn set_unordered(&mut self) -> Vec<TransportMessage> {
+        self.unordered = true;
+        let queued = std::mem::take(&mut self.queue);
+        for msg in &queued {
+            self.mark_received(msg.nonce);
+            self.budget.release(buffered_len(msg));
+        }
+        queued.into_iter().collect()
+    }
+
+    /// records that the message with the given nonce was handed out,
+    /// returning false if it already was.
+    fn mark_received(&mut self, nonce: u64) -> bool {
+        if nonce < self.next_expected_nonce || !self.received.insert(nonce) {
+            return false;
+        }
+
+        // only remember the nonces above the highest contiguous one
+        while self.received.remove(&self.next_expected_nonce) {
+            self.next_expected_nonce = self.next_expected_nonce.wrapping_add(1);
+        }
+        true
+    }
+
     /// tries to push a message into the queue.
     /// if the message has the next expected nonce, then the message is returned,
     /// and should be processed by the caller.
     /// in that case, the internal queue's next expected nonce is incremented.
-    pub(crate) fn try_push(&mut self, msg: TransportMessage) -> Option<TransportMessage> {
+    /// fails if the message would have to be held back, but is outside the
+    /// reorder window; the connection only recovers from that if the remote
+    /// retransmits the message.
+    pub(crate) fn try_push(
+        &mut self,
+        msg: TransportMessage,
+    ) -> Result<Option<TransportMessage>, Error> {
+        if self.unordered {
+            if msg.nonce > self.next_expected_nonce && !self.received.contains(&msg.nonce) {
+                self.push_out_of_order(msg.nonce, self.received.len())?;
+            }
+            if !self.mark_received(msg.nonce) {
+                warn!("received a message with a duplicate nonce");
+                self.stats.dropped += 1;
+                return Ok(None);
+            }
+            return Ok(Some(msg));
+        }
+
         if msg.nonce == self.next_expected_nonce {
             self.next_expected_nonce = self.next_expected_nonce.wrapping_add(1);
-            Some(msg)
+            Ok(Some(msg))
         } else {
             if msg.nonce < self.next_expected_nonce {
                 // this shouldn't happen normally, only if the other node
                 // is not following the protocol
                 warn!("received a message with a nonce that is too low");
-                return None;
+                self.stats.dropped += 1;
+                return Ok(None);
             }
 
-            if !self.queue.insert(msg) {
+            if self.queue.contains(&msg) {
                 // this shouldn't happen normally, only if the other node
                 // is not following the protocol
                 warn!("received a message with a duplicate nonce");
-                return None;
+                self.stats.dropped += 1;
+                return Ok(None);
             }
 
-            None
+            self.push_out_of_order(msg.nonce, self.queue.len())?;
+            if !self
+                .budget
+                .try_reserve(BufferKind::HeldBack, buffered_len(&msg))
+            {
+                self.stats.dropped += 1;
+                return Err(Error::BufferBudgetExceeded(msg.nonce));
+            }
+            self.queue.insert(msg);
+            Ok(None)
         }
     }
 
@@ -80,40 +237,51 @@ impl MessageQueue {
 
         if head.nonce == self.next_expected_nonce {
             self.next_expected_nonce = self.next_expected_nonce.wrapping_add(1);
-            Some(self.queue.pop_first().unwrap())
+            let msg = self.queue.pop_first().unwrap();
+            self.budget.release(buffered_len(&msg));
+            Some(msg)
         } else {
             None
         }
     }
 }
 
+impl Drop for MessageQueue {
+    fn drop(&mut self) {
+        self.budget
+            .release(self.queue.iter().map(buffered_len).sum());
+    }
+}
+
+/// returns the number of bytes of data the message holds.
+pub(crate) fn buffered_len(msg: &TransportMessage) -> usize {
+    match &msg.message.message_type {
+        SubstreamMessageType::Data(data) => data.len(),
+        _ => 0,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::super::message::{ConnectionId, SubstreamId, SubstreamMessage};
 
     use super::*;
 
-    impl TransportMessage {
-        fn new(nonce: u64, message: SubstreamMessage, id: ConnectionId) -> Self {
-            TransportMessage { nonce, message, id }
-        }
-    }
-
     #[test]
     fn test_message_queue() {
-        let mut queue = MessageQueue::new();
+        let mut queue = MessageQueue::new(None, Arc::default());
 
         let test_substream_message =
             SubstreamMessage::new_with_data(SubstreamId::generate(), vec![1, 2, 3]);
         let connection_id = ConnectionId::generate();
 
-        let msg1 = TransportMessage::new(1, test_substream_message.clone(), connection_id.clone());
-        let msg2 = TransportMessage::new(2, test_substream_message.clone(), connection_id.clone());
-        let msg3 = TransportMessage::new(3, test_substream_message.clone(), connection_id.clone());
+        let msg1 = TransportMessage::new(connection_id.clone(), 1, test_substream_message.clone());
+        let msg2 = TransportMessage::new(connection_id.clone(), 2, test_substream_message.clone());
+        let msg3 = TransportMessage::new(connection_id.clone(), 3, test_substream_message.clone());
 
-        assert_eq!(queue.try_push(msg1.clone()), None);
-        assert_eq!(queue.try_push(msg3.clone()), None);
-        assert_eq!(queue.try_push(msg2.clone()), None);
+        assert_eq!(queue.try_push(msg1.clone()).unwrap(), None);
+        assert_eq!(queue.try_push(msg3.clone()).unwrap(), None);
+        assert_eq!(queue.try_push(msg2.clone()).unwrap(), None);
 
         assert_eq!(queue.pop(), None);
 
@@ -121,8 +289,8 @@ mod test {
         queue.set_connection_message_received();
         assert_eq!(queue.pop(), Some(msg1));
 
-        let msg4 = TransportMessage::new(4, test_substream_message.clone(), connection_id.clone());
-        assert_eq!(queue.try_push(msg4.clone()), None);
+        let msg4 = TransportMessage::new(connection_id.clone(), 4, test_substream_message.clone());
+        assert_eq!(queue.try_push(msg4.clone()).unwrap(), None);
 
         assert_eq!(queue.pop(), Some(msg2));
         assert_eq!(queue.pop(), Some(msg3));
@@ -131,8 +299,152 @@ mod test {
         assert_eq!(queue.next_expected_nonce, 5);
 
         // should just return the message and increment nonce when message nonce = next expected nonce
-        let msg5 = TransportMessage::new(5, test_substream_message, connection_id);
-        assert_eq!(queue.try_push(msg5.clone()), Some(msg5));
+        let msg5 = TransportMessage::new(connection_id, 5, test_substream_message);
+        assert_eq!(queue.try_push(msg5.clone()).unwrap(), Some(msg5));
         assert_eq!(queue.next_expected_nonce, 6);
     }
+
+    #[test]
+    fn test_message_queue_received_ranges() {
+        let test_substream_message =
+            SubstreamMessage::new_with_data(SubstreamId::generate(), vec![1, 2, 3]);
+        let connection_id = ConnectionId::generate();
+        let msg = |nonce| {
+            TransportMessage::new(connection_id.clone(), nonce, test_substream_message.clone())
+        };
+
+        let mut queue = MessageQueue::new(None, Arc::default());
+        queue.set_connection_message_received();
+        for nonce in [4, 3, 9, 6] {
+            assert_eq!(queue.try_push(msg(nonce)).unwrap(), None);
+        }
+        assert_eq!(queue.received_ranges(16), vec![(3, 4), (6, 6), (9, 9)]);
+        assert_eq!(queue.received_ranges(2), vec![(3, 4), (6, 6)]);
+
+        // unordered queues only remember the nonces of what they returned
+        let mut queue = MessageQueue::new(None, Arc::default());
+        queue.set_connection_message_received();
+        queue.set_unordered();
+        for nonce in [3, 5, 4] {
+            assert_eq!(queue.try_push(msg(nonce)).unwrap(), Some(msg(nonce)));
+        }
+        assert_eq!(queue.received_ranges(16), vec![(3, 5)]);
+        assert_eq!(queue.try_push(msg(1)).unwrap(), Some(msg(1)));
+        assert_eq!(queue.received_ranges(16), vec![(3, 5)]);
+    }
+
+    #[test]
+    fn test_message_queue_unordered() {
+        let mut queue = MessageQueue::new(None, Arc::default());
+
+        let test_substream_message =
+            SubstreamMessage::new_with_data(SubstreamId::generate(), vec![1, 2, 3]);
+        let connection_id = ConnectionId::generate();
+        let msg = |nonce| {
+            TransportMessage::new(connection_id.clone(), nonce, test_substream_message.clone())
+        };
+
+        // messages which arrived before the connection message are released
+        // once the queue becomes unordered
+        assert_eq!(queue.try_push(msg(3)).unwrap(), None);
+        assert_eq!(queue.try_push(msg(2)).unwrap(), None);
+        queue.set_connection_message_received();
+        assert_eq!(queue.set_unordered(), vec![msg(2), msg(3)]);
+
+        // later messages are returned right away, regardless of order
+        assert_eq!(queue.try_push(msg(5)).unwrap(), Some(msg(5)));
+        assert_eq!(queue.try_push(msg(1)).unwrap(), Some(msg(1)));
+        assert_eq!(queue.next_expected_nonce, 4);
+        assert_eq!(queue.try_push(msg(4)).unwrap(), Some(msg(4)));
+        assert_eq!(queue.next_expected_nonce, 6);
+        assert!(queue.received.is_empty());
+
+        // duplicates are dropped
+        assert_eq!(queue.try_push(msg(2)).unwrap(), None);
+        assert_eq!(queue.try_push(msg(7)).unwrap(), Some(msg(7)));
+        assert_eq!(queue.try_push(msg(7)).unwrap(), None);
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_message_queue_reorder_window() {
+        let test_substream_message =
+            SubstreamMessage::new_with_data(SubstreamId::generate(), vec![1, 2, 3]);
+        let connection_id = ConnectionId::generate();
+        let msg = |nonce| {
+            TransportMessage::new(connection_id.clone(), nonce, test_substream_message.clone())
+        };
+        let window = ReorderWindow {
+            max_gap: 4,
+            max_queued: 2,
+        };
+
+        for unordered in [false, true] {
+            let mut queue = MessageQueue::new(Some(window), Arc::default());
+            queue.set_connection_message_received();
+            if unordered {
+                queue.set_unordered();
+            }
+
+            // too far ahead of the next expected nonce
+            assert!(matches!(
+                queue.try_push(msg(6)),
+                Err(Error::ReorderWindowExceeded(6))
+            ));
+
+            // too many messages held back
+            assert!(queue.try_push(msg(5)).is_ok());
+            assert!(queue.try_push(msg(3)).is_ok());
+            assert!(matches!(
+                queue.try_push(msg(4)),
+                Err(Error::ReorderWindowExceeded(4))
+            ));
+
+            // duplicates and the next expected message are always fine
+            assert!(queue.try_push(msg(5)).is_ok());
+            assert!(queue.try_push(msg(1)).unwrap().is_some());
+
+            assert_eq!(
+                queue.stats(),
+                &ReorderStats {
+                    out_of_order: 4,
+                    max_gap: 5,
+                    dropped: 3,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_message_queue_memory_budget() {
+        let connection_id = ConnectionId::generate();
+        let msg = |nonce| {
+            TransportMessage::new(
+                connection_id.clone(),
+                nonce,
+                SubstreamMessage::new_with_data(SubstreamId::generate(), vec![0; 10]),
+            )
+        };
+        let budget = Arc::new(MemoryBudget::new(Some(40), Arc::default()));
+        let mut queue = MessageQueue::new(None, budget.clone());
+        queue.set_connection_message_received();
+
+        // held-back messages may use half of the budget
+        assert!(queue.try_push(msg(3)).unwrap().is_none());
+        assert!(queue.try_push(msg(4)).unwrap().is_none());
+        assert!(matches!(
+            queue.try_push(msg(5)),
+            Err(Error::BufferBudgetExceeded(5))
+        ));
+        assert_eq!(budget.used(), 20);
+
+        // messages which are handed out right away don't count
+        assert!(queue.try_push(msg(1)).unwrap().is_some());
+        assert!(queue.try_push(msg(2)).unwrap().is_some());
+        assert!(queue.pop().is_some());
+        assert_eq!(budget.used(), 10);
+
+        drop(queue);
+        assert_eq!(budget.used(), 0);
+    }
 }
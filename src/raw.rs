@@ -0,0 +1,175 @@
+use futures::{
+    future::{poll_fn, BoxFuture},
+    io::Error as IoError,
+    AsyncRead, AsyncWrite, FutureExt,
+};
+use libp2p::core::{
+    multiaddr::Multiaddr,
+    transport::{DialOpts, ListenerId, TransportError, TransportEvent},
+    StreamMuxer, Transport,
+};
+use std::{
+    fmt::{Debug, Formatter},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::time::timeout;
+
+use super::connection::Connection;
+use super::error::Error;
+use super::substream::Substream;
+use super::transport::NymTransport;
+
+/// RawNymTransport is a `NymTransport` whose connections are single
+/// `RawStream`s rather than multiplexed `Connection`s, so that the standard
+/// libp2p upgrades can be applied on top, eg. noise and yamux:
+///
+/// ```ignore
+/// let transport = nym_transport
+///     .into_raw()
+///     .upgrade(upgrade::Version::V1)
+///     .authenticate(noise::Config::new(&local_key)?)
+///     .multiplex(yamux::Config::default())
+///     .boxed();
+/// ```
+///
+/// The PeerId of the nym handshake is dropped, since the authentication
+/// upgrade establishes the actual one. Opening the stream is bounded by the
+/// handshake timeout, so a remote that completes the handshake but never opens
+/// it can't hold an inbound upgrade forever.
+pub struct RawNymTransport(NymTransport);
+
+impl NymTransport {
+    /// returns the transport in raw stream mode; see `RawNymTransport`.
+    pub fn into_raw(self) -> RawNymTransport {
+        RawNymTransport(self)
+    }
+}
+
+impl RawNymTransport {
+    /// returns the underlying transport, eg. to access its metrics.
+    pub fn inner(&self) -> &NymTransport {
+        &self.0
+    }
+}
+
+impl Transport for RawNymTransport {
+    type Output = RawStream;
+    type Error = Error;
+    type ListenerUpgrade = BoxFuture<'static, Result<RawStream, Error>>;
+    type Dial = BoxFuture<'static, Result<RawStream, Error>>;
+
+    fn listen_on(
+        &mut self,
+        id: ListenerId,
+        addr: Multiaddr,
+    ) -> Result<(), TransportError<Self::Error>> {
+        self.0.listen_on(id, addr)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.0.remove_listener(id)
+    }
+
+    fn dial(
+        &mut self,
+        addr: Multiaddr,
+        opts: DialOpts,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let open_timeout = self.0.handshake_timeout();
+        let dial = self.0.dial(addr, opts)?;
+        Ok(async move {
+            let (_, conn) = dial.await?;
+            timeout(open_timeout, RawStream::outbound(conn))
+                .await
+                .map_err(|_| Error::RawStreamTimeout)?
+        }
+        .boxed())
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        let open_timeout = self.0.handshake_timeout();
+        Pin::new(&mut self.0).poll(cx).map(|event| {
+            event.map_upgrade(|upgrade| {
+                async move {
+                    let (_, conn) = upgrade.await?;
+                    timeout(open_timeout, RawStream::inbound(conn))
+                        .await
+                        .map_err(|_| Error::RawStreamTimeout)?
+                }
+                .boxed()
+            })
+        })
+    }
+}
+
+/// RawStream is the single stream of a connection made by a `RawNymTransport`.
+/// The dialer opens it right after the handshake, and the listener accepts it;
+/// any further substreams the remote opens are ignored.
+pub struct RawStream {
+    conn: Connection,
+    substream: Substream,
+}
+
+impl Debug for RawStream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawStream")
+            .field("conn", &self.conn)
+            .field("substream", &self.substream)
+            .finish()
+    }
+}
+
+impl RawStream {
+    async fn outbound(mut conn: Connection) -> Result<Self, Error> {
+        let substream = poll_fn(|cx| Pin::new(&mut conn).poll_outbound(cx)).await?;
+        Ok(RawStream { conn, substream })
+    }
+
+    async fn inbound(mut conn: Connection) -> Result<Self, Error> {
+        let substream = poll_fn(|cx| {
+            // the connection hands the remote's OpenRequest to poll_inbound
+            if let Poll::Ready(Err(e)) = Pin::new(&mut conn).poll(cx) {
+                return Poll::Ready(Err(e));
+            }
+            Pin::new(&mut conn).poll_inbound(cx)
+        })
+        .await?;
+        Ok(RawStream { conn, substream })
+    }
+}
+
+impl AsyncRead for RawStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, IoError>> {
+        // the connection routes inbound data to the substream
+        if let Poll::Ready(Err(e)) = Pin::new(&mut self.conn).poll(cx) {
+            return Poll::Ready(Err(IoError::other(e)));
+        }
+        Pin::new(&mut self.substream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RawStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoError>> {
+        Pin::new(&mut self.substream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.substream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.substream).poll_close(cx)
+    }
+}
@@ -0,0 +1,118 @@
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+/// whether identifiers are redacted in log output; on by default in release builds.
+static REDACT_LOGS: AtomicBool = AtomicBool::new(!cfg!(debug_assertions));
+
+/// number of hash bytes shown in place of a redacted identifier.
+const REDACTED_HASH_LENGTH: usize = 4;
+
+/// enables or disables the redaction of nym addresses, sender tags and PeerIds
+/// in the log output of this crate. It's enabled by default in release builds,
+/// since full recipient addresses in logs undermine the anonymity the mixnet
+/// provides. Redacted identifiers are replaced by a short hash, which is
+/// stable for the lifetime of the process, so log lines about the same peer
+/// can still be correlated.
+pub fn set_log_redaction(enabled: bool) {
+    REDACT_LOGS.store(enabled, Ordering::Relaxed);
+}
+
+/// returns whether identifiers are redacted in the log output of this crate.
+pub fn log_redaction_enabled() -> bool {
+    REDACT_LOGS.load(Ordering::Relaxed)
+}
+
+/// Redacted formats an identifier for log output, replacing it by a short
+/// hash if redaction is enabled.
+pub(crate) struct Redacted<T> {
+    value: T,
+    /// whether the identifier is redacted even if redaction is disabled.
+    always: bool,
+}
+
+/// wraps an identifier so that it's redacted when formatted, if enabled.
+pub(crate) fn redact<T: Display>(value: T) -> Redacted<T> {
+    Redacted {
+        value,
+        always: false,
+    }
+}
+
+/// wraps an identifier so that it's always redacted when formatted; used by
+/// the Debug implementations of public types, whose output ends up in the
+/// application's logs rather than ours.
+pub(crate) fn redact_always<T: Display>(value: T) -> Redacted<T> {
+    Redacted {
+        value,
+        always: true,
+    }
+}
+
+impl<T: Display> Display for Redacted<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if !self.always && !log_redaction_enabled() {
+            return Display::fmt(&self.value, f);
+        }
+
+        // the hash is salted, so it can't be matched against known addresses
+        static SALT: OnceLock<[u8; 32]> = OnceLock::new();
+        let salt = SALT.get_or_init(|| {
+            let mut salt = [0u8; 32];
+            OsRng.fill_bytes(&mut salt);
+            salt
+        });
+        let hash = Sha256::new()
+            .chain_update(salt)
+            .chain_update(self.value.to_string())
+            .finalize();
+        write!(
+            f,
+            "<redacted {}>",
+            hex::encode(&hash[..REDACTED_HASH_LENGTH])
+        )
+    }
+}
+
+impl<T: Display> Debug for Redacted<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libp2p::core::PeerId;
+
+    #[test]
+    fn test_redact() {
+        let peer_id = PeerId::random();
+
+        set_log_redaction(false);
+        assert_eq!(redact(peer_id).to_string(), peer_id.to_string());
+
+        set_log_redaction(true);
+        let redacted = redact(peer_id).to_string();
+        assert!(!redacted.contains(&peer_id.to_string()));
+        assert_eq!(
+            redacted.len(),
+            "<redacted >".len() + 2 * REDACTED_HASH_LENGTH
+        );
+        // stable, so log lines can be correlated
+        assert_eq!(redact(peer_id).to_string(), redacted);
+        assert_ne!(redact(PeerId::random()).to_string(), redacted);
+        assert_eq!(
+            format!("{:?}", Some(redact(peer_id))),
+            format!("Some({})", redacted)
+        );
+
+        set_log_redaction(false);
+        assert_eq!(redact_always(peer_id).to_string(), redacted);
+
+        set_log_redaction(!cfg!(debug_assertions));
+    }
+}
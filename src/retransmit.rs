@@ -0,0 +1,287 @@
+use nym_sphinx::addressing::clients::Recipient;
+use nym_sphinx::anonymous_replies::requests::AnonymousSenderTag;
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    fmt::{Debug, Formatter},
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use super::alias::AliasId;
+use super::budget::{BufferKind, MemoryBudget};
+use super::config::SelectiveRepeat;
+use super::message::{AckMessage, Message, OutboundMessage, TransportMessage};
+use super::queue::buffered_len;
+use super::session::Session;
+
+/// SendBuffer keeps the TransportMessages sent on a connection until the
+/// remote acknowledges them, and hands out the ones which weren't
+/// acknowledged in time for retransmission; see `SelectiveRepeat`.
+///
+/// Messages are recorded by the connection's `OutboundSender` as they're
+/// sent, and released by the acks the transport receives. Writers wait with
+/// `poll_ready` while the unacked data fills the memory budget.
+pub(crate) struct SendBuffer {
+    config: SelectiveRepeat,
+    /// nonce -> the sent message
+    unacked: Mutex<BTreeMap<u64, SentMessage>>,
+    /// the transport's budget, which the data of unacked messages counts against.
+    budget: Arc<MemoryBudget>,
+}
+
+/// SentMessage is a message waiting for its ack, along with what's needed to
+/// send it again.
+struct SentMessage {
+    msg: TransportMessage,
+    recipient: Option<Recipient>,
+    sender_tag: Option<AnonymousSenderTag>,
+    session: Option<Arc<Session>>,
+    compact_ids: bool,
+    alias: Option<AliasId>,
+    sent_at: Instant,
+    /// how long to wait for an ack before sending it again.
+    backoff: Duration,
+    retransmissions: u32,
+}
+
+impl Debug for SendBuffer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendBuffer")
+            .field("unacked", &self.unacked.lock().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SendBuffer {
+    pub(crate) fn new(config: SelectiveRepeat, budget: Arc<MemoryBudget>) -> Self {
+        SendBuffer {
+            config,
+            unacked: Mutex::new(BTreeMap::new()),
+            budget,
+        }
+    }
+
+    /// polls until there's room in the memory budget for another message.
+    /// One is always let through if nothing is unacked, so that sending
+    /// can't get stuck.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.unacked.lock().is_empty() {
+            return Poll::Ready(());
+        }
+        self.budget.poll_ready(cx)
+    }
+
+    /// records a message which is being sent for the first time; anything
+    /// but a TransportMessage is ignored.
+    pub(crate) fn record(&self, outbound: &OutboundMessage) {
+        let Message::TransportMessage(msg) = &outbound.message else {
+            return;
+        };
+
+        // the message's nonce is taken already, and the remote can't skip
+        // it, so one which raced another writer for the room is kept anyway
+        let len = buffered_len(msg);
+        if !self.budget.try_reserve(BufferKind::Unacked, len) {
+            self.budget.reserve(len);
+        }
+        let sent = SentMessage {
+            msg: msg.clone(),
+            recipient: outbound.recipient,
+            sender_tag: outbound.sender_tag,
+            session: outbound.session.clone(),
+            compact_ids: outbound.compact_ids,
+            alias: outbound.alias,
+            sent_at: Instant::now(),
+            backoff: self.config.retransmit.initial_backoff,
+            retransmissions: 0,
+        };
+        if let Some(replaced) = self.unacked.lock().insert(msg.nonce, sent) {
+            self.budget.release(buffered_len(&replaced.msg));
+        }
+    }
+
+    /// drops the messages covered by an ack, and returns how many there were.
+    pub(crate) fn on_ack(&self, ack: &AckMessage) -> usize {
+        let mut unacked = self.unacked.lock();
+        let mut released = 0;
+
+        let rest = unacked.split_off(&ack.nonce.saturating_add(1));
+        let mut acked = unacked.len();
+        released += unacked
+            .values()
+            .map(|sent| buffered_len(&sent.msg))
+            .sum::<usize>();
+        *unacked = rest;
+
+        for (start, end) in &ack.sacks {
+            if start > end {
+                continue;
+            }
+            let nonces: Vec<u64> = unacked
+                .range(start..=end)
+                .map(|(nonce, _)| *nonce)
+                .collect();
+            for nonce in nonces {
+                if let Some(sent) = unacked.remove(&nonce) {
+                    acked += 1;
+                    released += buffered_len(&sent.msg);
+                }
+            }
+        }
+        drop(unacked);
+        self.budget.release(released);
+        acked
+    }
+
+    /// returns the messages which weren't acknowledged within their backoff,
+    /// to be sent again, or the nonce of one which was already resent as
+    /// often as allowed, in which case the remote is considered gone.
+    pub(crate) fn take_due(&self) -> Result<Vec<OutboundMessage>, u64> {
+        let mut due = vec![];
+        for (nonce, sent) in self.unacked.lock().iter_mut() {
+            if sent.sent_at.elapsed() < sent.backoff {
+                continue;
+            }
+            if sent.retransmissions >= self.config.max_retransmissions {
+                return Err(*nonce);
+            }
+
+            sent.retransmissions += 1;
+            sent.sent_at = Instant::now();
+            sent.backoff = self.config.retransmit.next_backoff(sent.backoff);
+            due.push(OutboundMessage {
+                message: Message::TransportMessage(sent.msg.clone()),
+                recipient: sent.recipient,
+                sender_tag: sent.sender_tag,
+                queued_at: Instant::now(),
+                session: sent.session.clone(),
+                write_credit: None,
+                compact_ids: sent.compact_ids,
+                alias: sent.alias,
+            });
+        }
+        Ok(due)
+    }
+
+    /// returns the nonces of the messages waiting for an ack.
+    #[cfg(test)]
+    pub(crate) fn unacked(&self) -> Vec<u64> {
+        self.unacked.lock().keys().copied().collect()
+    }
+}
+
+impl Drop for SendBuffer {
+    fn drop(&mut self) {
+        let released = self
+            .unacked
+            .get_mut()
+            .values()
+            .map(|sent| buffered_len(&sent.msg))
+            .sum();
+        self.budget.release(released);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::RetryPolicy;
+    use super::super::message::{ConnectionId, SubstreamId, SubstreamMessage};
+    use super::super::metrics::TransportMetrics;
+    use super::*;
+
+    fn outbound(nonce: u64) -> OutboundMessage {
+        OutboundMessage {
+            message: Message::TransportMessage(TransportMessage {
+                nonce,
+                id: ConnectionId::generate(),
+                message: SubstreamMessage::new_with_data(SubstreamId::generate(), vec![0; 10]),
+            }),
+            recipient: None,
+            sender_tag: None,
+            queued_at: Instant::now(),
+            session: None,
+            write_credit: None,
+            compact_ids: false,
+            alias: None,
+        }
+    }
+
+    fn nonces(due: Vec<OutboundMessage>) -> Vec<u64> {
+        due.into_iter()
+            .map(|outbound| match outbound.message {
+                Message::TransportMessage(msg) => msg.nonce,
+                _ => panic!("expected Message::TransportMessage"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_send_buffer() {
+        let budget = Arc::new(MemoryBudget::new(
+            None,
+            Arc::new(TransportMetrics::default()),
+        ));
+        let buffer = SendBuffer::new(
+            SelectiveRepeat {
+                retransmit: RetryPolicy {
+                    initial_backoff: Duration::ZERO,
+                    max_backoff: Duration::ZERO,
+                    multiplier: 2,
+                },
+                max_retransmissions: 1,
+            },
+            budget.clone(),
+        );
+
+        for nonce in 1..=6 {
+            buffer.record(&outbound(nonce));
+        }
+        assert_eq!(budget.used(), 60);
+
+        // only what's neither covered by the cumulative ack nor a range is kept
+        buffer.on_ack(&AckMessage {
+            id: ConnectionId::generate(),
+            nonce: 2,
+            sacks: vec![(4, 4), (6, 9)],
+        });
+        assert_eq!(buffer.unacked(), vec![3, 5]);
+        assert_eq!(budget.used(), 20);
+
+        // the missing messages are resent until they run out of retransmissions
+        assert_eq!(nonces(buffer.take_due().unwrap()), vec![3, 5]);
+        buffer.on_ack(&AckMessage {
+            id: ConnectionId::generate(),
+            nonce: 3,
+            sacks: vec![],
+        });
+        assert_eq!(buffer.take_due().unwrap_err(), 5);
+
+        drop(buffer);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn test_send_buffer_memory_budget() {
+        let budget = Arc::new(MemoryBudget::new(
+            Some(20),
+            Arc::new(TransportMetrics::default()),
+        ));
+        let buffer = SendBuffer::new(SelectiveRepeat::default(), budget.clone());
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+        // writers wait once the unacked data fills the budget
+        assert!(buffer.poll_ready(&mut cx).is_ready());
+        buffer.record(&outbound(1));
+        buffer.record(&outbound(2));
+        assert!(buffer.poll_ready(&mut cx).is_pending());
+        buffer.on_ack(&AckMessage {
+            id: ConnectionId::generate(),
+            nonce: 1,
+            sacks: vec![],
+        });
+        assert!(buffer.poll_ready(&mut cx).is_ready());
+        assert_eq!(budget.used(), 10);
+    }
+}
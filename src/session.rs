@@ -0,0 +1,1413 @@
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::{
+    aead::{Aead, AeadInPlace, KeyInit, Payload, Tag},
+    ChaCha20Poly1305, Nonce,
+};
+use hkdf::Hkdf;
+use libp2p::core::Endpoint;
+#[cfg(feature = "post-quantum")]
+use ml_kem::{Ciphertext, Decapsulate, Encapsulate, Encoded, EncodedSizeUser, KemCore, MlKem768};
+use parking_lot::Mutex;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use std::collections::BTreeSet;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use zeroize::Zeroizing;
+
+use super::error::Error;
+use super::message::{
+    CipherSuite, ConnectionId, EncryptedTransportMessage, KeyUpdateKind, KeyUpdateLimits,
+    KeyUpdateMessage, Message, SealedMessage, SubstreamMessage, TransportMessage,
+    EPHEMERAL_KEY_LENGTH, KEY_UPDATE_TAG_LENGTH,
+};
+
+/// number of messages sent in one direction after which the key for that
+/// direction is replaced by one derived from it. Old keys are erased, so
+/// compromising a session key doesn't expose earlier traffic.
+const REKEY_INTERVAL: u64 = 1024;
+
+/// number of epochs a received message may be ahead of the current one.
+/// bounds the work done for a message with a bogus nonce.
+const MAX_EPOCH_SKIP: u64 = 16;
+
+const SESSION_KEY_LENGTH: usize = 32;
+const AAD_LENGTH: usize = 32 + 8; // connection ID and nonce
+const DIALER_KEY_INFO: &[u8] = b"nym-libp2p-session dialer->listener";
+const LISTENER_KEY_INFO: &[u8] = b"nym-libp2p-session listener->dialer";
+const REKEY_INFO: &[u8] = b"nym-libp2p-session rekey";
+const ROOT_KEY_INFO: &[u8] = b"nym-libp2p-session root";
+const KEY_UPDATE_INFO: &[u8] = b"nym-libp2p-session key update";
+const DIALER_CONTROL_KEY_INFO: &[u8] = b"nym-libp2p-session control dialer->listener";
+const LISTENER_CONTROL_KEY_INFO: &[u8] = b"nym-libp2p-session control listener->dialer";
+
+/// number of counters below the highest one received for which sealed
+/// control messages are still accepted; they may be reordered like any
+/// other message. Older ones are dropped as possible replays.
+const CONTROL_REPLAY_WINDOW: u64 = 1024;
+
+type SessionKey = Zeroizing<[u8; SESSION_KEY_LENGTH]>;
+
+/// HandshakeSecret is our half of the X25519 key exchange carried out in the
+/// ConnectionRequest and ConnectionResponse. A new one is generated for every
+/// connection, and it's consumed once the session keys are derived.
+pub(crate) struct HandshakeSecret {
+    secret: EphemeralSecret,
+    public: PublicKey,
+    /// the ML-KEM keys of a dialer offering a hybrid suite.
+    kem: Option<KemSecret>,
+}
+
+impl HandshakeSecret {
+    pub(crate) fn generate() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        HandshakeSecret {
+            secret,
+            public,
+            kem: None,
+        }
+    }
+
+    /// adds the ML-KEM keys a dialer needs to offer a hybrid suite, if the
+    /// crate was built with them.
+    pub(crate) fn with_kem(mut self) -> Self {
+        self.kem = KemSecret::generate();
+        self
+    }
+
+    pub(crate) fn public_key(&self) -> [u8; EPHEMERAL_KEY_LENGTH] {
+        self.public.to_bytes()
+    }
+
+    /// returns the ML-KEM encapsulation key to send with a hybrid offer.
+    pub(crate) fn key_share(&self) -> Option<Vec<u8>> {
+        self.kem.as_ref().map(KemSecret::encapsulation_key)
+    }
+
+    /// completes the key exchange with the remote's public key and derives
+    /// the session keys for the given connection, used with the AEAD of the
+    /// negotiated suite. Hybrid suites also take the remote's key share, and
+    /// the session keys then depend on both halves of the exchange.
+    pub(crate) fn into_session(
+        self,
+        remote_public: &[u8; EPHEMERAL_KEY_LENGTH],
+        remote_key_share: Option<&[u8]>,
+        id: &ConnectionId,
+        role: Endpoint,
+        suite: CipherSuite,
+    ) -> Result<Session, Error> {
+        let cipher = Cipher::for_suite(suite)?;
+        let local_public = self.public_key();
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*remote_public));
+        if !shared.was_contributory() {
+            return Err(Error::NonContributoryKeyExchange);
+        }
+
+        let mut secret = Zeroizing::new(shared.as_bytes().to_vec());
+        let mut local_key_share = None;
+        if suite.is_hybrid() {
+            let remote_key_share = remote_key_share.ok_or(Error::InvalidKeyShare)?;
+            let kem_shared = match role {
+                // the listener encapsulated a secret to our key
+                Endpoint::Dialer => self
+                    .kem
+                    .as_ref()
+                    .ok_or(Error::InvalidKeyShare)?
+                    .decapsulate(remote_key_share)?,
+                Endpoint::Listener => {
+                    let (ciphertext, kem_shared) = encapsulate(remote_key_share)?;
+                    local_key_share = Some(ciphertext);
+                    kem_shared
+                }
+            };
+            secret.extend_from_slice(&kem_shared);
+        }
+
+        let keys = GenerationKeys::derive(&id.0, &secret);
+        let (send_key, recv_key) = match role {
+            Endpoint::Dialer => (keys.dialer, keys.listener),
+            Endpoint::Listener => (keys.listener, keys.dialer),
+        };
+        let control = ControlKeys::derive(&id.0, &secret, role);
+
+        Ok(Session {
+            id: id.clone(),
+            suite,
+            cipher,
+            role,
+            local_public,
+            local_key_share,
+            key_updates: None,
+            send: Mutex::new(Keys::new(send_key)),
+            recv: Mutex::new(Keys::new(recv_key)),
+            update: Mutex::new(KeyUpdate::new(keys.root)),
+            messages: AtomicU64::new(0),
+            control,
+        })
+    }
+}
+
+/// Session holds the keys used to encrypt the TransportMessages of a connection.
+/// Each direction has its own key, which is replaced every `REKEY_INTERVAL` messages,
+/// and by a new generation of keys with each KeyUpdateMessage exchange.
+pub(crate) struct Session {
+    id: ConnectionId,
+    suite: CipherSuite,
+    cipher: Cipher,
+    role: Endpoint,
+    /// our public key from the handshake; resent if the ConnectionResponse
+    /// has to be sent again.
+    local_public: [u8; EPHEMERAL_KEY_LENGTH],
+    /// our ML-KEM ciphertext from a hybrid handshake, resent with the key.
+    local_key_share: Option<Vec<u8>>,
+    /// the limits the handshake negotiated for key updates, if any.
+    key_updates: Option<KeyUpdateLimits>,
+    send: Mutex<Keys>,
+    recv: Mutex<Keys>,
+    update: Mutex<KeyUpdate>,
+    /// the number of messages sealed and opened, which key updates are due after.
+    messages: AtomicU64,
+    /// seals the control messages of the connection; see `SealedMessage`.
+    control: ControlKeys,
+}
+
+impl Debug for Session {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session")
+            .field("id", &self.id)
+            .field("suite", &self.suite)
+            .finish()
+    }
+}
+
+impl Session {
+    pub(crate) fn local_public_key(&self) -> [u8; EPHEMERAL_KEY_LENGTH] {
+        self.local_public
+    }
+
+    pub(crate) fn local_key_share(&self) -> Option<&[u8]> {
+        self.local_key_share.as_deref()
+    }
+
+    pub(crate) fn cipher_suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    /// enables key updates with the limits the handshake negotiated.
+    pub(crate) fn with_key_updates(mut self, limits: Option<KeyUpdateLimits>) -> Self {
+        self.key_updates = limits;
+        self
+    }
+
+    pub(crate) fn key_update_limits(&self) -> Option<KeyUpdateLimits> {
+        self.key_updates
+    }
+
+    /// appends the encoding of the EncryptedTransportMessage corresponding to
+    /// `msg` to `buf`. The payload is encrypted in place, so no buffers are
+    /// allocated besides `buf` growing. If `compact` is set, the IDs are
+    /// encoded in compact form.
+    pub(crate) fn seal_into(
+        &self,
+        msg: &TransportMessage,
+        buf: &mut Vec<u8>,
+        compact: bool,
+    ) -> Result<(), Error> {
+        let key = self.send.lock().key_for(msg.nonce)?;
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        msg.encode_header_into(buf, compact);
+        let plaintext_start = buf.len();
+        msg.message.encode_into(buf, compact);
+        let tag = self.cipher.seal_in_place(
+            &key,
+            msg.nonce,
+            &aad(&msg.id, msg.nonce),
+            &mut buf[plaintext_start..],
+        )?;
+        buf.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn seal(&self, msg: &TransportMessage) -> Result<EncryptedTransportMessage, Error> {
+        let mut buf = vec![];
+        self.seal_into(msg, &mut buf, false)?;
+        EncryptedTransportMessage::try_from_bytes(buf.into(), false)
+    }
+
+    pub(crate) fn open(&self, msg: &EncryptedTransportMessage) -> Result<TransportMessage, Error> {
+        let plaintext = self.recv.lock().open(
+            self.cipher,
+            msg.nonce,
+            &msg.ciphertext,
+            &aad(&msg.id, msg.nonce),
+        )?;
+        self.messages.fetch_add(1, Ordering::Relaxed);
+
+        Ok(TransportMessage {
+            nonce: msg.nonce,
+            id: msg.id.clone(),
+            message: SubstreamMessage::try_from_bytes(plaintext.into(), msg.compact)?,
+        })
+    }
+
+    /// appends the encoding of a SealedMessage carrying `msg` to `buf`, for a
+    /// control message which has to be authenticated, like a ConnectionClose.
+    pub(crate) fn seal_control_into(&self, msg: &Message, buf: &mut Vec<u8>) -> Result<(), Error> {
+        let counter = self.control.counter.fetch_add(1, Ordering::SeqCst);
+        SealedMessage::encode_header_into(&self.id, counter, buf);
+        let plaintext_start = buf.len();
+        msg.encode_into(buf);
+        let tag = self.cipher.seal_in_place(
+            &self.control.send,
+            counter,
+            &aad(&self.id, counter),
+            &mut buf[plaintext_start..],
+        )?;
+        buf.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    /// opens a SealedMessage from the remote, returning the control message
+    /// it carries. Fails for messages which don't authenticate, which
+    /// belong to another connection, or which were received before.
+    pub(crate) fn open_control(&self, msg: &SealedMessage) -> Result<Message, Error> {
+        if msg.id != self.id {
+            return Err(Error::DecryptionFailure);
+        }
+        let plaintext = self.cipher.open(
+            &self.control.recv,
+            msg.counter,
+            Payload {
+                msg: &msg.ciphertext,
+                aad: &aad(&msg.id, msg.counter),
+            },
+        )?;
+        // only messages which authenticate may move the window
+        if !self.control.received.lock().insert(msg.counter) {
+            return Err(Error::ReplayedMessage);
+        }
+        let inner = Message::try_from_bytes(plaintext.into())?;
+        if inner.connection_id() != &self.id || !inner.is_sealable() {
+            return Err(Error::InvalidMessageBytes);
+        }
+        Ok(inner)
+    }
+
+    /// returns the KeyUpdateMessage the dialer of a connection sends next, if
+    /// any: a Request once the keys reached the negotiated limits, or the
+    /// last message again while the listener hasn't answered it.
+    pub(crate) fn poll_key_update(&self) -> Option<KeyUpdateMessage> {
+        let limits = self.key_updates?;
+        if self.role != Endpoint::Dialer {
+            return None;
+        }
+
+        let mut update = self.update.lock();
+        match &update.step {
+            KeyUpdateStep::Requested { public, .. } => {
+                let kind = KeyUpdateKind::Request {
+                    public_key: *public,
+                };
+                let tag_key = tag_key(&update.root);
+                return Some(self.key_update(update.generation + 1, kind, &tag_key));
+            }
+            KeyUpdateStep::Switched { from_nonce } => {
+                let kind = KeyUpdateKind::Switched {
+                    from_nonce: *from_nonce,
+                };
+                let tag_key = update.tag_key.as_ref()?;
+                return Some(self.key_update(update.generation, kind, tag_key));
+            }
+            KeyUpdateStep::Abandoned => return None,
+            KeyUpdateStep::Idle | KeyUpdateStep::Responded { .. } => {}
+        }
+
+        let messages = self.messages.load(Ordering::Relaxed) - update.installed_after;
+        if messages < limits.max_messages
+            && update.installed_at.elapsed() < Duration::from_secs(limits.max_age_secs)
+        {
+            return None;
+        }
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret).to_bytes();
+        update.step = KeyUpdateStep::Requested { secret, public };
+        let tag_key = tag_key(&update.root);
+        Some(self.key_update(
+            update.generation + 1,
+            KeyUpdateKind::Request { public_key: public },
+            &tag_key,
+        ))
+    }
+
+    /// handles a KeyUpdateMessage from the remote, returning our answer to
+    /// it, if any. `next_nonce` is the connection's outbound nonce counter,
+    /// which tells where we switch to the new keys.
+    pub(crate) fn handle_key_update(
+        &self,
+        msg: &KeyUpdateMessage,
+        next_nonce: &AtomicU64,
+    ) -> Result<Option<KeyUpdateMessage>, Error> {
+        if self.key_updates.is_none() {
+            return Err(Error::InvalidKeyUpdate);
+        }
+
+        // the messages of a generation are authenticated with the keys of
+        // the one before it
+        let mut update = self.update.lock();
+        let tag_key = if msg.generation == update.generation + 1 {
+            tag_key(&update.root)
+        } else if msg.generation == update.generation {
+            update.tag_key.clone().ok_or(Error::InvalidKeyUpdate)?
+        } else {
+            // a late retransmission of an older update
+            return Ok(None);
+        };
+        let (nonce, aad) = tag_input(msg, self.role == Endpoint::Listener);
+        self.cipher
+            .open(
+                &tag_key,
+                nonce,
+                Payload {
+                    msg: &msg.tag,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| Error::InvalidKeyUpdate)?;
+
+        let answer = match (msg.kind, self.role) {
+            (KeyUpdateKind::Request { public_key }, Endpoint::Listener) => {
+                self.handle_key_update_request(&mut update, msg.generation, public_key)?
+            }
+            (KeyUpdateKind::Response { public_key }, Endpoint::Dialer) => self
+                .handle_key_update_response(&mut update, msg.generation, public_key, next_nonce)?,
+            (KeyUpdateKind::Switched { from_nonce }, _) if msg.generation == update.generation => {
+                self.handle_key_update_switched(&mut update, from_nonce, next_nonce)
+            }
+            (KeyUpdateKind::Switched { .. }, _) => None,
+            _ => return Err(Error::InvalidKeyUpdate),
+        };
+        Ok(answer.map(|kind| self.key_update(msg.generation, kind, &tag_key)))
+    }
+
+    fn handle_key_update_request(
+        &self,
+        update: &mut KeyUpdate,
+        generation: u64,
+        remote_public: [u8; EPHEMERAL_KEY_LENGTH],
+    ) -> Result<Option<KeyUpdateKind>, Error> {
+        if generation == update.generation {
+            // the dialer didn't get our response
+            return Ok(match &update.step {
+                KeyUpdateStep::Responded { remote, public } if *remote == remote_public => {
+                    Some(KeyUpdateKind::Response {
+                        public_key: *public,
+                    })
+                }
+                _ => None,
+            });
+        }
+        if matches!(update.step, KeyUpdateStep::Responded { .. }) {
+            // the dialer hasn't switched to the newest keys yet
+            return Ok(None);
+        }
+
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret).to_bytes();
+        let keys = update.install(
+            secret,
+            &remote_public,
+            self.messages.load(Ordering::Relaxed),
+        )?;
+        // we keep sending with the current keys until the dialer switched
+        self.recv.lock().install(keys.dialer);
+        self.send.lock().install(keys.listener);
+        update.step = KeyUpdateStep::Responded {
+            remote: remote_public,
+            public,
+        };
+        Ok(Some(KeyUpdateKind::Response { public_key: public }))
+    }
+
+    fn handle_key_update_response(
+        &self,
+        update: &mut KeyUpdate,
+        generation: u64,
+        remote_public: [u8; EPHEMERAL_KEY_LENGTH],
+        next_nonce: &AtomicU64,
+    ) -> Result<Option<KeyUpdateKind>, Error> {
+        if generation != update.generation + 1 {
+            // a duplicate of the response we switched with
+            return Ok(None);
+        }
+        let step = std::mem::replace(&mut update.step, KeyUpdateStep::Abandoned);
+        let KeyUpdateStep::Requested { secret, .. } = step else {
+            update.step = step;
+            return Ok(None);
+        };
+
+        let keys = update.install(
+            secret,
+            &remote_public,
+            self.messages.load(Ordering::Relaxed),
+        )?;
+        self.recv.lock().install(keys.listener);
+        self.send.lock().install(keys.dialer);
+        let from_nonce = self.switch_send_keys(next_nonce);
+        update.step = KeyUpdateStep::Switched { from_nonce };
+        Ok(Some(KeyUpdateKind::Switched { from_nonce }))
+    }
+
+    fn handle_key_update_switched(
+        &self,
+        update: &mut KeyUpdate,
+        remote_from_nonce: u64,
+        next_nonce: &AtomicU64,
+    ) -> Option<KeyUpdateKind> {
+        self.recv.lock().switch_at(remote_from_nonce);
+        match (self.role, &update.step) {
+            // the update is complete
+            (Endpoint::Dialer, KeyUpdateStep::Switched { .. }) => {
+                update.step = KeyUpdateStep::Idle;
+                None
+            }
+            (Endpoint::Listener, KeyUpdateStep::Responded { .. }) => {
+                let from_nonce = self.switch_send_keys(next_nonce);
+                update.step = KeyUpdateStep::Switched { from_nonce };
+                Some(KeyUpdateKind::Switched { from_nonce })
+            }
+            // the dialer didn't get our switch
+            (Endpoint::Listener, KeyUpdateStep::Switched { from_nonce }) => {
+                Some(KeyUpdateKind::Switched {
+                    from_nonce: *from_nonce,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// switches to the installed sending key from the next nonce on, and
+    /// returns that nonce. It's taken with the keys locked, so that every
+    /// message with a later nonce is sealed with the new key.
+    fn switch_send_keys(&self, next_nonce: &AtomicU64) -> u64 {
+        let mut send = self.send.lock();
+        let from_nonce = next_nonce.load(Ordering::SeqCst);
+        send.switch_at(from_nonce);
+        from_nonce
+    }
+
+    fn key_update(
+        &self,
+        generation: u64,
+        kind: KeyUpdateKind,
+        tag_key: &SessionKey,
+    ) -> KeyUpdateMessage {
+        let mut msg = KeyUpdateMessage {
+            id: self.id.clone(),
+            generation,
+            kind,
+            tag: [0; KEY_UPDATE_TAG_LENGTH],
+        };
+        let (nonce, aad) = tag_input(&msg, self.role == Endpoint::Dialer);
+        let tag = self
+            .cipher
+            .seal_in_place(tag_key, nonce, &aad, &mut [])
+            .expect("sealing an empty message can't fail");
+        msg.tag.copy_from_slice(&tag);
+        msg
+    }
+}
+
+/// Cipher is the AEAD of a session's cipher suite. All of them take 32 byte
+/// keys and 12 byte nonces, and append a 16 byte tag.
+#[derive(Clone, Copy, Debug)]
+enum Cipher {
+    ChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+impl Cipher {
+    fn for_suite(suite: CipherSuite) -> Result<Self, Error> {
+        match suite {
+            CipherSuite::X25519_CHACHA20_POLY1305 => Ok(Cipher::ChaCha20Poly1305),
+            CipherSuite::X25519_MLKEM768_CHACHA20_POLY1305 if suite.is_supported() => {
+                Ok(Cipher::ChaCha20Poly1305)
+            }
+            CipherSuite::X25519_AES_256_GCM => Ok(Cipher::Aes256Gcm),
+            _ => Err(Error::UnsupportedCipherSuite),
+        }
+    }
+
+    fn seal_in_place(
+        self,
+        key: &SessionKey,
+        nonce: u64,
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<Tag<ChaCha20Poly1305>, Error> {
+        let nonce = aead_nonce(nonce);
+        match self {
+            Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(key.as_ref().into())
+                .encrypt_in_place_detached(&nonce, aad, buf),
+            Cipher::Aes256Gcm => {
+                Aes256Gcm::new(key.as_ref().into()).encrypt_in_place_detached(&nonce, aad, buf)
+            }
+        }
+        .map_err(|_| Error::EncryptionFailure)
+    }
+
+    fn open(self, key: &SessionKey, nonce: u64, payload: Payload) -> Result<Vec<u8>, Error> {
+        let nonce = aead_nonce(nonce);
+        match self {
+            Cipher::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(key.as_ref().into()).decrypt(&nonce, payload)
+            }
+            Cipher::Aes256Gcm => Aes256Gcm::new(key.as_ref().into()).decrypt(&nonce, payload),
+        }
+        .map_err(|_| Error::DecryptionFailure)
+    }
+}
+
+/// KemSecret is a dialer's ML-KEM-768 key pair, for the post-quantum half of
+/// a hybrid key exchange. The listener encapsulates a secret to the public
+/// half, and only the dialer can decapsulate it from the ciphertext.
+#[cfg(feature = "post-quantum")]
+struct KemSecret {
+    decapsulation_key: <MlKem768 as KemCore>::DecapsulationKey,
+    encapsulation_key: <MlKem768 as KemCore>::EncapsulationKey,
+}
+
+#[cfg(feature = "post-quantum")]
+impl KemSecret {
+    fn generate() -> Option<Self> {
+        let (decapsulation_key, encapsulation_key) = MlKem768::generate(&mut OsRng);
+        Some(KemSecret {
+            decapsulation_key,
+            encapsulation_key,
+        })
+    }
+
+    fn encapsulation_key(&self) -> Vec<u8> {
+        self.encapsulation_key.as_bytes().to_vec()
+    }
+
+    fn decapsulate(&self, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let ciphertext =
+            Ciphertext::<MlKem768>::try_from(ciphertext).map_err(|_| Error::InvalidKeyShare)?;
+        let shared = self
+            .decapsulation_key
+            .decapsulate(&ciphertext)
+            .map_err(|_| Error::InvalidKeyShare)?;
+        Ok(Zeroizing::new(shared.to_vec()))
+    }
+}
+
+/// encapsulates a new secret to the dialer's ML-KEM encapsulation key,
+/// returning the ciphertext for the dialer and the secret.
+#[cfg(feature = "post-quantum")]
+fn encapsulate(encapsulation_key: &[u8]) -> Result<(Vec<u8>, Zeroizing<Vec<u8>>), Error> {
+    type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+    let encoded = Encoded::<EncapsulationKey>::try_from(encapsulation_key)
+        .map_err(|_| Error::InvalidKeyShare)?;
+    let (ciphertext, shared) = EncapsulationKey::from_bytes(&encoded)
+        .encapsulate(&mut OsRng)
+        .map_err(|_| Error::InvalidKeyShare)?;
+    Ok((ciphertext.to_vec(), Zeroizing::new(shared.to_vec())))
+}
+
+/// without the `post-quantum` feature, hybrid suites are never negotiated.
+#[cfg(not(feature = "post-quantum"))]
+enum KemSecret {}
+
+#[cfg(not(feature = "post-quantum"))]
+impl KemSecret {
+    fn generate() -> Option<Self> {
+        None
+    }
+
+    fn encapsulation_key(&self) -> Vec<u8> {
+        match *self {}
+    }
+
+    fn decapsulate(&self, _ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "post-quantum"))]
+fn encapsulate(_encapsulation_key: &[u8]) -> Result<(Vec<u8>, Zeroizing<Vec<u8>>), Error> {
+    Err(Error::UnsupportedCipherSuite)
+}
+
+/// KeyChain is the sequence of keys used in one direction of a session.
+/// Since messages may be sent and received slightly out of order, the key of
+/// the previous epoch is kept around as well; older keys are erased.
+#[derive(Clone)]
+struct KeyChain {
+    epoch: u64,
+    key: SessionKey,
+    previous: Option<SessionKey>,
+}
+
+impl KeyChain {
+    fn new(key: SessionKey) -> Self {
+        KeyChain::starting_at(key, 0)
+    }
+
+    /// returns a chain whose key is the one of the given epoch.
+    fn starting_at(key: SessionKey, epoch: u64) -> Self {
+        KeyChain {
+            epoch,
+            key,
+            previous: None,
+        }
+    }
+
+    /// decrypts a message with the key for its nonce. The chain only
+    /// advances once the message is authenticated, so that forged messages
+    /// can't make us erase keys that are still needed.
+    fn open(
+        &mut self,
+        cipher: Cipher,
+        nonce: u64,
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let mut chain = self.clone();
+        let key = chain.key_for(nonce)?;
+        let plaintext = cipher.open(
+            &key,
+            nonce,
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )?;
+        *self = chain;
+        Ok(plaintext)
+    }
+
+    /// returns the key for the epoch the given message nonce belongs to,
+    /// advancing the chain if needed.
+    fn key_for(&mut self, nonce: u64) -> Result<SessionKey, Error> {
+        let epoch = nonce / REKEY_INTERVAL;
+        if epoch + 1 == self.epoch {
+            return self
+                .previous
+                .clone()
+                .ok_or(Error::SessionKeyUnavailable(nonce));
+        }
+        if epoch < self.epoch || epoch - self.epoch > MAX_EPOCH_SKIP {
+            return Err(Error::SessionKeyUnavailable(nonce));
+        }
+
+        while self.epoch < epoch {
+            let next = next_key(&self.key);
+            self.previous = Some(std::mem::replace(&mut self.key, next));
+            self.epoch += 1;
+        }
+
+        Ok(self.key.clone())
+    }
+}
+
+/// Keys are the key chains of one direction of a session. A key update
+/// installs the key of the next generation, which the sender switches to at
+/// some nonce; the previous generation's chain is kept for the messages sent
+/// before the switch which are still in flight.
+#[derive(Clone)]
+struct Keys {
+    chain: KeyChain,
+    /// the previous generation's chain, and the nonce the sender switched
+    /// at, once it told us.
+    previous: Option<(KeyChain, Option<u64>)>,
+    /// the next generation's key, until the sender switches to it.
+    next: Option<SessionKey>,
+}
+
+impl Keys {
+    fn new(key: SessionKey) -> Self {
+        Keys {
+            chain: KeyChain::new(key),
+            previous: None,
+            next: None,
+        }
+    }
+
+    fn install(&mut self, key: SessionKey) {
+        self.next = Some(key);
+    }
+
+    /// switches to the installed key from the given nonce on, whose epoch is
+    /// the new chain's first. We may have switched already, if messages with
+    /// the new keys overtook the sender's Switched message.
+    fn switch_at(&mut self, nonce: u64) {
+        if let Some(key) = self.next.take() {
+            let chain = KeyChain::starting_at(key, nonce / REKEY_INTERVAL);
+            let previous = std::mem::replace(&mut self.chain, chain);
+            self.previous = Some((previous, Some(nonce)));
+        } else if let Some((_, switched_at)) = &mut self.previous {
+            switched_at.get_or_insert(nonce);
+        }
+    }
+
+    /// returns the key to seal the message with the given nonce with.
+    fn key_for(&mut self, nonce: u64) -> Result<SessionKey, Error> {
+        if let Some((previous, Some(switched_at))) = &mut self.previous {
+            if nonce < *switched_at {
+                return previous.key_for(nonce);
+            }
+        }
+        let key = self.chain.key_for(nonce)?;
+        self.forget_previous();
+        Ok(key)
+    }
+
+    fn open(
+        &mut self,
+        cipher: Cipher,
+        nonce: u64,
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        if let Some((previous, switched_at)) = &mut self.previous {
+            match switched_at {
+                Some(switched_at) if nonce < *switched_at => {
+                    return previous.open(cipher, nonce, ciphertext, aad);
+                }
+                Some(_) => {}
+                // we don't know where the sender switched yet, so messages
+                // may belong to either generation
+                None => {
+                    if let Ok(plaintext) = previous.open(cipher, nonce, ciphertext, aad) {
+                        return Ok(plaintext);
+                    }
+                }
+            }
+        }
+
+        let result = self.chain.open(cipher, nonce, ciphertext, aad);
+        let Some(next) = self.next.as_ref().filter(|_| result.is_err()) else {
+            if result.is_ok() {
+                self.forget_previous();
+            }
+            return result;
+        };
+        // the sender switched to the next generation before its Switched
+        // message arrived. The new chain starts at the epoch of the switch,
+        // which came after the messages we opened with the current one.
+        let epoch = nonce / REKEY_INTERVAL;
+        for first_epoch in (self.chain.epoch..=epoch)
+            .rev()
+            .take(MAX_EPOCH_SKIP as usize)
+        {
+            let mut chain = KeyChain::starting_at(next.clone(), first_epoch);
+            if let Ok(plaintext) = chain.open(cipher, nonce, ciphertext, aad) {
+                let previous = std::mem::replace(&mut self.chain, chain);
+                self.previous = Some((previous, None));
+                self.next = None;
+                return Ok(plaintext);
+            }
+        }
+        result
+    }
+
+    /// erases the previous generation's chain once the current one moved
+    /// more than an epoch past the switch, as chains erase older epochs.
+    fn forget_previous(&mut self) {
+        if let Some((_, Some(switched_at))) = &self.previous {
+            if self.chain.epoch > switched_at / REKEY_INTERVAL + 1 {
+                self.previous = None;
+            }
+        }
+    }
+}
+
+/// GenerationKeys are the keys of a generation of a session: the handshake's
+/// or a key update's. The root key is what the next generation's keys are
+/// derived from, along with its key exchange.
+struct GenerationKeys {
+    root: SessionKey,
+    dialer: SessionKey,
+    listener: SessionKey,
+}
+
+impl GenerationKeys {
+    fn derive(salt: &[u8], secret: &[u8]) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(Some(salt), secret);
+        let expand = |info: &[u8]| {
+            let mut key = Zeroizing::new([0u8; SESSION_KEY_LENGTH]);
+            hkdf.expand(info, key.as_mut())
+                .expect("session key length is valid for HKDF-SHA256");
+            key
+        };
+        GenerationKeys {
+            root: expand(ROOT_KEY_INFO),
+            dialer: expand(DIALER_KEY_INFO),
+            listener: expand(LISTENER_KEY_INFO),
+        }
+    }
+}
+
+/// ControlKeys seal the control messages of a session, each direction with
+/// its own key. They're derived from the handshake alone and aren't replaced
+/// by key updates, since control messages are few; each message has its own
+/// counter, so a key never seals two messages with the same nonce.
+struct ControlKeys {
+    send: SessionKey,
+    recv: SessionKey,
+    /// the counter of the next message we seal.
+    counter: AtomicU64,
+    /// the counters of the messages received so far.
+    received: Mutex<ReplayWindow>,
+}
+
+impl ControlKeys {
+    fn derive(salt: &[u8], secret: &[u8], role: Endpoint) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(Some(salt), secret);
+        let expand = |info: &[u8]| {
+            let mut key = Zeroizing::new([0u8; SESSION_KEY_LENGTH]);
+            hkdf.expand(info, key.as_mut())
+                .expect("session key length is valid for HKDF-SHA256");
+            key
+        };
+        let (dialer, listener) = (
+            expand(DIALER_CONTROL_KEY_INFO),
+            expand(LISTENER_CONTROL_KEY_INFO),
+        );
+        let (send, recv) = match role {
+            Endpoint::Dialer => (dialer, listener),
+            Endpoint::Listener => (listener, dialer),
+        };
+        ControlKeys {
+            send,
+            recv,
+            counter: AtomicU64::new(0),
+            received: Mutex::new(ReplayWindow::default()),
+        }
+    }
+}
+
+/// ReplayWindow remembers the counters received within
+/// `CONTROL_REPLAY_WINDOW` of the highest one.
+#[derive(Default)]
+struct ReplayWindow {
+    highest: u64,
+    seen: BTreeSet<u64>,
+}
+
+impl ReplayWindow {
+    /// records the counter, returning false if it was seen before or is too
+    /// old to tell.
+    fn insert(&mut self, counter: u64) -> bool {
+        if counter.saturating_add(CONTROL_REPLAY_WINDOW) <= self.highest
+            || !self.seen.insert(counter)
+        {
+            return false;
+        }
+        if counter > self.highest {
+            self.highest = counter;
+            let oldest = counter.saturating_sub(CONTROL_REPLAY_WINDOW);
+            self.seen = self.seen.split_off(&oldest);
+        }
+        true
+    }
+}
+
+/// KeyUpdate tracks the key updates of a session; see `KeyUpdateMessage`.
+struct KeyUpdate {
+    /// the generation of the newest keys, the handshake's being 0.
+    generation: u64,
+    /// the newest generation's root key.
+    root: SessionKey,
+    /// authenticates the KeyUpdateMessages of the newest generation, which
+    /// may be resent after it was installed.
+    tag_key: Option<SessionKey>,
+    step: KeyUpdateStep,
+    /// when the newest generation was installed, and the number of messages
+    /// sealed and opened by then.
+    installed_at: Instant,
+    installed_after: u64,
+}
+
+enum KeyUpdateStep {
+    Idle,
+    /// the dialer asked for the next generation.
+    Requested {
+        secret: EphemeralSecret,
+        public: [u8; EPHEMERAL_KEY_LENGTH],
+    },
+    /// the listener installed the newest generation with the dialer's
+    /// public key, and waits for the dialer to switch to it.
+    Responded {
+        remote: [u8; EPHEMERAL_KEY_LENGTH],
+        public: [u8; EPHEMERAL_KEY_LENGTH],
+    },
+    /// we switched to the newest generation at the given nonce.
+    Switched {
+        from_nonce: u64,
+    },
+    /// the listener answered with an invalid key. No more updates are
+    /// started, since their requests would reuse the nonce of the tag of
+    /// the one which failed.
+    Abandoned,
+}
+
+impl KeyUpdate {
+    fn new(root: SessionKey) -> Self {
+        KeyUpdate {
+            generation: 0,
+            root,
+            tag_key: None,
+            step: KeyUpdateStep::Idle,
+            installed_at: Instant::now(),
+            installed_after: 0,
+        }
+    }
+
+    /// completes the key exchange of the next generation, and returns its keys.
+    fn install(
+        &mut self,
+        secret: EphemeralSecret,
+        remote_public: &[u8; EPHEMERAL_KEY_LENGTH],
+        messages: u64,
+    ) -> Result<GenerationKeys, Error> {
+        let shared = secret.diffie_hellman(&PublicKey::from(*remote_public));
+        if !shared.was_contributory() {
+            return Err(Error::NonContributoryKeyExchange);
+        }
+        let keys = GenerationKeys::derive(self.root.as_ref(), shared.as_bytes());
+        self.tag_key = Some(tag_key(&self.root));
+        self.root = keys.root.clone();
+        self.generation += 1;
+        self.installed_at = Instant::now();
+        self.installed_after = messages;
+        Ok(keys)
+    }
+}
+
+/// returns the key which authenticates the KeyUpdateMessages of the
+/// generation after the one with the given root key.
+fn tag_key(root: &SessionKey) -> SessionKey {
+    let mut key = Zeroizing::new([0u8; SESSION_KEY_LENGTH]);
+    Hkdf::<Sha256>::new(None, root.as_ref())
+        .expand(KEY_UPDATE_INFO, key.as_mut())
+        .expect("session key length is valid for HKDF-SHA256");
+    key
+}
+
+/// returns the AEAD nonce and associated data of a KeyUpdateMessage's tag.
+/// Each step of an update has its own nonce, and resending a step resends
+/// the same message, so a nonce is never used for two different messages.
+fn tag_input(msg: &KeyUpdateMessage, from_dialer: bool) -> (u64, Vec<u8>) {
+    let nonce = match msg.kind {
+        KeyUpdateKind::Request { .. } => 0,
+        KeyUpdateKind::Response { .. } => 1,
+        KeyUpdateKind::Switched { .. } if from_dialer => 2,
+        KeyUpdateKind::Switched { .. } => 3,
+    };
+    let mut aad = vec![];
+    msg.encode_authenticated_into(&mut aad);
+    (nonce, aad)
+}
+
+fn next_key(key: &SessionKey) -> SessionKey {
+    let mut next = Zeroizing::new([0u8; SESSION_KEY_LENGTH]);
+    Hkdf::<Sha256>::new(None, key.as_ref())
+        .expand(REKEY_INFO, next.as_mut())
+        .expect("session key length is valid for HKDF-SHA256");
+    next
+}
+
+/// message nonces are unique per direction, and each direction has its own
+/// key, so they can be used as AEAD nonces directly.
+fn aead_nonce(nonce: u64) -> Nonce {
+    let mut bytes = [0u8; 12];
+    bytes[4..].copy_from_slice(&nonce.to_be_bytes());
+    bytes.into()
+}
+
+fn aad(id: &ConnectionId, nonce: u64) -> [u8; AAD_LENGTH] {
+    let mut aad = [0u8; AAD_LENGTH];
+    aad[..id.0.len()].copy_from_slice(&id.0);
+    aad[id.0.len()..].copy_from_slice(&nonce.to_be_bytes());
+    aad
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::{
+        OutOfBandMessage, SubstreamId, SubstreamMessageType, SEALED_MESSAGE_TYPE,
+    };
+    use bytes::Bytes;
+
+    fn session_pair() -> (Session, Session) {
+        session_pair_with_suite(CipherSuite::default())
+    }
+
+    fn session_pair_with_suite(suite: CipherSuite) -> (Session, Session) {
+        // compact, so that messages may be sealed in either form
+        let id = ConnectionId::generate_compact();
+        let dialer = HandshakeSecret::generate().with_kem();
+        let listener = HandshakeSecret::generate();
+        let dialer_public = dialer.public_key();
+        let listener_public = listener.public_key();
+        let dialer_key_share = dialer.key_share();
+        let listener = listener
+            .into_session(
+                &dialer_public,
+                dialer_key_share.as_deref(),
+                &id,
+                Endpoint::Listener,
+                suite,
+            )
+            .unwrap();
+        let dialer = dialer
+            .into_session(
+                &listener_public,
+                listener.local_key_share(),
+                &id,
+                Endpoint::Dialer,
+                suite,
+            )
+            .unwrap();
+        (dialer, listener)
+    }
+
+    fn data_message(id: &ConnectionId, nonce: u64) -> TransportMessage {
+        TransportMessage {
+            nonce,
+            id: id.clone(),
+            message: SubstreamMessage::new_with_data(
+                SubstreamId::generate_compact(),
+                b"hello".to_vec(),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_session_seal_control() {
+        let (dialer, listener) = session_pair();
+        let msg = Message::OutOfBandMessage(OutOfBandMessage {
+            id: dialer.id.clone(),
+            data: Bytes::from_static(b"hello"),
+        });
+        let seal = |session: &Session| {
+            let mut buf = vec![SEALED_MESSAGE_TYPE];
+            session.seal_control_into(&msg, &mut buf).unwrap();
+            match Message::try_from_bytes(buf.into()).unwrap() {
+                Message::Sealed(sealed) => sealed,
+                _ => panic!("expected Message::Sealed"),
+            }
+        };
+
+        let first = seal(&dialer);
+        let second = seal(&dialer);
+        assert!(!first.ciphertext.windows(5).any(|w| w == b"hello"));
+        // they may arrive out of order, but only once
+        for sealed in [&second, &first] {
+            match listener.open_control(sealed).unwrap() {
+                Message::OutOfBandMessage(opened) => assert_eq!(opened.data, &b"hello"[..]),
+                _ => panic!("expected Message::OutOfBandMessage"),
+            }
+        }
+        assert!(matches!(
+            listener.open_control(&first),
+            Err(Error::ReplayedMessage)
+        ));
+
+        // each direction uses its own key, and the counter is authenticated
+        assert!(matches!(
+            dialer.open_control(&seal(&dialer)),
+            Err(Error::DecryptionFailure)
+        ));
+        let mut tampered = seal(&dialer);
+        tampered.counter += 1;
+        assert!(matches!(
+            listener.open_control(&tampered),
+            Err(Error::DecryptionFailure)
+        ));
+
+        // counters far behind the highest one are dropped as well
+        let mut window = ReplayWindow::default();
+        assert!(window.insert(CONTROL_REPLAY_WINDOW + 1));
+        assert!(window.insert(2));
+        assert!(!window.insert(1));
+        assert!(!window.insert(2));
+    }
+
+    #[test]
+    fn test_session_seal_and_open() {
+        let (dialer, listener) = session_pair();
+        let msg = data_message(&dialer.id, 1);
+
+        let sealed = dialer.seal(&msg).unwrap();
+        assert!(!sealed.ciphertext.windows(5).any(|w| w == b"hello"));
+        let opened = listener.open(&sealed).unwrap();
+        match opened.message.message_type {
+            SubstreamMessageType::Data(data) => assert_eq!(data, &b"hello"[..]),
+            _ => panic!("expected SubstreamMessageType::Data"),
+        }
+
+        // each direction uses its own key
+        assert!(matches!(
+            dialer.open(&sealed),
+            Err(Error::DecryptionFailure)
+        ));
+
+        // the nonce is authenticated
+        let mut replayed = sealed;
+        replayed.nonce = 2;
+        assert!(matches!(
+            listener.open(&replayed),
+            Err(Error::DecryptionFailure)
+        ));
+
+        // the substream ID is encrypted in compact form as well
+        let msg = data_message(&dialer.id, 2);
+        let mut buf = vec![];
+        dialer.seal_into(&msg, &mut buf, true).unwrap();
+        let sealed = EncryptedTransportMessage::try_from_bytes(buf.into(), true).unwrap();
+        let opened = listener.open(&sealed).unwrap();
+        assert_eq!(opened.id, msg.id);
+        assert_eq!(opened.message.substream_id, msg.message.substream_id);
+    }
+
+    #[test]
+    fn test_session_cipher_suites() {
+        let (dialer, listener) = session_pair_with_suite(CipherSuite::X25519_AES_256_GCM);
+        let msg = data_message(&dialer.id, 1);
+        let sealed = dialer.seal(&msg).unwrap();
+        assert!(!sealed.ciphertext.windows(5).any(|w| w == b"hello"));
+        listener.open(&sealed).unwrap();
+
+        // a session of another suite can't open it, even with the same keys
+        let chacha = Session {
+            cipher: Cipher::ChaCha20Poly1305,
+            suite: CipherSuite::X25519_CHACHA20_POLY1305,
+            ..listener
+        };
+        assert!(matches!(
+            chacha.open(&sealed),
+            Err(Error::DecryptionFailure)
+        ));
+
+        // and suites we don't implement are refused
+        assert!(matches!(
+            HandshakeSecret::generate().into_session(
+                &HandshakeSecret::generate().public_key(),
+                None,
+                &dialer.id,
+                Endpoint::Dialer,
+                CipherSuite::from_id(200),
+            ),
+            Err(Error::UnsupportedCipherSuite)
+        ));
+    }
+
+    #[cfg(feature = "post-quantum")]
+    #[test]
+    fn test_session_hybrid_key_exchange() {
+        let (dialer, listener) =
+            session_pair_with_suite(CipherSuite::X25519_MLKEM768_CHACHA20_POLY1305);
+        assert!(listener.local_key_share().is_some());
+        let sealed = dialer.seal(&data_message(&dialer.id, 1)).unwrap();
+        listener.open(&sealed).unwrap();
+
+        // the KEM half can't be left out, or replaced
+        let id = ConnectionId::generate();
+        let dialer = HandshakeSecret::generate().with_kem();
+        let listener = HandshakeSecret::generate();
+        let suite = CipherSuite::X25519_MLKEM768_CHACHA20_POLY1305;
+        assert!(matches!(
+            HandshakeSecret::generate().into_session(
+                &dialer.public_key(),
+                None,
+                &id,
+                Endpoint::Listener,
+                suite,
+            ),
+            Err(Error::InvalidKeyShare)
+        ));
+        let listener_public = listener.public_key();
+        let listener = listener
+            .into_session(
+                &dialer.public_key(),
+                HandshakeSecret::generate()
+                    .with_kem()
+                    .key_share()
+                    .as_deref(),
+                &id,
+                Endpoint::Listener,
+                suite,
+            )
+            .unwrap();
+        let dialer = dialer
+            .into_session(
+                &listener_public,
+                listener.local_key_share(),
+                &id,
+                Endpoint::Dialer,
+                suite,
+            )
+            .unwrap();
+        let sealed = dialer.seal(&data_message(&id, 1)).unwrap();
+        assert!(matches!(
+            listener.open(&sealed),
+            Err(Error::DecryptionFailure)
+        ));
+    }
+
+    #[test]
+    fn test_session_rekey() {
+        let (dialer, listener) = session_pair();
+        let id = dialer.id.clone();
+
+        // messages of the previous epoch can still be opened after a rekey...
+        let late = dialer.seal(&data_message(&id, REKEY_INTERVAL - 1)).unwrap();
+        let next = dialer.seal(&data_message(&id, REKEY_INTERVAL)).unwrap();
+        listener.open(&next).unwrap();
+        listener.open(&late).unwrap();
+
+        // ...but older keys are erased
+        let next = dialer.seal(&data_message(&id, 3 * REKEY_INTERVAL)).unwrap();
+        listener.open(&next).unwrap();
+        assert!(matches!(
+            listener.open(&late),
+            Err(Error::SessionKeyUnavailable(_))
+        ));
+
+        // nonces too far ahead are rejected, and forged messages don't advance the chain
+        let forged = EncryptedTransportMessage {
+            nonce: 5 * REKEY_INTERVAL,
+            id: id.clone(),
+            ciphertext: vec![0u8; 32].into(),
+            compact: false,
+        };
+        assert!(matches!(
+            listener.open(&forged),
+            Err(Error::DecryptionFailure)
+        ));
+        let far = EncryptedTransportMessage {
+            nonce: (4 + MAX_EPOCH_SKIP) * REKEY_INTERVAL,
+            id: id.clone(),
+            ciphertext: vec![0u8; 32].into(),
+            compact: false,
+        };
+        assert!(matches!(
+            listener.open(&far),
+            Err(Error::SessionKeyUnavailable(_))
+        ));
+        let next = dialer
+            .seal(&data_message(&id, 3 * REKEY_INTERVAL + 1))
+            .unwrap();
+        listener.open(&next).unwrap();
+    }
+
+    #[test]
+    fn test_session_key_update() {
+        let limits = KeyUpdateLimits {
+            max_messages: 3,
+            max_age_secs: 3600,
+        };
+        let (dialer, listener) = session_pair();
+        let (dialer, listener) = (
+            dialer.with_key_updates(Some(limits)),
+            listener.with_key_updates(Some(limits)),
+        );
+        let id = dialer.id.clone();
+        let (dialer_nonce, listener_nonce) = (AtomicU64::new(3), AtomicU64::new(1));
+
+        // updates are due once the keys were used for enough messages, and
+        // only the dialer starts them
+        for nonce in 0..2 {
+            listener
+                .open(&dialer.seal(&data_message(&id, nonce)).unwrap())
+                .unwrap();
+        }
+        assert!(dialer.poll_key_update().is_none());
+        let late = dialer.seal(&data_message(&id, 2)).unwrap();
+        let request = dialer.poll_key_update().unwrap();
+        assert_eq!(request.generation, 1);
+        assert!(listener.poll_key_update().is_none());
+        // until it's answered, the same request is resent
+        assert_eq!(dialer.poll_key_update().unwrap(), request);
+
+        // only the peer the connection is with can update the keys
+        let mut forged = request.clone();
+        forged.kind = KeyUpdateKind::Request {
+            public_key: HandshakeSecret::generate().public_key(),
+        };
+        assert!(matches!(
+            listener.handle_key_update(&forged, &listener_nonce),
+            Err(Error::InvalidKeyUpdate)
+        ));
+
+        let old_keys = listener.recv.lock().chain.clone();
+        let response = listener
+            .handle_key_update(&request, &listener_nonce)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(response.kind, KeyUpdateKind::Response { .. }));
+        // a resent request gets the same response
+        assert_eq!(
+            listener
+                .handle_key_update(&request, &listener_nonce)
+                .unwrap(),
+            Some(response.clone())
+        );
+        let switched = dialer
+            .handle_key_update(&response, &dialer_nonce)
+            .unwrap()
+            .unwrap();
+        assert_eq!(switched.kind, KeyUpdateKind::Switched { from_nonce: 3 });
+        assert!(dialer
+            .handle_key_update(&response, &dialer_nonce)
+            .unwrap()
+            .is_none());
+
+        // the new keys don't follow from the old ones. Messages sealed with
+        // them may overtake the switch, and those sent before it may arrive late
+        let next = dialer.seal(&data_message(&id, 3)).unwrap();
+        assert!(old_keys
+            .clone()
+            .open(listener.cipher, 3, &next.ciphertext, &aad(&id, 3))
+            .is_err());
+        listener.open(&next).unwrap();
+        listener.open(&late).unwrap();
+
+        let listener_switched = listener
+            .handle_key_update(&switched, &listener_nonce)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            listener_switched.kind,
+            KeyUpdateKind::Switched { from_nonce: 1 }
+        );
+        dialer
+            .open(&listener.seal(&data_message(&id, 1)).unwrap())
+            .unwrap();
+        assert!(dialer
+            .handle_key_update(&listener_switched, &dialer_nonce)
+            .unwrap()
+            .is_none());
+
+        // the next update is due once the new keys were used enough
+        assert!(dialer.poll_key_update().is_none());
+        dialer
+            .open(&listener.seal(&data_message(&id, 2)).unwrap())
+            .unwrap();
+        assert_eq!(dialer.poll_key_update().unwrap().generation, 2);
+
+        // sessions which didn't negotiate updates refuse them
+        let (_, plain_listener) = session_pair();
+        assert!(matches!(
+            plain_listener.handle_key_update(&request, &listener_nonce),
+            Err(Error::InvalidKeyUpdate)
+        ));
+    }
+}
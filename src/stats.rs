@@ -0,0 +1,435 @@
+use libp2p::core::{Endpoint, Multiaddr, PeerId};
+use parking_lot::{Mutex, RwLock};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::message::{AckMessage, ConnectionId};
+use super::redact::redact_always;
+
+/// ConnectionStats describes how a connection was set up.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// the remote's PeerId.
+    pub peer_id: PeerId,
+    /// whether we dialed the connection or accepted it.
+    pub endpoint: Endpoint,
+    /// the remote's nym address, which it can be dialed at again. Known for
+    /// outbound connections, and for inbound connections if the dialer shared
+    /// it; see `NymTransportConfig::address_exchange`.
+    pub remote_address: Option<Multiaddr>,
+    /// time between sending the ConnectionRequest and receiving the
+    /// ConnectionResponse. If the request was retransmitted, it's measured
+    /// from the first transmission. Only known for outbound connections.
+    pub handshake_rtt: Option<Duration>,
+    /// time between the dial and the connection being established, including
+    /// any time spent waiting for a dial slot. Only known for outbound connections.
+    pub setup_duration: Option<Duration>,
+    /// outbound data messages dropped because they waited longer than the
+    /// configured message TTL to be sent.
+    pub expired_messages: u64,
+    /// what became of the messages sent over the connection.
+    pub delivery: DeliveryStats,
+    /// how inbound messages arrived on the connection.
+    pub reorder: ReorderStats,
+    /// the reply SURBs of the connection: those we gave the remote to answer
+    /// with on outbound connections, and those our replies used up on
+    /// inbound ones.
+    pub reply_surbs: Option<ReplySurbBudget>,
+    /// the estimated round trip time of the connection, from the handshake
+    /// and the remote's acks. Unknown for inbound connections until the
+    /// first ack, and for those which didn't negotiate acks.
+    pub rtt: Option<RttEstimate>,
+    /// the size of the connection's data messages, if they're sized by its
+    /// loss; see `AdaptiveFrameSize`. Unknown until the first ack.
+    pub frame_size: Option<FrameSizeStats>,
+}
+
+impl Debug for ConnectionStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionStats")
+            .field("peer_id", &self.peer_id)
+            .field("endpoint", &self.endpoint)
+            .field(
+                "remote_address",
+                &self.remote_address.as_ref().map(redact_always),
+            )
+            .field("handshake_rtt", &self.handshake_rtt)
+            .field("setup_duration", &self.setup_duration)
+            .field("expired_messages", &self.expired_messages)
+            .field("delivery", &self.delivery)
+            .field("reorder", &self.reorder)
+            .field("reply_surbs", &self.reply_surbs)
+            .field("rtt", &self.rtt)
+            .field("frame_size", &self.frame_size)
+            .finish()
+    }
+}
+
+/// RttEstimate is the smoothed round trip time of a connection and how much
+/// it varies, estimated as for TCP's retransmission timer (RFC 6298). The
+/// samples include the time the remote holds back its acks, so the
+/// estimate errs on the high side.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RttEstimate {
+    /// moving average of the samples, weighting the newest one by 1/8.
+    pub smoothed: Duration,
+    /// moving average of the samples' deviation from `smoothed`, weighting
+    /// the newest one by 1/4.
+    pub variation: Duration,
+    /// the number of samples the estimate is based on.
+    pub samples: u64,
+}
+
+impl RttEstimate {
+    pub(crate) fn new(sample: Duration) -> Self {
+        RttEstimate {
+            smoothed: sample,
+            variation: sample / 2,
+            samples: 1,
+        }
+    }
+
+    fn update(&mut self, sample: Duration) {
+        let deviation = self.smoothed.abs_diff(sample);
+        self.variation = (self.variation * 3 + deviation) / 4;
+        self.smoothed = (self.smoothed * 7 + sample) / 8;
+        self.samples += 1;
+    }
+
+    /// returns a timeout which a round trip rarely exceeds: the smoothed
+    /// round trip time plus four times its variation. Protocols which make
+    /// a single round trip per request can time their requests out with it,
+    /// rather than with a fixed guess.
+    pub fn timeout(&self) -> Duration {
+        self.smoothed + self.variation * 4
+    }
+}
+
+/// RttSampler times one TransportMessage of a connection at a time, from
+/// when it's queued until the remote acknowledges it, as TCP does; timing
+/// every message would mean keeping all their send times. Samples of
+/// retransmitted messages are discarded, since it's unknown which of the
+/// transmissions the ack is for.
+#[derive(Debug, Default)]
+pub(crate) struct RttSampler {
+    timed: Mutex<Option<(u64, Instant)>>,
+}
+
+impl RttSampler {
+    pub(crate) fn on_send(&self, nonce: u64) {
+        self.timed
+            .lock()
+            .get_or_insert_with(|| (nonce, Instant::now()));
+    }
+
+    pub(crate) fn on_retransmit(&self, nonce: u64) {
+        let mut timed = self.timed.lock();
+        if timed.is_some_and(|(timed_nonce, _)| timed_nonce == nonce) {
+            *timed = None;
+        }
+    }
+
+    /// returns the round trip time of the timed message if the ack covers it.
+    pub(crate) fn on_ack(&self, ack: &AckMessage) -> Option<Duration> {
+        let mut timed = self.timed.lock();
+        let (nonce, sent_at) = (*timed)?;
+        let acked = nonce <= ack.nonce
+            || ack
+                .sacks
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&nonce));
+        if !acked {
+            return None;
+        }
+        *timed = None;
+        Some(sent_at.elapsed())
+    }
+}
+
+/// FrameSizeStats is the size a connection picked for its data messages, and
+/// the packet loss it's based on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameSizeStats {
+    /// the most bytes sent in a data message.
+    pub frame_size: usize,
+    /// the sphinx packets a data message of that size spans.
+    pub packets: usize,
+    /// the estimated share of packets lost, in parts per million.
+    pub packet_loss_ppm: u32,
+}
+
+/// ReplySurbBudget estimates how many of the reply SURBs we attached to our
+/// messages are left for the listener to send with. Each message received
+/// from the listener used up at least one SURB, more if it spanned several
+/// packets, so the estimate errs on the high side.
+///
+/// The listener can't tell how many SURBs the dialer attached, since its
+/// mixnet client keeps them, so on inbound connections only `used` and
+/// `exhausted` are counted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ReplySurbBudget {
+    /// SURBs attached to the messages sent over the connection, including
+    /// the first ConnectionRequest.
+    pub attached: u64,
+    /// the part of `attached` sent after the handshake, topping up the
+    /// listener's supply.
+    pub topped_up: u64,
+    /// messages received over an outbound connection, including the
+    /// ConnectionResponse, or replies sent over an inbound one.
+    pub used: u64,
+    /// replies over an inbound connection which found no SURB left; see
+    /// `Error::SurbExhausted`.
+    pub exhausted: u64,
+}
+
+impl ReplySurbBudget {
+    /// returns the estimated number of SURBs the listener has left, on an
+    /// outbound connection.
+    pub fn remaining(&self) -> u64 {
+        self.attached.saturating_sub(self.used)
+    }
+}
+
+/// DeliveryStats counts the messages sent over a connection by how far they're
+/// known to have got. The mixnet client doesn't report whether its gateway
+/// accepted a message, so the first confirmation after the hand-off to the
+/// client is the remote's ack.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeliveryStats {
+    /// messages the mixnet client accepted for sending.
+    pub sent: u64,
+    /// messages the mixnet client failed to accept, which were never sent.
+    pub failed: u64,
+    /// TransportMessages the remote acknowledged receiving. Only counted if
+    /// acks were negotiated; see `NymTransportConfig::congestion_control`
+    /// and `NymTransportConfig::selective_repeat`.
+    pub acknowledged: u64,
+}
+
+/// ReorderStats describes how a connection's inbound messages were reordered
+/// by the mixnet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReorderStats {
+    /// messages which arrived before one with a lower nonce, including those
+    /// which overtook the connection handshake.
+    pub out_of_order: u64,
+    /// the largest observed distance between the nonce of an arriving message
+    /// and the next expected one.
+    pub max_gap: u64,
+    /// messages dropped because they were duplicates, or arrived too far out
+    /// of order.
+    pub dropped: u64,
+}
+
+/// ConnectionStatsRegistry holds the `ConnectionStats` of a transport's open
+/// connections. A handle can be obtained with `NymTransport::connection_stats()`
+/// before the transport is moved into a swarm; entries are removed when the
+/// corresponding connection is dropped.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStatsRegistry {
+    inner: Arc<RwLock<HashMap<ConnectionId, ConnectionStats>>>,
+}
+
+impl ConnectionStatsRegistry {
+    /// returns the stats of all open connections with the given peer.
+    pub fn get(&self, peer_id: &PeerId) -> Vec<ConnectionStats> {
+        self.inner
+            .read()
+            .values()
+            .filter(|stats| stats.peer_id == *peer_id)
+            .cloned()
+            .collect()
+    }
+
+    /// returns the stats of all open connections.
+    pub fn all(&self) -> Vec<ConnectionStats> {
+        self.inner.read().values().cloned().collect()
+    }
+
+    pub(crate) fn insert(&self, id: ConnectionId, stats: ConnectionStats) {
+        self.inner.write().insert(id, stats);
+    }
+
+    /// returns the remote's PeerId of the given open connection.
+    pub(crate) fn peer_id(&self, id: &ConnectionId) -> Option<PeerId> {
+        self.inner.read().get(id).map(|stats| stats.peer_id)
+    }
+
+    pub(crate) fn record_remote_address(&self, id: &ConnectionId, address: Multiaddr) {
+        if let Some(stats) = self.inner.write().get_mut(id) {
+            stats.remote_address = Some(address);
+        }
+    }
+
+    pub(crate) fn record_expired(&self, id: &ConnectionId) {
+        if let Some(stats) = self.inner.write().get_mut(id) {
+            stats.expired_messages += 1;
+        }
+    }
+
+    pub(crate) fn record_delivery(&self, id: &ConnectionId, sent: bool) {
+        if let Some(stats) = self.inner.write().get_mut(id) {
+            match sent {
+                true => stats.delivery.sent += 1,
+                false => stats.delivery.failed += 1,
+            }
+        }
+    }
+
+    pub(crate) fn record_acknowledged(&self, id: &ConnectionId, messages: u64) {
+        if messages == 0 {
+            return;
+        }
+        if let Some(stats) = self.inner.write().get_mut(id) {
+            stats.delivery.acknowledged += messages;
+        }
+    }
+
+    pub(crate) fn record_rtt(&self, id: &ConnectionId, sample: Duration) {
+        if let Some(stats) = self.inner.write().get_mut(id) {
+            match &mut stats.rtt {
+                Some(rtt) => rtt.update(sample),
+                None => stats.rtt = Some(RttEstimate::new(sample)),
+            }
+        }
+    }
+
+    pub(crate) fn record_frame_size(&self, id: &ConnectionId, frame_size: FrameSizeStats) {
+        if let Some(stats) = self.inner.write().get_mut(id) {
+            stats.frame_size = Some(frame_size);
+        }
+    }
+
+    pub(crate) fn record_reorder(&self, id: &ConnectionId, reorder: &ReorderStats) {
+        if let Some(stats) = self.inner.write().get_mut(id) {
+            stats.reorder = reorder.clone();
+        }
+    }
+
+    pub(crate) fn record_reply_surbs_attached(&self, id: &ConnectionId, surbs: u32, top_up: bool) {
+        if let Some(budget) = self
+            .inner
+            .write()
+            .get_mut(id)
+            .and_then(|stats| stats.reply_surbs.as_mut())
+        {
+            budget.attached += surbs as u64;
+            if top_up {
+                budget.topped_up += surbs as u64;
+            }
+        }
+    }
+
+    /// records that a message arrived with one of our SURBs. Returns false
+    /// if the connection isn't one we dialed, so the message didn't use one.
+    pub(crate) fn record_reply_surb_used(&self, id: &ConnectionId) -> bool {
+        let mut inner = self.inner.write();
+        let Some(stats) = inner.get_mut(id) else {
+            return false;
+        };
+        if stats.endpoint != Endpoint::Dialer {
+            return false;
+        }
+        if let Some(budget) = &mut stats.reply_surbs {
+            budget.used += 1;
+        }
+        true
+    }
+
+    /// records a reply sent over an inbound connection, which used up one
+    /// of the dialer's SURBs unless there were none left.
+    pub(crate) fn record_reply(&self, id: &ConnectionId, exhausted: bool) {
+        if let Some(budget) = self
+            .inner
+            .write()
+            .get_mut(id)
+            .and_then(|stats| stats.reply_surbs.as_mut())
+        {
+            match exhausted {
+                true => budget.exhausted += 1,
+                false => budget.used += 1,
+            }
+        }
+    }
+
+    pub(crate) fn remove(&self, id: &ConnectionId) {
+        self.inner.write().remove(id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rtt_estimate() {
+        let mut rtt = RttEstimate::new(Duration::from_secs(2));
+        assert_eq!(rtt.timeout(), Duration::from_secs(6));
+
+        // a steady round trip time narrows the timeout down to it
+        for _ in 0..50 {
+            rtt.update(Duration::from_secs(2));
+        }
+        assert_eq!(rtt.smoothed, Duration::from_secs(2));
+        assert!(rtt.timeout() < Duration::from_millis(2100));
+
+        // while a slower one moves the estimate, and widens the timeout
+        rtt.update(Duration::from_secs(10));
+        assert_eq!(rtt.smoothed, Duration::from_secs(3));
+        assert!(rtt.timeout() > Duration::from_secs(10));
+        assert_eq!(rtt.samples, 52);
+    }
+
+    #[test]
+    fn test_connection_stats_debug_redacts_address() {
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let stats = ConnectionStats {
+            peer_id: PeerId::random(),
+            endpoint: Endpoint::Dialer,
+            remote_address: Some(address.clone()),
+            handshake_rtt: None,
+            setup_duration: None,
+            expired_messages: 0,
+            delivery: DeliveryStats::default(),
+            reorder: ReorderStats::default(),
+            reply_surbs: None,
+            rtt: None,
+            frame_size: None,
+        };
+        let debug = format!("{stats:?}");
+        assert!(!debug.contains(&address.to_string()));
+        assert!(debug.contains(&redact_always(&address).to_string()));
+    }
+
+    #[test]
+    fn test_rtt_sampler() {
+        let ack = |nonce, sacks| AckMessage {
+            id: ConnectionId::generate(),
+            nonce,
+            sacks,
+        };
+        let sampler = RttSampler::default();
+        assert_eq!(sampler.on_ack(&ack(1, vec![])), None);
+
+        // one message is timed at a time
+        sampler.on_send(1);
+        sampler.on_send(2);
+        assert!(sampler.on_ack(&ack(1, vec![])).is_some());
+        assert_eq!(sampler.on_ack(&ack(2, vec![])), None);
+
+        // selective acks cover the timed message too
+        sampler.on_send(3);
+        assert_eq!(sampler.on_ack(&ack(2, vec![(4, 5)])), None);
+        assert!(sampler.on_ack(&ack(2, vec![(3, 5)])).is_some());
+
+        // retransmitted messages aren't timed
+        sampler.on_send(6);
+        sampler.on_retransmit(6);
+        assert_eq!(sampler.on_ack(&ack(6, vec![])), None);
+    }
+}
@@ -1,84 +1,589 @@
+use super::bandwidth::PeerBandwidth;
+use super::budget::MemoryBudget;
+use super::compression::DataCodec;
+use super::config::QuotaAction;
+use super::congestion::CongestionWindow;
+use super::framing::FrameSizer;
 use super::message::{
-    ConnectionId, Message, OutboundMessage, SubstreamId, SubstreamMessage, TransportMessage,
+    ConnectionId, Message, OutboundMessage, SubstreamId, SubstreamMessage, SubstreamMessageType,
+    TransportMessage,
 };
+use super::mixnet::OutboundSender;
+use super::redact::redact_always;
+use super::session::Session;
+use super::{DEFAULT_MAX_FRAME_BYTES, DEFAULT_MAX_UNSENT_BYTES};
+use bytes::{Bytes, BytesMut};
 use futures::{
-    io::{Error as IoError, ErrorKind},
-    AsyncRead, AsyncWrite,
+    io::{Error as IoError, ErrorKind, IoSlice},
+    task::AtomicWaker,
+    AsyncBufRead, AsyncRead, AsyncWrite, Future, FutureExt,
 };
 use log::debug;
-use nym_sdk::mixnet::AnonymousSenderTag;
 use nym_sphinx::addressing::clients::Recipient;
+use nym_sphinx::anonymous_replies::requests::AnonymousSenderTag;
 use parking_lot::Mutex;
 use std::{
+    collections::VecDeque,
+    fmt::{Debug, Formatter},
     pin::Pin,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 use tokio::sync::{
-    mpsc::{UnboundedReceiver, UnboundedSender},
-    oneshot::Receiver,
+    mpsc::UnboundedReceiver,
+    oneshot::{error::TryRecvError, Receiver},
 };
+use tokio::time::{sleep, Sleep};
+
+/// the multistream-select negotiation of a substream's protocol is looked
+/// for in this many bytes written to it; see `TrafficClasses::bulk_protocols`.
+const MAX_NEGOTIATION_BYTES: usize = 1024;
+
+/// TrafficClass is how the data written to a substream is scheduled; see
+/// `NymTransportConfig::traffic_classes`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrafficClass {
+    /// latency-sensitive data, eg. requests and responses or chat messages,
+    /// which is handed to the mixnet client first.
+    #[default]
+    Interactive,
+    /// background transfers, eg. syncing a store, which are handed to the
+    /// mixnet client when no interactive data is waiting.
+    Bulk,
+}
+
+/// SubstreamState is how far a substream got; see `Connection::substreams`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubstreamState {
+    /// we asked the remote to open it, and haven't heard back yet. Data
+    /// can already be written to it.
+    Opening,
+    /// data can be written to and read from it.
+    Open,
+    /// the remote closed it; the data it sent before is still waiting to
+    /// be read.
+    HalfClosed,
+    /// we closed it, but haven't dropped it yet.
+    Closing,
+}
 
+/// WriteWindow bounds the number of bytes written to a substream which
+/// haven't been handed to the mixnet client yet.
 #[derive(Debug)]
-pub struct Substream {
+struct WriteWindow {
+    unsent: AtomicUsize,
+    max_unsent: Option<usize>,
+    /// the task waiting in poll_write or poll_flush for the window to drain.
+    waker: AtomicWaker,
+    /// the transport's budget, which unsent data counts against as well.
+    budget: Arc<MemoryBudget>,
+    /// set when the mixnet client refused a frame since the last flush.
+    send_failed: AtomicBool,
+    /// set when written data expired before it was sent, and was replaced
+    /// by a Close; the substream is then reset.
+    expired: AtomicBool,
+    /// the reader, woken once written data expired.
+    reader_waker: AtomicWaker,
+}
+
+impl WriteWindow {
+    fn new(max_unsent: Option<usize>, budget: Arc<MemoryBudget>) -> Self {
+        WriteWindow {
+            unsent: AtomicUsize::new(0),
+            max_unsent,
+            waker: AtomicWaker::new(),
+            budget,
+            send_failed: AtomicBool::new(false),
+            expired: AtomicBool::new(false),
+            reader_waker: AtomicWaker::new(),
+        }
+    }
+
+    /// polls until the number of unsent bytes satisfies `ready`.
+    fn poll_until(&self, cx: &mut Context<'_>, ready: impl Fn(usize) -> bool) -> Poll<()> {
+        if ready(self.unsent.load(Ordering::SeqCst)) {
+            return Poll::Ready(());
+        }
+        self.waker.register(cx.waker());
+        // credit may have been released before the waker was registered
+        if ready(self.unsent.load(Ordering::SeqCst)) {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+
+    /// polls until there's room for another frame. A frame is always let
+    /// through if nothing is unsent, so writes can't get stuck.
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(max_unsent) = self.max_unsent {
+            if self
+                .poll_until(cx, |unsent| unsent == 0 || unsent < max_unsent)
+                .is_pending()
+            {
+                return Poll::Pending;
+            }
+        }
+        if self.unsent.load(Ordering::SeqCst) == 0 {
+            return Poll::Ready(());
+        }
+        self.budget.poll_ready(cx)
+    }
+
+    /// polls until everything written was handed to the mixnet client, and
+    /// returns whether the client accepted all of it.
+    fn poll_drained(&self, cx: &mut Context<'_>) -> Poll<bool> {
+        self.poll_until(cx, |unsent| unsent == 0)
+            .map(|_| !self.send_failed.swap(false, Ordering::SeqCst))
+    }
+}
+
+/// WriteCredit is the space a frame takes up in its substream's write
+/// window; it's returned when the credit is dropped.
+#[derive(Debug)]
+pub(crate) struct WriteCredit {
+    window: Arc<WriteWindow>,
+    len: usize,
+}
+
+impl WriteCredit {
+    fn new(window: &Arc<WriteWindow>, len: usize) -> Self {
+        window.unsent.fetch_add(len, Ordering::SeqCst);
+        window.budget.reserve(len);
+        WriteCredit {
+            window: window.clone(),
+            len,
+        }
+    }
+
+    /// records that the mixnet client refused the frame, which fails the
+    /// substream's next flush.
+    pub(crate) fn send_failed(&self) {
+        self.window.send_failed.store(true, Ordering::SeqCst);
+    }
+
+    /// records that the frame expired before it was sent, and a Close went
+    /// out instead, which resets the substream.
+    pub(crate) fn expired(&self) {
+        self.window.expired.store(true, Ordering::SeqCst);
+        self.window.reader_waker.wake();
+        self.window.waker.wake();
+    }
+}
+
+impl Drop for WriteCredit {
+    fn drop(&mut self) {
+        self.window.unsent.fetch_sub(self.len, Ordering::SeqCst);
+        self.window.budget.release(self.len);
+        self.window.waker.wake();
+    }
+}
+
+/// Closed is how a Connection closed one of its substreams.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Closed {
+    /// the remote closed it; the data it sent before that is still read.
+    /// With half-close, it can still be written to as well.
+    Remote,
+    /// it was reset, eg. because its reader fell too far behind; the unread
+    /// data is dropped.
+    Reset,
+}
+
+/// FrameSender sends the messages of a substream to the mixnet client.
+#[derive(Clone)]
+struct FrameSender {
     remote_recipient: Option<Recipient>,
     connection_id: ConnectionId,
+    substream_id: SubstreamId,
+    /// outbound messages; go directly to the mixnet
+    outbound_tx: OutboundSender,
+    sender_tag: Option<AnonymousSenderTag>,
+    message_nonce: Arc<AtomicU64>,
+    /// if set, outbound TransportMessages are encrypted with the session keys
+    session: Option<Arc<Session>>,
+    /// if set, outbound TransportMessages use the compact ID encoding
+    compact_ids: bool,
+    /// if set, data is compressed with the negotiated algorithm
+    codec: Option<DataCodec>,
+}
+
+impl FrameSender {
+    fn send(
+        &self,
+        mut message: SubstreamMessage,
+        write_credit: Option<WriteCredit>,
+    ) -> Result<(), IoError> {
+        if let (Some(codec), SubstreamMessageType::Data(data)) =
+            (&self.codec, &mut message.message_type)
+        {
+            *data = codec.compress(data);
+        }
+        let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
+        self.outbound_tx
+            .send(OutboundMessage {
+                recipient: self.remote_recipient,
+                message: Message::TransportMessage(TransportMessage {
+                    nonce,
+                    id: self.connection_id.clone(),
+                    message,
+                }),
+                sender_tag: self.sender_tag,
+                queued_at: Instant::now(),
+                session: self.session.clone(),
+                write_credit,
+                compact_ids: self.compact_ids,
+                alias: None,
+            })
+            .map_err(IoError::other)
+    }
+
+    /// sends the data held back by a Coalescer, if any.
+    fn send_pending(
+        &self,
+        pending: &mut PendingFrame,
+        write_window: &Arc<WriteWindow>,
+    ) -> Result<(), IoError> {
+        pending.since = None;
+        if pending.data.is_empty() {
+            return Ok(());
+        }
+        let data = pending.data.split().freeze();
+        let write_credit = WriteCredit::new(write_window, data.len());
+        self.send(
+            SubstreamMessage::new_with_data(self.substream_id.clone(), data),
+            Some(write_credit),
+        )
+    }
+}
+
+/// PendingFrame is the data a Coalescer holds back.
+#[derive(Debug, Default)]
+struct PendingFrame {
+    data: BytesMut,
+    /// when the first byte of `data` was written.
+    since: Option<Instant>,
+    /// whether a task is waiting to send `data` once the interval has passed.
+    timer_armed: bool,
+}
+
+/// Coalescer holds small writes back for up to the flush interval, so that
+/// they're sent in fewer, fuller frames; see `NymTransportConfig::flush_interval`.
+/// Every data frame of its substream is sent with `pending` locked, so that
+/// the frames keep the order they were written in.
+#[derive(Debug)]
+struct Coalescer {
+    interval: Duration,
+    pending: Mutex<PendingFrame>,
+}
+
+impl Coalescer {
+    /// sends the pending data once it's been held back for the interval, in
+    /// the background, as the writer may not poll the substream again.
+    fn arm_timer(
+        self: &Arc<Self>,
+        pending: &mut PendingFrame,
+        frames: &FrameSender,
+        write_window: &Arc<WriteWindow>,
+    ) {
+        if pending.timer_armed {
+            return;
+        }
+        pending.timer_armed = true;
+        let (coalescer, frames, write_window) =
+            (self.clone(), frames.clone(), write_window.clone());
+        tokio::spawn(async move {
+            loop {
+                let deadline = {
+                    let mut pending = coalescer.pending.lock();
+                    match pending.since {
+                        Some(since) if since.elapsed() < coalescer.interval => {
+                            since + coalescer.interval
+                        }
+                        Some(_) => {
+                            if let Err(e) = frames.send_pending(&mut pending, &write_window) {
+                                // the next flush reports it
+                                debug!("failed to send coalesced data: {}", e);
+                                write_window.send_failed.store(true, Ordering::SeqCst);
+                            }
+                            pending.timer_armed = false;
+                            return;
+                        }
+                        // sent early, because it filled a frame or was flushed
+                        None => {
+                            pending.timer_armed = false;
+                            return;
+                        }
+                    }
+                };
+                tokio::time::sleep_until(deadline.into()).await;
+            }
+        });
+    }
+}
+
+pub struct Substream {
     pub(crate) substream_id: SubstreamId,
 
     /// inbound messages; inbound_tx is in the corresponding Connection
-    pub(crate) inbound_rx: UnboundedReceiver<Vec<u8>>,
+    pub(crate) inbound_rx: UnboundedReceiver<Bytes>,
 
-    /// outbound messages; go directly to the mixnet
-    outbound_tx: UnboundedSender<OutboundMessage>,
-
-    sender_tag: Option<AnonymousSenderTag>,
+    /// sends outbound messages to the mixnet
+    frames: FrameSender,
 
-    /// used to signal when the substream is closed
-    close_rx: Receiver<()>,
+    /// used to signal when the substream is closed; None once the Connection
+    /// was dropped without signalling it.
+    close_rx: Option<Receiver<Closed>>,
     closed: Mutex<bool>,
+    /// whether the substream was reset, rather than closed.
+    reset: bool,
+    /// whether both sides support half-close: closing the substream only
+    /// ends our writes, and the remote's Close only ends our reads.
+    half_close: bool,
+    /// set once the remote closed its side of a half-closed substream; reads
+    /// return EOF once its data has been read.
+    remote_closed: bool,
 
     // buffer of data that's been written to the stream,
     // but not yet read by the application.
-    unread_data: Mutex<Vec<u8>>,
+    unread_data: Mutex<VecDeque<Bytes>>,
+
+    /// number of received bytes not yet read by the application, including
+    /// those still in inbound_rx; shared with the Connection, which enforces
+    /// the buffer limit.
+    pub(crate) buffered: Arc<AtomicUsize>,
+
+    /// set once we closed the substream; shared with the Connection, which
+    /// reports it as closing.
+    pub(crate) closed_locally: Arc<AtomicBool>,
+
+    /// writes are split into data messages of at most this many bytes.
+    max_frame_size: usize,
+    /// if set, picks a smaller size for the data messages by the
+    /// connection's loss.
+    frame_sizer: Option<Arc<FrameSizer>>,
+
+    /// bounds the written data which hasn't been handed to the mixnet yet.
+    write_window: Arc<WriteWindow>,
+
+    /// the transport's budget, which `buffered` and unsent data count against.
+    budget: Arc<MemoryBudget>,
+
+    /// the connection's congestion window, if congestion control was negotiated.
+    congestion: Option<Arc<CongestionWindow>>,
+
+    /// the remote peer's entry in the transport's bandwidth ledger, which
+    /// written data is counted against.
+    bandwidth: Option<Arc<PeerBandwidth>>,
+    /// fires once the peer is within its bandwidth quota again.
+    throttle: Option<Pin<Box<Sleep>>>,
+
+    /// protocols which make the substream bulk if it negotiates them.
+    bulk_protocols: Arc<[String]>,
+    /// bytes written so far which were searched for a bulk protocol; set to
+    /// the maximum once the class is decided.
+    negotiation_bytes: usize,
+
+    /// holds small writes back to coalesce them, if a flush interval is set.
+    coalescer: Option<Arc<Coalescer>>,
+}
 
-    message_nonce: Arc<AtomicU64>,
+impl Debug for Substream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Substream")
+            .field(
+                "remote_recipient",
+                &self.frames.remote_recipient.map(redact_always),
+            )
+            .field("connection_id", &self.frames.connection_id)
+            .field("substream_id", &self.substream_id)
+            .field("sender_tag", &self.frames.sender_tag.map(redact_always))
+            .field("closed", &*self.closed.lock())
+            .finish_non_exhaustive()
+    }
 }
 
 impl Substream {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new_with_sender_tag(
         remote_recipient: Option<Recipient>,
         connection_id: ConnectionId,
         substream_id: SubstreamId,
-        inbound_rx: UnboundedReceiver<Vec<u8>>,
-        outbound_tx: UnboundedSender<OutboundMessage>,
-        close_rx: Receiver<()>,
+        inbound_rx: UnboundedReceiver<Bytes>,
+        outbound_tx: OutboundSender,
+        close_rx: Receiver<Closed>,
         message_nonce: Arc<AtomicU64>,
         sender_tag: Option<AnonymousSenderTag>,
     ) -> Self {
         Substream {
-            remote_recipient,
-            connection_id,
+            frames: FrameSender {
+                remote_recipient,
+                connection_id,
+                substream_id: substream_id.clone(),
+                outbound_tx,
+                sender_tag,
+                message_nonce,
+                session: None,
+                compact_ids: false,
+                codec: None,
+            },
             substream_id,
             inbound_rx,
-            outbound_tx,
-            sender_tag,
-            close_rx,
+            close_rx: Some(close_rx),
             closed: Mutex::new(false),
-            unread_data: Mutex::new(vec![]),
-            message_nonce,
+            reset: false,
+            half_close: false,
+            remote_closed: false,
+            unread_data: Mutex::new(VecDeque::new()),
+            buffered: Arc::new(AtomicUsize::new(0)),
+            closed_locally: Arc::new(AtomicBool::new(false)),
+            max_frame_size: DEFAULT_MAX_FRAME_BYTES,
+            frame_sizer: None,
+            write_window: Arc::new(WriteWindow::new(
+                Some(DEFAULT_MAX_UNSENT_BYTES),
+                Arc::default(),
+            )),
+            budget: Arc::default(),
+            congestion: None,
+            bandwidth: None,
+            throttle: None,
+            bulk_protocols: Arc::new([]),
+            negotiation_bytes: 0,
+            coalescer: None,
+        }
+    }
+
+    pub(crate) fn with_session(mut self, session: Option<Arc<Session>>) -> Self {
+        self.frames.session = session;
+        self
+    }
+
+    pub(crate) fn with_compact_ids(mut self, enabled: bool) -> Self {
+        self.frames.compact_ids = enabled;
+        self
+    }
+
+    pub(crate) fn with_half_close(mut self, enabled: bool) -> Self {
+        self.half_close = enabled;
+        self
+    }
+
+    pub(crate) fn with_compression(mut self, codec: Option<DataCodec>) -> Self {
+        self.frames.codec = codec;
+        self
+    }
+
+    /// sets the maximum size of a data message, and the maximum number of
+    /// written bytes which may wait to be handed to the mixnet client.
+    pub(crate) fn with_write_limits(
+        mut self,
+        max_frame_size: usize,
+        max_unsent_bytes: Option<usize>,
+    ) -> Self {
+        self.max_frame_size = max_frame_size;
+        self.write_window = Arc::new(WriteWindow::new(max_unsent_bytes, self.budget.clone()));
+        self
+    }
+
+    pub(crate) fn with_frame_sizer(mut self, frame_sizer: Option<Arc<FrameSizer>>) -> Self {
+        self.frame_sizer = frame_sizer;
+        self
+    }
+
+    /// returns the most bytes to send in the next data message; at least one,
+    /// so that writes make progress even if `max_frame_size` was set to zero.
+    fn frame_size(&self) -> usize {
+        let max_frame_size = self.max_frame_size.max(1);
+        match &self.frame_sizer {
+            Some(frame_sizer) => frame_sizer.frame_size().min(max_frame_size),
+            None => max_frame_size,
         }
     }
 
+    pub(crate) fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.write_window = Arc::new(WriteWindow::new(
+            self.write_window.max_unsent,
+            budget.clone(),
+        ));
+        self.budget = budget;
+        self
+    }
+
+    pub(crate) fn with_congestion_window(mut self, window: Option<Arc<CongestionWindow>>) -> Self {
+        self.congestion = window;
+        self
+    }
+
+    pub(crate) fn with_bandwidth(mut self, bandwidth: Option<Arc<PeerBandwidth>>) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    /// holds writes smaller than a frame back for up to the given interval.
+    pub(crate) fn with_flush_interval(mut self, interval: Option<Duration>) -> Self {
+        self.coalescer = interval.map(|interval| {
+            Arc::new(Coalescer {
+                interval,
+                pending: Mutex::default(),
+            })
+        });
+        self
+    }
+
+    pub(crate) fn with_bulk_protocols(mut self, protocols: Arc<[String]>) -> Self {
+        self.bulk_protocols = protocols;
+        self
+    }
+
+    /// sets how the data written from now on is scheduled, overriding the
+    /// class derived from the negotiated protocol. The remote delivers a
+    /// connection's data in order, so interactive data can't overtake bulk
+    /// data of the same connection which was written before it, only wait
+    /// less behind it.
+    pub fn set_traffic_class(&mut self, class: TrafficClass) {
+        self.frames.outbound_tx = self.frames.outbound_tx.clone().with_traffic_class(class);
+        self.negotiation_bytes = usize::MAX;
+    }
+
+    pub fn traffic_class(&self) -> TrafficClass {
+        self.frames.outbound_tx.traffic_class()
+    }
+
+    /// makes the substream bulk if the data, which is written while its
+    /// protocol is negotiated, names one of the bulk protocols.
+    fn classify(&mut self, data: &[u8]) {
+        if self.negotiation_bytes >= MAX_NEGOTIATION_BYTES {
+            return;
+        }
+        self.negotiation_bytes += data.len();
+        let bulk = self.bulk_protocols.iter().any(|protocol| {
+            !protocol.is_empty()
+                && data
+                    .windows(protocol.len())
+                    .any(|window| window == protocol.as_bytes())
+        });
+        if bulk {
+            self.set_traffic_class(TrafficClass::Bulk);
+        }
+    }
+
+    /// returns the number of bytes written to the substream which haven't
+    /// been handed to the mixnet client yet.
+    pub fn unsent_bytes(&self) -> usize {
+        self.write_window.unsent.load(Ordering::SeqCst)
+    }
+
+    #[cfg(test)]
     pub(crate) fn new(
         remote_recipient: Option<Recipient>,
         connection_id: ConnectionId,
         substream_id: SubstreamId,
-        inbound_rx: UnboundedReceiver<Vec<u8>>,
-        outbound_tx: UnboundedSender<OutboundMessage>,
-        close_rx: Receiver<()>,
+        inbound_rx: UnboundedReceiver<Bytes>,
+        outbound_tx: OutboundSender,
+        close_rx: Receiver<Closed>,
         message_nonce: Arc<AtomicU64>,
     ) -> Self {
         Self::new_with_sender_tag(
@@ -93,24 +598,143 @@ impl Substream {
         )
     }
 
-    fn check_closed(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Result<(), IoError> {
-        let closed_err = IoError::new(ErrorKind::Other, "stream closed");
-
-        // close_rx will return an error if the channel is closed (ie. sender was dropped),
-        // or if it's empty
-        let received_closed = self.close_rx.try_recv();
+    /// check_closed returns an error if the substream has been closed by either
+    /// side. If `cx` is given, its task is woken once the remote closes the
+    /// substream; only the reader waits on it, so writers pass None to avoid
+    /// replacing the reader's waker.
+    fn check_closed(&mut self, cx: Option<&mut Context<'_>>) -> Result<(), IoError> {
+        let closed_err = IoError::other("stream closed");
 
-        let mut closed = self.closed.lock();
+        let closed = self.closed.get_mut();
         if *closed {
             return Err(closed_err);
         }
 
-        if received_closed.is_ok() {
+        // the remote was sent a Close in place of our expired data
+        if let Some(cx) = &cx {
+            self.write_window.reader_waker.register(cx.waker());
+        }
+        if self.write_window.expired.load(Ordering::SeqCst) {
             *closed = true;
-            return Err(closed_err);
+            self.reset = true;
+            return Err(IoError::new(
+                ErrorKind::TimedOut,
+                "written data expired before it was sent",
+            ));
+        }
+
+        let Some(close_rx) = &mut self.close_rx else {
+            return Ok(());
+        };
+
+        // close_rx returns an error if the sender was dropped without
+        // signalling, in which case it must not be polled again.
+        let received_closed = match cx {
+            Some(cx) => match close_rx.poll_unpin(cx) {
+                Poll::Ready(res) => Some(res.ok()),
+                Poll::Pending => None,
+            },
+            None => match close_rx.try_recv() {
+                Ok(how) => Some(Some(how)),
+                Err(TryRecvError::Closed) => Some(None),
+                Err(TryRecvError::Empty) => None,
+            },
+        };
+
+        match received_closed {
+            // the remote only closed its side; what it sent is still read
+            Some(Some(Closed::Remote)) if self.half_close => {
+                self.remote_closed = true;
+                self.close_rx = None;
+                Ok(())
+            }
+            Some(Some(how)) => {
+                *closed = true;
+                self.reset = how == Closed::Reset;
+                Err(closed_err)
+            }
+            Some(None) => {
+                self.close_rx = None;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// polls until the remote peer is within its bandwidth quota, or fails
+    /// if it exceeded a quota which closes its connections.
+    fn poll_quota(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        let Some(bandwidth) = &self.bandwidth else {
+            return Poll::Ready(Ok(()));
+        };
+        match bandwidth.action() {
+            Some(QuotaAction::Close) if bandwidth.exceeded() => {
+                Poll::Ready(Err(IoError::other("bandwidth quota exceeded")))
+            }
+            Some(QuotaAction::Throttle) => {
+                let Some(delay) = bandwidth.throttle_delay() else {
+                    self.throttle = None;
+                    return Poll::Ready(Ok(()));
+                };
+                let throttle = self.throttle.insert(Box::pin(sleep(delay)));
+                throttle.as_mut().poll(cx).map(Ok)
+            }
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl Substream {
+    /// moves the data received so far from the channel to `unread_data`.
+    /// Ready once there's unread data, or once the substream was closed and
+    /// all its data has been read: with no unread data if the remote closed
+    /// its side of a half-closed substream, or with an error otherwise.
+    fn poll_unread(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        // the data the remote sent before closing the substream is still
+        // returned; it's all in the channel by the time the close is seen.
+        let closed = self.check_closed(Some(cx));
+        if let Err(e) = &closed {
+            if self.reset {
+                return Poll::Ready(Err(IoError::new(e.kind(), "stream reset")));
+            }
+        }
+
+        // drain the channel until it's pending, so that our waker is registered
+        // even if the received data doesn't fill the buffer. The Connection's
+        // buffer limit bounds how much this can be.
+        let unread_data = self.unread_data.get_mut();
+        let mut disconnected = false;
+        loop {
+            match self.inbound_rx.poll_recv(cx) {
+                Poll::Ready(Some(data)) => unread_data.push_back(data),
+                Poll::Ready(None) => {
+                    disconnected = true;
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        if !unread_data.is_empty() || self.remote_closed {
+            return Poll::Ready(Ok(()));
+        }
+        if let Err(e) = closed {
+            return Poll::Ready(Err(e));
         }
 
-        Ok(())
+        if disconnected {
+            // the Connection drops its end right before it signals the
+            // remote's Close
+            if self.check_closed(None).is_ok() && self.remote_closed {
+                return Poll::Ready(Ok(()));
+            }
+            // the Connection dropped its end without a Close, eg. because
+            // it was dropped itself; nothing will ever arrive.
+            *self.closed.get_mut() = true;
+            return Poll::Ready(Err(IoError::other("stream closed")));
+        }
+
+        Poll::Pending
     }
 }
 
@@ -120,59 +744,174 @@ impl AsyncRead for Substream {
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<Result<usize, IoError>> {
-        let closed_result = self.as_mut().check_closed(cx);
-        if let Err(e) = closed_result {
-            return Poll::Ready(Err(e));
+        let this = &mut *self;
+        match this.poll_unread(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
         }
 
-        let inbound_rx_data = self.inbound_rx.poll_recv(cx);
+        // copy as much unread data to the buf as fits, saving the rest for later
+        let unread_data = this.unread_data.get_mut();
+        let mut filled_len = 0;
+        while filled_len < buf.len() {
+            let Some(mut chunk) = unread_data.pop_front() else {
+                break;
+            };
+            let copy_len = std::cmp::min(chunk.len(), buf.len() - filled_len);
+            buf[filled_len..filled_len + copy_len].copy_from_slice(&chunk.split_to(copy_len));
+            filled_len += copy_len;
+            if !chunk.is_empty() {
+                unread_data.push_front(chunk);
+            }
+        }
 
-        // first, write any previously unread data to the buf
-        let mut unread_data = self.unread_data.lock();
-        let filled_len = if unread_data.len() > 0 {
-            let unread_len = unread_data.len();
-            let buf_len = buf.len();
-            let copy_len = std::cmp::min(unread_len, buf_len);
-            buf[..copy_len].copy_from_slice(&unread_data[..copy_len]);
-            *unread_data = unread_data[copy_len..].to_vec();
-            copy_len
-        } else {
-            0
-        };
+        debug!("poll_read copied {} bytes", filled_len);
+        this.budget.release_from(&this.buffered, filled_len);
+        Poll::Ready(Ok(filled_len))
+    }
+}
 
-        if let Poll::Ready(Some(data)) = inbound_rx_data {
-            if filled_len == buf.len() {
-                // we've filled the buffer, so we'll have to save the rest for later
-                let mut new = vec![];
-                new.extend(unread_data.drain(..));
-                new.extend(data.iter());
-                *unread_data = new;
-                return Poll::Ready(Ok(filled_len));
+/// the substream's received frames are its buffer: `poll_fill_buf` returns
+/// the unread part of the oldest one, without copying it. Like `poll_read`,
+/// it fails once the substream is closed and all its data has been read.
+impl AsyncBufRead for Substream {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<&[u8], IoError>> {
+        let this = self.get_mut();
+        match this.poll_unread(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        let chunk = this
+            .unread_data
+            .get_mut()
+            .front()
+            .map_or(&[][..], |chunk| chunk);
+        Poll::Ready(Ok(chunk))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        let unread_data = this.unread_data.get_mut();
+        let mut consumed = 0;
+        while consumed < amt {
+            let Some(chunk) = unread_data.front_mut() else {
+                break;
+            };
+            let len = chunk.len().min(amt - consumed);
+            let _ = chunk.split_to(len);
+            consumed += len;
+            if chunk.is_empty() {
+                unread_data.pop_front();
             }
+        }
+        this.budget.release_from(&this.buffered, consumed);
+    }
+}
 
-            // otherwise, there's still room in the buffer, so we'll copy the rest of the data
-            let remaining_len = buf.len() - filled_len;
-            let data_len = data.len();
+impl Drop for Substream {
+    fn drop(&mut self) {
+        // the unread data is dropped along with the substream
+        self.budget.release_from(&self.buffered, usize::MAX);
+    }
+}
 
-            // we have more data than buffer room remaining, save the extra for later
-            if remaining_len < data_len {
-                unread_data.extend_from_slice(&data[remaining_len..]);
-            }
+impl Substream {
+    /// writes as much of `bufs` as fits in one frame, so that a vectored
+    /// write becomes a single mixnet message rather than one per slice.
+    fn poll_write_slices(
+        &mut self,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, IoError>> {
+        if let Err(e) = self.check_closed(None) {
+            return Poll::Ready(Err(e));
+        }
+        // only set on its own once we closed a half-closed substream
+        if self.closed_locally.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(IoError::other("stream closed")));
+        }
 
-            let copied = std::cmp::min(remaining_len, data_len);
-            buf[filled_len..filled_len + copied].copy_from_slice(&data[..copied]);
-            // debug!("poll_read copied {} bytes: data {:?}", copied, buf);
-            debug!("poll_read copied {} bytes", copied);
-            return Poll::Ready(Ok(copied));
+        let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if total == 0 {
+            return Poll::Ready(Ok(0));
         }
 
-        if filled_len > 0 {
-            // debug!("poll_read copied {} bytes: data {:?}", filled_len, buf);
-            debug!("poll_read copied {} bytes", filled_len);
-            return Poll::Ready(Ok(filled_len));
+        // wait for the remote peer's bandwidth quota to allow more
+        match self.poll_quota(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        // and for the mixnet to catch up with what we've written so far
+        if self.write_window.poll_ready(cx).is_pending() {
+            return Poll::Pending;
+        }
+        // and for the remote to acknowledge enough of it
+        if let Some(window) = &self.congestion {
+            if window.poll_ready(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        // and for the mixnet client to work through the connections' backlog
+        if self.frames.outbound_tx.poll_ready(cx).is_pending() {
+            return Poll::Pending;
         }
 
-        Poll::Pending
+        // the slices are copied into the pending frame if small writes are
+        // held back, or into a frame of their own
+        let coalescer = self.coalescer.clone();
+        let mut pending = coalescer.as_ref().map(|coalescer| coalescer.pending.lock());
+        let frame_size = self.frame_size();
+        if let Some(pending) = &mut pending {
+            // the frame size shrank since the pending data was written
+            if pending.data.len() >= frame_size {
+                self.frames.send_pending(pending, &self.write_window)?;
+            }
+        }
+        let mut frame = BytesMut::new();
+        let data = match &mut pending {
+            Some(pending) => &mut pending.data,
+            None => {
+                frame.reserve(total.min(frame_size));
+                &mut frame
+            }
+        };
+        // only take one frame's worth; the caller writes the rest later
+        let start = data.len();
+        for buf in bufs {
+            let len = buf.len().min(frame_size - data.len());
+            data.extend_from_slice(&buf[..len]);
+            if data.len() == frame_size {
+                break;
+            }
+        }
+        let written = data.len() - start;
+        self.classify(&data[start..]);
+
+        match (&coalescer, &mut pending) {
+            // the pending frame is sent once it's full
+            (Some(coalescer), Some(pending)) => {
+                pending.since.get_or_insert_with(Instant::now);
+                if pending.data.len() >= frame_size {
+                    self.frames.send_pending(pending, &self.write_window)?;
+                } else {
+                    coalescer.arm_timer(pending, &self.frames, &self.write_window);
+                }
+            }
+            _ => {
+                self.frames.send(
+                    SubstreamMessage::new_with_data(self.substream_id.clone(), frame.freeze()),
+                    Some(WriteCredit::new(&self.write_window, written)),
+                )?;
+            }
+        }
+        if let Some(bandwidth) = &self.bandwidth {
+            bandwidth.record_sent(written);
+        }
+
+        Poll::Ready(Ok(written))
     }
 }
 
@@ -182,91 +921,103 @@ impl AsyncWrite for Substream {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, IoError>> {
-        if let Err(e) = self.as_mut().check_closed(cx) {
-            return Poll::Ready(Err(e));
-        }
-
-        let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
-
-        self.outbound_tx
-            .send(OutboundMessage {
-                recipient: self.remote_recipient,
-                message: Message::TransportMessage(TransportMessage {
-                    nonce,
-                    id: self.connection_id.clone(),
-                    message: SubstreamMessage::new_with_data(
-                        self.substream_id.clone(),
-                        buf.to_vec(),
-                    ),
-                }),
-                sender_tag: self.sender_tag.clone(),
-            })
-            .map_err(|e| {
-                IoError::new(
-                    ErrorKind::Other,
-                    format!("poll_write outbound_tx error: {}", e),
-                )
-            })?;
+        self.poll_write_slices(cx, &[IoSlice::new(buf)])
+    }
 
-        Poll::Ready(Ok(buf.len()))
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, IoError>> {
+        self.poll_write_slices(cx, bufs)
     }
 
     fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
-        let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
+        let mut pending = self
+            .coalescer
+            .as_ref()
+            .map(|coalescer| coalescer.pending.lock());
 
         let mut closed = self.closed.lock();
         if *closed {
-            return Poll::Ready(Err(IoError::new(ErrorKind::Other, "stream closed")));
+            return Poll::Ready(Err(IoError::other("stream closed")));
+        }
+        if self.closed_locally.load(Ordering::SeqCst) {
+            return Poll::Ready(Ok(()));
         }
 
-        *closed = true;
+        // with half-close, the remote's data is still read after closing
+        *closed = !self.half_close;
+        self.closed_locally.store(true, Ordering::SeqCst);
 
+        // the data held back goes before the close
+        if let Some(pending) = &mut pending {
+            self.frames.send_pending(pending, &self.write_window)?;
+        }
         // send a close message to the mixnet
-        self.outbound_tx
-            .send(OutboundMessage {
-                recipient: self.remote_recipient,
-                message: Message::TransportMessage(TransportMessage {
-                    nonce,
-                    id: self.connection_id.clone(),
-                    message: SubstreamMessage::new_close(self.substream_id.clone()),
-                }),
-                sender_tag: self.sender_tag.clone(),
-            })
-            .map_err(|e| {
-                IoError::new(
-                    ErrorKind::Other,
-                    format!("poll_close outbound_rx error: {}", e),
-                )
-            })?;
+        self.frames
+            .send(SubstreamMessage::new_close(self.substream_id.clone()), None)?;
 
         Poll::Ready(Ok(()))
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
-        if let Err(e) = self.check_closed(cx) {
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        if let Err(e) = self.check_closed(None) {
             return Poll::Ready(Err(e));
         }
 
-        Poll::Ready(Ok(()))
+        // the data held back is sent right away
+        if let Some(coalescer) = &self.coalescer {
+            let mut pending = coalescer.pending.lock();
+            self.frames.send_pending(&mut pending, &self.write_window)?;
+        }
+        // flushed once everything written has been handed to the mixnet client;
+        // data dropped along with a stopped mixnet task was never sent
+        self.write_window.poll_drained(cx).map(|sent| {
+            if !sent {
+                Err(IoError::other(
+                    "the mixnet client failed to send written data",
+                ))
+            } else if self.frames.outbound_tx.is_closed() {
+                Err(IoError::new(
+                    ErrorKind::BrokenPipe,
+                    "the mixnet task stopped",
+                ))
+            } else {
+                Ok(())
+            }
+        })
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::super::message::{
-        ConnectionId, Message, SubstreamId, SubstreamMessage, TransportMessage,
+        ConnectionId, Message, OutboundMessage, SubstreamId, SubstreamMessage,
+        SubstreamMessageType, TransportMessage,
     };
+    #[cfg(feature = "nym-client")]
     use super::super::mixnet::initialize_mixnet;
-    use super::Substream;
-    use futures::{AsyncReadExt, AsyncWriteExt};
+    use super::super::mixnet::OutboundSender;
+    use super::{Closed, Substream, TrafficClass};
+    use crate::config::{NymTransportConfig, TrafficClasses};
+    use crate::test_utils::ConnectedPair;
+    use futures::{
+        io::IoSlice, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, FutureExt,
+    };
+    #[cfg(feature = "nym-client")]
     use nym_sdk::mixnet::MixnetClient;
     use nym_sphinx::addressing::clients::Recipient;
+    use std::io::ErrorKind;
+    use std::pin::Pin;
     use std::sync::atomic::AtomicU64;
     use std::sync::Arc;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_substream_poll_read_unread_data() {
         let (outbound_tx, _) = tokio::sync::mpsc::unbounded_channel();
+        let outbound_tx = OutboundSender::new(outbound_tx, Default::default());
         let connection_id = ConnectionId::generate();
         let substream_id = SubstreamId::generate();
 
@@ -285,7 +1036,7 @@ mod test {
 
         // test writing and reading w/ same length data
         let data = b"hello".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx.send(data.clone().into()).unwrap();
         let mut buf = [0u8; 5];
         let read_len = substream.read(&mut buf).await.unwrap();
         assert_eq!(read_len, data.len());
@@ -293,7 +1044,7 @@ mod test {
 
         // test writing data longer than read buffer
         let data = b"nootwashere".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx.send(data.clone().into()).unwrap();
 
         let mut buf = [0u8; 4];
         let read_len = substream.read(&mut buf).await.unwrap();
@@ -307,7 +1058,7 @@ mod test {
 
         // test read buffer larger than written data
         let data = b"nootwashere".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx.send(data.clone().into()).unwrap();
         let mut buf = [0u8; 16];
         let read_len = substream.read(&mut buf).await.unwrap();
         assert_eq!(read_len, data.len());
@@ -316,7 +1067,7 @@ mod test {
 
         // test writing data longer than read buffer multiple times
         let data = b"nootwashere".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx.send(data.clone().into()).unwrap();
 
         let mut buf = [0u8; 4];
         let read_len = substream.read(&mut buf).await.unwrap();
@@ -324,7 +1075,7 @@ mod test {
         assert_eq!(buf.to_vec(), b"noot".to_vec());
 
         let data = b"asdf".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx.send(data.clone().into()).unwrap();
 
         let mut buf = [0u8; 4];
         let read_len = substream.read(&mut buf).await.unwrap();
@@ -337,11 +1088,307 @@ mod test {
         assert_eq!(buf[..7], b"ereasdf".to_vec());
     }
 
+    #[tokio::test]
+    async fn test_substream_write_frames_and_window() {
+        let (outbound_tx, mut outbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_inbound_tx, inbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_close_tx, close_rx) = tokio::sync::oneshot::channel();
+        let mut substream = Substream::new(
+            None,
+            ConnectionId::generate(),
+            SubstreamId::generate(),
+            inbound_rx,
+            OutboundSender::new(outbound_tx, Default::default()),
+            close_rx,
+            Arc::new(AtomicU64::new(1)),
+        )
+        .with_write_limits(4, Some(8));
+        let frame_len = |msg: OutboundMessage| match msg.message {
+            Message::TransportMessage(TransportMessage {
+                message:
+                    SubstreamMessage {
+                        message_type: SubstreamMessageType::Data(data),
+                        ..
+                    },
+                ..
+            }) => data.len(),
+            _ => panic!("expected a data message"),
+        };
+
+        // a large write is accepted one frame at a time, until the window is full
+        assert_eq!(substream.write(&[1; 10]).await.unwrap(), 4);
+        assert_eq!(substream.write(&[1; 6]).await.unwrap(), 4);
+        assert_eq!(substream.unsent_bytes(), 8);
+        assert!(substream.write(&[1; 2]).now_or_never().is_none());
+        assert!(substream.flush().now_or_never().is_none());
+
+        // handing a frame to the mixnet makes room for the next one
+        assert_eq!(frame_len(outbound_rx.try_recv().unwrap()), 4);
+        assert_eq!(substream.unsent_bytes(), 4);
+        assert_eq!(substream.write(&[1; 2]).await.unwrap(), 2);
+
+        // flushing completes once everything has been handed over
+        let mut flush = substream.flush();
+        assert!((&mut flush).now_or_never().is_none());
+        assert_eq!(frame_len(outbound_rx.try_recv().unwrap()), 4);
+        assert_eq!(frame_len(outbound_rx.try_recv().unwrap()), 2);
+        flush.await.unwrap();
+        assert_eq!(substream.unsent_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_substream_expired_write_resets() {
+        let (outbound_tx, mut outbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_inbound_tx, inbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_close_tx, close_rx) = tokio::sync::oneshot::channel();
+        let mut substream = Substream::new(
+            None,
+            ConnectionId::generate(),
+            SubstreamId::generate(),
+            inbound_rx,
+            OutboundSender::new(outbound_tx, Default::default()),
+            close_rx,
+            Arc::new(AtomicU64::new(1)),
+        );
+        substream.write_all(b"hello").await.unwrap();
+
+        // a pending read is woken once the mixnet task expires the data
+        let mut buf = [0u8; 5];
+        let mut read = substream.read(&mut buf);
+        assert!((&mut read).now_or_never().is_none());
+        let msg = outbound_rx.try_recv().unwrap();
+        msg.write_credit.as_ref().unwrap().expired();
+        drop(msg);
+        let e = read.await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+
+        // and further writes fail
+        substream.write_all(b"world").await.unwrap_err();
+        substream.flush().await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_substream_flush_fails_without_mixnet_task() {
+        let (outbound_tx, outbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_inbound_tx, inbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_close_tx, close_rx) = tokio::sync::oneshot::channel();
+        let mut substream = Substream::new(
+            None,
+            ConnectionId::generate(),
+            SubstreamId::generate(),
+            inbound_rx,
+            OutboundSender::new(outbound_tx, Default::default()),
+            close_rx,
+            Arc::new(AtomicU64::new(1)),
+        );
+        substream.write_all(b"hello").await.unwrap();
+
+        // the queued frame is dropped along with the mixnet task
+        let mut flush = substream.flush();
+        assert!((&mut flush).now_or_never().is_none());
+        drop(outbound_rx);
+        let e = flush.await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn test_substream_write_vectored() {
+        let (outbound_tx, mut outbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_inbound_tx, inbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_close_tx, close_rx) = tokio::sync::oneshot::channel();
+        let mut substream = Substream::new(
+            None,
+            ConnectionId::generate(),
+            SubstreamId::generate(),
+            inbound_rx,
+            OutboundSender::new(outbound_tx, Default::default()),
+            close_rx,
+            Arc::new(AtomicU64::new(1)),
+        )
+        .with_write_limits(8, None);
+        let frame = |msg: OutboundMessage| match msg.message {
+            Message::TransportMessage(TransportMessage {
+                message:
+                    SubstreamMessage {
+                        message_type: SubstreamMessageType::Data(data),
+                        ..
+                    },
+                ..
+            }) => data,
+            _ => panic!("expected a data message"),
+        };
+
+        // the slices are sent in one frame, up to its size
+        let bufs = [
+            IoSlice::new(b"he"),
+            IoSlice::new(b""),
+            IoSlice::new(b"llo"),
+            IoSlice::new(b" world"),
+        ];
+        assert_eq!(substream.write_vectored(&bufs).await.unwrap(), 8);
+        assert_eq!(&frame(outbound_rx.try_recv().unwrap())[..], b"hello wo");
+        assert!(outbound_rx.try_recv().is_err());
+
+        let bufs = [IoSlice::new(b"rl"), IoSlice::new(b"d")];
+        assert_eq!(substream.write_vectored(&bufs).await.unwrap(), 3);
+        assert_eq!(&frame(outbound_rx.try_recv().unwrap())[..], b"rld");
+        assert_eq!(substream.write_vectored(&[]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_substream_pending_read_is_woken() {
+        let new_substream = || {
+            let (outbound_tx, _) = tokio::sync::mpsc::unbounded_channel();
+            let (inbound_tx, inbound_rx) = tokio::sync::mpsc::unbounded_channel();
+            let (close_tx, close_rx) = tokio::sync::oneshot::channel();
+            let substream = Substream::new(
+                None,
+                ConnectionId::generate(),
+                SubstreamId::generate(),
+                inbound_rx,
+                OutboundSender::new(outbound_tx, Default::default()),
+                close_rx,
+                Arc::new(AtomicU64::new(1)),
+            );
+            (substream, inbound_tx, close_tx)
+        };
+        let read = |mut substream: Substream| {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 16];
+                substream.read(&mut buf).await.map(|n| buf[..n].to_vec())
+            })
+        };
+        let timeout = Duration::from_secs(1);
+
+        // by inbound data
+        let (substream, inbound_tx, _close_tx) = new_substream();
+        let reader = read(substream);
+        tokio::task::yield_now().await;
+        inbound_tx.send(b"hello".to_vec().into()).unwrap();
+        let data = tokio::time::timeout(timeout, reader)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(data.unwrap(), b"hello".to_vec());
+
+        // by the substream being closed
+        let (substream, _inbound_tx, close_tx) = new_substream();
+        let reader = read(substream);
+        tokio::task::yield_now().await;
+        close_tx.send(Closed::Remote).unwrap();
+        let res = tokio::time::timeout(timeout, reader)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(res.is_err());
+
+        // by the connection going away
+        let (substream, inbound_tx, close_tx) = new_substream();
+        let reader = read(substream);
+        tokio::task::yield_now().await;
+        drop(inbound_tx);
+        drop(close_tx);
+        let res = tokio::time::timeout(timeout, reader)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_substream_half_close() {
+        let new_substream = |half_close: bool| {
+            let (outbound_tx, outbound_rx) = tokio::sync::mpsc::unbounded_channel();
+            let (inbound_tx, inbound_rx) = tokio::sync::mpsc::unbounded_channel();
+            let (close_tx, close_rx) = tokio::sync::oneshot::channel();
+            let substream = Substream::new(
+                None,
+                ConnectionId::generate(),
+                SubstreamId::generate(),
+                inbound_rx,
+                OutboundSender::new(outbound_tx, Default::default()),
+                close_rx,
+                Arc::new(AtomicU64::new(1)),
+            )
+            .with_half_close(half_close);
+            (substream, inbound_tx, close_tx, outbound_rx)
+        };
+
+        // closing a half-closed substream only ends our writes
+        let (mut substream, inbound_tx, close_tx, _outbound_rx) = new_substream(true);
+        substream.close().await.unwrap();
+        substream.close().await.unwrap();
+        substream.write_all(b"more").await.unwrap_err();
+        inbound_tx.send(b"pong".to_vec().into()).unwrap();
+        // the remote's Close ends our reads once its data was read
+        drop(inbound_tx);
+        close_tx.send(Closed::Remote).unwrap();
+        let mut buf = vec![];
+        substream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"pong");
+
+        // and we can still write after it
+        let (mut substream, inbound_tx, close_tx, mut outbound_rx) = new_substream(true);
+        drop(inbound_tx);
+        close_tx.send(Closed::Remote).unwrap();
+        substream.read_to_end(&mut vec![]).await.unwrap();
+        substream.write_all(b"late").await.unwrap();
+        assert!(outbound_rx.try_recv().is_ok());
+
+        // without half-close, the remote's Close ends the substream once its
+        // data was read
+        let (mut substream, inbound_tx, close_tx, _outbound_rx) = new_substream(false);
+        inbound_tx.send(b"bye".to_vec().into()).unwrap();
+        close_tx.send(Closed::Remote).unwrap();
+        let mut buf = [0u8; 3];
+        substream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"bye");
+        substream.read(&mut buf).await.unwrap_err();
+        substream.write_all(b"more").await.unwrap_err();
+
+        // and a reset drops the unread data either way
+        for half_close in [true, false] {
+            let (mut substream, inbound_tx, close_tx, _outbound_rx) = new_substream(half_close);
+            inbound_tx.send(b"bye".to_vec().into()).unwrap();
+            close_tx.send(Closed::Reset).unwrap();
+            substream.read(&mut buf).await.unwrap_err();
+            substream.write_all(b"more").await.unwrap_err();
+        }
+
+        // a request answered over a half-closed substream, as Kademlia does
+        let config = NymTransportConfig::default().with_half_close(true);
+        let mut pair = ConnectedPair::with_config(config).await.unwrap();
+        let (mut dialer, mut listener) = pair.open_substream().await.unwrap();
+        dialer.write_all(b"ping").await.unwrap();
+        dialer.close().await.unwrap();
+        let mut request = vec![];
+        listener.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"ping");
+        listener.write_all(b"pong").await.unwrap();
+        listener.close().await.unwrap();
+        let mut response = vec![];
+        dialer.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"pong");
+    }
+
+    #[cfg(feature = "nym-client")]
     #[tokio::test]
     async fn test_substream_read_write() {
         let client = MixnetClient::connect_new().await.unwrap();
-        let (self_address, mut mixnet_inbound_rx, outbound_tx) =
-            initialize_mixnet(client, None).await.unwrap();
+        let (self_address, mut mixnet_inbound_rx, outbound_tx, _task) = initialize_mixnet(
+            client,
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
 
         const MSG_INNER: &[u8] = "hello".as_bytes();
         let connection_id = ConnectionId::generate();
@@ -419,10 +1466,24 @@ mod test {
         }
     }
 
+    #[cfg(feature = "nym-client")]
     #[tokio::test]
     async fn test_substream_recv_close() {
         let client = MixnetClient::connect_new().await.unwrap();
-        let (self_address, _, outbound_tx) = initialize_mixnet(client, None).await.unwrap();
+        let (self_address, _, outbound_tx, _task) = initialize_mixnet(
+            client,
+            None,
+            None,
+            Default::default(),
+            &Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
 
         const MSG_INNER: &[u8] = "hello".as_bytes();
         let connection_id = ConnectionId::generate();
@@ -442,11 +1503,113 @@ mod test {
         );
 
         // close substream
-        close_tx.send(()).unwrap();
+        close_tx.send(Closed::Remote).unwrap();
 
         // try to read/write to closed substream; should error
         substream.write_all(MSG_INNER).await.unwrap_err();
         let mut buf = [0u8; MSG_INNER.len()];
         substream.read_exact(&mut buf).await.unwrap_err();
     }
+
+    #[tokio::test]
+    async fn test_substream_traffic_class() {
+        let config = NymTransportConfig::default().with_traffic_classes(TrafficClasses {
+            bulk_protocols: vec!["/sync/1.0.0".to_string()],
+            ..Default::default()
+        });
+        let mut pair = ConnectedPair::with_config(config).await.unwrap();
+
+        // both ends of a substream negotiating a bulk protocol become bulk
+        let (mut dialer, mut listener) = pair.open_substream().await.unwrap();
+        assert_eq!(dialer.traffic_class(), TrafficClass::Interactive);
+        let proposal = b"\x13/multistream/1.0.0\n\x0c/sync/1.0.0\n";
+        dialer.write_all(proposal).await.unwrap();
+        let mut buf = [0u8; 33];
+        listener.read_exact(&mut buf).await.unwrap();
+        listener.write_all(&buf).await.unwrap();
+        assert_eq!(dialer.traffic_class(), TrafficClass::Bulk);
+        assert_eq!(listener.traffic_class(), TrafficClass::Bulk);
+
+        // other protocols stay interactive
+        let (mut other, _) = pair.open_substream().await.unwrap();
+        other
+            .write_all(b"\x13/multistream/1.0.0\n\x0c/chat/1.0.0\n")
+            .await
+            .unwrap();
+        assert_eq!(other.traffic_class(), TrafficClass::Interactive);
+
+        // and the application's choice wins over the protocol
+        let (mut chosen, _) = pair.open_substream().await.unwrap();
+        chosen.set_traffic_class(TrafficClass::Interactive);
+        chosen.write_all(proposal).await.unwrap();
+        assert_eq!(chosen.traffic_class(), TrafficClass::Interactive);
+    }
+
+    #[tokio::test]
+    async fn test_substream_buf_read() {
+        let mut pair = ConnectedPair::new().await.unwrap();
+        let (mut dialer, mut listener) = pair.open_substream().await.unwrap();
+        dialer.write_all(b"hello\nwor").await.unwrap();
+        dialer.write_all(b"ld\n").await.unwrap();
+
+        // lines are read out of the received frames, across their boundaries
+        let mut line = String::new();
+        listener.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "hello\n");
+        line.clear();
+        listener.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "world\n");
+
+        // and mixed with plain reads
+        dialer.write_all(b"abc").await.unwrap();
+        assert_eq!(listener.fill_buf().await.unwrap(), b"abc");
+        Pin::new(&mut listener).consume(1);
+        let mut buf = [0u8; 2];
+        listener.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"bc");
+    }
+
+    #[tokio::test]
+    async fn test_substream_flush_interval() {
+        let config = NymTransportConfig::default().with_flush_interval(Duration::from_secs(60));
+        let mut pair = ConnectedPair::with_config(config).await.unwrap();
+
+        // small writes are held back until the substream is flushed
+        let (mut dialer, mut listener) = pair.open_substream().await.unwrap();
+        for chunk in [&b"hello"[..], b" ", b"world"] {
+            dialer.write_all(chunk).await.unwrap();
+        }
+        let mut buf = [0u8; 11];
+        let read = tokio::time::timeout(Duration::from_millis(200), listener.read(&mut buf)).await;
+        assert!(read.is_err());
+
+        // and then arrive in a single frame
+        dialer.flush().await.unwrap();
+        let n = listener.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello world");
+
+        // closing sends the held back data before the close
+        dialer.write_all(b"bye").await.unwrap();
+        dialer.close().await.unwrap();
+        let mut rest = [0u8; 3];
+        listener.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"bye");
+    }
+
+    #[tokio::test]
+    async fn test_substream_flush_interval_elapsed() {
+        let config = NymTransportConfig::default().with_flush_interval(Duration::from_millis(50));
+        let mut pair = ConnectedPair::with_config(config).await.unwrap();
+
+        // held back data is sent once the interval passes, without a flush
+        let (mut dialer, mut listener) = pair.open_substream().await.unwrap();
+        dialer.write_all(b"hello").await.unwrap();
+        dialer.write_all(b" world").await.unwrap();
+        let mut buf = [0u8; 11];
+        tokio::time::timeout(Duration::from_secs(5), listener.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello world");
+    }
 }
@@ -0,0 +1,437 @@
+use futures::future::poll_fn;
+use futures::FutureExt;
+use libp2p::core::{
+    muxing::StreamMuxerExt,
+    transport::{DialOpts, PortUse, TransportError, TransportEvent},
+    Endpoint, Transport,
+};
+use libp2p_identity::{Keypair, PeerId};
+use log::debug;
+use nym_sphinx::addressing::clients::Recipient;
+use nym_sphinx::anonymous_replies::requests::AnonymousSenderTag;
+use parking_lot::Mutex;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+use tokio::task::JoinHandle;
+
+use super::config::NymTransportConfig;
+use super::connection::Connection;
+use super::error::Error;
+use super::message::{parse_message_data, InboundMessage};
+use super::mixnet::{OutboundBacklog, OutboundReceiver};
+use super::substream::Substream;
+use super::transport::NymTransport;
+
+/// MockMixnet carries messages between the transports built on it in memory,
+/// without delay or loss. Like on the mixnet, a message sent to an address
+/// arrives with its sender's tag, and replies to the tag arrive at the sender.
+#[derive(Clone, Debug, Default)]
+pub struct MockMixnet {
+    clients: Arc<Mutex<Vec<MockClient>>>,
+}
+
+#[derive(Debug)]
+struct MockClient {
+    address: Recipient,
+    sender_tag: AnonymousSenderTag,
+    inbound_tx: UnboundedSender<InboundMessage>,
+}
+
+impl MockMixnet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns a builder for a transport on this mixnet.
+    pub fn transport(&self) -> TestTransport {
+        TestTransport {
+            mixnet: self.clone(),
+            keypair: None,
+            config: NymTransportConfig::default(),
+        }
+    }
+
+    /// adds a client with a new address to the mixnet, which sends the
+    /// messages received from the given channel. Must be called within a
+    /// tokio runtime.
+    pub(crate) fn register(
+        &self,
+        mut outbound_rx: OutboundReceiver,
+        backlog: Arc<OutboundBacklog>,
+    ) -> (Recipient, UnboundedReceiver<InboundMessage>) {
+        let address = random_address();
+        let (inbound_tx, inbound_rx) = unbounded_channel();
+        self.clients.lock().push(MockClient {
+            address,
+            sender_tag: AnonymousSenderTag::new_random(&mut OsRng),
+            inbound_tx,
+        });
+
+        let mixnet = self.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![];
+            while let Some(msg) = outbound_rx.recv().await {
+                backlog.dequeued(msg.queued_at);
+                buf.clear();
+                if let Err(e) = msg.encode_into(&mut buf) {
+                    debug!("mock mixnet failed to encode a message: {e}");
+                    continue;
+                }
+                mixnet.deliver(address, msg.recipient, msg.sender_tag, &buf);
+            }
+        });
+        (address, inbound_rx)
+    }
+
+    fn deliver(
+        &self,
+        from: Recipient,
+        recipient: Option<Recipient>,
+        sender_tag: Option<AnonymousSenderTag>,
+        bytes: &[u8],
+    ) {
+        let clients = self.clients.lock();
+        // replies go to the tag's owner, and arrive without a tag
+        let (to, sender_tag) = match (recipient, sender_tag) {
+            (_, Some(tag)) => (clients.iter().find(|c| c.sender_tag == tag), None),
+            (Some(recipient), None) => (
+                clients.iter().find(|c| c.address == recipient),
+                clients
+                    .iter()
+                    .find(|c| c.address == from)
+                    .map(|c| c.sender_tag),
+            ),
+            (None, None) => (None, None),
+        };
+        let Some(to) = to else {
+            debug!("mock mixnet has no client for a message, dropping it");
+            return;
+        };
+        match parse_message_data(bytes.to_vec().into(), sender_tag) {
+            // the transport may have been dropped, which is fine.
+            Ok(msg) => drop(to.inbound_tx.send(msg)),
+            Err(e) => debug!("mock mixnet failed to parse a message: {e}"),
+        }
+    }
+}
+
+/// returns a random address, with valid keys so that it's accepted anywhere
+/// a nym address is parsed.
+pub(crate) fn random_address() -> Recipient {
+    let mut bytes = [0u8; Recipient::LEN];
+    let identity = Keypair::generate_ed25519().public();
+    bytes[..32].copy_from_slice(&identity.try_into_ed25519().unwrap().to_bytes());
+    OsRng.fill_bytes(&mut bytes[32..64]);
+    let gateway = Keypair::generate_ed25519().public();
+    bytes[64..].copy_from_slice(&gateway.try_into_ed25519().unwrap().to_bytes());
+    Recipient::try_from_bytes(bytes).unwrap()
+}
+
+/// TestTransport builds a `NymTransport` on a `MockMixnet`, for testing
+/// applications and protocols against the transport without the mixnet.
+pub struct TestTransport {
+    mixnet: MockMixnet,
+    keypair: Option<Keypair>,
+    config: NymTransportConfig,
+}
+
+impl TestTransport {
+    /// the transport's identity; a new one is generated if not given.
+    pub fn with_keypair(mut self, keypair: Keypair) -> Self {
+        self.keypair = Some(keypair);
+        self
+    }
+
+    pub fn with_config(mut self, config: NymTransportConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// returns the transport, which has a new address on the mixnet. Must be
+    /// called within a tokio runtime.
+    pub fn build(self) -> Result<NymTransport, Error> {
+        let keypair = self.keypair.unwrap_or_else(Keypair::generate_ed25519);
+        NymTransport::new_on_mock_mixnet(&self.mixnet, keypair, self.config)
+    }
+}
+
+/// dials `listener` from `dialer` and runs the handshake, returning the
+/// dialer's and the listener's end of the connection, each with the peer ID
+/// of the other side. Both transports are polled until the handshake
+/// completes; any other events they return meanwhile are dropped.
+pub async fn connect(
+    dialer: &mut NymTransport,
+    listener: &mut NymTransport,
+) -> Result<((PeerId, Connection), (PeerId, Connection)), Error> {
+    let dial_opts = DialOpts {
+        role: Endpoint::Dialer,
+        port_use: PortUse::Reuse,
+    };
+    let mut dial = dialer
+        .dial(listener.listen_addr().clone(), dial_opts)
+        .map_err(|e| match e {
+            TransportError::Other(e) => e,
+            TransportError::MultiaddrNotSupported(_) => Error::InvalidProtocolForMultiaddr,
+        })?;
+
+    let mut upgrade = None;
+    let mut dialed = None;
+    let mut accepted = None;
+    poll_fn(|cx| {
+        while Pin::new(&mut *dialer).poll(cx).is_ready() {}
+        while let Poll::Ready(event) = Pin::new(&mut *listener).poll(cx) {
+            if let TransportEvent::Incoming { upgrade: u, .. } = event {
+                upgrade = Some(u);
+            }
+        }
+        if dialed.is_none() {
+            if let Poll::Ready(res) = dial.poll_unpin(cx) {
+                dialed = Some(res?);
+            }
+        }
+        if let (Some(upgrade), None) = (&mut upgrade, &accepted) {
+            if let Poll::Ready(res) = upgrade.poll_unpin(cx) {
+                accepted = Some(res?);
+            }
+        }
+        if dialed.is_some() && accepted.is_some() {
+            return Poll::Ready(Ok::<_, Error>(()));
+        }
+        Poll::Pending
+    })
+    .await?;
+    Ok((dialed.unwrap(), accepted.unwrap()))
+}
+
+/// TransportDriver keeps polling a transport in the background, which it
+/// has to be for its connections to make progress; see `drive`. The
+/// transport is dropped along with the driver.
+pub struct TransportDriver {
+    task: JoinHandle<()>,
+    incoming_rx: UnboundedReceiver<Result<(PeerId, Connection), Error>>,
+}
+
+/// moves the transport into a background task, which polls it and accepts
+/// inbound connections; see `TransportDriver::accept`.
+pub fn drive(mut transport: NymTransport) -> TransportDriver {
+    let (incoming_tx, incoming_rx) = unbounded_channel();
+    let task = tokio::spawn(async move {
+        loop {
+            let event = poll_fn(|cx| Pin::new(&mut transport).poll(cx)).await;
+            if let TransportEvent::Incoming { upgrade, .. } = event {
+                let incoming_tx = incoming_tx.clone();
+                tokio::spawn(async move {
+                    // the driver may have been dropped, which is fine.
+                    let _ = incoming_tx.send(upgrade.await);
+                });
+            }
+        }
+    });
+    TransportDriver { task, incoming_rx }
+}
+
+impl TransportDriver {
+    /// waits for the next inbound connection.
+    pub async fn accept(&mut self) -> Result<(PeerId, Connection), Error> {
+        self.incoming_rx.recv().await.ok_or(Error::RecvFailure)?
+    }
+}
+
+impl Drop for TransportDriver {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+type OpenRequest = oneshot::Sender<Result<Substream, Error>>;
+
+/// TestConnection polls a connection in a background task, so that its
+/// substreams can be used without driving the connection by hand. The
+/// connection is dropped along with the TestConnection.
+pub struct TestConnection {
+    open_tx: UnboundedSender<OpenRequest>,
+    inbound_rx: UnboundedReceiver<Result<Substream, Error>>,
+}
+
+impl TestConnection {
+    /// moves the connection into a background task. Must be called within a
+    /// tokio runtime.
+    pub fn new(mut connection: Connection) -> Self {
+        let (open_tx, mut open_rx) = unbounded_channel::<OpenRequest>();
+        let (inbound_tx, inbound_rx) = unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let event = poll_fn(|cx| {
+                    if let Poll::Ready(request) = open_rx.poll_recv(cx) {
+                        return Poll::Ready(TaskEvent::Open(request));
+                    }
+                    poll_inbound(&mut connection, cx).map(TaskEvent::Inbound)
+                })
+                .await;
+                match event {
+                    TaskEvent::Open(Some(reply_tx)) => {
+                        let res = poll_fn(|cx| connection.poll_outbound_unpin(cx)).await;
+                        let _ = reply_tx.send(res);
+                    }
+                    // the TestConnection was dropped
+                    TaskEvent::Open(None) => return,
+                    TaskEvent::Inbound(res) => {
+                        let failed = res.is_err();
+                        let _ = inbound_tx.send(res);
+                        if failed {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        TestConnection {
+            open_tx,
+            inbound_rx,
+        }
+    }
+
+    /// opens a new substream to the remote.
+    pub async fn open_substream(&self) -> Result<Substream, Error> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.open_tx
+            .send(reply_tx)
+            .map_err(|_| Error::RecvFailure)?;
+        reply_rx.await?
+    }
+
+    /// waits for the remote to open a substream. Fails once the connection failed.
+    pub async fn accept_substream(&mut self) -> Result<Substream, Error> {
+        self.inbound_rx.recv().await.ok_or(Error::RecvFailure)?
+    }
+}
+
+/// what the task of a TestConnection handles next; short-lived, so its size
+/// doesn't matter.
+#[allow(clippy::large_enum_variant)]
+enum TaskEvent {
+    Open(Option<OpenRequest>),
+    Inbound(Result<Substream, Error>),
+}
+
+fn poll_inbound(
+    connection: &mut Connection,
+    cx: &mut Context<'_>,
+) -> Poll<Result<Substream, Error>> {
+    // the connection's events have to be handled for substreams to arrive
+    while let Poll::Ready(event) = connection.poll_unpin(cx) {
+        event?;
+    }
+    connection.poll_inbound_unpin(cx)
+}
+
+/// ConnectedPair is a connection between two transports on a `MockMixnet`,
+/// both of which are driven in the background:
+///
+/// ```ignore
+/// let mut pair = ConnectedPair::new().await?;
+/// let (mut dialer, mut listener) = pair.open_substream().await?;
+/// dialer.write_all(b"hello").await?;
+/// ```
+pub struct ConnectedPair {
+    pub dialer: TestConnection,
+    pub listener: TestConnection,
+    /// the dialer's identity for this connection, which is generated for
+    /// every connection it dials.
+    pub dialer_peer_id: PeerId,
+    pub listener_peer_id: PeerId,
+    _transports: [TransportDriver; 2],
+}
+
+impl ConnectedPair {
+    /// connects two transports with the default config.
+    pub async fn new() -> Result<Self, Error> {
+        Self::with_config(NymTransportConfig::default()).await
+    }
+
+    /// connects two transports with the given config.
+    pub async fn with_config(config: NymTransportConfig) -> Result<Self, Error> {
+        let mixnet = MockMixnet::new();
+        let mut dialer = mixnet.transport().with_config(config.clone()).build()?;
+        let mut listener = mixnet.transport().with_config(config).build()?;
+        let ((listener_peer_id, dialer_conn), (dialer_peer_id, listener_conn)) =
+            connect(&mut dialer, &mut listener).await?;
+        Ok(ConnectedPair {
+            dialer: TestConnection::new(dialer_conn),
+            listener: TestConnection::new(listener_conn),
+            dialer_peer_id,
+            listener_peer_id,
+            _transports: [drive(dialer), drive(listener)],
+        })
+    }
+
+    /// opens a substream from the dialer, returning the dialer's and the
+    /// listener's end of it.
+    pub async fn open_substream(&mut self) -> Result<(Substream, Substream), Error> {
+        let dialer = self.dialer.open_substream().await?;
+        let listener = self.listener.accept_substream().await?;
+        Ok((dialer, listener))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_connected_pair() {
+        let mut pair = ConnectedPair::new().await.unwrap();
+        let (mut dialer, mut listener) = pair.open_substream().await.unwrap();
+
+        dialer.write_all(b"hello world").await.unwrap();
+        let mut buf = [0u8; 11];
+        listener.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+        listener.write_all(b"hello back").await.unwrap();
+        let mut buf = [0u8; 10];
+        dialer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello back");
+
+        // substreams can be opened from either side
+        let mut listener = pair.listener.open_substream().await.unwrap();
+        let mut dialer = pair.dialer.accept_substream().await.unwrap();
+        listener.write_all(b"hi").await.unwrap();
+        let mut buf = [0u8; 2];
+        dialer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+    }
+
+    #[tokio::test]
+    async fn test_mock_mixnet_connect() {
+        let mixnet = MockMixnet::new();
+        let listener_key = Keypair::generate_ed25519();
+        let mut dialer = mixnet.transport().build().unwrap();
+        let mut listener = mixnet
+            .transport()
+            .with_keypair(listener_key.clone())
+            .build()
+            .unwrap();
+        let mut other = mixnet.transport().build().unwrap();
+        assert_ne!(dialer.listen_addr(), listener.listen_addr());
+
+        let ((listener_peer_id, dialer_conn), (dialer_peer_id, listener_conn)) =
+            connect(&mut dialer, &mut listener).await.unwrap();
+        assert_eq!(listener_peer_id, listener_key.public().to_peer_id());
+        assert_eq!(dialer_conn.peer_id, listener_peer_id);
+        assert_eq!(listener_conn.peer_id, dialer_peer_id);
+
+        // a transport can hold connections to several peers
+        let ((_, other_conn), _) = connect(&mut dialer, &mut other).await.unwrap();
+        assert_ne!(other_conn.peer_id, listener_peer_id);
+    }
+}
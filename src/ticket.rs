@@ -0,0 +1,292 @@
+use bytes::Bytes;
+use libp2p::core::{Multiaddr, PeerId};
+use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::RwLock;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::codec::{self, put_length_prefixed, unix_time, Decoder, NONCE_LENGTH};
+use super::config::SessionResumption;
+use super::error::Error;
+use super::message::{ConnectionFlags, ConnectionId};
+use super::redact::redact_always;
+use super::transport::{multiaddress_to_nym_address, nym_address_to_multiaddress};
+
+/// version of the sealed ticket's plaintext encoding.
+const TICKET_VERSION: u8 = 1;
+/// version of `SessionTicket::to_bytes`.
+const STORED_TICKET_VERSION: u8 = 1;
+const TICKET_AAD: &[u8] = b"nym-libp2p-session-ticket";
+
+/// SessionTicket lets the dialer of a connection resume it after a restart,
+/// without another handshake; see `SessionResumption`. The listener issues
+/// it once the connection is set up, and a new one on every resumption. Its
+/// contents are sealed with the listener's key, so the dialer only keeps it
+/// with the address it dialed.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SessionTicket {
+    pub(crate) recipient: Recipient,
+    pub(crate) peer_id: PeerId,
+    pub(crate) id: ConnectionId,
+    pub(crate) flags: ConnectionFlags,
+    pub(crate) ticket: Bytes,
+}
+
+impl SessionTicket {
+    /// returns the PeerId of the listener which issued the ticket.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// returns the listen address of the listener which issued the ticket.
+    pub fn address(&self) -> Multiaddr {
+        nym_address_to_multiaddress(self.recipient).expect("nym addresses are valid multiaddrs")
+    }
+
+    /// encodes the ticket, so that it can be stored across restarts.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![STORED_TICKET_VERSION];
+        bytes.extend_from_slice(&self.recipient.to_bytes());
+        bytes.extend_from_slice(&self.id.0);
+        bytes.push(self.flags.bits());
+        put_length_prefixed(&mut bytes, &self.peer_id.to_bytes());
+        bytes.extend_from_slice(&self.ticket);
+        bytes
+    }
+
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut bytes = Decoder::new(bytes, || Error::InvalidSessionTicket);
+        if bytes.take_u8()? != STORED_TICKET_VERSION {
+            return Err(Error::InvalidSessionTicket);
+        }
+        let recipient = Recipient::try_from_bytes(bytes.take_array::<{ Recipient::LEN }>()?)
+            .map_err(|_| Error::InvalidSessionTicket)?;
+        let id = ConnectionId(bytes.take_array()?);
+        let flags = ConnectionFlags::from_bits(bytes.take_u8()?);
+        let peer_id = PeerId::from_bytes(bytes.take_length_prefixed()?)
+            .map_err(|_| Error::InvalidSessionTicket)?;
+        if bytes.is_empty() {
+            return Err(Error::InvalidSessionTicket);
+        }
+        Ok(SessionTicket {
+            recipient,
+            peer_id,
+            id,
+            flags,
+            ticket: Bytes::copy_from_slice(bytes.rest()),
+        })
+    }
+}
+
+impl Debug for SessionTicket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionTicket")
+            .field("recipient", &redact_always(self.recipient))
+            .field("peer_id", &self.peer_id)
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// SessionTicketStore holds the session tickets a transport received for the
+/// connections it dialed, one per listen address. A handle can be obtained
+/// with `NymTransport::session_tickets()` before the transport is moved into
+/// a swarm. Dialing an address with a ticket resumes the ticket's connection,
+/// and uses the ticket up; the listener issues a new one.
+#[derive(Clone, Debug, Default)]
+pub struct SessionTicketStore {
+    inner: Arc<RwLock<HashMap<ConnectionId, SessionTicket>>>,
+}
+
+impl SessionTicketStore {
+    /// returns all tickets, eg. to store them before shutting down.
+    pub fn all(&self) -> Vec<SessionTicket> {
+        self.inner.read().values().cloned().collect()
+    }
+
+    /// adds a ticket, eg. one stored by a previous transport, replacing the
+    /// one for the same address.
+    pub fn insert(&self, ticket: SessionTicket) {
+        let mut tickets = self.inner.write();
+        tickets.retain(|_, other| other.recipient != ticket.recipient);
+        tickets.insert(ticket.id.clone(), ticket);
+    }
+
+    /// removes the ticket for the given address, if there is one.
+    pub fn remove(&self, addr: &Multiaddr) -> Option<SessionTicket> {
+        let recipient = multiaddress_to_nym_address(addr.clone()).ok()?;
+        self.take(&recipient)
+    }
+
+    pub(crate) fn take(&self, recipient: &Recipient) -> Option<SessionTicket> {
+        let mut tickets = self.inner.write();
+        let id = tickets
+            .values()
+            .find(|ticket| ticket.recipient == *recipient)?
+            .id
+            .clone();
+        tickets.remove(&id)
+    }
+}
+
+/// SealedTicket is what a listener reads back from a ticket it issued.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct SealedTicket {
+    pub(crate) id: ConnectionId,
+    pub(crate) peer_id: PeerId,
+    pub(crate) flags: ConnectionFlags,
+}
+
+/// seals the given connection into a ticket only we can open.
+pub(crate) fn seal(config: &SessionResumption, ticket: &SealedTicket) -> Result<Bytes, Error> {
+    seal_at(config, ticket, unix_time())
+}
+
+fn seal_at(
+    config: &SessionResumption,
+    ticket: &SealedTicket,
+    issued_at: u64,
+) -> Result<Bytes, Error> {
+    let mut plaintext = vec![TICKET_VERSION];
+    plaintext.extend_from_slice(&issued_at.to_be_bytes());
+    plaintext.extend_from_slice(&ticket.id.0);
+    plaintext.push(ticket.flags.bits());
+    plaintext.extend_from_slice(&ticket.peer_id.to_bytes());
+    Ok(codec::seal(config.key(), TICKET_AAD, &plaintext)?.into())
+}
+
+/// opens a ticket we issued, unless it expired.
+pub(crate) fn open(config: &SessionResumption, ticket: &[u8]) -> Result<SealedTicket, Error> {
+    let plaintext =
+        codec::open(config.key(), TICKET_AAD, ticket).ok_or(Error::InvalidSessionTicket)?;
+
+    let mut bytes = Decoder::new(&plaintext, || Error::InvalidSessionTicket);
+    if bytes.take_u8()? != TICKET_VERSION {
+        return Err(Error::InvalidSessionTicket);
+    }
+    let issued_at = bytes.take_u64()?;
+    if unix_time().saturating_sub(issued_at) > config.lifetime.as_secs() {
+        return Err(Error::InvalidSessionTicket);
+    }
+    let id = ConnectionId(bytes.take_array()?);
+    let flags = ConnectionFlags::from_bits(bytes.take_u8()?);
+    let peer_id = PeerId::from_bytes(bytes.rest()).map_err(|_| Error::InvalidSessionTicket)?;
+    Ok(SealedTicket { id, peer_id, flags })
+}
+
+/// UsedTickets remembers the tickets which resumed a connection until they
+/// expire, so that each one resumes a connection only once. Every ticket is
+/// sealed with a fresh nonce, which identifies it.
+#[derive(Debug, Default)]
+pub(crate) struct UsedTickets {
+    expiries: HashMap<[u8; NONCE_LENGTH], Instant>,
+}
+
+impl UsedTickets {
+    /// marks a ticket we opened as used; returns false if it already was.
+    pub(crate) fn insert(&mut self, config: &SessionResumption, ticket: &[u8]) -> bool {
+        let now = Instant::now();
+        self.expiries.retain(|_, expires_at| *expires_at > now);
+        let Some(nonce) = ticket_nonce(ticket) else {
+            return false;
+        };
+        match self.expiries.entry(nonce) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now + config.lifetime);
+                true
+            }
+        }
+    }
+}
+
+fn ticket_nonce(ticket: &[u8]) -> Option<[u8; NONCE_LENGTH]> {
+    ticket.get(..NONCE_LENGTH)?.try_into().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test_utils::random_address;
+    use super::*;
+    use std::time::Duration;
+
+    fn sealed_ticket() -> SealedTicket {
+        SealedTicket {
+            id: ConnectionId::generate(),
+            peer_id: PeerId::random(),
+            flags: ConnectionFlags::COMPACT_IDS.union(ConnectionFlags::SESSION_TICKETS),
+        }
+    }
+
+    #[test]
+    fn test_session_ticket_seal() {
+        let config = SessionResumption::new([3u8; 32]);
+        let sealed = sealed_ticket();
+        let ticket = seal(&config, &sealed).unwrap();
+        assert_eq!(open(&config, &ticket).unwrap(), sealed);
+
+        // the connection isn't readable from the ticket
+        assert!(!ticket.windows(32).any(|w| w == sealed.id.0));
+
+        // only the key it was sealed with opens it
+        let other = SessionResumption::new([4u8; 32]);
+        assert!(matches!(
+            open(&other, &ticket),
+            Err(Error::InvalidSessionTicket)
+        ));
+        let mut tampered = ticket.to_vec();
+        tampered[NONCE_LENGTH] ^= 1;
+        assert!(open(&config, &tampered).is_err());
+
+        // and only until it expires
+        let config = config.with_lifetime(Duration::from_secs(60));
+        let expired = seal_at(&config, &sealed, unix_time() - 120).unwrap();
+        assert!(matches!(
+            open(&config, &expired),
+            Err(Error::InvalidSessionTicket)
+        ));
+    }
+
+    #[test]
+    fn test_used_tickets() {
+        let config = SessionResumption::new([3u8; 32]);
+        let ticket = seal(&config, &sealed_ticket()).unwrap();
+        let mut used = UsedTickets::default();
+        assert!(used.insert(&config, &ticket));
+        assert!(!used.insert(&config, &ticket));
+
+        // each ticket is issued with its own nonce
+        let other = seal(&config, &sealed_ticket()).unwrap();
+        assert!(used.insert(&config, &other));
+
+        // and forgotten once it expired
+        let config = config.with_lifetime(Duration::ZERO);
+        let mut used = UsedTickets::default();
+        assert!(used.insert(&config, &ticket));
+        assert!(used.insert(&config, &ticket));
+    }
+
+    #[test]
+    fn test_session_ticket_encoding() {
+        let sealed = sealed_ticket();
+        let ticket = SessionTicket {
+            recipient: random_address(),
+            peer_id: sealed.peer_id,
+            id: sealed.id,
+            flags: sealed.flags,
+            ticket: Bytes::from_static(b"sealed"),
+        };
+        let bytes = ticket.to_bytes();
+        assert_eq!(SessionTicket::try_from_bytes(&bytes).unwrap(), ticket);
+        assert!(SessionTicket::try_from_bytes(&bytes[..bytes.len() - 6]).is_err());
+
+        let store = SessionTicketStore::default();
+        store.insert(ticket.clone());
+        assert_eq!(store.all(), vec![ticket.clone()]);
+        assert_eq!(store.remove(&ticket.address()), Some(ticket));
+        assert!(store.all().is_empty());
+    }
+}
@@ -0,0 +1,16 @@
+//! The messages transports exchange through the mixnet, for protocol
+//! analyzers and other implementations. Changes to these definitions follow
+//! semver: breaking the encoding of a message, or the types below, takes a
+//! major version. New kinds of messages and connection flags don't, nor do
+//! new fields, which is why the message types are `#[non_exhaustive]`:
+//! decode them with `Message::try_from_bytes` and build them with their
+//! constructors.
+pub use super::connection::{CloseCode, CloseReason};
+pub use super::message::{
+    AckMessage, AddressMessage, CipherSuite, CompressionAlgorithm, ConnectionCloseMessage,
+    ConnectionFlags, ConnectionId, ConnectionMessage, ConnectionMessageBuilder,
+    ConnectionMessageKind, EncryptedTransportMessage, HandshakeExtensions, KeyUpdateKind,
+    KeyUpdateLimits, KeyUpdateMessage, Message, MigrateMessage, OutOfBandMessage, ParityMessage,
+    ProbeMessage, ResumeMessage, SealedMessage, SessionTicketMessage, SubstreamId,
+    SubstreamMessage, SubstreamMessageType, TransportMessage, MAX_MESSAGE_LEN, MAX_SACK_BLOCKS,
+};
@@ -0,0 +1,157 @@
+use futures::StreamExt;
+use libp2p::kad::{
+    self, store::MemoryStore, GetProvidersOk, QueryResult, RecordKey, PROTOCOL_NAME,
+};
+use libp2p::swarm::{dial_opts::DialOpts, NetworkBehaviour, Swarm, SwarmEvent};
+use libp2p::{Multiaddr, PeerId, SwarmBuilder};
+use libp2p_identity::Keypair;
+use rust_libp2p_nym::announce;
+use rust_libp2p_nym::config::NymTransportConfig;
+use rust_libp2p_nym::test_utils::MockMixnet;
+use std::time::Duration;
+
+#[derive(NetworkBehaviour)]
+struct Node {
+    kad: kad::Behaviour<MemoryStore>,
+    announce: announce::Behaviour,
+}
+
+fn node(mixnet: &MockMixnet) -> (Swarm<Node>, PeerId, Multiaddr) {
+    let keypair = Keypair::generate_ed25519();
+    let peer_id = keypair.public().to_peer_id();
+    let transport = mixnet
+        .transport()
+        .with_keypair(keypair.clone())
+        .with_config(NymTransportConfig::default().with_kademlia_compatibility())
+        .build()
+        .unwrap();
+    let address = transport.listen_addr().clone();
+
+    // the swarm's identity has to be the transport's for Kademlia to work
+    let swarm = SwarmBuilder::with_existing_identity(keypair.clone())
+        .with_tokio()
+        .with_other_transport(|_| transport)
+        .unwrap()
+        .with_behaviour(|_| {
+            let mut config = kad::Config::new(PROTOCOL_NAME);
+            config.set_query_timeout(Duration::from_secs(30));
+            Node {
+                kad: kad::Behaviour::with_config(peer_id, MemoryStore::new(peer_id), config),
+                announce: announce::Behaviour::new(
+                    keypair,
+                    announce::Config::new().with_external_address(true),
+                ),
+            }
+        })
+        .unwrap()
+        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+    (swarm, peer_id, address)
+}
+
+/// handles the events every node reacts to the same way, returning the rest.
+fn handle(swarm: &mut Swarm<Node>, event: SwarmEvent<NodeEvent>) -> Option<kad::Event> {
+    match event {
+        // the listener of a connection learns where to reach its dialer from
+        // the announcement, and adds it to its routing table
+        SwarmEvent::Behaviour(NodeEvent::Announce(announce::Event {
+            peer_id, address, ..
+        })) => {
+            swarm.behaviour_mut().kad.add_address(&peer_id, address);
+            None
+        }
+        SwarmEvent::Behaviour(NodeEvent::Kad(event)) => Some(event),
+        _ => None,
+    }
+}
+
+fn routing_table_len(swarm: &mut Swarm<Node>) -> usize {
+    swarm
+        .behaviour_mut()
+        .kad
+        .kbuckets()
+        .map(|bucket| bucket.num_entries())
+        .sum()
+}
+
+#[tokio::test]
+async fn test_kademlia_providers() {
+    let mixnet = MockMixnet::new();
+    let (mut bootstrap, bootstrap_id, bootstrap_addr) = node(&mixnet);
+    let (mut provider, provider_id, _) = node(&mixnet);
+    let (mut seeker, _, _) = node(&mixnet);
+
+    // the provider and the seeker only know the bootstrap node
+    for swarm in [&mut provider, &mut seeker] {
+        swarm
+            .behaviour_mut()
+            .kad
+            .add_address(&bootstrap_id, bootstrap_addr.clone());
+        swarm
+            .dial(
+                DialOpts::peer_id(bootstrap_id)
+                    .addresses(vec![bootstrap_addr.clone()])
+                    .build(),
+            )
+            .unwrap();
+    }
+
+    let key = RecordKey::new(&"nym-kademlia-test");
+    let mut providing = false;
+    let mut found = false;
+    let test = async {
+        loop {
+            tokio::select! {
+                event = bootstrap.select_next_some() => {
+                    handle(&mut bootstrap, event);
+                }
+                event = provider.select_next_some() => {
+                    if let SwarmEvent::ConnectionEstablished { peer_id, .. } = &event {
+                        if *peer_id == bootstrap_id && !providing {
+                            providing = true;
+                            provider.behaviour_mut().kad.start_providing(key.clone()).unwrap();
+                        }
+                    }
+                    if let Some(kad::Event::OutboundQueryProgressed {
+                        result: QueryResult::StartProviding(res),
+                        ..
+                    }) = handle(&mut provider, event)
+                    {
+                        res.unwrap();
+                        seeker.behaviour_mut().kad.get_providers(key.clone());
+                    }
+                }
+                event = seeker.select_next_some() => {
+                    if let Some(kad::Event::OutboundQueryProgressed {
+                        result: QueryResult::GetProviders(res),
+                        step,
+                        ..
+                    }) = handle(&mut seeker, event)
+                    {
+                        match res.unwrap() {
+                            GetProvidersOk::FoundProviders { providers, .. } => {
+                                found |= providers.contains(&provider_id);
+                            }
+                            // the provider record may not have been stored yet
+                            GetProvidersOk::FinishedWithNoAdditionalRecord { .. } => {
+                                if step.last && !found {
+                                    tokio::time::sleep(Duration::from_millis(100)).await;
+                                    seeker.behaviour_mut().kad.get_providers(key.clone());
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            // the bootstrap node has both other nodes in its routing table,
+            // with the addresses they announced
+            if found && routing_table_len(&mut bootstrap) == 2 {
+                return;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(60), test)
+        .await
+        .expect("the seeker didn't find the provider in time");
+}
@@ -0,0 +1,60 @@
+use bytes::Bytes;
+use libp2p_identity::Keypair;
+use rust_libp2p_nym::wire::{
+    CloseCode, ConnectionFlags, ConnectionId, ConnectionMessage, ConnectionMessageKind, Message,
+    SubstreamId, SubstreamMessage, SubstreamMessageType, TransportMessage,
+};
+
+#[test]
+fn handshake_round_trip() {
+    let keypair = Keypair::generate_ed25519();
+    let id = ConnectionId::generate();
+    let request = ConnectionMessage::new_signed(
+        &keypair,
+        id.clone(),
+        ConnectionMessageKind::Request,
+        ConnectionFlags::COMPACT_IDS.union(ConnectionFlags::ACKS),
+        None,
+    )
+    .unwrap();
+    let bytes = Message::ConnectionRequest(request).to_bytes();
+
+    let Message::ConnectionRequest(request) = Message::try_from_bytes(bytes.into()).unwrap() else {
+        panic!("expected Message::ConnectionRequest");
+    };
+    assert_eq!(request.id, id);
+    assert_eq!(request.peer_id, keypair.public().to_peer_id());
+    assert!(request.flags.contains(ConnectionFlags::ACKS));
+    request.verify(ConnectionMessageKind::Request).unwrap();
+    // a request can't be replayed as a response
+    assert!(request.verify(ConnectionMessageKind::Response).is_err());
+}
+
+#[test]
+fn transport_message_round_trip() {
+    let id = ConnectionId::generate();
+    let substream_id = SubstreamId::generate();
+    let msg = Message::TransportMessage(TransportMessage::new(
+        id.clone(),
+        1,
+        SubstreamMessage::new_with_data(substream_id.clone(), b"hello".to_vec()),
+    ));
+
+    let Message::TransportMessage(msg) = Message::try_from_bytes(msg.to_bytes().into()).unwrap()
+    else {
+        panic!("expected Message::TransportMessage");
+    };
+    assert_eq!(msg.id, id);
+    assert_eq!(msg.nonce, 1);
+    assert_eq!(msg.message.substream_id, substream_id);
+    assert!(matches!(
+        msg.message.message_type,
+        SubstreamMessageType::Data(data) if data == Bytes::from_static(b"hello")
+    ));
+}
+
+#[test]
+fn invalid_bytes() {
+    assert!(Message::try_from_bytes(Bytes::from_static(&[42, 0, 0])).is_err());
+    assert_eq!(u16::from(CloseCode::Idle), 2);
+}